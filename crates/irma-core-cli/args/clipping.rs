@@ -1,6 +1,8 @@
+use crate::shared::barcode_pairs::BarcodePairs;
 use clap::{Args, ValueEnum, builder::PossibleValue};
 use foldhash::fast::SeedableRandomState;
-use irma_records::hashing::get_hasher;
+use irma_records::{hashing::get_hasher, paired::ReadSide};
+use serde::{Deserialize, Serialize};
 use std::{fmt::Debug, num::NonZeroUsize, path::PathBuf};
 use zoe::{
     data::err::ResultWithErrorContext,
@@ -10,6 +12,8 @@ use zoe::{
 
 pub const MAX_KMER_LENGTH: usize = 21;
 
+/// The trimming and clipping options, flattened into both `trimmer` and
+/// `preprocess` so that every option added here is available in both.
 #[derive(Args, Debug)]
 pub struct ClippingArgs {
     #[arg(short = 's', long, alias = "preserve_fastq")]
@@ -17,6 +21,12 @@ pub struct ClippingArgs {
     /// canonical bases (A, C, T, G, N)
     pub preserve_bases: bool,
 
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "fail")]
+    /// Checks each read for characters that are not IUPAC nucleotide codes,
+    /// which suggest a corrupt input. Such a read is an error with `fail` (the
+    /// default if no value is given), or is filtered with `drop`
+    pub strict_bases: Option<StrictBases>,
+
     #[arg(short = 'G', long)]
     /// Trims multiple consecutive G's (a possible artifact of Illumina
     /// sequencing) found at the ends of a sequence
@@ -37,45 +47,68 @@ pub struct ClippingArgs {
     /// sequence for poly-g trimming
     pub g_polyg_right: Option<NonZeroUsize>,
 
-    #[arg(short = 'B', long, value_parser = validate_acgtn, group = "adapter_vs_barcode")]
+    #[arg(short = 'B', long, value_parser = validate_acgtn, groups = ["adapter_vs_barcode", "barcode_source"])]
     /// Trim barcodes and their reverse complements from sequence using string
     /// matching. Requires literal barcode as argument
     pub barcode_trim: Option<Nucleotides>,
 
+    #[arg(long, value_name = "TSV", groups = ["adapter_vs_barcode", "barcode_source"])]
+    /// Assign reads to samples using combinatorial dual barcodes, then trim
+    /// both barcodes. Requires a tab-delimited file of left barcode, right
+    /// barcode, and sample name. See `--b-annotate-sample` for recording the
+    /// sample in the headers
+    pub barcode_pairs: Option<PathBuf>,
+
+    #[arg(long, requires = "barcode_pairs")]
+    /// Append the sample assigned by `--barcode-pairs` to each header as
+    /// ` sample=NAME` (or ` sample=unassigned`)
+    pub b_annotate_sample: bool,
+
     #[arg(long, value_enum, default_value = "b", requires = "barcode_trim")]
     /// Specifies the end of the sequence for barcode trimming : 'l' (left), 'r'
     /// (right), or 'b' (both)
     pub b_end: TrimEnd,
 
-    #[arg(long, requires = "barcode_trim")]
+    #[arg(long, requires = "barcode_source")]
     /// Restriction window size for barcode trimming on both ends of the
     /// sequence. If no restriction is provided, full scan is performed
     pub b_restrict: Option<NonZeroUsize>,
 
-    #[arg(long, requires = "barcode_trim")]
+    #[arg(long, requires = "barcode_source")]
     /// Restriction window for trimming barcodes on the left end of the
     /// sequence. Overrides --b-restrict
     pub b_restrict_left: Option<NonZeroUsize>,
 
-    #[arg(long, requires = "barcode_trim")]
+    #[arg(long, requires = "barcode_source")]
     /// Restriction window for trimming barcodes on the right end of the
     /// sequence. Overrides --b_restrict
     pub b_restrict_right: Option<NonZeroUsize>,
 
-    #[arg(long, value_parser = validate_b_hdist, default_value = "0", requires = "barcode_trim")]
+    #[arg(long, value_parser = validate_hdist, default_value = "0", requires = "barcode_source")]
     /// Accepted Hamming distance for fuzzy barcode matching and trimming,
     /// between 0 and 3
     pub b_hdist: usize,
 
+    #[arg(long, value_name = "Q", requires = "barcode_trim", conflicts_with = "barcode_pairs")]
+    /// Forgive barcode mismatches at bases with a quality score below Q, so
+    /// that only mismatches at higher-quality bases count towards --b-hdist
+    pub b_quality_aware: Option<u8>,
+
     #[arg(short = 'A', long, value_parser = validate_acgtn, group = "adapter_vs_barcode")]
     /// Trim adapters and their reverse complements from sequence. Requires
     /// literal adapter as argument
     pub adapter_trim: Option<Nucleotides>,
 
-    #[arg(long, requires = "adapter_trim")]
-    /// Allow up to one mismatch during adapter matching and trimming
+    #[arg(long, requires = "adapter_trim", conflicts_with = "a_max_mismatch")]
+    /// Allow up to one mismatch during adapter matching and trimming. This is
+    /// equivalent to `--a-max-mismatch 1`
     pub a_fuzzy: bool,
 
+    #[arg(long, value_parser = validate_hdist, requires = "adapter_trim")]
+    /// Accepted Hamming distance for fuzzy adapter matching and trimming,
+    /// between 0 and 3. Exact matches are always preferred
+    pub a_max_mismatch: Option<usize>,
+
     #[arg(short = 'P', long, requires = "p_kmer_length")]
     /// Trim primers from sequence using k-mer matching. Requires path to primer
     /// fasta file and a kmer length
@@ -89,6 +122,11 @@ pub struct ClippingArgs {
     /// Length of k-mer used for matching primers.
     pub p_kmer_length: Option<usize>,
 
+    #[arg(long, requires = "primer_trim")]
+    /// Lowers the k-mer length to that of the shortest primer when any primer
+    /// is shorter than --p-kmer-length
+    pub p_kmer_auto: bool,
+
     #[arg(long, default_value = "b", requires = "primer_trim")]
     /// Specifies the end of the sequence for primer trimming : 'l' (left), 'r'
     /// (right), or 'b' (both)
@@ -121,10 +159,70 @@ pub struct ClippingArgs {
     /// Hard trim range for only the right end of the sequence. Overrides
     /// hard-trim
     pub h_right: Option<usize>,
+
+    #[arg(long)]
+    /// Hard trim from each end of R2 reads the specified number of bases.
+    /// Defaults to the amounts for R1
+    pub hard_trim_r2: Option<usize>,
+
+    #[arg(long)]
+    /// Hard trim range for only the left end of R2 reads. Overrides
+    /// hard-trim-r2
+    pub h_left_r2: Option<usize>,
+
+    #[arg(long)]
+    /// Hard trim range for only the right end of R2 reads. Overrides
+    /// hard-trim-r2
+    pub h_right_r2: Option<usize>,
+
+    #[arg(long, value_enum, value_delimiter = ',')]
+    /// Comma-separated order in which to apply the trimming operations, such as
+    /// `hard,polyg,adapter,primer`. Every configured operation must be listed
+    /// exactly once. Defaults to `polyg,adapter,barcode,primer,hard`
+    pub trim_order: Option<Vec<TrimStep>>,
+}
+
+/// How `--strict-bases` handles a read containing characters that are not
+/// IUPAC nucleotide codes.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StrictBases {
+    /// Stop with an error naming the read
+    Fail,
+    /// Filter the read
+    Drop,
 }
 
+/// A trimming operation, as listed in `--trim-order`.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrimStep {
+    /// Poly-G trimming (`--polyg-trim`)
+    Polyg,
+    /// Adapter trimming (`--adapter-trim`)
+    Adapter,
+    /// Barcode trimming (`--barcode-trim` or `--barcode-pairs`)
+    Barcode,
+    /// Primer trimming (`--primer-trim`)
+    Primer,
+    /// Hard trimming (`--hard-trim`, `--h-left`, `--h-right`, or their `-r2`
+    /// variants)
+    Hard,
+}
+
+/// The order of the trimming operations when `--trim-order` is not given.
+/// Operations that are not configured are skipped.
+const DEFAULT_TRIM_ORDER: [TrimStep; 5] = [
+    TrimStep::Polyg,
+    TrimStep::Adapter,
+    TrimStep::Barcode,
+    TrimStep::Primer,
+    TrimStep::Hard,
+];
+
 /// Enum for trimming end options
-#[derive(Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum TrimEnd {
     L, // Left
     R, // Right
@@ -150,7 +248,7 @@ impl ValueEnum for TrimEnd {
 
 /// Ensures user has entered valid non-empty adapter or barcode literal for
 /// trimming
-fn validate_acgtn(value: &str) -> Result<Nucleotides, String> {
+pub fn validate_acgtn(value: &str) -> Result<Nucleotides, String> {
     if value.trim().is_empty() {
         // prevents panicking when `-A ""` is passed
         Err("Adapter (-A) or barcode (-B) cannot be empty!".to_string())
@@ -175,15 +273,17 @@ fn validate_kmer_length(value: &str) -> Result<usize, String> {
     }
 }
 
-/// Validates barcode hamming distance to be between 0 and 3
-fn validate_b_hdist(value: &str) -> Result<usize, String> {
+/// Validates a barcode or adapter hamming distance to be between 0 and 3
+fn validate_hdist(value: &str) -> Result<usize, String> {
     let parsed = value
         .parse::<usize>()
         .map_err(|_| format!("`{value}` is not a valid integer between 0 and 3."))?;
     if (0..=3).contains(&parsed) {
         Ok(parsed)
     } else {
-        Err(format!("b-hdist must be between 0 and 3, but `{parsed}` was provided."))
+        Err(format!(
+            "The hamming distance must be between 0 and 3, but `{parsed}` was provided."
+        ))
     }
 }
 
@@ -204,18 +304,68 @@ fn get_forward_reverse_sequence(mut adapter: Nucleotides, preserve_seq: bool) ->
 /// included. This mismatch can involve the introduction of an ambiguous base
 /// 'N'.
 ///
+/// Primers shorter than `kmer_length` yield no k-mers, so a warning listing
+/// them is printed. If `auto_kmer` is set, the k-mer length is instead lowered
+/// to the length of the shortest primer.
+///
 /// ## Errors
 ///
 /// `primer_path` must be successfully opened, and all lines must be parsed
-/// without error.
+/// without error. An error is also returned if every primer is shorter than
+/// `kmer_length` and `auto_kmer` is not set, or if `auto_kmer` is set and the
+/// shortest primer is shorter than 2 bases.
 ///
 /// ## Panics
 ///
 /// `kmer_length` must be between 2 and [`MAX_KMER_LENGTH`], inclusive.
 fn prepare_primer_kmers(
-    primer_path: &PathBuf, kmer_length: usize, fuzzy_kmer: bool,
+    primer_path: &PathBuf, kmer_length: usize, fuzzy_kmer: bool, auto_kmer: bool,
 ) -> std::io::Result<ThreeBitKmerSet<MAX_KMER_LENGTH, SeedableRandomState>> {
-    let mut fasta_primer_reader = FastaReader::from_path(primer_path)?;
+    let primers = FastaReader::from_path(primer_path)?.collect::<std::io::Result<Vec<_>>>()?;
+
+    let shortest = primers
+        .iter()
+        .map(|primer| primer.sequence.len())
+        .min()
+        .unwrap_or(kmer_length);
+
+    let short_names = primers
+        .iter()
+        .filter(|primer| primer.sequence.len() < kmer_length)
+        .map(|primer| primer.name.as_str())
+        .collect::<Vec<_>>();
+
+    let kmer_length = if short_names.is_empty() {
+        kmer_length
+    } else if auto_kmer {
+        if shortest < 2 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "`--p-kmer-auto` cannot lower the k-mer length below 2, but these primers are shorter: {}",
+                    short_names.join(", ")
+                ),
+            ));
+        }
+        eprintln!(
+            "IRMA-core WARNING! Lowering the primer k-mer length from {kmer_length} to {shortest} to fit these primers: {}",
+            short_names.join(", ")
+        );
+        shortest
+    } else if short_names.len() == primers.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Every primer is shorter than the k-mer length {kmer_length}, so none can be trimmed. Lower `--p-kmer-length` or pass `--p-kmer-auto`"
+            ),
+        ));
+    } else {
+        eprintln!(
+            "IRMA-core WARNING! These primers are shorter than the k-mer length {kmer_length} and will not be trimmed: {}",
+            short_names.join(", ")
+        );
+        kmer_length
+    };
 
     let mut unique_kmers =
         ThreeBitKmerSet::<MAX_KMER_LENGTH, _>::with_hasher(kmer_length, get_hasher()).expect("Expected valid kmer length");
@@ -230,13 +380,12 @@ fn prepare_primer_kmers(
         }
     };
 
-    fasta_primer_reader.try_for_each(|f| -> Result<(), std::io::Error> {
-        let mut seq = Nucleotides::from_vec_unchecked(f?.sequence);
+    for primer in primers {
+        let mut seq = Nucleotides::from_vec_unchecked(primer.sequence);
         insert_fn(&mut unique_kmers, &seq);
         seq.make_reverse_complement();
         insert_fn(&mut unique_kmers, &seq);
-        Ok(())
-    })?;
+    }
 
     Ok(unique_kmers)
 }
@@ -244,46 +393,129 @@ fn prepare_primer_kmers(
 /// Arguments specifying the types of clipping to be performed
 #[derive(Debug)]
 pub struct ParsedClippingArgs {
-    pub preserve_bases:   bool,
-    pub barcodes:         Option<(Nucleotides, Nucleotides)>,
-    pub b_restrict_left:  Option<usize>,
-    pub b_restrict_right: Option<usize>,
-    pub b_hdist:          usize,
-    pub adapters:         Option<(Nucleotides, Nucleotides)>,
-    pub a_fuzzy:          bool,
-    pub primer_kmers:     Option<ThreeBitKmerSet<MAX_KMER_LENGTH, SeedableRandomState>>,
-    pub p_restrict_left:  Option<usize>,
-    pub p_restrict_right: Option<usize>,
-    pub polyg_left:       Option<usize>,
-    pub polyg_right:      Option<usize>,
-    pub hard_left:        usize,
-    pub hard_right:       usize,
+    pub preserve_bases:    bool,
+    /// How reads with non-IUPAC characters are handled, if they are checked
+    pub strict_bases:      Option<StrictBases>,
+    pub barcodes:          Option<(Nucleotides, Nucleotides)>,
+    pub barcode_pairs:     Option<BarcodePairs>,
+    /// Whether the sample assigned by `barcode_pairs` is appended to headers
+    pub b_annotate_sample: bool,
+    pub b_restrict_left:   Option<usize>,
+    pub b_restrict_right:  Option<usize>,
+    pub b_hdist:           usize,
+    /// The quality score below which barcode mismatches are forgiven
+    pub b_quality_aware:   Option<u8>,
+    pub adapters:          Option<(Nucleotides, Nucleotides)>,
+    /// The number of mismatches allowed when matching adapters
+    pub a_max_mismatch:    usize,
+    pub primer_kmers:      Option<ThreeBitKmerSet<MAX_KMER_LENGTH, SeedableRandomState>>,
+    pub p_restrict_left:   Option<usize>,
+    pub p_restrict_right:  Option<usize>,
+    pub polyg_left:        Option<usize>,
+    pub polyg_right:       Option<usize>,
+    pub hard_left:         usize,
+    pub hard_right:        usize,
+    /// The hard trim amounts for R2 reads, which default to those for R1
+    pub hard_left_r2:      usize,
+    pub hard_right_r2:     usize,
+    /// The order in which the trimming operations are applied
+    pub trim_order:        Vec<TrimStep>,
+}
+
+impl ParsedClippingArgs {
+    /// Whether the trimming operation `step` was configured on the command
+    /// line.
+    pub fn is_configured(&self, step: TrimStep) -> bool {
+        match step {
+            TrimStep::Polyg => self.polyg_left.is_some() || self.polyg_right.is_some(),
+            TrimStep::Adapter => self.adapters.is_some(),
+            TrimStep::Barcode => self.barcodes.is_some() || self.barcode_pairs.is_some(),
+            TrimStep::Primer => self.primer_kmers.is_some(),
+            TrimStep::Hard => self.hard_left > 0 || self.hard_right > 0 || self.hard_left_r2 > 0 || self.hard_right_r2 > 0,
+        }
+    }
+
+    /// The number of bases to hard trim from the left and right ends of a read
+    /// on `side`. Unpaired reads use the amounts for R1.
+    #[inline]
+    pub fn hard_trim(&self, side: ReadSide) -> (usize, usize) {
+        match side {
+            ReadSide::R1 | ReadSide::Unpaired => (self.hard_left, self.hard_right),
+            ReadSide::R2 => (self.hard_left_r2, self.hard_right_r2),
+        }
+    }
+}
+
+/// Validates `--trim-order` against the configured trimming operations, which
+/// must each be listed exactly once.
+fn validate_trim_order(order: &[TrimStep], args: &ParsedClippingArgs) -> std::io::Result<()> {
+    let name = |step: TrimStep| {
+        step.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    };
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+
+    for (i, &step) in order.iter().enumerate() {
+        if order[..i].contains(&step) {
+            return Err(invalid(format!(
+                "`{}` is listed more than once in `--trim-order`",
+                name(step)
+            )));
+        }
+        if !args.is_configured(step) {
+            return Err(invalid(format!(
+                "`{}` is listed in `--trim-order`, but its trimming operation was not configured",
+                name(step)
+            )));
+        }
+    }
+
+    if let Some(&step) = DEFAULT_TRIM_ORDER
+        .iter()
+        .find(|&&step| args.is_configured(step) && !order.contains(&step))
+    {
+        return Err(invalid(format!(
+            "The trimming operation `{}` is configured but is missing from `--trim-order`",
+            name(step)
+        )));
+    }
+
+    Ok(())
 }
 
 /// Parses all arguments related to clipping.
 ///
 /// ## Errors
 ///
-/// Any errors while processing the primers are propagated. The path is added as
-/// context.
+/// Any errors while processing the primers or reading the barcode pairs are
+/// propagated. The path is added as context. An error is also returned
+/// if `--trim-order` repeats an operation, lists one that was not configured,
+/// or omits one that was.
 pub fn parse_clipping_args(args: ClippingArgs) -> std::io::Result<ParsedClippingArgs> {
     let ClippingArgs {
         preserve_bases,
+        strict_bases,
         polyg_trim,
         g_polyg_end,
         g_polyg_left,
         g_polyg_right,
         barcode_trim,
+        barcode_pairs,
+        b_annotate_sample,
         b_end,
         b_restrict,
         b_restrict_left,
         b_restrict_right,
         b_hdist,
+        b_quality_aware,
         adapter_trim,
         a_fuzzy,
+        a_max_mismatch,
         primer_trim,
         p_fuzzy,
         p_kmer_length,
+        p_kmer_auto,
         p_end,
         p_restrict,
         p_restrict_left,
@@ -291,11 +523,22 @@ pub fn parse_clipping_args(args: ClippingArgs) -> std::io::Result<ParsedClipping
         hard_trim,
         h_left,
         h_right,
+        hard_trim_r2,
+        h_left_r2,
+        h_right_r2,
+        trim_order,
     } = args;
 
     let adapters = adapter_trim.map(|adapter| get_forward_reverse_sequence(adapter, preserve_bases));
     let barcodes = barcode_trim.map(|barcode| get_forward_reverse_sequence(barcode, preserve_bases));
 
+    let barcode_pairs = match &barcode_pairs {
+        Some(path) => Some(
+            BarcodePairs::from_tsv(path, preserve_bases).with_path_context("Failed to read the barcode pairs file", path)?,
+        ),
+        None => None,
+    };
+
     let primer_kmers = if let Some(primer_path) = &primer_trim {
         Some(
             prepare_primer_kmers(
@@ -303,6 +546,7 @@ pub fn parse_clipping_args(args: ClippingArgs) -> std::io::Result<ParsedClipping
                 // This is unreachable through clap due to being required
                 p_kmer_length.expect("A kmer length must be provided for primer trimming"),
                 p_fuzzy,
+                p_kmer_auto,
             )
             .with_path_context("Failed to read the primer file", primer_path)?,
         )
@@ -368,15 +612,23 @@ pub fn parse_clipping_args(args: ClippingArgs) -> std::io::Result<ParsedClipping
     let default_hard_bases = hard_trim.unwrap_or(0);
     let hard_left = h_left.unwrap_or(default_hard_bases);
     let hard_right = h_right.unwrap_or(default_hard_bases);
+    let hard_left_r2 = h_left_r2.or(hard_trim_r2).unwrap_or(hard_left);
+    let hard_right_r2 = h_right_r2.or(hard_trim_r2).unwrap_or(hard_right);
+
+    let a_max_mismatch = a_max_mismatch.unwrap_or(usize::from(a_fuzzy));
 
-    let parsed_args = ParsedClippingArgs {
+    let mut parsed_args = ParsedClippingArgs {
         preserve_bases,
+        strict_bases,
         barcodes,
+        barcode_pairs,
+        b_annotate_sample,
         b_restrict_left,
         b_restrict_right,
         b_hdist,
+        b_quality_aware,
         adapters,
-        a_fuzzy,
+        a_max_mismatch,
         primer_kmers,
         p_restrict_left,
         p_restrict_right,
@@ -384,7 +636,15 @@ pub fn parse_clipping_args(args: ClippingArgs) -> std::io::Result<ParsedClipping
         polyg_right,
         hard_left,
         hard_right,
+        hard_left_r2,
+        hard_right_r2,
+        trim_order: DEFAULT_TRIM_ORDER.to_vec(),
     };
 
+    if let Some(trim_order) = trim_order {
+        validate_trim_order(&trim_order, &parsed_args)?;
+        parsed_args.trim_order = trim_order;
+    }
+
     Ok(parsed_args)
 }
@@ -2,6 +2,7 @@ use crate::Cli;
 use clap::{CommandFactory, error::ErrorKind};
 
 pub(crate) mod clipping;
+pub(crate) mod quality_filter;
 
 /// Aborts clap with a given error `message` due to a custom parsing error.
 ///
@@ -0,0 +1,126 @@
+use clap::Args;
+use std::{num::NonZeroUsize, simd::prelude::*};
+
+#[derive(Args, Debug)]
+pub struct QualityFilterArgs {
+    #[arg(short = 'T', long, default_value_t = 0)]
+    /// Specify the read quality threshold (geometric mean, median).
+    pub min_read_quality: u8,
+
+    #[arg(short = 'M', long)]
+    /// Interprets the threshold (-T) as the median, not the geometric mean.
+    pub use_median: bool,
+
+    #[arg(short = 'n', long, default_value = "1")]
+    /// Minimum length of sequence read data, filtered otherwise.
+    pub min_length: NonZeroUsize,
+
+    #[arg(short = 'E', long, requires = "min_length")]
+    /// The minimum length threshold (-n) is enforced after all trimming.
+    /// `trimmer` always does so.
+    pub enforce_clipped_length: bool,
+
+    #[arg(long, value_name = "FRAC", value_parser = validate_n_fraction)]
+    /// Maximum fraction of ambiguous bases (N) in a read after trimming,
+    /// filtered otherwise.
+    pub max_n_frac: Option<f64>,
+}
+
+/// The parsed quality control filters, applied to each read in the same way by
+/// `preprocess` and `trimmer`.
+#[derive(Debug)]
+pub struct ParsedQualityFilterArgs {
+    pub min_read_quality:       u8,
+    pub use_median:             bool,
+    pub min_length:             usize,
+    pub enforce_clipped_length: bool,
+    pub max_n_frac:             Option<f64>,
+}
+
+impl ParsedQualityFilterArgs {
+    /// Whether a read of length `len` passes the length filter prior to
+    /// trimming.
+    #[inline]
+    pub fn passes_raw_length(&self, len: usize) -> bool {
+        len >= self.min_length
+    }
+
+    /// Whether a read of length `len` after trimming passes the length filter.
+    /// Empty reads are always filtered.
+    #[inline]
+    pub fn passes_clipped_length(&self, len: usize) -> bool {
+        len > 0 && !(self.enforce_clipped_length && len < self.min_length)
+    }
+
+    /// Whether a read with the quality center `q_center` (see
+    /// [`get_q_center`]) passes the quality filter. Reads without a quality
+    /// center are always filtered.
+    ///
+    /// [`get_q_center`]: irma_records::fastq::ReadTransforms::get_q_center
+    #[inline]
+    pub fn passes_quality(&self, q_center: Option<f32>) -> bool {
+        q_center >= Some(f32::from(self.min_read_quality))
+    }
+
+    /// Whether a trimmed read with the given sequence passes the
+    /// `--max-n-frac` filter. All reads pass if it was not given.
+    #[inline]
+    pub fn passes_n_fraction(&self, sequence: &[u8]) -> bool {
+        match self.max_n_frac {
+            Some(max_n_frac) => count_n(sequence) as f64 <= max_n_frac * sequence.len() as f64,
+            None => true,
+        }
+    }
+}
+
+/// The fraction of bases in `sequence` that are `N` (of either case), or 0 for
+/// an empty sequence.
+pub fn n_fraction(sequence: &[u8]) -> f64 {
+    if sequence.is_empty() {
+        0.0
+    } else {
+        count_n(sequence) as f64 / sequence.len() as f64
+    }
+}
+
+/// Counts the `N` and `n` bases in `sequence`, comparing 16 bytes at a time.
+fn count_n(sequence: &[u8]) -> usize {
+    let upper = Simd::splat(b'N');
+    let lower = Simd::splat(b'n');
+    let count_scalar = |bytes: &[u8]| bytes.iter().filter(|&&b| b == b'N' || b == b'n').count();
+
+    let (pre, mid, sfx) = sequence.as_simd::<16>();
+    let count: usize = mid
+        .iter()
+        .map(|&v| (v.simd_eq(upper) | v.simd_eq(lower)).to_bitmask().count_ones() as usize)
+        .sum();
+
+    count + count_scalar(pre) + count_scalar(sfx)
+}
+
+/// Validates `--max-n-frac` to be between 0 and 1.
+fn validate_n_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(frac) if (0.0..=1.0).contains(&frac) => Ok(frac),
+        Ok(_) => Err("Value must be between 0.0 and 1.0".to_string()),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+pub fn parse_quality_filter_args(args: QualityFilterArgs) -> ParsedQualityFilterArgs {
+    let QualityFilterArgs {
+        min_read_quality,
+        use_median,
+        min_length,
+        enforce_clipped_length,
+        max_n_frac,
+    } = args;
+
+    ParsedQualityFilterArgs {
+        min_read_quality,
+        use_median,
+        min_length: min_length.get(),
+        enforce_clipped_length,
+        max_n_frac,
+    }
+}
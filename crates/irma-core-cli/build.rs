@@ -0,0 +1,39 @@
+//! Embeds the git commit of the build as `IRMA_CORE_GIT_HASH`, so that outputs
+//! can be traced to the exact source that produced them. The hash can instead
+//! be given in the `IRMA_CORE_GIT_HASH` environment variable, such as when
+//! building from a source archive, and is `unknown` otherwise.
+
+use std::{path::Path, process::Command};
+
+/// Runs `git` with the given arguments, returning its trimmed output if it
+/// succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string())
+}
+
+fn main() {
+    println!("cargo::rerun-if-env-changed=IRMA_CORE_GIT_HASH");
+
+    // Rebuild when the checked out commit changes. Packed refs have no file of
+    // their own, and a missing file would rerun this script on every build
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        let head = std::iter::once("HEAD".to_string()).chain(git(&["symbolic-ref", "-q", "HEAD"]));
+        for file in head.map(|file| Path::new(&git_dir).join(file)) {
+            if file.exists() {
+                println!("cargo::rerun-if-changed={}", file.display());
+            }
+        }
+    }
+
+    let hash = std::env::var("IRMA_CORE_GIT_HASH")
+        .ok()
+        .filter(|hash| !hash.is_empty())
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo::rustc-env=IRMA_CORE_GIT_HASH={hash}");
+}
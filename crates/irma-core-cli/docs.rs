@@ -0,0 +1,430 @@
+//! Usage documentation rendered from the clap definitions, so that it ships
+//! inside the binary and cannot drift from the actual arguments. The hidden
+//! `docs` subcommand writes man pages, and `--help-long` prints the long help
+//! of a subcommand followed by its worked [`EXAMPLES`].
+
+use crate::Cli;
+use clap::{Args, Command, CommandFactory};
+use clap_mangen::Man;
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// The flag printing the long help of a subcommand along with its examples.
+/// It is handled before the arguments are parsed, so that it can be given
+/// without the required arguments of the subcommand.
+pub(crate) const HELP_LONG_FLAG: &str = "--help-long";
+
+#[derive(Args, Debug)]
+#[group(required = true, multiple = true)]
+pub struct DocsArgs {
+    #[arg(long, value_name = "DIR")]
+    /// Writes a man page for `irma-core` and for each subcommand to this
+    /// directory, including the worked examples
+    man_dir: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Checks that every example parses with the current arguments, and that
+    /// every subcommand has at least one example
+    check: bool,
+}
+
+/// A worked example of a subcommand.
+struct Example {
+    /// The name of the subcommand
+    subcommand:  &'static str,
+    /// What the example does, as a sentence
+    description: &'static str,
+    /// The arguments following the subcommand name
+    args:        &'static [&'static str],
+}
+
+impl Example {
+    /// The example as a shell command, quoting any empty arguments or those
+    /// with spaces.
+    fn command_line(&self) -> String {
+        let mut line = format!("irma-core {}", self.subcommand);
+        for arg in self.args {
+            if arg.is_empty() || arg.contains(' ') {
+                let _ = write!(line, " \"{arg}\"");
+            } else {
+                let _ = write!(line, " {arg}");
+            }
+        }
+        line
+    }
+}
+
+/// The worked examples of each subcommand, in the order they are shown.
+const EXAMPLES: &[Example] = &[
+    Example {
+        subcommand:  "preprocess",
+        description: "Trims adapters from paired reads, filters reads shorter than 50 bases, and deflates them to an XFL table and FASTA, with a log of the counts.",
+        args:        &[
+            "sample.xfl",
+            "sample_R1.fastq.gz",
+            "sample_R2.fastq.gz",
+            "--adapter-trim",
+            "CTGTCTCTTATACACATCT",
+            "--min-length",
+            "50",
+            "--log-file",
+            "sample.log",
+        ],
+    },
+    Example {
+        subcommand:  "preprocess",
+        description: "Infers the R2 file from the name of the R1 file, and filters reads with a median quality below 30.",
+        args:        &[
+            "sample.xfl",
+            "sample_S1_L001_R1_001.fastq.gz",
+            "--auto-r2",
+            "--min-read-quality",
+            "30",
+            "--use-median",
+        ],
+    },
+    Example {
+        subcommand:  "merge-sam",
+        description: "Merges the read pairs of an alignment against its reference, writing the merged SAM with the given prefix along with merge statistics.",
+        args:        &["reference.fasta", "aligned.sam", "merged/sample", "--store-stats"],
+    },
+    Example {
+        subcommand:  "xflate",
+        description: "Deflates reads to an XFL table, writing a FASTA of the unique sequences to STDOUT.",
+        args:        &["reads.xfl", "reads.fastq"],
+    },
+    Example {
+        subcommand:  "xflate",
+        description: "Reinflates the deflated sequences using the XFL table, writing FASTQ to STDOUT.",
+        args:        &["--inflate", "reads.xfl", "deflated.fasta"],
+    },
+    Example {
+        subcommand:  "phase",
+        description: "Assigns a phase to each variant of a gene, updating the variants table.",
+        args:        &["HA-variants.txt", "HA-EXPENRD.sqm"],
+    },
+    Example {
+        subcommand:  "num-procs",
+        description: "Prints the number of physical cores.",
+        args:        &["--physical"],
+    },
+    Example {
+        subcommand:  "trimmer",
+        description: "Hard trims 10 bases from each end, then trims an adapter and poly-G runs of at least 10 bases.",
+        args:        &[
+            "input.fastq",
+            "--output",
+            "trimmed.fastq",
+            "--hard-trim",
+            "10",
+            "--adapter-trim",
+            "CTGTCTCTTATACACATCT",
+            "--polyg-trim",
+            "10",
+        ],
+    },
+    Example {
+        subcommand:  "trimmer",
+        description: "Trims a barcode allowing up to two mismatches within 30 bases of either end.",
+        args:        &[
+            "input.fastq",
+            "--barcode-trim",
+            "CACAAAGACACCGACAACTTTCTT",
+            "--b-restrict",
+            "30",
+            "--b-hdist",
+            "2",
+        ],
+    },
+    Example {
+        subcommand:  "trim-eval",
+        description: "Compares no trimming, adapter trimming, and adapter trimming with poly-G trimming on a sample of reads.",
+        args:        &[
+            "sample_R1.fastq.gz",
+            "--flags",
+            "",
+            "--flags",
+            "-A CTGTCTCTTATACACATCT",
+            "--flags",
+            "-A CTGTCTCTTATACACATCT -G 12",
+        ],
+    },
+    Example {
+        subcommand:  "sampler",
+        description: "Downsamples paired reads to 10,000 pairs, writing two zipped outputs.",
+        args:        &[
+            "input_R1.fastq",
+            "input_R2.fastq",
+            "--output",
+            "sampled_R1.fastq.gz",
+            "--output2",
+            "sampled_R2.fastq.gz",
+            "--subsample-target",
+            "10000",
+        ],
+    },
+    Example {
+        subcommand:  "sampler",
+        description: "Produces nested samples of 1,000, 10,000, and 100,000 reads in a single pass.",
+        args:        &[
+            "input.fastq",
+            "--targets",
+            "1000,10000,100000",
+            "--output",
+            "sampled_{target}.fastq.gz",
+        ],
+    },
+    Example {
+        subcommand:  "xleave",
+        description: "De-interleaves paired reads into two files.",
+        args:        &["input.fastq.gz", "-1", "out_R1.fastq", "-2", "out_R2.fastq"],
+    },
+    Example {
+        subcommand:  "xleave",
+        description: "Interleaves paired reads into a single zipped file.",
+        args:        &["input_R1.fastq", "input_R2.fastq", "-o", "interleaved_out.fastq.gz"],
+    },
+    Example {
+        subcommand:  "aligner",
+        description: "Aligns reads against references with the given scores and gap penalties, writing SAM.",
+        args:        &[
+            "reference.fasta",
+            "query.fastq",
+            "--output",
+            "alignments.sam",
+            "-m",
+            "1",
+            "-x",
+            "1",
+            "-o",
+            "2",
+            "-e",
+            "1",
+        ],
+    },
+    Example {
+        subcommand:  "aligner",
+        description: "Aligns each read and its reverse complement, keeping the best alignment of each read.",
+        args:        &["references.fasta", "reads.fastq", "--best-match", "--rev-comp"],
+    },
+    Example {
+        subcommand:  "barcodes",
+        description: "Tabulates the index combinations of a run and estimates index hopping from the expected combinations.",
+        args:        &["run_R1.fastq.gz", "--expected", "samples.tsv", "--json", "hopping.json"],
+    },
+    Example {
+        subcommand:  "compare",
+        description: "Writes the records removed from and added to a file by some processing.",
+        args:        &[
+            "before.fastq.gz",
+            "after.fastq.gz",
+            "--diff-a",
+            "removed.fastq",
+            "--diff-b",
+            "added.fastq",
+        ],
+    },
+    Example {
+        subcommand:  "rc",
+        description: "Reverse complements the read pairs whose IDs are listed in a file.",
+        args:        &[
+            "R1.fastq.gz",
+            "R2.fastq.gz",
+            "--ids",
+            "reverse_ids.txt",
+            "-1",
+            "R1.rc.fastq.gz",
+            "-2",
+            "R2.rc.fastq.gz",
+        ],
+    },
+    Example {
+        subcommand:  "xfl-stats",
+        description: "Summarizes an XFL table as JSON, verifying its FASTA and reporting the 20 largest clusters.",
+        args:        &["sample.xfl", "sample.fa", "--top", "20", "--format", "json"],
+    },
+];
+
+/// The examples of the subcommand `name`.
+fn examples_for(name: &str) -> impl Iterator<Item = &'static Example> + '_ {
+    EXAMPLES.iter().filter(move |example| example.subcommand == name)
+}
+
+/// The root command, named after the binary and built so that the
+/// subcommands have their full names.
+fn built_command() -> Command {
+    let mut cmd = Cli::command()
+        .name("irma-core")
+        .bin_name("irma-core")
+        .disable_help_subcommand(true);
+    cmd.build();
+    cmd
+}
+
+/// Prints the long help of the subcommand named in `args` (or of `irma-core`
+/// if there is none) followed by its examples, and exits, if `args` contain
+/// [`HELP_LONG_FLAG`] before any `--`.
+pub(crate) fn print_help_long_if_requested(args: &[OsString]) {
+    let end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    if !args[..end].iter().any(|arg| arg == HELP_LONG_FLAG) {
+        return;
+    }
+
+    let mut cmd = built_command();
+    let subcommand = args[1..end]
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .find_map(|arg| cmd.find_subcommand(arg).map(|subcommand| subcommand.get_name().to_string()));
+
+    let mut out = String::new();
+    match subcommand {
+        Some(name) => {
+            // Validity: the name was found among the subcommands
+            let subcommand = cmd.find_subcommand_mut(&name).expect("The subcommand should exist");
+            let _ = write!(out, "{}", subcommand.render_long_help());
+            write_examples_text(&mut out, examples_for(&name));
+        }
+        None => {
+            let _ = write!(out, "{}", cmd.render_long_help());
+            write_examples_text(&mut out, EXAMPLES.iter());
+        }
+    }
+    print!("{out}");
+    std::process::exit(0)
+}
+
+/// Appends an `Examples:` section to the long help text, if there are any
+/// examples.
+fn write_examples_text<'a>(out: &mut String, examples: impl Iterator<Item = &'a Example>) {
+    let mut examples = examples.peekable();
+    if examples.peek().is_none() {
+        return;
+    }
+    out.push_str("\nExamples:\n");
+    for example in examples {
+        let _ = write!(out, "\n  {}\n\n      {}\n", example.description, example.command_line());
+    }
+}
+
+/// Escapes text for roff, so that backslashes and hyphens are printed as
+/// written and a leading `.` or `'` is not read as a request.
+fn roff_escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with(['.', '\'']) {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+/// Renders the man page of `cmd`, with an `EXAMPLES` section after the
+/// options and subcommands.
+fn render_man_page(cmd: Command, examples: &[&Example], writer: &mut dyn Write) -> std::io::Result<()> {
+    let has_options = cmd.get_arguments().any(|arg| !arg.is_hide_set());
+    let has_subcommands = cmd.get_subcommands().any(|subcommand| !subcommand.is_hide_set());
+    let has_version = cmd.get_version().is_some();
+    let has_authors = cmd.get_author().is_some();
+
+    let man = Man::new(cmd);
+    man.render_title(writer)?;
+    man.render_name_section(writer)?;
+    man.render_synopsis_section(writer)?;
+    man.render_description_section(writer)?;
+    if has_options {
+        man.render_options_section(writer)?;
+    }
+    if has_subcommands {
+        man.render_subcommands_section(writer)?;
+    }
+
+    if !examples.is_empty() {
+        writeln!(writer, ".SH EXAMPLES")?;
+        for example in examples {
+            writeln!(
+                writer,
+                ".PP\n{}\n.PP\n.RS 4\n.nf\n{}\n.fi\n.RE",
+                roff_escape(example.description),
+                roff_escape(&example.command_line())
+            )?;
+        }
+    }
+
+    if has_version {
+        man.render_version_section(writer)?;
+    }
+    if has_authors {
+        man.render_authors_section(writer)?;
+    }
+    Ok(())
+}
+
+/// Writes the man page of `cmd` to `dir`, returning its path.
+fn write_man_page(cmd: Command, examples: &[&Example], dir: &Path) -> std::io::Result<PathBuf> {
+    let path = dir.join(Man::new(cmd.clone()).get_filename());
+    let mut writer = BufWriter::new(File::create(&path)?);
+    render_man_page(cmd, examples, &mut writer)?;
+    writer.flush()?;
+    Ok(path)
+}
+
+/// Writes the man pages of `irma-core` and its visible subcommands to `dir`.
+fn write_man_pages(dir: &Path) -> std::io::Result<()> {
+    let cmd = built_command();
+    for subcommand in cmd.get_subcommands().filter(|subcommand| !subcommand.is_hide_set()) {
+        let examples = examples_for(subcommand.get_name()).collect::<Vec<_>>();
+        write_man_page(subcommand.clone(), &examples, dir)?;
+    }
+    let path = write_man_page(cmd, &[], dir)?;
+    eprintln!("Wrote man pages to {}", path.parent().unwrap_or(dir).display());
+    Ok(())
+}
+
+/// Checks that every example parses, and that every visible subcommand has an
+/// example, returning an error listing any problems.
+fn check_examples() -> std::io::Result<()> {
+    let cmd = built_command();
+    let mut problems = Vec::new();
+
+    for subcommand in cmd.get_subcommands().filter(|subcommand| !subcommand.is_hide_set()) {
+        if examples_for(subcommand.get_name()).next().is_none() {
+            problems.push(format!("The subcommand `{}` has no examples", subcommand.get_name()));
+        }
+    }
+
+    for example in EXAMPLES {
+        let args = ["irma-core", example.subcommand]
+            .into_iter()
+            .chain(example.args.iter().copied());
+        if let Err(e) = cmd.clone().try_get_matches_from(args) {
+            problems.push(format!(
+                "The example `{}` does not parse: {}",
+                example.command_line(),
+                e.render().to_string().trim_end()
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        eprintln!("All {} examples parse.", EXAMPLES.len());
+        Ok(())
+    } else {
+        Err(std::io::Error::other(problems.join("\n")))
+    }
+}
+
+/// Main process for the hidden `docs` subcommand.
+pub fn docs_process(args: DocsArgs) -> std::io::Result<()> {
+    if args.check {
+        check_examples()?;
+    }
+    if let Some(dir) = &args.man_dir {
+        std::fs::create_dir_all(dir)?;
+        write_man_pages(dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Reporting of fatal subcommand errors, either as text or (with
+//! `--error-format json`) as a single JSON object for orchestrators
+
+use clap::ValueEnum;
+use irma_records::io::{RecordNumberError, cleanup_temp_files};
+use serde::Serialize;
+use std::error::Error;
+use zoe::data::err::{GetCode, OrFail};
+
+/// The format in which fatal errors are written to stderr.
+#[derive(ValueEnum, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum ErrorFormat {
+    /// A human-readable message followed by the stack of context
+    #[default]
+    Text,
+    /// A single line containing a JSON object
+    Json,
+}
+
+/// A fatal error, as written by `--error-format json`.
+#[derive(Serialize)]
+struct JsonError<'a> {
+    /// The exit code of the process
+    code:       i32,
+    /// The name of the subcommand that failed
+    subcommand: &'a str,
+    /// The underlying cause of the error
+    message:    String,
+    /// The context added to the error, from the outermost to the innermost
+    context:    Vec<String>,
+    /// The file involved in the error, if known
+    path:       Option<String>,
+    /// The 1-based number of the record at which the error occurred, if known
+    record:     Option<usize>,
+}
+
+impl<'a> JsonError<'a> {
+    /// Collects the fields of a [`JsonError`] from `error` and its sources.
+    ///
+    /// The path is taken from the innermost context of the form `msg: 'path'`,
+    /// as added by [`with_path_context`], and the record number from any
+    /// [`RecordNumberError`] in the stack.
+    ///
+    /// [`with_path_context`]: zoe::data::err::WithErrorContext::with_path_context
+    fn new(error: &std::io::Error, subcommand: &'a str) -> Self {
+        let mut messages = Vec::new();
+        let mut path = None;
+        let mut record = None;
+
+        let mut source: Option<&(dyn Error + 'static)> = Some(error);
+        while let Some(err) = source {
+            if let Some(err) = err.downcast_ref::<RecordNumberError>() {
+                record = Some(err.record());
+            } else {
+                let message = err.to_string();
+                if let Some(found) = path_from_context(&message) {
+                    path = Some(found.to_string());
+                }
+                messages.push(message);
+            }
+            source = err.source();
+        }
+
+        let message = messages.pop().unwrap_or_default();
+
+        Self {
+            code: error.get_code(),
+            subcommand,
+            message,
+            context: messages,
+            path,
+            record,
+        }
+    }
+}
+
+/// Extracts the path from a context message of the form `msg: 'path'`.
+fn path_from_context(message: &str) -> Option<&str> {
+    let (_, quoted) = message.lines().next()?.split_once(": '")?;
+    quoted.strip_suffix('\'')
+}
+
+/// An extension trait for reporting fatal subcommand errors in the format
+/// given by `--error-format`.
+pub trait OrReport<T> {
+    /// Unwraps the result, or writes the error to stderr in the given
+    /// `format` and exits with its code. Any temporary files are removed
+    /// before exiting.
+    fn unwrap_or_report(self, subcommand: &str, format: ErrorFormat) -> T;
+}
+
+impl<T> OrReport<T> for std::io::Result<T> {
+    fn unwrap_or_report(self, subcommand: &str, format: ErrorFormat) -> T {
+        if self.is_err() {
+            cleanup_temp_files();
+        }
+
+        match (self, format) {
+            (Ok(out), _) => out,
+            (result @ Err(_), ErrorFormat::Text) => result.unwrap_or_die(&format!("subcommand '{subcommand}'")),
+            (Err(e), ErrorFormat::Json) => {
+                let error = JsonError::new(&e, subcommand);
+                // Serializing strings and integers cannot fail
+                eprintln!("{}", serde_json::to_string(&error).unwrap_or_default());
+                std::process::exit(error.code)
+            }
+        }
+    }
+}
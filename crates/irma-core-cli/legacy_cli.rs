@@ -0,0 +1,158 @@
+//! The `--legacy-cli` compatibility layer, which translates the subcommand
+//! names and flags of earlier IRMA tools into their current equivalents before
+//! the arguments are parsed, so that existing IRMA wrappers can switch to
+//! IRMA-core without rewriting every call site
+
+use crate::Cli;
+use clap::CommandFactory;
+use std::ffi::OsString;
+
+/// The flag enabling the translation.
+const LEGACY_CLI_FLAG: &str = "--legacy-cli";
+
+/// The legacy subcommand names, along with the subcommands replacing them.
+const LEGACY_SUBCOMMANDS: &[(&str, &str)] = &[
+    ("qc-trim-deflate", "preprocess"),
+    ("fastq_converter", "preprocess"),
+    ("fastQ_converter", "preprocess"),
+    ("mergeSAMpairs", "merge-sam"),
+];
+
+/// The legacy flags of each (current) subcommand, along with their
+/// replacements.
+const LEGACY_FLAGS: &[LegacyFlag] = &[
+    // Headers have been kept unconditionally since IRMA v1.1.2
+    LegacyFlag {
+        subcommand:  "preprocess",
+        legacy:      "--keep-header",
+        replacement: None,
+    },
+    LegacyFlag {
+        subcommand:  "trimmer",
+        legacy:      "--fastq-output-file",
+        replacement: Some("--fastq-output"),
+    },
+];
+
+/// A legacy flag of a subcommand.
+struct LegacyFlag {
+    /// The current name of the subcommand
+    subcommand:  &'static str,
+    /// The legacy flag, either short or long
+    legacy:      &'static str,
+    /// The flag replacing it, or `None` if the flag no longer has any effect
+    /// and is dropped
+    replacement: Option<&'static str>,
+}
+
+/// Translates the command line arguments if `--legacy-cli` is among them, and
+/// otherwise returns them unchanged.
+///
+/// A legacy subcommand name is replaced with the current name, and the legacy
+/// flags of the subcommand are replaced or dropped, both with a deprecation
+/// warning. Flags are matched as whole arguments or in the `--flag=value` form,
+/// and arguments after `--` are left as is. `--legacy-cli` itself is kept, so
+/// that it is accepted by the parser.
+pub(crate) fn translate_legacy_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let args = args.into_iter().collect::<Vec<_>>();
+    let end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+
+    if !args[..end].iter().any(|arg| arg == LEGACY_CLI_FLAG) {
+        return args;
+    }
+
+    let value_options = top_level_value_options();
+    let mut translated = Vec::with_capacity(args.len());
+    let mut rest = args.into_iter().enumerate();
+    let mut subcommand = None;
+
+    // The program name is never translated
+    translated.extend(rest.next().map(|(_, arg)| arg));
+
+    for (i, arg) in rest {
+        let Some(arg_str) = arg.to_str().filter(|_| i < end) else {
+            translated.push(arg);
+            continue;
+        };
+
+        match subcommand {
+            None if !arg_str.starts_with('-') && !follows_value_option(&translated, &value_options) => {
+                let name = match LEGACY_SUBCOMMANDS.iter().find(|(legacy, _)| *legacy == arg_str) {
+                    Some((legacy, current)) => {
+                        warn_deprecated(&format!("The subcommand `{legacy}` is deprecated, use `{current}` instead"));
+                        current
+                    }
+                    None => arg_str,
+                };
+                subcommand = Some(name.to_string());
+                translated.push(name.into());
+            }
+            Some(ref name) => {
+                let (flag, value) = match arg_str.split_once('=') {
+                    Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+                    _ => (arg_str, None),
+                };
+
+                match LEGACY_FLAGS
+                    .iter()
+                    .find(|shim| shim.subcommand == name && shim.legacy == flag)
+                {
+                    Some(LegacyFlag {
+                        replacement: Some(replacement),
+                        ..
+                    }) => {
+                        warn_deprecated(&format!("`{flag}` is deprecated for `{name}`, use `{replacement}` instead"));
+                        match value {
+                            Some(value) => translated.push(format!("{replacement}={value}").into()),
+                            None => translated.push(replacement.into()),
+                        }
+                    }
+                    Some(LegacyFlag { replacement: None, .. }) => {
+                        warn_deprecated(&format!("`{flag}` no longer has any effect for `{name}` and is ignored"));
+                    }
+                    None => translated.push(arg),
+                }
+            }
+            None => translated.push(arg),
+        }
+    }
+
+    translated
+}
+
+/// The top-level options taking a value (such as `--tmpdir`), in both their
+/// long and short forms, including aliases. These are read from the parser, so
+/// that options added later (or enabled by features) are always included.
+fn top_level_value_options() -> Vec<String> {
+    let command = Cli::command();
+    let mut options = Vec::new();
+    for arg in command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && arg.get_action().takes_values())
+    {
+        let longs = arg.get_long().into_iter().chain(arg.get_all_aliases().into_iter().flatten());
+        let shorts = arg
+            .get_short()
+            .into_iter()
+            .chain(arg.get_all_short_aliases().into_iter().flatten());
+        options.extend(longs.map(|long| format!("--{long}")));
+        options.extend(shorts.map(|short| format!("-{short}")));
+    }
+    options
+}
+
+/// Whether the last argument is a top-level option taking a separate value,
+/// in which case the next argument is its value rather than the subcommand.
+fn follows_value_option(translated: &[OsString], value_options: &[String]) -> bool {
+    translated
+        .last()
+        .is_some_and(|arg| value_options.iter().any(|option| arg == option.as_str()))
+}
+
+/// Prints a deprecation warning for a translated argument to stderr.
+fn warn_deprecated(message: &str) {
+    eprintln!("IRMA-core WARNING! {message} (translated by `{LEGACY_CLI_FLAG}`)");
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,82 @@
+use crate::legacy_cli::translate_legacy_args;
+use std::ffi::OsString;
+
+fn translate(args: &[&str]) -> Vec<OsString> {
+    translate_legacy_args(args.iter().map(OsString::from))
+}
+
+fn os_strings(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+}
+
+#[test]
+fn translate_subcommand() {
+    assert_eq!(
+        translate(&["irma-core", "--legacy-cli", "mergeSAMpairs", "--help"]),
+        os_strings(&["irma-core", "--legacy-cli", "merge-sam", "--help"])
+    );
+}
+
+#[test]
+fn translate_without_flag() {
+    let args = ["irma-core", "mergeSAMpairs", "--help"];
+    assert_eq!(translate(&args), os_strings(&args));
+}
+
+#[test]
+fn translate_after_value_options() {
+    assert_eq!(
+        translate(&[
+            "irma-core",
+            "--legacy-cli",
+            "--error-format",
+            "json",
+            "--tmpdir",
+            "/tmp",
+            "fastq_converter",
+            "--keep-header",
+        ]),
+        os_strings(&[
+            "irma-core",
+            "--legacy-cli",
+            "--error-format",
+            "json",
+            "--tmpdir",
+            "/tmp",
+            "preprocess"
+        ])
+    );
+
+    // Values given with `=` are part of the option itself
+    assert_eq!(
+        translate(&["irma-core", "--legacy-cli", "--tmpdir=/tmp", "mergeSAMpairs"]),
+        os_strings(&["irma-core", "--legacy-cli", "--tmpdir=/tmp", "merge-sam"])
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn translate_after_metrics_options() {
+    assert_eq!(
+        translate(&[
+            "irma-core",
+            "--legacy-cli",
+            "--metrics-interval",
+            "5",
+            "--metrics-prefix",
+            "irma",
+            "mergeSAMpairs",
+            "--help",
+        ]),
+        os_strings(&[
+            "irma-core",
+            "--legacy-cli",
+            "--metrics-interval",
+            "5",
+            "--metrics-prefix",
+            "irma",
+            "merge-sam",
+            "--help",
+        ])
+    );
+}
@@ -2,19 +2,56 @@
 #![allow(unreachable_patterns)]
 #![feature(portable_simd)]
 
-use crate::processes::{
-    aligner::*, merge_sam_pairs::*, num_procs::*, phase::*, preprocess::*, trimmer::*, xflate::*, xleave::*,
+use crate::{
+    docs::{DocsArgs, docs_process, print_help_long_if_requested},
+    error_format::{ErrorFormat, OrReport},
+    legacy_cli::translate_legacy_args,
+    panic_report::{PanicContext, install_panic_report, note_record},
+    processes::{
+        aligner::*, barcodes::*, compare::*, merge_sam_pairs::*, num_procs::*, phase::*, preprocess::*, rc::*, trimmer::*,
+        xfl_stats::*, xflate::*, xleave::*,
+    },
 };
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint};
+use irma_records::io::{install_cleanup_hook, set_record_hook, set_temp_dir};
 use processes::sampler::{SamplerArgs, sampler_process};
-use zoe::data::err::OrFail;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(after_long_help = "Pass `--help-long` after a subcommand to show its help along with worked examples.")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(long, global = true, value_enum, default_value_t)]
+    /// The format of fatal error messages written to stderr. `json` writes a
+    /// single JSON object with the exit code, subcommand, message, and the
+    /// offending path and record number where available
+    error_format: ErrorFormat,
+
+    #[arg(long = "legacy-cli", global = true)]
+    /// Accepts the subcommand names and flags of earlier IRMA tools (such as
+    /// `qc-trim-deflate` or `mergeSAMpairs`), translating them to their
+    /// current equivalents with a deprecation warning
+    _legacy_cli: bool,
+
+    #[arg(long, global = true, value_name = "DIR", value_hint = ValueHint::DirPath, value_parser = parse_tmpdir)]
+    /// The directory for any temporary files, which are otherwise placed beside
+    /// the outputs they belong to. Temporary files are removed on exit, even
+    /// after an error or panic
+    tmpdir: Option<PathBuf>,
+
+    #[arg(long, global = true)]
+    /// Replaces the file paths in the arguments recorded by a panic report with
+    /// `<path>` (keeping their extensions), for sharing the report without
+    /// revealing the directory layout or sample names
+    redact_paths: bool,
+
+    #[cfg(feature = "metrics")]
+    #[command(flatten)]
+    metrics: metrics::MetricsArgs,
 }
 
 #[derive(Subcommand, Debug)]
@@ -41,33 +78,110 @@ enum Commands {
     )]
     /// Read FastQ files and trim with various options.
     Trimmer(TrimmerArgs),
+    /// Compares trimming configurations on a sample of reads, reporting the
+    /// reads dropped, bases removed, adapter remnants, and quality of each.
+    TrimEval(TrimEvalArgs),
     /// Randomly downsamples FastQ or FASTA files. Deinterleaving supported.
     Sampler(SamplerArgs),
     /// Interleaves or De-interleaves paired FastQ or FASTA files.
     Xleave(XleaveArgs),
+    /// Summarizes an XFL table, reporting its cluster sizes and the runs of
+    /// its largest clusters.
+    XflStats(XflStatsArgs),
     /// Performs sequence alignment.
     Aligner(AlignerArgs),
+    /// Tabulates index combinations and estimates the rate of index hopping.
+    Barcodes(BarcodesArgs),
+    /// Compares two FastQ or FASTA files record by record, reporting records
+    /// unique to either file and shared records that differ.
+    Compare(CompareArgs),
+    /// Reverse complements FastQ or FASTA records, optionally only those
+    /// matching an ID list or regular expression.
+    Rc(RcArgs),
+    /// Writes man pages for irma-core and each subcommand, or checks the
+    /// worked examples shown by `--help-long`.
+    #[command(hide = true)]
+    Docs(DocsArgs),
+}
+
+/// Parses `--tmpdir`, which must be an existing directory.
+fn parse_tmpdir(value: &str) -> Result<PathBuf, String> {
+    let dir = PathBuf::from(value);
+    if dir.is_dir() {
+        Ok(dir)
+    } else {
+        Err(format!("The temporary directory `{value}` does not exist."))
+    }
 }
 
 fn main() {
-    let args = Cli::parse();
-    match args.command {
-        Commands::Preprocess(cmd_args) => preprocess_process(cmd_args).unwrap_or_die("subcommand 'preprocess'"),
-        Commands::MergeSAM(cmd_args) => merge_sam_pairs_process(cmd_args).unwrap_or_die("subcommand 'merge-sam'"),
-        Commands::Xflate(cmd_args) => xflate_process(cmd_args).unwrap_or_die("subcommand 'xflate'"),
-        Commands::Trimmer(cmd_args) => trimmer_process(cmd_args).unwrap_or_die("subcommand 'trimmer'"),
-        Commands::Sampler(cmd_args) => sampler_process(cmd_args).unwrap_or_die("subcommand 'sampler'"),
-        Commands::NumProcs(cmd_args) => num_procs_process(cmd_args).unwrap_or_die("subcommand 'num-procs'"),
-        Commands::Xleave(cmd_args) => xleave_process(cmd_args).unwrap_or_die("subcommand 'xleave'"),
-        Commands::Aligner(cmd_args) => aligner_process(cmd_args).unwrap_or_die("subcommand 'aligner'"),
-        Commands::Phase(cmd_args) => phase_process(cmd_args).unwrap_or_die("subcommand 'phase'"),
+    let args = translate_legacy_args(std::env::args_os());
+    print_help_long_if_requested(&args);
+
+    // Parsed through the matches, so that the subcommand's name is known for
+    // any panic report
+    let matches = Cli::command().get_matches_from(&args);
+    let subcommand = matches.subcommand_name().unwrap_or("irma-core").to_string();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let Cli {
+        command,
+        error_format,
+        tmpdir,
+        redact_paths,
+        #[cfg(feature = "metrics")]
+        metrics,
+        ..
+    } = cli;
+
+    install_cleanup_hook();
+    // Installed after the cleanup hook, so that temporary files are removed
+    // before the report is written
+    install_panic_report(PanicContext {
+        subcommand,
+        args,
+        redact_paths,
+        dir: tmpdir.clone(),
+    });
+    set_record_hook(note_record);
+    if let Some(tmpdir) = tmpdir {
+        // The directory was checked when parsing
+        set_temp_dir(tmpdir).unwrap_or_report("irma-core", error_format);
+    }
+
+    // Held until the subcommand finishes, when the final metrics are sent
+    #[cfg(feature = "metrics")]
+    let _metrics = metrics.start().unwrap_or_report("irma-core", error_format);
+
+    match command {
+        Commands::Preprocess(cmd_args) => preprocess_process(cmd_args).unwrap_or_report("preprocess", error_format),
+        Commands::MergeSAM(cmd_args) => merge_sam_pairs_process(cmd_args).unwrap_or_report("merge-sam", error_format),
+        Commands::Xflate(cmd_args) => xflate_process(cmd_args).unwrap_or_report("xflate", error_format),
+        Commands::Trimmer(cmd_args) => trimmer_process(cmd_args).unwrap_or_report("trimmer", error_format),
+        Commands::TrimEval(cmd_args) => trim_eval_process(cmd_args).unwrap_or_report("trim-eval", error_format),
+        Commands::Sampler(cmd_args) => sampler_process(cmd_args).unwrap_or_report("sampler", error_format),
+        Commands::NumProcs(cmd_args) => num_procs_process(cmd_args).unwrap_or_report("num-procs", error_format),
+        Commands::Xleave(cmd_args) => xleave_process(cmd_args).unwrap_or_report("xleave", error_format),
+        Commands::XflStats(cmd_args) => xfl_stats_process(cmd_args).unwrap_or_report("xfl-stats", error_format),
+        Commands::Aligner(cmd_args) => aligner_process(cmd_args).unwrap_or_report("aligner", error_format),
+        Commands::Phase(cmd_args) => phase_process(cmd_args).unwrap_or_report("phase", error_format),
+        Commands::Barcodes(cmd_args) => barcodes_process(cmd_args).unwrap_or_report("barcodes", error_format),
+        Commands::Compare(cmd_args) => compare_process(cmd_args).unwrap_or_report("compare", error_format),
+        Commands::Rc(cmd_args) => rc_process(cmd_args).unwrap_or_report("rc", error_format),
+        Commands::Docs(cmd_args) => docs_process(cmd_args).unwrap_or_report("docs", error_format),
         _ => {
-            eprintln!("IRMA-CORE: unrecognized command {:?}", args.command);
+            eprintln!("IRMA-CORE: unrecognized command {command:?}");
             std::process::exit(1)
         }
     }
 }
 
+mod docs;
+mod error_format;
+mod legacy_cli;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod panic_report;
 mod processes;
 
 pub(crate) mod args;
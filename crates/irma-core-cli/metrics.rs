@@ -0,0 +1,176 @@
+//! Optional reporting of progress metrics for long-running invocations, either
+//! as statsd gauges sent over UDP or from a local HTTP status endpoint. The
+//! number of records read is taken from [`records_read`], which is updated by
+//! every input opened through `irma_records`.
+
+use clap::Args;
+use irma_records::io::records_read;
+use serde::Serialize;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    num::NonZeroU64,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+#[derive(Args, Debug)]
+pub struct MetricsArgs {
+    #[arg(long, global = true, value_name = "HOST:PORT")]
+    /// Sends the records read, throughput, and elapsed time as statsd gauges
+    /// over UDP to this address every `--metrics-interval` seconds
+    metrics_statsd: Option<String>,
+
+    #[arg(long, global = true, value_name = "ADDR:PORT")]
+    /// Serves the records read, throughput, and elapsed time as a JSON object
+    /// over HTTP on this local address
+    metrics_http: Option<SocketAddr>,
+
+    #[arg(long, global = true, value_name = "SECS", default_value = "10")]
+    /// The number of seconds between statsd emissions
+    metrics_interval: NonZeroU64,
+
+    #[arg(long, global = true, value_name = "PREFIX", default_value = "irma_core")]
+    /// The prefix of the statsd gauge names
+    metrics_prefix: String,
+}
+
+/// A snapshot of the progress of the process.
+#[derive(Serialize, Debug)]
+struct Snapshot {
+    /// The number of records read so far
+    records_read:    u64,
+    /// The mean number of records read per second since the process started
+    records_per_sec: f64,
+    /// The number of seconds since the process started
+    elapsed_secs:    f64,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the progress since `start`.
+    fn take(start: Instant) -> Self {
+        let records_read = records_read();
+        let elapsed_secs = start.elapsed().as_secs_f64();
+        let records_per_sec = if elapsed_secs > 0.0 {
+            records_read as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+        Self {
+            records_read,
+            records_per_sec,
+            elapsed_secs,
+        }
+    }
+
+    /// Formats the snapshot as statsd gauges, one per line.
+    fn to_statsd(&self, prefix: &str) -> String {
+        format!(
+            "{prefix}.records_read:{}|g\n{prefix}.records_per_sec:{:.3}|g\n{prefix}.elapsed_secs:{:.3}|g",
+            self.records_read, self.records_per_sec, self.elapsed_secs
+        )
+    }
+}
+
+/// The background threads reporting metrics. When dropped, a final statsd
+/// emission is sent. The HTTP endpoint is served until the process exits.
+pub struct MetricsReporter {
+    /// Signals the statsd thread to send its final emission and stop
+    stop:   Option<Sender<()>>,
+    /// The statsd thread
+    statsd: Option<JoinHandle<()>>,
+}
+
+impl MetricsArgs {
+    /// Starts reporting metrics to the sinks requested, if any.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the statsd address cannot be resolved or the
+    /// HTTP address cannot be bound.
+    pub fn start(self) -> std::io::Result<Option<MetricsReporter>> {
+        let MetricsArgs {
+            metrics_statsd,
+            metrics_http,
+            metrics_interval,
+            metrics_prefix,
+        } = self;
+
+        if metrics_statsd.is_none() && metrics_http.is_none() {
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+
+        if let Some(addr) = metrics_http {
+            let listener = TcpListener::bind(addr)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to bind the metrics endpoint to {addr}: {e}")))?;
+            std::thread::spawn(move || serve_http(&listener, start));
+        }
+
+        let (stop, statsd) = match metrics_statsd {
+            Some(addr) => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.connect(&addr).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("Failed to resolve the statsd address {addr}: {e}"))
+                })?;
+                let (stop, stopped) = mpsc::channel();
+                let interval = Duration::from_secs(metrics_interval.get());
+                let handle = std::thread::spawn(move || {
+                    loop {
+                        let finished = !matches!(stopped.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+                        // A metrics sink being unavailable should not fail the
+                        // process, so send errors are ignored
+                        let _ = socket.send(Snapshot::take(start).to_statsd(&metrics_prefix).as_bytes());
+                        if finished {
+                            break;
+                        }
+                    }
+                });
+                (Some(stop), Some(handle))
+            }
+            None => (None, None),
+        };
+
+        Ok(Some(MetricsReporter { stop, statsd }))
+    }
+}
+
+impl Drop for MetricsReporter {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(handle) = self.statsd.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Responds to each connection on `listener` with the current [`Snapshot`] as
+/// JSON, regardless of the request path.
+fn serve_http(listener: &TcpListener, start: Instant) {
+    for stream in listener.incoming().flatten() {
+        // A failed response only affects that client
+        let _ = respond(stream, start);
+    }
+}
+
+/// Reads the request headers from `stream` and writes the response.
+fn respond(mut stream: TcpStream, start: Instant) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let body = serde_json::to_string(&Snapshot::take(start))?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,228 @@
+//! Diagnostic reports written when a subcommand panics, so that rare,
+//! data-dependent crashes can be reported without sharing the whole dataset.
+//!
+//! The report records the version, subcommand, arguments, panic message, and a
+//! backtrace, along with the IDs of the last records processed by the panicking
+//! thread (see [`note_record`]).
+
+use jiff::Zoned;
+use std::{
+    backtrace::Backtrace,
+    cell::RefCell,
+    ffi::OsString,
+    fmt::Write as _,
+    panic::PanicHookInfo,
+    path::{Path, PathBuf},
+    sync::Once,
+};
+
+/// The number of record IDs remembered by each thread.
+const RECENT_RECORDS: usize = 32;
+
+/// Ensures that only the first panic writes a report, since a panic on one
+/// thread often causes others.
+static REPORT: Once = Once::new();
+
+thread_local! {
+    /// The IDs of the last records processed by the current thread.
+    static RECENT: RefCell<RecentRecords> = const { RefCell::new(RecentRecords::new()) };
+}
+
+/// A ring buffer of the IDs of the last [`RECENT_RECORDS`] records. The
+/// buffers of the IDs are reused once full, so that noting a record does not
+/// allocate.
+struct RecentRecords {
+    ids:  Vec<String>,
+    /// The position of the next ID to overwrite, once the buffer is full
+    next: usize,
+}
+
+impl RecentRecords {
+    const fn new() -> Self {
+        RecentRecords {
+            ids:  Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Adds an ID, unless it is the same as the newest ID, since a record is
+    /// noted both when it is read and when its processing starts.
+    fn push(&mut self, id: &str) {
+        if self.newest().is_some_and(|newest| newest == id) {
+            return;
+        }
+
+        if self.ids.len() < RECENT_RECORDS {
+            self.ids.push(id.to_string());
+        } else {
+            let slot = &mut self.ids[self.next];
+            slot.clear();
+            slot.push_str(id);
+            self.next = (self.next + 1) % RECENT_RECORDS;
+        }
+    }
+
+    /// The most recently added ID.
+    fn newest(&self) -> Option<&String> {
+        if self.ids.len() < RECENT_RECORDS {
+            self.ids.last()
+        } else {
+            self.ids.get((self.next + RECENT_RECORDS - 1) % RECENT_RECORDS)
+        }
+    }
+
+    /// The IDs from the oldest to the newest.
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.ids[self.next..].iter().chain(&self.ids[..self.next])
+    }
+}
+
+/// Notes that the current thread is processing the record with the given ID,
+/// so that it is listed if a panic report is written. This is called for every
+/// record read from an input (see [`set_record_hook`]), and again when the
+/// record is traced (see [`ReadTracer::start`]), since records may be
+/// processed on a different thread than they are read on.
+///
+/// [`set_record_hook`]: irma_records::io::set_record_hook
+/// [`ReadTracer::start`]: crate::processes::shared::trace::ReadTracer::start
+#[inline]
+pub(crate) fn note_record(id: &str) {
+    // The buffer is unavailable while the thread is being torn down, in which
+    // case the record is not noted
+    let _ = RECENT.try_with(|recent| {
+        if let Ok(mut recent) = recent.try_borrow_mut() {
+            recent.push(id);
+        }
+    });
+}
+
+/// The context of the run included in a panic report.
+pub(crate) struct PanicContext {
+    /// The name of the subcommand being run
+    pub(crate) subcommand:   String,
+    /// The command line arguments, after any legacy translation
+    pub(crate) args:         Vec<OsString>,
+    /// Whether paths in the arguments are redacted, from `--redact-paths`
+    pub(crate) redact_paths: bool,
+    /// The directory in which the report is written, from `--tmpdir`, or
+    /// otherwise the system's temporary directory
+    pub(crate) dir:          Option<PathBuf>,
+}
+
+/// Installs a panic hook which, after running the previous hook, writes a
+/// diagnostic report for the first panic and prints its path to stderr.
+pub(crate) fn install_panic_report(context: PanicContext) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        REPORT.call_once(|| write_report(&context, info));
+    }));
+}
+
+/// Writes the report for a panic, printing where it was written, or why it
+/// could not be. The report is best effort, so failures are not fatal.
+fn write_report(context: &PanicContext, info: &PanicHookInfo<'_>) {
+    let dir = context.dir.clone().unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!(
+        "irma-core-panic-{subcommand}-{pid}.txt",
+        subcommand = context.subcommand,
+        pid = std::process::id()
+    ));
+
+    match std::fs::write(&path, format_report(context, info)) {
+        Ok(()) => eprintln!(
+            "IRMA-core: a diagnostic report was written to {path}. Please attach it when reporting this bug.",
+            path = path.display()
+        ),
+        Err(e) => eprintln!(
+            "IRMA-core: a diagnostic report could not be written to {path}. See: {e}",
+            path = path.display()
+        ),
+    }
+}
+
+/// Formats the report for a panic, which is plain text with a section for the
+/// recent records and for the backtrace.
+fn format_report(context: &PanicContext, info: &PanicHookInfo<'_>) -> String {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => (*message).to_string(),
+        None => info
+            .payload()
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "(no message)".to_string()),
+    };
+    let location = info.location().map_or_else(|| "(unknown)".to_string(), ToString::to_string);
+    let thread = std::thread::current();
+    let args = context
+        .args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let arg = arg.to_string_lossy();
+            match (context.redact_paths, i) {
+                // The program itself is only named
+                (true, 0) => Path::new(arg.as_ref())
+                    .file_name()
+                    .map_or_else(|| arg.to_string(), |name| name.to_string_lossy().into_owned()),
+                (true, _) => redact_arg(&arg),
+                (false, _) => arg.into_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // Writing to a String cannot fail
+    let mut report = String::new();
+    let _ = writeln!(report, "IRMA-core panic report");
+    let _ = writeln!(report, "time\t{now}", now = Zoned::now().strftime("%Y-%m-%d %H:%M:%S %Z"));
+    let _ = writeln!(report, "version\t{}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "platform\t{}-{}", std::env::consts::OS, std::env::consts::ARCH);
+    let _ = writeln!(report, "subcommand\t{}", context.subcommand);
+    let _ = writeln!(report, "arguments\t{args}");
+    let _ = writeln!(report, "thread\t{}", thread.name().unwrap_or("(unnamed)"));
+    let _ = writeln!(report, "location\t{location}");
+    let _ = writeln!(report, "message\t{message}");
+
+    let _ = writeln!(
+        report,
+        "\n# The last records processed by the panicking thread (at most {RECENT_RECORDS}), oldest first"
+    );
+    let _ = RECENT.try_with(|recent| match recent.try_borrow() {
+        Ok(recent) if recent.ids.is_empty() => {
+            let _ = writeln!(report, "(none)");
+        }
+        Ok(recent) => {
+            for id in recent.iter() {
+                let _ = writeln!(report, "{id}");
+            }
+        }
+        Err(_) => {
+            let _ = writeln!(report, "(unavailable)");
+        }
+    });
+
+    let _ = writeln!(report, "\n# Backtrace\n{}", Backtrace::force_capture());
+    report
+}
+
+/// Replaces an argument (or the value of a `--flag=value` argument) with
+/// `<path>` if it looks like a path, keeping its extension so that the file
+/// type is still known.
+fn redact_arg(arg: &str) -> String {
+    if let Some((flag, value)) = arg.split_once('=')
+        && flag.starts_with('-')
+    {
+        return format!("{flag}={value}", value = redact_arg(value));
+    }
+
+    let path = Path::new(arg);
+    if arg.starts_with('-') || !(arg.contains(std::path::MAIN_SEPARATOR) || arg.contains('/') || path.exists()) {
+        return arg.to_string();
+    }
+
+    match path.extension() {
+        Some(extension) => format!("<path>.{}", extension.to_string_lossy()),
+        None => "<path>".to_string(),
+    }
+}
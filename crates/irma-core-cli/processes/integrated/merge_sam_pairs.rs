@@ -2,14 +2,30 @@
 //! detection.
 
 use clap::Args;
+use foldhash::fast::SeedableRandomState;
 use irma_records::{
     hashing::get_hasher,
     io::{InputOptions, OutputOptions, ValidatePaths},
     paired::get_molecular_id_side,
-    sam::{PairedMergeStats, SamMergeablePairs},
+    sam::{CigarValidationError, PairedMergeStats, SamMergeablePairs, TypedCigar},
 };
-use std::{collections::HashMap, io::Write, path::PathBuf};
-use zoe::data::{sam::*, views::Len};
+use rayon::iter::{ParallelBridge, ParallelIterator};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    io::Write,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::mpsc::{Receiver, sync_channel},
+};
+use zoe::{
+    data::{cigar::LenInAlignment, err::ResultWithErrorContext, sam::*, views::Len},
+    prelude::{FastQ, Nucleotides},
+};
+
+/// The number of merged pairs that may be waiting to be written before the
+/// merging threads wait for the writer.
+const MERGED_CHANNEL_CAPACITY: usize = 4096;
 
 #[derive(Args, Debug)]
 pub struct MergeSAMArgs {
@@ -30,6 +46,28 @@ pub struct MergeSAMArgs {
     #[arg(short = 'B', long)]
     /// SAM is in bowtie format.
     bowtie_format: bool,
+
+    #[arg(long, value_name = "PATH")]
+    /// Also write each merged pair as a single error-corrected FASTQ record.
+    /// The output is zipped if the path ends in `.gz`.
+    fastq_out: Option<PathBuf>,
+
+    #[arg(long, value_name = "N")]
+    /// Only merge pairs whose alignments span at most N reference bases (the
+    /// insert size). Other pairs are written unmerged with a `YD:Z` tag.
+    max_insert: Option<NonZeroUsize>,
+
+    #[arg(long)]
+    /// Only merge pairs whose reads align to opposite strands and face each
+    /// other. Other pairs are written unmerged with a `YD:Z` tag.
+    require_proper_orientation: bool,
+
+    #[arg(long)]
+    /// Annotate each merged record with the decisions of its merge: the number
+    /// of overlapping positions (`YO:i`), disagreements between the reads
+    /// (`YC:i`), and disagreements resolved in favor of R1 (`Y1:i`) and R2
+    /// (`Y2:i`).
+    merge_tags: bool,
 }
 
 struct ParsedMergeSamArgs {
@@ -48,6 +86,15 @@ struct ParsedMergeSamArgs {
 
     /// SAM is in bowtie format.
     bowtie_format: bool,
+
+    /// If `Some`, the file to output the merged pairs as FASTQ.
+    fastq_out: Option<PathBuf>,
+
+    /// The restrictions on which pairs are merged.
+    pair_filter: PairFilter,
+
+    /// Whether merged records are annotated with their merge decisions.
+    merge_tags: bool,
 }
 
 fn parse_merge_sam_args(args: MergeSAMArgs) -> ParsedMergeSamArgs {
@@ -57,6 +104,12 @@ fn parse_merge_sam_args(args: MergeSAMArgs) -> ParsedMergeSamArgs {
         merged_sam_file:   args.output_prefix.with_extension("sam"),
         paired_stats_file: args.store_stats.then(|| args.output_prefix.with_extension("stats")),
         bowtie_format:     args.bowtie_format,
+        fastq_out:         args.fastq_out,
+        pair_filter:       PairFilter {
+            max_insert:                 args.max_insert.map(NonZeroUsize::get),
+            require_proper_orientation: args.require_proper_orientation,
+        },
+        merge_tags:        args.merge_tags,
     }
 }
 
@@ -68,8 +121,9 @@ impl ValidatePaths for ParsedMergeSamArgs {
     fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
         let merged_sam_file = std::iter::once(&self.merged_sam_file);
         let paired_stats_file = self.paired_stats_file.iter();
+        let fastq_out = self.fastq_out.iter();
 
-        merged_sam_file.chain(paired_stats_file)
+        merged_sam_file.chain(paired_stats_file).chain(fastq_out)
     }
 }
 
@@ -108,8 +162,19 @@ pub fn merge_sam_pairs_process(args: MergeSAMArgs) -> Result<(), std::io::Error>
         .use_file()
         .open()?;
 
+    let mut fastq_writer = args
+        .fastq_out
+        .as_ref()
+        .map(|path| {
+            OutputOptions::new_from_opt_path(Some(path))
+                .with_capacity(ONE_MB)
+                .use_file_zip_or_stdout()
+                .open()
+        })
+        .transpose()?;
+
     let mut sam_data: Vec<SamData> = Vec::new();
-    let mut pairs: HashMap<String, IndexPair, _> = HashMap::with_hasher(get_hasher());
+    let mut pairs = IndexPairs::new();
     let mut index = 0;
 
     let sam_records = InputOptions::new_from_path(&args.sam_file).use_file().parse_sam().open()?;
@@ -120,6 +185,8 @@ pub fn merge_sam_pairs_process(args: MergeSAMArgs) -> Result<(), std::io::Error>
                 if d.rname != reference.name {
                     continue;
                 }
+                validate_cigar(&d)
+                    .with_path_context(format!("Invalid CIGAR for read '{}' in file", d.qname), &args.sam_file)?;
                 d
             }
             SamRow::Header(h) => {
@@ -131,24 +198,15 @@ pub fn merge_sam_pairs_process(args: MergeSAMArgs) -> Result<(), std::io::Error>
         match get_molecular_id_side(&row.qname, '0') {
             Some((mol_name_id, read_side @ ('1' | '2'))) => {
                 let new_pair = read_side.into_indexpair(index);
-                pairs
-                    .entry(mol_name_id.to_string())
-                    .and_modify(|old_pair| old_pair.merge(&new_pair))
-                    .or_insert(new_pair);
+                pairs.add(mol_name_id, new_pair, new_pair);
             }
             // If the header is parseable but does not encode an explicit read
             // side, fall back to order-based pairing instead of panicking.
             Some((mol_name_id, _)) => {
-                pairs
-                    .entry(mol_name_id.to_string())
-                    .and_modify(|pair| pair.merge(&IndexPair::new_r2(index)))
-                    .or_insert(IndexPair::new_r1(index));
+                pairs.add(mol_name_id, IndexPair::new_r2(index), IndexPair::new_r1(index));
             }
             None => {
-                pairs
-                    .entry(row.qname.clone())
-                    .and_modify(|pair| pair.merge(&IndexPair::new_r2(index)))
-                    .or_insert(IndexPair::new_r1(index));
+                pairs.add(&row.qname, IndexPair::new_r2(index), IndexPair::new_r1(index));
             }
         }
 
@@ -156,37 +214,44 @@ pub fn merge_sam_pairs_process(args: MergeSAMArgs) -> Result<(), std::io::Error>
         index += 1;
     }
 
+    // Pairs are merged in parallel and sent to the writer thread tagged with
+    // their index, so that the output is in the order each pair was first seen
+    let (sender, receiver) = sync_channel(MERGED_CHANNEL_CAPACITY);
+
     // Store statistics: Observations, deletion minor variants, true SNV, false
     // SNV, insertion observations, insertion discrepancy.
-    let mut paired_merging_stats = PairedMergeStats::default();
-
-    for pair in pairs.values() {
-        match (pair.r1, pair.r2) {
-            (Some(pair_index1), Some(pair_index2)) => {
-                let (sam1, sam2) = (&sam_data[pair_index1], &sam_data[pair_index2]);
-
-                // IRMA does not define read-pair merging yet for the empty quality score case.
-                // TODO: in v0.0.32 Zoe will only require checking for empty
-                if !sam1.qual.is_empty()
-                    && !sam2.qual.is_empty()
-                    && sam1.qual.as_bytes() != b"*"
-                    && sam2.qual.as_bytes() != b"*"
-                {
-                    let (s, stats) = sam1.merge_pair_using_reference(sam2, &reference.sequence, args.bowtie_format);
-                    paired_merging_stats += stats;
-
-                    writeln!(sam_writer, "{s}")?;
-                } else {
-                    writeln!(sam_writer, "{sam1}")?;
-                    writeln!(sam_writer, "{sam2}")?;
-                }
-            }
-            (Some(index), None) | (None, Some(index)) => {
-                writeln!(sam_writer, "{}", sam_data[index])?;
-            }
-            _ => continue,
-        }
-    }
+    let (merge_result, write_result) = std::thread::scope(|scope| {
+        let writer_thread = scope.spawn(|| write_in_order(receiver, &mut sam_writer, fastq_writer.as_mut()));
+
+        // `par_bridge` hands out pairs close to in order, which keeps the
+        // number of records held back by the writer small
+        let merge_result = pairs
+            .pairs
+            .iter()
+            .enumerate()
+            .par_bridge()
+            .map_with(sender, |sender, (i, pair)| {
+                let (merged, stats) = merge_index_pair(
+                    pair,
+                    &sam_data,
+                    &reference.sequence,
+                    args.bowtie_format,
+                    args.fastq_out.is_some(),
+                    &args.pair_filter,
+                    args.merge_tags,
+                );
+                sender.send((i, merged)).map(|()| stats)
+            })
+            .try_reduce(PairedMergeStats::default, |a, b| Ok(a + b));
+
+        let write_result = writer_thread.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+        (merge_result, write_result)
+    });
+
+    // A failed send means the writer stopped early, so its error is reported
+    write_result?;
+    let paired_merging_stats =
+        merge_result.map_err(|_| std::io::Error::other("The merged SAM writer stopped unexpectedly"))?;
 
     if let Some(paired_stats_file) = args.paired_stats_file {
         let mut w = OutputOptions::new_from_path(&paired_stats_file).use_file().open()?;
@@ -198,6 +263,8 @@ pub fn merge_sam_pairs_process(args: MergeSAMArgs) -> Result<(), std::io::Error>
             deletion_errors,
             insert_obs,
             insert_errors,
+            r1_wins: _,
+            r2_wins: _,
         } = paired_merging_stats;
 
         writeln!(
@@ -214,10 +281,252 @@ pub fn merge_sam_pairs_process(args: MergeSAMArgs) -> Result<(), std::io::Error>
         w.flush()?;
     }
 
+    if let Some(mut fastq_writer) = fastq_writer {
+        fastq_writer.flush()?;
+    }
+
     sam_writer.flush()
 }
 
-#[derive(Debug)]
+/// The output for a single pair.
+struct MergedOutput {
+    /// The SAM lines to write for the pair
+    sam:   String,
+    /// The FASTQ record of the merged pair, if it was merged and `--fastq-out`
+    /// was given
+    fastq: Option<String>,
+}
+
+impl MergedOutput {
+    /// The output for a pair which was not merged.
+    fn unmerged(sam: String) -> Self {
+        Self { sam, fastq: None }
+    }
+}
+
+/// Formats a merged pair as a FASTQ record, with the corrected sequence (in
+/// upper case, including any insertions) and the qualities from the merge.
+/// Returns `None` if the merge produced no sequence, such as when both reads
+/// were unmapped.
+fn merged_fastq_record(merged: &SamData) -> Option<String> {
+    if merged.seq.is_empty() || merged.qual.is_empty() || merged.qual.as_bytes() == b"*" {
+        return None;
+    }
+
+    let fastq = FastQ {
+        header:   merged.qname.clone(),
+        sequence: Nucleotides::from(merged.seq.as_bytes().to_ascii_uppercase()),
+        quality:  merged.qual.clone(),
+    };
+    Some(fastq.to_string())
+}
+
+/// The restrictions on which pairs are merged, from `--max-insert` and
+/// `--require-proper-orientation`.
+#[derive(Clone, Copy, Debug, Default)]
+struct PairFilter {
+    /// The maximum number of reference bases spanned by the pair
+    max_insert:                 Option<usize>,
+    /// Whether the reads must align to opposite strands and face each other
+    require_proper_orientation: bool,
+}
+
+impl PairFilter {
+    /// Returns why the pair should not be merged, or `None` if it passes the
+    /// restrictions.
+    fn discordance(&self, sam1: &SamData, sam2: &SamData) -> Option<Discordance> {
+        if self.require_proper_orientation && !is_convergent(sam1, sam2) {
+            return Some(Discordance::Orientation);
+        }
+
+        if let Some(max_insert) = self.max_insert
+            && insert_size(sam1, sam2) > max_insert
+        {
+            return Some(Discordance::InsertSize);
+        }
+
+        None
+    }
+}
+
+/// Why a pair was written unmerged by the [`PairFilter`], reported in the
+/// `YD:Z` tag of both reads.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Discordance {
+    /// The reads are not convergent
+    Orientation,
+    /// The insert size is above `--max-insert`
+    InsertSize,
+}
+
+impl Display for Discordance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Discordance::Orientation => write!(f, "orientation"),
+            Discordance::InsertSize => write!(f, "insert_size"),
+        }
+    }
+}
+
+/// Checks the CIGAR string of a mapped record for consistency with its
+/// sequence, so that an invalid record is reported rather than causing a panic
+/// while merging.
+fn validate_cigar(sam: &SamData) -> Result<(), CigarValidationError> {
+    if sam.is_unmapped() {
+        return Ok(());
+    }
+    let seq_len = (!sam.seq.is_empty() && sam.seq.as_bytes() != b"*").then(|| sam.seq.len());
+    TypedCigar::try_from(&sam.cigar)?.validate(seq_len)
+}
+
+/// Returns the 1-based, inclusive position of the last reference base in the
+/// alignment of a read.
+fn ref_end(sam: &SamData) -> usize {
+    (sam.pos + sam.cigar.ref_len_in_alignment()).saturating_sub(1)
+}
+
+/// Whether the reads of a pair align to opposite strands and face each other,
+/// which is when the forward read starts at or before the 5' end of the reverse
+/// read (the end of its alignment). Overlapping reads are convergent.
+fn is_convergent(sam1: &SamData, sam2: &SamData) -> bool {
+    let (forward, reverse) = match (sam1.flag & 16 != 0, sam2.flag & 16 != 0) {
+        (false, true) => (sam1, sam2),
+        (true, false) => (sam2, sam1),
+        _ => return false,
+    };
+    forward.pos <= ref_end(reverse)
+}
+
+/// The number of reference bases spanned by the alignments of a pair.
+fn insert_size(sam1: &SamData, sam2: &SamData) -> usize {
+    let start = sam1.pos.min(sam2.pos);
+    let end = ref_end(sam1).max(ref_end(sam2));
+    (end + 1).saturating_sub(start)
+}
+
+/// Merges the SAM records of a pair if both are present and have quality
+/// scores, returning the output for the pair. The FASTQ record of the merged
+/// pair is only formatted if `fastq_out` is true. Pairs which do not pass
+/// `pair_filter` are written unmerged, with a `YD:Z` tag giving the reason.
+/// Merged records are annotated with [`format_merge_tags`] if `merge_tags` is
+/// true.
+fn merge_index_pair(
+    pair: &IndexPair, sam_data: &[SamData], reference: &[u8], bowtie_format: bool, fastq_out: bool,
+    pair_filter: &PairFilter, merge_tags: bool,
+) -> (MergedOutput, PairedMergeStats) {
+    match (pair.r1, pair.r2) {
+        (Some(pair_index1), Some(pair_index2)) => {
+            let (sam1, sam2) = (&sam_data[pair_index1], &sam_data[pair_index2]);
+
+            if let Some(reason) = pair_filter.discordance(sam1, sam2) {
+                return (
+                    MergedOutput::unmerged(format!("{sam1}\tYD:Z:{reason}\n{sam2}\tYD:Z:{reason}\n")),
+                    PairedMergeStats::default(),
+                );
+            }
+
+            // IRMA does not define read-pair merging yet for the empty quality score case.
+            // TODO: in v0.0.32 Zoe will only require checking for empty
+            if !sam1.qual.is_empty() && !sam2.qual.is_empty() && sam1.qual.as_bytes() != b"*" && sam2.qual.as_bytes() != b"*"
+            {
+                let (s, stats) = sam1.merge_pair_using_reference(sam2, reference, bowtie_format);
+                let tags = if merge_tags {
+                    format_merge_tags(&stats)
+                } else {
+                    String::new()
+                };
+                let output = MergedOutput {
+                    sam:   format!("{s}{tags}\n"),
+                    fastq: if fastq_out { merged_fastq_record(&s) } else { None },
+                };
+                (output, stats)
+            } else {
+                (
+                    MergedOutput::unmerged(format!("{sam1}\n{sam2}\n")),
+                    PairedMergeStats::default(),
+                )
+            }
+        }
+        (Some(index), None) | (None, Some(index)) => (
+            MergedOutput::unmerged(format!("{}\n", sam_data[index])),
+            PairedMergeStats::default(),
+        ),
+        (None, None) => (MergedOutput::unmerged(String::new()), PairedMergeStats::default()),
+    }
+}
+
+/// Formats the decisions made when merging a pair as SAM tags, each preceded
+/// by a tab: the overlapping positions (`YO:i`), the disagreements between the
+/// reads at bases or insertions (`YC:i`), and the disagreements resolved in
+/// favor of R1 (`Y1:i`) and R2 (`Y2:i`). Disagreements resolved to `N` or
+/// dropped count toward neither read.
+fn format_merge_tags(stats: &PairedMergeStats) -> String {
+    format!(
+        "\tYO:i:{overlap}\tYC:i:{disagreements}\tY1:i:{r1}\tY2:i:{r2}",
+        overlap = stats.observations,
+        disagreements = stats.variant_errors + stats.insert_errors,
+        r1 = stats.r1_wins,
+        r2 = stats.r2_wins
+    )
+}
+
+/// Writes the merged pairs received from the merging threads in order of their
+/// index, holding back any which arrive early. FASTQ records are written to
+/// `fastq_writer` if it is provided.
+///
+/// ## Errors
+///
+/// Any IO errors when writing are propagated, after which the receiver is
+/// dropped so that the merging threads stop.
+fn write_in_order<W: Write, F: Write>(
+    receiver: Receiver<(usize, MergedOutput)>, writer: &mut W, mut fastq_writer: Option<&mut F>,
+) -> std::io::Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+
+    for (i, merged) in receiver {
+        pending.insert(i, merged);
+        while let Some(merged) = pending.remove(&next) {
+            writer.write_all(merged.sam.as_bytes())?;
+            if let (Some(fastq_writer), Some(fastq)) = (fastq_writer.as_deref_mut(), merged.fastq) {
+                fastq_writer.write_all(fastq.as_bytes())?;
+            }
+            next += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// The pairs of SAM records, in the order each molecule was first seen, along
+/// with a lookup from molecular ID to pair.
+struct IndexPairs {
+    pairs:  Vec<IndexPair>,
+    lookup: HashMap<String, usize, SeedableRandomState>,
+}
+
+impl IndexPairs {
+    fn new() -> Self {
+        Self {
+            pairs:  Vec::new(),
+            lookup: HashMap::with_hasher(get_hasher()),
+        }
+    }
+
+    /// Merges `other` into the pair for `mol_name_id` if it exists, and
+    /// otherwise adds `new_pair`.
+    fn add(&mut self, mol_name_id: &str, other: IndexPair, new_pair: IndexPair) {
+        match self.lookup.get(mol_name_id) {
+            Some(&i) => self.pairs[i].merge(&other),
+            None => {
+                self.lookup.insert(mol_name_id.to_string(), self.pairs.len());
+                self.pairs.push(new_pair);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 struct IndexPair {
     r1: Option<usize>,
     r2: Option<usize>,
@@ -26,13 +26,28 @@ pub struct NumProcsArgs {
 }
 
 pub fn num_procs_process(args: NumProcsArgs) -> Result<(), std::io::Error> {
-    let mut cores = if args.physical {
-        num_cpus::get_physical()
+    let cores = get_num_procs(args.physical, args.cap_cores_using_env);
+
+    if args.include_half {
+        let half_cores = (cores / 2).max(1);
+        println!("{cores} {half_cores}");
     } else {
-        num_cpus::get()
-    };
+        println!("{cores}");
+    }
+
+    Ok(())
+}
 
-    if args.cap_cores_using_env
+/// Gets the number of physical or logical cores available to the process.
+///
+/// The logical core count respects cgroup CPU quotas when present. If
+/// `cap_cores_using_env` is set, the count is capped to `NSLOTS` (falling back
+/// to `IFX_LOCAL_PROCS`). In all cases, `LOCAL_PROCS_OVERRIDE` takes precedence
+/// when it is a valid positive number.
+pub(crate) fn get_num_procs(physical: bool, cap_cores_using_env: bool) -> usize {
+    let mut cores = if physical { num_cpus::get_physical() } else { num_cpus::get() };
+
+    if cap_cores_using_env
         && let Some(n) = ["NSLOTS", "IFX_LOCAL_PROCS"]
             .into_iter()
             .find_map(|v| env::var(v).ok().and_then(|v| v.parse::<usize>().ok()))
@@ -56,12 +71,5 @@ pub fn num_procs_process(args: NumProcsArgs) -> Result<(), std::io::Error> {
         }
     }
 
-    if args.include_half {
-        let half_cores = (cores / 2).max(1);
-        println!("{cores} {half_cores}");
-    } else {
-        println!("{cores}");
-    }
-
-    Ok(())
+    cores
 }
@@ -0,0 +1,101 @@
+//! Collapses near-identical read patterns after exact deduplication.
+
+use crate::shared::xfl::{ClusterMembers, DeflatedSequences};
+use irma_records::hashing::get_hasher;
+use std::{collections::HashMap, hash::BuildHasher};
+use zoe::prelude::*;
+
+/// Collapses read patterns which differ from a more abundant pattern by a
+/// single mismatch, when the quality scores indicate the mismatch is more
+/// likely an error. The number of read patterns that were collapsed is
+/// returned.
+///
+/// Patterns are visited in order of descending read count (ties broken by
+/// sequence), so the higher-count pattern is always kept as the
+/// representative. A pattern is merged into a representative if the mean
+/// quality score of its reads at the mismatching position is lower than that of
+/// the representative's reads. The merged reads take on the representative's
+/// sequence but retain their own headers and quality scores, with at most
+/// `max_members` stored per representative.
+///
+/// Candidate representatives are found by bucketing on each half of the
+/// sequence: two sequences of equal length differing by at most one mismatch
+/// must share at least one half exactly, keeping the search near-linear.
+pub(super) fn collapse_near_identical(deflated: &mut DeflatedSequences, max_members: Option<usize>) -> usize {
+    let mut clusters: Vec<(Nucleotides, ClusterMembers)> = deflated.drain().collect();
+    clusters.sort_unstable_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.as_bytes().cmp(b.0.as_bytes())));
+
+    let hasher = get_hasher();
+    let mut buckets: HashMap<(usize, usize, u64), Vec<usize>, _> = HashMap::with_hasher(get_hasher());
+    let mut merged_into = vec![None; clusters.len()];
+
+    for (i, (sequence, metadata)) in clusters.iter().enumerate() {
+        let sequence = sequence.as_bytes();
+        let keys = half_keys(sequence, &hasher);
+
+        let representative = keys.iter().filter_map(|key| buckets.get(key)).flatten().copied().find(|&r| {
+            let (rep_sequence, rep_metadata) = &clusters[r];
+            single_mismatch(rep_sequence.as_bytes(), sequence)
+                .is_some_and(|pos| mean_quality_at(metadata, pos) < mean_quality_at(rep_metadata, pos))
+        });
+
+        if let Some(r) = representative {
+            merged_into[i] = Some(r);
+        } else {
+            for key in keys {
+                buckets.entry(key).or_default().push(i);
+            }
+        }
+    }
+
+    let mut num_collapsed = 0;
+    for (i, r) in merged_into.iter().enumerate() {
+        if let Some(r) = *r {
+            let metadata = std::mem::take(&mut clusters[i].1);
+            clusters[r].1.merge(metadata, max_members);
+            num_collapsed += 1;
+        }
+    }
+
+    deflated.extend(
+        clusters
+            .into_iter()
+            .zip(merged_into)
+            .filter_map(|(cluster, r)| r.is_none().then_some(cluster)),
+    );
+
+    num_collapsed
+}
+
+/// Gets the bucket keys for each half of the sequence, consisting of the
+/// sequence length, which half, and a hash of the half.
+fn half_keys(sequence: &[u8], hasher: &impl BuildHasher) -> [(usize, usize, u64); 2] {
+    let (left, right) = sequence.split_at(sequence.len() / 2);
+    [
+        (sequence.len(), 0, hasher.hash_one(left)),
+        (sequence.len(), 1, hasher.hash_one(right)),
+    ]
+}
+
+/// If the sequences are the same length and differ at exactly one position,
+/// returns that position.
+fn single_mismatch(a: &[u8], b: &[u8]) -> Option<usize> {
+    if a.len() != b.len() {
+        return None;
+    }
+
+    let mut mismatches = a.iter().zip(b).enumerate().filter(|(_, (x, y))| x != y).map(|(i, _)| i);
+    let pos = mismatches.next()?;
+    mismatches.next().is_none().then_some(pos)
+}
+
+/// The mean encoded quality score at `pos` across the stored reads of a
+/// cluster.
+fn mean_quality_at(metadata: &ClusterMembers, pos: usize) -> f32 {
+    let total: usize = metadata
+        .stored
+        .iter()
+        .map(|(_, quality)| usize::from(quality.as_bytes()[pos]))
+        .sum();
+    total as f32 / metadata.stored.len() as f32
+}
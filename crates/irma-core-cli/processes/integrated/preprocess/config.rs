@@ -0,0 +1,189 @@
+//! The effective configuration of a run, written to the log so that any output
+//! can be traced to the exact parameters and build that produced it.
+
+use super::{OnBadRecord, PreprocessArgs};
+use crate::{
+    args::clipping::{StrictBases, TrimEnd, TrimStep},
+    shared::xfl::ClusterOrder,
+};
+use irma_records::hashing::{get_seed, is_legacy_seed_derivation};
+use serde::Serialize;
+use std::{
+    io::Write,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+use zoe::prelude::Nucleotides;
+
+/// The git commit `irma-core` was built from, embedded by the build script.
+const GIT_HASH: &str = env!("IRMA_CORE_GIT_HASH");
+
+/// The canonicalized parameters of a run. Every option is present, with
+/// defaults filled in, `--auto-r2` resolved to the inferred path,
+/// `--a-fuzzy` folded into `a_max_mismatch`, file paths made absolute, and the
+/// hash seed derived from `IRMA_SEED`. Fields are always written in the same
+/// order, so the configurations of two runs can be compared as text.
+#[derive(Serialize, Debug)]
+pub(super) struct PreprocessConfiguration {
+    table_file:              PathBuf,
+    fastq_input:             PathBuf,
+    fastq_input2:            Option<PathBuf>,
+    log_file:                Option<PathBuf>,
+    min_read_quality:        u8,
+    use_median:              bool,
+    min_length:              NonZeroUsize,
+    enforce_clipped_length:  bool,
+    max_n_frac:              Option<f64>,
+    filter_widows:           bool,
+    deflate_pairs:           bool,
+    collapse_hdist:          Option<u8>,
+    max_members_per_cluster: Option<NonZeroUsize>,
+    skip_trimming:           bool,
+    clusters_fastq:          Option<PathBuf>,
+    cluster_stats:           Option<PathBuf>,
+    cluster_prefix:          String,
+    cluster_start:           usize,
+    sort_clusters:           Option<ClusterOrder>,
+    long_reads:              Option<PathBuf>,
+    long_min_length:         NonZeroUsize,
+    long_min_read_quality:   u8,
+    on_bad_record:           OnBadRecord,
+    validate_inputs:         bool,
+    hash_seed:               Option<u64>,
+    legacy_seed_derivation:  bool,
+    compress_level:          Option<u32>,
+    compress_threads:        NonZeroUsize,
+    preserve_bases:          bool,
+    strict_bases:            Option<StrictBases>,
+    polyg_trim:              Option<NonZeroUsize>,
+    g_polyg_end:             TrimEnd,
+    g_polyg_left:            Option<NonZeroUsize>,
+    g_polyg_right:           Option<NonZeroUsize>,
+    barcode_trim:            Option<String>,
+    barcode_pairs:           Option<PathBuf>,
+    b_annotate_sample:       bool,
+    b_end:                   TrimEnd,
+    b_restrict:              Option<NonZeroUsize>,
+    b_restrict_left:         Option<NonZeroUsize>,
+    b_restrict_right:        Option<NonZeroUsize>,
+    b_hdist:                 usize,
+    b_quality_aware:         Option<u8>,
+    adapter_trim:            Option<String>,
+    a_max_mismatch:          usize,
+    primer_trim:             Option<PathBuf>,
+    p_fuzzy:                 bool,
+    p_kmer_length:           Option<usize>,
+    p_kmer_auto:             bool,
+    p_end:                   TrimEnd,
+    p_restrict:              NonZeroUsize,
+    p_restrict_left:         Option<NonZeroUsize>,
+    p_restrict_right:        Option<NonZeroUsize>,
+    hard_trim:               Option<usize>,
+    h_left:                  Option<usize>,
+    h_right:                 Option<usize>,
+    hard_trim_r2:            Option<usize>,
+    h_left_r2:               Option<usize>,
+    h_right_r2:              Option<usize>,
+    trim_order:              Option<Vec<TrimStep>>,
+    tee_input:               Option<PathBuf>,
+    tee_input2:              Option<PathBuf>,
+    trace_read:              Vec<String>,
+}
+
+impl PreprocessConfiguration {
+    /// Records the parameters of the run. This must be called after
+    /// `--auto-r2` is resolved and `--legacy-seed-derivation` is applied.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if any path cannot be made absolute.
+    pub(super) fn from_args(args: &PreprocessArgs) -> std::io::Result<Self> {
+        let absolute = |path: &Option<PathBuf>| path.as_deref().map(std::path::absolute).transpose();
+        let sequence =
+            |seq: &Option<Nucleotides>| seq.as_ref().map(|seq| String::from_utf8_lossy(seq.as_bytes()).into_owned());
+        let quality_filter = &args.quality_filter;
+        let clipping = &args.clipping_args;
+
+        Ok(PreprocessConfiguration {
+            table_file:              std::path::absolute(&args.table_file)?,
+            fastq_input:             std::path::absolute(&args.fastq_input)?,
+            fastq_input2:            absolute(&args.fastq_input2)?,
+            log_file:                absolute(&args.log_file)?,
+            min_read_quality:        quality_filter.min_read_quality,
+            use_median:              quality_filter.use_median,
+            min_length:              quality_filter.min_length,
+            enforce_clipped_length:  quality_filter.enforce_clipped_length,
+            max_n_frac:              quality_filter.max_n_frac,
+            filter_widows:           args.filter_widows,
+            deflate_pairs:           args.deflate_pairs,
+            collapse_hdist:          args.collapse_hdist,
+            max_members_per_cluster: args.max_members_per_cluster,
+            skip_trimming:           args.skip_trimming,
+            clusters_fastq:          absolute(&args.clusters_fastq)?,
+            cluster_stats:           absolute(&args.cluster_stats)?,
+            cluster_prefix:          args.cluster_prefix.clone(),
+            cluster_start:           args.cluster_start,
+            sort_clusters:           args.sort_clusters,
+            long_reads:              absolute(&args.long_reads)?,
+            long_min_length:         args.long_min_length,
+            long_min_read_quality:   args.long_min_read_quality,
+            on_bad_record:           args.on_bad_record,
+            validate_inputs:         args.validate_inputs,
+            hash_seed:               get_seed(),
+            legacy_seed_derivation:  is_legacy_seed_derivation(),
+            compress_level:          args.compression.compress_level,
+            compress_threads:        args.compression.compress_threads,
+            preserve_bases:          clipping.preserve_bases,
+            strict_bases:            clipping.strict_bases,
+            polyg_trim:              clipping.polyg_trim,
+            g_polyg_end:             clipping.g_polyg_end,
+            g_polyg_left:            clipping.g_polyg_left,
+            g_polyg_right:           clipping.g_polyg_right,
+            barcode_trim:            sequence(&clipping.barcode_trim),
+            barcode_pairs:           absolute(&clipping.barcode_pairs)?,
+            b_annotate_sample:       clipping.b_annotate_sample,
+            b_end:                   clipping.b_end,
+            b_restrict:              clipping.b_restrict,
+            b_restrict_left:         clipping.b_restrict_left,
+            b_restrict_right:        clipping.b_restrict_right,
+            b_hdist:                 clipping.b_hdist,
+            b_quality_aware:         clipping.b_quality_aware,
+            adapter_trim:            sequence(&clipping.adapter_trim),
+            a_max_mismatch:          clipping.a_max_mismatch.unwrap_or(usize::from(clipping.a_fuzzy)),
+            primer_trim:             absolute(&clipping.primer_trim)?,
+            p_fuzzy:                 clipping.p_fuzzy,
+            p_kmer_length:           clipping.p_kmer_length,
+            p_kmer_auto:             clipping.p_kmer_auto,
+            p_end:                   clipping.p_end,
+            p_restrict:              clipping.p_restrict,
+            p_restrict_left:         clipping.p_restrict_left,
+            p_restrict_right:        clipping.p_restrict_right,
+            hard_trim:               clipping.hard_trim,
+            h_left:                  clipping.h_left,
+            h_right:                 clipping.h_right,
+            hard_trim_r2:            clipping.hard_trim_r2,
+            h_left_r2:               clipping.h_left_r2,
+            h_right_r2:              clipping.h_right_r2,
+            trim_order:              clipping.trim_order.clone(),
+            tee_input:               absolute(&args.tee.tee_input)?,
+            tee_input2:              absolute(&args.tee.tee_input2)?,
+            trace_read:              args.trace.trace_read.clone(),
+        })
+    }
+
+    /// Writes the version and git commit of `irma-core`, followed by the
+    /// configuration as a single line of JSON, to the log.
+    pub(super) fn write_log(&self, mut log_writer: impl Write, log_file: &Path) -> std::io::Result<()> {
+        let configuration = serde_json::to_string(self).map_err(|e| {
+            std::io::Error::other(format!(
+                "Failed to serialize the configuration for {}: {e}",
+                log_file.display()
+            ))
+        })?;
+        writeln!(
+            log_writer,
+            "IRMA_CORE_VERSION\t{version}\nIRMA_CORE_GIT_HASH\t{GIT_HASH}\nEFFECTIVE_CONFIGURATION\t{configuration}",
+            version = env!("CARGO_PKG_VERSION"),
+        )
+    }
+}
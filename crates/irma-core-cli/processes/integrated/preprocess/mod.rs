@@ -3,39 +3,52 @@
 //! FASTA format.
 
 use crate::{
-    args::clipping::{ClippingArgs, ParsedClippingArgs, parse_clipping_args},
+    args::{
+        clipping::{ClippingArgs, ParsedClippingArgs, StrictBases, parse_clipping_args},
+        quality_filter::{ParsedQualityFilterArgs, QualityFilterArgs, n_fraction, parse_quality_filter_args},
+    },
     shared::{
         PrintWarning,
-        trimming::{TrimmedCounts, trim_read},
+        compression::CompressionArgs,
+        paired_paths::infer_r2_path,
+        tee_input::TeeInputArgs,
+        trace::{ReadTracer, TraceArgs},
+        trimming::{NonCanonicalBases, TrimmedCounts, trim_read},
+        xfl::{
+            ClusterNaming, ClusterOrder, DeflatedSequences, XflLayout, join_pair, order_clusters, parse_cluster_prefix,
+            write_deflated_sequences,
+        },
     },
 };
-use clap::{Args, ValueHint};
-use foldhash::fast::SeedableRandomState;
+use clap::{ArgGroup, Args, ValueEnum, ValueHint};
+use config::PreprocessConfiguration;
 use irma_records::{
     fastq::ReadTransforms,
-    hashing::get_hasher,
+    hashing::{get_hasher, get_seed, is_legacy_seed_derivation, use_legacy_seed_derivation},
     io::{
-        InputOptions, IterWithContext, OutputOptions, ReadFileZipInThread, RecordReaders, ValidatePaths, WriterWithContext,
+        GzipFormat, InputOptions, IterWithContext, OutputOptions, ReadFileZipInThread, RecordReaders, TeeReader,
+        TolerantFastQReader, ValidatePaths, WriteFileZipStdout, WriterWithContext,
     },
-    paired::{ReadSide, ZipPairedReadsError, ZipPairedReadsExt},
+    paired::{ReadSide, ZipPairedReadsError, ZipPairedReadsExt, get_run_metadata},
 };
+use serde::Serialize;
 use std::{
-    collections::HashMap,
     fs::File,
-    io::{BufWriter, prelude::*},
+    io::{BufReader, BufWriter, prelude::*},
     num::NonZeroUsize,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use zoe::prelude::*;
 
+mod collapse;
+mod config;
 mod stats;
+mod validate;
 pub(crate) use stats::FastQMetadata;
-
-/// A type alias for the [`HashMap`] used to store the deflated sequences and
-/// the associated headers and quality scores.
-type DeflatedSequences = HashMap<Nucleotides, Vec<(String, QualityScores)>, SeedableRandomState>;
+use stats::{ClusterSizes, mean_q, write_cluster_stats, write_run_metadata};
 
 #[derive(Args, Debug)]
+#[command(group(ArgGroup::new("paired_input").args(["fastq_input2", "auto_r2"])))]
 pub struct PreprocessArgs {
     /// Location to store the XFL file.
     table_file: PathBuf,
@@ -46,83 +59,255 @@ pub struct PreprocessArgs {
     /// The R2 paired-end FASTQ file.
     fastq_input2: Option<PathBuf>,
 
+    #[arg(long, conflicts_with = "fastq_input2")]
+    /// Infers the second input from the first input's file name (`_R1_`,
+    /// `_R1.`, or `_1.` becomes `_R2_`, `_R2.`, or `_2.`), checking that it
+    /// exists and that the first records of both files are paired
+    auto_r2: bool,
+
     #[arg(short = 'L', long, value_hint = ValueHint::FilePath)]
     /// Quality control log path and filename.
     log_file: Option<PathBuf>,
 
-    #[arg(short = 'T', long, default_value_t = 0)]
-    /// Specify the read quality threshold (geometric mean, median).
-    min_read_quality: u8,
-
-    #[arg(short = 'M', long)]
-    /// Interprets the threshold (-T) as the median, not the geometric mean.
-    use_median: bool,
-
-    #[arg(short = 'n', long, default_value = "1")]
-    /// Minimum length of sequence read data, filtered otherwise.
-    min_length: NonZeroUsize,
-
-    #[arg(short = 'E', long, requires = "min_length")]
-    /// The minimum length threshold (-n) is enforced after all trimming.
-    enforce_clipped_length: bool,
+    #[command(flatten)]
+    quality_filter: QualityFilterArgs,
 
-    #[arg(short = 'f', long, requires = "fastq_input2")]
+    #[arg(short = 'f', long, requires = "paired_input")]
     /// Filter widowed reads
     filter_widows: bool,
 
+    #[arg(long, requires = "paired_input")]
+    /// Deflates each read pair as a single molecule, joining R1 and R2 (after
+    /// trimming) with `|`, so that a pair is deduplicated only when both reads
+    /// match. Pairs are filtered together, and reads without a mate are an
+    /// error.
+    deflate_pairs: bool,
+
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=1))]
+    /// Collapses read patterns differing from a more abundant pattern by up to
+    /// this many mismatches, when supported by the quality scores. Currently
+    /// only 1 is supported
+    collapse_hdist: Option<u8>,
+
+    #[arg(long, value_name = "N")]
+    /// Stores the headers and quality scores of at most this many reads per
+    /// cluster in the XFL table, bounding memory for very deep clusters. The
+    /// remaining reads are still counted in the cluster sizes, but cannot be
+    /// reinflated.
+    max_members_per_cluster: Option<NonZeroUsize>,
+
+    #[arg(long, conflicts_with_all = [
+        "polyg_trim", "barcode_trim", "barcode_pairs", "adapter_trim", "primer_trim", "hard_trim", "h_left", "h_right",
+        "hard_trim_r2", "h_left_r2", "h_right_r2",
+    ])]
+    /// Skips all trimming, only applying the quality and length filters before
+    /// deflating, for inputs already trimmed by `trimmer`. Cannot be combined
+    /// with any trimming options.
+    skip_trimming: bool,
+
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    /// Also write each read pattern as a FastQ record, named by its cluster ID,
+    /// with a consensus quality computed from all of its reads. The output is
+    /// compressed if the path ends in `.gz` or `.bgz`.
+    clusters_fastq: Option<PathBuf>,
+
+    #[arg(long, value_name = "TSV", value_hint = ValueHint::FilePath)]
+    /// Also write a tab-delimited summary of each read pattern, with its
+    /// cluster ID, size, length, and the mean, minimum, and maximum of the mean
+    /// quality scores of its reads, for spotting abundant low-quality artifacts
+    cluster_stats: Option<PathBuf>,
+
+    #[arg(long, default_value = "C", value_parser = parse_cluster_prefix)]
+    /// The prefix of the cluster names. Sharded runs can use a distinct prefix
+    /// for each shard, so that the clusters remain unique once gathered.
+    cluster_prefix: String,
+
+    #[arg(long, default_value_t = 0)]
+    /// The number of the first cluster. Sharded runs can instead number each
+    /// shard from a distinct offset.
+    cluster_start: usize,
+
+    #[arg(long, value_enum)]
+    /// The order in which the clusters are numbered and written to the FASTA,
+    /// the XFL table, `--clusters-fastq`, and `--cluster-stats`: the largest
+    /// clusters first (`size`), the longest sequences first (`length`), or by
+    /// the header of each cluster's first read (`name`). Ties are broken by
+    /// sequence. By default, the order depends on the hash seed
+    sort_clusters: Option<ClusterOrder>,
+
+    #[arg(long, value_name = "FASTQ", value_hint = ValueHint::FilePath, conflicts_with = "deflate_pairs")]
+    /// Long (Oxford Nanopore) reads to deflate along with the Illumina inputs,
+    /// for hybrid assemblies. Long reads are not trimmed and are filtered with
+    /// their own length and quality thresholds, and each read in the XFL table
+    /// is tagged with its technology.
+    long_reads: Option<PathBuf>,
+
+    #[arg(long, default_value = "1", requires = "long_reads")]
+    /// Minimum length of the long reads, filtered otherwise.
+    long_min_length: NonZeroUsize,
+
+    #[arg(long, default_value_t = 0, requires = "long_reads")]
+    /// The read quality threshold of the long reads (geometric mean, or median
+    /// with -M).
+    long_min_read_quality: u8,
+
+    #[arg(long, value_enum, default_value_t)]
+    /// How to handle a structurally invalid FASTQ record, such as one whose
+    /// sequence and quality scores differ in length. With `skip`, such records
+    /// are dropped with a warning and counted in the log
+    on_bad_record: OnBadRecord,
+
+    #[arg(long)]
+    /// Reads every input in full before processing, checking the CRC of zipped
+    /// inputs and counting the records of each, so that a corrupt or truncated
+    /// input fails before any outputs are written
+    validate_inputs: bool,
+
+    #[arg(long)]
+    /// Derives the hash seed from `IRMA_SEED` by XOR-folding its bytes, as in
+    /// earlier versions, to reproduce their outputs. This gives only 256
+    /// distinct seeds, so similar `IRMA_SEED` values often collide
+    legacy_seed_derivation: bool,
+
+    #[command(flatten)]
+    compression: CompressionArgs,
+
     #[command(flatten)]
     clipping_args: ClippingArgs,
+
+    #[command(flatten)]
+    tee: TeeInputArgs,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+}
+
+/// How `preprocess` handles a structurally invalid FASTQ record.
+#[derive(ValueEnum, Serialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum OnBadRecord {
+    /// Stop with an error
+    #[default]
+    Fail,
+    /// Skip the record with a warning, counting it in the log
+    Skip,
 }
 
 impl ValidatePaths for PreprocessArgs {
     fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
         let input1 = std::iter::once(&self.fastq_input);
         let input2 = self.fastq_input2.iter();
+        let long_reads = self.long_reads.iter();
 
-        input1.chain(input2)
+        input1.chain(input2).chain(long_reads)
     }
 
     fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
         let table_file = std::iter::once(&self.table_file);
         let log_file = self.log_file.iter();
-
-        table_file.chain(log_file)
+        let clusters_fastq = self.clusters_fastq.iter();
+        let cluster_stats = self.cluster_stats.iter();
+
+        table_file
+            .chain(log_file)
+            .chain(clusters_fastq)
+            .chain(cluster_stats)
+            .chain(self.tee.outputs())
     }
 }
 
-const CLUSTER_PREFIX: &str = "C";
 static MODULE: &str = "IRMA-CORE PREPROCESS";
 
 /// # Panics
 ///
 /// Sub-program for processing FASTQ data.
-pub fn preprocess_process(args: PreprocessArgs) -> Result<(), std::io::Error> {
+pub fn preprocess_process(mut args: PreprocessArgs) -> Result<(), std::io::Error> {
+    if args.auto_r2 {
+        args.fastq_input2 = Some(infer_r2_path(&args.fastq_input)?);
+    }
+    args.tee.check_paired(args.fastq_input2.is_some(), "preprocess");
     args.validate_paths()?;
 
+    if args.validate_inputs {
+        let paired = args.fastq_input2.is_some();
+        let inputs = [
+            Some((if paired { "R1" } else { "single-ended" }, args.fastq_input.as_path())),
+            args.fastq_input2.as_deref().map(|path| ("R2", path)),
+            args.long_reads.as_deref().map(|path| ("long read", path)),
+        ];
+        validate::validate_inputs(&inputs.into_iter().flatten().collect::<Vec<_>>())?;
+    }
+
+    // Set before any hash maps are created, so that they all use the same seed
+    if args.legacy_seed_derivation {
+        use_legacy_seed_derivation();
+    }
+
+    // Recorded before the arguments are consumed, once `--auto-r2` and the
+    // seed derivation are resolved
+    let configuration = match args.log_file {
+        Some(_) => Some(PreprocessConfiguration::from_args(&args)?),
+        None => None,
+    };
+
     let ParsedPreprocessArgs { mut io_args, options } = parse_preprocess_args(args)?;
 
     let paired_reads = io_args.reader2.is_some();
 
-    let (metadata_by_sequence, metadata) = trim_and_deflate(&options, &mut io_args)?;
+    let (mut metadata_by_sequence, metadata, long_metadata) = trim_and_deflate(&options, &mut io_args)?;
+
+    let read_patterns_collapsed = options
+        .collapse_hdist
+        .map(|_| collapse::collapse_near_identical(&mut metadata_by_sequence, options.max_members));
 
-    let read_pattern_count_passing = if metadata.passed_qc_count == 0 {
+    let cluster_sizes = ClusterSizes::new(&metadata_by_sequence);
+
+    if metadata.passed_qc_count + long_metadata.as_ref().map_or(0, |long| long.passed_qc_count) == 0 {
         diagnose_none_passing(&metadata, paired_reads, &options);
-        0
+        if let Some(long_metadata) = &long_metadata {
+            diagnose_no_long_reads_passing(long_metadata, &options);
+        }
     } else {
-        output_deflated_sequences(metadata_by_sequence, io_args.table_writer)?
-    };
+        let layout = if options.deflate_pairs {
+            XflLayout::Pairs
+        } else {
+            XflLayout::Reads
+        };
+        let clusters = order_clusters(metadata_by_sequence, options.sort_clusters);
+        if let Some(stats_writer) = io_args.stats_writer {
+            write_cluster_stats(&clusters, layout, &options.cluster_naming, stats_writer)?;
+        }
+        write_deflated_sequences(
+            clusters,
+            layout,
+            &options.cluster_naming,
+            options.max_members,
+            io_args.table_writer,
+            OutputOptions::new_stdout().open()?,
+            io_args.clusters_writer,
+        )?;
+    }
 
-    if let Some(log_writer) = io_args.log_writer
+    if let Some(mut log_writer) = io_args.log_writer
         && let Some(log_file) = io_args.log_file
     {
         write_log(
-            log_writer,
+            &mut log_writer,
             &metadata,
             paired_reads,
-            read_pattern_count_passing,
+            &cluster_sizes,
+            read_patterns_collapsed,
             &options,
-            log_file,
-        )?;
+            &log_file,
+        );
+        if let Some(long_metadata) = &long_metadata {
+            write_long_read_log(&mut log_writer, long_metadata, &options, &log_file);
+        }
+        if let Some(configuration) = configuration {
+            configuration.write_log(log_writer, &log_file).unwrap_or_else(|e| {
+                eprintln!("{MODULE} WARNING! Cannot write to {}. See: {e}", log_file.display());
+            });
+        }
     }
 
     Ok(())
@@ -130,27 +315,83 @@ pub fn preprocess_process(args: PreprocessArgs) -> Result<(), std::io::Error> {
 
 /// The type preprocess uses for input, along with the input path for error
 /// context.
-struct Reader {
+struct Reader<R: Read = TeeReader<ReadFileZipInThread>> {
     path: PathBuf,
-    iter: IterWithContext<FastQReader<ReadFileZipInThread>>,
+    iter: IterWithContext<TolerantFastQReader<BufReader<R>>>,
 }
 
+/// The type preprocess uses for the `--long-reads` input, which is not teed.
+type LongReader = Reader<ReadFileZipInThread>;
+
 struct ParsedPreprocessIoArgs {
-    table_writer: BufWriter<WriterWithContext<File>>,
-    reader1:      Reader,
-    reader2:      Option<Reader>,
-    log_writer:   Option<BufWriter<WriterWithContext<File>>>,
-    log_file:     Option<PathBuf>,
+    table_writer:    BufWriter<WriterWithContext<File>>,
+    reader1:         Reader,
+    reader2:         Option<Reader>,
+    long_reader:     Option<LongReader>,
+    log_writer:      Option<BufWriter<WriterWithContext<File>>>,
+    log_file:        Option<PathBuf>,
+    clusters_writer: Option<WriteFileZipStdout>,
+    stats_writer:    Option<BufWriter<WriterWithContext<File>>>,
 }
 
 #[derive(Debug)]
 struct ParsedPreprocessOptions {
-    min_read_quality:       u8,
-    use_median:             bool,
-    min_length:             usize,
-    enforce_clipped_length: bool,
-    filter_widows:          bool,
-    clipping_args:          ParsedClippingArgs,
+    quality_filter:   ParsedQualityFilterArgs,
+    filter_widows:    bool,
+    deflate_pairs:    bool,
+    collapse_hdist:   Option<u8>,
+    /// The maximum number of reads stored per cluster, if capped
+    max_members:      Option<usize>,
+    skip_trimming:    bool,
+    /// Whether the mean quality scores of each cluster are tallied, for
+    /// `--cluster-stats`
+    cluster_stats:    bool,
+    /// Whether structurally invalid records are skipped, from `--on-bad-record`
+    skip_bad_records: bool,
+    /// The quality filters of the long reads, if `--long-reads` was provided
+    long_reads:       Option<ParsedQualityFilterArgs>,
+    cluster_naming:   ClusterNaming,
+    /// The order of the clusters, from `--sort-clusters`
+    sort_clusters:    Option<ClusterOrder>,
+    clipping_args:    ParsedClippingArgs,
+    tracer:           ReadTracer,
+}
+
+impl ParsedPreprocessOptions {
+    /// The quality filters applied to reads of the given technology.
+    fn quality_filter(&self, technology: Technology) -> &ParsedQualityFilterArgs {
+        match (technology, &self.long_reads) {
+            (Technology::Ont, Some(long_reads)) => long_reads,
+            _ => &self.quality_filter,
+        }
+    }
+
+    /// The technology tagged on the reads of the given technology, which is
+    /// only done when `--long-reads` was provided.
+    fn technology_tag(&self, technology: Technology) -> Option<Technology> {
+        self.long_reads.is_some().then_some(technology)
+    }
+}
+
+/// The sequencing technology of a read. With `--long-reads`, each read in the
+/// XFL table is tagged with its technology, so that hybrid assemblies can tell
+/// the members of a cluster apart.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Technology {
+    /// A read from the (single or paired) Illumina inputs
+    Illumina,
+    /// A read from `--long-reads`
+    Ont,
+}
+
+impl Technology {
+    /// The tag appended to the header of each read, separated by a space.
+    fn tag(self) -> &'static str {
+        match self {
+            Technology::Illumina => "tech=illumina",
+            Technology::Ont => "tech=ont",
+        }
+    }
 }
 
 struct ParsedPreprocessArgs {
@@ -163,19 +404,38 @@ fn parse_preprocess_args(args: PreprocessArgs) -> std::io::Result<ParsedPreproce
         table_file,
         fastq_input,
         fastq_input2,
+        auto_r2: _,
         log_file,
-        min_read_quality,
-        use_median,
-        min_length,
-        enforce_clipped_length,
+        quality_filter,
         filter_widows,
+        deflate_pairs,
+        collapse_hdist,
+        max_members_per_cluster,
+        skip_trimming,
+        clusters_fastq,
+        cluster_stats,
+        cluster_prefix,
+        cluster_start,
+        sort_clusters,
+        long_reads,
+        long_min_length,
+        long_min_read_quality,
+        validate_inputs: _,
+        legacy_seed_derivation: _,
+        on_bad_record,
+        compression,
         clipping_args,
+        tee,
+        trace,
     } = args;
 
+    let skip_bad_records = on_bad_record == OnBadRecord::Skip;
+    let (tee_path1, tee_path2) = tee.paths();
     let readers = InputOptions::new_from_paths(&fastq_input, fastq_input2.as_ref())
         .use_file_or_zip()
         .decode_in_thread()
-        .parse_fastq()
+        .tee_to(tee_path1, tee_path2)
+        .parse_fastq_tolerant(skip_bad_records)
         .open()?;
 
     let RecordReaders { reader1, reader2 } = readers;
@@ -186,6 +446,18 @@ fn parse_preprocess_args(args: PreprocessArgs) -> std::io::Result<ParsedPreproce
     };
     let reader2 = fastq_input2.zip(reader2).map(|(path, iter)| Reader { path, iter });
 
+    let long_reader = match &long_reads {
+        Some(path) => Some(LongReader {
+            iter: InputOptions::new_from_path(path)
+                .use_file_or_zip()
+                .decode_in_thread()
+                .parse_fastq_tolerant(skip_bad_records)
+                .open()?,
+            path: path.clone(),
+        }),
+        None => None,
+    };
+
     let log_writer = match log_file {
         Some(ref file_path) => Some(OutputOptions::new_from_path(file_path).use_file().open()?),
         None => None,
@@ -193,8 +465,30 @@ fn parse_preprocess_args(args: PreprocessArgs) -> std::io::Result<ParsedPreproce
 
     let table_writer = OutputOptions::new_from_path(&table_file).use_file().open()?;
 
-    let min_length = min_length.get();
+    let clusters_writer = match clusters_fastq {
+        Some(ref file_path) => Some(
+            OutputOptions::new_from_opt_path(Some(file_path))
+                .with_compression(compression.options(GzipFormat::Gzip))
+                .use_file_zip_or_stdout()
+                .open()?,
+        ),
+        None => None,
+    };
+
+    let stats_writer = match cluster_stats {
+        Some(ref file_path) => Some(OutputOptions::new_from_path(file_path).use_file().open()?),
+        None => None,
+    };
 
+    let quality_filter = parse_quality_filter_args(quality_filter);
+    // Long reads are not trimmed, so their minimum length is always enforced
+    let long_reads = long_reads.map(|_| ParsedQualityFilterArgs {
+        min_read_quality:       long_min_read_quality,
+        use_median:             quality_filter.use_median,
+        min_length:             long_min_length.get(),
+        enforce_clipped_length: true,
+        max_n_frac:             quality_filter.max_n_frac,
+    });
     let clipping_args = parse_clipping_args(clipping_args)?;
 
     let parsed = ParsedPreprocessArgs {
@@ -202,16 +496,29 @@ fn parse_preprocess_args(args: PreprocessArgs) -> std::io::Result<ParsedPreproce
             table_writer,
             reader1,
             reader2,
+            long_reader,
             log_writer,
             log_file,
+            clusters_writer,
+            stats_writer,
         },
         options: ParsedPreprocessOptions {
-            min_read_quality,
-            use_median,
-            min_length,
-            enforce_clipped_length,
+            quality_filter,
             filter_widows,
+            deflate_pairs,
+            collapse_hdist,
+            max_members: max_members_per_cluster.map(NonZeroUsize::get),
+            skip_trimming,
+            cluster_stats: cluster_stats.is_some(),
+            skip_bad_records,
+            long_reads,
+            cluster_naming: ClusterNaming {
+                prefix: cluster_prefix,
+                start:  cluster_start,
+            },
+            sort_clusters,
             clipping_args,
+            tracer: ReadTracer::new("preprocess", trace),
         },
     };
 
@@ -219,11 +526,12 @@ fn parse_preprocess_args(args: PreprocessArgs) -> std::io::Result<ParsedPreproce
 }
 
 /// Trims all sequences, applies quality filtering, and deflates the sequences.
-/// Returns the deflated sequences and the log file metadata.
+/// Returns the deflated sequences and the log file metadata, along with the
+/// metadata of the long reads if `--long-reads` was provided.
 #[allow(clippy::result_large_err)]
 fn trim_and_deflate(
     options: &ParsedPreprocessOptions, io_args: &mut ParsedPreprocessIoArgs,
-) -> std::io::Result<(DeflatedSequences, FastQMetadata)> {
+) -> std::io::Result<(DeflatedSequences, FastQMetadata, Option<FastQMetadata>)> {
     let Reader {
         path: input_path1,
         iter: reader1,
@@ -238,10 +546,18 @@ fn trim_and_deflate(
             iter: reader2,
         } = reader2;
 
-        if options.filter_widows {
+        if options.deflate_pairs {
+            reader1
+                .by_ref()
+                .zip_paired_reads(reader2.by_ref())
+                .try_for_each(|pair| {
+                    preprocess_joined_pair(pair?, &mut metadata, &mut deflated, options)
+                        .map_err(ZipPairedReadsError::IoError)
+                })
+                .map_err(|e: ZipPairedReadsError<FastQ>| e.add_path_context(input_path1, input_path2))?;
+        } else if options.filter_widows {
             let result = reader1.by_ref().zip_paired_reads(reader2.by_ref()).try_for_each(|pair| {
-                preprocess_pair(pair?, &mut metadata, &mut deflated, options);
-                Ok(())
+                preprocess_pair(pair?, &mut metadata, &mut deflated, options).map_err(ZipPairedReadsError::IoError)
             });
 
             match result {
@@ -260,13 +576,11 @@ fn trim_and_deflate(
                     err.warn(MODULE, "`--filter-widows` or `-f` is being disabled for the remainder of the processing. Consider rerunning with corrected inputs.", true);
 
                     std::iter::once(Ok(r1)).chain(reader1).try_for_each(|read| {
-                        preprocess_seq(&mut read?, ReadSide::R1, &mut metadata, &mut deflated, options);
-                        std::io::Result::Ok(())
+                        preprocess_seq(&mut read?, ReadSide::R1, &mut metadata, &mut deflated, options)
                     })?;
 
                     std::iter::once(Ok(r2)).chain(reader2).try_for_each(|read| {
-                        preprocess_seq(&mut read?, ReadSide::R2, &mut metadata, &mut deflated, options);
-                        std::io::Result::Ok(())
+                        preprocess_seq(&mut read?, ReadSide::R2, &mut metadata, &mut deflated, options)
                     })?;
                 }
                 Err(ZipPairedReadsError::ExtraFirstRead(r1)) => {
@@ -275,8 +589,7 @@ fn trim_and_deflate(
                     err.warn(MODULE, "`--filter-widows` or `-f` is being disabled for the remainder of the processing. Consider rerunning with corrected inputs.", true);
 
                     std::iter::once(Ok(r1)).chain(reader1).try_for_each(|read| {
-                        preprocess_seq(&mut read?, ReadSide::R1, &mut metadata, &mut deflated, options);
-                        std::io::Result::Ok(())
+                        preprocess_seq(&mut read?, ReadSide::R1, &mut metadata, &mut deflated, options)
                     })?;
                 }
                 Err(ZipPairedReadsError::ExtraSecondRead(r2)) => {
@@ -285,79 +598,76 @@ fn trim_and_deflate(
                     err.warn(MODULE, "`--filter-widows` or `-f` is being disabled for the remainder of the processing. Consider rerunning with corrected inputs.", true);
 
                     std::iter::once(Ok(r2)).chain(reader2).try_for_each(|read| {
-                        preprocess_seq(&mut read?, ReadSide::R2, &mut metadata, &mut deflated, options);
-                        std::io::Result::Ok(())
+                        preprocess_seq(&mut read?, ReadSide::R2, &mut metadata, &mut deflated, options)
                     })?;
                 }
             }
         } else {
-            reader1.try_for_each(|read| {
-                preprocess_seq(&mut read?, ReadSide::R1, &mut metadata, &mut deflated, options);
-                std::io::Result::Ok(())
-            })?;
-
-            reader2.try_for_each(|read| {
-                preprocess_seq(&mut read?, ReadSide::R2, &mut metadata, &mut deflated, options);
-                std::io::Result::Ok(())
-            })?;
+            reader1.try_for_each(|read| preprocess_seq(&mut read?, ReadSide::R1, &mut metadata, &mut deflated, options))?;
+
+            reader2.try_for_each(|read| preprocess_seq(&mut read?, ReadSide::R2, &mut metadata, &mut deflated, options))?;
         }
     } else {
-        reader1.try_for_each(|read| {
-            preprocess_seq(&mut read?, ReadSide::Unpaired, &mut metadata, &mut deflated, options);
-            std::io::Result::Ok(())
-        })?;
+        reader1
+            .try_for_each(|read| preprocess_seq(&mut read?, ReadSide::Unpaired, &mut metadata, &mut deflated, options))?;
     };
 
-    Ok((deflated, metadata))
-}
-
-/// Writes the table file to `table_writer` and the XFL file to STDOUT. The
-/// number of read patterns is returned.
-fn output_deflated_sequences(
-    metadata_by_sequence: DeflatedSequences, mut table_writer: impl Write,
-) -> std::io::Result<usize> {
-    let mut stdout_writer = OutputOptions::new_stdout().open()?;
-
-    let mut read_pattern_number = 0;
-    for (sequence, metadata) in metadata_by_sequence {
-        let cluster_size = metadata.len();
-
-        writeln!(
-            stdout_writer,
-            ">{CLUSTER_PREFIX}{read_pattern_number}%{cluster_size}\n{sequence}"
-        )?;
-
-        write!(table_writer, "{CLUSTER_PREFIX}{read_pattern_number}%{cluster_size}")?;
-        for (mut header, quality_scores) in metadata {
-            crate::shared::replace_tabs_with_spaces(&mut header);
-
-            // Validity: both `header` and `quality_scores` are tab free, the
-            // header by sanitization and quality scores by construction
-            // (graphic ASCII)
-            write!(table_writer, "\t{header}\t{quality_scores}")?;
+    metadata.bad_records_skipped =
+        warn_skipped_records(&io_args.reader1) + io_args.reader2.as_ref().map_or(0, warn_skipped_records);
+
+    let long_metadata = match &mut io_args.long_reader {
+        Some(long_reader) => {
+            let mut long_metadata = FastQMetadata::default();
+            long_reader
+                .iter
+                .try_for_each(|read| preprocess_long_read(&mut read?, &mut long_metadata, &mut deflated, options))?;
+            long_metadata.bad_records_skipped = warn_skipped_records(long_reader);
+            Some(long_metadata)
         }
-        writeln!(table_writer)?;
-        read_pattern_number += 1;
-    }
+        None => None,
+    };
 
-    table_writer.flush()?;
-    stdout_writer.flush()?;
+    Ok((deflated, metadata, long_metadata))
+}
 
-    Ok(read_pattern_number)
+/// Prints a warning if any structurally invalid records were skipped from
+/// `reader`, returning the number skipped.
+fn warn_skipped_records<R: Read>(reader: &Reader<R>) -> usize {
+    let path = &reader.path;
+    let reader = reader.iter.inner_iter();
+    if let Some((record, e)) = reader.first_skipped() {
+        e.warn(
+            MODULE,
+            &format!(
+                "Skipped {skipped} invalid FASTQ record(s) in '{path}'. The first was record {record}:",
+                skipped = reader.skipped(),
+                path = path.display()
+            ),
+            true,
+        );
+    }
+    reader.skipped()
 }
 
-/// Writes the log file.
+/// Writes the log file. The number of read patterns and the duplication and
+/// complexity metrics are derived from `cluster_sizes`.
 fn write_log(
-    mut log_writer: impl Write, metadata: &FastQMetadata, paired_reads: bool, read_pattern_count_passing: usize,
-    options: &ParsedPreprocessOptions, log_file: PathBuf,
-) -> Result<(), std::io::Error> {
+    mut log_writer: impl Write, metadata: &FastQMetadata, paired_reads: bool, cluster_sizes: &ClusterSizes,
+    read_patterns_collapsed: Option<usize>, options: &ParsedPreprocessOptions, log_file: &Path,
+) {
     let FastQMetadata {
         passed_qc_count,
         passed_len_count,
+        n_filtered_count,
+        recoded_bases,
+        non_iupac_bases,
+        strict_filtered_count,
         observed_q_max,
         observed_raw_reads,
         observed_max_read_len,
         observed_max_clipped_read_len,
+        run_metadata,
+        bad_records_skipped,
     } = metadata;
 
     writeln!(
@@ -374,21 +684,138 @@ fn write_log(
         READ_PATTERN_COUNT_PASSING\t{read_pattern_count_passing}\n\
         MIN_PHRED_QUALITY_THRESHOLD\t{min_read_quality}\n\
         MIN_READ_LENGTH_THRESHOLD\t{min_length}\n\
-        QUALITY_MEASURE\t{center_type}\
+        QUALITY_MEASURE\t{center_type}\n\
+        DUPLICATION_RATE\t{duplication_rate}\n\
+        LIBRARY_COMPLEXITY_CHAO1\t{chao1:.1}\n\
+        CLUSTER_SIZE_HISTOGRAM\t{cluster_sizes}\n\
+        HASH_SEED\t{hash_seed}\
         ",
-        num_files = if paired_reads { 2 } else { 1 },
+        num_files = 1 + usize::from(paired_reads) + usize::from(options.long_reads.is_some()),
+        read_pattern_count_passing = cluster_sizes.num_clusters(),
         r1_raw_reads = observed_raw_reads[0],
         r2_raw_reads = observed_raw_reads[1],
         observed_q_max = observed_q_max.map(|q| q.to_string()).unwrap_or_else(|| "NONE".to_string()),
-        min_read_quality = options.min_read_quality,
-        min_length = options.min_length,
-        center_type = if options.use_median { "median" } else { "average" },
+        min_read_quality = options.quality_filter.min_read_quality,
+        min_length = options.quality_filter.min_length,
+        center_type = if options.quality_filter.use_median {
+            "median"
+        } else {
+            "average"
+        },
+        duplication_rate = cluster_sizes
+            .duplication_rate()
+            .map(|rate| format!("{rate:.4}"))
+            .unwrap_or_else(|| "NONE".to_string()),
+        chao1 = cluster_sizes.chao1(),
+        hash_seed = get_seed().map_or_else(|| "NONE".to_string(), |seed| seed.to_string()),
     )
+    .and_then(|()| write_run_metadata(&mut log_writer, run_metadata.as_ref()))
+    .and_then(|()| match read_patterns_collapsed {
+        Some(collapsed) => writeln!(log_writer, "READ_PATTERNS_COLLAPSED\t{collapsed}"),
+        None => Ok(()),
+    })
+    .and_then(|()| match options.quality_filter.max_n_frac {
+        Some(max_n_frac) => writeln!(
+            log_writer,
+            "MAX_N_FRACTION_THRESHOLD\t{max_n_frac}\nREAD_COUNT_FILTERED_BY_N_FRACTION\t{n_filtered_count}"
+        ),
+        None => Ok(()),
+    })
+    .and_then(|()| {
+        writeln!(
+            log_writer,
+            "RECODED_BASES\t{recoded_bases}\nNON_IUPAC_BASES\t{non_iupac_bases}"
+        )
+    })
+    .and_then(|()| {
+        if options.clipping_args.strict_bases == Some(StrictBases::Drop) {
+            writeln!(log_writer, "READ_COUNT_FILTERED_BY_STRICT_BASES\t{strict_filtered_count}")
+        } else {
+            Ok(())
+        }
+    })
+    .and_then(|()| {
+        if options.skip_bad_records {
+            writeln!(log_writer, "BAD_RECORDS_SKIPPED\t{bad_records_skipped}")
+        } else {
+            Ok(())
+        }
+    })
+    .and_then(|()| {
+        if options.skip_trimming {
+            writeln!(log_writer, "TRIMMING_MODE\tskipped")
+        } else {
+            Ok(())
+        }
+    })
+    .and_then(|()| {
+        if is_legacy_seed_derivation() {
+            writeln!(log_writer, "HASH_SEED_DERIVATION\tlegacy")
+        } else {
+            Ok(())
+        }
+    })
     .unwrap_or_else(|e| {
         eprintln!("{MODULE} WARNING! Cannot write to {}. See: {e}", log_file.display());
     });
+}
 
-    Ok(())
+/// Writes the log lines of the long reads from `--long-reads`, following the
+/// lines written by [`write_log`].
+fn write_long_read_log(
+    mut log_writer: impl Write, long_metadata: &FastQMetadata, options: &ParsedPreprocessOptions, log_file: &Path,
+) {
+    let Some(long_reads) = &options.long_reads else {
+        return;
+    };
+
+    writeln!(
+        log_writer,
+        "\
+        OBSERVED_LONG_READS\t{observed_long_reads}\n\
+        OBSERVED_MAX_LONG_READ_LEN\t{observed_max_read_len}\n\
+        LONG_READ_COUNT_PASSING_ALL_QUALITY_CONTROL_FILTERS\t{passed_qc_count}\n\
+        LONG_READ_MIN_PHRED_QUALITY_THRESHOLD\t{min_read_quality}\n\
+        LONG_READ_MIN_LENGTH_THRESHOLD\t{min_length}\
+        ",
+        observed_long_reads = long_metadata.observed_raw_reads[0],
+        observed_max_read_len = long_metadata.observed_max_read_len,
+        passed_qc_count = long_metadata.passed_qc_count,
+        min_read_quality = long_reads.min_read_quality,
+        min_length = long_reads.min_length,
+    )
+    .and_then(|()| {
+        writeln!(
+            log_writer,
+            "LONG_READ_RECODED_BASES\t{}\nLONG_READ_NON_IUPAC_BASES\t{}",
+            long_metadata.recoded_bases, long_metadata.non_iupac_bases
+        )
+    })
+    .and_then(|()| {
+        if options.clipping_args.strict_bases == Some(StrictBases::Drop) {
+            writeln!(
+                log_writer,
+                "LONG_READ_COUNT_FILTERED_BY_STRICT_BASES\t{}",
+                long_metadata.strict_filtered_count
+            )
+        } else {
+            Ok(())
+        }
+    })
+    .and_then(|()| {
+        if options.skip_bad_records {
+            writeln!(
+                log_writer,
+                "LONG_READ_BAD_RECORDS_SKIPPED\t{}",
+                long_metadata.bad_records_skipped
+            )
+        } else {
+            Ok(())
+        }
+    })
+    .unwrap_or_else(|e| {
+        eprintln!("{MODULE} WARNING! Cannot write to {}. See: {e}", log_file.display());
+    });
 }
 
 /// Attempt to diagnose the problem when no reads pass all quality filters.
@@ -415,66 +842,210 @@ fn diagnose_none_passing(metadata: &FastQMetadata, paired_reads: bool, options:
     }
 
     if let Some(obs_max) = metadata.observed_q_max
-        && obs_max < f32::from(options.min_read_quality)
+        && obs_max < f32::from(options.quality_filter.min_read_quality)
     {
         eprintln!(
             "{MODULE} WARNING! The observed max phred quality score ({obs_max}) is below the user specified threshold (QUAL_THRESHOLD = {}).",
-            options.min_read_quality
+            options.quality_filter.min_read_quality
         );
     }
 
-    if metadata.observed_max_read_len < options.min_length {
+    if metadata.observed_max_read_len < options.quality_filter.min_length {
         eprintln!(
             "{MODULE} WARNING! The observed max read length ({}) is below the user specified threshold (MIN_LEN = {}).",
-            metadata.observed_max_read_len, options.min_length
+            metadata.observed_max_read_len, options.quality_filter.min_length
+        );
+    }
+}
+
+/// Attempt to diagnose why no long reads from `--long-reads` pass the quality
+/// filters, when no other reads pass either. Warnings are printed to STDERR.
+fn diagnose_no_long_reads_passing(long_metadata: &FastQMetadata, options: &ParsedPreprocessOptions) {
+    let Some(long_reads) = &options.long_reads else {
+        return;
+    };
+
+    if long_metadata.observed_raw_reads[0] == 0 {
+        eprintln!("{MODULE} WARNING! No reads were found in the long read input file.");
+        return;
+    }
+
+    if let Some(obs_max) = long_metadata.observed_q_max
+        && obs_max < f32::from(long_reads.min_read_quality)
+    {
+        eprintln!(
+            "{MODULE} WARNING! The observed max phred quality score of the long reads ({obs_max}) is below the user specified threshold (LONG_QUAL_THRESHOLD = {}).",
+            long_reads.min_read_quality
+        );
+    }
+
+    if long_metadata.observed_max_read_len < long_reads.min_length {
+        eprintln!(
+            "{MODULE} WARNING! The observed max long read length ({}) is below the user specified threshold (LONG_MIN_LEN = {}).",
+            long_metadata.observed_max_read_len, long_reads.min_length
         );
     }
 }
 
 /// Trims a read and tallies its metadata. `Some` is returned if it passes all
-/// quality filters.
+/// quality filters, and an error if it violates `--strict-bases fail`. Long
+/// reads are filtered with their own thresholds, and are not trimmed.
+///
+/// Each step is traced for reads selected with `--trace-read`.
 fn trim_filter_tally<'a>(
-    read: &'a mut FastQ, side: ReadSide, metadata: &mut FastQMetadata, options: &ParsedPreprocessOptions,
-) -> Option<FastQViewMut<'a>> {
+    read: &'a mut FastQ, side: ReadSide, technology: Technology, metadata: &mut FastQMetadata,
+    options: &ParsedPreprocessOptions,
+) -> std::io::Result<Option<FastQViewMut<'a>>> {
+    let quality_filter = options.quality_filter(technology);
+    let trace = options.tracer.start(&read.header);
+    trace.step("input", || {
+        let side = match technology {
+            Technology::Illumina => side.to_char().map_or("unpaired".to_string(), |side| format!("R{side}")),
+            Technology::Ont => "long".to_string(),
+        };
+        format!("{side} read of length {len}", len = read.sequence.len())
+    });
+
+    if metadata.observed_raw_reads[0] + metadata.observed_raw_reads[1] == 0 {
+        metadata.run_metadata = get_run_metadata(&read.header);
+    }
     metadata.observed_raw_reads += side.to_simd();
     metadata.observed_max_read_len = metadata.observed_max_read_len.max(read.sequence.len());
-    if read.sequence.len() < options.min_length {
-        return None;
+
+    let bases = NonCanonicalBases::new(read.sequence.as_bytes());
+    if !options.clipping_args.preserve_bases {
+        metadata.recoded_bases += bases.recoded;
+    }
+    metadata.non_iupac_bases += bases.non_iupac;
+    if bases.is_dropped(options.clipping_args.strict_bases, &read.header)? {
+        metadata.strict_filtered_count += 1;
+        trace.step("dropped", || {
+            format!(
+                "contains {count} character(s) that are not IUPAC nucleotide codes",
+                count = bases.non_iupac
+            )
+        });
+        return Ok(None);
     }
 
-    let mut _counts = TrimmedCounts::default();
-    let clipped = trim_read(read.as_view_mut(), false, &options.clipping_args, &mut _counts, false);
+    if !quality_filter.passes_raw_length(read.sequence.len()) {
+        trace.step("dropped", || {
+            format!(
+                "the untrimmed length {len} is below the minimum length {min_length}",
+                len = read.sequence.len(),
+                min_length = quality_filter.min_length
+            )
+        });
+        return Ok(None);
+    }
+
+    let clipped = if options.skip_trimming || technology == Technology::Ont {
+        // The bases are still recoded, matching the output of `trimmer`
+        let mut clipped = read.as_view_mut();
+        clipped.to_canonical_bases(!options.clipping_args.preserve_bases);
+        trace.step("trim", || match technology {
+            Technology::Illumina => "skipped with `--skip-trimming`".to_string(),
+            Technology::Ont => "skipped for long reads".to_string(),
+        });
+        clipped
+    } else {
+        let mut _counts = TrimmedCounts::default();
+        // The early exit in `trim_read` is disabled (with a minimum length of
+        // 0), since the maximum clipped length is logged even for filtered
+        // reads
+        trim_read(
+            read.as_view_mut(),
+            false,
+            &options.clipping_args,
+            &mut _counts,
+            false,
+            0,
+            side,
+            &trace,
+        )
+        .expect("Trimming should not exit early with a minimum length of 0")
+    };
 
     metadata.observed_max_clipped_read_len = metadata.observed_max_clipped_read_len.max(clipped.sequence.len());
-    if (options.enforce_clipped_length && clipped.sequence.len() < options.min_length) || clipped.sequence.is_empty() {
-        return None;
+    if !quality_filter.passes_clipped_length(clipped.sequence.len()) {
+        trace.step("dropped", || {
+            format!(
+                "the trimmed length {len} is empty or below the minimum length {min_length} (enforced with `-E`)",
+                len = clipped.sequence.len(),
+                min_length = quality_filter.min_length
+            )
+        });
+        return Ok(None);
     }
     metadata.passed_len_count += 1;
 
-    let read_q_center = clipped.get_q_center(options.use_median);
+    let read_q_center = clipped.get_q_center(quality_filter.use_median);
     metadata.observed_q_max = if read_q_center > metadata.observed_q_max {
         read_q_center
     } else {
         metadata.observed_q_max
     };
-    if read_q_center < Some(f32::from(options.min_read_quality)) {
-        return None;
+    if !quality_filter.passes_quality(read_q_center) {
+        trace.step("dropped", || {
+            format!(
+                "the quality center {read_q_center:?} is below the minimum quality {min_quality}",
+                min_quality = quality_filter.min_read_quality
+            )
+        });
+        return Ok(None);
+    }
+    if !quality_filter.passes_n_fraction(clipped.sequence.as_bytes()) {
+        metadata.n_filtered_count += 1;
+        trace.step("dropped", || {
+            format!(
+                "the N fraction {n_frac:.4} is above the maximum {max_n_frac}",
+                n_frac = n_fraction(clipped.sequence.as_bytes()),
+                max_n_frac = quality_filter.max_n_frac.unwrap_or_default()
+            )
+        });
+        return Ok(None);
     }
 
     metadata.passed_qc_count += 1;
+    trace.step("kept", || {
+        format!(
+            "length {len}, quality center {read_q_center:?}; stored for deflation",
+            len = clipped.sequence.len()
+        )
+    });
 
-    Some(clipped)
+    Ok(Some(clipped))
 }
 
-/// Fixes the header on a read and stores it to `deflated`.
-fn fix_and_store<'a>(mut trimmed: FastQViewMut<'a>, side: ReadSide, deflated: &mut DeflatedSequences) {
+/// Traces a read selected with `--trace-read` being dropped, since its mate was
+/// filtered.
+fn trace_widow(tracer: &ReadTracer, header: &str) {
+    tracer.start(header).step("dropped", || "its mate was filtered".to_string());
+}
+
+/// Fixes the header on a read and stores it to `deflated`, keeping at most
+/// `max_members` reads per cluster. The technology tag, if any, is appended to
+/// the header, and the read's mean quality score is tallied with
+/// `--cluster-stats`.
+fn fix_and_store<'a>(
+    mut trimmed: FastQViewMut<'a>, side: ReadSide, tag: Option<Technology>, deflated: &mut DeflatedSequences,
+    options: &ParsedPreprocessOptions,
+) {
     trimmed.fix_header(side.to_char());
 
-    let header = std::mem::take(trimmed.header);
+    let mut header = std::mem::take(trimmed.header);
+    if let Some(technology) = tag {
+        header.push(' ');
+        header.push_str(technology.tag());
+    }
     let sequence = trimmed.sequence.to_owned_data();
     let quality = trimmed.quality.to_owned_data();
 
-    deflated.entry(sequence).or_default().push((header, quality));
+    let members = deflated.entry(sequence).or_default();
+    if options.cluster_stats {
+        members.tally_quality(mean_q(&[quality.as_bytes()]));
+    }
+    members.push_capped((header, quality), options.max_members);
 }
 
 /// Preprocesses a single sequence.
@@ -488,10 +1059,22 @@ fn fix_and_store<'a>(mut trimmed: FastQViewMut<'a>, side: ReadSide, deflated: &m
 fn preprocess_seq(
     read: &mut FastQ, side: ReadSide, metadata: &mut FastQMetadata, deflated: &mut DeflatedSequences,
     options: &ParsedPreprocessOptions,
-) {
-    if let Some(trimmed) = trim_filter_tally(read, side, metadata, options) {
-        fix_and_store(trimmed, side, deflated);
+) -> std::io::Result<()> {
+    if let Some(trimmed) = trim_filter_tally(read, side, Technology::Illumina, metadata, options)? {
+        fix_and_store(trimmed, side, options.technology_tag(Technology::Illumina), deflated, options);
+    }
+    Ok(())
+}
+
+/// Preprocesses a single long read from `--long-reads`, following the steps of
+/// [`preprocess_seq`] without trimming. The read is tagged with its technology.
+fn preprocess_long_read(
+    read: &mut FastQ, metadata: &mut FastQMetadata, deflated: &mut DeflatedSequences, options: &ParsedPreprocessOptions,
+) -> std::io::Result<()> {
+    if let Some(filtered) = trim_filter_tally(read, ReadSide::Unpaired, Technology::Ont, metadata, options)? {
+        fix_and_store(filtered, ReadSide::Unpaired, Some(Technology::Ont), deflated, options);
     }
+    Ok(())
 }
 
 /// Preprocesses a pair of reads, discarding any widows.
@@ -504,14 +1087,65 @@ fn preprocess_seq(
 /// 5. Adding to the deflated sequences hashmap
 fn preprocess_pair(
     pair: [FastQ; 2], metadata: &mut FastQMetadata, deflated: &mut DeflatedSequences, options: &ParsedPreprocessOptions,
-) {
+) -> std::io::Result<()> {
     let [mut read1, mut read2] = pair;
-    let Some(r1_trimmed) = trim_filter_tally(&mut read1, ReadSide::R1, metadata, options) else {
-        return;
+    let Some(r1_trimmed) = trim_filter_tally(&mut read1, ReadSide::R1, Technology::Illumina, metadata, options)? else {
+        trace_widow(&options.tracer, &read2.header);
+        return Ok(());
     };
-    let Some(r2_trimmed) = trim_filter_tally(&mut read2, ReadSide::R2, metadata, options) else {
-        return;
+    let Some(r2_trimmed) = trim_filter_tally(&mut read2, ReadSide::R2, Technology::Illumina, metadata, options)? else {
+        trace_widow(&options.tracer, r1_trimmed.header);
+        return Ok(());
     };
-    fix_and_store(r1_trimmed, ReadSide::R1, deflated);
-    fix_and_store(r2_trimmed, ReadSide::R2, deflated);
+    let tag = options.technology_tag(Technology::Illumina);
+    fix_and_store(r1_trimmed, ReadSide::R1, tag, deflated, options);
+    fix_and_store(r2_trimmed, ReadSide::R2, tag, deflated, options);
+    Ok(())
+}
+
+/// Preprocesses a pair of reads, storing them as a single molecule for
+/// `--deflate-pairs`.
+///
+/// This follows the steps of [`preprocess_pair`], except that the two reads
+/// are joined (see [`join_pair`]) before being added to the deflated sequences
+/// hashmap.
+fn preprocess_joined_pair(
+    pair: [FastQ; 2], metadata: &mut FastQMetadata, deflated: &mut DeflatedSequences, options: &ParsedPreprocessOptions,
+) -> std::io::Result<()> {
+    let [mut read1, mut read2] = pair;
+    let Some(mut r1_trimmed) = trim_filter_tally(&mut read1, ReadSide::R1, Technology::Illumina, metadata, options)? else {
+        trace_widow(&options.tracer, &read2.header);
+        return Ok(());
+    };
+    let Some(mut r2_trimmed) = trim_filter_tally(&mut read2, ReadSide::R2, Technology::Illumina, metadata, options)? else {
+        trace_widow(&options.tracer, r1_trimmed.header);
+        return Ok(());
+    };
+    r1_trimmed.fix_header(ReadSide::R1.to_char());
+    r2_trimmed.fix_header(ReadSide::R2.to_char());
+
+    // Tallied before joining, so that the separator is not counted
+    let pair_mean_q = options
+        .cluster_stats
+        .then(|| mean_q(&[r1_trimmed.quality.as_bytes(), r2_trimmed.quality.as_bytes()]));
+
+    let (header, sequence, quality) = join_pair([
+        (
+            std::mem::take(r1_trimmed.header),
+            r1_trimmed.sequence.as_bytes(),
+            r1_trimmed.quality.as_bytes(),
+        ),
+        (
+            std::mem::take(r2_trimmed.header),
+            r2_trimmed.sequence.as_bytes(),
+            r2_trimmed.quality.as_bytes(),
+        ),
+    ]);
+
+    let members = deflated.entry(sequence).or_default();
+    if let Some(pair_mean_q) = pair_mean_q {
+        members.tally_quality(pair_mean_q);
+    }
+    members.push_capped((header, quality), options.max_members);
+    Ok(())
 }
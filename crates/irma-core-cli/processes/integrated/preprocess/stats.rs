@@ -1,13 +1,32 @@
-use std::simd::prelude::*;
+use crate::shared::xfl::{ClusterNaming, DeflatedSequences, OrderedClusters, XflLayout};
+use irma_records::paired::RunMetadata;
+use std::{collections::BTreeMap, fmt::Display, io::Write, simd::prelude::*};
+use zoe::prelude::Len;
 
 #[derive(Debug)]
 pub(crate) struct FastQMetadata {
     pub(crate) passed_qc_count:               usize,
     pub(crate) passed_len_count:              usize,
+    /// The number of reads passing the other filters but dropped by
+    /// `--max-n-frac`
+    pub(crate) n_filtered_count:              usize,
+    /// The number of bases recoded to canonical uppercase ACGTN, which is
+    /// zero with `--preserve-bases`
+    pub(crate) recoded_bases:                 usize,
+    /// The number of characters that are not IUPAC nucleotide codes
+    pub(crate) non_iupac_bases:               usize,
+    /// The number of reads dropped by `--strict-bases drop`
+    pub(crate) strict_filtered_count:         usize,
     pub(crate) observed_q_max:                Option<f32>,
     pub(crate) observed_raw_reads:            Simd<usize, 2>,
     pub(crate) observed_max_read_len:         usize,
     pub(crate) observed_max_clipped_read_len: usize,
+    /// The run-level metadata parsed from the header of the first read, if it
+    /// is an Illumina or SRA-style header
+    pub(crate) run_metadata:                  Option<RunMetadata>,
+    /// The number of structurally invalid records skipped with
+    /// `--on-bad-record skip`
+    pub(crate) bad_records_skipped:           usize,
 }
 
 impl Default for FastQMetadata {
@@ -15,10 +34,143 @@ impl Default for FastQMetadata {
         FastQMetadata {
             passed_qc_count:               0,
             passed_len_count:              0,
+            n_filtered_count:              0,
+            recoded_bases:                 0,
+            non_iupac_bases:               0,
+            strict_filtered_count:         0,
             observed_q_max:                None,
             observed_raw_reads:            Simd::splat(0),
             observed_max_read_len:         0,
             observed_max_clipped_read_len: 0,
+            run_metadata:                  None,
+            bad_records_skipped:           0,
         }
     }
 }
+
+/// The distribution of cluster sizes (the number of reads or pairs sharing
+/// each read pattern), from which the dataset-level duplication and complexity
+/// metrics in the log are derived.
+#[derive(Debug, Default)]
+pub(crate) struct ClusterSizes {
+    /// The number of clusters of each size
+    histogram:    BTreeMap<usize, usize>,
+    /// The number of clusters
+    num_clusters: usize,
+    /// The number of reads (or pairs) across all clusters
+    num_reads:    usize,
+}
+
+impl ClusterSizes {
+    /// Tallies the cluster sizes of the deflated sequences.
+    pub(crate) fn new(deflated: &DeflatedSequences) -> Self {
+        let mut sizes = ClusterSizes::default();
+        for metadata in deflated.values() {
+            *sizes.histogram.entry(metadata.len()).or_default() += 1;
+            sizes.num_clusters += 1;
+            sizes.num_reads += metadata.len();
+        }
+        sizes
+    }
+
+    /// The number of clusters.
+    pub(crate) fn num_clusters(&self) -> usize {
+        self.num_clusters
+    }
+
+    /// The fraction of reads that duplicate an earlier read, `1 - clusters /
+    /// reads`, or `None` if there are no reads.
+    pub(crate) fn duplication_rate(&self) -> Option<f64> {
+        (self.num_reads > 0).then(|| 1.0 - self.num_clusters as f64 / self.num_reads as f64)
+    }
+
+    /// The bias-corrected Chao1 estimate of the number of distinct molecules
+    /// in the library, `S + F1 * (F1 - 1) / (2 * (F2 + 1))`, where `S` is the
+    /// number of clusters and `F1` and `F2` are the numbers of clusters of size
+    /// one and two. An estimate far above the number of clusters suggests the
+    /// library was undersampled, while one close to it suggests it was
+    /// saturated (as for an over-amplified library).
+    pub(crate) fn chao1(&self) -> f64 {
+        let singletons = self.histogram.get(&1).copied().unwrap_or_default() as f64;
+        let doubletons = self.histogram.get(&2).copied().unwrap_or_default() as f64;
+        self.num_clusters as f64 + singletons * (singletons - 1.0).max(0.0) / (2.0 * (doubletons + 1.0))
+    }
+}
+
+impl Display for ClusterSizes {
+    /// Formats the histogram as comma-separated `size:count` pairs in order of
+    /// increasing size, or `NONE` if there are no clusters.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.histogram.is_empty() {
+            return write!(f, "NONE");
+        }
+        for (i, (size, count)) in self.histogram.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{size}:{count}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the run-level metadata parsed from the first header to the log, with
+/// the instrument, run ID, flowcell, and lane of an Illumina run or the
+/// accession of an SRA run. Nothing is written if the header was not
+/// recognized.
+pub(crate) fn write_run_metadata(mut writer: impl Write, run_metadata: Option<&RunMetadata>) -> std::io::Result<()> {
+    match run_metadata {
+        Some(RunMetadata::Illumina {
+            instrument,
+            run_id,
+            flowcell,
+            lane,
+        }) => writeln!(
+            writer,
+            "INSTRUMENT\t{instrument}\nRUN_ID\t{run_id}\nFLOWCELL\t{flowcell}\nLANE\t{lane}"
+        ),
+        Some(RunMetadata::Sra { accession }) => writeln!(writer, "SRA_ACCESSION\t{accession}"),
+        None => Ok(()),
+    }
+}
+
+/// The mean phred quality score of the bases of a read, or of both reads of a
+/// pair with `--deflate-pairs`, given as their encoded quality scores.
+pub(crate) fn mean_q(qualities: &[&[u8]]) -> f32 {
+    let (sum, len) = qualities.iter().fold((0usize, 0usize), |(sum, len), quality| {
+        let sum = sum + quality.iter().map(|&q| usize::from(q - b'!')).sum::<usize>();
+        (sum, len + quality.len())
+    });
+    if len == 0 { 0.0 } else { sum as f32 / len as f32 }
+}
+
+/// Writes the `--cluster-stats` table, with a line for each cluster giving
+/// its name (without the size), size, length, and the mean, minimum, and
+/// maximum of the mean quality scores of its reads. The clusters must be in
+/// the same order as given to [`write_deflated_sequences`]. For [`XflLayout::Pairs`], the length is the
+/// combined length of both reads.
+///
+/// [`write_deflated_sequences`]: crate::shared::xfl::write_deflated_sequences
+pub(crate) fn write_cluster_stats(
+    clusters: &OrderedClusters, layout: XflLayout, naming: &ClusterNaming, mut writer: impl Write,
+) -> std::io::Result<()> {
+    writeln!(writer, "cluster\tsize\tlength\tmean_q\tmin_q\tmax_q")?;
+    for (i, (sequence, members)) in clusters.iter().enumerate() {
+        let length = match layout {
+            XflLayout::Reads => sequence.len(),
+            XflLayout::Pairs => sequence.len() - 1,
+        };
+        write!(
+            writer,
+            "{prefix}{i}\t{size}\t{length}",
+            prefix = naming.prefix,
+            i = naming.start + i,
+            size = members.len()
+        )?;
+        match members.qualities.mean_min_max() {
+            Some((mean, min, max)) => writeln!(writer, "\t{mean:.2}\t{min:.2}\t{max:.2}")?,
+            None => writeln!(writer, "\tNA\tNA\tNA")?,
+        }
+    }
+    writer.flush()
+}
@@ -0,0 +1,35 @@
+//! The `--validate-inputs` pre-pass, which reads every input in full before
+//! any outputs are written.
+
+use super::MODULE;
+use irma_records::io::{InputOptions, SkipRecords};
+use std::path::Path;
+
+/// Reads each input in full, failing on the first that is corrupt, and prints
+/// the number of records in each. Each input is paired with a label for its
+/// side, such as `R1`.
+///
+/// Zipped inputs are decompressed on a separate thread, which checks the CRC
+/// and length of every gzip member. Records are skipped rather than parsed
+/// (see [`SkipRecords`]), so only their overall structure is checked, such as a
+/// file ending partway through a record.
+///
+/// ## Errors
+///
+/// Any IO error, decompression error, or truncated record is returned, with the
+/// path as context.
+pub(super) fn validate_inputs(inputs: &[(&str, &Path)]) -> std::io::Result<()> {
+    for &(side, path) in inputs {
+        let records = InputOptions::new_from_path(path)
+            .use_file_or_zip()
+            .decode_in_thread()
+            .parse_fastq_skippable()
+            .open()?
+            .skip_remaining()?;
+        eprintln!(
+            "{MODULE}: validated {records} records in the {side} input {path}",
+            path = path.display()
+        );
+    }
+    Ok(())
+}
@@ -1,23 +1,34 @@
-//! Reads FastQ files and deflates into a custom XFL format, converting to FASTA
-//! as well. Also can re-inflate back to FASTQ.
+//! Reads FastQ files (or the primary alignments of SAM/BAM files) and deflates
+//! into a custom XFL format, converting to FASTA as well. Also can re-inflate
+//! back to FASTQ.
 
+use crate::shared::xfl::{
+    self, ClusterNaming, DeflatedSequences, XflHeader, XflLayout, find_pair_separator, order_clusters, parse_cluster_id,
+    write_deflated_sequences,
+};
 use clap::Parser;
+use flate2::read::MultiGzDecoder;
+use foldhash::fast::SeedableRandomState;
 use irma_records::{
     hashing::get_hasher,
     io::{InputOptions, OutputOptions, ValidatePaths},
+    paired::ReadSide,
 };
 use std::{
     collections::HashMap,
-    io::{BufRead, Write},
+    io::{BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
 };
 use zoe::{
-    data::{fasta::FastaSeq, fastq::FastQ, types::phred::QualityScores},
-    prelude::Nucleotides,
+    data::{
+        err::ResultWithErrorContext,
+        fasta::FastaSeq,
+        fastq::FastQ,
+        sam::{SamData, SamRow},
+    },
+    prelude::{Len, Nucleotides, QualityScores},
 };
 
-const CLUSTER_PREFIX: &str = "C";
-
 #[derive(Debug, Parser)]
 #[command(version, about)]
 pub struct XflateArgs {
@@ -29,6 +40,12 @@ pub struct XflateArgs {
     /// Inflate sequence files
     #[arg(short, long)]
     inflate: bool,
+
+    /// Deflate from the primary alignments of SAM or BAM files instead of
+    /// FastQ files. Files ending in `.bam` are read as BAM. Paired reads are
+    /// given a `/1` or `/2` header suffix from their SAM flags
+    #[arg(long, conflicts_with = "inflate")]
+    sam: bool,
 }
 
 impl ValidatePaths for XflateArgs {
@@ -42,18 +59,40 @@ impl ValidatePaths for XflateArgs {
     }
 }
 
+/// The sequences of the clusters in the deflated FASTA files, keyed by cluster
+/// prefix and then by cluster number.
+type ClusterSequences = HashMap<String, HashMap<usize, Nucleotides, SeedableRandomState>, SeedableRandomState>;
+
 /// ## Validity
 ///
 /// This function returns an error intended to be displayed at the top-level. No
 /// callers should add additional context other than calling a method in
 /// [`OrFail`].
 ///
+/// ## Errors
+///
+/// If the XFL table has a header, the FASTA files are verified against it and
+/// an error is returned when they could not have been deflated together. For
+/// tables with or without a header, an error is returned if a cluster sequence
+/// and its quality scores differ in length, or if a cluster of a table with the
+/// [`XflLayout::Pairs`] layout is not a joined pair.
+///
+/// Tables from sharded runs may be concatenated, in which case each header
+/// begins a section of the table that is verified separately.
+///
+/// For the [`XflLayout::Pairs`] layout, both reads of each pair are written,
+/// interleaved.
+///
+/// For tables written with a cap on the reads stored per cluster (see
+/// [`XflHeader::max_members`]), only the stored reads can be written, and a
+/// warning is printed with the number that could not be.
+///
 /// [`OrFail`]: zoe::data::err::OrFail
 fn inflate(table_file: &Path, fasta_files: &Vec<PathBuf>) -> Result<(), std::io::Error> {
     let table_reader = InputOptions::new_from_path(table_file).use_file().open()?;
     let mut stdout_writer = OutputOptions::new_stdout().open()?;
 
-    let mut sequence_by_cluster = HashMap::with_hasher(get_hasher());
+    let mut sequence_by_cluster: ClusterSequences = HashMap::with_hasher(get_hasher());
 
     for file in fasta_files {
         let reader = InputOptions::new_from_path(file).use_file().parse_fasta().open()?;
@@ -62,15 +101,24 @@ fn inflate(table_file: &Path, fasta_files: &Vec<PathBuf>) -> Result<(), std::io:
             let FastaSeq { name, sequence } = record?;
             let mut sequence = Nucleotides::from_vec_unchecked(sequence);
 
-            let cluster_num = parse_cluster_num(&name, file)?;
+            let (prefix, cluster_num) = parse_cluster_num(&name, file)?;
 
             if name.ends_with("{c}") {
                 sequence.make_reverse_complement();
             }
-            sequence_by_cluster.insert(cluster_num, sequence);
+            sequence_by_cluster
+                .entry(prefix.to_string())
+                .or_insert_with(|| HashMap::with_hasher(get_hasher()))
+                .insert(cluster_num, sequence);
         }
     }
 
+    let mut xfl_headers: Vec<XflHeader> = Vec::new();
+
+    let mut observed_clusters = 0;
+    let mut observed_reads = 0;
+    let mut unstored_reads = 0;
+
     for table_record in table_reader.lines() {
         let data = table_record?;
 
@@ -78,6 +126,19 @@ fn inflate(table_file: &Path, fasta_files: &Vec<PathBuf>) -> Result<(), std::io:
             continue;
         }
 
+        if let Some(xfl_header) = XflHeader::parse(&data, table_file)? {
+            if let Some(previous) = xfl_headers.last() {
+                verify_section_counts(previous, observed_clusters, observed_reads, table_file)?;
+            }
+            verify_fasta_checksum(&xfl_header, &sequence_by_cluster, table_file)?;
+            xfl_headers.push(xfl_header);
+            (observed_clusters, observed_reads) = (0, 0);
+            continue;
+        }
+
+        let layout = xfl_headers.last().map_or(XflLayout::Reads, |xfl_header| xfl_header.layout);
+        let capped = xfl_headers.last().is_some_and(|xfl_header| xfl_header.max_members.is_some());
+
         let mut split = data.split('\t');
 
         let Some(name) = split.next() else {
@@ -85,27 +146,203 @@ fn inflate(table_file: &Path, fasta_files: &Vec<PathBuf>) -> Result<(), std::io:
             continue;
         };
 
-        let cluster_num = parse_cluster_num(name, table_file)?;
+        let (prefix, cluster_num) = parse_cluster_num(name, table_file)?;
+        observed_clusters += 1;
+
+        // The reads of a capped cluster which were not stored are only counted
+        // in its size
+        if capped {
+            let stored = data.matches('\t').count() / layout.fields_per_read();
+            let unstored = parse_cluster_size(name, table_file)?.saturating_sub(stored);
+            observed_reads += unstored;
+            unstored_reads += unstored;
+        }
+
+        let Some(sequence) = sequence_by_cluster
+            .get(prefix)
+            .and_then(|sequences| sequences.get(&cluster_num))
+        else {
+            observed_reads += split.count() / layout.fields_per_read();
+            continue;
+        };
 
-        if let Some(sequence) = sequence_by_cluster.get(&cluster_num) {
-            while let (Some(header), Some(quality)) = (split.next(), split.next()) {
-                write!(stdout_writer, "@{header}\n{sequence}\n+\n{quality}\n")?;
+        let check_quality_len = |header: &str, quality: &str| {
+            if quality.len() == sequence.len() {
+                Ok(())
+            } else {
+                Err(mismatched_table_error(
+                    format!(
+                        "Cluster {name} has a sequence of length {seq_len} but the read '{header}' has {qual_len} quality scores",
+                        seq_len = sequence.len(),
+                        qual_len = quality.len()
+                    ),
+                    table_file,
+                ))
+            }
+        };
+
+        match layout {
+            XflLayout::Reads => {
+                while let (Some(header), Some(quality)) = (split.next(), split.next()) {
+                    check_quality_len(header, quality)?;
+                    write!(stdout_writer, "@{header}\n{sequence}\n+\n{quality}\n")?;
+                    observed_reads += 1;
+                }
+            }
+            XflLayout::Pairs => {
+                let Some(position) = find_pair_separator(sequence.as_bytes()) else {
+                    return Err(mismatched_table_error(
+                        format!("Cluster {name} is not a joined read pair, but the XFL table has the pairs layout"),
+                        table_file,
+                    ));
+                };
+                let (sequence1, sequence2) = (&sequence.as_bytes()[..position], &sequence.as_bytes()[position + 1..]);
+
+                while let (Some(header1), Some(header2), Some(quality)) = (split.next(), split.next(), split.next()) {
+                    check_quality_len(header1, quality)?;
+                    let (quality1, quality2) = (&quality.as_bytes()[..position], &quality.as_bytes()[position + 1..]);
+
+                    for (header, sequence, quality) in [(header1, sequence1, quality1), (header2, sequence2, quality2)] {
+                        writeln!(stdout_writer, "@{header}")?;
+                        stdout_writer.write_all(sequence)?;
+                        stdout_writer.write_all(b"\n+\n")?;
+                        stdout_writer.write_all(quality)?;
+                        writeln!(stdout_writer)?;
+                    }
+                    observed_reads += 1;
+                }
             }
         }
     }
 
+    if let Some(last) = xfl_headers.last() {
+        verify_section_counts(last, observed_clusters, observed_reads, table_file)?;
+        verify_clusters_listed(&xfl_headers, &sequence_by_cluster, table_file)?;
+    }
+
     stdout_writer.flush()?;
 
+    if unstored_reads > 0 {
+        eprintln!(
+            "IRMA-core WARNING! {unstored_reads} reads were counted but not stored in the XFL table (see `--max-members-per-cluster`), so they were not reinflated."
+        );
+    }
+
     Ok(())
 }
 
-fn deflate(table_file: &Path, fastq_files: &Vec<PathBuf>) -> Result<(), std::io::Error> {
-    let mut table_writer = OutputOptions::new_from_path(table_file).use_file().open()?;
-    let mut stdout_writer = OutputOptions::new_stdout().open()?;
+/// Verifies that the number of clusters and reads in a section of the XFL
+/// table match its header.
+fn verify_section_counts(
+    xfl_header: &XflHeader, observed_clusters: usize, observed_reads: usize, table_file: &Path,
+) -> std::io::Result<()> {
+    if observed_clusters == xfl_header.clusters && observed_reads == xfl_header.reads {
+        Ok(())
+    } else {
+        Err(mismatched_table_error(
+            format!(
+                "The XFL header expects {clusters} clusters and {reads} reads, but {observed_clusters} clusters and {observed_reads} reads were found. The table may be truncated",
+                clusters = xfl_header.clusters,
+                reads = xfl_header.reads,
+            ),
+            table_file,
+        ))
+    }
+}
+
+/// Verifies the checksum of the deflated FASTA sequences against an XFL
+/// header, if all of the header's clusters are present. Otherwise, the FASTA
+/// files are assumed to be a subset of the deflated sequences (such as after
+/// sorting).
+fn verify_fasta_checksum(
+    xfl_header: &XflHeader, sequence_by_cluster: &ClusterSequences, table_file: &Path,
+) -> std::io::Result<()> {
+    let ClusterNaming { prefix, start } = &xfl_header.naming;
+    let Some(sequences) = sequence_by_cluster.get(prefix) else {
+        return Ok(());
+    };
+
+    let observed = (0..xfl_header.clusters)
+        .map(|i| sequences.get(&(start + i)).map(|sequence| (sequence, 0)))
+        .collect::<Option<Vec<_>>>()
+        .map(|clusters| XflHeader::from_clusters(clusters, xfl_header.layout, xfl_header.naming.clone()));
+
+    if let Some(observed) = observed
+        && observed.crc32 != xfl_header.crc32
+    {
+        return Err(mismatched_table_error(
+            format!(
+                "The checksum of the FASTA sequences ({observed:08x}) does not match the XFL header ({expected:08x})",
+                observed = observed.crc32,
+                expected = xfl_header.crc32
+            ),
+            table_file,
+        ));
+    }
+
+    Ok(())
+}
 
-    let mut metadata_by_sequence: HashMap<Nucleotides, Vec<(String, QualityScores)>, _> = HashMap::with_hasher(get_hasher());
+/// Verifies that every cluster in the deflated FASTA files is numbered within
+/// the range given by one of the XFL headers.
+fn verify_clusters_listed(
+    xfl_headers: &[XflHeader], sequence_by_cluster: &ClusterSequences, table_file: &Path,
+) -> std::io::Result<()> {
+    let unlisted = sequence_by_cluster.iter().find_map(|(prefix, sequences)| {
+        sequences
+            .keys()
+            .find(|&&num| {
+                !xfl_headers
+                    .iter()
+                    .any(|xfl_header| xfl_header.naming.contains(prefix, num, xfl_header.clusters))
+            })
+            .map(|num| (prefix, num))
+    });
+
+    if let Some((prefix, cluster_num)) = unlisted {
+        let ranges = xfl_headers
+            .iter()
+            .map(|xfl_header| {
+                let ClusterNaming { prefix, start } = &xfl_header.naming;
+                format!(
+                    "{prefix}{start} to {prefix}{end}",
+                    end = (start + xfl_header.clusters).saturating_sub(1)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(mismatched_table_error(
+            format!("The cluster {prefix}{cluster_num} is not present in the XFL table, which has clusters {ranges}"),
+            table_file,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates an error for when the FASTA files and XFL table do not correspond.
+fn mismatched_table_error(msg: String, table_file: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "{msg}\n\nThe FASTA file(s) do not appear to have been deflated alongside the XFL table: {path}",
+            path = table_file.display()
+        ),
+    )
+}
+
+fn deflate(table_file: &Path, seq_files: &Vec<PathBuf>, sam: bool) -> Result<(), std::io::Error> {
+    let table_writer = OutputOptions::new_from_path(table_file).use_file().open()?;
+    let stdout_writer = OutputOptions::new_stdout().open()?;
+
+    let mut metadata_by_sequence: DeflatedSequences = HashMap::with_hasher(get_hasher());
+
+    for file in seq_files {
+        if sam {
+            add_alignments(file, &mut metadata_by_sequence)?;
+            continue;
+        }
 
-    for file in fastq_files {
         let reader = InputOptions::new_from_path(file).use_file().parse_fastq().open()?;
         for record in reader {
             let FastQ {
@@ -118,25 +355,15 @@ fn deflate(table_file: &Path, fastq_files: &Vec<PathBuf>) -> Result<(), std::io:
         }
     }
 
-    for (i, (sequence, metadata)) in metadata_by_sequence.into_iter().enumerate() {
-        let cluster_size = metadata.len();
-
-        write!(stdout_writer, ">{CLUSTER_PREFIX}{i}%{cluster_size}\n{sequence}\n")?;
-
-        write!(table_writer, "{CLUSTER_PREFIX}{i}%{cluster_size}")?;
-        for (mut header, quality_scores) in metadata {
-            crate::shared::replace_tabs_with_spaces(&mut header);
-
-            // Validity: both `header` and `quality_scores` are tab free, the
-            // header by sanitization and quality scores by construction
-            // (graphic ASCII)
-            write!(table_writer, "\t{header}\t{quality_scores}")?;
-        }
-        writeln!(table_writer)?;
-    }
-
-    table_writer.flush()?;
-    stdout_writer.flush()?;
+    write_deflated_sequences(
+        order_clusters(metadata_by_sequence, None),
+        XflLayout::Reads,
+        &ClusterNaming::default(),
+        None,
+        table_writer,
+        stdout_writer,
+        None::<std::io::Sink>,
+    )?;
 
     Ok(())
 }
@@ -148,34 +375,275 @@ pub fn xflate_process(args: XflateArgs) -> Result<(), std::io::Error> {
         // Validity: No context is added to the result
         inflate(&args.table_file, &args.seq_files)
     } else {
-        deflate(&args.table_file, &args.seq_files)
+        deflate(&args.table_file, &args.seq_files, args.sam)
+    }
+}
+
+/// Adds the reads of the primary alignments in a SAM or BAM file to the
+/// deflated sequences. Secondary and supplementary alignments are skipped,
+/// since they repeat a read already given by its primary alignment. Files
+/// ending in `.bam` are read as BAM, while other files are read as SAM (zipped
+/// if they end in `.gz`).
+fn add_alignments(file: &Path, metadata_by_sequence: &mut DeflatedSequences) -> std::io::Result<()> {
+    let mut add = |read: AlignedRead| -> std::io::Result<()> {
+        if let Some(FastQ {
+            header,
+            sequence,
+            quality,
+        }) = read.into_fastq()?
+        {
+            metadata_by_sequence.entry(sequence).or_default().push((header, quality));
+        }
+        Ok(())
+    };
+
+    if file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("bam")) {
+        let reader = InputOptions::new_from_path(file).use_file().open()?;
+        let mut bam_reader = BamReader::new(MultiGzDecoder::new(reader)).with_context(format!(
+            "Failed to read the BAM header in file: {path}",
+            path = file.display()
+        ))?;
+
+        while let Some(read) = bam_reader
+            .next_read()
+            .with_context(format!("Invalid BAM record in file: {path}", path = file.display()))?
+        {
+            add(read).with_context(format!("Invalid BAM record in file: {path}", path = file.display()))?;
+        }
+    } else {
+        let reader = InputOptions::new_from_path(file).use_file_or_zip().parse_sam().open()?;
+        for row in reader {
+            if let SamRow::Data(data) = row? {
+                add(AlignedRead::from(data))
+                    .with_context(format!("Invalid SAM record in file: {path}", path = file.display()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The fields of a SAM or BAM alignment needed to recover the read as FastQ.
+struct AlignedRead {
+    qname:    String,
+    flag:     u16,
+    sequence: Vec<u8>,
+    quality:  Vec<u8>,
+}
+
+impl From<SamData> for AlignedRead {
+    fn from(data: SamData) -> Self {
+        AlignedRead {
+            qname:    data.qname,
+            flag:     data.flag,
+            sequence: data.seq.into_vec(),
+            quality:  data.qual.as_bytes().to_vec(),
+        }
+    }
+}
+
+impl AlignedRead {
+    /// The SAM flags for secondary (256) and supplementary (2048) alignments.
+    const NOT_PRIMARY: u16 = 0x900;
+
+    /// The SAM flag for an alignment against the reverse strand.
+    const REVERSE: u16 = 0x10;
+
+    /// Recovers the read as it was sequenced, returning `None` if this is not a
+    /// primary alignment. For alignments against the reverse strand, the
+    /// sequence is reverse complemented and the quality scores are reversed.
+    ///
+    /// Since both reads of a pair share a QNAME, the read side given by the
+    /// first (64) and last (128) segment flags is appended to the header as a
+    /// legacy Illumina `/1` or `/2` suffix, so that R1 and R2 remain distinct
+    /// and can be paired again after inflation.
+    ///
+    /// ## Errors
+    ///
+    /// The sequence and quality scores must both be present (not `*`).
+    fn into_fastq(self) -> std::io::Result<Option<FastQ>> {
+        if self.flag & Self::NOT_PRIMARY != 0 {
+            return Ok(None);
+        }
+
+        if self.sequence.is_empty() || self.sequence == b"*" || self.quality.is_empty() || self.quality == b"*" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "The primary alignment of '{qname}' is missing its sequence or quality scores, which are needed for deflation",
+                    qname = self.qname
+                ),
+            ));
+        }
+
+        let mut sequence = Nucleotides::from_vec_unchecked(self.sequence);
+        let mut quality = QualityScores::try_from(self.quality)?;
+        if self.flag & Self::REVERSE != 0 {
+            sequence.make_reverse_complement();
+            quality.make_reverse();
+        }
+
+        let mut header = self.qname;
+        if let Some(side) = ReadSide::from_sam_flag(self.flag).to_char() {
+            header.push('/');
+            header.push(side);
+        }
+
+        Ok(Some(FastQ {
+            header,
+            sequence,
+            quality,
+        }))
+    }
+}
+
+/// A minimal reader for the alignments of a decompressed BAM file, decoding
+/// only the fields in [`AlignedRead`].
+struct BamReader<R> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> BamReader<R> {
+    /// The 4-bit encoding of the bases in a BAM sequence.
+    const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+    /// Creates a new [`BamReader`], reading past the BAM header.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the input does not begin with the BAM magic
+    /// string or the header is truncated.
+    fn new(reader: R) -> std::io::Result<Self> {
+        let mut bam_reader = BamReader {
+            reader: BufReader::new(reader),
+        };
+
+        let mut magic = [0; 4];
+        bam_reader.reader.read_exact(&mut magic)?;
+        if &magic != b"BAM\x01" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The file does not begin with the BAM magic string",
+            ));
+        }
+
+        let text_len = bam_reader.read_len()?;
+        bam_reader.skip(text_len)?;
+
+        let num_refs = bam_reader.read_len()?;
+        for _ in 0..num_refs {
+            let name_len = bam_reader.read_len()?;
+            bam_reader.skip(name_len + 4)?;
+        }
+
+        Ok(bam_reader)
+    }
+
+    /// Reads the next alignment, returning `None` at the end of the file.
+    fn next_read(&mut self) -> std::io::Result<Option<AlignedRead>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let block_len = self.read_len()?;
+        let mut block = vec![0; block_len];
+        self.reader.read_exact(&mut block)?;
+
+        let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "The BAM record is truncated");
+        let field = |start: usize, len: usize| block.get(start..start + len).ok_or_else(truncated);
+
+        // The fixed-length fields preceding the read name
+        let fixed = field(0, 32)?;
+        let name_len = usize::from(fixed[8]);
+        let num_cigar_ops = usize::from(u16::from_le_bytes([fixed[12], fixed[13]]));
+        let flag = u16::from_le_bytes([fixed[14], fixed[15]]);
+        let seq_len = u32::from_le_bytes([fixed[16], fixed[17], fixed[18], fixed[19]]) as usize;
+
+        let name_start = 32;
+        let seq_start = name_start + name_len + 4 * num_cigar_ops;
+        let qual_start = seq_start + seq_len.div_ceil(2);
+
+        // The read name is NUL terminated
+        let name = field(name_start, name_len)?;
+        let qname = String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(name)).into_owned();
+
+        let sequence = field(seq_start, seq_len.div_ceil(2))?
+            .iter()
+            .flat_map(|&byte| [Self::BASES[usize::from(byte >> 4)], Self::BASES[usize::from(byte & 0xF)]])
+            .take(seq_len)
+            .collect();
+
+        // Missing quality scores are stored as 0xFF
+        let quality = field(qual_start, seq_len)?;
+        let quality = if quality.first() == Some(&0xFF) {
+            Vec::new()
+        } else {
+            quality.iter().map(|&q| q.saturating_add(33)).collect()
+        };
+
+        Ok(Some(AlignedRead {
+            qname,
+            flag,
+            sequence,
+            quality,
+        }))
+    }
+
+    /// Reads a little-endian length field.
+    fn read_len(&mut self) -> std::io::Result<usize> {
+        let mut bytes = [0; 4];
+        self.reader.read_exact(&mut bytes)?;
+        usize::try_from(i32::from_le_bytes(bytes))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "The BAM file contains a negative length"))
+    }
+
+    /// Skips over `len` bytes.
+    fn skip(&mut self, len: usize) -> std::io::Result<()> {
+        let skipped = std::io::copy(&mut self.reader.by_ref().take(len as u64), &mut std::io::sink())?;
+        if skipped == len as u64 {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The BAM header is truncated",
+            ))
+        }
     }
 }
 
-/// Given a header containing the contents `name`, parse the cluster number from
-/// it.
+/// Given a header containing the contents `name`, parse the cluster prefix and
+/// number from it.
 ///
-/// The header should be of the format `C<ID>%[REST]`, where `<ID>` is the
-/// cluster number being parsed and `[REST]` is any additional optional
-/// characters.
+/// The header should be of the format `<PREFIX><ID>%[REST]`, where `<PREFIX>`
+/// is the cluster prefix (`C` by default), `<ID>` is the cluster number being
+/// parsed, and `[REST]` is any additional optional characters.
 ///
 /// ## Errors
 ///
 /// If `name` does not meet the required format, then an error is returned,
 /// including `name` and `path` as context.
-fn parse_cluster_num(name: &str, path: &Path) -> std::io::Result<usize> {
-    if let Some(name) = name.strip_prefix(CLUSTER_PREFIX)
-        && let Some(cluster_id) = name.split('%').next()
-        && let Ok(cluster_num) = cluster_id.parse::<usize>()
-    {
-        Ok(cluster_num)
-    } else {
-        Err(std::io::Error::new(
+fn parse_cluster_num<'a>(name: &'a str, path: &Path) -> std::io::Result<(&'a str, usize)> {
+    parse_cluster_id(name).ok_or_else(|| {
+        std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             format!(
-                "Invalid header in file: {path}\nHeader: {name}\n\nInflation requires a header of the format: C<ID>%[REST], where:\n  - <ID> is a nonnegative integer\n  - [REST] is any additional optional characters",
+                "Invalid header in file: {path}\nHeader: {name}\n\nInflation requires a header of the format: <PREFIX><ID>%[REST], where:\n  - <PREFIX> is the nonempty cluster prefix, such as C\n  - <ID> is a nonnegative integer\n  - [REST] is any additional optional characters",
                 path = path.display()
             ),
-        ))
-    }
+        )
+    })
+}
+
+/// Parses the size of a cluster from a name of the form `<PREFIX><ID>%<SIZE>`,
+/// as required for the clusters of a capped XFL table.
+fn parse_cluster_size(name: &str, path: &Path) -> std::io::Result<usize> {
+    xfl::parse_cluster_size(name).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid header in file: {path}\nHeader: {name}\n\nThe XFL table stores a limited number of reads per cluster, so each cluster must be named <PREFIX><ID>%<SIZE> with its full size",
+                    path = path.display()
+                ),
+            )
+        })
 }
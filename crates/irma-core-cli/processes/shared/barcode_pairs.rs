@@ -0,0 +1,183 @@
+//! Assignment and trimming of combinatorial dual barcodes, where each sample is
+//! identified by the pair of barcodes found on the two ends of a read.
+
+use foldhash::fast::SeedableRandomState;
+use irma_records::hashing::get_hasher;
+use std::{collections::HashSet, ops::Range, path::Path};
+use zoe::{
+    prelude::{CheckNucleotides, FastQViewMut, IsValidDNA, Nucleotides, NucleotidesViewMut, RecodeNucleotides, Restrict},
+    search::{ByteSubstring, RangeSearch, ToRangeSearch},
+};
+
+/// The sample name given to reads without a unique barcode pair.
+pub const UNASSIGNED_SAMPLE: &str = "unassigned";
+
+/// The prefix of the tag appended to read headers to record the sample.
+const SAMPLE_TAG: &str = "sample=";
+
+/// A single combinatorial barcode pair and the sample it identifies.
+#[derive(Debug)]
+struct BarcodePair {
+    left:     Nucleotides,
+    left_rc:  Nucleotides,
+    right:    Nucleotides,
+    right_rc: Nucleotides,
+    sample:   String,
+}
+
+/// The barcode pairs read from `--barcode-pairs`.
+#[derive(Debug)]
+pub struct BarcodePairs(Vec<BarcodePair>);
+
+impl BarcodePairs {
+    /// Reads the barcode pairs from a tab-delimited file with the columns
+    /// `left`, `right`, and `sample`. Blank lines and lines starting with `#`
+    /// are skipped. The barcodes are converted to uppercase unless
+    /// `preserve_bases` is set.
+    ///
+    /// ## Errors
+    ///
+    /// Errors are returned if the file cannot be read, is empty, or contains a
+    /// line with fewer than three columns, an empty or non-`ACGTN` barcode, or
+    /// a repeated barcode pair. Each contains the path as context.
+    pub fn from_tsv(path: &Path, preserve_bases: bool) -> std::io::Result<Self> {
+        let invalid = |msg: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{msg} in the barcode pairs file: {path}", path = path.display()),
+            )
+        };
+
+        let contents = std::fs::read_to_string(path)?;
+
+        let mut pairs = Vec::new();
+        let mut seen = HashSet::<(Vec<u8>, Vec<u8>), SeedableRandomState>::with_hasher(get_hasher());
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line_number = line_number + 1;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+            let [left, right, sample, ..] = fields.as_slice() else {
+                return Err(invalid(format!("Expected three columns on line {line_number}")));
+            };
+
+            let parse_barcode = |barcode: &str| {
+                if barcode.is_empty() || !barcode.as_bytes().is_valid_dna(IsValidDNA::AcgtnNoGaps) {
+                    return Err(invalid(format!(
+                        "Barcodes must be non-empty and only consist of canonical (ACGTN) bases, but '{barcode}' was found on line {line_number}"
+                    )));
+                }
+                let mut barcode = Nucleotides::from(barcode.as_bytes());
+                if !preserve_bases {
+                    barcode.as_mut_bytes().make_ascii_uppercase();
+                }
+                Ok(barcode)
+            };
+
+            let left = parse_barcode(left)?;
+            let right = parse_barcode(right)?;
+
+            if !seen.insert((left.as_bytes().to_vec(), right.as_bytes().to_vec())) {
+                return Err(invalid(format!("Repeated barcode pair on line {line_number}")));
+            }
+
+            pairs.push(BarcodePair {
+                left_rc: left.to_reverse_complement(),
+                right_rc: right.to_reverse_complement(),
+                left,
+                right,
+                sample: sample.to_string(),
+            });
+        }
+
+        if pairs.is_empty() {
+            return Err(invalid("No barcode pairs were found".to_string()));
+        }
+
+        Ok(BarcodePairs(pairs))
+    }
+
+    /// Assigns a read to the sample with a matching barcode pair and trims (or
+    /// masks) both barcodes, returning the sample name. `None` is returned and
+    /// the read is left unchanged if no pair, or more than one pair, matches.
+    ///
+    /// A pair matches if its left barcode is found within the first
+    /// `restrict_left` bases and the reverse complement of its right barcode
+    /// is found within the last `restrict_right` bases, or vice versa for reads
+    /// from the opposite strand. `None` for a restriction searches the full
+    /// read. Exact matches are tried first, after which the allowed Hamming
+    /// distance is increased by one up to `hdist`, so a read is assigned at
+    /// the smallest distance with a unique match.
+    ///
+    /// ## Panics
+    ///
+    /// `hdist` must be between 0 and 3.
+    pub fn assign_and_trim<'a>(
+        &'a self, fq_view: &mut FastQViewMut<'_>, hdist: usize, mask: bool, restrict_left: Option<usize>,
+        restrict_right: Option<usize>,
+    ) -> Option<&'a str> {
+        let restrict_left = restrict_left.unwrap_or(usize::MAX);
+        let restrict_right = restrict_right.unwrap_or(usize::MAX);
+
+        for distance in 0..=hdist {
+            let mut found: Option<(usize, Range<usize>, Range<usize>)> = None;
+
+            for (i, pair) in self.0.iter().enumerate() {
+                let orientations = [(&pair.left, &pair.right_rc), (&pair.right, &pair.left_rc)];
+                let matched = orientations.into_iter().find_map(|(start, end)| {
+                    let left = find_barcode(&fq_view.sequence.search_in_first(restrict_left), start, distance)?;
+                    let right = find_barcode(&fq_view.sequence.search_in_last(restrict_right), end, distance)?;
+                    (left.end <= right.start).then_some((left, right))
+                });
+
+                if let Some((left, right)) = matched {
+                    if found.is_some() {
+                        return None;
+                    }
+                    found = Some((i, left, right));
+                }
+            }
+
+            if let Some((i, left, right)) = found {
+                if mask {
+                    fq_view.sequence.mask_if_exists(left);
+                    fq_view.sequence.mask_if_exists(right);
+                } else {
+                    fq_view.restrict(left.end..right.start);
+                }
+                return Some(&self.0[i].sample);
+            }
+        }
+
+        None
+    }
+}
+
+/// Appends the sample tag (such as ` sample=S1`) to a read header, using
+/// [`UNASSIGNED_SAMPLE`] if `sample` is `None`.
+pub fn annotate_sample(header: &mut String, sample: Option<&str>) {
+    header.push(' ');
+    header.push_str(SAMPLE_TAG);
+    header.push_str(sample.unwrap_or(UNASSIGNED_SAMPLE));
+}
+
+/// Finds the first occurrence of `barcode` within `haystack`, allowing up to
+/// `hdist` mismatches.
+///
+/// ## Panics
+///
+/// `hdist` must be between 0 and 3.
+fn find_barcode(
+    haystack: &RangeSearch<'_, NucleotidesViewMut<'_>>, barcode: &Nucleotides, hdist: usize,
+) -> Option<Range<usize>> {
+    match hdist {
+        0 => haystack.find_substring(barcode),
+        1 => haystack.find_fuzzy_substring::<1>(barcode),
+        2 => haystack.find_fuzzy_substring::<2>(barcode),
+        3 => haystack.find_fuzzy_substring::<3>(barcode),
+        _ => panic!("The value of hdist must be between 0 and 3. Found: {hdist}"),
+    }
+}
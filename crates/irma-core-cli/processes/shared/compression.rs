@@ -0,0 +1,34 @@
+//! Command line options for how compressed outputs are encoded.
+
+use clap::Args;
+use flate2::Compression;
+use irma_records::io::{CompressionOptions, GzipFormat};
+use std::num::NonZeroUsize;
+
+/// The `--compress-level` and `--compress-threads` options, which apply to
+/// outputs ending in `.gz` or `.bgz`.
+#[derive(Args, Debug)]
+pub struct CompressionArgs {
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(0..=9))]
+    /// The compression level of gzip outputs, from 0 (fastest) to 9 (smallest).
+    /// Defaults to 6
+    pub compress_level: Option<u32>,
+
+    #[arg(long, value_name = "N", default_value = "1")]
+    /// The number of threads used to compress each gzip output. With more than
+    /// one, the output is written as multiple gzip members (or BGZF blocks),
+    /// which standard gzip readers decode as a whole
+    pub compress_threads: NonZeroUsize,
+}
+
+impl CompressionArgs {
+    /// The compression selected on the command line, writing compressed
+    /// outputs in the given `format`.
+    pub fn options(&self, format: GzipFormat) -> CompressionOptions {
+        CompressionOptions {
+            format,
+            level: self.compress_level.map_or_else(Compression::default, Compression::new),
+            threads: self.compress_threads,
+        }
+    }
+}
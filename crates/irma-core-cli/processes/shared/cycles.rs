@@ -0,0 +1,163 @@
+//! Per-cycle quality and base composition, such as that written by `trimmer
+//! --cycle-report`.
+
+use std::{io::Write, ops::Add};
+
+/// The bases and quality scores observed at a single cycle (position) of the
+/// reads.
+#[derive(Clone, Copy, Default, Debug)]
+struct CycleColumn {
+    /// The counts of `A`, `C`, `G`, `T`, and any other base
+    bases:       [usize; 5],
+    /// The number of bases with quality scores
+    num_quality: usize,
+    /// The sum of the Phred quality scores
+    quality_sum: u64,
+}
+
+impl CycleColumn {
+    /// The number of reads reaching this cycle.
+    #[inline]
+    fn reads(&self) -> usize {
+        self.bases.iter().sum()
+    }
+
+    /// Writes the number of reads, the mean quality (or `*` if no bases had
+    /// quality scores), and the fraction of each base, each preceded by a tab.
+    /// The fractions are `*` if no reads reach this cycle.
+    fn write_tsv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let reads = self.reads();
+        write!(writer, "\t{reads}")?;
+
+        if self.num_quality > 0 {
+            write!(writer, "\t{:.2}", self.quality_sum as f64 / self.num_quality as f64)?;
+        } else {
+            write!(writer, "\t*")?;
+        }
+
+        for count in self.bases {
+            if reads > 0 {
+                write!(writer, "\t{:.4}", count as f64 / reads as f64)?;
+            } else {
+                write!(writer, "\t*")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Add for CycleColumn {
+    type Output = Self;
+
+    fn add(mut self, other: Self) -> Self::Output {
+        for (count, other) in self.bases.iter_mut().zip(other.bases) {
+            *count += other;
+        }
+        self.num_quality += other.num_quality;
+        self.quality_sum += other.quality_sum;
+        self
+    }
+}
+
+/// The quality and base composition at each cycle of a set of reads. Cycles
+/// are allocated as longer reads are seen.
+#[derive(Clone, Default, Debug)]
+pub struct CycleStats {
+    columns: Vec<CycleColumn>,
+}
+
+impl CycleStats {
+    /// Counts the bases of a read, along with its quality scores (encoded with
+    /// an offset of 33) if it has any.
+    #[inline]
+    pub fn add(&mut self, sequence: &[u8], quality: Option<&[u8]>) {
+        if sequence.len() > self.columns.len() {
+            self.columns.resize(sequence.len(), CycleColumn::default());
+        }
+
+        for (column, base) in self.columns.iter_mut().zip(sequence) {
+            let index = match base.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            };
+            column.bases[index] += 1;
+        }
+
+        if let Some(quality) = quality {
+            for (column, score) in self.columns.iter_mut().zip(quality) {
+                column.num_quality += 1;
+                column.quality_sum += u64::from(score.saturating_sub(33));
+            }
+        }
+    }
+
+    /// The statistics at cycle `cycle` (0-based).
+    #[inline]
+    fn column(&self, cycle: usize) -> CycleColumn {
+        self.columns.get(cycle).copied().unwrap_or_default()
+    }
+}
+
+impl Add for CycleStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self::Output {
+        let (mut longer, shorter) = if self.columns.len() >= other.columns.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        for (column, other) in longer.columns.iter_mut().zip(shorter.columns) {
+            *column = *column + other;
+        }
+        longer
+    }
+}
+
+/// The per-cycle statistics of the reads before and after an operation such
+/// as trimming.
+#[derive(Clone, Default, Debug)]
+pub struct BeforeAfterCycles {
+    pub before: CycleStats,
+    pub after:  CycleStats,
+}
+
+impl BeforeAfterCycles {
+    /// Writes the statistics as a tab-delimited table with a header, with one
+    /// row per cycle (1-based) up to the longest read. For both before and
+    /// after, the columns are the number of reads reaching the cycle, their
+    /// mean quality, and the fraction of `A`, `C`, `G`, `T`, and other bases.
+    pub fn write_tsv<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let num_cycles = self.before.columns.len().max(self.after.columns.len());
+
+        write!(writer, "cycle")?;
+        for stage in ["before", "after"] {
+            for column in ["reads", "mean_qual", "A", "C", "G", "T", "N"] {
+                write!(writer, "\t{stage}_{column}")?;
+            }
+        }
+        writeln!(writer)?;
+
+        for cycle in 0..num_cycles {
+            write!(writer, "{}", cycle + 1)?;
+            self.before.column(cycle).write_tsv(&mut writer)?;
+            self.after.column(cycle).write_tsv(&mut writer)?;
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+impl Add for BeforeAfterCycles {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self::Output {
+        BeforeAfterCycles {
+            before: self.before + other.before,
+            after:  self.after + other.after,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Read length histograms, such as those written by `trimmer --length-hist`.
+
+use std::{io::Write, num::NonZeroUsize, ops::Add};
+
+/// A histogram of read lengths, counting the reads in bins of a fixed width.
+/// Bins are allocated as longer reads are seen.
+#[derive(Clone, Debug)]
+pub struct LengthHistogram {
+    bin_width: NonZeroUsize,
+    counts:    Vec<usize>,
+}
+
+impl LengthHistogram {
+    /// Creates an empty histogram, where bin `i` holds the lengths from `i *
+    /// bin_width` to `(i + 1) * bin_width - 1`.
+    pub fn new(bin_width: NonZeroUsize) -> Self {
+        LengthHistogram {
+            bin_width,
+            counts: Vec::new(),
+        }
+    }
+
+    /// Counts a read of length `len`.
+    #[inline]
+    pub fn add(&mut self, len: usize) {
+        let bin = len / self.bin_width;
+        if bin >= self.counts.len() {
+            self.counts.resize(bin + 1, 0);
+        }
+        self.counts[bin] += 1;
+    }
+
+    /// The number of reads counted in bin `bin`.
+    #[inline]
+    fn count(&self, bin: usize) -> usize {
+        self.counts.get(bin).copied().unwrap_or_default()
+    }
+}
+
+impl Add for LengthHistogram {
+    type Output = Self;
+
+    /// Sums two histograms, which must have the same bin width.
+    fn add(self, other: Self) -> Self::Output {
+        debug_assert_eq!(self.bin_width, other.bin_width);
+        let (mut longer, shorter) = if self.counts.len() >= other.counts.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        for (count, other) in longer.counts.iter_mut().zip(shorter.counts) {
+            *count += other;
+        }
+        longer
+    }
+}
+
+/// Histograms of the read lengths before and after an operation such as
+/// trimming, sharing a bin width.
+#[derive(Clone, Debug)]
+pub struct BeforeAfterHistograms {
+    pub before: LengthHistogram,
+    pub after:  LengthHistogram,
+}
+
+impl BeforeAfterHistograms {
+    /// Creates empty histograms with the given bin width.
+    pub fn new(bin_width: NonZeroUsize) -> Self {
+        BeforeAfterHistograms {
+            before: LengthHistogram::new(bin_width),
+            after:  LengthHistogram::new(bin_width),
+        }
+    }
+
+    /// Writes the histograms as a tab-delimited table with a header, with one
+    /// row per bin from length 0 up to the longest read. The columns are the
+    /// first and last lengths of the bin (inclusive), followed by the number of
+    /// reads before and after.
+    pub fn write_tsv<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let bin_width = self.before.bin_width.get();
+        let num_bins = self.before.counts.len().max(self.after.counts.len());
+
+        writeln!(writer, "min_length\tmax_length\tbefore\tafter")?;
+        for bin in 0..num_bins {
+            writeln!(
+                writer,
+                "{min}\t{max}\t{before}\t{after}",
+                min = bin * bin_width,
+                max = (bin + 1) * bin_width - 1,
+                before = self.before.count(bin),
+                after = self.after.count(bin)
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+impl Add for BeforeAfterHistograms {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self::Output {
+        BeforeAfterHistograms {
+            before: self.before + other.before,
+            after:  self.after + other.after,
+        }
+    }
+}
@@ -2,7 +2,17 @@ use jiff::Zoned;
 use std::error::Error;
 use zoe::{data::err::DisplayErrStack, search::ByteSubstringMut};
 
+pub mod barcode_pairs;
+pub mod compression;
+pub mod cycles;
+pub mod histogram;
+pub mod ont_summary;
+pub mod output_buffering;
+pub mod paired_paths;
+pub mod tee_input;
+pub mod trace;
 pub mod trimming;
+pub mod xfl;
 
 /// Replaces tabs with spaces in a String.
 ///
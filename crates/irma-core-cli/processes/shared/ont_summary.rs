@@ -0,0 +1,190 @@
+//! Filtering of Oxford Nanopore reads by the per-read metadata recorded in a
+//! basecaller's `sequencing_summary.txt`.
+
+use foldhash::fast::SeedableRandomState;
+use irma_records::hashing::get_hasher;
+use std::{collections::HashMap, ops::RangeInclusive, path::Path};
+
+/// The column holding the read ID, which is always required.
+const READ_ID: &str = "read_id";
+/// The column holding the channel of the flow cell that sequenced the read.
+const CHANNEL: &str = "channel";
+/// The column holding the mean quality score of the read.
+const MEAN_QSCORE: &str = "mean_qscore_template";
+/// The column holding the basecaller's pass/fail status for the read.
+const PASSES_FILTERING: &str = "passes_filtering";
+
+/// The criteria a read must meet in the sequencing summary to be kept.
+#[derive(Debug, Default)]
+pub struct OntSummaryCriteria {
+    /// The minimum `mean_qscore_template`
+    pub min_qscore: Option<f32>,
+    /// The channels, as inclusive ranges, from which reads are kept
+    pub channels:   Option<Vec<RangeInclusive<u32>>>,
+    /// Whether reads must have `passes_filtering` set
+    pub pass_only:  bool,
+}
+
+impl OntSummaryCriteria {
+    /// Whether a read with the given metadata meets the criteria. Fields that
+    /// are not needed by the criteria are `None`.
+    fn passes(&self, channel: Option<u32>, mean_qscore: Option<f32>, passes_filtering: Option<bool>) -> bool {
+        let passes_qscore = self.min_qscore.is_none_or(|min| mean_qscore >= Some(min));
+        let passes_channel = self
+            .channels
+            .as_ref()
+            .is_none_or(|ranges| channel.is_some_and(|channel| ranges.iter().any(|range| range.contains(&channel))));
+        let passes_status = !self.pass_only || passes_filtering == Some(true);
+        passes_qscore && passes_channel && passes_status
+    }
+}
+
+/// Whether a read in the sequencing summary meets the criteria given to
+/// `trimmer`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OntStatus {
+    /// The read meets all criteria
+    Kept,
+    /// The read is listed but fails at least one criterion
+    Filtered,
+    /// The read is not listed in the sequencing summary
+    Unlisted,
+}
+
+/// The reads listed in `--ont-summary`, keyed by read ID, and whether each
+/// meets the criteria.
+#[derive(Debug)]
+pub struct OntSummary(HashMap<Box<str>, bool, SeedableRandomState>);
+
+impl OntSummary {
+    /// Reads a tab-delimited sequencing summary, as written by Guppy or
+    /// Dorado, evaluating each read against `criteria`.
+    ///
+    /// The first line must be a header naming the columns. Only `read_id` and
+    /// the columns needed by `criteria` are read: `channel`,
+    /// `mean_qscore_template`, and `passes_filtering` (either `TRUE` or
+    /// `FALSE`, in any case). Blank lines are skipped.
+    ///
+    /// ## Errors
+    ///
+    /// Errors are returned if the file cannot be read, lacks a header or a
+    /// needed column, or contains a line with too few columns, an unparsable
+    /// value, or a repeated read ID.
+    pub fn from_tsv(path: &Path, criteria: &OntSummaryCriteria) -> std::io::Result<Self> {
+        let invalid = |msg: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{msg} in the sequencing summary: {path}", path = path.display()),
+            )
+        };
+
+        let contents = std::fs::read_to_string(path)?;
+        let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+
+        let Some((_, header)) = lines.next() else {
+            return Err(invalid("No header was found".to_string()));
+        };
+        let columns = header.split('\t').map(str::trim).collect::<Vec<_>>();
+        let find_column = |name: &str| {
+            columns
+                .iter()
+                .position(|column| *column == name)
+                .ok_or_else(|| invalid(format!("The column '{name}' was not found")))
+        };
+
+        let read_id_col = find_column(READ_ID)?;
+        let channel_col = criteria.channels.is_some().then(|| find_column(CHANNEL)).transpose()?;
+        let qscore_col = criteria.min_qscore.is_some().then(|| find_column(MEAN_QSCORE)).transpose()?;
+        let status_col = criteria.pass_only.then(|| find_column(PASSES_FILTERING)).transpose()?;
+
+        let mut reads = HashMap::with_hasher(get_hasher());
+
+        for (line_number, line) in lines {
+            let line_number = line_number + 1;
+            let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+
+            let get_field = |col: usize| {
+                fields.get(col).copied().ok_or_else(|| {
+                    invalid(format!(
+                        "Expected at least {} columns on line {line_number}, but found {}",
+                        col + 1,
+                        fields.len()
+                    ))
+                })
+            };
+
+            let read_id = get_field(read_id_col)?;
+            if read_id.is_empty() {
+                return Err(invalid(format!("Empty read ID on line {line_number}")));
+            }
+
+            let channel = channel_col
+                .map(|col| {
+                    let field = get_field(col)?;
+                    field
+                        .parse::<u32>()
+                        .map_err(|_| invalid(format!("Invalid channel '{field}' on line {line_number}")))
+                })
+                .transpose()?;
+            let mean_qscore = qscore_col
+                .map(|col| {
+                    let field = get_field(col)?;
+                    field
+                        .parse::<f32>()
+                        .map_err(|_| invalid(format!("Invalid mean quality score '{field}' on line {line_number}")))
+                })
+                .transpose()?;
+            let passes_filtering = status_col
+                .map(|col| {
+                    let field = get_field(col)?;
+                    if field.eq_ignore_ascii_case("true") {
+                        Ok(true)
+                    } else if field.eq_ignore_ascii_case("false") {
+                        Ok(false)
+                    } else {
+                        Err(invalid(format!("Invalid pass/fail status '{field}' on line {line_number}")))
+                    }
+                })
+                .transpose()?;
+
+            let passes = criteria.passes(channel, mean_qscore, passes_filtering);
+            if reads.insert(Box::from(read_id), passes).is_some() {
+                return Err(invalid(format!("Repeated read ID '{read_id}' on line {line_number}")));
+            }
+        }
+
+        Ok(OntSummary(reads))
+    }
+
+    /// Looks up a read by its FASTQ header, whose first word is the read ID.
+    pub fn status(&self, header: &str) -> OntStatus {
+        let read_id = header.split_ascii_whitespace().next().unwrap_or_default();
+        match self.0.get(read_id) {
+            Some(true) => OntStatus::Kept,
+            Some(false) => OntStatus::Filtered,
+            None => OntStatus::Unlisted,
+        }
+    }
+}
+
+/// Parses a channel or an inclusive range of channels, such as `1-256`, from
+/// the command line.
+pub fn parse_channel_range(value: &str) -> Result<RangeInclusive<u32>, String> {
+    let parse = |channel: &str| {
+        channel
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| format!("`{channel}` is not a valid channel number."))
+    };
+
+    match value.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                return Err(format!("The channel range `{value}` must not start after it ends."));
+            }
+            Ok(start..=end)
+        }
+        None => parse(value).map(|channel| channel..=channel),
+    }
+}
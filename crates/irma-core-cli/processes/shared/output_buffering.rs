@@ -0,0 +1,51 @@
+//! Command line options for how uncompressed outputs are buffered, for
+//! streaming records to another process through a pipe.
+
+use clap::Args;
+use irma_records::io::OutputBuffering;
+
+#[cfg(feature = "async-io")]
+use crate::args::abort_clap;
+#[cfg(feature = "async-io")]
+use clap::error::ErrorKind;
+
+/// The `--unbuffered` and `--line-buffered` options. If neither is given,
+/// outputs are block buffered, with a smaller buffer for named pipes.
+#[derive(Args, Debug)]
+pub struct OutputBufferingArgs {
+    #[arg(long, conflicts_with = "line_buffered")]
+    /// Writes uncompressed output without buffering, so that each record
+    /// reaches the consumer of a pipe as soon as it is written
+    pub unbuffered: bool,
+
+    #[arg(long)]
+    /// Flushes uncompressed output after each line, for streaming to another
+    /// process through a pipe or process substitution
+    pub line_buffered: bool,
+}
+
+impl OutputBufferingArgs {
+    /// The buffering selected on the command line.
+    pub fn buffering(&self) -> OutputBuffering {
+        if self.unbuffered {
+            OutputBuffering::Unbuffered
+        } else if self.line_buffered {
+            OutputBuffering::Line
+        } else {
+            OutputBuffering::Block
+        }
+    }
+
+    /// Exits with a clap error if `--unbuffered` or `--line-buffered` was
+    /// given alongside `--async-io`, whose writers do their own buffering.
+    #[cfg(feature = "async-io")]
+    pub fn check_async_io(&self, async_io: bool, subcommand: &str) {
+        if async_io && self.buffering() != OutputBuffering::Block {
+            abort_clap(
+                ErrorKind::ArgumentConflict,
+                "`--unbuffered` and `--line-buffered` cannot be used with `--async-io`",
+                Some(subcommand),
+            );
+        }
+    }
+}
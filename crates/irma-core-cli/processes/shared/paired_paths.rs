@@ -0,0 +1,80 @@
+//! Inference of the R2 path of a paired-end run from its R1 path, for
+//! `--auto-r2`.
+
+use irma_records::{io::InputOptions, paired::check_paired_headers};
+use std::path::{Path, PathBuf};
+use zoe::data::err::ResultWithErrorContext;
+
+/// The markers distinguishing R1 from R2 file names, in the order they are
+/// tried: Illumina (`_S1_L001_R1_001.fastq.gz`), shortened Illumina
+/// (`_R1.fastq.gz`), and SRA (`_1.fastq.gz`).
+const R1_R2_MARKERS: [(&str, &str); 3] = [("_R1_", "_R2_"), ("_R1.", "_R2."), ("_1.", "_2.")];
+
+/// Infers the R2 path from an R1 path, by replacing the last R1 marker in the
+/// file name (see [`R1_R2_MARKERS`]) with its R2 counterpart. The first marker
+/// found in the file name is used.
+///
+/// The R2 file must exist, and the first records of the two files must have
+/// matching molecular IDs (see [`check_paired_headers`]). If either file is
+/// empty, the headers are not checked.
+///
+/// ## Errors
+///
+/// Errors are returned if R1 is not a regular file (since streams cannot be
+/// read twice), no marker is found, the inferred R2 file does not exist, or the
+/// first records are not paired. IO errors while reading the first records are
+/// propagated with the path as context.
+pub fn infer_r2_path(r1: &Path) -> std::io::Result<PathBuf> {
+    if !r1.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "`--auto-r2` requires the R1 input to be a regular file, but found: {}",
+                r1.display()
+            ),
+        ));
+    }
+
+    let file_name = r1.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let Some(r2_name) = R1_R2_MARKERS.iter().find_map(|(r1_marker, r2_marker)| {
+        let start = file_name.rfind(r1_marker)?;
+        Some(format!(
+            "{}{r2_marker}{}",
+            &file_name[..start],
+            &file_name[start + r1_marker.len()..]
+        ))
+    }) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Failed to infer the R2 path, since the R1 file name contains none of `_R1_`, `_R1.`, or `_1.`: {}",
+                r1.display()
+            ),
+        ));
+    };
+
+    let r2 = r1.with_file_name(r2_name);
+    if !r2.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("The inferred R2 file does not exist: {}", r2.display()),
+        ));
+    }
+
+    let first1 = InputOptions::new_from_path(r1).use_file_or_zip().parse_fastx().open()?.next();
+    let first2 = InputOptions::new_from_path(&r2)
+        .use_file_or_zip()
+        .parse_fastx()
+        .open()?
+        .next();
+
+    if let (Some(read1), Some(read2)) = (first1, first2) {
+        check_paired_headers(&read1?, &read2?).with_context(format!(
+            "The first records of the R1 file and the inferred R2 file are not paired:\n    | R1: {r1}\n    | R2: {r2}",
+            r1 = r1.display(),
+            r2 = r2.display()
+        ))?;
+    }
+
+    Ok(r2)
+}
@@ -0,0 +1,50 @@
+//! Command line options for writing an exact copy of the inputs while they are
+//! processed, for archiving the bytes a subcommand read from a pipe or process
+//! substitution.
+
+use crate::args::abort_clap;
+use clap::{Args, error::ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The `--tee-input` and `--tee-input2` options.
+#[derive(Args, Debug)]
+pub struct TeeInputArgs {
+    #[arg(long, value_name = "PATH")]
+    /// Writes an exact copy of the first input, after any decompression, to
+    /// this path as it is read. Useful for archiving input from a pipe
+    pub tee_input: Option<PathBuf>,
+
+    #[arg(long, value_name = "PATH")]
+    /// Writes an exact copy of the second input, after any decompression, to
+    /// this path as it is read
+    pub tee_input2: Option<PathBuf>,
+}
+
+impl TeeInputArgs {
+    /// The paths the copies are written to, for [`ValidatePaths::outputs`].
+    ///
+    /// [`ValidatePaths::outputs`]: irma_records::io::ValidatePaths::outputs
+    pub fn outputs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.tee_input.iter().chain(&self.tee_input2)
+    }
+
+    /// The paths the copies of the first and second inputs are written to,
+    /// for [`InputOptions::tee_to`].
+    ///
+    /// [`InputOptions::tee_to`]: irma_records::io::InputOptions::tee_to
+    pub fn paths(&self) -> (Option<&Path>, Option<&Path>) {
+        (self.tee_input.as_deref(), self.tee_input2.as_deref())
+    }
+
+    /// Exits with a clap error if `--tee-input2` was given without a second
+    /// input.
+    pub fn check_paired(&self, paired: bool, subcommand: &str) {
+        if self.tee_input2.is_some() && !paired {
+            abort_clap(
+                ErrorKind::MissingRequiredArgument,
+                "`--tee-input2` requires a second input",
+                Some(subcommand),
+            );
+        }
+    }
+}
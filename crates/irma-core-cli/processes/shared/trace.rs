@@ -0,0 +1,112 @@
+//! Command line options for tracing how individual records are processed, for
+//! answering why a read was dropped, trimmed, or unmapped without rebuilding
+//! with additional logging.
+
+use crate::panic_report::note_record;
+use clap::Args;
+use std::{cell::RefCell, io::Write};
+
+/// The `--trace-read` option.
+#[derive(Args, Debug)]
+pub struct TraceArgs {
+    #[arg(long, value_name = "ID")]
+    /// Prints a step-by-step trace of how the record with this ID (its header
+    /// up to the first whitespace) is processed to stderr. May be repeated
+    pub trace_read: Vec<String>,
+}
+
+/// Selects the records traced with `--trace-read`, and starts their traces.
+#[derive(Debug)]
+pub struct ReadTracer {
+    program: &'static str,
+    ids:     Vec<String>,
+}
+
+impl ReadTracer {
+    /// Creates a tracer for the records with the given IDs, labelling each
+    /// trace with `program`.
+    pub fn new(program: &'static str, args: TraceArgs) -> Self {
+        ReadTracer {
+            program,
+            ids: args.trace_read,
+        }
+    }
+
+    /// Whether any records are traced.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.ids.is_empty()
+    }
+
+    /// Starts the trace of a record, which is disabled (and records nothing)
+    /// unless the ID of `header` was given to `--trace-read`. A trailing `/1`
+    /// or `/2` on the ID is ignored, so that both reads of a pair are traced.
+    ///
+    /// The ID is also noted for any panic report on the processing thread,
+    /// whether or not it is traced.
+    pub fn start(&self, header: &str) -> RecordTrace {
+        let id = header.split_ascii_whitespace().next().unwrap_or_default();
+        note_record(id);
+        if !self.is_enabled() {
+            return RecordTrace::disabled();
+        }
+
+        let base_id = id.strip_suffix("/1").or_else(|| id.strip_suffix("/2")).unwrap_or(id);
+        if self.ids.iter().any(|traced| traced == id || traced == base_id) {
+            RecordTrace {
+                label: Some(format!("{program} trace [{id}]", program = self.program)),
+                steps: RefCell::new(Vec::new()),
+            }
+        } else {
+            RecordTrace::disabled()
+        }
+    }
+}
+
+/// The trace of a single record, holding each step until the trace is dropped.
+/// The steps are then written to stderr together, so that the traces of records
+/// processed on different threads are not interleaved.
+#[derive(Debug)]
+pub struct RecordTrace {
+    /// The label preceding each step, or `None` if the record is not traced
+    label: Option<String>,
+    steps: RefCell<Vec<String>>,
+}
+
+impl RecordTrace {
+    /// A trace which records nothing, for records that are not traced.
+    #[inline]
+    pub fn disabled() -> Self {
+        RecordTrace {
+            label: None,
+            steps: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Whether the record is traced.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.label.is_some()
+    }
+
+    /// Records a step of the trace. The `detail` is only computed if the record
+    /// is traced.
+    #[inline]
+    pub fn step(&self, step: &str, detail: impl FnOnce() -> String) {
+        if self.is_enabled() {
+            self.steps.borrow_mut().push(format!("{step}: {detail}", detail = detail()));
+        }
+    }
+}
+
+impl Drop for RecordTrace {
+    fn drop(&mut self) {
+        if let Some(label) = &self.label {
+            let mut stderr = std::io::stderr().lock();
+            for step in self.steps.get_mut().iter() {
+                // Tracing is best effort, so a closed stderr is ignored
+                let _ = writeln!(stderr, "{label} {step}");
+            }
+        }
+    }
+}
@@ -1,66 +1,340 @@
-use crate::args::clipping::ParsedClippingArgs;
-use irma_records::fastq::ReadTransforms;
+use crate::{
+    args::clipping::{ParsedClippingArgs, StrictBases, TrimStep},
+    shared::{
+        barcode_pairs::annotate_sample, cycles::BeforeAfterCycles, histogram::BeforeAfterHistograms, trace::RecordTrace,
+    },
+};
+use irma_records::{fastq::ReadTransforms, paired::ReadSide};
 use std::ops::Add;
-use zoe::prelude::{FastQViewMut, Len};
+use zoe::{
+    data::SanitizeBase,
+    prelude::{CheckNucleotides, FastQViewMut, IsValidDNA, Len},
+};
 
 /// Trims or masks a read based on user provided arguments. This edits the
-/// underlying FASTQ data for masking and recoding.
+/// underlying FASTQ data for masking and recoding. After the bases are
+/// recoded, the trimming operations are applied in the order given by
+/// `--trim-order`.
+///
+/// Since no operation lengthens a read, `None` is returned as soon as the read
+/// is shorter than `min_length`, skipping the remaining operations (primer
+/// k-mer scans in particular). A `min_length` of 0 disables this. The early
+/// exit is also disabled when `verbose` is true, so that every operation is
+/// tallied in `counts`, and when `mask` is true, since masked reads are
+/// filtered on their full length. The hard trim amounts are selected by
+/// `side`.
+///
+/// Each operation applied is recorded in `trace`, along with the bases it
+/// removed or masked.
+#[allow(clippy::too_many_arguments)]
 pub fn trim_read<'a>(
     mut fq_view: FastQViewMut<'a>, mask: bool, args: &ParsedClippingArgs, counts: &mut TrimmedCounts, verbose: bool,
-) -> FastQViewMut<'a> {
+    min_length: usize, side: ReadSide, trace: &RecordTrace,
+) -> Option<FastQViewMut<'a>> {
+    let too_short = |fq_view: &FastQViewMut<'_>| {
+        let too_short = !verbose && !mask && fq_view.len() < min_length;
+        if too_short {
+            trace.step("trim", || {
+                format!(
+                    "stopped early, since the length {len} is below the minimum length {min_length}",
+                    len = fq_view.len()
+                )
+            });
+        }
+        too_short
+    };
+
     fq_view.to_canonical_bases(!args.preserve_bases);
+    trace.step("recode", || {
+        format!(
+            "{action} bases, giving length {len}",
+            action = if args.preserve_bases { "preserved" } else { "recoded" },
+            len = fq_view.len()
+        )
+    });
+    if too_short(&fq_view) {
+        return None;
+    }
 
     counts.last_read_len = fq_view.sequence.len();
     let mut original_len = fq_view.sequence.len();
+    let origin = fq_view.sequence.as_bytes().as_ptr().addr();
 
-    fq_view.process_polyg(args.polyg_left, args.polyg_right, mask);
-    update_trimmed_counts_field(&mut counts.poly_g, &fq_view, &mut counts.last_read_len, verbose);
-
-    if let Some((ref forward_adapter, ref reverse_adapter)) = args.adapters {
-        fq_view.process_adapter(reverse_adapter.as_bytes(), forward_adapter.as_bytes(), args.a_fuzzy, mask);
-        update_trimmed_counts_field(&mut counts.adapter, &fq_view, &mut counts.last_read_len, verbose);
-    } else if let Some((barcode, reverse)) = &args.barcodes {
-        fq_view.process_barcode(
-            barcode.as_bytes(),
-            reverse.as_bytes(),
-            args.b_hdist,
-            mask,
-            args.b_restrict_left,
-            args.b_restrict_right,
-        );
-        update_trimmed_counts_field(&mut counts.barcode, &fq_view, &mut counts.last_read_len, verbose);
-    }
+    for &step in &args.trim_order {
+        let before = trace.is_enabled().then(|| TracedSpan::new(&fq_view, origin));
 
-    if let Some(ref kmers) = args.primer_kmers {
-        if let Some(p_restrict_left) = args.p_restrict_left {
-            fq_view.process_left_primer(p_restrict_left, kmers, mask);
+        match step {
+            TrimStep::Polyg => {
+                if args.polyg_left.is_none() && args.polyg_right.is_none() {
+                    continue;
+                }
+                fq_view.process_polyg(args.polyg_left, args.polyg_right, mask);
+                trace.step("polyg", || {
+                    format!(
+                        "left threshold {left:?}, right threshold {right:?}; {change}",
+                        left = args.polyg_left,
+                        right = args.polyg_right,
+                        change = TracedSpan::describe(before, &fq_view, origin)
+                    )
+                });
+                update_trimmed_counts_field(&mut counts.poly_g, &fq_view, &mut counts.last_read_len, verbose);
+            }
+            TrimStep::Adapter => {
+                let Some((forward_adapter, reverse_adapter)) = &args.adapters else {
+                    continue;
+                };
+                fq_view.process_adapter(
+                    reverse_adapter.as_bytes(),
+                    forward_adapter.as_bytes(),
+                    args.a_max_mismatch,
+                    mask,
+                );
+                trace.step("adapter", || {
+                    format!(
+                        "searched for {forward_adapter} and its reverse complement with up to {mismatches} mismatches; {change}",
+                        mismatches = args.a_max_mismatch,
+                        change = TracedSpan::describe(before, &fq_view, origin)
+                    )
+                });
+                update_trimmed_counts_field(&mut counts.adapter, &fq_view, &mut counts.last_read_len, verbose);
+            }
+            TrimStep::Barcode => {
+                if let Some((barcode, reverse)) = &args.barcodes {
+                    fq_view.process_barcode(
+                        barcode.as_bytes(),
+                        reverse.as_bytes(),
+                        args.b_hdist,
+                        args.b_quality_aware,
+                        mask,
+                        args.b_restrict_left,
+                        args.b_restrict_right,
+                    );
+                    trace.step("barcode", || {
+                        let forgiven = args
+                            .b_quality_aware
+                            .map(|q| format!(", forgiving mismatches below quality {q}"))
+                            .unwrap_or_default();
+                        format!(
+                            "searched for {barcode} within Hamming distance {hdist}{forgiven} (left window {left:?}, right window {right:?}); {change}",
+                            hdist = args.b_hdist,
+                            left = args.b_restrict_left,
+                            right = args.b_restrict_right,
+                            change = TracedSpan::describe(before, &fq_view, origin)
+                        )
+                    });
+                } else if let Some(barcode_pairs) = &args.barcode_pairs {
+                    let sample = barcode_pairs.assign_and_trim(
+                        &mut fq_view,
+                        args.b_hdist,
+                        mask,
+                        args.b_restrict_left,
+                        args.b_restrict_right,
+                    );
+                    if sample.is_none() {
+                        counts.unassigned += 1;
+                    }
+                    trace.step("barcode", || {
+                        format!(
+                            "assigned to sample {sample} within Hamming distance {hdist}; {change}",
+                            sample = sample.unwrap_or("(unassigned)"),
+                            hdist = args.b_hdist,
+                            change = TracedSpan::describe(before, &fq_view, origin)
+                        )
+                    });
+                    if args.b_annotate_sample {
+                        annotate_sample(fq_view.header, sample);
+                    }
+                } else {
+                    continue;
+                }
+                update_trimmed_counts_field(&mut counts.barcode, &fq_view, &mut counts.last_read_len, verbose);
+            }
+            TrimStep::Primer => {
+                let Some(kmers) = &args.primer_kmers else {
+                    continue;
+                };
+                if let Some(p_restrict_left) = args.p_restrict_left {
+                    fq_view.process_left_primer(p_restrict_left, kmers, mask);
+                }
+                if let Some(p_restrict_right) = args.p_restrict_right {
+                    fq_view.process_right_primer(p_restrict_right, kmers, mask);
+                }
+                trace.step("primer", || {
+                    format!(
+                        "searched for primer k-mers (left window {left:?}, right window {right:?}); {change}",
+                        left = args.p_restrict_left,
+                        right = args.p_restrict_right,
+                        change = TracedSpan::describe(before, &fq_view, origin)
+                    )
+                });
+                update_trimmed_counts_field(&mut counts.primer, &fq_view, &mut counts.last_read_len, verbose);
+            }
+            TrimStep::Hard => {
+                let (hard_left, hard_right) = args.hard_trim(side);
+                if hard_left == 0 && hard_right == 0 {
+                    continue;
+                }
+                fq_view.hard_clip_or_mask(hard_left, hard_right, mask);
+                trace.step("hard", || {
+                    format!(
+                        "{hard_left} bases from the left and {hard_right} from the right; {change}",
+                        change = TracedSpan::describe(before, &fq_view, origin)
+                    )
+                });
+                update_trimmed_counts_field(&mut counts.hard, &fq_view, &mut counts.last_read_len, verbose);
+            }
         }
-        if let Some(p_restrict_right) = args.p_restrict_right {
-            fq_view.process_right_primer(p_restrict_right, kmers, mask);
+
+        if too_short(&fq_view) {
+            return None;
         }
-        update_trimmed_counts_field(&mut counts.primer, &fq_view, &mut counts.last_read_len, verbose);
     }
 
-    if args.hard_left > 0 || args.hard_right > 0 {
-        fq_view.hard_clip_or_mask(args.hard_left, args.hard_right, mask);
-        update_trimmed_counts_field(&mut counts.hard, &fq_view, &mut counts.last_read_len, verbose);
+    if verbose {
+        counts.bases_trimmed += original_len - fq_view.len();
     }
     update_trimmed_counts_field(&mut counts.total_trimmed, &fq_view, &mut original_len, verbose);
-    fq_view
+    Some(fq_view)
 }
 
-#[derive(Default, Debug)]
+/// The characters of a read that are not canonical bases, counted before the
+/// bases are recoded.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct NonCanonicalBases {
+    /// The number of characters other than uppercase `ACGTN`, which are
+    /// recoded unless `--preserve-bases` is given
+    pub recoded:     usize,
+    /// The number of characters that are not IUPAC nucleotide codes in either
+    /// case, such as binary data in a corrupt input
+    pub non_iupac:   usize,
+    /// The first character that is not an IUPAC nucleotide code
+    first_non_iupac: Option<u8>,
+}
+
+impl NonCanonicalBases {
+    /// Counts the non-canonical characters of `sequence`. Sequences of only
+    /// uppercase `ACGTN` are checked without visiting each base.
+    pub fn new(sequence: &[u8]) -> Self {
+        let mut counts = NonCanonicalBases::default();
+        if sequence.is_acgtn_uc() {
+            return counts;
+        }
+
+        for &base in sequence {
+            if !base.is_valid(IsValidDNA::AcgtnNoGapsUc) {
+                counts.recoded += 1;
+            }
+            if !base.is_valid(IsValidDNA::IupacNoGaps) {
+                counts.non_iupac += 1;
+                counts.first_non_iupac.get_or_insert(base);
+            }
+        }
+        counts
+    }
+
+    /// Applies `--strict-bases` to the read with `header`, returning whether it
+    /// is dropped.
+    ///
+    /// ## Errors
+    ///
+    /// With [`StrictBases::Fail`], an error naming the read and its first
+    /// non-IUPAC character is returned if it has any.
+    pub fn is_dropped(&self, strict_bases: Option<StrictBases>, header: &str) -> std::io::Result<bool> {
+        let Some(first) = self.first_non_iupac else {
+            return Ok(false);
+        };
+        match strict_bases {
+            None => Ok(false),
+            Some(StrictBases::Drop) => Ok(true),
+            Some(StrictBases::Fail) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "The read '{header}' contains {count} character(s) that are not IUPAC nucleotide codes, the first being '{first}' (see `--strict-bases`)",
+                    count = self.non_iupac,
+                    first = first.escape_ascii()
+                ),
+            )),
+        }
+    }
+}
+
+/// The bases of a read kept so far, as a range of the read after recoding, and
+/// the number of `N` bases among them, for describing the effect of each
+/// trimming operation with `--trace-read`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct TracedSpan {
+    start:  usize,
+    end:    usize,
+    masked: usize,
+}
+
+impl TracedSpan {
+    /// Finds the span of `fq_view`, where `origin` is the address of the first
+    /// base of the read after recoding. Since trimming only narrows the view,
+    /// the offset of its first base gives the number of bases trimmed from the
+    /// left.
+    fn new(fq_view: &FastQViewMut<'_>, origin: usize) -> Self {
+        let start = fq_view.sequence.as_bytes().as_ptr().addr() - origin;
+        TracedSpan {
+            start,
+            end: start + fq_view.len(),
+            masked: fq_view.sequence.as_bytes().iter().filter(|&&b| b == b'N').count(),
+        }
+    }
+
+    /// Describes how a trimming operation changed the span from `before`.
+    fn describe(before: Option<Self>, fq_view: &FastQViewMut<'_>, origin: usize) -> String {
+        let Some(before) = before else {
+            return String::new();
+        };
+        let after = TracedSpan::new(fq_view, origin);
+
+        if after == before {
+            "no bases removed".to_string()
+        } else if (after.start, after.end) == (before.start, before.end) {
+            format!("masked {masked} bases", masked = after.masked.saturating_sub(before.masked))
+        } else {
+            format!(
+                "removed {left} bases from the left and {right} from the right, keeping bases {start}..{end}",
+                left = after.start - before.start,
+                right = before.end - after.end,
+                start = after.start,
+                end = after.end
+            )
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
 pub struct TrimmedCounts {
-    pub last_read_len:   usize,
-    pub hard:            usize,
-    pub poly_g:          usize,
-    pub adapter:         usize,
-    pub barcode:         usize,
-    pub primer:          usize,
-    pub length_filtered: usize,
-    pub widow_filtered:  usize,
-    pub total_trimmed:   usize,
-    pub total_processed: usize,
+    pub last_read_len:    usize,
+    pub hard:             usize,
+    pub poly_g:           usize,
+    pub adapter:          usize,
+    pub barcode:          usize,
+    pub unassigned:       usize,
+    pub primer:           usize,
+    pub length_filtered:  usize,
+    pub quality_filtered: usize,
+    pub n_filtered:       usize,
+    pub ont_filtered:     usize,
+    pub ont_unlisted:     usize,
+    pub widow_filtered:   usize,
+    pub total_trimmed:    usize,
+    pub bases_trimmed:    usize,
+    pub total_processed:  usize,
+    /// The number of bases recoded to uppercase `ACGTN`
+    pub recoded_bases:    usize,
+    /// The number of characters that are not IUPAC nucleotide codes
+    pub non_iupac_bases:  usize,
+    /// The number of reads dropped with `--strict-bases drop`
+    pub strict_filtered:  usize,
+    /// The lengths of the reads before trimming and of those written, if
+    /// `--length-hist` was given
+    pub lengths:          Option<BeforeAfterHistograms>,
+    /// The quality and base composition at each cycle of the reads before
+    /// trimming and of those written, if `--cycle-report` was given
+    pub cycles:           Option<BeforeAfterCycles>,
 }
 
 impl Add for TrimmedCounts {
@@ -68,16 +342,92 @@ impl Add for TrimmedCounts {
 
     fn add(self, other: Self) -> Self::Output {
         TrimmedCounts {
-            last_read_len:   self.last_read_len,
-            hard:            self.hard + other.hard,
-            poly_g:          self.poly_g + other.poly_g,
-            adapter:         self.adapter + other.adapter,
-            barcode:         self.barcode + other.barcode,
-            primer:          self.primer + other.primer,
-            length_filtered: self.length_filtered + other.length_filtered,
-            widow_filtered:  self.widow_filtered + other.widow_filtered,
-            total_trimmed:   self.total_trimmed + other.total_trimmed,
-            total_processed: self.total_processed + other.total_processed,
+            last_read_len:    self.last_read_len,
+            hard:             self.hard + other.hard,
+            poly_g:           self.poly_g + other.poly_g,
+            adapter:          self.adapter + other.adapter,
+            barcode:          self.barcode + other.barcode,
+            unassigned:       self.unassigned + other.unassigned,
+            primer:           self.primer + other.primer,
+            length_filtered:  self.length_filtered + other.length_filtered,
+            quality_filtered: self.quality_filtered + other.quality_filtered,
+            n_filtered:       self.n_filtered + other.n_filtered,
+            ont_filtered:     self.ont_filtered + other.ont_filtered,
+            ont_unlisted:     self.ont_unlisted + other.ont_unlisted,
+            widow_filtered:   self.widow_filtered + other.widow_filtered,
+            total_trimmed:    self.total_trimmed + other.total_trimmed,
+            bases_trimmed:    self.bases_trimmed + other.bases_trimmed,
+            total_processed:  self.total_processed + other.total_processed,
+            recoded_bases:    self.recoded_bases + other.recoded_bases,
+            non_iupac_bases:  self.non_iupac_bases + other.non_iupac_bases,
+            strict_filtered:  self.strict_filtered + other.strict_filtered,
+            lengths:          match (self.lengths, other.lengths) {
+                (Some(lengths), Some(other)) => Some(lengths + other),
+                (lengths, other) => lengths.or(other),
+            },
+            cycles:           match (self.cycles, other.cycles) {
+                (Some(cycles), Some(other)) => Some(cycles + other),
+                (cycles, other) => cycles.or(other),
+            },
+        }
+    }
+}
+
+impl TrimmedCounts {
+    /// The number of reads that were not written, whether filtered on their
+    /// own or as widows. Reads absent from the sequencing summary are only
+    /// included if they were not kept with `--ont-keep-unlisted`.
+    pub fn dropped(&self, ont_keep_unlisted: bool) -> usize {
+        let ont_unlisted = if ont_keep_unlisted { 0 } else { self.ont_unlisted };
+        self.length_filtered
+            + self.quality_filtered
+            + self.n_filtered
+            + self.ont_filtered
+            + ont_unlisted
+            + self.widow_filtered
+            + self.strict_filtered
+    }
+}
+
+/// [`TrimmedCounts`] kept separately for each side of paired reads, so that
+/// asymmetries between R1 and R2 (such as adapter contamination on only one
+/// side) can be reported. Unpaired reads are counted with R1.
+#[derive(Default, Debug)]
+pub struct SidedTrimmedCounts {
+    pub r1:                   TrimmedCounts,
+    pub r2:                   TrimmedCounts,
+    /// The pairs dropped because a mate was below the minimum length, under
+    /// the trimmer's `--pair-filter` policy
+    pub pairs_length_dropped: usize,
+    /// The pairs written even though a mate was below the minimum length,
+    /// under the trimmer's `--pair-filter` policy
+    pub pairs_short_kept:     usize,
+}
+
+impl SidedTrimmedCounts {
+    /// The counts for the given side.
+    pub fn side_mut(&mut self, side: ReadSide) -> &mut TrimmedCounts {
+        match side {
+            ReadSide::R1 | ReadSide::Unpaired => &mut self.r1,
+            ReadSide::R2 => &mut self.r2,
+        }
+    }
+
+    /// The counts summed over both sides.
+    pub fn combined(&self) -> TrimmedCounts {
+        self.r1.clone() + self.r2.clone()
+    }
+}
+
+impl Add for SidedTrimmedCounts {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self::Output {
+        SidedTrimmedCounts {
+            r1:                   self.r1 + other.r1,
+            r2:                   self.r2 + other.r2,
+            pairs_length_dropped: self.pairs_length_dropped + other.pairs_length_dropped,
+            pairs_short_kept:     self.pairs_short_kept + other.pairs_short_kept,
         }
     }
 }
@@ -0,0 +1,542 @@
+//! Shared support for the XFL table format written by `xflate` and
+//! `preprocess` and consumed during reinflation.
+
+use clap::ValueEnum;
+use flate2::Crc;
+use foldhash::fast::SeedableRandomState;
+use serde::Serialize;
+use std::{collections::HashMap, io::Write, path::Path};
+use zoe::prelude::*;
+
+/// The default prefix for the cluster names in the deflated FASTA and XFL
+/// table.
+const DEFAULT_CLUSTER_PREFIX: &str = "C";
+
+/// The tag beginning the header line of an XFL table.
+const XFL_TAG: &str = "#XFL";
+
+/// The current version of the XFL header line.
+pub(crate) const XFL_VERSION: &str = "v1";
+
+/// The byte joining the sequences (and quality scores) of R1 and R2 when pairs
+/// are deflated as single molecules. It is neither a nucleotide nor a tab, and
+/// is a valid (if meaningless) quality score.
+pub(crate) const PAIR_SEPARATOR: u8 = b'|';
+
+/// How the reads of an XFL table are laid out, recorded in the header with
+/// `layout=` when it is not the default.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub(crate) enum XflLayout {
+    /// Each read is stored as a header and quality scores
+    #[default]
+    Reads,
+    /// Each read pair is stored as a single molecule, with the R1 header, the
+    /// R2 header, and the joined quality scores. The cluster sequences are
+    /// joined in the same way, using [`PAIR_SEPARATOR`]
+    Pairs,
+}
+
+impl XflLayout {
+    /// The number of tab-delimited fields stored for each read (or pair) in a
+    /// row of the table.
+    pub(crate) fn fields_per_read(self) -> usize {
+        match self {
+            XflLayout::Reads => 2,
+            XflLayout::Pairs => 3,
+        }
+    }
+}
+
+/// How clusters are named in the deflated FASTA and XFL table: the prefix
+/// followed by the cluster number, counting from `start`. Sharded runs of
+/// `preprocess` can use distinct prefixes or starting numbers, so that the
+/// clusters of each shard remain unique once gathered.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct ClusterNaming {
+    pub(crate) prefix: String,
+    pub(crate) start:  usize,
+}
+
+impl Default for ClusterNaming {
+    fn default() -> Self {
+        ClusterNaming {
+            prefix: DEFAULT_CLUSTER_PREFIX.to_string(),
+            start:  0,
+        }
+    }
+}
+
+impl ClusterNaming {
+    /// Whether the cluster with the given prefix and number is among the
+    /// `clusters` clusters named from this prefix and starting number.
+    pub(crate) fn contains(&self, prefix: &str, num: usize, clusters: usize) -> bool {
+        prefix == self.prefix && num >= self.start && num - self.start < clusters
+    }
+}
+
+/// Parses a cluster prefix from the command line. The prefix must be nonempty,
+/// must not end with a digit (which would run into the cluster number) or
+/// start with `#`, and cannot contain whitespace or any of `%`, `{`, `}`, `>`,
+/// or `@`.
+pub(crate) fn parse_cluster_prefix(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        Err("The cluster prefix must not be empty.".to_string())
+    } else if value.ends_with(|c: char| c.is_ascii_digit()) {
+        Err(format!(
+            "The cluster prefix `{value}` must not end with a digit, since it is followed by the cluster number."
+        ))
+    } else if value.starts_with('#') {
+        Err(format!("The cluster prefix `{value}` must not start with `#`."))
+    } else if value.contains(|c: char| c.is_whitespace() || "%{}>@".contains(c)) {
+        Err(format!(
+            "The cluster prefix `{value}` must not contain whitespace or any of `%`, `{{`, `}}`, `>`, or `@`."
+        ))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// Splits a cluster name of the form `<PREFIX><ID>%[REST]` into its prefix
+/// and cluster number, returning `None` if it is not of this form.
+pub(crate) fn parse_cluster_id(name: &str) -> Option<(&str, usize)> {
+    let id = name.split('%').next()?;
+    let prefix = id.trim_end_matches(|c: char| c.is_ascii_digit());
+    let num = id[prefix.len()..].parse::<usize>().ok()?;
+    (!prefix.is_empty()).then_some((prefix, num))
+}
+
+/// Parses the size of a cluster from a name of the form `<PREFIX><ID>%<SIZE>`,
+/// returning `None` if there is no size after the `%`.
+pub(crate) fn parse_cluster_size(name: &str) -> Option<usize> {
+    let (_, rest) = name.split_once('%')?;
+    rest.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+}
+
+/// Replaces the size of a cluster in a name of the form
+/// `<PREFIX><ID>%<SIZE>[REST]`, keeping any `[REST]`. Names without a `%` are
+/// returned unchanged.
+pub(crate) fn replace_cluster_size(name: &str, size: usize) -> String {
+    match name.split_once('%') {
+        Some((id, rest)) => {
+            let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+            format!("{id}%{size}{rest}")
+        }
+        None => name.to_string(),
+    }
+}
+
+/// A type alias for the [`HashMap`] used to store the deflated sequences and
+/// the associated headers and quality scores.
+pub(crate) type DeflatedSequences = HashMap<Nucleotides, ClusterMembers, SeedableRandomState>;
+
+/// The deflated sequences with their members, in the order that the clusters
+/// are numbered (see [`order_clusters`]).
+pub(crate) type OrderedClusters = Vec<(Nucleotides, ClusterMembers)>;
+
+/// The order in which clusters are numbered and written, for `--sort-clusters`.
+/// Ties are broken by the cluster sequence, so that a sorted order does not
+/// depend on the hash seed.
+#[derive(ValueEnum, Serialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClusterOrder {
+    /// The largest clusters first
+    Size,
+    /// The longest cluster sequences first
+    Length,
+    /// By the header of each cluster's first read
+    Name,
+}
+
+/// Collects the deflated sequences into the order that the clusters are
+/// numbered. Without an `order`, the iteration order of the [`HashMap`] is
+/// used, which depends on the hash seed.
+pub(crate) fn order_clusters(deflated: DeflatedSequences, order: Option<ClusterOrder>) -> OrderedClusters {
+    fn first_header(members: &ClusterMembers) -> Option<&str> {
+        members.stored.first().map(|(header, _)| header.as_str())
+    }
+
+    let mut clusters = deflated.into_iter().collect::<OrderedClusters>();
+
+    match order {
+        Some(ClusterOrder::Size) => clusters.sort_unstable_by(|(seq1, members1), (seq2, members2)| {
+            members2
+                .len()
+                .cmp(&members1.len())
+                .then_with(|| seq1.as_bytes().cmp(seq2.as_bytes()))
+        }),
+        Some(ClusterOrder::Length) => clusters.sort_unstable_by(|(seq1, _), (seq2, _)| {
+            seq2.len().cmp(&seq1.len()).then_with(|| seq1.as_bytes().cmp(seq2.as_bytes()))
+        }),
+        Some(ClusterOrder::Name) => clusters.sort_unstable_by(|(seq1, members1), (seq2, members2)| {
+            first_header(members1)
+                .cmp(&first_header(members2))
+                .then_with(|| seq1.as_bytes().cmp(seq2.as_bytes()))
+        }),
+        None => {}
+    }
+
+    clusters
+}
+
+/// The members of a cluster: the headers and quality scores stored for its
+/// reads, along with the number of further reads whose metadata was not
+/// stored (see [`ClusterMembers::push_capped`]).
+#[derive(Clone, Default, Debug)]
+pub(crate) struct ClusterMembers {
+    pub(crate) stored:    Vec<(String, QualityScores)>,
+    pub(crate) dropped:   usize,
+    /// The mean quality scores of the reads, if tallied (see
+    /// [`ClusterMembers::tally_quality`])
+    pub(crate) qualities: MeanQualities,
+}
+
+impl ClusterMembers {
+    /// The number of reads in the cluster, whether or not their metadata was
+    /// stored.
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.stored.len() + self.dropped
+    }
+
+    /// Adds the header and quality scores of a read to the cluster.
+    #[inline]
+    pub(crate) fn push(&mut self, member: (String, QualityScores)) {
+        self.stored.push(member);
+    }
+
+    /// Adds a read to the cluster, only storing its header and quality scores
+    /// if fewer than `max_members` have been stored. Otherwise, the read is
+    /// only counted.
+    #[inline]
+    pub(crate) fn push_capped(&mut self, member: (String, QualityScores), max_members: Option<usize>) {
+        if max_members.is_some_and(|max_members| self.stored.len() >= max_members) {
+            self.dropped += 1;
+        } else {
+            self.stored.push(member);
+        }
+    }
+
+    /// Tallies the mean quality score of a read added to the cluster. Unlike
+    /// the stored metadata, this is done for every read, so that the
+    /// aggregates cover the whole cluster even when its members are capped.
+    #[inline]
+    pub(crate) fn tally_quality(&mut self, mean_q: f32) {
+        self.qualities.tally(mean_q);
+    }
+
+    /// Moves the reads of `other` into the cluster, after its own, keeping at
+    /// most `max_members` stored.
+    pub(crate) fn merge(&mut self, other: ClusterMembers, max_members: Option<usize>) {
+        self.stored.extend(other.stored);
+        self.dropped += other.dropped;
+        self.qualities.merge(other.qualities);
+        if let Some(max_members) = max_members
+            && self.stored.len() > max_members
+        {
+            self.dropped += self.stored.len() - max_members;
+            self.stored.truncate(max_members);
+        }
+    }
+}
+
+/// The aggregates of the mean quality scores of the reads in a cluster: their
+/// mean, minimum, and maximum.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub(crate) struct MeanQualities {
+    reads: usize,
+    sum:   f64,
+    min:   f32,
+    max:   f32,
+}
+
+impl MeanQualities {
+    /// Adds the mean quality score of a read.
+    #[inline]
+    fn tally(&mut self, mean_q: f32) {
+        if self.reads == 0 {
+            self.min = mean_q;
+            self.max = mean_q;
+        } else {
+            self.min = self.min.min(mean_q);
+            self.max = self.max.max(mean_q);
+        }
+        self.reads += 1;
+        self.sum += f64::from(mean_q);
+    }
+
+    /// Combines the aggregates of two sets of reads.
+    fn merge(&mut self, other: MeanQualities) {
+        if other.reads == 0 {
+            return;
+        }
+        if self.reads == 0 {
+            *self = other;
+            return;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.reads += other.reads;
+        self.sum += other.sum;
+    }
+
+    /// The mean, minimum, and maximum of the mean quality scores, or `None` if
+    /// none were tallied.
+    pub(crate) fn mean_min_max(&self) -> Option<(f64, f32, f32)> {
+        (self.reads > 0).then(|| (self.sum / self.reads as f64, self.min, self.max))
+    }
+}
+
+/// The header line of an XFL table, recording the number of clusters and reads
+/// in the table and a CRC-32 checksum of the companion deflated FASTA.
+///
+/// The line has the form `#XFL\tv1\tclusters=<N>\treads=<M>\tcrc32=<HEX>`,
+/// followed by `\tlayout=pairs` for the [`XflLayout::Pairs`] layout (in which
+/// case `reads` counts pairs), by `\tprefix=<PREFIX>\tstart=<S>` when the
+/// clusters are not named with the default [`ClusterNaming`], and by
+/// `\tmax_members=<N>` when at most `N` reads were stored for each cluster (in
+/// which case `reads` still counts every read, as do the cluster sizes). The checksum is
+/// computed over each cluster's sequence followed by a newline, in order of
+/// cluster number, so that it does not depend on how the FASTA was later split
+/// across files.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct XflHeader {
+    pub(crate) clusters:    usize,
+    pub(crate) reads:       usize,
+    pub(crate) crc32:       u32,
+    pub(crate) layout:      XflLayout,
+    pub(crate) naming:      ClusterNaming,
+    /// The maximum number of reads stored for each cluster, if capped
+    pub(crate) max_members: Option<usize>,
+}
+
+impl XflHeader {
+    /// Creates a header from an iterator over the cluster sequences (in cluster
+    /// order) and their sizes.
+    pub(crate) fn from_clusters<'a>(
+        clusters: impl IntoIterator<Item = (&'a Nucleotides, usize)>, layout: XflLayout, naming: ClusterNaming,
+    ) -> Self {
+        let mut crc = Crc::new();
+        let mut num_clusters = 0;
+        let mut reads = 0;
+
+        for (sequence, cluster_size) in clusters {
+            crc.update(sequence.as_bytes());
+            crc.update(b"\n");
+            num_clusters += 1;
+            reads += cluster_size;
+        }
+
+        XflHeader {
+            clusters: num_clusters,
+            reads,
+            crc32: crc.sum(),
+            layout,
+            naming,
+            max_members: None,
+        }
+    }
+
+    /// Parses a line of an XFL table as a header, returning `Ok(None)` if the
+    /// line is not a header (such as for legacy tables).
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the line is tagged as a header but is malformed
+    /// or has an unsupported version. The `path` is included as context.
+    pub(crate) fn parse(line: &str, path: &Path) -> std::io::Result<Option<Self>> {
+        let Some(rest) = line.strip_prefix(XFL_TAG) else {
+            return Ok(None);
+        };
+
+        let invalid = |msg: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{msg} in the XFL table: {path}\nHeader: {line}", path = path.display()),
+            )
+        };
+
+        let mut fields = rest.split('\t').skip(1);
+
+        match fields.next() {
+            Some(XFL_VERSION) => {}
+            Some(version) => return Err(invalid(format!("Unsupported XFL version '{version}'"))),
+            None => return Err(invalid("Missing XFL version".to_string())),
+        }
+
+        let (mut clusters, mut reads, mut crc32) = (None, None, None);
+        let mut layout = XflLayout::Reads;
+        let mut naming = ClusterNaming::default();
+        let mut max_members = None;
+        for field in fields {
+            match field.split_once('=') {
+                Some(("clusters", value)) => clusters = value.parse::<usize>().ok(),
+                Some(("reads", value)) => reads = value.parse::<usize>().ok(),
+                Some(("crc32", value)) => crc32 = u32::from_str_radix(value, 16).ok(),
+                Some(("layout", "reads")) => layout = XflLayout::Reads,
+                Some(("layout", "pairs")) => layout = XflLayout::Pairs,
+                Some(("layout", value)) => return Err(invalid(format!("Unsupported XFL layout '{value}'"))),
+                Some(("prefix", value)) => naming.prefix = value.to_string(),
+                Some(("start", value)) => match value.parse::<usize>() {
+                    Ok(start) => naming.start = start,
+                    Err(_) => return Err(invalid(format!("Invalid cluster start '{value}'"))),
+                },
+                Some(("max_members", value)) => match value.parse::<usize>() {
+                    Ok(max) if max > 0 => max_members = Some(max),
+                    _ => return Err(invalid(format!("Invalid maximum members per cluster '{value}'"))),
+                },
+                _ => {}
+            }
+        }
+
+        if let (Some(clusters), Some(reads), Some(crc32)) = (clusters, reads, crc32) {
+            Ok(Some(XflHeader {
+                clusters,
+                reads,
+                crc32,
+                layout,
+                naming,
+                max_members,
+            }))
+        } else {
+            Err(invalid("Malformed XFL header".to_string()))
+        }
+    }
+}
+
+impl std::fmt::Display for XflHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{XFL_TAG}\t{XFL_VERSION}\tclusters={clusters}\treads={reads}\tcrc32={crc32:08x}",
+            clusters = self.clusters,
+            reads = self.reads,
+            crc32 = self.crc32
+        )?;
+        if self.layout == XflLayout::Pairs {
+            write!(f, "\tlayout=pairs")?;
+        }
+        if self.naming != ClusterNaming::default() {
+            write!(
+                f,
+                "\tprefix={prefix}\tstart={start}",
+                prefix = self.naming.prefix,
+                start = self.naming.start
+            )?;
+        }
+        if let Some(max_members) = self.max_members {
+            write!(f, "\tmax_members={max_members}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the deflated sequences as FASTA to `fasta_writer` and as an XFL table
+/// (with header) to `table_writer`, with the clusters numbered in the order
+/// given and named by `naming`. If
+/// `clusters_writer` is provided, each cluster is also written to it as a FastQ
+/// record with a consensus quality (see [`consensus_quality`]). The number of
+/// clusters written is returned.
+///
+/// Each cluster is named with its full size, while only its stored reads are
+/// written to the table. `max_members` should be the cap with which the reads
+/// were stored, if any, so that the table is flagged as capped.
+///
+/// For [`XflLayout::Pairs`], each stored header must already be the two
+/// tab-free headers of the pair joined by a tab (see [`join_pair`]).
+pub(crate) fn write_deflated_sequences(
+    clusters: OrderedClusters, layout: XflLayout, naming: &ClusterNaming, max_members: Option<usize>,
+    mut table_writer: impl Write, mut fasta_writer: impl Write, mut clusters_writer: Option<impl Write>,
+) -> std::io::Result<usize> {
+    let mut header = XflHeader::from_clusters(
+        clusters.iter().map(|(seq, members)| (seq, members.len())),
+        layout,
+        naming.clone(),
+    );
+    header.max_members = max_members;
+    let prefix = &naming.prefix;
+    writeln!(table_writer, "{header}")?;
+
+    for (i, (sequence, members)) in clusters.into_iter().enumerate() {
+        let cluster_size = members.len();
+        let i = naming.start + i;
+
+        write!(fasta_writer, ">{prefix}{i}%{cluster_size}\n{sequence}\n")?;
+
+        if let Some(clusters_writer) = &mut clusters_writer {
+            write!(clusters_writer, "@{prefix}{i}%{cluster_size}\n{sequence}\n+\n")?;
+            clusters_writer.write_all(&consensus_quality(&members.stored, sequence.len()))?;
+            writeln!(clusters_writer)?;
+        }
+
+        write!(table_writer, "{prefix}{i}%{cluster_size}")?;
+        for (mut header, quality_scores) in members.stored {
+            if layout == XflLayout::Reads {
+                crate::shared::replace_tabs_with_spaces(&mut header);
+            }
+
+            // Validity: both `header` and `quality_scores` are tab free, the
+            // header by sanitization and quality scores by construction
+            // (graphic ASCII)
+            write!(table_writer, "\t{header}\t{quality_scores}")?;
+        }
+        writeln!(table_writer)?;
+    }
+
+    table_writer.flush()?;
+    fasta_writer.flush()?;
+    if let Some(clusters_writer) = &mut clusters_writer {
+        clusters_writer.flush()?;
+    }
+
+    Ok(header.clusters)
+}
+
+/// Joins the two reads of a pair into a single molecule, returning the joined
+/// header, sequence, and quality scores for [`XflLayout::Pairs`]. Tabs within
+/// the headers are replaced with spaces before they are joined with a tab.
+pub(crate) fn join_pair(
+    [(mut header1, sequence1, quality1), (mut header2, sequence2, quality2)]: [(String, &[u8], &[u8]); 2],
+) -> (String, Nucleotides, QualityScores) {
+    crate::shared::replace_tabs_with_spaces(&mut header1);
+    crate::shared::replace_tabs_with_spaces(&mut header2);
+    header1.push('\t');
+    header1.push_str(&header2);
+
+    let join = |a: &[u8], b: &[u8]| [a, &[PAIR_SEPARATOR], b].concat();
+    let sequence = Nucleotides::from_vec_unchecked(join(sequence1, sequence2));
+    // Safety: the quality scores are graphic ASCII, as is the separator
+    let quality = unsafe { QualityScores::from_vec_unchecked(join(quality1, quality2)) };
+
+    (header1, sequence, quality)
+}
+
+/// Finds the position of [`PAIR_SEPARATOR`] in the joined sequence of a pair,
+/// at which both the sequence and the quality scores are split.
+pub(crate) fn find_pair_separator(sequence: &[u8]) -> Option<usize> {
+    sequence.iter().position(|&b| b == PAIR_SEPARATOR)
+}
+
+/// Computes the consensus quality scores (as Phred+33) for a cluster of reads
+/// sharing a sequence of length `len`.
+///
+/// At each position, the error probabilities of the stored members' quality
+/// scores are averaged and converted back to a Phred score, so that the
+/// consensus reflects the expected error rate of a read in the cluster. The cluster size
+/// is recorded separately in the header.
+fn consensus_quality(metadata: &[(String, QualityScores)], len: usize) -> Vec<u8> {
+    let mut error_sums = vec![0.0; len];
+    for (_, quality_scores) in metadata {
+        for (sum, &q) in error_sums.iter_mut().zip(quality_scores.as_bytes()) {
+            *sum += 10f64.powf(-f64::from(q.saturating_sub(33)) / 10.0);
+        }
+    }
+
+    let num_reads = metadata.len().max(1) as f64;
+    error_sums
+        .into_iter()
+        .map(|sum| {
+            let phred = (-10.0 * (sum / num_reads).log10()).round().clamp(0.0, 93.0);
+            phred as u8 + 33
+        })
+        .collect()
+}
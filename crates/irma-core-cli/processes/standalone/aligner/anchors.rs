@@ -0,0 +1,162 @@
+//! Anchoring of amplicon queries to their expected reference coordinates, as
+//! described by a BED file
+
+use foldhash::fast::SeedableRandomState;
+use irma_records::{hashing::get_hasher, io::FastX};
+use std::{collections::HashMap, ops::Range, path::Path};
+use zoe::{data::fasta::FastaSeq, prelude::NucleotidesView};
+
+/// The number of bases at each end of an amplicon used to detect its primers
+/// at the start of a query.
+const PRIMER_KMER_LEN: usize = 16;
+
+/// The prefix of the header tag naming the amplicon of a query, such as
+/// `amplicon=nCoV_1`.
+const AMPLICON_TAG: &str = "amplicon=";
+
+/// The region of a reference to which a query is anchored.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Amplicon {
+    /// The index of the reference containing the amplicon
+    pub reference: usize,
+    /// The 0-based range of the reference to align against, which is the
+    /// amplicon extended by the slop and clamped to the reference
+    pub window:    Range<usize>,
+}
+
+/// The amplicons read from `--anchor-bed`, along with the lookups used to
+/// identify the amplicon of a query.
+pub struct Anchors {
+    amplicons: Vec<Amplicon>,
+    /// The amplicon with each name
+    by_name:   HashMap<String, usize, SeedableRandomState>,
+    /// The amplicon starting with each primer k-mer, or `None` if the k-mer is
+    /// shared by several amplicons
+    by_primer: HashMap<Vec<u8>, Option<usize>, SeedableRandomState>,
+}
+
+impl Anchors {
+    /// Reads the amplicons from the BED file at `path`, whose first column
+    /// names a reference in `references` (by the first word of its header).
+    /// The fourth column, if present, names the amplicon for header tags.
+    ///
+    /// Each amplicon is extended by `slop` bases on both sides. Blank lines and
+    /// `#`, `track`, and `browser` lines are skipped.
+    ///
+    /// ## Errors
+    ///
+    /// Errors are returned if the file cannot be read, or if a line has fewer
+    /// than three columns, invalid coordinates, an unknown reference, or a
+    /// repeated name. Each contains the path and line number as context.
+    pub fn from_bed(path: &Path, references: &[FastaSeq], slop: usize) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Failed to read the anchor BED file: {}\n{e}", path.display()),
+            )
+        })?;
+
+        let reference_ids = references
+            .iter()
+            .map(|reference| reference.name.split_ascii_whitespace().next().unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        let mut anchors = Anchors {
+            amplicons: Vec::new(),
+            by_name:   HashMap::with_hasher(get_hasher()),
+            by_primer: HashMap::with_hasher(get_hasher()),
+        };
+
+        for (line_number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue;
+            }
+
+            let invalid = |msg: &str| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{msg} on line {line_number} of the anchor BED file: {path}\nLine: {line}",
+                        line_number = line_number + 1,
+                        path = path.display()
+                    ),
+                )
+            };
+
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [chrom, start, end, rest @ ..] = fields.as_slice() else {
+                return Err(invalid("Expected at least three columns"));
+            };
+
+            let Some(reference) = reference_ids.iter().position(|id| id == chrom) else {
+                return Err(invalid("The reference does not match any reference header"));
+            };
+            let reference_len = references[reference].sequence.len();
+
+            let (Ok(start), Ok(end)) = (start.trim().parse::<usize>(), end.trim().parse::<usize>()) else {
+                return Err(invalid("Invalid coordinates"));
+            };
+            if start >= end || end > reference_len {
+                return Err(invalid("The coordinates are empty or extend past the end of the reference"));
+            }
+
+            let index = anchors.amplicons.len();
+            if let Some(name) = rest.first().map(|name| name.trim()).filter(|name| !name.is_empty())
+                && anchors.by_name.insert(name.to_string(), index).is_some()
+            {
+                return Err(invalid("The amplicon name is repeated"));
+            }
+
+            // A query may begin with either the forward primer, or the reverse
+            // primer on the opposite strand
+            let amplicon_seq = &references[reference].sequence[start..end];
+            if amplicon_seq.len() >= PRIMER_KMER_LEN {
+                let forward = amplicon_seq[..PRIMER_KMER_LEN].to_ascii_uppercase();
+                let reverse = NucleotidesView::from(&amplicon_seq[amplicon_seq.len() - PRIMER_KMER_LEN..])
+                    .to_reverse_complement()
+                    .into_vec()
+                    .to_ascii_uppercase();
+
+                for kmer in [forward, reverse] {
+                    anchors
+                        .by_primer
+                        .entry(kmer)
+                        .and_modify(|existing| {
+                            if *existing != Some(index) {
+                                *existing = None;
+                            }
+                        })
+                        .or_insert(Some(index));
+                }
+            }
+
+            anchors.amplicons.push(Amplicon {
+                reference,
+                window: start.saturating_sub(slop)..(end + slop).min(reference_len),
+            });
+        }
+
+        Ok(anchors)
+    }
+
+    /// Identifies the amplicon of a query, first from an `amplicon=` tag in its
+    /// header, and otherwise from the primer at the start of its sequence.
+    /// `None` is returned if no amplicon (or more than one) is identified.
+    pub fn find(&self, query: &FastX) -> Option<&Amplicon> {
+        let tagged = query
+            .header
+            .split_ascii_whitespace()
+            .find_map(|token| token.strip_prefix(AMPLICON_TAG))
+            .and_then(|name| self.by_name.get(name));
+
+        let index = match tagged {
+            Some(&index) => index,
+            None => {
+                let kmer = query.sequence.get(..PRIMER_KMER_LEN)?.to_ascii_uppercase();
+                (*self.by_primer.get(&kmer)?)?
+            }
+        };
+
+        self.amplicons.get(index)
+    }
+}
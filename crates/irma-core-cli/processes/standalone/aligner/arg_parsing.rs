@@ -1,13 +1,28 @@
+#[cfg(not(feature = "dev_no_rayon"))]
+use crate::num_procs::get_num_procs;
 use crate::{
-    aligner::{AlignerArgs, QueryReader},
+    aligner::{
+        AlignerArgs,
+        anchors::Anchors,
+        manifest::resolve_manifest,
+        normalize::Normalization,
+        ref_params::{RefScoring, read_ref_params},
+        samples::{Sample, SampleSheetPaths, read_sample_sheet},
+        scores::ScoreNormalizer,
+        spans::{SpanKind, SpanRecorder, TimeSpan},
+        sweep::{Sweep, SweepParams},
+        writers::Coords,
+    },
     args::abort_clap,
+    shared::trace::ReadTracer,
 };
 use clap::{ValueEnum, builder::PossibleValue, error::ErrorKind};
-use irma_records::io::InputOptions;
-use std::{fmt::Display, path::PathBuf};
+use irma_records::io::{GzipFormat, InputOptions, ValidatePaths};
+use std::{fmt::Display, num::NonZeroUsize, path::PathBuf};
 use zoe::{
     data::{
         AA_ALL_AMBIG_PROFILE_MAP_WITH_STOP, WeightMatrix,
+        err::ResultWithErrorContext,
         fasta::FastaSeq,
         matrices::{BLOSUM_62, aa_mat_from_name},
     },
@@ -16,46 +31,120 @@ use zoe::{
 
 /// The parsed and validated command line arguments for `aligner`
 pub struct ParsedAlignerArgs {
-    /// The streamed query sequences
-    pub query_reader:      QueryReader,
+    /// The query files to align, each with its output path. There is exactly
+    /// one unless `--sample-sheet` was provided
+    pub samples:           Vec<Sample>,
+    /// Whether the samples were read from `--sample-sheet`, in which case a
+    /// summary of each is written to stdout
+    pub sample_sheet:      bool,
     /// The slurped reference sequences
     ///
     /// ## Validity
     ///
     /// This field must be non-empty.
     pub references:        Vec<FastaSeq>,
+    /// The group of each reference, if provided by a reference manifest
+    pub reference_groups:  Vec<Option<String>>,
     /// The weight matrix to use for the alignment
     pub weight_matrix:     AnyMatrix<'static, i8>,
+    /// The scoring of each reference overridden by `--ref-params`, in the
+    /// order of `references`, or empty if it was not provided
+    pub ref_scoring:       Vec<Option<RefScoring<5>>>,
     /// Whether to write the SAM header
     pub header:            bool,
     /// The file to print tally diagnostics to
     pub tally_diagnostics: Option<PathBuf>,
+    /// The FASTQ file to write the aligned portion of each mapped query to, if
+    /// `--extract-aligned` was provided
+    pub extract_aligned:   Option<PathBuf>,
+    /// The per-reference outputs, if `--split-by-best-ref` was provided
+    pub split:             Option<SplitOptions>,
+    /// The parameter sweep to run instead of aligning, if `--sweep` was
+    /// provided
+    pub sweep:             Option<Sweep>,
     /// Any additional configuration
     pub config:            AlignerConfig,
 }
 
+/// The options for `--split-by-best-ref`
+pub struct SplitOptions {
+    /// The directory in which the per-reference outputs are written, which has
+    /// been created
+    pub dir:      PathBuf,
+    /// Whether the whole queries are also written, for `--split-queries`
+    pub queries:  bool,
+    /// The maximum number of files kept open
+    pub max_open: NonZeroUsize,
+}
+
 /// The parsed and validated configuration options for `aligner`
 pub struct AlignerConfig {
     /// The gap open weight (should be non-positive)
-    pub gap_open:         i8,
+    pub gap_open:            i8,
     /// The gap extend weight (should be non-positive)
-    pub gap_extend:       i8,
+    pub gap_extend:          i8,
     /// Whether to also align the reverse complements
-    pub rev_comp:         bool,
+    pub rev_comp:            bool,
+    /// Whether to also write the alignment of the worse strand
+    pub report_both_strands: bool,
     /// Any override for which sequence to build the profile from
-    pub profile_from:     Option<WhichSequence>,
+    pub profile_from:        Option<WhichSequence>,
     /// Any override for the number of passes to use
-    pub method:           Option<NumPasses>,
+    pub method:              Option<NumPasses>,
+    /// Whether to compute ungapped alignments instead, for `--ungapped`
+    pub ungapped:            bool,
+    /// The minimum score for an alignment to be mapped
+    pub min_score:           u32,
     /// Whether to exclude unmapped alignments from the final output
-    pub exclude_unmapped: bool,
+    pub exclude_unmapped:    bool,
+    /// Whether to annotate unmapped alignments with the reason they are
+    /// unmapped
+    pub unmapped_reason:     bool,
     /// Whether to perform best match alignment
-    pub best_match:       bool,
-    /// The output path for the alignments (included in the config so that error
-    /// context can be added)
-    pub output:           Option<PathBuf>,
-    /// Whether to set the Rayon number of threads to one
+    pub best_match:          bool,
+    /// How to normalize scores for reporting and best match selection, if at
+    /// all
+    pub normalizer:          Option<ScoreNormalizer>,
+    /// The output path for the mpileup-style output, if requested
+    pub pileup:              Option<PathBuf>,
+    /// The output path for the reference usage statistics, if requested
+    pub ref_usage:           Option<PathBuf>,
+    /// Whether the tab-delimited outputs end with a footer, for `--footer`
+    pub footer:              bool,
+    /// The coordinate system of the reference positions in outputs other than
+    /// SAM
+    pub coords:              Coords,
+    /// The amplicons to anchor queries to, if `--anchor-bed` was provided
+    pub anchors:             Option<Anchors>,
+    /// The recorder for timing spans, if `--profile-spans` was provided
+    pub spans:               Option<SpanRecorder>,
+    /// The maximum length of the concatenated references, if `--concat-refs`
+    /// was provided
+    pub concat_refs:         Option<NonZeroUsize>,
+    /// The number of references each query is aligned against, if
+    /// `--top-k-refs` was provided
+    pub top_k_refs:          Option<NonZeroUsize>,
+    /// The number of references profiled at a time, if `--ref-chunk-size` was
+    /// provided
+    pub ref_chunk_size:      Option<NonZeroUsize>,
+    /// Whether lowercase bases in the references are masked as `N`
+    pub respect_softmask:    bool,
+    /// How the residues of the queries and references are normalized as they
+    /// are read
+    pub normalization:       Normalization,
+    /// The queries traced with `--trace-read`
+    pub tracer:              ReadTracer,
+    /// How compressed outputs are written, with `--bgzf`
+    pub gzip_format:         GzipFormat,
+    /// Whether each sample's outputs name it as a read group, for
+    /// `--read-groups`
+    pub read_groups:         bool,
+    /// The number of samples aligned at a time
+    #[cfg(not(feature = "dev_no_rayon"))]
+    pub concurrent_samples:  NonZeroUsize,
+    /// The number of threads in the Rayon pool used for alignment
     #[cfg(not(feature = "dev_no_rayon"))]
-    pub single_thread:    bool,
+    pub threads:             usize,
 }
 
 /// Parses and validates the arguments for `aligner` from the clap struct.
@@ -63,19 +152,36 @@ pub struct AlignerConfig {
 /// [`abort_clap`] will be called if:
 ///
 /// - [`AnyMatrix::parse_from_clap`] fails (see the docs)
+/// - The alphabet is [`Aa`] and `respect_softmask` is true
+/// - The alphabet is [`Aa`] and `rna_to_dna` is true
 /// - The alphabet is [`Aa`] and `rev_comp` is true
+/// - The alphabet is [`Aa`] and `emit_mpileup_like` is specified
+/// - The alphabet is [`Aa`] and `anchor_bed` is specified
+/// - The alphabet is [`Aa`] and `concat_refs` is specified
+/// - The alphabet is [`Aa`] and `top_k_refs` is specified
+/// - The alphabet is [`Aa`] and `ref_params` is specified
+/// - Bit scores are requested, but the weight matrix has no Karlin-Altschul
+///   `lambda` (see [`ScoreNormalizer::new`])
 /// - The gap open penalty is smaller than the gap extend penalty
+/// - `--sweep` varies `matching` or `mismatch` along with `--matrix`, or every
+///   combination it gives has a gap open penalty smaller than the gap extend
+///   penalty
+/// - `--ref-chunk-size` splits the references into more than one chunk, and a
+///   query file is not a regular file (so it cannot be read more than once)
 ///
 /// ## Errors
 ///
-/// Any IO errors from opening the queries, references, and output file are
-/// propagated, with context containing the file path. If there is an invalid
-/// record in the reference file, an error with the file path as context is
-/// returned.
+/// Any IO errors from opening the references are propagated, with context
+/// containing the file path. If there is an invalid record in the reference
+/// file, an error with the file path as context is returned. When a reference
+/// manifest is used, errors from resolving it (see [`resolve_manifest`]) are
+/// also propagated, as are errors from reading the anchor BED file (see
+/// [`Anchors::from_bed`]), the sample sheet (see [`read_sample_sheet`]), or the
+/// reference parameters (see [`read_ref_params`]). Errors creating the
+/// `--split-by-best-ref` directory are propagated with the path as context.
+/// The paths listed in a sample sheet are validated as in [`ValidatePaths`].
 ///
-/// Any invalid records in the query file do not immediately produce errors
-/// (since the reader is lazy), but any errors later produced will contain the
-/// file path as context.
+/// The query files are not opened until they are aligned.
 ///
 /// [`Aa`]: Alphabet::Aa
 #[allow(unused_mut)]
@@ -83,7 +189,58 @@ pub fn parse_aligner_args(args: AlignerArgs) -> std::io::Result<ParsedAlignerArg
     #[cfg(not(feature = "dev-adaptive"))]
     let mut args = args;
 
-    let weight_matrix = AnyMatrix::parse_from_clap(args.alphabet, args.matrix, args.matching, args.mismatch, args.ignore_n);
+    if args.alphabet == Some(Alphabet::Aa) && args.respect_softmask {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--respect-softmask` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    // Masked bases are replaced with `N`, which must then score 0
+    let ignore_n = args.ignore_n || args.respect_softmask;
+
+    let sweep = args.sweep.map(|spec| {
+        if args.matrix.is_some() && spec.sweeps_scores() {
+            abort_clap(
+                ErrorKind::ArgumentConflict,
+                "`--sweep` cannot vary `matching` or `mismatch` when `--matrix` is specified",
+                Some("aligner"),
+            );
+        }
+
+        // The scores only apply to a simple weight matrix, which is used
+        // unless a protein matrix is named or defaulted to
+        let named_matrix = args.matrix.is_some()
+            || (args.alphabet == Some(Alphabet::Aa)
+                && args.matching.is_none()
+                && args.mismatch.is_none()
+                && !spec.sweeps_scores());
+        let defaults = SweepParams {
+            gap_open:   args.gap_open,
+            gap_extend: args.gap_extend,
+            matching:   (!named_matrix).then(|| args.matching.unwrap_or(DEFAULT_MATCHING)),
+            mismatch:   (!named_matrix).then(|| args.mismatch.unwrap_or(DEFAULT_MISMATCH)),
+        };
+
+        let sweep = Sweep::new(
+            spec,
+            defaults,
+            args.sweep_queries.get(),
+            args.alphabet,
+            args.matrix.clone(),
+            ignore_n,
+        );
+        if sweep.combinations.is_empty() {
+            abort_clap(
+                ErrorKind::InvalidValue,
+                "Every combination in `--sweep` has a gap open penalty smaller than the gap extend penalty",
+                Some("aligner"),
+            );
+        }
+        sweep
+    });
+    let weight_matrix = AnyMatrix::parse_from_clap(args.alphabet, args.matrix, args.matching, args.mismatch, ignore_n);
 
     if weight_matrix.alphabet() == Alphabet::Aa && args.rev_comp {
         abort_clap(
@@ -93,6 +250,64 @@ pub fn parse_aligner_args(args: AlignerArgs) -> std::io::Result<ParsedAlignerArg
         );
     }
 
+    if weight_matrix.alphabet() == Alphabet::Aa && args.rna_to_dna {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--rna-to-dna` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    if weight_matrix.alphabet() == Alphabet::Aa && args.emit_mpileup_like.is_some() {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--emit-mpileup-like` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    if weight_matrix.alphabet() == Alphabet::Aa && args.anchor_bed.is_some() {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--anchor-bed` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    if weight_matrix.alphabet() == Alphabet::Aa && args.concat_refs.is_some() {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--concat-refs` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    if weight_matrix.alphabet() == Alphabet::Aa && args.top_k_refs.is_some() {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--top-k-refs` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    if weight_matrix.alphabet() == Alphabet::Aa && args.ref_params.is_some() {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--ref-params` cannot be specified with an amino acid alphabet",
+            Some("aligner"),
+        );
+    }
+
+    let normalizer = args.normalize_scores.map(|normalization| {
+        ScoreNormalizer::new(normalization, &weight_matrix).unwrap_or_else(|| {
+            abort_clap(
+                ErrorKind::InvalidValue,
+                "Bit scores require a weight matrix with a negative expected score and at least one positive score",
+                Some("aligner"),
+            )
+        })
+    });
+
     let gap_open = -(args.gap_open as i8);
     let gap_extend = -(args.gap_extend as i8);
 
@@ -108,26 +323,98 @@ pub fn parse_aligner_args(args: AlignerArgs) -> std::io::Result<ParsedAlignerArg
         )
     }
 
-    let query_reader = InputOptions::new_from_path(&args.query_file)
-        .use_file_or_zip()
-        .decode_in_thread()
-        .parse_fastx()
-        .open()?;
+    // Validity: clap requires the reference path unless `--self-test` is given,
+    // which is handled before parsing
+    let Some(ref_file) = &args.ref_file else {
+        unreachable!("The reference file is required by clap")
+    };
 
-    let references = InputOptions::new_from_path(&args.ref_file)
-        .use_file_or_zip()
-        .parse_fasta()
-        .open()?
-        .collect::<Result<Vec<_>, _>>()?;
+    let samples = match (&args.sample_sheet, args.query_file) {
+        (Some(sample_sheet), _) => {
+            let samples = read_sample_sheet(sample_sheet)?;
+            SampleSheetPaths {
+                inputs:  std::iter::once(ref_file)
+                    .chain(&args.anchor_bed)
+                    .chain(samples.iter().map(|sample| &sample.query_file))
+                    .cloned()
+                    .collect(),
+                outputs: samples
+                    .iter()
+                    .filter_map(|sample| sample.output.as_ref())
+                    .chain(&args.profile_spans)
+                    .cloned()
+                    .collect(),
+            }
+            .validate_paths()?;
+            samples
+        }
+        (None, Some(query_file)) => vec![Sample {
+            name: query_file.display().to_string(),
+            query_file,
+            output: args.output,
+        }],
+        // Validity: clap requires the query path unless `--self-test` or
+        // `--sample-sheet` is given
+        (None, None) => unreachable!("The query file is required by clap"),
+    };
+
+    // Created first, so that reading the references is included
+    let spans = args.profile_spans.map(SpanRecorder::new);
+
+    let (mut references, reference_groups) = spans.time(SpanKind::Read, || -> std::io::Result<_> {
+        if args.ref_manifest {
+            let resolved = resolve_manifest(ref_file)?;
+            Ok((resolved.references, resolved.groups))
+        } else {
+            let references = InputOptions::new_from_path(ref_file)
+                .use_file_or_zip()
+                .parse_fasta()
+                .open()?
+                .collect::<Result<Vec<_>, _>>()?;
+            let groups = vec![None; references.len()];
+            Ok((references, groups))
+        }
+    })?;
 
     // Validity: references field is required to be non-empty
     if references.is_empty() {
-        return Err(std::io::Error::other(format!(
-            "Empty reference file: {}",
-            args.ref_file.display()
-        )));
+        return Err(std::io::Error::other(format!("Empty reference file: {}", ref_file.display())));
+    }
+
+    let normalization = Normalization {
+        uppercase:  args.normalize_case,
+        rna_to_dna: args.rna_to_dna,
+        alphabet:   weight_matrix.alphabet(),
+    };
+    normalization.apply_to_references(&mut references, args.respect_softmask);
+
+    if let Some(chunk_size) = args.ref_chunk_size
+        && references.len() > chunk_size.get()
+        && let Some(sample) = samples.iter().find(|sample| !sample.query_file.is_file())
+    {
+        abort_clap(
+            ErrorKind::InvalidValue,
+            format!(
+                "`--ref-chunk-size` reads the queries once per chunk of references, so they must be in a regular file, but {path} is not",
+                path = sample.query_file.display()
+            ),
+            Some("aligner"),
+        );
     }
 
+    let anchors = match &args.anchor_bed {
+        Some(path) => Some(Anchors::from_bed(path, &references, args.anchor_slop)?),
+        None => None,
+    };
+
+    let ref_scoring = match &args.ref_params {
+        Some(path) => read_ref_params(path, &references)?
+            .into_iter()
+            .map(|params| params.map(|params| RefScoring::new_dna(params, ignore_n)))
+            .collect(),
+        None => Vec::new(),
+    };
+
     let mut profile_from = if args.profile_from_query {
         Some(WhichSequence::Query)
     } else if args.profile_from_ref {
@@ -136,6 +423,18 @@ pub fn parse_aligner_args(args: AlignerArgs) -> std::io::Result<ParsedAlignerArg
         None
     };
 
+    // Alignments bridging two concatenated references are realigned with the
+    // query profile, so it is used throughout
+    if args.concat_refs.is_some() {
+        profile_from = Some(WhichSequence::Query);
+    }
+
+    // The overridden scoring is built into the reference profiles, so they are
+    // used throughout
+    if args.ref_params.is_some() {
+        profile_from = Some(WhichSequence::Reference);
+    }
+
     #[cfg(not(feature = "dev-adaptive"))]
     if profile_from.is_none() {
         profile_from = Some(WhichSequence::Query);
@@ -146,27 +445,93 @@ pub fn parse_aligner_args(args: AlignerArgs) -> std::io::Result<ParsedAlignerArg
         args.method = Some(NumPasses::OnePass);
     }
 
+    let split = match args.split_by_best_ref {
+        Some(dir) => {
+            std::fs::create_dir_all(&dir).with_path_context("Failed to create the directory", &dir)?;
+            Some(SplitOptions {
+                dir,
+                queries: args.split_queries,
+                max_open: args.split_max_open,
+            })
+        }
+        None => None,
+    };
+
     Ok(ParsedAlignerArgs {
-        query_reader,
+        samples,
+        sample_sheet: args.sample_sheet.is_some(),
         references,
+        reference_groups,
         weight_matrix,
+        ref_scoring,
         header: args.header,
         tally_diagnostics: args.tally_diagnostics,
+        extract_aligned: args.extract_aligned,
+        split,
+        sweep,
         config: AlignerConfig {
             gap_open,
             gap_extend,
             rev_comp: args.rev_comp,
+            report_both_strands: args.report_both_strands,
             profile_from,
             method: args.method,
+            ungapped: args.ungapped,
+            min_score: args.min_score,
             exclude_unmapped: args.exclude_unmapped,
+            unmapped_reason: args.unmapped_reason,
             best_match: args.best_match,
-            output: args.output,
+            normalizer,
+            pileup: args.emit_mpileup_like,
+            ref_usage: args.ref_usage,
+            footer: args.footer,
+            coords: args.coords,
+            anchors,
+            spans,
+            concat_refs: args.concat_refs,
+            top_k_refs: args.top_k_refs,
+            ref_chunk_size: args.ref_chunk_size,
+            respect_softmask: args.respect_softmask,
+            normalization,
+            tracer: ReadTracer::new("aligner", args.trace),
+            gzip_format: if args.bgzf { GzipFormat::Bgzf } else { GzipFormat::Gzip },
+            read_groups: args.read_groups,
+            #[cfg(not(feature = "dev_no_rayon"))]
+            concurrent_samples: args.concurrent_samples,
             #[cfg(not(feature = "dev_no_rayon"))]
-            single_thread: args.single_thread,
+            threads: get_thread_count(args.single_thread, args.threads),
         },
     })
 }
 
+/// Determines the number of threads to use for alignment.
+///
+/// An explicit `--threads` or `--single-thread` takes precedence, followed by a
+/// valid `RAYON_NUM_THREADS` environment variable. Otherwise, the logical core
+/// count (respecting cgroup limits and the IRMA core-capping variables) is
+/// used.
+#[cfg(not(feature = "dev_no_rayon"))]
+fn get_thread_count(single_thread: bool, threads: Option<std::num::NonZeroUsize>) -> usize {
+    if single_thread {
+        1
+    } else if let Some(threads) = threads {
+        threads.get()
+    } else if let Some(threads) = std::env::var("RAYON_NUM_THREADS")
+        .ok()
+        .and_then(|v| v.parse::<std::num::NonZeroUsize>().ok())
+    {
+        threads.get()
+    } else {
+        get_num_procs(false, true)
+    }
+}
+
+/// The default score for matching residues in a simple weight matrix.
+const DEFAULT_MATCHING: u8 = 2;
+
+/// The default penalty for mismatching residues in a simple weight matrix.
+const DEFAULT_MISMATCH: u8 = 5;
+
 /// A clap enum for specifying the alphabet.
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Alphabet {
@@ -317,15 +682,15 @@ impl<'a> AnyMatrix<'a, i8> {
     ///
     /// [`Dna`]: Alphabet::Dna
     /// [`Aa`]: Alphabet::Aa
-    fn parse_from_clap(
+    pub fn parse_from_clap(
         alphabet: Option<Alphabet>, matrix: Option<String>, matching: Option<u8>, mismatch: Option<u8>, ignore_n: bool,
     ) -> Self {
         // If either matching or mismatch is specified, then use the defaults
         // for the other
         let scores = match (matching, mismatch) {
             (None, None) => None,
-            (Some(matching), None) => Some((matching, 5)),
-            (None, Some(mismatch)) => Some((2, mismatch)),
+            (Some(matching), None) => Some((matching, DEFAULT_MISMATCH)),
+            (None, Some(mismatch)) => Some((DEFAULT_MATCHING, mismatch)),
             (Some(matching), Some(mismatch)) => Some((matching, mismatch)),
         };
 
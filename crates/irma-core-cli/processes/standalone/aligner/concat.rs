@@ -0,0 +1,165 @@
+//! Concatenation of many short references into a few longer ones for
+//! `--concat-refs`, along with the mapping of alignments back to the original
+//! references
+
+use crate::aligner::{AlignmentAndSeqs, align_best_ref, scores::ScoreNormalizer};
+use std::ops::Range;
+use zoe::data::fasta::FastaSeq;
+
+/// The sentinel placed between consecutive references in a concatenated
+/// reference. It is long enough that an alignment bridging two references is
+/// heavily penalized under the default scores, though such alignments are still
+/// detected and realigned.
+const SEPARATOR: &[u8] = b"NNNNNNNNNNNNNNNNNNNNNNNNNNNNNNNN";
+
+/// An original reference placed within a concatenated reference.
+struct Member {
+    /// The index of the original reference
+    index: usize,
+    /// The 0-based offset of the original reference within the concatenated
+    /// reference
+    start: usize,
+}
+
+/// The references concatenated for `--concat-refs`, along with the layout of
+/// the originals within each of them.
+pub struct ConcatRefs<'r> {
+    /// The original references
+    originals:  &'r [FastaSeq],
+    /// The concatenated references, which are aligned against in place of the
+    /// originals
+    pub chunks: Vec<FastaSeq>,
+    /// The original references within each concatenated reference, in order
+    members:    Vec<Vec<Member>>,
+}
+
+impl<'r> ConcatRefs<'r> {
+    /// Concatenates the `originals` in order, joined by [`SEPARATOR`], into
+    /// references of at most `max_len` bases. A reference longer than
+    /// `max_len` is placed on its own.
+    pub fn new(originals: &'r [FastaSeq], max_len: usize) -> Self {
+        let mut chunks = Vec::new();
+        let mut members = Vec::new();
+        let mut sequence: Vec<u8> = Vec::new();
+        let mut chunk_members = Vec::new();
+
+        for (index, original) in originals.iter().enumerate() {
+            if !chunk_members.is_empty() && sequence.len() + SEPARATOR.len() + original.sequence.len() > max_len {
+                chunks.push(FastaSeq {
+                    name:     format!("concat_{}", chunks.len() + 1),
+                    sequence: std::mem::take(&mut sequence),
+                });
+                members.push(std::mem::take(&mut chunk_members));
+            }
+
+            if !chunk_members.is_empty() {
+                sequence.extend_from_slice(SEPARATOR);
+            }
+            chunk_members.push(Member {
+                index,
+                start: sequence.len(),
+            });
+            sequence.extend_from_slice(&original.sequence);
+        }
+
+        if !chunk_members.is_empty() {
+            chunks.push(FastaSeq {
+                name: format!("concat_{}", chunks.len() + 1),
+                sequence,
+            });
+            members.push(chunk_members);
+        }
+
+        Self {
+            originals,
+            chunks,
+            members,
+        }
+    }
+
+    /// Converts an alignment against a concatenated reference into one against
+    /// the original reference containing it.
+    ///
+    /// If the alignment spans more than one original (by extending through a
+    /// separator), the query is instead realigned against each original in
+    /// the concatenated reference using `realign`, which accepts the range of
+    /// the original within the concatenated reference. The best of these is
+    /// kept, as in [`align_best_ref`]. Unmapped alignments are attributed to
+    /// the first original.
+    ///
+    /// ## Errors
+    ///
+    /// Any errors from `realign` are propagated without context.
+    ///
+    /// ## Panics
+    ///
+    /// The alignment must be against one of the concatenated references in
+    /// `chunks`.
+    pub fn resolve<'q, F>(
+        &self, alignment: AlignmentAndSeqs<'q, 'r>, normalizer: Option<&ScoreNormalizer>, realign: F,
+    ) -> std::io::Result<AlignmentAndSeqs<'q, 'r>>
+    where
+        F: Fn(Range<usize>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>>, {
+        // There are few concatenated references, so a linear scan is cheap
+        // relative to the alignment itself
+        let chunk = self
+            .chunks
+            .iter()
+            .position(|chunk| std::ptr::eq(chunk, alignment.reference))
+            .expect("The alignment should be against a concatenated reference");
+        let members = &self.members[chunk];
+
+        if let Ok(alignment) = self.remap(members, alignment) {
+            return Ok(alignment);
+        }
+
+        let windows = members.iter().map(|member| self.window(member));
+        let best_alignment = align_best_ref(windows, normalizer, realign)?;
+
+        // Validity: each realignment is within the range of a single original,
+        // so remapping it cannot fail
+        match self.remap(members, best_alignment) {
+            Ok(alignment) | Err(alignment) => Ok(alignment),
+        }
+    }
+
+    /// Maps the reference coordinates of `alignment` from a concatenated
+    /// reference (with the given `members`) to the original containing it. If
+    /// the alignment is unmapped, only the reference is replaced.
+    ///
+    /// ## Errors
+    ///
+    /// If the alignment is mapped but is not contained in a single original,
+    /// it is returned unchanged as an error.
+    fn remap<'q>(
+        &self, members: &[Member], mut alignment: AlignmentAndSeqs<'q, 'r>,
+    ) -> Result<AlignmentAndSeqs<'q, 'r>, AlignmentAndSeqs<'q, 'r>> {
+        // Validity: `--report-both-strands` conflicts with `--concat-refs`, so
+        // the other strand is never written and need not be remapped
+        alignment.other_strand = None;
+
+        let Some(mapping) = alignment.mapping.as_mut().filter(|mapping| mapping.inner.score > 0) else {
+            alignment.reference = &self.originals[members[0].index];
+            return Ok(alignment);
+        };
+
+        let ref_range = mapping.inner.ref_range.clone();
+        let member = &members[members
+            .partition_point(|member| member.start <= ref_range.start)
+            .saturating_sub(1)];
+        let window = self.window(member);
+        if ref_range.start < window.start || ref_range.end > window.end {
+            return Err(alignment);
+        }
+
+        mapping.inner.ref_range = ref_range.start - window.start..ref_range.end - window.start;
+        mapping.inner.ref_len = window.len();
+        alignment.reference = &self.originals[member.index];
+        Ok(alignment)
+    }
+
+    /// The range of an original reference within its concatenated reference.
+    fn window(&self, member: &Member) -> Range<usize> {
+        member.start..member.start + self.originals[member.index].sequence.len()
+    }
+}
@@ -0,0 +1,121 @@
+//! The `--footer` trailer appended to the tab-delimited outputs of `aligner`,
+//! so that downstream steps can cheaply detect a truncated file.
+
+use flate2::Crc;
+use std::io::Write;
+
+/// The tag beginning the footer line.
+const FOOTER_TAG: &str = "#footer";
+
+/// The number of records written and the checksum of their lines, for the
+/// footer.
+struct RecordTally {
+    records:       u64,
+    crc:           Crc,
+    /// Whether the next byte begins a line
+    at_line_start: bool,
+    /// Whether the current line is a comment, and so is not a record
+    in_comment:    bool,
+}
+
+impl RecordTally {
+    fn new() -> Self {
+        RecordTally {
+            records:       0,
+            crc:           Crc::new(),
+            at_line_start: true,
+            in_comment:    false,
+        }
+    }
+
+    /// Tallies the bytes written, which may begin or end partway through a
+    /// line.
+    fn update(&mut self, mut bytes: &[u8]) {
+        while let Some(&first) = bytes.first() {
+            if self.at_line_start {
+                self.in_comment = first == b'#';
+                self.at_line_start = false;
+            }
+
+            let (line, rest) = match bytes.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    if !self.in_comment {
+                        self.records += 1;
+                    }
+                    self.at_line_start = true;
+                    bytes.split_at(end + 1)
+                }
+                None => (bytes, &[][..]),
+            };
+
+            if !self.in_comment {
+                self.crc.update(line);
+            }
+            bytes = rest;
+        }
+    }
+}
+
+/// A writer for the records of a tab-delimited output which, with `--footer`,
+/// appends a final comment line of the form
+/// `#footer\trecords=<N>\tcrc32=<HEX>` once finished.
+///
+/// `records` is the number of lines written through the writer, other than
+/// comments (lines beginning with `#`), and `crc32` is the CRC32 of those lines
+/// including their newlines, in order. Any column header should be written
+/// before the writer is wrapped, so that it is not counted.
+pub struct FooterWriter<W: Write> {
+    inner: W,
+    /// The tally of the records written, if `--footer` was given
+    tally: Option<RecordTally>,
+}
+
+impl<W: Write> FooterWriter<W> {
+    /// Wraps `inner`, tallying the records written if `footer` is true.
+    pub fn new(inner: W, footer: bool) -> Self {
+        FooterWriter {
+            inner,
+            tally: footer.then(RecordTally::new),
+        }
+    }
+
+    /// Writes the footer line (if enabled) and flushes the underlying writer.
+    /// A final record lacking a newline is counted, and ended with one before
+    /// the footer.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors from writing or flushing are propagated.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if let Some(tally) = &mut self.tally {
+            if !tally.at_line_start {
+                if !tally.in_comment {
+                    tally.records += 1;
+                    tally.crc.update(b"\n");
+                }
+                writeln!(self.inner)?;
+            }
+            writeln!(
+                self.inner,
+                "{FOOTER_TAG}\trecords={records}\tcrc32={crc32:08x}",
+                records = tally.records,
+                crc32 = tally.crc.sum()
+            )?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for FooterWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(tally) = &mut self.tally {
+            tally.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,217 @@
+//! Resolution of reference panels described by a TOML or JSON manifest.
+
+use irma_records::io::InputOptions;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::File,
+    path::{Path, PathBuf},
+};
+use zoe::data::fasta::FastaSeq;
+
+/// A reference manifest, listing the named references making up a panel.
+///
+/// In TOML, each entry is given as a `[[reference]]` table. In JSON, the
+/// entries are given as an array under the `reference` key.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RefManifest {
+    #[serde(rename = "reference")]
+    references: Vec<ManifestEntry>,
+}
+
+/// A single entry in a reference manifest.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    /// The unique name of the entry, used for reporting
+    name:   String,
+    /// The path to the FASTA file, relative to the manifest's directory
+    path:   PathBuf,
+    /// An optional SHA-256 checksum of the file, as hexadecimal
+    sha256: Option<String>,
+    /// An optional group, such as the segment or lineage, for the references
+    /// in the file. This is only reported in the SAM header, and does not
+    /// affect alignment or `--best-match`
+    group:  Option<String>,
+    /// An optional URL from which the file is downloaded if it is missing
+    /// (requires the `ref-download` feature)
+    url:    Option<String>,
+}
+
+/// The references resolved from a manifest.
+pub struct ResolvedReferences {
+    /// The reference sequences from all entries, in manifest order
+    pub references: Vec<FastaSeq>,
+    /// The group of each reference in `references`, if one was provided
+    pub groups:     Vec<Option<String>>,
+}
+
+/// Reads the manifest at `manifest_path`, validates the checksum of every
+/// listed file, and then loads the references.
+///
+/// Files with a `.json` extension are parsed as JSON and all others as TOML.
+/// Relative paths within the manifest are resolved against the directory
+/// containing it. With the `ref-download` feature, missing files with a `url`
+/// are downloaded before validation.
+///
+/// ## Errors
+///
+/// Errors are returned if the manifest cannot be read or parsed, if it has no
+/// entries or duplicate names, if a file is missing, if a checksum does not
+/// match, or if a reference file cannot be read or is empty. Each contains the
+/// manifest path or entry name as context.
+pub fn resolve_manifest(manifest_path: &Path) -> std::io::Result<ResolvedReferences> {
+    let manifest = read_manifest(manifest_path)?;
+    let base_dir = manifest_path.parent().unwrap_or(Path::new(""));
+
+    if manifest.references.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("No references are listed in the manifest: {}", manifest_path.display()),
+        ));
+    }
+
+    let mut names = HashSet::new();
+    for entry in &manifest.references {
+        if !names.insert(entry.name.as_str()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "The reference name '{name}' is repeated in the manifest: {path}",
+                    name = entry.name,
+                    path = manifest_path.display()
+                ),
+            ));
+        }
+    }
+
+    // All files are validated before any are loaded, so that a bad panel fails
+    // quickly
+    let mut paths = Vec::with_capacity(manifest.references.len());
+    for entry in &manifest.references {
+        let path = base_dir.join(&entry.path);
+        fetch_if_missing(entry, &path)?;
+        if let Some(expected) = &entry.sha256 {
+            verify_sha256(entry, &path, expected)?;
+        }
+        paths.push(path);
+    }
+
+    let mut references = Vec::new();
+    let mut groups = Vec::new();
+    for (entry, path) in manifest.references.into_iter().zip(paths) {
+        let sequences = InputOptions::new_from_path(&path)
+            .use_file_or_zip()
+            .parse_fasta()
+            .open()?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if sequences.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "Empty reference file for '{name}': {path}",
+                name = entry.name,
+                path = path.display()
+            )));
+        }
+
+        groups.extend(std::iter::repeat_n(entry.group, sequences.len()));
+        references.extend(sequences);
+    }
+
+    Ok(ResolvedReferences { references, groups })
+}
+
+/// Reads and deserializes the manifest, choosing the format from the
+/// extension.
+fn read_manifest(manifest_path: &Path) -> std::io::Result<RefManifest> {
+    let contents = std::fs::read_to_string(manifest_path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Failed to read the reference manifest: {}\n{e}", manifest_path.display()),
+        )
+    })?;
+
+    let is_json = manifest_path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+    let parsed = if is_json {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    };
+
+    parsed.map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid reference manifest: {}\n{e}", manifest_path.display()),
+        )
+    })
+}
+
+/// Checks that the file for `entry` exists, downloading it from the entry's URL
+/// when the `ref-download` feature is enabled.
+fn fetch_if_missing(entry: &ManifestEntry, path: &Path) -> std::io::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+
+    match &entry.url {
+        #[cfg(feature = "ref-download")]
+        Some(url) => download(url, path),
+        #[cfg(not(feature = "ref-download"))]
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "The reference file for '{name}' is missing: {path}\nDownloading from its URL requires building with the `ref-download` feature",
+                name = entry.name,
+                path = path.display()
+            ),
+        )),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!(
+                "The reference file for '{name}' is missing: {path}",
+                name = entry.name,
+                path = path.display()
+            ),
+        )),
+    }
+}
+
+/// Downloads `url` to `path`, writing to a partial file first so that an
+/// interrupted download is not mistaken for a complete one.
+#[cfg(feature = "ref-download")]
+fn download(url: &str, path: &Path) -> std::io::Result<()> {
+    let partial = path.with_extension("part");
+
+    let response = ureq::get(url)
+        .call()
+        .map_err(|e| std::io::Error::other(format!("Failed to download the reference from {url}\n{e}")))?;
+
+    let mut file = File::create(&partial)?;
+    std::io::copy(&mut response.into_reader(), &mut file)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)
+}
+
+/// Computes the SHA-256 checksum of the file at `path` and compares it to
+/// `expected` (case-insensitively).
+fn verify_sha256(entry: &ManifestEntry, path: &Path, expected: &str) -> std::io::Result<()> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    let actual = hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Checksum mismatch for reference '{name}': {path}\nExpected SHA-256 {expected}, found {actual}",
+                name = entry.name,
+                path = path.display()
+            ),
+        ))
+    }
+}
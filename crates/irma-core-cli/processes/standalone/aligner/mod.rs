@@ -1,11 +1,39 @@
-use crate::aligner::{
-    arg_parsing::{AlignerConfig, Alphabet, AnyMatrix, NumPasses, ParsedAlignerArgs, parse_aligner_args},
-    tallies::{AlignmentTallies, AllTallies, QueryTallies, RefTallies, pick_alignment_method},
-    writers::{AlignmentWriter, write_header},
+use crate::{
+    aligner::{
+        arg_parsing::{AlignerConfig, Alphabet, AnyMatrix, NumPasses, ParsedAlignerArgs, SplitOptions, parse_aligner_args},
+        concat::ConcatRefs,
+        normalize::NormalizedQueries,
+        pileup::Pileup,
+        placement::ThreadPlacement,
+        prefilter::{Ranking, RefPrefilter},
+        ref_chunks::{RefChunks, align_best_match_chunked},
+        ref_params::{RefParams, RefScoring},
+        ref_usage::RefUsage,
+        samples::{Sample, write_summary},
+        scores::{ScoreNormalization, ScoreNormalizer},
+        self_test::run_self_test,
+        spans::{SpanKind, SpanRecorder, TimeSpan, TimedReader},
+        sweep::{SweepSpec, parse_sweep_spec, run_sweep},
+        tallies::{AlignmentTallies, AllTallies, QueryTallies, RefTallies, pick_alignment_method},
+        ungapped::sw_ungapped,
+        writers::{AlignerOutputs, Coords, SplitFiles, SplitWriter, UnmappedReason, write_header},
+    },
+    shared::trace::{RecordTrace, TraceArgs},
+};
+use clap::{
+    Args,
+    builder::{RangedI64ValueParser, RangedU64ValueParser},
+};
+use irma_records::io::{
+    FastX, FastXReader, InputOptions, IterWithContext, OutputOptions, ReadFileZipInThread, ValidatePaths,
+};
+use std::{
+    cmp::Ordering,
+    io::Write,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
 };
-use clap::{Args, builder::RangedI64ValueParser};
-use irma_records::io::{FastX, FastXReader, IterWithContext, OutputOptions, ReadFileZipInThread, ValidatePaths};
-use std::{cmp::Ordering, io::Write, path::PathBuf};
 use zoe::{
     alignment::{Alignment, LocalProfiles, MaybeAligned, SharedProfiles},
     data::{err::ResultWithErrorContext, fasta::FastaSeq, matrices::WeightMatrix},
@@ -15,44 +43,88 @@ use zoe::{
 #[cfg(not(feature = "dev_no_rayon"))]
 use crate::aligner::writers::{AlignmentWriterThreaded, ThreadedWriteError};
 #[cfg(not(feature = "dev_no_rayon"))]
-use rayon::iter::{ParallelBridge, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
 
+mod anchors;
 mod arg_parsing;
+mod concat;
+mod footer;
+mod manifest;
+mod normalize;
+mod pileup;
+mod placement;
+mod prefilter;
+mod ref_chunks;
+mod ref_params;
+mod ref_usage;
+mod samples;
+mod scores;
+mod self_test;
+mod spans;
+mod sweep;
 mod tallies;
+mod ungapped;
 mod writers;
 
-/// A type alias for the query reader used by `aligner`.
-type QueryReader = IterWithContext<FastXReader<ReadFileZipInThread>>;
+/// The query length from which a query is aligned against its candidate
+/// references in parallel (see [`Candidates::align_each`]).
+#[cfg(not(feature = "dev_no_rayon"))]
+const PARALLEL_CANDIDATES_MIN_LEN: usize = 10_000;
+
+/// A type alias for the query reader used by `aligner`, which normalizes the
+/// queries as they are read.
+type QueryReader = NormalizedQueries<IterWithContext<FastXReader<ReadFileZipInThread>>>;
 
-/// A type alias for the writer being used for the SAM file, which depends on
-/// whether `dev_no_rayon` is set.
+/// A type alias for the writers being used for the SAM file and any
+/// `--extract-aligned` output, which depends on whether `dev_no_rayon` is set.
 #[cfg(not(feature = "dev_no_rayon"))]
-type SamWriter = AlignmentWriterThreaded;
+type SamWriter = AlignerOutputs<AlignmentWriterThreaded>;
 
-/// A type alias for the writer being used for the SAM file, which depends on
-/// whether `dev_no_rayon` is set.
+/// A type alias for the writers being used for the SAM file and any
+/// `--extract-aligned` output, which depends on whether `dev_no_rayon` is set.
 #[cfg(feature = "dev_no_rayon")]
-type SamWriter = irma_records::io::WriteFileZipStdout;
+type SamWriter = AlignerOutputs<irma_records::io::WriteFileZipStdout>;
 
 /// The command line arguments for `aligner`
 #[derive(Args, Debug)]
 pub struct AlignerArgs {
-    /// Path to the FASTA file containing the reference sequence(s)
-    ref_file: PathBuf,
+    #[arg(required_unless_present = "self_test")]
+    /// Path to the FASTA file containing the reference sequence(s), or to a
+    /// reference manifest if `--ref-manifest` is specified
+    ref_file: Option<PathBuf>,
 
+    #[arg(required_unless_present_any = ["self_test", "sample_sheet"])]
     /// Path to the FASTQ or FASTA file containing the query sequence(s)
-    query_file: PathBuf,
+    query_file: Option<PathBuf>,
 
     #[arg(long, alias = "out")]
     /// Output filepath for alignments. Alignments print to STDOUT if not
     /// provided
     output: Option<PathBuf>,
 
+    #[arg(long)]
+    /// Writes SAM outputs ending in `.gz` as BGZF (blocked gzip), which htslib
+    /// tools can index. Outputs ending in `.bgz` are always written as BGZF
+    bgzf: bool,
+
+    #[arg(long)]
+    /// Treat the reference file as a TOML or JSON manifest listing named
+    /// reference files with optional checksums and groups. Groups are only
+    /// reported in the SAM header
+    ref_manifest: bool,
+
     #[arg(short = 'r', long)]
     /// Also align against the reverse complement, reporting the alignment with
     /// the better score
     rev_comp: bool,
 
+    #[arg(long, requires = "rev_comp", conflicts_with = "concat_refs")]
+    /// Also reports the alignment of the worse strand as a secondary alignment
+    /// (FLAG 256), for quantifying strand bias or detecting inverted
+    /// duplications. Each mapped alignment is annotated with whether it is the
+    /// better or worse strand in a `YB:Z` tag (`best` or `other`)
+    report_both_strands: bool,
+
     #[arg(short = 'm', long, value_parser = RangedI64ValueParser::<u8>::new().range(0..=127))]
     /// The score for a match, in [0, 127]
     matching: Option<u8>,
@@ -81,10 +153,40 @@ pub struct AlignerArgs {
     /// allowed when alphabet is DNA
     ignore_n: bool,
 
+    #[arg(long, conflicts_with = "matrix")]
+    /// Treats lowercase (soft-masked) bases in the references as `N`, so that
+    /// they contribute a score of 0 rather than attracting spurious alignments.
+    /// This implies `--ignore-n`, and is only allowed when alphabet is DNA
+    respect_softmask: bool,
+
+    #[arg(long)]
+    /// Uppercases the residues of the queries and references as they are
+    /// read, so that the output uses canonical residues. With
+    /// `--respect-softmask`, only the queries are uppercased
+    normalize_case: bool,
+
+    #[arg(long)]
+    /// Recodes `U` as `T` in the queries and references as they are read, for
+    /// aligning RNA sequences. This is only allowed when alphabet is DNA
+    rna_to_dna: bool,
+
     #[arg(long)]
     /// The alphabet to use. [defaults: DNA, if --matrix, then AA]
     alphabet: Option<Alphabet>,
 
+    #[arg(
+        long,
+        value_name = "TSV",
+        conflicts_with_all = ["profile_from_query", "anchor_bed", "concat_refs", "normalize_scores", "sweep"],
+    )]
+    /// Overrides the scoring for the references listed in this tab-delimited
+    /// file (reference name, match score, and mismatch, gap open, and gap
+    /// extend penalties), which are aligned with their own parameters. This
+    /// implies `--profile-from-ref`, and each alignment to a listed reference
+    /// is annotated with its parameters in a `YP:Z` tag. Only allowed when the
+    /// alphabet is DNA
+    ref_params: Option<PathBuf>,
+
     #[arg(long, conflicts_with = "profile_from_query")]
     /// Builds the profile from the reference sequences instead of the queries
     profile_from_ref: bool,
@@ -98,18 +200,65 @@ pub struct AlignerArgs {
     /// is used
     method: Option<NumPasses>,
 
+    #[arg(
+        long,
+        conflicts_with_all = ["method", "profile_from_ref", "profile_from_query", "anchor_bed", "concat_refs", "sweep"],
+    )]
+    /// Computes the best ungapped local alignment of each query instead of a
+    /// full Smith-Waterman alignment, which is faster. The gap penalties
+    /// are not used. Combined with `--min-score`, this gives a cheap screen,
+    /// such as for contaminants
+    ungapped: bool,
+
+    #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<u32>::new().range(1..))]
+    /// The minimum score for an alignment to be mapped. Alignments scoring
+    /// below this are reported as unmapped
+    min_score: u32,
+
     #[arg(long)]
     /// Excludes the unmapped alignments from the final alignment
     exclude_unmapped: bool,
 
+    #[arg(long, conflicts_with = "exclude_unmapped")]
+    /// Annotates each unmapped alignment with the reason it is unmapped, in a
+    /// `YU:Z` tag (`below_min_score` or `outside_amplicon`)
+    unmapped_reason: bool,
+
     #[arg(long)]
     /// Only output the best scoring alignment for each query
     best_match: bool,
 
     #[arg(long)]
-    /// Set the code to use only one thread for performing alignments
+    /// Normalizes the scores by alignment length or as bit scores, which are
+    /// reported alongside the raw scores in a `YN:f` tag and used to select the
+    /// best match. For bit scores, lambda is estimated from the scoring matrix
+    /// but the Karlin-Altschul K is fixed at 0.1, so bit scores are only
+    /// approximately comparable across scoring schemes
+    normalize_scores: Option<ScoreNormalization>,
+
+    #[arg(long, conflicts_with = "threads")]
+    /// Set the code to use only one thread for performing alignments. This is
+    /// equivalent to `--threads 1`
     single_thread: bool,
 
+    #[arg(short = 't', long)]
+    /// The number of threads to use for performing alignments. Defaults to
+    /// `RAYON_NUM_THREADS` if set, otherwise the number of logical cores
+    /// available
+    threads: Option<NonZeroUsize>,
+
+    #[arg(long)]
+    /// Pin each alignment thread to its own core, so that threads are not
+    /// migrated between cores (or sockets). Only supported on Linux
+    pin_threads: bool,
+
+    #[arg(long, value_name = "N")]
+    /// Run the alignment threads only on the cores of the given NUMA node, with
+    /// the references and profiles allocated in that node's memory. The thread
+    /// count defaults to the number of cores of the node. Only supported on
+    /// Linux
+    numa_node: Option<usize>,
+
     #[arg(long)]
     /// Include the SAM header line
     header: bool,
@@ -117,51 +266,287 @@ pub struct AlignerArgs {
     #[arg(long)]
     /// The file to print tally diagnostics to
     tally_diagnostics: Option<PathBuf>,
+
+    #[arg(long, value_name = "PATH")]
+    /// Also write the aligned query bases and quality scores at each covered
+    /// reference position to this file, in the format of `samtools mpileup`.
+    /// Only allowed when the alphabet is DNA
+    emit_mpileup_like: Option<PathBuf>,
+
+    #[arg(long, default_value_t = Coords::OneBased)]
+    /// The coordinate system (0-based or 1-based) of the reference positions
+    /// in `--emit-mpileup-like`. The SAM output is always 1-based
+    coords: Coords,
+
+    #[arg(long, value_name = "TSV", requires = "best_match", conflicts_with = "sweep")]
+    /// Writes how often each reference was selected as the best match and the
+    /// mean identity of those alignments to this tab-delimited file at the end
+    /// of the run, including references that were never selected. Only allowed
+    /// with `--best-match`
+    ref_usage: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Appends a final `#footer` comment line with the record count and a CRC32
+    /// of the records to the tab-delimited outputs (`--emit-mpileup-like`,
+    /// `--ref-usage`, and the `--sweep` and `--sample-sheet` summaries), so
+    /// that truncated files can be detected
+    footer: bool,
+
+    #[arg(long, value_name = "FASTQ")]
+    /// Also write the aligned portion of each mapped query to this FASTQ file,
+    /// trimmed to the alignment boundaries and reverse complemented to the
+    /// orientation of the reference. Requires FASTQ queries
+    extract_aligned: Option<PathBuf>,
+
+    #[arg(long, value_name = "DIR", requires = "best_match", conflicts_with_all = ["output", "sample_sheet", "sweep"])]
+    /// Writes the best alignment of each query to `<DIR>/<REF>.sam`, named
+    /// after its reference, binning the queries by reference in one pass.
+    /// Unmapped queries are written to `<DIR>/unmapped.sam`. The directory is
+    /// created if needed. Only allowed with `--best-match`
+    split_by_best_ref: Option<PathBuf>,
+
+    #[arg(long, requires = "split_by_best_ref")]
+    /// With `--split-by-best-ref`, also writes each whole query to
+    /// `<DIR>/<REF>.fastq` (or `<DIR>/unmapped.fastq`). Requires FASTQ queries
+    split_queries: bool,
+
+    #[arg(long, value_name = "N", default_value = "64", requires = "split_by_best_ref")]
+    /// The maximum number of files kept open by `--split-by-best-ref`. Files
+    /// are closed and reopened as needed beyond this
+    split_max_open: NonZeroUsize,
+
+    #[arg(long, value_name = "BED")]
+    /// Anchors amplicon queries to their expected regions, given as a BED file.
+    /// Each query is matched to an amplicon by an `amplicon=NAME` header tag or
+    /// by its leading primer, and is only aligned against that region of its
+    /// reference. Only allowed when the alphabet is DNA
+    anchor_bed: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 50, requires = "anchor_bed")]
+    /// The number of bases by which each amplicon is extended on both sides
+    /// when anchoring
+    anchor_slop: usize,
+
+    #[arg(long, value_name = "PATH")]
+    /// Records coarse timing spans (read, profile-build, align, and write) on
+    /// each thread, and writes them to this file as a Chrome trace (JSON), to
+    /// show whether a run is IO- or compute-bound
+    profile_spans: Option<PathBuf>,
+
+    #[arg(long, value_name = "LEN", requires = "best_match", conflicts_with_all = ["profile_from_ref", "anchor_bed"])]
+    /// Concatenates the references (separated by runs of `N`) into combined
+    /// references of at most LEN bases, which are aligned against in place of
+    /// the originals. Alignments are reported against the original references.
+    /// This speeds up screening against many short references, such as primer
+    /// or probe panels. Only allowed with `--best-match` and when the alphabet
+    /// is DNA
+    concat_refs: Option<NonZeroUsize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        requires_all = ["best_match", "profile_from_ref"],
+        conflicts_with_all = ["anchor_bed", "top_k_refs", "sweep"],
+    )]
+    /// Builds the reference profiles N references at a time, bounding the
+    /// memory used for large reference sets. All the queries are aligned
+    /// against each chunk before the next is profiled, keeping only the best
+    /// score of each query, and the best alignments are computed again in a
+    /// final pass. The queries are read once per chunk and once more, so they
+    /// must be in a regular file. Only allowed with `--best-match` and
+    /// `--profile-from-ref`
+    ref_chunk_size: Option<NonZeroUsize>,
+
+    #[arg(long, value_name = "N", conflicts_with = "concat_refs")]
+    /// Aligns each query against only the N references sharing the most
+    /// minimizers with it, bounding the work per query for large reference
+    /// panels. Queries for which the best reference may have been excluded are
+    /// counted and reported. Only allowed when the alphabet is DNA
+    top_k_refs: Option<NonZeroUsize>,
+
+    #[arg(long, value_name = "TSV", conflicts_with_all = ["query_file", "output", "tally_diagnostics", "emit_mpileup_like", "extract_aligned"])]
+    /// Aligns the queries of each sample listed in this tab-delimited file
+    /// (sample name, query path, and optional output path) against the
+    /// references, which are loaded and profiled once. Outputs default to
+    /// `<name>.sam`, and a summary of each sample is written to stdout
+    sample_sheet: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1",
+        requires = "sample_sheet",
+        conflicts_with = "query_file"
+    )]
+    /// Aligns up to N samples of `--sample-sheet` at a time, sharing the
+    /// threads and the profiled references. Samples are started in groups of
+    /// N, each writing to its own output
+    concurrent_samples: NonZeroUsize,
+
+    #[arg(long, requires = "sample_sheet", conflicts_with = "query_file")]
+    /// Adds an `@RG` line naming the sample to the SAM header of each sample of
+    /// `--sample-sheet`, and tags each of its records with `RG:Z:<name>`, so
+    /// that the outputs can be merged without losing track of the samples
+    read_groups: bool,
+
+    #[arg(
+        long,
+        value_name = "SPEC",
+        value_parser = parse_sweep_spec,
+        conflicts_with_all = [
+            "sample_sheet", "output", "tally_diagnostics", "emit_mpileup_like", "extract_aligned", "profile_spans",
+            "anchor_bed", "concat_refs", "top_k_refs", "normalize_scores", "trace_read",
+        ],
+    )]
+    /// Aligns the first `--sweep-queries` queries under each combination of the
+    /// scoring parameters in SPEC, such as `gap_open=5..15:5,gap_extend=1..3`,
+    /// and writes the mapped fraction and mean best score of each combination
+    /// to STDOUT instead of the alignments. The parameters are `gap_open`,
+    /// `gap_extend`, `matching`, and `mismatch`, each given a value or an
+    /// inclusive range `START..END` with an optional `:STEP`
+    sweep: Option<SweepSpec>,
+
+    #[arg(long, value_name = "N", default_value = "1000", requires = "sweep")]
+    /// The number of queries aligned under each combination by `--sweep`,
+    /// taken from the start of the query file
+    sweep_queries: NonZeroUsize,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+
+    #[arg(long, exclusive = true)]
+    /// Runs a battery of built-in alignment cases with known scores and CIGAR
+    /// strings, reporting whether each passed, and exits with an error if any
+    /// failed. This checks that the build aligns correctly on this machine
+    self_test: bool,
 }
 
 impl ValidatePaths for AlignerArgs {
     fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
-        [&self.ref_file, &self.query_file]
+        self.ref_file
+            .iter()
+            .chain(&self.query_file)
+            .chain(&self.anchor_bed)
+            .chain(&self.sample_sheet)
+            .chain(&self.ref_params)
     }
 
     fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
-        self.output.iter()
+        self.output
+            .iter()
+            .chain(&self.emit_mpileup_like)
+            .chain(&self.ref_usage)
+            .chain(&self.extract_aligned)
+            .chain(&self.profile_spans)
     }
 }
 
 /// Sub-program for performing sequence alignment
 pub fn aligner_process(args: AlignerArgs) -> std::io::Result<()> {
+    if args.self_test {
+        return run_self_test();
+    }
+
     args.validate_paths()?;
 
+    // The main thread is placed before the references are read, so that their
+    // memory is first touched (and allocated) on the selected NUMA node, and so
+    // that the default thread count reflects the cores available
+    let placement = ThreadPlacement::new(args.pin_threads, args.numa_node)?;
+    if let Some(placement) = &placement {
+        placement.pin_current_thread()?;
+    }
+
     let ParsedAlignerArgs {
-        query_reader,
+        samples,
+        sample_sheet,
         references,
+        reference_groups,
         weight_matrix,
+        ref_scoring,
         header,
         tally_diagnostics,
+        extract_aligned,
+        split,
+        sweep,
         config,
     } = parse_aligner_args(args)?;
 
+    // A local pool is used rather than the global pool, so that the thread
+    // count does not conflict with any other configuration of rayon
     #[cfg(not(feature = "dev_no_rayon"))]
-    if config.single_thread {
-        rayon::ThreadPoolBuilder::new().num_threads(1).build_global().unwrap();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads)
+        .thread_name(|i| format!("aligner-{i}"))
+        .start_handler(move |i| {
+            if let Some(placement) = &placement
+                && let Err(e) = placement.pin_worker(i)
+            {
+                eprintln!("Aligner Warning: {e}");
+            }
+        })
+        .build()
+        .map_err(|e| std::io::Error::other(format!("Failed to build the aligner thread pool: {e}")))?;
+
+    // Without a pool, the alignments run on the main thread, which is pinned
+    // as the first worker
+    #[cfg(feature = "dev_no_rayon")]
+    if let Some(placement) = &placement {
+        placement.pin_worker(0)?;
     }
 
-    let mut writer = OutputOptions::new_from_opt_path(config.output.as_ref())
-        .use_file_zip_or_stdout()
-        .open()?;
+    // Validity: `--sweep` conflicts with `--sample-sheet`, so there is a single
+    // sample
+    if let Some(sweep) = sweep {
+        #[cfg(not(feature = "dev_no_rayon"))]
+        return pool.install(|| run_sweep(&sweep, &samples[0].query_file, &references, &config));
 
-    if header {
-        write_header(&mut writer, &references)?;
+        #[cfg(feature = "dev_no_rayon")]
+        return run_sweep(&sweep, &samples[0].query_file, &references, &config);
     }
 
+    // Each sample is opened only once the previous one has been aligned.
+    // Validity: `--extract-aligned` and `--split-by-best-ref` conflict with
+    // `--sample-sheet`, so they apply to the single sample
+    let jobs = samples.iter().map(|sample| {
+        let (query_reader, writer) = open_sample(
+            sample,
+            header.then_some((references.as_slice(), reference_groups.as_slice())),
+            extract_aligned.as_ref(),
+            split.as_ref(),
+            &config,
+        )?;
+        Ok((sample.query_file.as_path(), query_reader, writer))
+    });
+
+    // Validity: No context is added to the result
     #[cfg(not(feature = "dev_no_rayon"))]
-    let writer = AlignmentWriterThreaded::from_writer(writer);
+    let all_tallies = pool.install(|| dispatch_alphabet(jobs, &references, weight_matrix, &ref_scoring, &config))?;
 
     // Validity: No context is added to the result
-    let tallies = dispatch_alphabet(query_reader, references, writer, weight_matrix, &config)?;
+    #[cfg(feature = "dev_no_rayon")]
+    let all_tallies = dispatch_alphabet(jobs, &references, weight_matrix, &ref_scoring, &config)?;
+
+    if sample_sheet {
+        write_summary(&samples, &all_tallies, config.top_k_refs.is_some(), config.footer)?;
+    }
+
+    let queries_near_cutoff = all_tallies.iter().map(|tallies| tallies.queries_near_cutoff).sum::<u64>();
+    let num_queries = all_tallies.iter().map(|tallies| tallies.num_queries).sum::<u64>();
 
-    if let Some(path) = tally_diagnostics {
+    if let Some(top_k) = config.top_k_refs
+        && queries_near_cutoff > 0
+    {
+        eprintln!(
+            "Aligner Warning: for {queries_near_cutoff} of {num_queries} queries, a reference excluded by `--top-k-refs {top_k}` shared nearly as many minimizers as those aligned against, so the best reference may have been missed. Consider increasing `--top-k-refs`."
+        );
+    }
+
+    // Validity: `--tally-diagnostics` conflicts with `--sample-sheet`, so there
+    // is a single sample
+    if let Some(path) = tally_diagnostics
+        && let Some(tallies) = all_tallies.into_iter().next()
+    {
         let mut tally_diagnostics = OutputOptions::new_from_path(&path).use_file().open()?;
 
         let AllTallies {
@@ -171,6 +556,7 @@ pub fn aligner_process(args: AlignerArgs) -> std::io::Result<()> {
             first_ref_len,
             num_alignments,
             est_scores_fitting_i8,
+            queries_near_cutoff,
         } = tallies;
 
         writeln!(tally_diagnostics, "Number of queries: {num_queries}")?;
@@ -182,6 +568,16 @@ pub fn aligner_process(args: AlignerArgs) -> std::io::Result<()> {
             tally_diagnostics,
             "Estimated number of alignments fitting i8: {est_scores_fitting_i8}"
         )?;
+        if config.top_k_refs.is_some() {
+            writeln!(
+                tally_diagnostics,
+                "Queries near the --top-k-refs cutoff: {queries_near_cutoff}"
+            )?;
+        }
+    }
+
+    if let Some(spans) = &config.spans {
+        spans.write_chrome_trace()?;
     }
 
     Ok(())
@@ -202,15 +598,18 @@ pub fn aligner_process(args: AlignerArgs) -> std::io::Result<()> {
 /// [`OrFail`].
 ///
 /// [`OrFail`]: zoe::data::err::OrFail
-fn dispatch_alphabet(
-    query_reader: QueryReader, references: Vec<FastaSeq>, writer: SamWriter, weight_matrix: AnyMatrix<'static, i8>,
+fn dispatch_alphabet<'s, J>(
+    jobs: J, references: &[FastaSeq], weight_matrix: AnyMatrix<'static, i8>, ref_scoring: &[Option<RefScoring<5>>],
     config: &AlignerConfig,
-) -> std::io::Result<AllTallies> {
-    // Validity: No context is added to the results
+) -> std::io::Result<Vec<AllTallies>>
+where
+    J: Iterator<Item = std::io::Result<(&'s Path, QueryReader, SamWriter)>>, {
+    // Validity: No context is added to the results. `--ref-params` is only
+    // allowed for DNA, so there are no overrides for the protein matrices
     match weight_matrix {
-        AnyMatrix::Dna(weight_matrix) => dispatch_method(query_reader, references, writer, &weight_matrix, config),
-        AnyMatrix::AaNamed(weight_matrix) => dispatch_method(query_reader, references, writer, weight_matrix, config),
-        AnyMatrix::AaSimple(weight_matrix) => dispatch_method(query_reader, references, writer, &weight_matrix, config),
+        AnyMatrix::Dna(weight_matrix) => dispatch_method(jobs, references, &weight_matrix, ref_scoring, config),
+        AnyMatrix::AaNamed(weight_matrix) => dispatch_method(jobs, references, weight_matrix, &[], config),
+        AnyMatrix::AaSimple(weight_matrix) => dispatch_method(jobs, references, &weight_matrix, &[], config),
     }
 }
 
@@ -231,28 +630,232 @@ fn dispatch_alphabet(
 /// [`OrFail`].
 ///
 /// [`OrFail`]: zoe::data::err::OrFail
-fn dispatch_method<const S: usize>(
-    query_reader: QueryReader, references: Vec<FastaSeq>, writer: SamWriter, weight_matrix: &WeightMatrix<'static, i8, S>,
+fn dispatch_method<'s, J, const S: usize>(
+    jobs: J, references: &[FastaSeq], weight_matrix: &WeightMatrix<'static, i8, S>, ref_scoring: &[Option<RefScoring<S>>],
     config: &AlignerConfig,
-) -> std::io::Result<AllTallies> {
-    let references = References::new(
-        &references,
-        weight_matrix,
-        config.gap_open,
-        config.gap_extend,
-        config.rev_comp,
-    )?;
-
-    if config.best_match {
-        align_best_match(query_reader, references, writer, weight_matrix, config)
+) -> std::io::Result<Vec<AllTallies>>
+where
+    J: Iterator<Item = std::io::Result<(&'s Path, QueryReader, SamWriter)>>, {
+    // With `--respect-softmask` or `--concat-refs`, the masked or concatenated
+    // references are aligned against, while the pileup is still over the
+    // originals
+    let masked = config.respect_softmask.then(|| mask_softmasked(references));
+    let to_align = masked.as_deref().unwrap_or(references);
+    let concat = config.concat_refs.map(|max_len| ConcatRefs::new(to_align, max_len.get()));
+    let to_profile = concat.as_ref().map_or(to_align, |concat| concat.chunks.as_slice());
+
+    // With `--ref-chunk-size`, the profiles are instead built a chunk at a time
+    // while aligning, unless the references fit in a single chunk
+    let chunks = config
+        .ref_chunk_size
+        .filter(|chunk_size| to_align.len() > chunk_size.get())
+        .map(|chunk_size| RefChunks::new(to_align, weight_matrix, ref_scoring, chunk_size.get()));
+
+    let profiled = if chunks.is_some() {
+        References(Vec::new(), None)
     } else {
-        align_all(query_reader, references, writer, weight_matrix, config)
+        config.spans.time(SpanKind::ProfileBuild, || {
+            References::new(
+                to_profile,
+                weight_matrix,
+                ref_scoring,
+                config.gap_open,
+                config.gap_extend,
+                config.rev_comp,
+                config.min_score,
+            )
+        })?
+    };
+    let profiled = profiled.with_prefilter(config.top_k_refs.map(|top_k| RefPrefilter::new(to_align, top_k.get())));
+
+    let pileup = config.pileup.as_ref().map(|path| Pileup::new(references, path)).transpose()?;
+    let ref_usage = config.ref_usage.as_ref().map(|_| RefUsage::new(to_align));
+
+    // The profiled references are shared by every sample
+    let align_sample = |(query_file, query_reader, writer): (&Path, QueryReader, SamWriter)| {
+        if let Some(chunks) = &chunks {
+            align_best_match_chunked(
+                query_reader,
+                query_file,
+                writer,
+                chunks,
+                pileup.as_ref(),
+                ref_usage.as_ref(),
+                config,
+            )
+        } else if config.best_match {
+            align_best_match(
+                query_reader,
+                &profiled,
+                concat.as_ref(),
+                writer,
+                weight_matrix,
+                pileup.as_ref(),
+                ref_usage.as_ref(),
+                config,
+            )
+        } else {
+            align_all(query_reader, &profiled, writer, weight_matrix, pileup.as_ref(), config)
+        }
+    };
+
+    #[cfg(not(feature = "dev_no_rayon"))]
+    let all_tallies = align_samples(jobs, config.concurrent_samples, align_sample)?;
+
+    #[cfg(feature = "dev_no_rayon")]
+    let all_tallies = jobs
+        .map(|job| job.and_then(align_sample))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    if let (Some(pileup), Some(path)) = (pileup, &config.pileup) {
+        pileup.write_to(path, config.coords, config.footer)?;
     }
+
+    if let (Some(ref_usage), Some(path)) = (ref_usage, &config.ref_usage) {
+        ref_usage.write_to(path, config.footer)?;
+    }
+
+    Ok(all_tallies)
+}
+
+/// Aligns the samples of `jobs` with `align_sample`, returning their tallies in
+/// order. The samples are opened and aligned in groups of `concurrent`, which
+/// share the threads of the pool, and each group is finished before the next is
+/// opened.
+///
+/// ## Errors
+///
+/// Any error opening or aligning a sample is propagated.
+#[cfg(not(feature = "dev_no_rayon"))]
+fn align_samples<T, J, F>(jobs: J, concurrent: NonZeroUsize, align_sample: F) -> std::io::Result<Vec<AllTallies>>
+where
+    T: Send,
+    J: Iterator<Item = std::io::Result<T>>,
+    F: Fn(T) -> std::io::Result<AllTallies> + Sync + Send, {
+    let mut jobs = jobs.peekable();
+    let mut all_tallies = Vec::new();
+    while jobs.peek().is_some() {
+        let group = jobs.by_ref().take(concurrent.get()).collect::<std::io::Result<Vec<_>>>()?;
+        let tallies = group
+            .into_par_iter()
+            .map(&align_sample)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        all_tallies.extend(tallies);
+    }
+    Ok(all_tallies)
+}
+
+/// Opens the queries and output of a sample, writing the SAM header if the
+/// references and their groups are provided, along with any outputs for
+/// `--extract-aligned` and `--split-by-best-ref`. With `--split-by-best-ref`,
+/// the SAM header is instead written to each per-reference SAM file. With
+/// `--read-groups`, the headers name the sample as a read group, which tags
+/// each of its records.
+///
+/// ## Errors
+///
+/// IO errors when opening the query file or outputs, or when writing the
+/// header, are propagated with the path as context.
+fn open_sample(
+    sample: &Sample, header: Option<(&[FastaSeq], &[Option<String>])>, extract_aligned: Option<&PathBuf>,
+    split: Option<&SplitOptions>, config: &AlignerConfig,
+) -> std::io::Result<(QueryReader, SamWriter)> {
+    let query_reader = open_queries(&sample.query_file, config, true)?;
+
+    let mut writer = OutputOptions::new_from_opt_path(sample.output.as_ref())
+        .with_gzip_format(config.gzip_format)
+        .use_file_zip_or_stdout()
+        .open()?;
+
+    let split = match split {
+        Some(split) => {
+            let header = match header {
+                Some((references, reference_groups)) => {
+                    let mut header = Vec::new();
+                    write_header(&mut header, references, reference_groups)?;
+                    if config.read_groups {
+                        writeln!(header, "@RG\tID:{name}\tSM:{name}", name = sample.name)?;
+                    }
+                    Some(String::from_utf8_lossy(&header).into_owned())
+                }
+                None => None,
+            };
+            let files = SplitFiles::new(split.dir.clone(), header, split.max_open.get());
+            Some(SplitWriter::new(files, split.queries))
+        }
+        None => {
+            if let Some((references, reference_groups)) = header {
+                write_header(&mut writer, references, reference_groups)?;
+                if config.read_groups {
+                    writeln!(writer, "@RG\tID:{name}\tSM:{name}", name = sample.name)?;
+                }
+            }
+            None
+        }
+    };
+
+    let extracted = match extract_aligned {
+        Some(path) => Some(
+            OutputOptions::new_from_opt_path(Some(path))
+                .with_gzip_format(config.gzip_format)
+                .use_file_zip_or_stdout()
+                .open()?,
+        ),
+        None => None,
+    };
+
+    #[cfg(not(feature = "dev_no_rayon"))]
+    let (writer, extracted) = (
+        AlignmentWriterThreaded::from_writer(writer),
+        extracted.map(AlignmentWriterThreaded::from_writer),
+    );
+
+    let read_group = config.read_groups.then_some(sample.name.as_str());
+    Ok((
+        query_reader,
+        AlignerOutputs::new(writer, extracted, split).with_read_group(read_group),
+    ))
+}
+
+/// Opens the queries at `path`, which are decoded in a separate thread and
+/// normalized as they are read (see [`NormalizedQueries`]). A warning is
+/// printed after the last query if `warn` is true and many residues are outside
+/// the alphabet.
+///
+/// ## Errors
+///
+/// IO errors when opening the file are propagated with the path as context.
+fn open_queries(path: &Path, config: &AlignerConfig, warn: bool) -> std::io::Result<QueryReader> {
+    let queries = InputOptions::new_from_path(path)
+        .use_file_or_zip()
+        .decode_in_thread()
+        .parse_fastx()
+        .open()?;
+    Ok(NormalizedQueries::new(queries, config.normalization, path, warn))
+}
+
+/// Replaces the lowercase (soft-masked) bases in each reference with `N`, for
+/// `--respect-softmask`.
+fn mask_softmasked(references: &[FastaSeq]) -> Vec<FastaSeq> {
+    references
+        .iter()
+        .map(|reference| FastaSeq {
+            name:     reference.name.clone(),
+            sequence: reference
+                .sequence
+                .iter()
+                .map(|&base| if base.is_ascii_lowercase() { b'N' } else { base })
+                .collect(),
+        })
+        .collect()
 }
 
 /// Aligns all the queries in `query_reader` to the `references`, writing the
 /// outputs to `writer`. The method used is specified by the first argument.
 ///
+/// If the `references` have a prefilter, each query is only aligned against its
+/// top-ranked references (see `--top-k-refs`).
+///
 /// ## Errors
 ///
 /// Errors while reading the queries, building the profiles, performing the
@@ -267,54 +870,159 @@ fn dispatch_method<const S: usize>(
 ///
 /// [`OrFail`]: zoe::data::err::OrFail
 fn align_all<'r, const S: usize>(
-    query_reader: QueryReader, references: References<'r, S>, writer: SamWriter,
-    weight_matrix: &WeightMatrix<'static, i8, S>, config: &AlignerConfig,
+    query_reader: QueryReader, references: &References<'r, S>, writer: SamWriter,
+    weight_matrix: &WeightMatrix<'static, i8, S>, pileup: Option<&Pileup<'r>>, config: &AlignerConfig,
 ) -> std::io::Result<AllTallies> {
     let query_tallies = QueryTallies::default();
-    let ref_tallies = RefTallies::new(&references);
+    let ref_tallies = RefTallies::new(references);
     let alignment_tallies = AlignmentTallies::default();
 
-    align_queries(query_reader, writer, |writer, query| {
+    align_queries(query_reader, writer, &config.spans, |writer, query| {
         let query = query?;
         query_tallies.tally(&query.sequence);
 
         let method = pick_alignment_method(&query_tallies, &ref_tallies, &alignment_tallies, config);
+        let trace = config.tracer.start(&query.header);
+        trace.step("query", || {
+            format!("length {len}, aligned with {method:?}", len = query.sequence.len())
+        });
+
+        if let Some(amplicon) = config.anchors.as_ref().and_then(|anchors| anchors.find(&query)) {
+            trace.step("anchor", || {
+                format!(
+                    "anchored to reference {name} at positions {start}..{end}",
+                    name = references.0[amplicon.reference].forward.name,
+                    start = amplicon.window.start,
+                    end = amplicon.window.end
+                )
+            });
+            let query = config.spans.time(SpanKind::ProfileBuild, || {
+                QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+            })?;
+
+            let reference = &references.0[amplicon.reference];
+            let alignment = config.spans.time(SpanKind::Align, || {
+                query.sw_anchored_query_profile(reference, amplicon.window.clone(), method.is_three_pass())
+            })?;
+            alignment_tallies.tally(&alignment, weight_matrix);
+            trace_alignment(&trace, &alignment, config);
+            if let Some(pileup) = pileup {
+                pileup.add(&alignment)?;
+            }
+            config
+                .spans
+                .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
+
+            return Ok(());
+        }
+
+        let ranking = rank_references(references, &query, &query_tallies);
+        let query_len = query.sequence.len();
+        trace_ranking(&trace, references, ranking.as_ref());
 
         match method {
             AlignmentMethod::OnePassQueryProfile => {
-                let query = QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend)?;
+                let query = config.spans.time(SpanKind::ProfileBuild, || {
+                    QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+                })?;
 
-                for reference in &references {
-                    let alignment = query.sw_1pass_query_profile(reference)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || query.sw_1pass_query_profile(reference))
+                        })?;
+                for alignment in alignments {
                     alignment_tallies.tally(&alignment, weight_matrix);
-                    writer.write_alignment(alignment, config)?;
+                    trace_alignment(&trace, &alignment, config);
+                    if let Some(pileup) = pileup {
+                        pileup.add(&alignment)?;
+                    }
+                    config
+                        .spans
+                        .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
                 }
             }
             AlignmentMethod::OnePassRefProfile => {
                 let query = QueryWithRc::new(&query, config.rev_comp);
 
-                for reference in &references.0 {
-                    let alignment = reference.sw_1pass_ref_profile(&query)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || reference.sw_1pass_ref_profile(query))
+                        })?;
+                for alignment in alignments {
                     alignment_tallies.tally(&alignment, weight_matrix);
-                    writer.write_alignment(alignment, config)?;
+                    trace_alignment(&trace, &alignment, config);
+                    if let Some(pileup) = pileup {
+                        pileup.add(&alignment)?;
+                    }
+                    config
+                        .spans
+                        .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
                 }
             }
             AlignmentMethod::ThreePassQueryProfile => {
-                let query = QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend)?;
+                let query = config.spans.time(SpanKind::ProfileBuild, || {
+                    QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+                })?;
 
-                for reference in references.0.iter() {
-                    let alignment = query.sw_3pass_query_profile(reference)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || query.sw_3pass_query_profile(reference))
+                        })?;
+                for alignment in alignments {
                     alignment_tallies.tally(&alignment, weight_matrix);
-                    writer.write_alignment(alignment, config)?;
+                    trace_alignment(&trace, &alignment, config);
+                    if let Some(pileup) = pileup {
+                        pileup.add(&alignment)?;
+                    }
+                    config
+                        .spans
+                        .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
                 }
             }
             AlignmentMethod::ThreePassRefProfile => {
                 let query = QueryWithRc::new(&query, config.rev_comp);
 
-                for reference in references.0.iter() {
-                    let alignment = reference.sw_3pass_ref_profile(&query)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || reference.sw_3pass_ref_profile(query))
+                        })?;
+                for alignment in alignments {
+                    alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
+                    if let Some(pileup) = pileup {
+                        pileup.add(&alignment)?;
+                    }
+                    config
+                        .spans
+                        .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
+                }
+            }
+            AlignmentMethod::Ungapped => {
+                let query = QueryWithRc::new(&query, config.rev_comp);
+
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || reference.sw_ungapped(query))
+                        })?;
+                for alignment in alignments {
                     alignment_tallies.tally(&alignment, weight_matrix);
-                    writer.write_alignment(alignment, config)?;
+                    trace_alignment(&trace, &alignment, config);
+                    if let Some(pileup) = pileup {
+                        pileup.add(&alignment)?;
+                    }
+                    config
+                        .spans
+                        .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
                 }
             }
         }
@@ -329,6 +1037,12 @@ fn align_all<'r, const S: usize>(
 /// best reference for each and writing that alignment to `writer`. The method
 /// used is specified by the first argument.
 ///
+/// If `concat` is provided, the `references` are the concatenated references
+/// from `--concat-refs`, and each alignment is mapped back to its original
+/// reference before the best is picked. Otherwise, if the `references` have a
+/// prefilter, each query is only aligned against its top-ranked references
+/// (see `--top-k-refs`).
+///
 /// ## Errors
 ///
 /// Errors while reading the queries, building the profiles, performing the
@@ -342,19 +1056,104 @@ fn align_all<'r, const S: usize>(
 /// [`OrFail`].
 ///
 /// [`OrFail`]: zoe::data::err::OrFail
+#[allow(clippy::too_many_arguments)]
 fn align_best_match<'r, const S: usize>(
-    query_reader: QueryReader, references: References<'r, S>, writer: SamWriter,
-    weight_matrix: &WeightMatrix<'static, i8, S>, config: &AlignerConfig,
+    query_reader: QueryReader, references: &References<'r, S>, concat: Option<&ConcatRefs<'r>>, writer: SamWriter,
+    weight_matrix: &WeightMatrix<'static, i8, S>, pileup: Option<&Pileup<'r>>, ref_usage: Option<&RefUsage<'r>>,
+    config: &AlignerConfig,
 ) -> std::io::Result<AllTallies> {
     let query_tallies = QueryTallies::default();
-    let ref_tallies = RefTallies::new(&references);
+    let ref_tallies = RefTallies::new(references);
     let alignment_tallies = AlignmentTallies::default();
 
-    align_queries(query_reader, writer, |writer, query| {
+    align_queries(query_reader, writer, &config.spans, |writer, query| {
         let query = query?;
         query_tallies.tally(&query.sequence);
 
         let method = pick_alignment_method(&query_tallies, &ref_tallies, &alignment_tallies, config);
+        let trace = config.tracer.start(&query.header);
+        trace.step("query", || {
+            format!("length {len}, aligned with {method:?}", len = query.sequence.len())
+        });
+
+        if let Some(amplicon) = config.anchors.as_ref().and_then(|anchors| anchors.find(&query)) {
+            trace.step("anchor", || {
+                format!(
+                    "anchored to reference {name} at positions {start}..{end}",
+                    name = references.0[amplicon.reference].forward.name,
+                    start = amplicon.window.start,
+                    end = amplicon.window.end
+                )
+            });
+            let query = config.spans.time(SpanKind::ProfileBuild, || {
+                QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+            })?;
+
+            let reference = &references.0[amplicon.reference];
+            let alignment = config.spans.time(SpanKind::Align, || {
+                query.sw_anchored_query_profile(reference, amplicon.window.clone(), method.is_three_pass())
+            })?;
+            alignment_tallies.tally(&alignment, weight_matrix);
+            trace_alignment(&trace, &alignment, config);
+            if let Some(pileup) = pileup {
+                pileup.add(&alignment)?;
+            }
+            if let Some(ref_usage) = ref_usage {
+                ref_usage.add(&alignment);
+            }
+            config
+                .spans
+                .time(SpanKind::Write, || writer.write_alignment(alignment, config))?;
+
+            return Ok(());
+        }
+
+        // Validity: `--concat-refs` always builds the profile from the query
+        if let Some(concat) = concat {
+            let query = config.spans.time(SpanKind::ProfileBuild, || {
+                QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+            })?;
+            let three_pass = method.is_three_pass();
+
+            let best_alignment = align_best_ref(references, config.normalizer.as_ref(), |reference| {
+                let alignment = config.spans.time(SpanKind::Align, || {
+                    if three_pass {
+                        query.sw_3pass_query_profile(reference)
+                    } else {
+                        query.sw_1pass_query_profile(reference)
+                    }
+                })?;
+                alignment_tallies.tally(&alignment, weight_matrix);
+                trace_alignment(&trace, &alignment, config);
+
+                concat.resolve(alignment, config.normalizer.as_ref(), |window| {
+                    let alignment = config.spans.time(SpanKind::Align, || {
+                        query.sw_anchored_query_profile(reference, window, three_pass)
+                    })?;
+                    alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
+                    Ok(alignment)
+                })
+            })?;
+
+            trace_best_match(&trace, &best_alignment);
+
+            if let Some(pileup) = pileup {
+                pileup.add(&best_alignment)?;
+            }
+            if let Some(ref_usage) = ref_usage {
+                ref_usage.add(&best_alignment);
+            }
+            config
+                .spans
+                .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
+
+            return Ok(());
+        }
+
+        let ranking = rank_references(references, &query, &query_tallies);
+        let query_len = query.sequence.len();
+        trace_ranking(&trace, references, ranking.as_ref());
 
         // Each match statement ends with a write, which appears redundant.
         // However, this is needed since the lifetime of the query is limited to
@@ -363,48 +1162,143 @@ fn align_best_match<'r, const S: usize>(
 
         match method {
             AlignmentMethod::OnePassQueryProfile => {
-                let query = QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend)?;
+                let query = config.spans.time(SpanKind::ProfileBuild, || {
+                    QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+                })?;
 
-                let best_alignment = align_best_ref(&references, |reference| {
-                    let alignment = query.sw_1pass_query_profile(reference)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || query.sw_1pass_query_profile(reference))
+                        })?;
+                let best_alignment = align_best_ref(alignments, config.normalizer.as_ref(), |alignment| {
                     alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
                     Ok(alignment)
                 })?;
 
-                writer.write_alignment(best_alignment, config)?;
+                trace_best_match(&trace, &best_alignment);
+
+                if let Some(pileup) = pileup {
+                    pileup.add(&best_alignment)?;
+                }
+                if let Some(ref_usage) = ref_usage {
+                    ref_usage.add(&best_alignment);
+                }
+                config
+                    .spans
+                    .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
             }
             AlignmentMethod::OnePassRefProfile => {
                 let query = QueryWithRc::new(&query, config.rev_comp);
 
-                let best_alignment = align_best_ref(&references, |reference| {
-                    let alignment = reference.sw_1pass_ref_profile(&query)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || reference.sw_1pass_ref_profile(query))
+                        })?;
+                let best_alignment = align_best_ref(alignments, config.normalizer.as_ref(), |alignment| {
                     alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
                     Ok(alignment)
                 })?;
 
-                writer.write_alignment(best_alignment, config)?;
+                trace_best_match(&trace, &best_alignment);
+
+                if let Some(pileup) = pileup {
+                    pileup.add(&best_alignment)?;
+                }
+                if let Some(ref_usage) = ref_usage {
+                    ref_usage.add(&best_alignment);
+                }
+                config
+                    .spans
+                    .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
             }
             AlignmentMethod::ThreePassQueryProfile => {
-                let query = QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend)?;
+                let query = config.spans.time(SpanKind::ProfileBuild, || {
+                    QueryWithProfile::new(&query, weight_matrix, config.gap_open, config.gap_extend, config.min_score)
+                })?;
 
-                let best_alignment = align_best_ref(&references, |reference| {
-                    let alignment = query.sw_3pass_query_profile(reference)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || query.sw_3pass_query_profile(reference))
+                        })?;
+                let best_alignment = align_best_ref(alignments, config.normalizer.as_ref(), |alignment| {
                     alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
                     Ok(alignment)
                 })?;
 
-                writer.write_alignment(best_alignment, config)?;
+                trace_best_match(&trace, &best_alignment);
+
+                if let Some(pileup) = pileup {
+                    pileup.add(&best_alignment)?;
+                }
+                if let Some(ref_usage) = ref_usage {
+                    ref_usage.add(&best_alignment);
+                }
+                config
+                    .spans
+                    .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
             }
             AlignmentMethod::ThreePassRefProfile => {
                 let query = QueryWithRc::new(&query, config.rev_comp);
 
-                let best_alignment = align_best_ref(&references, |reference| {
-                    let alignment = reference.sw_3pass_ref_profile(&query)?;
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || reference.sw_3pass_ref_profile(query))
+                        })?;
+                let best_alignment = align_best_ref(alignments, config.normalizer.as_ref(), |alignment| {
                     alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
                     Ok(alignment)
                 })?;
 
-                writer.write_alignment(best_alignment, config)?;
+                trace_best_match(&trace, &best_alignment);
+
+                if let Some(pileup) = pileup {
+                    pileup.add(&best_alignment)?;
+                }
+                if let Some(ref_usage) = ref_usage {
+                    ref_usage.add(&best_alignment);
+                }
+                config
+                    .spans
+                    .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
+            }
+            AlignmentMethod::Ungapped => {
+                let query = QueryWithRc::new(&query, config.rev_comp);
+
+                let alignments =
+                    references
+                        .candidates(ranking.as_ref())
+                        .align_each(&query, query_len, |query, reference| {
+                            config.spans.time(SpanKind::Align, || reference.sw_ungapped(query))
+                        })?;
+                let best_alignment = align_best_ref(alignments, config.normalizer.as_ref(), |alignment| {
+                    alignment_tallies.tally(&alignment, weight_matrix);
+                    trace_alignment(&trace, &alignment, config);
+                    Ok(alignment)
+                })?;
+
+                trace_best_match(&trace, &best_alignment);
+
+                if let Some(pileup) = pileup {
+                    pileup.add(&best_alignment)?;
+                }
+                if let Some(ref_usage) = ref_usage {
+                    ref_usage.add(&best_alignment);
+                }
+                config
+                    .spans
+                    .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
             }
         }
 
@@ -414,9 +1308,97 @@ fn align_best_match<'r, const S: usize>(
     Ok(AllTallies::new(&query_tallies, &ref_tallies, &alignment_tallies, config))
 }
 
+/// Ranks the `references` for `query` with their prefilter, if one was
+/// provided by `--top-k-refs`. Queries for which the best reference may have
+/// been excluded are counted in `query_tallies`.
+fn rank_references<const S: usize>(
+    references: &References<'_, S>, query: &FastX, query_tallies: &QueryTallies,
+) -> Option<Ranking> {
+    let ranking = references.1.as_ref()?.rank(&query.sequence);
+    if ranking.near_cutoff {
+        query_tallies.tally_near_cutoff();
+    }
+    Some(ranking)
+}
+
+/// Traces the references a query selected with `--trace-read` is aligned
+/// against, if they were narrowed by `--top-k-refs`.
+fn trace_ranking<const S: usize>(trace: &RecordTrace, references: &References<'_, S>, ranking: Option<&Ranking>) {
+    if let Some(ranking) = ranking {
+        trace.step("top-k refs", || {
+            let names = ranking
+                .indices
+                .iter()
+                .map(|&i| references.0[i].forward.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let near_cutoff = if ranking.near_cutoff {
+                " (an excluded reference was near the cutoff)"
+            } else {
+                ""
+            };
+            format!("aligning against {names}{near_cutoff}")
+        });
+    }
+}
+
+/// Traces an alignment of a query selected with `--trace-read`, with its score
+/// and CIGAR string if mapped, or the reason it is unmapped.
+fn trace_alignment(trace: &RecordTrace, alignment: &AlignmentAndSeqs, config: &AlignerConfig) {
+    trace.step("align", || {
+        let name = &alignment.reference.name;
+        match &alignment.mapping {
+            Some(mapping) if mapping.inner.score > 0 => {
+                let mut detail = format!(
+                    "reference {name}: score {score} on the {strand:?} strand, CIGAR {cigar} at positions {start}..{end}",
+                    score = mapping.inner.score,
+                    strand = mapping.strand,
+                    cigar = mapping.inner.states.to_cigar_unchecked(),
+                    start = mapping.inner.ref_range.start,
+                    end = mapping.inner.ref_range.end
+                );
+                if let Some(normalizer) = &config.normalizer {
+                    detail.push_str(&format!(
+                        ", normalized score {normalized:.4}",
+                        normalized = normalizer.normalize(&mapping.inner)
+                    ));
+                }
+                if let Some(other) = &alignment.other_strand {
+                    detail.push_str(&format!(
+                        " (the {strand:?} strand scored {score})",
+                        strand = other.strand,
+                        score = other.inner.score
+                    ));
+                }
+                detail
+            }
+            _ => {
+                let excluded = if config.exclude_unmapped {
+                    ", excluded by `--exclude-unmapped`"
+                } else {
+                    ""
+                };
+                format!(
+                    "reference {name}: unmapped ({reason}){excluded}",
+                    reason = UnmappedReason::new(alignment, config)
+                )
+            }
+        }
+    });
+}
+
+/// Traces the alignment selected by `--best-match` for a query selected with
+/// `--trace-read`.
+fn trace_best_match(trace: &RecordTrace, best_alignment: &AlignmentAndSeqs) {
+    trace.step("best match", || {
+        let score = best_alignment.mapping.as_ref().map_or(0, |mapping| mapping.inner.score);
+        format!("reference {name} with score {score}", name = best_alignment.reference.name)
+    });
+}
+
 /// Performs all alignments as indicated by closure `f`, using either a parallel
 /// iterator (`par_bridge`) or a serial iterator depending on the `dev_no_rayon`
-/// feature.
+/// feature. Reading the queries and the final flush are recorded in `spans`.
 ///
 /// This implementation is for the serial case.
 ///
@@ -426,17 +1408,20 @@ fn align_best_match<'r, const S: usize>(
 /// propagated.
 #[inline]
 #[cfg(feature = "dev_no_rayon")]
-fn align_queries<F>(query_reader: QueryReader, mut writer: SamWriter, f: F) -> std::io::Result<()>
+fn align_queries<I, T, F>(
+    query_reader: I, mut writer: SamWriter, spans: &Option<SpanRecorder>, f: F,
+) -> std::io::Result<()>
 where
-    F: Fn(&mut SamWriter, std::io::Result<FastX>) -> std::io::Result<()> + Sync + Send, {
-    let mut query_reader = query_reader;
+    I: Iterator<Item = std::io::Result<T>>,
+    F: Fn(&mut SamWriter, std::io::Result<T>) -> std::io::Result<()> + Sync + Send, {
+    let mut query_reader = TimedReader::new(query_reader, spans);
     query_reader.try_for_each(|query| f(&mut writer, query))?;
-    writer.flush()
+    spans.time(SpanKind::Write, || writer.flush())
 }
 
 /// Performs all alignments as indicated by closure `f`, using either a parallel
 /// iterator (`par_bridge`) or a serial iterator depending on the `dev_no_rayon`
-/// feature.
+/// feature. Reading the queries and the final flush are recorded in `spans`.
 ///
 /// This implementation is for the parallel case.
 ///
@@ -449,15 +1434,19 @@ where
 /// thrown.
 #[inline]
 #[cfg(not(feature = "dev_no_rayon"))]
-fn align_queries<F>(query_reader: QueryReader, writer: AlignmentWriterThreaded, f: F) -> std::io::Result<()>
+fn align_queries<I, T, F>(query_reader: I, writer: SamWriter, spans: &Option<SpanRecorder>, f: F) -> std::io::Result<()>
 where
-    F: Fn(&mut AlignmentWriterThreaded, std::io::Result<FastX>) -> Result<(), ThreadedWriteError> + Sync + Send, {
-    let res = query_reader
+    I: Iterator<Item = std::io::Result<T>> + Send,
+    T: Send,
+    F: Fn(&mut SamWriter, std::io::Result<T>) -> Result<(), ThreadedWriteError> + Sync + Send, {
+    let res = TimedReader::new(query_reader, spans)
         .par_bridge()
         .try_for_each_with(writer.clone(), |w, record| f(w, record));
 
     match res {
-        Ok(()) => writer.flush(),
+        // The writer thread may still be writing, so this shows how long the
+        // output lags behind the alignments
+        Ok(()) => spans.time(SpanKind::Write, || writer.flush()),
         Err(ThreadedWriteError::IoError(e)) => Err(e),
         Err(ThreadedWriteError::ReceiverDeallocated) => Err(writer.flush().err().unwrap_or(std::io::Error::other(
             "The receiver in the writing thread unexpectedly closed",
@@ -487,23 +1476,26 @@ impl MaybeRevComp {
 }
 
 /// A query record together with a set of profiles for alignment.
+#[derive(Clone)]
 pub struct QueryWithProfile<'q, const S: usize> {
     /// The record for the query.
-    forward: &'q FastX,
+    forward:   &'q FastX,
     /// The profile set for the query sequence.
-    profile: LocalProfiles<'q, 32, 16, 8, S>,
+    profile:   LocalProfiles<'q, 32, 16, 8, S>,
+    /// The minimum score for an alignment to be mapped.
+    min_score: u32,
 }
 
 impl<'q, const S: usize> QueryWithProfile<'q, S> {
     /// Bundles a query record together with a corresponding profile set for use
-    /// in alignment.
+    /// in alignment. Alignments scoring below `min_score` are unmapped.
     ///
     /// ## Errors
     ///
     /// An error containing the query header as context is returned if a profile
     /// fails to be made from the sequence.
     pub fn new(
-        query: &'q FastX, matrix: &'q WeightMatrix<'q, i8, S>, gap_open: i8, gap_extend: i8,
+        query: &'q FastX, matrix: &'q WeightMatrix<'q, i8, S>, gap_open: i8, gap_extend: i8, min_score: u32,
     ) -> std::io::Result<Self> {
         let forward = query;
 
@@ -511,7 +1503,11 @@ impl<'q, const S: usize> QueryWithProfile<'q, S> {
         let header = &forward.header;
         let profile = LocalProfiles::make_profile(forward_seq, header, matrix, gap_open, gap_extend)?;
 
-        Ok(Self { forward, profile })
+        Ok(Self {
+            forward,
+            profile,
+            min_score,
+        })
     }
 
     /// Aligns the query profile against the provided reference using the 1-pass
@@ -527,8 +1523,8 @@ impl<'q, const S: usize> QueryWithProfile<'q, S> {
     /// If the alignment fails (due to overflow), context with the query and
     /// reference header is added. If it was the reverse complement alignment
     /// that failed, context is also added mentioning this.
-    pub fn sw_1pass_query_profile<'r>(&'q self, reference: &Reference<'r, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
-        let mapping = align_maybe_rc(SeqSrc::Reference(&reference.forward.sequence), &reference.reverse, |seq| {
+    pub fn sw_1pass_query_profile<'r>(&self, reference: &Reference<'r, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
+        let (mapping, other_strand) = align_maybe_rc(SeqSrc::Reference(&reference.forward.sequence), &reference.reverse, self.min_score, |seq| {
             self.profile.sw_1pass(seq)
         })
         .with_context(format!(
@@ -538,8 +1534,10 @@ impl<'q, const S: usize> QueryWithProfile<'q, S> {
 
         Ok(AlignmentAndSeqs {
             mapping,
+            other_strand,
             query: self.forward,
             reference: reference.forward,
+            ref_params: None,
         })
     }
 
@@ -556,8 +1554,8 @@ impl<'q, const S: usize> QueryWithProfile<'q, S> {
     /// If the alignment fails (due to overflow), context with the query and
     /// reference header is added. If it was the reverse complement alignment
     /// that failed, context is also added mentioning this.
-    pub fn sw_3pass_query_profile<'r>(&'q self, reference: &Reference<'r, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
-        let mapping = align_maybe_rc(SeqSrc::Reference(&reference.forward.sequence), &reference.reverse, |seq| {
+    pub fn sw_3pass_query_profile<'r>(&self, reference: &Reference<'r, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
+        let (mapping, other_strand) = align_maybe_rc(SeqSrc::Reference(&reference.forward.sequence), &reference.reverse, self.min_score, |seq| {
             self.profile.sw_3pass(seq)
         }).with_context(format!(
             "Failed to align the sequences with the following headers:\n    | Query: {q_header}\n    | Reference: {r_header}",
@@ -566,14 +1564,72 @@ impl<'q, const S: usize> QueryWithProfile<'q, S> {
 
         Ok(AlignmentAndSeqs {
             mapping,
+            other_strand,
+            query: self.forward,
+            reference: reference.forward,
+            ref_params: None,
+        })
+    }
+
+    /// Aligns the query profile against only the `window` of the provided
+    /// reference, for queries anchored with `--anchor-bed`. The 3-pass
+    /// algorithm is used if `three_pass` is true, and the 1-pass algorithm
+    /// otherwise.
+    ///
+    /// The reverse complement alignment (if `--rev-comp` is used) is against
+    /// the reverse complement of the window. The coordinates of the returned
+    /// alignment are relative to the full reference.
+    ///
+    /// ## Errors
+    ///
+    /// If the alignment fails (due to overflow), context with the query and
+    /// reference header is added. If it was the reverse complement alignment
+    /// that failed, context is also added mentioning this.
+    pub fn sw_anchored_query_profile<'r>(
+        &'q self, reference: &Reference<'r, S>, window: Range<usize>, three_pass: bool,
+    ) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
+        let ref_len = reference.forward.sequence.len();
+        let forward = &reference.forward.sequence[window.clone()];
+        let reverse = MaybeRevComp(
+            reference
+                .reverse
+                .0
+                .as_ref()
+                .map(|rc| rc[ref_len - window.end..ref_len - window.start].to_vec()),
+        );
+
+        let (mut mapping, mut other_strand) = align_maybe_rc(SeqSrc::Reference(forward), &reverse, self.min_score, |seq| {
+            if three_pass {
+                self.profile.sw_3pass(seq)
+            } else {
+                self.profile.sw_1pass(seq)
+            }
+        }).with_context(format!(
+            "Failed to align the sequences with the following headers:\n    | Query: {q_header}\n    | Reference: {r_header}",
+            q_header=self.forward.header, r_header=reference.forward.name
+        ))?;
+
+        // Validity: the reverse complement alignment was already converted to
+        // forward coordinates within the window by `align_maybe_rc`
+        for mapping in mapping.iter_mut().chain(other_strand.as_deref_mut()) {
+            let alignment = &mut mapping.inner;
+            alignment.ref_range = alignment.ref_range.start + window.start..alignment.ref_range.end + window.start;
+            alignment.ref_len = ref_len;
+        }
+
+        Ok(AlignmentAndSeqs {
+            mapping,
+            other_strand,
             query: self.forward,
             reference: reference.forward,
+            ref_params: None,
         })
     }
 }
 
 /// The query record together with its reverse complement (if `--rev-comp` was
 /// passed).
+#[derive(Clone)]
 pub struct QueryWithRc<'q, const S: usize> {
     /// The record for the query.
     forward: &'q FastX,
@@ -604,16 +1660,25 @@ impl<'q, const S: usize> QueryWithRc<'q, S> {
 #[derive(Clone, Debug)]
 pub struct Reference<'r, const S: usize> {
     /// The record for the reference.
-    forward: &'r FastaSeq,
+    forward:   &'r FastaSeq,
     /// The reverse complement of the reference, if `--rev-comp` was passed.
-    reverse: MaybeRevComp,
+    reverse:   MaybeRevComp,
     /// The profile set for the forward reference sequence.
-    profile: SharedProfiles<'r, 32, 16, 8, S>,
+    profile:   SharedProfiles<'r, 32, 16, 8, S>,
+    /// The weight matrix used for the profile, which is also used by
+    /// `--ungapped`.
+    matrix:    &'r WeightMatrix<'r, i8, S>,
+    /// The scoring parameters used for the profile, if overridden by
+    /// `--ref-params`.
+    params:    Option<RefParams>,
+    /// The minimum score for an alignment to be mapped.
+    min_score: u32,
 }
 
 impl<'r, const S: usize> Reference<'r, S> {
     /// Bundles a reference together with its reverse complement (if `rev_comp`
-    /// is true) and a profile set for use in alignment.
+    /// is true) and a profile set for use in alignment. Alignments scoring
+    /// below `min_score` are unmapped.
     ///
     /// ## Errors
     ///
@@ -621,6 +1686,7 @@ impl<'r, const S: usize> Reference<'r, S> {
     /// profile fails to be made from the sequence.
     pub fn new(
         reference: &'r FastaSeq, matrix: &'r WeightMatrix<'r, i8, S>, gap_open: i8, gap_extend: i8, rev_comp: bool,
+        min_score: u32,
     ) -> std::io::Result<Self> {
         let forward = reference;
 
@@ -633,6 +1699,9 @@ impl<'r, const S: usize> Reference<'r, S> {
             forward,
             reverse,
             profile,
+            matrix,
+            params: None,
+            min_score,
         })
     }
 
@@ -655,7 +1724,7 @@ impl<'r, const S: usize> Reference<'r, S> {
     /// reference header is added. If it was the reverse complement alignment
     /// that failed, context is also added mentioning this.
     pub fn sw_1pass_ref_profile<'q>(&self, query: &QueryWithRc<'q, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
-        let mapping = align_maybe_rc(SeqSrc::Query(&query.forward.sequence), &query.reverse, |seq| {
+        let (mapping, other_strand) = align_maybe_rc(SeqSrc::Query(&query.forward.sequence), &query.reverse, self.min_score, |seq| {
             self.profile.sw_1pass(seq)
         }).with_context(format!(
             "Failed to align the sequences with the following headers:\n    | Query: {q_header}\n    | Reference: {r_header}",
@@ -664,8 +1733,10 @@ impl<'r, const S: usize> Reference<'r, S> {
 
         Ok(AlignmentAndSeqs {
             mapping,
+            other_strand,
             query: query.forward,
             reference: self.forward,
+            ref_params: self.params,
         })
     }
 
@@ -683,7 +1754,7 @@ impl<'r, const S: usize> Reference<'r, S> {
     /// reference header is added. If it was the reverse complement alignment
     /// that failed, context is also added mentioning this.
     pub fn sw_3pass_ref_profile<'q>(&self, query: &QueryWithRc<'q, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
-        let mapping = align_maybe_rc(SeqSrc::Query(&query.forward.sequence), &query.reverse, |seq| {
+        let (mapping, other_strand) = align_maybe_rc(SeqSrc::Query(&query.forward.sequence), &query.reverse, self.min_score, |seq| {
             self.profile.sw_3pass(seq)
         }).with_context(format!(
             "Failed to align the sequences with the following headers:\n    | Query: {q_header}\n    | Reference: {r_header}",
@@ -692,37 +1763,164 @@ impl<'r, const S: usize> Reference<'r, S> {
 
         Ok(AlignmentAndSeqs {
             mapping,
+            other_strand,
+            query: query.forward,
+            reference: self.forward,
+            ref_params: self.params,
+        })
+    }
+    /// Computes the best ungapped local alignment of the provided query against
+    /// the reference, for `--ungapped`, using the reference's weight matrix.
+    /// The reverse complement of the query is also aligned if `--rev-comp` is
+    /// used.
+    ///
+    /// ## Errors
+    ///
+    /// Ungapped alignments cannot overflow, so this always succeeds. A result
+    /// is returned for consistency with the other alignment methods.
+    pub fn sw_ungapped<'q>(&self, query: &QueryWithRc<'q, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
+        let (mapping, other_strand) = align_maybe_rc(
+            SeqSrc::Query(&query.forward.sequence),
+            &query.reverse,
+            self.min_score,
+            |seq| {
+                let (SeqSrc::Query(seq) | SeqSrc::Reference(seq)) = seq;
+                Ok(sw_ungapped(&self.forward.sequence, seq, self.matrix))
+            },
+        )?;
+
+        Ok(AlignmentAndSeqs {
+            mapping,
+            other_strand,
             query: query.forward,
             reference: self.forward,
+            ref_params: self.params,
         })
     }
 }
 
-/// A collection of references to align against.
-pub struct References<'r, const S: usize>(Vec<Reference<'r, S>>);
+/// A collection of references to align against, along with the minimizer
+/// index used to rank them if `--top-k-refs` was provided.
+pub struct References<'r, const S: usize>(Vec<Reference<'r, S>>, Option<RefPrefilter>);
 
 impl<'r, const S: usize> References<'r, S> {
     /// Bundles the `references` with reverse complement and profile information
-    /// for alignment.
+    /// for alignment. The profile of each reference with an override in
+    /// `overrides` (which is in the same order, or empty) is built with its own
+    /// scoring instead of `matrix`, `gap_open`, and `gap_extend`. Alignments
+    /// scoring below `min_score` are unmapped.
     ///
     /// ## Errors
     ///
     /// If any of the profiles fail to build, an error is returned with context
     /// including the header.
     pub fn new(
-        references: &'r [FastaSeq], matrix: &'r WeightMatrix<'r, i8, S>, gap_open: i8, gap_extend: i8, rev_comp: bool,
+        references: &'r [FastaSeq], matrix: &'r WeightMatrix<'r, i8, S>, overrides: &'r [Option<RefScoring<S>>],
+        gap_open: i8, gap_extend: i8, rev_comp: bool, min_score: u32,
     ) -> std::io::Result<Self> {
         references
             .iter()
-            .map(|reference| Reference::new(reference, matrix, gap_open, gap_extend, rev_comp))
+            .enumerate()
+            .map(|(i, reference)| match overrides.get(i).and_then(Option::as_ref) {
+                Some(scoring) => {
+                    let mut reference = Reference::new(
+                        reference,
+                        &scoring.matrix,
+                        scoring.gap_open,
+                        scoring.gap_extend,
+                        rev_comp,
+                        min_score,
+                    )?;
+                    reference.params = Some(scoring.params);
+                    Ok(reference)
+                }
+                None => Reference::new(reference, matrix, gap_open, gap_extend, rev_comp, min_score),
+            })
             .collect::<Result<_, _>>()
-            .map(References)
+            .map(|references| References(references, None))
+    }
+
+    /// Sets the minimizer index used to rank the references for each query.
+    pub fn with_prefilter(mut self, prefilter: Option<RefPrefilter>) -> Self {
+        self.1 = prefilter;
+        self
     }
 
     /// Returns an iterator over the references.
     pub fn iter(&self) -> std::slice::Iter<'_, Reference<'r, S>> {
         self.0.iter()
     }
+
+    /// Returns an iterator over the references a query is aligned against,
+    /// which are those in the `ranking` if `--top-k-refs` was provided, and
+    /// otherwise all of them.
+    pub fn candidates<'c>(&'c self, ranking: Option<&'c Ranking>) -> Candidates<'c, 'r, S> {
+        match ranking {
+            Some(ranking) => Candidates::Ranked {
+                references: &self.0,
+                indices:    ranking.indices.iter(),
+            },
+            None => Candidates::All(self.0.iter()),
+        }
+    }
+}
+
+/// An iterator over the references a query is aligned against, returned by
+/// [`References::candidates`].
+pub enum Candidates<'c, 'r, const S: usize> {
+    /// All the references
+    All(std::slice::Iter<'c, Reference<'r, S>>),
+    /// The references at the given indices
+    Ranked {
+        references: &'c [Reference<'r, S>],
+        indices:    std::slice::Iter<'c, usize>,
+    },
+}
+
+impl<'c, 'r, const S: usize> Iterator for Candidates<'c, 'r, S> {
+    type Item = &'c Reference<'r, S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Candidates::All(references) => references.next(),
+            Candidates::Ranked { references, indices } => indices.next().map(|&i| &references[i]),
+        }
+    }
+}
+
+impl<'c, 'r, const S: usize> Candidates<'c, 'r, S> {
+    /// Aligns `query` (of length `query_len`) against each candidate with
+    /// `align`, returning the alignments in the order of the candidates.
+    ///
+    /// Queries of at least [`PARALLEL_CANDIDATES_MIN_LEN`] bases are aligned
+    /// against the candidates in parallel, so that a few long queries (such as
+    /// assembled contigs) still use all the threads. Each thread aligns with
+    /// its own clone of `query`, since a query profile is built lazily and
+    /// cannot be shared. Shorter queries are aligned serially, since they are
+    /// already spread across the threads.
+    ///
+    /// ## Errors
+    ///
+    /// The first error from `align` is propagated.
+    fn align_each<Q, T, F>(self, query: &Q, query_len: usize, align: F) -> std::io::Result<Vec<T>>
+    where
+        Q: Clone + Send,
+        T: Send,
+        F: Fn(&Q, &'c Reference<'r, S>) -> std::io::Result<T> + Sync, {
+        #[cfg(not(feature = "dev_no_rayon"))]
+        if query_len >= PARALLEL_CANDIDATES_MIN_LEN {
+            return self
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .map_with(query.clone(), |query, reference| align(query, reference))
+                .collect();
+        }
+        #[cfg(feature = "dev_no_rayon")]
+        let _ = query_len;
+
+        self.map(|reference| align(query, reference)).collect()
+    }
 }
 
 impl<'r, 'c, const S: usize> IntoIterator for &'c References<'r, S> {
@@ -744,6 +1942,18 @@ enum AlignmentMethod {
     OnePassRefProfile,
     ThreePassQueryProfile,
     ThreePassRefProfile,
+    Ungapped,
+}
+
+impl AlignmentMethod {
+    /// Returns whether the method uses the 3-pass algorithm.
+    #[inline]
+    fn is_three_pass(self) -> bool {
+        matches!(
+            self,
+            AlignmentMethod::ThreePassQueryProfile | AlignmentMethod::ThreePassRefProfile
+        )
+    }
 }
 
 /// A trait extending [`ProfileSets`] with methods that add context to errors.
@@ -766,7 +1976,6 @@ pub trait AlignerMethods<'a, const S: usize>: ProfileSets<'a, 32, 16, 8, S> {
     ///
     /// If the alignment overflows, then this is returned as an error.
     fn sw_1pass(&self, seq: SeqSrc<&[u8]>) -> std::io::Result<Option<Alignment<u32>>> {
-        let seq = seq.map(AsRef::as_ref);
         let alignment = self.sw_align_from_i8(seq);
         maybe_aligned_to_option(alignment)
     }
@@ -778,7 +1987,6 @@ pub trait AlignerMethods<'a, const S: usize>: ProfileSets<'a, 32, 16, 8, S> {
     ///
     /// If the alignment overflows, then this is returned as an error.
     fn sw_3pass(&self, seq: SeqSrc<&[u8]>) -> std::io::Result<Option<Alignment<u32>>> {
-        let seq = seq.map(AsRef::as_ref);
         let alignment = self.sw_align_from_i8_3pass(seq);
         maybe_aligned_to_option(alignment)
     }
@@ -827,49 +2035,63 @@ impl<'a, const S: usize> AlignerMethods<'a, S> for SharedProfiles<'a, 32, 16, 8,
 /// sequence to align as an argument (either the forward or reverse complement
 /// of `seq`).
 ///
-/// In the case of a tie, the forward strand is preferred.
+/// The alignment of the better strand is returned first, followed by that of
+/// the other strand (if the reverse complement was aligned and is mapped), for
+/// `--report-both-strands`. In the case of a tie, the forward strand is
+/// preferred. Alignments scoring below `min_score` are unmapped.
 ///
 /// ## Errors
 ///
 /// Any errors from the forward alignment are propagated without additional
 /// context. For the reverse alignment, errors are added with context specifying
 /// that the reverse complement alignment failed.
-pub fn align_maybe_rc<T, F>(seq: SeqSrc<&T>, seq_rc: &MaybeRevComp, f: F) -> std::io::Result<Option<AlignmentAndStrand>>
+pub fn align_maybe_rc<T, F>(
+    seq: SeqSrc<&T>, seq_rc: &MaybeRevComp, min_score: u32, f: F,
+) -> std::io::Result<(Option<AlignmentAndStrand>, Option<Box<AlignmentAndStrand>>)>
 where
     T: AsRef<[u8]> + ?Sized,
     F: Fn(SeqSrc<&[u8]>) -> std::io::Result<Option<Alignment<u32>>>, {
-    let alignment_forward = f(seq.map(AsRef::as_ref))?;
-
-    let (alignment, strand) = if let Some(seq_rc) = &seq_rc.0 {
-        let seq_rc = seq.map(|_| seq_rc.as_slice());
-        let mut alignment_rc = f(seq_rc).with_context("Failed to perform the reverse complement alignment")?;
-
-        if alignment_rc > alignment_forward {
-            if seq.is_reference()
-                && let Some(alignment) = alignment_rc.as_mut()
-            {
-                alignment.make_reverse();
-            }
-
-            (alignment_rc, Strand::Reverse)
-        } else {
-            (alignment_forward, Strand::Forward)
-        }
-    } else {
-        (alignment_forward, Strand::Forward)
+    let alignment_forward = f(seq.map(AsRef::as_ref))?
+        .filter(|alignment| alignment.score >= min_score)
+        .map(|alignment| AlignmentAndStrand {
+            inner:  alignment,
+            strand: Strand::Forward,
+        });
+
+    let Some(seq_rc) = &seq_rc.0 else {
+        return Ok((alignment_forward, None));
     };
 
-    Ok(alignment.map(|alignment| AlignmentAndStrand {
-        inner: alignment,
-        strand,
-    }))
+    let seq_rc = seq.map(|_| seq_rc.as_slice());
+    let mut alignment_rc = f(seq_rc).with_context("Failed to perform the reverse complement alignment")?;
+    if seq.is_reference()
+        && let Some(alignment) = alignment_rc.as_mut()
+    {
+        alignment.make_reverse();
+    }
+    let alignment_rc = alignment_rc
+        .filter(|alignment| alignment.score >= min_score)
+        .map(|alignment| AlignmentAndStrand {
+            inner:  alignment,
+            strand: Strand::Reverse,
+        });
+
+    if alignment_rc.as_ref().map(|a| &a.inner) > alignment_forward.as_ref().map(|a| &a.inner) {
+        Ok((alignment_rc, alignment_forward.map(Box::new)))
+    } else {
+        Ok((alignment_forward, alignment_rc.map(Box::new)))
+    }
 }
 
-/// Performs all the alignments against the provided `reference`, returning the
-/// one with the best score.
+/// Performs all the alignments against the provided `references`, returning
+/// the one with the best score.
 ///
 /// The alignment to perform is given by `f`, which is a closure accepting the
-/// reference to align against as an argument.
+/// reference to align against as an argument. This is usually a [`Reference`],
+/// but may be any item describing what to align against.
+///
+/// If a `normalizer` is provided, the alignments are compared using
+/// [`ScoreNormalizer::selection_key`] rather than the raw score.
 ///
 /// In the case of a tie, the last reference is preferred.
 ///
@@ -880,21 +2102,34 @@ where
 /// ## Panics
 ///
 /// The `references` provided must be non-empty.
-pub fn align_best_ref<'q, 'r, F, const S: usize>(
-    references: &References<'r, S>, f: F,
+pub fn align_best_ref<'q, 'r, I, F>(
+    references: I, normalizer: Option<&ScoreNormalizer>, f: F,
 ) -> std::io::Result<AlignmentAndSeqs<'q, 'r>>
 where
-    F: Fn(&Reference<'r, S>) -> std::io::Result<AlignmentAndSeqs<'q, 'r>>, {
-    let mut references = references.iter();
+    I: IntoIterator,
+    F: Fn(I::Item) -> std::io::Result<AlignmentAndSeqs<'q, 'r>>, {
+    let mut references = references.into_iter();
 
     let first_reference = references.next().expect("The references field should be non-empty");
     let mut best_alignment = f(first_reference)?;
 
-    for reference in references {
-        let alignment = f(reference)?;
-        match alignment.partial_cmp(&best_alignment) {
-            Some(Ordering::Greater) | None => best_alignment = alignment,
-            _ => {}
+    if let Some(normalizer) = normalizer {
+        let mut best_key = normalizer.selection_key(&best_alignment);
+        for reference in references {
+            let alignment = f(reference)?;
+            let key = normalizer.selection_key(&alignment);
+            if key >= best_key {
+                best_alignment = alignment;
+                best_key = key;
+            }
+        }
+    } else {
+        for reference in references {
+            let alignment = f(reference)?;
+            match alignment.partial_cmp(&best_alignment) {
+                Some(Ordering::Greater) | None => best_alignment = alignment,
+                _ => {}
+            }
         }
     }
 
@@ -922,11 +2157,17 @@ pub struct AlignmentAndStrand {
 pub struct AlignmentAndSeqs<'q, 'r> {
     /// The [`Alignment`] and [`Strand`], if the alignment is mapped. If
     /// unmapped, then this is `None`.
-    pub mapping:   Option<AlignmentAndStrand>,
+    pub mapping:      Option<AlignmentAndStrand>,
+    /// The alignment of the other strand, if the reverse complement was also
+    /// aligned. This is only written with `--report-both-strands`.
+    pub other_strand: Option<Box<AlignmentAndStrand>>,
     /// A reference to the query record.
-    pub query:     &'q FastX,
+    pub query:        &'q FastX,
     /// A reference to the reference record.
-    pub reference: &'r FastaSeq,
+    pub reference:    &'r FastaSeq,
+    /// The scoring parameters of the reference, if overridden by
+    /// `--ref-params`.
+    pub ref_params:   Option<RefParams>,
 }
 
 impl PartialOrd for AlignmentAndStrand {
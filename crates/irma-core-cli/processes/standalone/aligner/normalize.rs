@@ -0,0 +1,189 @@
+//! Normalization of the residues of the queries and references as they are
+//! read, for `--normalize-case` and `--rna-to-dna`, and the warning for inputs
+//! with many residues outside the alphabet.
+
+use crate::aligner::arg_parsing::Alphabet;
+use irma_records::io::FastX;
+use std::path::{Path, PathBuf};
+use zoe::{
+    data::{SanitizeBase, fasta::FastaSeq},
+    prelude::IsValidDNA,
+};
+
+/// The residues of the amino acid alphabet, including the catch-all `X`.
+const AA_RESIDUES: &[u8] = b"ACDEFGHIKLMNPQRSTVWY*BJZX";
+
+/// A warning is printed if more than this fraction of the residues of an input
+/// are outside the alphabet.
+const OUTSIDE_ALPHABET_WARN_FRACTION: f64 = 0.01;
+
+/// How residues are normalized as they are read.
+#[derive(Clone, Copy, Debug)]
+pub struct Normalization {
+    /// Whether residues are uppercased, for `--normalize-case`
+    pub uppercase:  bool,
+    /// Whether `U` is recoded as `T`, for `--rna-to-dna`
+    pub rna_to_dna: bool,
+    /// The alphabet of the weight matrix, against which residues are checked
+    pub alphabet:   Alphabet,
+}
+
+impl Normalization {
+    /// Normalizes the residues of `sequence` in place. Lowercase is kept for
+    /// `u` unless `uppercase` is also set.
+    pub fn apply(&self, sequence: &mut [u8]) {
+        if !self.uppercase && !self.rna_to_dna {
+            return;
+        }
+        for residue in sequence {
+            if self.uppercase {
+                residue.make_ascii_uppercase();
+            }
+            if self.rna_to_dna {
+                match residue {
+                    b'U' => *residue = b'T',
+                    b'u' => *residue = b't',
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Normalizes the references, and warns if many residues are outside the
+    /// alphabet. With `respect_softmask`, the references are not uppercased
+    /// and their lowercase bases are not counted as outside the alphabet, since
+    /// they are masked.
+    pub fn apply_to_references(&self, references: &mut [FastaSeq], respect_softmask: bool) {
+        let normalization = Normalization {
+            uppercase: self.uppercase && !respect_softmask,
+            ..*self
+        };
+        let mut tally = ResidueTally::default();
+        for reference in references {
+            normalization.apply(&mut reference.sequence);
+            tally.add(&reference.sequence, self.alphabet, respect_softmask);
+        }
+        tally.warn("the references", self);
+    }
+}
+
+/// The number of residues read, and of those outside the alphabet.
+#[derive(Default, Debug)]
+struct ResidueTally {
+    residues:  usize,
+    outside:   usize,
+    /// The number of lowercase residues, which `--normalize-case` would
+    /// uppercase
+    lowercase: usize,
+    /// The number of `U` residues (in either case), which `--rna-to-dna` would
+    /// recode
+    uracil:    usize,
+}
+
+impl ResidueTally {
+    /// Tallies the residues of a sequence, treating lowercase residues as
+    /// uppercase if `ignore_case` is true.
+    fn add(&mut self, sequence: &[u8], alphabet: Alphabet, ignore_case: bool) {
+        self.residues += sequence.len();
+        for &residue in sequence {
+            let residue = if ignore_case { residue.to_ascii_uppercase() } else { residue };
+            let inside = match alphabet {
+                Alphabet::Dna => residue.is_valid(IsValidDNA::AcgtnNoGapsUc),
+                Alphabet::Aa => AA_RESIDUES.contains(&residue),
+            };
+            if !inside {
+                self.outside += 1;
+                self.lowercase += usize::from(residue.is_ascii_lowercase());
+                self.uracil += usize::from(residue.eq_ignore_ascii_case(&b'U'));
+            }
+        }
+    }
+
+    /// Prints a warning if more than [`OUTSIDE_ALPHABET_WARN_FRACTION`] of the
+    /// residues are outside the alphabet, suggesting the options that would
+    /// normalize them.
+    fn warn(&self, input: &str, normalization: &Normalization) {
+        if self.residues == 0 {
+            return;
+        }
+        let fraction = self.outside as f64 / self.residues as f64;
+        if fraction <= OUTSIDE_ALPHABET_WARN_FRACTION {
+            return;
+        }
+
+        let allowed = match normalization.alphabet {
+            Alphabet::Dna => "ACGTN",
+            Alphabet::Aa => "ACDEFGHIKLMNPQRSTVWY*BJZX",
+        };
+        let mut hints = Vec::new();
+        if self.lowercase > 0 && !normalization.uppercase {
+            hints.push("`--normalize-case`");
+        }
+        if self.uracil > 0 && !normalization.rna_to_dna && normalization.alphabet == Alphabet::Dna {
+            hints.push("`--rna-to-dna`");
+        }
+        let hint = if hints.is_empty() {
+            String::new()
+        } else {
+            format!(" Consider {}.", hints.join(" or "))
+        };
+
+        eprintln!(
+            "Aligner Warning: {percent:.2}% of the residues in {input} are outside the {alphabet} alphabet ({allowed}), which may be scored or reported unexpectedly.{hint}",
+            percent = fraction * 100.0,
+            alphabet = normalization.alphabet,
+        );
+    }
+}
+
+/// An iterator adapter normalizing the queries as they are read, warning once
+/// all of them have been read if many residues are outside the alphabet.
+pub struct NormalizedQueries<I> {
+    inner:         I,
+    normalization: Normalization,
+    tally:         ResidueTally,
+    /// The query file, named in the warning, or `None` if the queries are not
+    /// tallied (such as when they are read again for another reference chunk)
+    path:          Option<PathBuf>,
+}
+
+impl<I> NormalizedQueries<I> {
+    /// Normalizes the queries from `inner`, which were read from `path`. The
+    /// residues are only tallied if `warn` is true.
+    pub fn new(inner: I, normalization: Normalization, path: &Path, warn: bool) -> Self {
+        NormalizedQueries {
+            inner,
+            normalization,
+            tally: ResidueTally::default(),
+            path: warn.then(|| path.to_path_buf()),
+        }
+    }
+}
+
+impl<I> Iterator for NormalizedQueries<I>
+where
+    I: Iterator<Item = std::io::Result<FastX>>,
+{
+    type Item = std::io::Result<FastX>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.next() {
+            Some(Ok(mut query)) => {
+                self.normalization.apply(&mut query.sequence);
+                if self.path.is_some() {
+                    self.tally.add(&query.sequence, self.normalization.alphabet, false);
+                }
+                Some(Ok(query))
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => {
+                // Warns only once, after the last query
+                if let Some(path) = self.path.take() {
+                    self.tally
+                        .warn(&format!("the queries of {}", path.display()), &self.normalization);
+                }
+                None
+            }
+        }
+    }
+}
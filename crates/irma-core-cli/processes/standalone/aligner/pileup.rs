@@ -0,0 +1,554 @@
+//! mpileup-style per-base output accumulated from the alignments in `aligner`
+
+use crate::aligner::{AlignmentAndSeqs, Strand, footer::FooterWriter, writers::Coords};
+use irma_records::io::{OutputOptions, TempDir, TempManager};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, IntoInnerError, Read, Seek, SeekFrom, Take, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use zoe::{data::fasta::FastaSeq, prelude::NucleotidesView};
+
+/// The column names written as the first line of the pileup output.
+const PILEUP_HEADER: &str = "#reference\tposition\tref_base\tdepth\tbases\tquals";
+
+/// The number of reference positions in each window of the pileup.
+const WINDOW_SIZE: usize = 1 << 16;
+
+/// The size at which the buffered events of a window are spilled to disk.
+const SPILL_BYTES: usize = 1 << 20;
+
+/// The total size of the buffered events of all windows, beyond which each
+/// window is spilled as soon as it receives more events.
+const BUFFER_BUDGET: usize = 64 << 20;
+
+/// The maximum number of sorted runs merged at once, which bounds the number
+/// of spilled files open while a window is written.
+const MERGE_FAN_IN: usize = 64;
+
+/// The size of the fixed fields of an encoded event: the position within the
+/// window (`u32`), the quality score (`u8`), and the length of the token
+/// (`u32`).
+const EVENT_FIELDS: usize = 9;
+
+/// The quality character written for queries without quality scores, and the
+/// mapping quality written after `^`. This matches `samtools mpileup`, which
+/// caps both at `~`, given that the SAM output has a missing quality string
+/// and a `MAPQ` of 255.
+const MAX_QUALITY: u8 = b'~';
+
+/// The events of a window of a reference, which are buffered in memory until
+/// they are sorted and spilled to the window's file as a run.
+#[derive(Default)]
+struct Window {
+    buffer: Vec<u8>,
+    /// The byte ranges of the sorted runs in the window's file
+    runs:   Vec<Range<u64>>,
+}
+
+/// An mpileup-style pileup over all references, accumulated as alignments
+/// are produced.
+///
+/// Each aligned query contributes an event to every reference position it
+/// covers: its base (or deletion) as an mpileup token, along with its quality
+/// score. The events are kept per window of [`WINDOW_SIZE`] positions, and
+/// sorted and spilled to a temporary file for the window as a run once it
+/// holds [`SPILL_BYTES`] (or once all windows together hold
+/// [`BUFFER_BUDGET`]). Since the queries are not sorted by position, a window
+/// is only complete once every query is aligned, after which the runs of each
+/// window are merged as they are written. Memory use is therefore bounded by
+/// the buffers and a single output line, no matter the read depth.
+pub struct Pileup<'r> {
+    references:   Vec<&'r FastaSeq>,
+    /// The index within `windows` of the first window of each reference
+    first_window: Vec<usize>,
+    windows:      Vec<Mutex<Window>>,
+    /// The total size of the buffered events of all windows
+    buffered:     AtomicUsize,
+    /// The directory holding the spilled events of each window
+    spill_dir:    TempDir,
+}
+
+impl<'r> Pileup<'r> {
+    /// Creates an empty pileup over the references, with its temporary files
+    /// placed beside `output` (or in `--tmpdir`).
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors from creating the temporary directory are propagated.
+    pub fn new(references: &'r [FastaSeq], output: &Path) -> std::io::Result<Self> {
+        let references = references.iter().collect::<Vec<_>>();
+
+        let mut first_window = Vec::with_capacity(references.len());
+        let mut num_windows = 0;
+        for reference in &references {
+            first_window.push(num_windows);
+            num_windows += reference.sequence.len().div_ceil(WINDOW_SIZE);
+        }
+
+        Ok(Self {
+            references,
+            first_window,
+            windows: std::iter::repeat_with(Mutex::default).take(num_windows).collect(),
+            buffered: AtomicUsize::new(0),
+            spill_dir: TempManager::new("pileup").create_dir(output)?,
+        })
+    }
+
+    /// Adds a mapped alignment to the pileup, walking its CIGAR to record the
+    /// base or deletion of the query at each reference position it covers.
+    /// Unmapped alignments (including those with a score of zero, which are
+    /// written as unmapped) are skipped.
+    ///
+    /// The tokens follow `samtools mpileup`: `.` or `,` for a base matching
+    /// the reference on the forward or reverse strand, the base itself
+    /// (uppercase for forward, lowercase for reverse) for a mismatch, `*` for a
+    /// deletion, and `>` or `<` for a skipped region. An insertion following a
+    /// position is appended to that position's token as `+<len><bases>`, and a
+    /// deletion as `-<len><reference bases>`. The first token of a query is
+    /// prefixed by `^~` (the read start marker and the mapping quality), and
+    /// the last is followed by `$`. Bases from reverse strand alignments are
+    /// the reverse complement of the query, matching the sequence in the SAM
+    /// output.
+    ///
+    /// Every token has a quality score. As in `samtools mpileup`, deletions
+    /// and skipped regions take the quality score of the next query base, and
+    /// queries without quality scores use [`MAX_QUALITY`].
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors from spilling the events to disk are propagated.
+    pub fn add(&self, alignment: &AlignmentAndSeqs<'_, 'r>) -> std::io::Result<()> {
+        let Some(mapping) = alignment.mapping.as_ref().filter(|mapping| mapping.inner.score > 0) else {
+            return Ok(());
+        };
+
+        // The references are matched by address, since names need not be
+        // unique. There are few enough references that a linear scan is cheap
+        // relative to the alignment itself
+        let Some(index) = self
+            .references
+            .iter()
+            .position(|reference| std::ptr::eq(*reference, alignment.reference))
+        else {
+            return Ok(());
+        };
+        let reference = &alignment.reference.sequence;
+
+        let (sequence, quality) = match mapping.strand {
+            Strand::Forward => (
+                Cow::Borrowed(alignment.query.sequence.as_slice()),
+                alignment
+                    .query
+                    .quality
+                    .as_ref()
+                    .map(|quality| Cow::Borrowed(quality.as_bytes())),
+            ),
+            Strand::Reverse => (
+                Cow::Owned(
+                    NucleotidesView::from(alignment.query.sequence.as_slice())
+                        .to_reverse_complement()
+                        .into_vec(),
+                ),
+                alignment
+                    .query
+                    .quality
+                    .as_ref()
+                    .map(|quality| Cow::Owned(quality.to_reverse().as_bytes().to_vec())),
+            ),
+        };
+        let reverse = mapping.strand == Strand::Reverse;
+        let strand_case = |base: u8| {
+            if reverse {
+                base.to_ascii_lowercase()
+            } else {
+                base.to_ascii_uppercase()
+            }
+        };
+        // A deletion or skipped region at the end of the query has no next
+        // base, so the last base is used instead
+        let quality_at = |query_pos: usize| {
+            quality
+                .as_ref()
+                .and_then(|quality| quality.get(query_pos).or(quality.last()).copied())
+                .unwrap_or(MAX_QUALITY)
+        };
+
+        let mut ref_pos = mapping.inner.ref_range.start;
+        let mut query_pos = 0;
+        let mut events = WindowEvents::new(ref_pos);
+
+        for ciglet in mapping.inner.states.iter() {
+            match ciglet.op {
+                b'M' | b'=' | b'X' => {
+                    for _ in 0..ciglet.inc {
+                        let base = sequence[query_pos];
+                        let token = if base.eq_ignore_ascii_case(&reference[ref_pos]) {
+                            if reverse { b',' } else { b'.' }
+                        } else {
+                            strand_case(base)
+                        };
+                        events.push(ref_pos, quality_at(query_pos), &[token]);
+                        ref_pos += 1;
+                        query_pos += 1;
+                    }
+                }
+                b'D' => {
+                    let deleted = &reference[ref_pos..ref_pos + ciglet.inc];
+                    events.extend_last(b'-', deleted.iter().copied().map(strand_case));
+                    for pos in ref_pos..ref_pos + ciglet.inc {
+                        events.push(pos, quality_at(query_pos), b"*");
+                    }
+                    ref_pos += ciglet.inc;
+                }
+                b'N' => {
+                    for pos in ref_pos..ref_pos + ciglet.inc {
+                        events.push(pos, quality_at(query_pos), if reverse { b"<" } else { b">" });
+                    }
+                    ref_pos += ciglet.inc;
+                }
+                b'I' => {
+                    let inserted = &sequence[query_pos..query_pos + ciglet.inc];
+                    events.extend_last(b'+', inserted.iter().copied().map(strand_case));
+                    query_pos += ciglet.inc;
+                }
+                b'S' => query_pos += ciglet.inc,
+                _ => {}
+            }
+        }
+        events.append_last(b"$");
+
+        let first_window = self.first_window[index] + events.first_window;
+        for (window, chunk) in events.chunks.iter().enumerate() {
+            self.append(first_window + window, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Appends encoded events to a window, spilling its buffer to disk as a
+    /// sorted run if it grows too large.
+    fn append(&self, window: usize, chunk: &[u8]) -> std::io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        let mut guard = self.windows[window].lock().unwrap_or_else(PoisonError::into_inner);
+        guard.buffer.extend_from_slice(chunk);
+        let buffered = self.buffered.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+
+        if guard.buffer.len() >= SPILL_BYTES || buffered > BUFFER_BUDGET {
+            let buffer = std::mem::take(&mut guard.buffer);
+            let spill_path = self.spill_path(window);
+            let run = spill_run(&spill_path, sorted_events(&buffer).map(Ok))?;
+            guard.runs.push(run);
+            self.buffered.fetch_sub(buffer.len(), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// The path of the file holding the spilled runs of a window.
+    fn spill_path(&self, window: usize) -> PathBuf {
+        self.spill_dir.path().join(window.to_string())
+    }
+
+    /// Writes the pileup as a tab-delimited table to `path`, with one line per
+    /// covered reference position (in reference order), in the format of
+    /// `samtools mpileup`:
+    ///
+    /// - `depth` is the number of queries covering the position, including
+    ///   deletions
+    /// - `bases` has a token for each of those queries (see [`Pileup::add`]),
+    ///   ordered so that the output does not depend on the order in which the
+    ///   queries were aligned
+    /// - `quals` has the quality score of each token, in the same order
+    ///
+    /// Positions are written in the coordinate system given by `coords`. The
+    /// windows are written one at a time, merging the sorted runs of each (at
+    /// most [`MERGE_FAN_IN`] at a time) so that only a single line is held in
+    /// memory. With `footer`, the table ends with a footer (see
+    /// [`FooterWriter`]).
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors from reading the spilled events, or from opening or
+    /// writing to the file, are propagated.
+    pub fn write_to(self, path: &Path, coords: Coords, footer: bool) -> std::io::Result<()> {
+        let mut writer = OutputOptions::new_from_opt_path(Some(path)).use_file_zip_or_stdout().open()?;
+        writeln!(writer, "{PILEUP_HEADER}")?;
+        let mut writer = FooterWriter::new(writer, footer);
+
+        let mut windows = self.windows.into_iter().enumerate();
+        let mut bases = Vec::new();
+        let mut quals = Vec::new();
+
+        for reference in &self.references {
+            let name = reference.name.split_ascii_whitespace().next().unwrap_or("*");
+            let num_windows = reference.sequence.len().div_ceil(WINDOW_SIZE);
+
+            for (window_index, (spill, window)) in windows.by_ref().take(num_windows).enumerate() {
+                let Window { buffer, mut runs } = window.into_inner().unwrap_or_else(PoisonError::into_inner);
+                let mut spill_path = self.spill_dir.path().join(spill.to_string());
+
+                let mut column = None;
+                let mut write_column = |pos: u32, bases: &[u8], quals: &[u8]| -> std::io::Result<()> {
+                    let pos = window_index * WINDOW_SIZE + pos as usize;
+                    let ref_base = char::from(reference.sequence[pos].to_ascii_uppercase());
+                    write!(
+                        writer,
+                        "{name}\t{pos}\t{ref_base}\t{depth}\t",
+                        pos = coords.position(pos),
+                        depth = quals.len()
+                    )?;
+                    writer.write_all(bases)?;
+                    writer.write_all(b"\t")?;
+                    writer.write_all(quals)?;
+                    writer.write_all(b"\n")
+                };
+                let mut add_event = |event: Event| -> std::io::Result<()> {
+                    if column.is_some_and(|pos| pos != event.pos) {
+                        write_column(column.unwrap_or_default(), &bases, &quals)?;
+                        bases.clear();
+                        quals.clear();
+                    }
+                    column = Some(event.pos);
+                    bases.extend_from_slice(&event.token);
+                    quals.push(event.quality);
+                    Ok(())
+                };
+
+                if runs.is_empty() {
+                    for event in sorted_events(&buffer) {
+                        add_event(event)?;
+                    }
+                } else {
+                    if !buffer.is_empty() {
+                        runs.push(spill_run(&spill_path, sorted_events(&buffer).map(Ok))?);
+                    }
+                    drop(buffer);
+
+                    // Merge passes reduce the number of runs until they can
+                    // all be merged at once
+                    let mut pass = 0;
+                    while runs.len() > MERGE_FAN_IN {
+                        pass += 1;
+                        let merged_path = self.spill_dir.path().join(format!("{spill}.{pass}"));
+                        let mut merged = Vec::with_capacity(runs.len().div_ceil(MERGE_FAN_IN));
+                        for group in runs.chunks(MERGE_FAN_IN) {
+                            merged.push(spill_run(&merged_path, MergedRuns::open(&spill_path, group)?)?);
+                        }
+                        std::fs::remove_file(&spill_path)?;
+                        spill_path = merged_path;
+                        runs = merged;
+                    }
+
+                    for event in MergedRuns::open(&spill_path, &runs)? {
+                        add_event(event?)?;
+                    }
+                    std::fs::remove_file(&spill_path)?;
+                }
+
+                if let Some(pos) = column {
+                    write_column(pos, &bases, &quals)?;
+                    bases.clear();
+                    quals.clear();
+                }
+            }
+        }
+
+        writer.finish()
+    }
+}
+
+/// The events of a single alignment, encoded per window before they are
+/// appended to the pileup. Each event is encoded by [`encode_event`].
+struct WindowEvents {
+    /// The window (relative to the reference) of the first event
+    first_window: usize,
+    /// The encoded events of each window, from `first_window` onward
+    chunks:       Vec<Vec<u8>>,
+    /// The chunk and offset of the token length of the last event, so that
+    /// insertions, deletions, and the read end marker can be appended to its
+    /// token
+    last:         Option<(usize, usize)>,
+}
+
+impl WindowEvents {
+    fn new(start: usize) -> Self {
+        WindowEvents {
+            first_window: start / WINDOW_SIZE,
+            chunks:       Vec::new(),
+            last:         None,
+        }
+    }
+
+    /// Records the token of a query at reference position `pos`, with the read
+    /// start marker if it is the first token of the query.
+    fn push(&mut self, pos: usize, quality: u8, token: &[u8]) {
+        let chunk = pos / WINDOW_SIZE - self.first_window;
+        if self.chunks.len() <= chunk {
+            self.chunks.resize_with(chunk + 1, Vec::new);
+        }
+        let buffer = &mut self.chunks[chunk];
+
+        // Validity: `WINDOW_SIZE` fits in a u32
+        let pos = (pos % WINDOW_SIZE) as u32;
+        let offset = if self.last.is_none() {
+            encode_event(buffer, pos, quality, &[&[b'^', MAX_QUALITY], token].concat())
+        } else {
+            encode_event(buffer, pos, quality, token)
+        };
+        self.last = Some((chunk, offset));
+    }
+
+    /// Appends an insertion (`+`) or deletion (`-`) to the token of the last
+    /// event, as the marker, the number of bases, and the bases. Indels before
+    /// the first event are ignored, since there is no position to attach them
+    /// to.
+    fn extend_last(&mut self, marker: u8, bases: impl ExactSizeIterator<Item = u8>) {
+        let mut extension = vec![marker];
+        extension.extend_from_slice(bases.len().to_string().as_bytes());
+        extension.extend(bases);
+        self.append_last(&extension);
+    }
+
+    /// Appends bytes to the token of the last event, if there is one.
+    fn append_last(&mut self, bytes: &[u8]) {
+        let Some((chunk, offset)) = self.last else {
+            return;
+        };
+        let buffer = &mut self.chunks[chunk];
+        buffer.extend_from_slice(bytes);
+
+        let length = &buffer[offset..offset + 4];
+        let extended = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) + bytes.len() as u32;
+        buffer[offset..offset + 4].copy_from_slice(&extended.to_le_bytes());
+    }
+}
+
+/// Encodes an event as the position within the window (`u32`), the quality
+/// score (`u8`), the length of the token (`u32`), and the token, with integers
+/// in little endian. Returns the offset of the token length within `buffer`.
+fn encode_event(buffer: &mut Vec<u8>, pos: u32, quality: u8, token: &[u8]) -> usize {
+    buffer.extend_from_slice(&pos.to_le_bytes());
+    buffer.push(quality);
+    let offset = buffer.len();
+    // Validity: a token is at most the length of the query plus a few bytes,
+    // so it fits in a u32
+    buffer.extend_from_slice(&(token.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(token);
+    offset
+}
+
+/// A decoded event of a window. The fields are ordered so that events sort by
+/// position, then token, then quality score.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Event {
+    pos:     u32,
+    token:   Vec<u8>,
+    quality: u8,
+}
+
+/// Decodes the events of a buffer encoded by [`encode_event`], and sorts them.
+fn sorted_events(events: &[u8]) -> impl Iterator<Item = Event> {
+    let mut decoded = Vec::new();
+    let mut reader = events;
+    while let Ok(Some(event)) = read_event(&mut reader) {
+        decoded.push(event);
+    }
+    decoded.sort_unstable();
+    decoded.into_iter()
+}
+
+/// Reads the next event encoded by [`encode_event`], or `None` at the end of
+/// the input.
+fn read_event(reader: &mut impl Read) -> std::io::Result<Option<Event>> {
+    let mut fields = [0; EVENT_FIELDS];
+    match reader.read_exact(&mut fields) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let pos = u32::from_le_bytes([fields[0], fields[1], fields[2], fields[3]]);
+    let len = u32::from_le_bytes([fields[5], fields[6], fields[7], fields[8]]) as usize;
+    let mut token = vec![0; len];
+    reader.read_exact(&mut token)?;
+    Ok(Some(Event {
+        pos,
+        token,
+        quality: fields[4],
+    }))
+}
+
+/// Appends sorted events to the file at `path` as a run, returning its byte
+/// range within the file.
+///
+/// ## Errors
+///
+/// Any IO errors from the events, or from opening or writing to the file, are
+/// propagated.
+fn spill_run(path: &Path, events: impl Iterator<Item = std::io::Result<Event>>) -> std::io::Result<Range<u64>> {
+    let file = File::options().create(true).append(true).open(path)?;
+    let start = file.metadata()?.len();
+    let mut writer = BufWriter::new(file);
+    let mut buffer = Vec::new();
+    for event in events {
+        let event = event?;
+        buffer.clear();
+        encode_event(&mut buffer, event.pos, event.quality, &event.token);
+        writer.write_all(&buffer)?;
+    }
+    let end = writer.into_inner().map_err(IntoInnerError::into_error)?.metadata()?.len();
+    Ok(start..end)
+}
+
+/// An iterator over the events of several sorted runs of a file, merged into
+/// sorted order.
+struct MergedRuns {
+    readers: Vec<BufReader<Take<File>>>,
+    /// The next event of each reader, keyed by the index of the reader
+    heap:    BinaryHeap<Reverse<(Event, usize)>>,
+}
+
+impl MergedRuns {
+    /// Opens the runs with the given byte ranges of the file at `path`.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors from opening or reading the file are propagated.
+    fn open(path: &Path, runs: &[Range<u64>]) -> std::io::Result<Self> {
+        let mut readers = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for run in runs {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(run.start))?;
+            let mut reader = BufReader::new(file.take(run.end - run.start));
+            if let Some(event) = read_event(&mut reader)? {
+                heap.push(Reverse((event, readers.len())));
+            }
+            readers.push(reader);
+        }
+        Ok(Self { readers, heap })
+    }
+}
+
+impl Iterator for MergedRuns {
+    type Item = std::io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((event, index)) = self.heap.pop()?;
+        match read_event(&mut self.readers[index]) {
+            Ok(Some(next)) => self.heap.push(Reverse((next, index))),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(event))
+    }
+}
@@ -0,0 +1,188 @@
+//! Placement of the aligner's threads on specific cores or NUMA nodes, for
+//! `--pin-threads` and `--numa-node`. Placement is only supported on Linux, and
+//! is skipped with a warning elsewhere.
+
+/// The cores on which the aligner's threads run.
+#[derive(Clone, Debug)]
+pub struct ThreadPlacement {
+    /// The cores available to the threads, in ascending order
+    cpus:     Vec<usize>,
+    /// Whether each worker is pinned to a single core, rather than allowed to
+    /// run on any of `cpus`
+    pin_each: bool,
+}
+
+impl ThreadPlacement {
+    /// Determines the cores from `--numa-node` (the cores of that node), or
+    /// otherwise the cores on which the process is allowed to run. `None` is
+    /// returned if neither option was given, or if placement is unsupported on
+    /// this platform.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the NUMA node does not exist or has no cores, or
+    /// if the allowed cores cannot be determined.
+    pub fn new(pin_threads: bool, numa_node: Option<usize>) -> std::io::Result<Option<Self>> {
+        if !pin_threads && numa_node.is_none() {
+            return Ok(None);
+        }
+
+        if !cfg!(target_os = "linux") {
+            eprintln!("Aligner Warning: `--pin-threads` and `--numa-node` are only supported on Linux, and were ignored.");
+            return Ok(None);
+        }
+
+        let cpus = match numa_node {
+            Some(node) => sys::node_cpus(node)?,
+            None => sys::allowed_cpus()?,
+        };
+
+        Ok(Some(Self {
+            cpus,
+            pin_each: pin_threads,
+        }))
+    }
+
+    /// Restricts the calling thread to the cores of the placement.
+    ///
+    /// This is called on the main thread before the references are read, so
+    /// that with `--numa-node`, their memory is first touched (and therefore
+    /// allocated) on that node. Threads spawned afterward inherit the
+    /// restriction.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the thread cannot be restricted, such as when
+    /// the cores are outside of the process's cgroup.
+    pub fn pin_current_thread(&self) -> std::io::Result<()> {
+        sys::set_affinity(&self.cpus)
+    }
+
+    /// Pins the worker with the given index within the pool. With
+    /// `--pin-threads`, each worker is pinned to its own core, cycling through
+    /// the cores if there are more workers than cores. Otherwise, the worker
+    /// may run on any of the cores.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the worker cannot be pinned.
+    pub fn pin_worker(&self, index: usize) -> std::io::Result<()> {
+        if self.pin_each {
+            sys::set_affinity(&[self.cpus[index % self.cpus.len()]])
+        } else {
+            sys::set_affinity(&self.cpus)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io::{Error, ErrorKind};
+
+    /// The number of cores representable in a [`libc::cpu_set_t`].
+    const CPU_SETSIZE: usize = libc::CPU_SETSIZE as usize;
+
+    /// Restricts the calling thread to the given cores.
+    pub(super) fn set_affinity(cpus: &[usize]) -> std::io::Result<()> {
+        // Safety: `cpu_set_t` is a bitmask, for which all zeros is valid (and
+        // empty)
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpus {
+            if cpu >= CPU_SETSIZE {
+                return Err(Error::other(format!("Core {cpu} cannot be pinned to")));
+            }
+            // Safety: `cpu` is within the bounds of the set, checked above
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+
+        // Safety: `set` is a valid `cpu_set_t` of the given size, and a pid of
+        // zero refers to the calling thread
+        let result = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &raw const set) };
+        if result == 0 {
+            Ok(())
+        } else {
+            let e = Error::last_os_error();
+            Err(Error::new(e.kind(), format!("Failed to pin a thread to cores {cpus:?}: {e}")))
+        }
+    }
+
+    /// The cores on which the process is allowed to run, such as when limited
+    /// by `taskset` or a cgroup.
+    pub(super) fn allowed_cpus() -> std::io::Result<Vec<usize>> {
+        // Safety: `cpu_set_t` is a bitmask, for which all zeros is valid (and
+        // empty)
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+        // Safety: `set` is a valid `cpu_set_t` of the given size, and a pid of
+        // zero refers to the calling thread
+        let result = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &raw mut set) };
+        if result != 0 {
+            let e = Error::last_os_error();
+            return Err(Error::new(e.kind(), format!("Failed to determine the available cores: {e}")));
+        }
+
+        // Safety: every core checked is within the bounds of the set
+        Ok((0..CPU_SETSIZE)
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect())
+    }
+
+    /// The cores of the given NUMA node, read from sysfs.
+    pub(super) fn node_cpus(node: usize) -> std::io::Result<Vec<usize>> {
+        let path = format!("/sys/devices/system/node/node{node}/cpulist");
+        let list = std::fs::read_to_string(&path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("NUMA node {node} was not found, since its cores could not be read from '{path}': {e}"),
+            )
+        })?;
+
+        match parse_cpu_list(&list) {
+            Some(cpus) if !cpus.is_empty() => Ok(cpus),
+            Some(_) => Err(Error::new(ErrorKind::InvalidInput, format!("NUMA node {node} has no cores"))),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse the cores of NUMA node {node} from '{path}': {list}"),
+            )),
+        }
+    }
+
+    /// Parses a Linux CPU list, such as `0-3,8-11`, as found in
+    /// `/sys/devices/system/node/node<N>/cpulist`.
+    fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+        let mut cpus = Vec::new();
+        for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+            match range.split_once('-') {
+                Some((start, end)) => cpus.extend(start.parse::<usize>().ok()?..=end.parse::<usize>().ok()?),
+                None => cpus.push(range.parse().ok()?),
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Some(cpus)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    //! Placement is unsupported, and [`ThreadPlacement::new`] never returns a
+    //! placement, so these are never called.
+    //!
+    //! [`ThreadPlacement::new`]: super::ThreadPlacement::new
+
+    fn unsupported() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, "Thread placement is only supported on Linux")
+    }
+
+    pub(super) fn set_affinity(_cpus: &[usize]) -> std::io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub(super) fn allowed_cpus() -> std::io::Result<Vec<usize>> {
+        Err(unsupported())
+    }
+
+    pub(super) fn node_cpus(_node: usize) -> std::io::Result<Vec<usize>> {
+        Err(unsupported())
+    }
+}
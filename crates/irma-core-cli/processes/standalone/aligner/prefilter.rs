@@ -0,0 +1,181 @@
+//! Ranking of the references by the minimizers they share with each query, for
+//! `--top-k-refs`. Each query is then only aligned against its top-ranked
+//! references, bounding the work per query for large reference panels.
+
+use foldhash::fast::SeedableRandomState;
+use irma_records::hashing::get_hasher;
+use std::collections::HashMap;
+use zoe::data::fasta::FastaSeq;
+
+/// The length of the k-mers from which minimizers are chosen.
+const K: usize = 15;
+
+/// The number of consecutive k-mers in each window, of which the smallest is
+/// the minimizer.
+const W: usize = 10;
+
+/// The mask keeping the `2 * K` bits of an encoded k-mer.
+const KMER_MASK: u32 = (1 << (2 * K)) - 1;
+
+/// An excluded reference sharing at least this fraction of the minimizers
+/// shared by the last included reference is considered near the cutoff, as a
+/// numerator and denominator.
+const NEAR_CUTOFF_FRACTION: (usize, usize) = (9, 10);
+
+/// Encodes a base in two bits, or returns `None` for anything other than
+/// `ACGT` (in either case).
+#[inline]
+fn encode_base(base: u8) -> Option<u32> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// Scrambles an encoded k-mer, so that minimizers are not biased toward
+/// low-complexity k-mers such as poly-A. This is invertible, so distinct
+/// k-mers remain distinct.
+#[inline]
+fn scramble(kmer: u32) -> u32 {
+    let mut x = kmer;
+    x = (x ^ (x >> 15)).wrapping_mul(0x2C1B_3C6D);
+    x = (x ^ (x >> 12)).wrapping_mul(0x297A_2D39);
+    x ^ (x >> 15)
+}
+
+/// Finds the canonical minimizers of `seq`, sorted and without duplicates.
+/// Canonical k-mers (the smaller of a k-mer and its reverse complement) are
+/// used, so that a sequence and its reverse complement have the same
+/// minimizers. Windows are restarted after any base other than `ACGT`.
+fn minimizers(seq: &[u8]) -> Vec<u32> {
+    let mut found = Vec::new();
+    let mut window = [u32::MAX; W];
+    let mut forward = 0u32;
+    let mut reverse = 0u32;
+    let mut valid = 0usize;
+
+    for &base in seq {
+        let Some(code) = encode_base(base) else {
+            valid = 0;
+            continue;
+        };
+
+        forward = ((forward << 2) | code) & KMER_MASK;
+        reverse = (reverse >> 2) | ((3 - code) << (2 * (K - 1)));
+        valid += 1;
+
+        if valid >= K {
+            let kmers_in_run = valid - K;
+            window[kmers_in_run % W] = scramble(forward.min(reverse));
+            if kmers_in_run + 1 >= W {
+                // Validity: the window is non-empty
+                let minimizer = *window.iter().min().unwrap();
+                if found.last() != Some(&minimizer) {
+                    found.push(minimizer);
+                }
+            }
+        } else if valid == 1 {
+            window = [u32::MAX; W];
+        }
+    }
+
+    // Sequences with no full window still contribute their k-mers
+    if found.is_empty()
+        && let Some(&minimizer) = window.iter().min()
+        && minimizer != u32::MAX
+    {
+        found.push(minimizer);
+    }
+
+    found.sort_unstable();
+    found.dedup();
+    found
+}
+
+/// The references ranked for a single query by `--top-k-refs`.
+pub struct Ranking {
+    /// The indices of the references to align against, in the order of the
+    /// reference file (so that ties are broken as without `--top-k-refs`)
+    pub indices:     Vec<usize>,
+    /// Whether an excluded reference shares nearly as many minimizers as the
+    /// last included one, in which case the best reference may have been
+    /// missed
+    pub near_cutoff: bool,
+}
+
+/// An index from each minimizer to the references containing it, for
+/// `--top-k-refs`.
+pub struct RefPrefilter {
+    /// The references containing each minimizer
+    index:    HashMap<u32, Vec<usize>, SeedableRandomState>,
+    /// The number of references
+    num_refs: usize,
+    /// The number of references each query is aligned against
+    top_k:    usize,
+}
+
+impl RefPrefilter {
+    /// Indexes the minimizers of the `references`, for aligning each query
+    /// against at most `top_k` of them.
+    pub fn new(references: &[FastaSeq], top_k: usize) -> Self {
+        let mut index: HashMap<u32, Vec<usize>, SeedableRandomState> = HashMap::with_hasher(get_hasher());
+        for (i, reference) in references.iter().enumerate() {
+            for minimizer in minimizers(&reference.sequence) {
+                index.entry(minimizer).or_default().push(i);
+            }
+        }
+
+        RefPrefilter {
+            index,
+            num_refs: references.len(),
+            top_k,
+        }
+    }
+
+    /// Ranks the references by the number of minimizers they share with
+    /// `query`, keeping the top `top_k`. Ties are broken by the order of the
+    /// references, and references sharing no minimizers fill any remaining
+    /// places, so that every query is aligned against `top_k` references (or
+    /// all of them, if there are fewer).
+    pub fn rank(&self, query: &[u8]) -> Ranking {
+        let mut shared: HashMap<usize, usize, SeedableRandomState> = HashMap::with_hasher(get_hasher());
+        for minimizer in minimizers(query) {
+            if let Some(references) = self.index.get(&minimizer) {
+                for &reference in references {
+                    *shared.entry(reference).or_default() += 1;
+                }
+            }
+        }
+
+        let mut ranked = shared.into_iter().collect::<Vec<_>>();
+        ranked.sort_unstable_by(|(ref1, count1), (ref2, count2)| count2.cmp(count1).then(ref1.cmp(ref2)));
+
+        let near_cutoff = match (ranked.get(self.top_k - 1), ranked.get(self.top_k)) {
+            (Some(&(_, last_kept)), Some(&(_, first_excluded))) => {
+                let (numerator, denominator) = NEAR_CUTOFF_FRACTION;
+                first_excluded * denominator >= last_kept * numerator
+            }
+            _ => false,
+        };
+
+        let mut indices = ranked
+            .into_iter()
+            .take(self.top_k)
+            .map(|(reference, _)| reference)
+            .collect::<Vec<_>>();
+        if indices.len() < self.top_k.min(self.num_refs) {
+            let mut included = vec![false; self.num_refs];
+            for &reference in &indices {
+                included[reference] = true;
+            }
+            let remaining = self.top_k.min(self.num_refs) - indices.len();
+            indices.extend((0..self.num_refs).filter(|&reference| !included[reference]).take(remaining));
+        }
+        indices.sort_unstable();
+
+        Ranking { indices, near_cutoff }
+    }
+}
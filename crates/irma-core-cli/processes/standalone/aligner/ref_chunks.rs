@@ -0,0 +1,290 @@
+//! Alignment against the references a chunk at a time for `--ref-chunk-size`,
+//! so that only one chunk of reference profiles is held in memory. Only the
+//! best score of each query is kept between chunks, and the winning alignments
+//! are computed again in a final pass
+
+use crate::aligner::{
+    AlignmentAndSeqs, AlignmentMethod, QueryReader, QueryWithRc, Reference, References, SamWriter, align_queries,
+    arg_parsing::AlignerConfig,
+    open_queries,
+    pileup::Pileup,
+    ref_params::RefScoring,
+    ref_usage::RefUsage,
+    scores::ScoreNormalizer,
+    spans::{SpanKind, TimeSpan, TimedReader},
+    tallies::{AlignmentTallies, AllTallies, QueryTallies, RefTallies, pick_alignment_method},
+    trace_alignment, trace_best_match,
+};
+use irma_records::io::FastX;
+use std::{ops::Range, path::Path};
+use zoe::data::{fasta::FastaSeq, matrices::WeightMatrix};
+
+#[cfg(not(feature = "dev_no_rayon"))]
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+/// The references for `--ref-chunk-size`, which are profiled a chunk at a time.
+pub struct RefChunks<'r, const S: usize> {
+    /// The references, in order
+    references: &'r [FastaSeq],
+    /// The weight matrix for the references without an override
+    matrix:     &'r WeightMatrix<'r, i8, S>,
+    /// The scoring overrides from `--ref-params`, in the same order as the
+    /// references, or empty
+    overrides:  &'r [Option<RefScoring<S>>],
+    /// The number of references in each chunk
+    chunk_size: usize,
+}
+
+impl<'r, const S: usize> RefChunks<'r, S> {
+    /// Splits the `references` into chunks of `chunk_size`, where `overrides`
+    /// are as for [`References::new`].
+    ///
+    /// ## Panics
+    ///
+    /// `chunk_size` must be nonzero.
+    pub fn new(
+        references: &'r [FastaSeq], matrix: &'r WeightMatrix<'r, i8, S>, overrides: &'r [Option<RefScoring<S>>],
+        chunk_size: usize,
+    ) -> Self {
+        assert!(chunk_size > 0, "The chunk size must be nonzero");
+        Self {
+            references,
+            matrix,
+            overrides,
+            chunk_size,
+        }
+    }
+
+    /// Returns the range of reference indices in each chunk.
+    fn ranges(&self) -> impl Iterator<Item = Range<usize>> {
+        (0..self.references.len())
+            .step_by(self.chunk_size)
+            .map(|start| start..(start + self.chunk_size).min(self.references.len()))
+    }
+
+    /// Builds the profiles of the references in `range`.
+    ///
+    /// ## Errors
+    ///
+    /// If any of the profiles fail to build, an error is returned with context
+    /// including the header.
+    fn profile(&self, range: Range<usize>, config: &AlignerConfig) -> std::io::Result<References<'r, S>> {
+        let overrides = self.overrides.get(range.clone()).unwrap_or_default();
+        References::new(
+            &self.references[range],
+            self.matrix,
+            overrides,
+            config.gap_open,
+            config.gap_extend,
+            config.rev_comp,
+            config.min_score,
+        )
+    }
+}
+
+/// The best reference found so far for a query.
+#[derive(Copy, Clone, Debug)]
+struct BestRef {
+    /// The index of the reference within all the references
+    reference: usize,
+    /// The key the reference was selected by (see [`selection_key`])
+    key:       f64,
+}
+
+/// Aligns all the queries in `query_reader` against the references in
+/// `chunks`, picking the best reference for each and writing that alignment to
+/// `writer`, as with [`align_best_match`].
+///
+/// The queries are aligned against each chunk in turn, keeping the best
+/// reference of each query. The queries are then read once more, and each is
+/// aligned again against its best reference, which is profiled alone. Ties
+/// are resolved as in [`align_best_ref`], so the same alignments are written
+/// as without chunks. The queries are reopened from `query_file` after the
+/// first chunk.
+///
+/// ## Errors
+///
+/// Errors while reading the queries, building the profiles, performing the
+/// alignment, and writing the alignment are propagated. Context containing the
+/// header(s) is added for failed profile building or alignment. An error is
+/// also returned if the number of queries changes between the passes.
+///
+/// ## Validity
+///
+/// This function returns an error intended to be displayed at the top-level. No
+/// callers should add additional context other than calling a method in
+/// [`OrFail`].
+///
+/// [`align_best_match`]: crate::aligner::align_best_match
+/// [`align_best_ref`]: crate::aligner::align_best_ref
+/// [`OrFail`]: zoe::data::err::OrFail
+pub fn align_best_match_chunked<'r, const S: usize>(
+    query_reader: QueryReader, query_file: &Path, writer: SamWriter, chunks: &RefChunks<'r, S>, pileup: Option<&Pileup<'r>>,
+    ref_usage: Option<&RefUsage<'r>>, config: &AlignerConfig,
+) -> std::io::Result<AllTallies> {
+    let query_tallies = QueryTallies::default();
+    let ref_tallies = RefTallies::from_records(chunks.references);
+    let alignment_tallies = AlignmentTallies::default();
+
+    let mut best_refs: Vec<Option<BestRef>> = Vec::new();
+    let mut query_reader = Some(query_reader);
+
+    for (chunk, range) in chunks.ranges().enumerate() {
+        let references = config
+            .spans
+            .time(SpanKind::ProfileBuild, || chunks.profile(range.clone(), config))?;
+        let query_reader = match query_reader.take() {
+            Some(query_reader) => query_reader,
+            None => open_queries(query_file, config, false)?,
+        };
+
+        let best_in_chunk = |(index, query): (usize, std::io::Result<FastX>)| -> std::io::Result<(usize, BestRef)> {
+            let query = query?;
+            // The queries are only tallied once
+            if chunk == 0 {
+                query_tallies.tally(&query.sequence);
+            }
+
+            let method = pick_alignment_method(&query_tallies, &ref_tallies, &alignment_tallies, config);
+            let trace = config.tracer.start(&query.header);
+            trace.step("reference chunk", || {
+                format!("{chunk} (references {start}..{end})", start = range.start, end = range.end)
+            });
+
+            let query_len = query.sequence.len();
+            let query = QueryWithRc::new(&query, config.rev_comp);
+            let alignments = references
+                .candidates(None)
+                .align_each(&query, query_len, |query, reference| {
+                    config
+                        .spans
+                        .time(SpanKind::Align, || align_ref_profile(reference, query, method))
+                })?;
+
+            let mut best: Option<BestRef> = None;
+            for (i, alignment) in alignments.iter().enumerate() {
+                alignment_tallies.tally(alignment, chunks.matrix);
+                trace_alignment(&trace, alignment, config);
+                let key = selection_key(alignment, config.normalizer.as_ref());
+                if best.is_none_or(|best| key >= best.key) {
+                    best = Some(BestRef {
+                        reference: range.start + i,
+                        key,
+                    });
+                }
+            }
+
+            // Validity: the chunks are non-empty, so each query has a best
+            // reference
+            Ok((index, best.expect("Each chunk should contain a reference")))
+        };
+
+        let queries = TimedReader::new(query_reader, &config.spans).enumerate();
+
+        #[cfg(not(feature = "dev_no_rayon"))]
+        let chunk_best_refs = queries.par_bridge().map(best_in_chunk).collect::<std::io::Result<Vec<_>>>()?;
+
+        #[cfg(feature = "dev_no_rayon")]
+        let chunk_best_refs = queries.map(best_in_chunk).collect::<std::io::Result<Vec<_>>>()?;
+
+        if chunk == 0 {
+            best_refs = vec![None; chunk_best_refs.len()];
+        } else if chunk_best_refs.len() != best_refs.len() {
+            return Err(queries_changed(query_file));
+        }
+
+        for (index, best) in chunk_best_refs {
+            let best_ref = &mut best_refs[index];
+            if best_ref.is_none_or(|best_ref| best.key >= best_ref.key) {
+                *best_ref = Some(best);
+            }
+        }
+    }
+
+    let queries = open_queries(query_file, config, false)?
+        .enumerate()
+        .map(|(index, query)| query.map(|query| (index, query)));
+
+    align_queries(queries, writer, &config.spans, |writer, query| {
+        let (index, query) = query?;
+        let best_ref = best_refs
+            .get(index)
+            .copied()
+            .flatten()
+            .ok_or_else(|| queries_changed(query_file))?;
+
+        let method = pick_alignment_method(&query_tallies, &ref_tallies, &alignment_tallies, config);
+        let trace = config.tracer.start(&query.header);
+
+        let reference = best_ref.reference;
+        let references = config
+            .spans
+            .time(SpanKind::ProfileBuild, || chunks.profile(reference..reference + 1, config))?;
+
+        let query = QueryWithRc::new(&query, config.rev_comp);
+        let best_alignment = config
+            .spans
+            .time(SpanKind::Align, || align_ref_profile(&references.0[0], &query, method))?;
+        alignment_tallies.tally(&best_alignment, chunks.matrix);
+
+        trace_best_match(&trace, &best_alignment);
+
+        if let Some(pileup) = pileup {
+            pileup.add(&best_alignment)?;
+        }
+        if let Some(ref_usage) = ref_usage {
+            ref_usage.add(&best_alignment);
+        }
+        config
+            .spans
+            .time(SpanKind::Write, || writer.write_alignment(best_alignment, config))?;
+
+        Ok(())
+    })?;
+
+    Ok(AllTallies::new(&query_tallies, &ref_tallies, &alignment_tallies, config))
+}
+
+/// Aligns `query` against the profile of `reference` with the 1-pass or 3-pass
+/// algorithm, depending on `method`.
+///
+/// ## Validity
+///
+/// `--ref-chunk-size` requires `--profile-from-ref` and conflicts with
+/// `--ungapped`, so `method` always builds the profile from the reference.
+///
+/// ## Errors
+///
+/// If the alignment fails (due to overflow), context with the query and
+/// reference header is added.
+fn align_ref_profile<'q, 'r, const S: usize>(
+    reference: &Reference<'r, S>, query: &QueryWithRc<'q, S>, method: AlignmentMethod,
+) -> std::io::Result<AlignmentAndSeqs<'q, 'r>> {
+    if method.is_three_pass() {
+        reference.sw_3pass_ref_profile(query)
+    } else {
+        reference.sw_1pass_ref_profile(query)
+    }
+}
+
+/// The key by which the best reference is selected, which is compared across
+/// the chunks. This is [`ScoreNormalizer::selection_key`] if a `normalizer` is
+/// provided, and otherwise the raw score, with unmapped alignments lowest.
+fn selection_key(alignment: &AlignmentAndSeqs<'_, '_>, normalizer: Option<&ScoreNormalizer>) -> f64 {
+    match normalizer {
+        Some(normalizer) => normalizer.selection_key(alignment),
+        None => alignment
+            .mapping
+            .as_ref()
+            .map_or(f64::NEG_INFINITY, |mapping| f64::from(mapping.inner.score)),
+    }
+}
+
+/// The error returned when a different number of queries is read in one of the
+/// passes over `query_file`.
+fn queries_changed(query_file: &Path) -> std::io::Error {
+    std::io::Error::other(format!(
+        "The query file changed while it was aligned against each chunk of references: {}",
+        query_file.display()
+    ))
+}
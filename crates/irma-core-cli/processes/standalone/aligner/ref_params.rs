@@ -0,0 +1,140 @@
+//! Per-reference scoring overrides for `--ref-params`, which align each listed
+//! reference with its own tuned scoring parameters.
+
+use std::{collections::HashMap, fmt::Display, path::Path};
+use zoe::data::{WeightMatrix, fasta::FastaSeq};
+
+/// The scoring parameters of a reference listed in `--ref-params`, given as
+/// nonnegative scores and penalties as on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RefParams {
+    pub matching:   u8,
+    pub mismatch:   u8,
+    pub gap_open:   u8,
+    pub gap_extend: u8,
+}
+
+impl Display for RefParams {
+    /// Displays the parameters as they are reported in the `YP:Z` tag, in the
+    /// order of the columns in the table.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{matching},{mismatch},{gap_open},{gap_extend}",
+            matching = self.matching,
+            mismatch = self.mismatch,
+            gap_open = self.gap_open,
+            gap_extend = self.gap_extend
+        )
+    }
+}
+
+/// The scoring used for a reference with overridden parameters: the
+/// parameters, along with the weight matrix and signed gap weights built from
+/// them.
+pub struct RefScoring<const S: usize> {
+    pub params:     RefParams,
+    pub matrix:     WeightMatrix<'static, i8, S>,
+    pub gap_open:   i8,
+    pub gap_extend: i8,
+}
+
+impl RefScoring<5> {
+    /// Builds the DNA scoring for the parameters, with a score of 0 for any
+    /// base involving `N` if `ignore_n` is true.
+    pub fn new_dna(params: RefParams, ignore_n: bool) -> Self {
+        RefScoring {
+            params,
+            matrix: WeightMatrix::new_dna_matrix(params.matching as i8, -(params.mismatch as i8), ignore_n.then_some(b'N')),
+            gap_open: -(params.gap_open as i8),
+            gap_extend: -(params.gap_extend as i8),
+        }
+    }
+}
+
+/// Reads the tab-delimited table of per-reference scoring overrides, with the
+/// reference name, the match score, and the mismatch, gap open, and gap
+/// extend penalties on each line. Blank lines and lines starting with `#` are
+/// skipped. A reference is named by its header up to the first whitespace.
+///
+/// The overrides are returned in the order of `references`, with `None` for
+/// any reference not listed, which uses the parameters from the command line.
+///
+/// ## Errors
+///
+/// Errors are returned if the table cannot be read, if a line does not have
+/// five columns, if a score or penalty is not an integer in [0, 127], if the
+/// gap open penalty is smaller than the gap extend penalty, or if a reference
+/// is repeated or not among `references`. Each contains the table path as
+/// context.
+pub fn read_ref_params(path: &Path, references: &[FastaSeq]) -> std::io::Result<Vec<Option<RefParams>>> {
+    let invalid = |msg: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{msg} in the reference parameters: {path}", path = path.display()),
+        )
+    };
+
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Failed to read the reference parameters: {}\n{e}", path.display()),
+        )
+    })?;
+
+    let indices = references
+        .iter()
+        .enumerate()
+        .map(|(i, reference)| (reference.name.split_ascii_whitespace().next().unwrap_or_default(), i))
+        .collect::<HashMap<_, _>>();
+    let mut overrides = vec![None; references.len()];
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line_number = line_number + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+        let [name, values @ ..] = fields.as_slice() else {
+            unreachable!("Splitting always yields at least one field")
+        };
+        let [matching, mismatch, gap_open, gap_extend] = values else {
+            return Err(invalid(format!(
+                "Expected five columns on line {line_number}, but found {}",
+                fields.len()
+            )));
+        };
+
+        let parse = |column: &str, value: &str| {
+            value.parse::<u8>().ok().filter(|value| *value <= 127).ok_or_else(|| {
+                invalid(format!(
+                    "The {column} '{value}' on line {line_number} is not an integer between 0 and 127"
+                ))
+            })
+        };
+        let params = RefParams {
+            matching:   parse("match score", matching)?,
+            mismatch:   parse("mismatch penalty", mismatch)?,
+            gap_open:   parse("gap open penalty", gap_open)?,
+            gap_extend: parse("gap extend penalty", gap_extend)?,
+        };
+
+        if params.gap_open < params.gap_extend {
+            return Err(invalid(format!(
+                "The gap open penalty is smaller than the gap extend penalty on line {line_number}"
+            )));
+        }
+
+        let Some(&index) = indices.get(name) else {
+            return Err(invalid(format!(
+                "The reference '{name}' on line {line_number} is not among the references"
+            )));
+        };
+        if overrides[index].replace(params).is_some() {
+            return Err(invalid(format!("The reference '{name}' is repeated on line {line_number}")));
+        }
+    }
+
+    Ok(overrides)
+}
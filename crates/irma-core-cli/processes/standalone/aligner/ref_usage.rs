@@ -0,0 +1,170 @@
+//! Per-reference usage statistics accumulated from the best matches in
+//! `aligner`, for `--ref-usage`
+
+use crate::aligner::{AlignmentAndSeqs, Strand, footer::FooterWriter};
+use irma_records::io::OutputOptions;
+use std::{
+    borrow::Cow,
+    io::Write,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+use zoe::{data::fasta::FastaSeq, prelude::NucleotidesView};
+
+/// The column names written as the first line of the reference usage output.
+const REF_USAGE_HEADER: &str = "#reference\tlength\tselected\tmean_identity";
+
+/// The usage of a single reference.
+#[derive(Copy, Clone, Debug, Default)]
+struct RefCounts {
+    /// The number of queries for which the reference was the best match
+    selected:     u64,
+    /// The sum of the identities of those best matches
+    identity_sum: f64,
+}
+
+/// The usage of each reference as the best match, accumulated as alignments
+/// are produced.
+///
+/// Each thread of the pool tallies into its own counters, which are merged when
+/// the table is written, so that the threads do not contend with one another.
+pub struct RefUsage<'r> {
+    references: Vec<&'r FastaSeq>,
+    /// The counters of each thread, indexed by the thread's index within the
+    /// pool, with a counter for every reference
+    threads:    Vec<Mutex<Vec<RefCounts>>>,
+}
+
+impl<'r> RefUsage<'r> {
+    /// Creates empty counters for each of the `references` aligned against,
+    /// for every thread of the current pool.
+    pub fn new(references: &'r [FastaSeq]) -> Self {
+        let references = references.iter().collect::<Vec<_>>();
+        let threads = std::iter::repeat_with(|| Mutex::new(vec![RefCounts::default(); references.len()]))
+            .take(rayon::current_num_threads().max(1))
+            .collect();
+        Self { references, threads }
+    }
+
+    /// Tallies the best match of a query against its reference, along with the
+    /// identity of the alignment. Unmapped alignments (including those with a
+    /// score of zero, which are written as unmapped) are skipped.
+    pub fn add(&self, best_alignment: &AlignmentAndSeqs<'_, 'r>) {
+        let Some(identity) = identity(best_alignment) else {
+            return;
+        };
+
+        // The references are matched by address, as in the pileup, since names
+        // need not be unique
+        let Some(index) = self
+            .references
+            .iter()
+            .position(|reference| std::ptr::eq(*reference, best_alignment.reference))
+        else {
+            return;
+        };
+
+        // Outside of the pool (such as with `dev_no_rayon`), the first
+        // counters are used
+        let thread = rayon::current_thread_index().unwrap_or(0) % self.threads.len();
+        let mut counts = self.threads[thread].lock().unwrap_or_else(PoisonError::into_inner);
+        counts[index].selected += 1;
+        counts[index].identity_sum += identity;
+    }
+
+    /// Merges the counters of every thread and writes them as a tab-delimited
+    /// table to `path`, with one line per reference (in reference order).
+    ///
+    /// `selected` is the number of queries for which the reference was the
+    /// best match, and `mean_identity` is the mean identity of those
+    /// alignments (or `*` if the reference was never selected). References
+    /// that were never selected are still written, so that they can be pruned
+    /// from the panel. With `footer`, the table ends with a footer (see
+    /// [`FooterWriter`]).
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors from opening or writing to the file are propagated.
+    pub fn write_to(&self, path: &Path, footer: bool) -> std::io::Result<()> {
+        let mut merged = vec![RefCounts::default(); self.references.len()];
+        for counts in &self.threads {
+            let counts = counts.lock().unwrap_or_else(PoisonError::into_inner);
+            for (merged, counts) in merged.iter_mut().zip(counts.iter()) {
+                merged.selected += counts.selected;
+                merged.identity_sum += counts.identity_sum;
+            }
+        }
+
+        let mut writer = OutputOptions::new_from_opt_path(Some(path)).use_file_zip_or_stdout().open()?;
+        writeln!(writer, "{REF_USAGE_HEADER}")?;
+        let mut writer = FooterWriter::new(writer, footer);
+
+        for (reference, counts) in self.references.iter().zip(&merged) {
+            let name = reference.name.split_ascii_whitespace().next().unwrap_or("*");
+            write!(
+                writer,
+                "{name}\t{length}\t{selected}\t",
+                length = reference.sequence.len(),
+                selected = counts.selected
+            )?;
+
+            if counts.selected == 0 {
+                writeln!(writer, "*")?;
+            } else {
+                let mean = counts.identity_sum / counts.selected as f64;
+                writeln!(writer, "{mean:.4}")?;
+            }
+        }
+
+        writer.finish()
+    }
+}
+
+/// The identity of a mapped alignment: the fraction of its columns (aligned
+/// bases, inserted bases, and deleted bases) where the query and reference
+/// bases match, ignoring case. `None` is returned for unmapped alignments.
+fn identity(alignment: &AlignmentAndSeqs<'_, '_>) -> Option<f64> {
+    let mapping = alignment.mapping.as_ref().filter(|mapping| mapping.inner.score > 0)?;
+
+    let sequence = match mapping.strand {
+        Strand::Forward => Cow::Borrowed(alignment.query.sequence.as_slice()),
+        Strand::Reverse => Cow::Owned(
+            NucleotidesView::from(alignment.query.sequence.as_slice())
+                .to_reverse_complement()
+                .into_vec(),
+        ),
+    };
+    let reference = &alignment.reference.sequence;
+
+    let mut ref_pos = mapping.inner.ref_range.start;
+    let mut query_pos = 0;
+    let mut matches = 0;
+    let mut columns = 0;
+
+    for ciglet in mapping.inner.states.iter() {
+        match ciglet.op {
+            b'M' | b'=' | b'X' => {
+                matches += sequence[query_pos..query_pos + ciglet.inc]
+                    .iter()
+                    .zip(&reference[ref_pos..ref_pos + ciglet.inc])
+                    .filter(|(query_base, ref_base)| query_base.eq_ignore_ascii_case(ref_base))
+                    .count();
+                columns += ciglet.inc;
+                ref_pos += ciglet.inc;
+                query_pos += ciglet.inc;
+            }
+            b'D' | b'N' => {
+                columns += ciglet.inc;
+                ref_pos += ciglet.inc;
+            }
+            b'I' => {
+                columns += ciglet.inc;
+                query_pos += ciglet.inc;
+            }
+            b'S' => query_pos += ciglet.inc,
+            _ => {}
+        }
+    }
+
+    (columns > 0).then(|| matches as f64 / columns as f64)
+}
@@ -0,0 +1,144 @@
+//! Sample sheets for `--sample-sheet`, which align the queries of many samples
+//! against the same references in a single invocation.
+
+use crate::aligner::{footer::FooterWriter, tallies::AllTallies};
+use irma_records::io::{OutputOptions, ValidatePaths};
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// A query file to align, along with where its alignments are written.
+pub struct Sample {
+    /// The name of the sample, used in the summary
+    pub name:       String,
+    /// The path to the FASTQ or FASTA file containing the queries
+    pub query_file: PathBuf,
+    /// The output path for the alignments, or `None` for stdout
+    pub output:     Option<PathBuf>,
+}
+
+/// Reads the samples listed in a tab-delimited sample sheet, with the sample
+/// name, the query path, and an optional output path on each line. Blank lines
+/// and lines starting with `#` are skipped.
+///
+/// Relative paths are resolved against the directory containing the sample
+/// sheet. If the output path is omitted, alignments are written to
+/// `<name>.sam` in that directory.
+///
+/// ## Errors
+///
+/// Errors are returned if the sample sheet cannot be read, if it lists no
+/// samples, if a line has fewer than two or more than three columns, or if a
+/// sample name is empty or repeated. Each contains the sample sheet path as
+/// context.
+pub fn read_sample_sheet(path: &Path) -> std::io::Result<Vec<Sample>> {
+    let invalid = |msg: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{msg} in the sample sheet: {path}", path = path.display()),
+        )
+    };
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to read the sample sheet: {}\n{e}", path.display())))?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut samples = Vec::new();
+    let mut names = HashSet::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line_number = line_number + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+        let (name, query_file, output) = match fields.as_slice() {
+            [name, query_file] => (*name, *query_file, None),
+            [name, query_file, output] if !output.is_empty() => (*name, *query_file, Some(*output)),
+            [name, query_file, _] => (*name, *query_file, None),
+            _ => {
+                return Err(invalid(format!(
+                    "Expected two or three columns on line {line_number}, but found {}",
+                    fields.len()
+                )));
+            }
+        };
+
+        if name.is_empty() || query_file.is_empty() {
+            return Err(invalid(format!("Missing sample name or query path on line {line_number}")));
+        }
+        if !names.insert(name.to_string()) {
+            return Err(invalid(format!("The sample name '{name}' is repeated on line {line_number}")));
+        }
+
+        let output = output.map_or_else(|| base_dir.join(format!("{name}.sam")), |output| base_dir.join(output));
+        samples.push(Sample {
+            name:       name.to_string(),
+            query_file: base_dir.join(query_file),
+            output:     Some(output),
+        });
+    }
+
+    if samples.is_empty() {
+        return Err(invalid("No samples were found".to_string()));
+    }
+
+    Ok(samples)
+}
+
+/// The paths read and written by a run over a sample sheet, which are only
+/// known once it has been read.
+pub struct SampleSheetPaths {
+    pub inputs:  Vec<PathBuf>,
+    pub outputs: Vec<PathBuf>,
+}
+
+impl ValidatePaths for SampleSheetPaths {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        &self.outputs
+    }
+}
+
+/// Writes a tab-delimited summary of each sample to stdout, with a header. The
+/// columns are the sample name, the query and output paths, and the number of
+/// queries and alignments, followed by the number of queries near the cutoff if
+/// `--top-k-refs` was used. With `footer`, the summary ends with a footer (see
+/// [`FooterWriter`]).
+pub fn write_summary(samples: &[Sample], tallies: &[AllTallies], top_k_refs: bool, footer: bool) -> std::io::Result<()> {
+    let mut writer = OutputOptions::new_stdout().open()?;
+
+    write!(writer, "sample\tquery_file\toutput\tnum_queries\tnum_alignments")?;
+    if top_k_refs {
+        write!(writer, "\tqueries_near_cutoff")?;
+    }
+    writeln!(writer)?;
+    let mut writer = FooterWriter::new(writer, footer);
+
+    for (sample, tallies) in samples.iter().zip(tallies) {
+        write!(
+            writer,
+            "{name}\t{query_file}\t{output}\t{num_queries}\t{num_alignments}",
+            name = sample.name,
+            query_file = sample.query_file.display(),
+            output = sample
+                .output
+                .as_ref()
+                .map_or("-".into(), |output| output.display().to_string()),
+            num_queries = tallies.num_queries,
+            num_alignments = tallies.num_alignments
+        )?;
+        if top_k_refs {
+            write!(writer, "\t{}", tallies.queries_near_cutoff)?;
+        }
+        writeln!(writer)?;
+    }
+
+    writer.finish()
+}
@@ -0,0 +1,167 @@
+//! Normalization of alignment scores for comparison across references of
+//! different lengths
+
+use crate::aligner::{AlignmentAndSeqs, arg_parsing::AnyMatrix};
+use clap::{ValueEnum, builder::PossibleValue};
+use std::fmt::Display;
+use zoe::{alignment::Alignment, data::WeightMatrix};
+
+/// The Karlin-Altschul `K` parameter used for bit scores.
+///
+/// Unlike `lambda`, this is not estimated from the weight matrix, so bit scores
+/// are only approximately comparable across scoring schemes (as documented for
+/// `--normalize-scores`). Since it only shifts every bit score by the same
+/// amount, it does not affect which reference is selected by `--best-match`.
+const KARLIN_K: f64 = 0.1;
+
+/// The residues over which `lambda` is estimated for DNA, each with equal
+/// background frequency.
+const DNA_RESIDUES: &[u8] = b"ACGT";
+
+/// The residues over which `lambda` is estimated for protein, each with equal
+/// background frequency.
+const AA_RESIDUES: &[u8] = b"ARNDCQEGHILKMFPSTWYV";
+
+/// A clap enum for specifying how alignment scores are normalized.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ScoreNormalization {
+    Length,
+    Bitscore,
+}
+
+impl Display for ScoreNormalization {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScoreNormalization::Length => write!(f, "length"),
+            ScoreNormalization::Bitscore => write!(f, "bitscore"),
+        }
+    }
+}
+
+impl ValueEnum for ScoreNormalization {
+    #[inline]
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Length, Self::Bitscore]
+    }
+
+    #[inline]
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Length => Some(PossibleValue::new("length")),
+            Self::Bitscore => Some(PossibleValue::new("bitscore").alias("bits")),
+        }
+    }
+}
+
+/// The parameters needed to normalize alignment scores, as selected by
+/// `--normalize-scores`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ScoreNormalizer {
+    /// The score per alignment column (matches, mismatches, and gaps)
+    Length,
+    /// The Karlin-Altschul bit score, using the `lambda` estimated for the
+    /// weight matrix
+    Bitscore { lambda: f64 },
+}
+
+impl ScoreNormalizer {
+    /// Creates the normalizer for `normalization`, estimating `lambda` from the
+    /// weight matrix if needed. `None` is returned if `lambda` does not exist
+    /// for the matrix (see [`estimate_lambda`]).
+    pub fn new(normalization: ScoreNormalization, weight_matrix: &AnyMatrix<'_, i8>) -> Option<Self> {
+        match normalization {
+            ScoreNormalization::Length => Some(ScoreNormalizer::Length),
+            ScoreNormalization::Bitscore => {
+                let lambda = match weight_matrix {
+                    AnyMatrix::Dna(matrix) => estimate_lambda(matrix, DNA_RESIDUES),
+                    AnyMatrix::AaNamed(matrix) => estimate_lambda(matrix, AA_RESIDUES),
+                    AnyMatrix::AaSimple(matrix) => estimate_lambda(matrix, AA_RESIDUES),
+                }?;
+                Some(ScoreNormalizer::Bitscore { lambda })
+            }
+        }
+    }
+
+    /// Computes the normalized score reported for a mapped alignment.
+    pub fn normalize(&self, alignment: &Alignment<u32>) -> f64 {
+        let score = f64::from(alignment.score);
+        match self {
+            ScoreNormalizer::Length => {
+                let columns = alignment
+                    .states
+                    .iter()
+                    .filter(|ciglet| matches!(ciglet.op, b'M' | b'=' | b'X' | b'I' | b'D'))
+                    .map(|ciglet| ciglet.inc)
+                    .sum::<usize>();
+                score / columns.max(1) as f64
+            }
+            ScoreNormalizer::Bitscore { lambda } => (lambda * score - KARLIN_K.ln()) / std::f64::consts::LN_2,
+        }
+    }
+
+    /// Computes the key used to select the best alignment with `--best-match`,
+    /// where larger is better. Unmapped alignments have a key of negative
+    /// infinity.
+    ///
+    /// For [`Length`], this is the normalized score. For [`Bitscore`], the bit
+    /// score alone is proportional to the raw score, so the search space of
+    /// the query and reference is also accounted for: the key is `-log2(E)`
+    /// for the E-value `E = m * n * 2^-S'`.
+    ///
+    /// [`Length`]: ScoreNormalizer::Length
+    /// [`Bitscore`]: ScoreNormalizer::Bitscore
+    pub fn selection_key(&self, alignment: &AlignmentAndSeqs<'_, '_>) -> f64 {
+        let Some(mapping) = alignment.mapping.as_ref().filter(|mapping| mapping.inner.score > 0) else {
+            return f64::NEG_INFINITY;
+        };
+
+        let normalized = self.normalize(&mapping.inner);
+        match self {
+            ScoreNormalizer::Length => normalized,
+            ScoreNormalizer::Bitscore { .. } => {
+                let search_space = alignment.query.sequence.len() as f64 * alignment.reference.sequence.len() as f64;
+                normalized - search_space.log2()
+            }
+        }
+    }
+}
+
+/// Estimates the ungapped Karlin-Altschul `lambda` for a weight matrix, assuming
+/// equal background frequencies for `residues`.
+///
+/// `lambda` is the unique positive solution to `sum(p_i * p_j * e^(lambda *
+/// s_ij)) = 1`, which is found by bisection. `None` is returned if no solution
+/// exists, which happens when the expected score is non-negative or no score
+/// is positive.
+fn estimate_lambda<const S: usize>(matrix: &WeightMatrix<'_, i8, S>, residues: &[u8]) -> Option<f64> {
+    let frequency = 1.0 / residues.len() as f64;
+    let scores = residues
+        .iter()
+        .flat_map(|&r| residues.iter().map(move |&q| f64::from(matrix.get_weight(r, q))))
+        .collect::<Vec<_>>();
+
+    let expected = scores.iter().sum::<f64>() * frequency * frequency;
+    if expected >= 0.0 || scores.iter().all(|&score| score <= 0.0) {
+        return None;
+    }
+
+    let f = |lambda: f64| scores.iter().map(|score| (lambda * score).exp()).sum::<f64>() * frequency * frequency - 1.0;
+
+    let mut high = 0.5;
+    while f(high) < 0.0 {
+        high *= 2.0;
+    }
+    let mut low = 0.0;
+
+    for _ in 0..100 {
+        let mid = (low + high) / 2.0;
+        if f(mid) < 0.0 {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+
+    Some((low + high) / 2.0)
+}
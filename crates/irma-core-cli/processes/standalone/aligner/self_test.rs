@@ -0,0 +1,321 @@
+//! Built-in alignment cases for `--self-test`, which check the aligner against
+//! known scores and CIGAR strings. A miscompiled build (such as one targeting
+//! SIMD features the host does not support correctly) is then caught at deploy
+//! time, rather than producing wrong alignments silently.
+
+use crate::aligner::{AlignmentAndSeqs, QueryWithProfile, QueryWithRc, Reference, Strand};
+use irma_records::io::FastX;
+use std::fmt::Display;
+use zoe::{
+    data::{
+        fasta::FastaSeq,
+        matrices::{BLOSUM_62, WeightMatrix},
+    },
+    prelude::NucleotidesView,
+};
+
+/// The gap open weight used by every case, matching the aligner's default.
+const GAP_OPEN: i8 = -10;
+
+/// The gap extend weight used by every case, matching the aligner's default.
+const GAP_EXTEND: i8 = -1;
+
+/// A short DNA reference shared by most of the DNA cases.
+const DNA_REF: &[u8] = b"ACGTTGCAAGGCTTACCGATCGGATCCATGCAAGTCTAGC";
+
+/// A short protein reference shared by the protein cases.
+const AA_REF: &[u8] = b"GSWDEMKTAYIAKQRPLNHF";
+
+/// The weight matrix a case is aligned with.
+#[derive(Copy, Clone, Debug)]
+enum CaseMatrix {
+    /// The default DNA matrix (match 2, mismatch -5), optionally scoring any
+    /// base involving `N` as 0
+    Dna { ignore_n: bool },
+    /// The BLOSUM62 protein matrix
+    Blosum62,
+}
+
+/// The summary of an alignment that a case is checked against.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct Summary {
+    score:  u32,
+    /// The 1-based position on the reference, as in SAM
+    pos:    usize,
+    cigar:  String,
+    strand: Strand,
+}
+
+impl Summary {
+    /// Summarizes an alignment, or returns `None` if it is unmapped.
+    fn new(alignment: &AlignmentAndSeqs) -> Option<Self> {
+        alignment.mapping.as_ref().map(|mapping| Summary {
+            score:  mapping.inner.score,
+            pos:    mapping.inner.ref_range.start + 1,
+            cigar:  mapping.inner.states.to_cigar_unchecked().to_string(),
+            strand: mapping.strand,
+        })
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let strand = match self.strand {
+            Strand::Forward => "forward",
+            Strand::Reverse => "reverse",
+        };
+        write!(
+            f,
+            "score {}, POS {}, CIGAR {}, {strand} strand",
+            self.score, self.pos, self.cigar
+        )
+    }
+}
+
+/// A single alignment case with its expected result.
+struct Case {
+    name:      &'static str,
+    matrix:    CaseMatrix,
+    reference: Vec<u8>,
+    query:     Vec<u8>,
+    rev_comp:  bool,
+    expected:  Summary,
+}
+
+impl Case {
+    /// Creates a case expecting a forward alignment.
+    fn new(name: &'static str, matrix: CaseMatrix, reference: &[u8], query: Vec<u8>, expected: (u32, usize, &str)) -> Self {
+        let (score, pos, cigar) = expected;
+        Case {
+            name,
+            matrix,
+            reference: reference.to_vec(),
+            query,
+            rev_comp: false,
+            expected: Summary {
+                score,
+                pos,
+                cigar: cigar.to_string(),
+                strand: Strand::Forward,
+            },
+        }
+    }
+}
+
+/// Concatenates several pieces of sequence.
+fn join(pieces: &[&[u8]]) -> Vec<u8> {
+    pieces.concat()
+}
+
+/// Replaces the base at `index` with a different one.
+fn substitute(seq: &[u8], index: usize) -> Vec<u8> {
+    let mut seq = seq.to_vec();
+    seq[index] = match seq[index] {
+        b'A' => b'C',
+        b'C' => b'G',
+        b'G' => b'T',
+        _ => b'A',
+    };
+    seq
+}
+
+/// Replaces the base at `index` with `N`.
+fn mask(seq: &[u8], index: usize) -> Vec<u8> {
+    let mut seq = seq.to_vec();
+    seq[index] = b'N';
+    seq
+}
+
+/// Generates a reproducible pseudorandom DNA sequence, for cases needing long
+/// sequences.
+fn pseudorandom_dna(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    (0..len)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"ACGT"[(state >> 62) as usize]
+        })
+        .collect()
+}
+
+/// The built-in cases. The long cases produce scores that do not fit in `i8`
+/// or `i16`, so that the wider SIMD profiles are also checked.
+fn cases() -> Vec<Case> {
+    let dna = CaseMatrix::Dna { ignore_n: false };
+    let dna_ignore_n = CaseMatrix::Dna { ignore_n: true };
+    let long_ref = pseudorandom_dna(17_000);
+
+    let mut reverse = Case::new(
+        "dna-reverse-strand",
+        dna,
+        DNA_REF,
+        NucleotidesView::from(&DNA_REF[5..25]).to_reverse_complement().into_vec(),
+        (40, 6, "20M"),
+    );
+    reverse.rev_comp = true;
+    reverse.expected.strand = Strand::Reverse;
+
+    vec![
+        Case::new("dna-exact", dna, DNA_REF, DNA_REF[5..25].to_vec(), (40, 6, "20M")),
+        Case::new("dna-mismatch", dna, DNA_REF, substitute(&DNA_REF[5..35], 15), (53, 6, "30M")),
+        Case::new(
+            "dna-deletion",
+            dna,
+            DNA_REF,
+            join(&[&DNA_REF[2..20], &DNA_REF[23..38]]),
+            (54, 3, "18M3D15M"),
+        ),
+        Case::new(
+            "dna-insertion",
+            dna,
+            DNA_REF,
+            join(&[&DNA_REF[2..20], b"AA", &DNA_REF[20..38]]),
+            (61, 3, "18M2I18M"),
+        ),
+        Case::new(
+            "dna-soft-clip",
+            dna,
+            DNA_REF,
+            join(&[b"TTTTT", &DNA_REF[10..30], b"TTTTT"]),
+            (40, 11, "5S20M5S"),
+        ),
+        reverse,
+        Case::new("dna-n-mismatch", dna, DNA_REF, mask(&DNA_REF[5..25], 10), (33, 6, "20M")),
+        Case::new(
+            "dna-ignore-n",
+            dna_ignore_n,
+            DNA_REF,
+            mask(&DNA_REF[5..25], 10),
+            (38, 6, "20M"),
+        ),
+        Case::new(
+            "dna-long-i16",
+            dna,
+            &long_ref[..400],
+            long_ref[100..300].to_vec(),
+            (400, 101, "200M"),
+        ),
+        Case::new(
+            "dna-long-i32",
+            dna,
+            &long_ref,
+            long_ref[200..16_700].to_vec(),
+            (33_000, 201, "16500M"),
+        ),
+        Case::new(
+            "aa-exact",
+            CaseMatrix::Blosum62,
+            AA_REF,
+            AA_REF[5..15].to_vec(),
+            (49, 6, "10M"),
+        ),
+        Case::new(
+            "aa-substitution",
+            CaseMatrix::Blosum62,
+            AA_REF,
+            b"MKTAYLAKQR".to_vec(),
+            (47, 6, "10M"),
+        ),
+    ]
+}
+
+/// Aligns a case with each combination of the 1-pass or 3-pass algorithm and
+/// the query or reference profile, returning the name of each method alongside
+/// the summary of its alignment (or `None` if unmapped).
+///
+/// ## Errors
+///
+/// Errors from building the profiles or aligning are propagated.
+fn align_each_method<const S: usize>(
+    case: &Case, matrix: &WeightMatrix<'_, i8, S>,
+) -> std::io::Result<Vec<(&'static str, Option<Summary>)>> {
+    let reference = FastaSeq {
+        name:     case.name.to_string(),
+        sequence: case.reference.clone(),
+    };
+    let query = FastX {
+        header:   case.name.to_string(),
+        sequence: case.query.clone(),
+        quality:  None,
+    };
+
+    let profiled_ref = Reference::new(&reference, matrix, GAP_OPEN, GAP_EXTEND, case.rev_comp, 1)?;
+    let profiled_query = QueryWithProfile::new(&query, matrix, GAP_OPEN, GAP_EXTEND, 1)?;
+    let query_with_rc = QueryWithRc::new(&query, case.rev_comp);
+
+    Ok(vec![
+        (
+            "1pass query profile",
+            Summary::new(&profiled_query.sw_1pass_query_profile(&profiled_ref)?),
+        ),
+        (
+            "3pass query profile",
+            Summary::new(&profiled_query.sw_3pass_query_profile(&profiled_ref)?),
+        ),
+        (
+            "1pass reference profile",
+            Summary::new(&profiled_ref.sw_1pass_ref_profile(&query_with_rc)?),
+        ),
+        (
+            "3pass reference profile",
+            Summary::new(&profiled_ref.sw_3pass_ref_profile(&query_with_rc)?),
+        ),
+    ])
+}
+
+/// Runs the built-in cases for `--self-test`, printing whether each passed to
+/// stdout. A case passes if every alignment method produces the expected
+/// score, position, CIGAR string, and strand.
+///
+/// ## Errors
+///
+/// An error is returned if any case fails, along with any errors from building
+/// the profiles or aligning.
+pub fn run_self_test() -> std::io::Result<()> {
+    let cases = cases();
+    let mut num_failed = 0;
+
+    for case in &cases {
+        let results = match case.matrix {
+            CaseMatrix::Dna { ignore_n } => {
+                let matrix = WeightMatrix::new_dna_matrix(2, -5, ignore_n.then_some(b'N'));
+                align_each_method(case, &matrix)?
+            }
+            CaseMatrix::Blosum62 => align_each_method(case, &BLOSUM_62)?,
+        };
+
+        let failures = results
+            .into_iter()
+            .filter(|(_, summary)| summary.as_ref() != Some(&case.expected))
+            .collect::<Vec<_>>();
+
+        if failures.is_empty() {
+            println!("PASS  {}", case.name);
+        } else {
+            num_failed += 1;
+            println!("FAIL  {}", case.name);
+            for (method, summary) in failures {
+                let observed = summary.map_or_else(|| "unmapped".to_string(), |summary| summary.to_string());
+                println!("      {method}: expected {}, got {observed}", case.expected);
+            }
+        }
+    }
+
+    let num_cases = cases.len();
+    println!(
+        "{passed} of {num_cases} aligner self-test cases passed ({arch})",
+        passed = num_cases - num_failed,
+        arch = std::env::consts::ARCH
+    );
+
+    if num_failed > 0 {
+        Err(std::io::Error::other(format!(
+            "{num_failed} of {num_cases} aligner self-test cases failed"
+        )))
+    } else {
+        Ok(())
+    }
+}
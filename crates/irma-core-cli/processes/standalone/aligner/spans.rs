@@ -0,0 +1,233 @@
+//! Coarse timing spans for `--profile-spans`, recorded per thread and written
+//! as a Chrome trace
+
+use irma_records::io::OutputOptions;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// The source of the small integer IDs given to threads in the trace.
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The ID of the current thread in the trace.
+    static THREAD_ID: usize = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The stage of the aligner covered by a span.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SpanKind {
+    /// Reading and parsing queries or references
+    Read,
+    /// Building the profiles for alignment
+    ProfileBuild,
+    /// Performing an alignment
+    Align,
+    /// Formatting and writing alignments
+    Write,
+}
+
+impl SpanKind {
+    /// The name of the span in the trace.
+    fn name(self) -> &'static str {
+        match self {
+            SpanKind::Read => "read",
+            SpanKind::ProfileBuild => "profile-build",
+            SpanKind::Align => "align",
+            SpanKind::Write => "write",
+        }
+    }
+}
+
+/// A completed span.
+struct Span {
+    kind:     SpanKind,
+    thread:   usize,
+    start:    Duration,
+    duration: Duration,
+}
+
+/// The spans recorded so far, along with the name of each thread that recorded
+/// them.
+#[derive(Default)]
+struct SpanLog {
+    spans:   Vec<Span>,
+    threads: BTreeMap<usize, String>,
+}
+
+/// Records timing spans from any number of threads, for writing to the path
+/// given by `--profile-spans`.
+///
+/// Each span takes a lock, so spans should cover coarse units of work (such as
+/// a single alignment) rather than inner loops.
+pub struct SpanRecorder {
+    path:  PathBuf,
+    start: Instant,
+    log:   Mutex<SpanLog>,
+}
+
+impl SpanRecorder {
+    /// Creates a new [`SpanRecorder`] writing to `path`. Span start times are
+    /// relative to when this is called.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            start: Instant::now(),
+            log: Mutex::new(SpanLog::default()),
+        }
+    }
+
+    /// Runs `f`, recording a span of `kind` on the current thread.
+    pub fn time<T>(&self, kind: SpanKind, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        let duration = start.elapsed();
+
+        let thread = THREAD_ID.with(|id| *id);
+        // A poisoned lock only means another thread panicked mid-push, which
+        // will surface on its own
+        let mut log = self.log.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        log.threads.entry(thread).or_insert_with(|| {
+            std::thread::current()
+                .name()
+                .map_or_else(|| format!("thread-{thread}"), str::to_string)
+        });
+        log.spans.push(Span {
+            kind,
+            thread,
+            start: start.duration_since(self.start),
+            duration,
+        });
+
+        out
+    }
+
+    /// Writes the recorded spans as a [Chrome trace], viewable in Perfetto or
+    /// `chrome://tracing`. Each span is a complete (`X`) event, and each thread
+    /// is named with a metadata (`M`) event.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors when creating or writing the file are propagated, with the
+    /// path as context.
+    ///
+    /// [Chrome trace]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+    pub fn write_chrome_trace(&self) -> std::io::Result<()> {
+        let log = self.log.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        let thread_names = log.threads.iter().map(|(&thread, name)| TraceEvent {
+            name: "thread_name",
+            cat:  None,
+            ph:   "M",
+            ts:   None,
+            dur:  None,
+            pid:  1,
+            tid:  thread,
+            args: Some(TraceArgs { name }),
+        });
+        let spans = log.spans.iter().map(|span| TraceEvent {
+            name: span.kind.name(),
+            cat:  Some("aligner"),
+            ph:   "X",
+            ts:   Some(micros(span.start)),
+            dur:  Some(micros(span.duration)),
+            pid:  1,
+            tid:  span.thread,
+            args: None,
+        });
+
+        let trace = Trace {
+            trace_events:      thread_names.chain(spans).collect(),
+            display_time_unit: "ms",
+        };
+
+        let mut writer = OutputOptions::new_from_path(&self.path).use_file().open()?;
+        serde_json::to_writer(&mut writer, &trace).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+/// Runs a closure while recording a span, if span recording is enabled.
+pub trait TimeSpan {
+    /// Runs `f`, recording a span of `kind` if a [`SpanRecorder`] is present.
+    fn time<T>(&self, kind: SpanKind, f: impl FnOnce() -> T) -> T;
+}
+
+impl TimeSpan for Option<SpanRecorder> {
+    #[inline]
+    fn time<T>(&self, kind: SpanKind, f: impl FnOnce() -> T) -> T {
+        match self {
+            Some(recorder) => recorder.time(kind, f),
+            None => f(),
+        }
+    }
+}
+
+/// An iterator recording a [`SpanKind::Read`] span for each item it yields,
+/// such as the queries.
+pub struct TimedReader<'a, I> {
+    iter:  I,
+    spans: &'a Option<SpanRecorder>,
+}
+
+impl<'a, I> TimedReader<'a, I> {
+    /// Wraps `iter`, recording spans in `spans` if it is present.
+    pub fn new(iter: I, spans: &'a Option<SpanRecorder>) -> Self {
+        Self { iter, spans }
+    }
+}
+
+impl<I: Iterator> Iterator for TimedReader<'_, I> {
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.spans.time(SpanKind::Read, || self.iter.next())
+    }
+}
+
+/// Converts a duration to fractional microseconds, the time unit of Chrome
+/// traces.
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+/// The top-level object of a Chrome trace.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace<'a> {
+    trace_events:      Vec<TraceEvent<'a>>,
+    display_time_unit: &'static str,
+}
+
+/// A single event in a Chrome trace.
+#[derive(Serialize)]
+struct TraceEvent<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cat:  Option<&'static str>,
+    ph:   &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts:   Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur:  Option<f64>,
+    pid:  u32,
+    tid:  usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<TraceArgs<'a>>,
+}
+
+/// The arguments of a thread name metadata event.
+#[derive(Serialize)]
+struct TraceArgs<'a> {
+    name: &'a str,
+}
@@ -0,0 +1,357 @@
+//! Parameter sweeps for `--sweep`, which align a subsample of the queries under
+//! each combination of scoring parameters and summarize the results, so that
+//! the parameters can be tuned without an external script looping over
+//! invocations.
+
+use crate::aligner::{
+    AlignmentMethod, QueryWithProfile, QueryWithRc, References, align_best_ref,
+    arg_parsing::{AlignerConfig, Alphabet, AnyMatrix},
+    footer::FooterWriter,
+    mask_softmasked,
+    normalize::NormalizedQueries,
+    tallies::{AlignmentTallies, QueryTallies, RefTallies, pick_alignment_method},
+};
+use irma_records::io::{FastX, InputOptions, OutputOptions};
+use std::{io::Write, path::Path};
+use zoe::data::{fasta::FastaSeq, matrices::WeightMatrix};
+
+#[cfg(not(feature = "dev_no_rayon"))]
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+/// The values of each scoring parameter given to `--sweep`. Parameters that
+/// are not swept are `None`, and take the value given on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct SweepSpec {
+    pub gap_open:   Option<Vec<u8>>,
+    pub gap_extend: Option<Vec<u8>>,
+    pub matching:   Option<Vec<u8>>,
+    pub mismatch:   Option<Vec<u8>>,
+}
+
+impl SweepSpec {
+    /// Whether the scores of a simple weight matrix are swept.
+    pub fn sweeps_scores(&self) -> bool {
+        self.matching.is_some() || self.mismatch.is_some()
+    }
+}
+
+/// Parses a `--sweep` specification from the command line.
+///
+/// The specification is a comma-separated list of `NAME=VALUES`, where `NAME`
+/// is `gap_open`, `gap_extend`, `matching`, or `mismatch`, and `VALUES` is a
+/// single value, an inclusive range `START..END`, or an inclusive range with a
+/// step `START..END:STEP`. Each value must be in [0, 127].
+pub fn parse_sweep_spec(value: &str) -> Result<SweepSpec, String> {
+    let mut spec = SweepSpec::default();
+
+    for entry in value.split(',').map(str::trim) {
+        let Some((name, values)) = entry.split_once('=') else {
+            return Err(format!("`{entry}` is not of the form NAME=VALUES"));
+        };
+
+        let field = match name.trim() {
+            "gap_open" => &mut spec.gap_open,
+            "gap_extend" => &mut spec.gap_extend,
+            "matching" => &mut spec.matching,
+            "mismatch" => &mut spec.mismatch,
+            name => {
+                return Err(format!(
+                    "`{name}` is not a parameter that can be swept. Expected one of gap_open, gap_extend, matching, or mismatch"
+                ));
+            }
+        };
+        if field.is_some() {
+            return Err(format!("The parameter `{name}` is given more than once"));
+        }
+        *field = Some(parse_sweep_values(values.trim())?);
+    }
+
+    Ok(spec)
+}
+
+/// Parses the values of a single swept parameter, which are either a single
+/// value, `START..END`, or `START..END:STEP`.
+fn parse_sweep_values(values: &str) -> Result<Vec<u8>, String> {
+    let parse_value = |value: &str| {
+        value
+            .parse::<u8>()
+            .ok()
+            .filter(|value| *value <= 127)
+            .ok_or_else(|| format!("`{value}` is not a valid value in [0, 127]"))
+    };
+
+    let Some((start, rest)) = values.split_once("..") else {
+        return Ok(vec![parse_value(values)?]);
+    };
+    let (end, step) = match rest.split_once(':') {
+        Some((end, step)) => (end, step),
+        None => (rest, "1"),
+    };
+
+    let start = parse_value(start)?;
+    let end = parse_value(end)?;
+    let step = step
+        .parse::<usize>()
+        .ok()
+        .filter(|step| *step > 0)
+        .ok_or_else(|| format!("`{step}` is not a valid, positive step"))?;
+
+    if start > end {
+        return Err(format!("The range `{values}` is empty"));
+    }
+
+    Ok((start..=end).step_by(step).collect())
+}
+
+/// A single combination of scoring parameters in a sweep, given as
+/// nonnegative penalties as on the command line. `matching` and `mismatch` are
+/// `None` when a named substitution matrix is used.
+#[derive(Copy, Clone, Debug)]
+pub struct SweepParams {
+    pub gap_open:   u8,
+    pub gap_extend: u8,
+    pub matching:   Option<u8>,
+    pub mismatch:   Option<u8>,
+}
+
+/// The parsed and validated configuration for `--sweep`.
+pub struct Sweep {
+    /// The combinations of parameters to align under
+    pub combinations: Vec<SweepParams>,
+    /// The maximum number of queries to align under each combination
+    pub num_queries:  usize,
+    /// The alphabet, as given on the command line
+    pub alphabet:     Option<Alphabet>,
+    /// The name of the substitution matrix, as given on the command line
+    pub matrix:       Option<String>,
+    /// Whether any base involving `N` has a score of 0
+    pub ignore_n:     bool,
+}
+
+impl Sweep {
+    /// Forms every combination of the swept values, with the parameters that
+    /// are not swept taken from `defaults`. Combinations where the gap open
+    /// penalty is less than the gap extend penalty are skipped, with a warning
+    /// giving their number.
+    pub fn new(
+        spec: SweepSpec, defaults: SweepParams, num_queries: usize, alphabet: Option<Alphabet>, matrix: Option<String>,
+        ignore_n: bool,
+    ) -> Self {
+        let values = |swept: Option<Vec<u8>>, default: u8| swept.unwrap_or_else(|| vec![default]);
+        let optional_values = |swept: Option<Vec<u8>>, default: Option<u8>| match swept {
+            Some(values) => values.into_iter().map(Some).collect(),
+            None => vec![default],
+        };
+
+        let gap_open = values(spec.gap_open, defaults.gap_open);
+        let gap_extend = values(spec.gap_extend, defaults.gap_extend);
+        let matching = optional_values(spec.matching, defaults.matching);
+        let mismatch = optional_values(spec.mismatch, defaults.mismatch);
+
+        let mut combinations = Vec::new();
+        let mut skipped = 0;
+        for &gap_open in &gap_open {
+            for &gap_extend in &gap_extend {
+                if gap_open < gap_extend {
+                    skipped += matching.len() * mismatch.len();
+                    continue;
+                }
+                for &matching in &matching {
+                    for &mismatch in &mismatch {
+                        combinations.push(SweepParams {
+                            gap_open,
+                            gap_extend,
+                            matching,
+                            mismatch,
+                        });
+                    }
+                }
+            }
+        }
+
+        if skipped > 0 {
+            eprintln!(
+                "Aligner Warning: {skipped} of {total} combinations in `--sweep` were skipped because the gap open penalty was less than the gap extend penalty.",
+                total = skipped + combinations.len()
+            );
+        }
+
+        Sweep {
+            combinations,
+            num_queries,
+            alphabet,
+            matrix,
+            ignore_n,
+        }
+    }
+}
+
+/// The outcome of aligning the queries under a single combination of
+/// parameters.
+#[derive(Copy, Clone, Debug, Default)]
+struct SweepSummary {
+    /// The number of queries aligned
+    num_queries: usize,
+    /// The number of queries with a mapped alignment to some reference
+    mapped:      usize,
+    /// The sum of the best scores of the mapped queries
+    total_score: u64,
+}
+
+/// Runs the parameter sweep, aligning the first `sweep.num_queries` queries in
+/// `query_file` against the `references` under each combination of parameters.
+/// The references and queries are only read once. A tab-delimited summary of
+/// each combination is written to stdout, with a header.
+///
+/// Each query is aligned against every reference, keeping the best alignment
+/// as with `--best-match`. The mean best score is over the mapped queries.
+///
+/// ## Errors
+///
+/// Errors while reading the queries, building the profiles, performing the
+/// alignments, and writing the summary are propagated.
+pub fn run_sweep(sweep: &Sweep, query_file: &Path, references: &[FastaSeq], config: &AlignerConfig) -> std::io::Result<()> {
+    let queries = InputOptions::new_from_path(query_file)
+        .use_file_or_zip()
+        .parse_fastx()
+        .open()?;
+    let queries = NormalizedQueries::new(queries, config.normalization, query_file, false)
+        .take(sweep.num_queries)
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let masked = config.respect_softmask.then(|| mask_softmasked(references));
+    let references = masked.as_deref().unwrap_or(references);
+
+    let mut writer = OutputOptions::new_stdout().open()?;
+    writeln!(
+        writer,
+        "gap_open\tgap_extend\tmatching\tmismatch\tnum_queries\tmapped\tmapped_fraction\tmean_best_score"
+    )?;
+    let mut writer = FooterWriter::new(writer, config.footer);
+
+    for params in &sweep.combinations {
+        // Validity: `--sweep` cannot vary the scores when `--matrix` is given,
+        // so `params.matching` and `params.mismatch` are `None` with a named
+        // matrix
+        let weight_matrix = AnyMatrix::parse_from_clap(
+            sweep.alphabet,
+            sweep.matrix.clone(),
+            params.matching,
+            params.mismatch,
+            sweep.ignore_n,
+        );
+        let summary = match weight_matrix {
+            AnyMatrix::Dna(weight_matrix) => align_combination(&queries, references, &weight_matrix, params, config)?,
+            AnyMatrix::AaNamed(weight_matrix) => align_combination(&queries, references, weight_matrix, params, config)?,
+            AnyMatrix::AaSimple(weight_matrix) => align_combination(&queries, references, &weight_matrix, params, config)?,
+        };
+
+        let optional = |value: Option<u8>| value.map_or("-".to_string(), |value| value.to_string());
+        let mapped_fraction = if summary.num_queries > 0 {
+            summary.mapped as f64 / summary.num_queries as f64
+        } else {
+            0.0
+        };
+        let mean_best_score = if summary.mapped > 0 {
+            format!("{:.2}", summary.total_score as f64 / summary.mapped as f64)
+        } else {
+            "-".to_string()
+        };
+
+        writeln!(
+            writer,
+            "{gap_open}\t{gap_extend}\t{matching}\t{mismatch}\t{num_queries}\t{mapped}\t{mapped_fraction:.4}\t{mean_best_score}",
+            gap_open = params.gap_open,
+            gap_extend = params.gap_extend,
+            matching = optional(params.matching),
+            mismatch = optional(params.mismatch),
+            num_queries = summary.num_queries,
+            mapped = summary.mapped,
+        )?;
+    }
+
+    writer.finish()
+}
+
+/// Aligns each query against every reference under a single combination of
+/// parameters, summarizing the best alignment of each.
+///
+/// ## Errors
+///
+/// Errors while building the profiles or performing the alignments are
+/// propagated, with context containing the header(s).
+fn align_combination<const S: usize>(
+    queries: &[FastX], references: &[FastaSeq], weight_matrix: &WeightMatrix<'static, i8, S>, params: &SweepParams,
+    config: &AlignerConfig,
+) -> std::io::Result<SweepSummary> {
+    let gap_open = -(params.gap_open as i8);
+    let gap_extend = -(params.gap_extend as i8);
+
+    let references = References::new(
+        references,
+        weight_matrix,
+        &[],
+        gap_open,
+        gap_extend,
+        config.rev_comp,
+        config.min_score,
+    )?;
+    let query_tallies = QueryTallies::default();
+    let ref_tallies = RefTallies::new(&references);
+    let alignment_tallies = AlignmentTallies::default();
+
+    let best_score = |query: &FastX| -> std::io::Result<Option<u32>> {
+        query_tallies.tally(&query.sequence);
+        let method = pick_alignment_method(&query_tallies, &ref_tallies, &alignment_tallies, config);
+
+        let best_alignment = match method {
+            AlignmentMethod::OnePassQueryProfile | AlignmentMethod::ThreePassQueryProfile => {
+                let query = QueryWithProfile::new(query, weight_matrix, gap_open, gap_extend, config.min_score)?;
+                align_best_ref(&references, None, |reference| {
+                    let alignment = if method.is_three_pass() {
+                        query.sw_3pass_query_profile(reference)?
+                    } else {
+                        query.sw_1pass_query_profile(reference)?
+                    };
+                    alignment_tallies.tally(&alignment, weight_matrix);
+                    Ok(alignment)
+                })?
+                .mapping
+            }
+            // Validity: `--sweep` conflicts with `--ungapped`, which only
+            // applies to the alignment methods without gaps
+            AlignmentMethod::OnePassRefProfile | AlignmentMethod::ThreePassRefProfile | AlignmentMethod::Ungapped => {
+                let query = QueryWithRc::new(query, config.rev_comp);
+                align_best_ref(&references, None, |reference| {
+                    let alignment = if method.is_three_pass() {
+                        reference.sw_3pass_ref_profile(&query)?
+                    } else {
+                        reference.sw_1pass_ref_profile(&query)?
+                    };
+                    alignment_tallies.tally(&alignment, weight_matrix);
+                    Ok(alignment)
+                })?
+                .mapping
+            }
+        };
+
+        Ok(best_alignment.map(|mapping| mapping.inner.score).filter(|score| *score > 0))
+    };
+
+    #[cfg(not(feature = "dev_no_rayon"))]
+    let best_scores = queries.par_iter().map(best_score).collect::<std::io::Result<Vec<_>>>()?;
+
+    #[cfg(feature = "dev_no_rayon")]
+    let best_scores = queries.iter().map(best_score).collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut summary = SweepSummary {
+        num_queries: queries.len(),
+        ..SweepSummary::default()
+    };
+    for score in best_scores.into_iter().flatten() {
+        summary.mapped += 1;
+        summary.total_score += u64::from(score);
+    }
+
+    Ok(summary)
+}
@@ -3,7 +3,10 @@ use crate::aligner::{
     arg_parsing::{AlignerConfig, NumPasses, WhichSequence},
 };
 use std::sync::atomic::{AtomicU64, Ordering};
-use zoe::{alignment::sw::max_score_for_int_type, data::WeightMatrix};
+use zoe::{
+    alignment::sw::max_score_for_int_type,
+    data::{WeightMatrix, fasta::FastaSeq},
+};
 
 /// A collection of non-blocking thread-safe tallies for the queries
 /// encountered.
@@ -13,6 +16,9 @@ pub struct QueryTallies {
     num_queries:         AtomicU64,
     /// The number of queries of length at most 300.
     queries_at_most_300: AtomicU64,
+    /// The number of queries for which `--top-k-refs` may have excluded the
+    /// best reference.
+    queries_near_cutoff: AtomicU64,
 }
 
 impl QueryTallies {
@@ -23,6 +29,10 @@ impl QueryTallies {
         }
     }
 
+    pub fn tally_near_cutoff(&self) {
+        self.queries_near_cutoff.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn num_queries(&self) -> u64 {
         self.num_queries.load(Ordering::Relaxed)
     }
@@ -30,6 +40,10 @@ impl QueryTallies {
     pub fn queries_at_most_300(&self) -> u64 {
         self.queries_at_most_300.load(Ordering::Relaxed)
     }
+
+    pub fn queries_near_cutoff(&self) -> u64 {
+        self.queries_near_cutoff.load(Ordering::Relaxed)
+    }
 }
 
 /// A collection information regarding the reference sequences.
@@ -48,6 +62,15 @@ impl RefTallies {
             first_ref_len: references.0.first().map_or(0, |seq| seq.forward.sequence.len()),
         }
     }
+
+    /// Tallies the `references` before they are profiled, for
+    /// `--ref-chunk-size`, where only one chunk is profiled at a time.
+    pub fn from_records(references: &[FastaSeq]) -> Self {
+        Self {
+            num_refs:      references.len(),
+            first_ref_len: references.first().map_or(0, |seq| seq.sequence.len()),
+        }
+    }
 }
 
 /// A collection of non-blocking thread-safe tallies for information regarding
@@ -90,6 +113,10 @@ impl AlignmentTallies {
 pub fn pick_alignment_method(
     _query_tallies: &QueryTallies, ref_tallies: &RefTallies, alignment_tallies: &AlignmentTallies, config: &AlignerConfig,
 ) -> AlignmentMethod {
+    if config.ungapped {
+        return AlignmentMethod::Ungapped;
+    }
+
     let mut num_alignments = alignment_tallies.num_alignments();
     let mut scores_fitting_i8 = alignment_tallies.scores_fitting_i8();
 
@@ -146,6 +173,9 @@ pub struct AllTallies {
     /// counted exactly for the highest scoring strand, it is assumed that the
     /// other strand (if `--rev-comp` is enabled) will always fit an `i8`.
     pub est_scores_fitting_i8: u64,
+    /// The number of queries for which `--top-k-refs` may have excluded the
+    /// best reference.
+    pub queries_near_cutoff:   u64,
 }
 
 impl AllTallies {
@@ -169,6 +199,7 @@ impl AllTallies {
             first_ref_len: ref_tallies.first_ref_len,
             num_alignments,
             est_scores_fitting_i8,
+            queries_near_cutoff: query_tallies.queries_near_cutoff(),
         }
     }
 }
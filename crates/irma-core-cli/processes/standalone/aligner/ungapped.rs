@@ -0,0 +1,124 @@
+//! Ungapped local alignment for `--ungapped`, which scores each query by its
+//! best gap-free diagonal segment against a reference as a fast screen.
+
+use std::{
+    ops::Add,
+    simd::{Simd, SimdElement, cmp::SimdOrd, num::SimdInt},
+};
+use zoe::{
+    alignment::{Alignment, AlignmentStates},
+    data::WeightMatrix,
+};
+
+/// The number of reference positions scored at once.
+const LANES: usize = 32;
+
+/// Computes the best ungapped local alignment of `query` against `reference`,
+/// which is the highest-scoring segment along any diagonal. `None` is returned
+/// if no segment scores above zero.
+///
+/// In the case of a tie, the segment ending earliest in the query is
+/// preferred, and then the one ending earliest in the reference.
+pub fn sw_ungapped<const S: usize>(
+    reference: &[u8], query: &[u8], matrix: &WeightMatrix<'_, i8, S>,
+) -> Option<Alignment<u32>> {
+    // Scores are kept as 16-bit integers, doubling the lanes per instruction,
+    // unless a query is long enough that its score could overflow them
+    let max_weight = matrix.weights.iter().flatten().copied().max().unwrap_or_default();
+    let (best_score, best_end) = if query.len() * usize::from(max_weight.unsigned_abs()) < i16::MAX as usize {
+        best_segment_end::<i16, S>(reference, query, matrix)
+    } else {
+        best_segment_end::<i32, S>(reference, query, matrix)
+    };
+
+    if best_score <= 0 {
+        return None;
+    }
+
+    // The segment starts after the last point at which the running score fell
+    // to zero or below, which is found by walking back along the diagonal
+    let (ref_end, query_end) = best_end;
+    let mut length = 0;
+    let mut score = 0;
+    while score < best_score {
+        length += 1;
+        score += i32::from(matrix.get_weight(reference[ref_end - length], query[query_end - length]));
+    }
+    let (ref_start, query_start) = (ref_end - length, query_end - length);
+
+    let mut states = AlignmentStates::new();
+    states.add_inc_op(query_start, b'S');
+    states.add_inc_op(length, b'M');
+    states.add_inc_op(query.len() - query_end, b'S');
+
+    // The ranges of a global alignment are narrowed to the segment
+    let mut alignment = Alignment::new_global(best_score as u32, states, reference.len(), query.len());
+    alignment.ref_range = ref_start..ref_end;
+    alignment.query_range = query_start..query_end;
+    Some(alignment)
+}
+
+/// Finds the score of the best ungapped segment, along with the (exclusive)
+/// reference and query positions at which it ends, using scores of type `T`.
+///
+/// The scores of each query base against every reference base are computed a
+/// row at a time, where each cell only depends on the cell diagonally before
+/// it, so that the inner loop has no branches and is vectorized.
+fn best_segment_end<T, const S: usize>(
+    reference: &[u8], query: &[u8], matrix: &WeightMatrix<'_, i8, S>,
+) -> (i32, (usize, usize))
+where
+    T: SimdElement + Ord + Default + Add<Output = T> + From<i8> + Into<i32>,
+    Simd<T, LANES>: SimdOrd + SimdInt<Scalar = T> + Add<Output = Simd<T, LANES>>, {
+    // The weight of each reference base against each residue, so that each row
+    // is read contiguously
+    let profile = (0..S)
+        .map(|query_index| {
+            reference
+                .iter()
+                .map(|&base| T::from(matrix.weights[matrix.mapping.to_index(base)][query_index]))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // The score of the best segment ending at each reference position (offset
+    // by one), for the previous and current query positions
+    let mut previous = vec![T::default(); reference.len() + 1];
+    let mut current = vec![T::default(); reference.len() + 1];
+
+    let mut best_score = T::default();
+    let mut best_end = (0, 0);
+
+    for (query_pos, &base) in query.iter().enumerate() {
+        let weights = &profile[matrix.mapping.to_index(base)];
+
+        let (current_chunks, current_rest) = current[1..].as_chunks_mut::<LANES>();
+        let (previous_chunks, previous_rest) = previous[..reference.len()].as_chunks::<LANES>();
+        let (weight_chunks, weight_rest) = weights.as_chunks::<LANES>();
+
+        let zero = Simd::splat(T::default());
+        let mut row_best = zero;
+        for ((cells, diagonals), weights) in current_chunks.iter_mut().zip(previous_chunks).zip(weight_chunks) {
+            let scores = Simd::from_array(*diagonals).simd_max(zero) + Simd::from_array(*weights);
+            row_best = row_best.simd_max(scores);
+            *cells = scores.to_array();
+        }
+
+        let mut row_max = row_best.reduce_max();
+        for ((cell, &diagonal), &weight) in current_rest.iter_mut().zip(previous_rest).zip(weight_rest) {
+            *cell = diagonal.max(T::default()) + weight;
+            row_max = row_max.max(*cell);
+        }
+
+        if row_max > best_score {
+            best_score = row_max;
+            // The earliest reference position is preferred in a tie
+            let ref_pos = current[1..].iter().position(|&score| score == row_max).unwrap_or_default();
+            best_end = (ref_pos + 1, query_pos + 1);
+        }
+
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    (best_score.into(), best_end)
+}
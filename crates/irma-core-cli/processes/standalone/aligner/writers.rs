@@ -1,18 +1,31 @@
 //! Traits and structs for writing the output of aligner
 
-use crate::aligner::{AlignerConfig, AlignmentAndSeqs, Strand};
-use std::io::Write;
+use crate::aligner::{AlignerConfig, AlignmentAndSeqs, AlignmentAndStrand, Strand, ref_params::RefParams};
+use clap::{ValueEnum, builder::PossibleValue};
+use foldhash::fast::SeedableRandomState;
+use irma_records::{
+    hashing::get_hasher,
+    io::{FastX, WriterWithContext, WriterWithErrorContext},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+};
 use zoe::{
-    data::{fasta::FastaSeq, sam::SamDataView},
+    data::{err::ResultWithErrorContext, fasta::FastaSeq, fastq::FastQView, sam::SamDataView},
     math::AnyInt,
-    prelude::{AsView, NucleotidesView, QualityScores, QualityScoresView},
+    prelude::{AsView, NucleotidesView, QualityScores, QualityScoresView, Slice},
 };
 
 #[cfg(feature = "dev_no_rayon")]
 use irma_records::io::WriteFileZipStdout;
 
 #[cfg(not(feature = "dev_no_rayon"))]
-use std::{error::Error, fmt::Display};
+use std::error::Error;
 #[cfg(not(feature = "dev_no_rayon"))]
 use zoe::data::err::{ErrorWithContext, GetCode};
 
@@ -174,18 +187,176 @@ impl AlignmentWriterThreaded {
     }
 }
 
+/// A clap enum for specifying the coordinate system of the reference positions
+/// in the outputs other than SAM, which is always 1-based.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Coords {
+    ZeroBased,
+    OneBased,
+}
+
+impl Coords {
+    /// Converts the 0-based index of a reference position to this coordinate
+    /// system.
+    #[inline]
+    pub fn position(self, index: usize) -> usize {
+        match self {
+            Coords::ZeroBased => index,
+            Coords::OneBased => index + 1,
+        }
+    }
+}
+
+impl Display for Coords {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Coords::ZeroBased => write!(f, "0"),
+            Coords::OneBased => write!(f, "1"),
+        }
+    }
+}
+
+impl ValueEnum for Coords {
+    #[inline]
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::ZeroBased, Self::OneBased]
+    }
+
+    #[inline]
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::ZeroBased => Some(PossibleValue::new("0")),
+            Self::OneBased => Some(PossibleValue::new("1")),
+        }
+    }
+}
+
+/// The reason an alignment is unmapped, reported in the `YU:Z` tag with
+/// `--unmapped-reason`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum UnmappedReason {
+    /// No local alignment scored above zero, which is the minimum score for a
+    /// mapped alignment
+    BelowMinScore,
+    /// The query was anchored to an amplicon with `--anchor-bed`, but did not
+    /// align within the amplicon's region
+    OutsideAmplicon,
+}
+
+impl UnmappedReason {
+    /// Determines why the query of an unmapped alignment is unmapped.
+    pub fn new(alignment: &AlignmentAndSeqs, config: &AlignerConfig) -> Self {
+        if config
+            .anchors
+            .as_ref()
+            .is_some_and(|anchors| anchors.find(alignment.query).is_some())
+        {
+            UnmappedReason::OutsideAmplicon
+        } else {
+            UnmappedReason::BelowMinScore
+        }
+    }
+}
+
+impl Display for UnmappedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnmappedReason::BelowMinScore => write!(f, "below_min_score"),
+            UnmappedReason::OutsideAmplicon => write!(f, "outside_amplicon"),
+        }
+    }
+}
+
+/// Which strand's alignment a record is, reported in the `YB:Z` tag with
+/// `--report-both-strands`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum StrandRank {
+    /// The alignment of the strand with the better score, which is the primary
+    /// alignment
+    Best,
+    /// The alignment of the other strand, which is written as a secondary
+    /// alignment
+    Other,
+}
+
+impl Display for StrandRank {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StrandRank::Best => write!(f, "best"),
+            StrandRank::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// The optional tags annotating a mapped alignment.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ExtraTags<'a> {
+    /// The strand rank for the `YB:Z` tag, with `--report-both-strands`
+    pub rank:       Option<StrandRank>,
+    /// The reference's scoring parameters for the `YP:Z` tag, if overridden by
+    /// `--ref-params`
+    pub params:     Option<RefParams>,
+    /// The sample's read group for the `RG:Z` tag, with `--read-groups`
+    pub read_group: Option<&'a str>,
+}
+
+impl Display for ExtraTags<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(rank) = self.rank {
+            write!(f, "\tYB:Z:{rank}")?;
+        }
+        if let Some(params) = self.params {
+            write!(f, "\tYP:Z:{params}")?;
+        }
+        if let Some(read_group) = self.read_group {
+            write!(f, "\tRG:Z:{read_group}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The optional tags annotating an unmapped alignment.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct UnmappedTags<'a> {
+    /// The reason the query is unmapped for the `YU:Z` tag, with
+    /// `--unmapped-reason`
+    pub reason:     Option<UnmappedReason>,
+    /// The sample's read group for the `RG:Z` tag, with `--read-groups`
+    pub read_group: Option<&'a str>,
+}
+
+impl Display for UnmappedTags<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(reason) = self.reason {
+            write!(f, "\tYU:Z:{reason}")?;
+        }
+        if let Some(read_group) = self.read_group {
+            write!(f, "\tRG:Z:{read_group}")?;
+        }
+        Ok(())
+    }
+}
+
 /// Encapsulates the necessary logic in order for a writer to work with
 /// `aligner`.
 ///
 /// This is specifically designed to share logic between a multi-threaded
 /// `AlignmentWriterThreaded` and a single-threaded `WriteFileZipStdout`.
 pub trait AlignmentWriter: Sized {
-    /// Given an unmapped alignment in a [`SamDataView`], write the alignment.
-    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>) -> Result<(), WriterError>;
+    /// Given an unmapped alignment in a [`SamDataView`] and any tags to
+    /// annotate it with, write the alignment.
+    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>, tags: UnmappedTags) -> Result<(), WriterError>;
 
     /// Given an alignment in a [`SamDataView`] along with an alignment score,
-    /// write the alignment.
-    fn write_record<'a, T: AnyInt>(&mut self, record: SamDataView<'a>, score: T) -> Result<(), WriterError>;
+    /// any normalized score, and any other tags to annotate it with, write the
+    /// alignment.
+    fn write_record<'a, T: AnyInt>(
+        &mut self, record: SamDataView<'a>, score: T, normalized: Option<f64>, tags: ExtraTags,
+    ) -> Result<(), WriterError>;
+
+    /// Given a FASTQ record, write it without a trailing linebreak.
+    fn write_fastq(&mut self, record: FastQView) -> Result<(), WriterError>;
 
     /// Writes an alignment in SAM format.
     ///
@@ -197,93 +368,620 @@ pub trait AlignmentWriter: Sized {
     ///   reference (if [`Strand::Reverse`]) is passed)
     ///
     /// The `MAPQ` field is not used and is set to 255. The optional `AS` tag
-    /// for the score is included when the read is mapped. The query and
-    /// reference name are truncated to only include the characters before the
-    /// first whitespace. A trailing linebreak is not included.
+    /// for the score is included when the read is mapped, along with a `YN`
+    /// tag for the normalized score when `--normalize-scores` is used. Unmapped
+    /// alignments have FLAG 4, along with a `YU` tag for the reason when
+    /// `--unmapped-reason` is used. The query and reference name are truncated
+    /// to only include the characters before the first whitespace. A trailing
+    /// linebreak is not included.
+    ///
+    /// With `--report-both-strands`, the alignment of the other strand (if
+    /// mapped) is written after it as a secondary alignment, and both carry a
+    /// `YB` tag with their [`StrandRank`]. Mapped alignments to a reference
+    /// with overridden scoring carry a `YP` tag with its [`RefParams`]. Every
+    /// record carries an `RG` tag if a `read_group` is given.
     fn write_alignment<'q, 'r>(
-        &mut self, alignment: AlignmentAndSeqs<'q, 'r>, config: &AlignerConfig,
+        &mut self, alignment: AlignmentAndSeqs<'q, 'r>, read_group: Option<&str>, config: &AlignerConfig,
     ) -> Result<(), WriterError> {
         let qname = process_header(&alignment.query.header);
 
-        match alignment.mapping {
+        match &alignment.mapping {
             Some(mapping) if mapping.inner.score > 0 => {
                 let rname = process_header(&alignment.reference.name);
-                let pos = mapping.inner.ref_range.start + 1;
-                let mapq = 255;
-                let cigar = mapping.inner.states.to_cigar_unchecked();
-
-                match mapping.strand {
-                    Strand::Forward => {
-                        let flag = 0;
-                        let seq = &alignment.query.sequence;
-                        let qual = alignment
-                            .query
-                            .quality
-                            .as_ref()
-                            .map_or(QualityScoresView::try_from(b"*").unwrap(), AsView::as_view);
-                        let record =
-                            SamDataView::new(qname, flag, rname, pos, mapq, cigar.as_view(), seq.as_slice().into(), qual);
-                        return self.write_record(record, mapping.inner.score);
-                    }
-                    Strand::Reverse => {
-                        let flag = 16;
-                        let seq = NucleotidesView::from(alignment.query.sequence.as_slice())
-                            .to_reverse_complement()
-                            .into_vec();
-                        let qual = alignment
-                            .query
-                            .quality
-                            .as_ref()
-                            .map_or(QualityScores::try_from(b"*").unwrap(), |qual| qual.to_reverse());
-                        let record = SamDataView::new(
-                            qname,
-                            flag,
-                            rname,
-                            pos,
-                            mapq,
-                            cigar.as_view(),
-                            seq.as_slice().into(),
-                            qual.as_view(),
-                        );
-                        return self.write_record(record, mapping.inner.score);
-                    }
+                let rank = config.report_both_strands.then_some(StrandRank::Best);
+                let tags = ExtraTags {
+                    rank,
+                    params: alignment.ref_params,
+                    read_group,
                 };
+                self.write_mapped(qname, rname, alignment.query, mapping, tags, config)?;
+
+                if config.report_both_strands
+                    && let Some(other) = alignment.other_strand.as_ref().filter(|other| other.inner.score > 0)
+                {
+                    let tags = ExtraTags {
+                        rank: Some(StrandRank::Other),
+                        params: alignment.ref_params,
+                        read_group,
+                    };
+                    self.write_mapped(qname, rname, alignment.query, other, tags, config)?;
+                }
             }
             _ => {
                 if !config.exclude_unmapped {
-                    return self.write_unmapped(SamDataView::unmapped(qname, "*"));
+                    let tags = UnmappedTags {
+                        reason: config.unmapped_reason.then(|| UnmappedReason::new(&alignment, config)),
+                        read_group,
+                    };
+                    return self.write_unmapped(SamDataView::unmapped(qname, "*"), tags);
                 }
             }
         };
         Ok(())
     }
+
+    /// Writes the aligned portion of a mapped query as a FASTQ record, for
+    /// `--extract-aligned`. The query is trimmed to the alignment boundaries,
+    /// and is reverse complemented (with its quality scores reversed) if the
+    /// reverse complement was aligned, so that it is in the orientation of the
+    /// reference.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the query has no quality scores.
+    fn write_extracted(&mut self, query: &FastX, mapping: &AlignmentAndStrand) -> Result<(), WriterError> {
+        let quality = query.quality.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "`--extract-aligned` requires FASTQ queries, but the query {header} has no quality scores",
+                    header = process_header(&query.header)
+                ),
+            )
+        })?;
+        let record = FastQView {
+            header:   &query.header,
+            sequence: query.sequence.as_slice().into(),
+            quality:  quality.as_view(),
+        };
+        let range = mapping.inner.query_range.clone();
+
+        match mapping.strand {
+            Strand::Forward => self.write_fastq(record.slice(range)),
+            Strand::Reverse => self.write_fastq(record.to_reverse_complement().slice(range)),
+        }
+    }
+
+    /// Writes a single mapped alignment for [`write_alignment`]. The alignment
+    /// is written as a secondary alignment if the rank in `tags` is
+    /// [`StrandRank::Other`].
+    ///
+    /// [`write_alignment`]: AlignmentWriter::write_alignment
+    fn write_mapped(
+        &mut self, qname: &str, rname: &str, query: &FastX, mapping: &AlignmentAndStrand, tags: ExtraTags,
+        config: &AlignerConfig,
+    ) -> Result<(), WriterError> {
+        let pos = mapping.inner.ref_range.start + 1;
+        let mapq = 255;
+        let cigar = mapping.inner.states.to_cigar_unchecked();
+        let normalized = config.normalizer.map(|normalizer| normalizer.normalize(&mapping.inner));
+        let secondary = if tags.rank == Some(StrandRank::Other) { 256 } else { 0 };
+
+        match mapping.strand {
+            Strand::Forward => {
+                let flag = secondary;
+                let seq = &query.sequence;
+                let qual = query
+                    .quality
+                    .as_ref()
+                    .map_or(QualityScoresView::try_from(b"*").unwrap(), AsView::as_view);
+                let record = SamDataView::new(qname, flag, rname, pos, mapq, cigar.as_view(), seq.as_slice().into(), qual);
+                self.write_record(record, mapping.inner.score, normalized, tags)
+            }
+            Strand::Reverse => {
+                let flag = secondary | 16;
+                let seq = NucleotidesView::from(query.sequence.as_slice())
+                    .to_reverse_complement()
+                    .into_vec();
+                let qual = query
+                    .quality
+                    .as_ref()
+                    .map_or(QualityScores::try_from(b"*").unwrap(), |qual| qual.to_reverse());
+                let record = SamDataView::new(
+                    qname,
+                    flag,
+                    rname,
+                    pos,
+                    mapq,
+                    cigar.as_view(),
+                    seq.as_slice().into(),
+                    qual.as_view(),
+                );
+                self.write_record(record, mapping.inner.score, normalized, tags)
+            }
+        }
+    }
 }
 
 #[cfg(feature = "dev_no_rayon")]
 impl AlignmentWriter for WriteFileZipStdout {
     #[inline]
-    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>) -> std::io::Result<()> {
-        writeln!(self, "{record}")?;
+    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>, tags: UnmappedTags) -> std::io::Result<()> {
+        writeln!(self, "{record}{tags}")
+    }
+
+    #[inline]
+    fn write_record<'a, T: AnyInt>(
+        &mut self, record: SamDataView<'a>, score: T, normalized: Option<f64>, tags: ExtraTags,
+    ) -> std::io::Result<()> {
+        write!(self, "{record}\tAS:i:{score}")?;
+        if let Some(normalized) = normalized {
+            write!(self, "\tYN:f:{normalized:.4}")?;
+        }
+        writeln!(self, "{tags}")
+    }
+
+    #[inline]
+    fn write_fastq(&mut self, record: FastQView) -> std::io::Result<()> {
+        write!(self, "{record}")
+    }
+}
+
+#[cfg(not(feature = "dev_no_rayon"))]
+impl AlignmentWriter for AlignmentWriterThreaded {
+    #[inline]
+    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>, tags: UnmappedTags) -> Result<(), ThreadedWriteError> {
+        self.write(format!("{record}{tags}"))
+    }
+
+    #[inline]
+    fn write_record<'a, T: AnyInt>(
+        &mut self, record: SamDataView<'a>, score: T, normalized: Option<f64>, tags: ExtraTags,
+    ) -> Result<(), ThreadedWriteError> {
+        let mut line = format!("{record}\tAS:i:{score}");
+        if let Some(normalized) = normalized {
+            line.push_str(&format!("\tYN:f:{normalized:.4}"));
+        }
+        line.push_str(&tags.to_string());
+        self.write(line)
+    }
+
+    #[inline]
+    fn write_fastq(&mut self, record: FastQView) -> Result<(), ThreadedWriteError> {
+        self.write(format!(
+            "@{header}\n{sequence}\n+\n{quality}",
+            header = record.header,
+            sequence = record.sequence,
+            quality = record.quality
+        ))
+    }
+}
+
+/// The outputs of `aligner` for a set of queries: the SAM output, along with
+/// the FASTQ output for `--extract-aligned` and the per-reference outputs for
+/// `--split-by-best-ref` if they were provided.
+#[cfg_attr(not(feature = "dev_no_rayon"), derive(Clone))]
+pub struct AlignerOutputs<W> {
+    sam:        W,
+    extracted:  Option<W>,
+    split:      Option<SplitWriter>,
+    /// The read group tagging each SAM record, with `--read-groups`
+    read_group: Option<Arc<str>>,
+}
+
+impl<W: AlignmentWriter> AlignerOutputs<W> {
+    /// Bundles the SAM output with any `--extract-aligned` and
+    /// `--split-by-best-ref` outputs.
+    #[inline]
+    pub fn new(sam: W, extracted: Option<W>, split: Option<SplitWriter>) -> Self {
+        AlignerOutputs {
+            sam,
+            extracted,
+            split,
+            read_group: None,
+        }
+    }
+
+    /// Tags each SAM record with the read group, for `--read-groups`.
+    #[inline]
+    #[must_use]
+    pub fn with_read_group(mut self, read_group: Option<&str>) -> Self {
+        self.read_group = read_group.map(Arc::from);
+        self
+    }
+
+    /// Writes an alignment to the SAM output (see
+    /// [`AlignmentWriter::write_alignment`]), along with the aligned portion of
+    /// the query to the `--extract-aligned` output if the query is mapped.
+    ///
+    /// With `--split-by-best-ref`, the alignment is instead written to the
+    /// output of its reference, or of the unmapped queries (see
+    /// [`SplitWriter`]).
+    #[inline]
+    pub fn write_alignment<'q, 'r>(
+        &mut self, alignment: AlignmentAndSeqs<'q, 'r>, config: &AlignerConfig,
+    ) -> Result<(), WriterError> {
+        if let Some(extracted) = &mut self.extracted
+            && let Some(mapping) = alignment.mapping.as_ref().filter(|mapping| mapping.inner.score > 0)
+        {
+            extracted.write_extracted(alignment.query, mapping)?;
+        }
+        match &mut self.split {
+            Some(split) => split.write_alignment(alignment, self.read_group.as_deref(), config),
+            None => self.sam.write_alignment(alignment, self.read_group.as_deref(), config),
+        }
+    }
+}
+
+#[cfg(feature = "dev_no_rayon")]
+impl AlignerOutputs<WriteFileZipStdout> {
+    /// Flushes all the outputs.
+    #[inline]
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.sam.flush()?;
+        if let Some(extracted) = &mut self.extracted {
+            extracted.flush()?;
+        }
+        if let Some(split) = &mut self.split {
+            split.files.flush()?;
+        }
         Ok(())
     }
+}
 
+#[cfg(not(feature = "dev_no_rayon"))]
+impl AlignerOutputs<AlignmentWriterThreaded> {
+    /// Finalizes the writing of all the outputs (see
+    /// [`AlignmentWriterThreaded::flush`]), propagating the first error.
     #[inline]
-    fn write_record<'a, T: AnyInt>(&mut self, record: SamDataView<'a>, score: T) -> std::io::Result<()> {
-        writeln!(self, "{record}\tAS:i:{score}")?;
+    pub fn flush(self) -> std::io::Result<()> {
+        let sam = self.sam.flush();
+        let extracted = self.extracted.map_or(Ok(()), AlignmentWriterThreaded::flush);
+        let split = self.split.map_or(Ok(()), |split| split.files.flush());
+        sam.and(extracted).and(split)
+    }
+}
+
+/// The kind of a per-reference output for `--split-by-best-ref`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum SplitKind {
+    /// The SAM output of the alignments
+    Sam,
+    /// The FASTQ output of the whole queries, for `--split-queries`
+    Queries,
+}
+
+impl SplitKind {
+    /// The extension of the output files of this kind.
+    fn extension(self) -> &'static str {
+        match self {
+            SplitKind::Sam => "sam",
+            SplitKind::Queries => "fastq",
+        }
+    }
+}
+
+/// Identifies an output file for `--split-by-best-ref`, named by its stem (the
+/// reference name, or `unmapped`) and its [`SplitKind`].
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SplitKey {
+    stem: String,
+    kind: SplitKind,
+}
+
+/// The output files for `--split-by-best-ref`, with at most `max_open` open at
+/// once.
+///
+/// Each file is created (truncating any existing file) the first time it is
+/// written to, along with the SAM header if one was provided. When the limit is
+/// reached, the least recently written file is closed, and it is reopened for
+/// appending if written to again.
+pub struct SplitFiles {
+    /// The directory in which the files are written
+    dir:      PathBuf,
+    /// The SAM header written at the start of each SAM file, if any
+    header:   Option<String>,
+    /// The maximum number of open files
+    max_open: usize,
+    /// The open files, with the tick at which each was last written to
+    open:     HashMap<SplitKey, (BufWriter<WriterWithContext<File>>, u64), SeedableRandomState>,
+    /// The files that have been created
+    created:  HashSet<SplitKey, SeedableRandomState>,
+    /// The number of writes so far, used to find the least recently written
+    /// file
+    tick:     u64,
+}
+
+impl SplitFiles {
+    /// Creates the outputs for `--split-by-best-ref` in `dir`, which must
+    /// exist. The `header` is written at the start of each SAM file.
+    ///
+    /// ## Panics
+    ///
+    /// `max_open` must be nonzero.
+    pub fn new(dir: PathBuf, header: Option<String>, max_open: usize) -> Self {
+        assert!(max_open > 0, "At least one file must be allowed to be open");
+        SplitFiles {
+            dir,
+            header,
+            max_open,
+            open: HashMap::with_hasher(get_hasher()),
+            created: HashSet::with_hasher(get_hasher()),
+            tick: 0,
+        }
+    }
+
+    /// Writes `line` followed by a linebreak to the file for `key`, opening it
+    /// if needed.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors when opening, closing, or writing to a file are propagated
+    /// with the path as context.
+    pub fn write_line(&mut self, key: SplitKey, line: &str) -> std::io::Result<()> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some((writer, last_written)) = self.open.get_mut(&key) {
+            *last_written = tick;
+            return writeln!(writer, "{line}");
+        }
+
+        if self.open.len() >= self.max_open
+            && let Some(oldest) = self
+                .open
+                .iter()
+                .min_by_key(|(_, (_, last_written))| *last_written)
+                .map(|(key, _)| key.clone())
+            && let Some((mut writer, _)) = self.open.remove(&oldest)
+        {
+            writer.flush()?;
+        }
+
+        let path = self.dir.join(format!("{}.{}", key.stem, key.kind.extension()));
+        let is_new = self.created.insert(key.clone());
+        let file = if is_new {
+            File::create(&path)
+        } else {
+            OpenOptions::new().append(true).open(&path)
+        }
+        .with_path_context("Failed to open the output file", &path)?;
+        let mut writer = BufWriter::new(file.writer_with_path_context("Failed to write to the output file", &path));
+
+        if is_new
+            && key.kind == SplitKind::Sam
+            && let Some(header) = &self.header
+        {
+            writer.write_all(header.as_bytes())?;
+        }
+        writeln!(writer, "{line}")?;
+        self.open.insert(key, (writer, tick));
+        Ok(())
+    }
+
+    /// Flushes and closes all the open files.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors when flushing are propagated with the path as context.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        for (_, (mut writer, _)) in self.open.drain() {
+            writer.flush()?;
+        }
         Ok(())
     }
 }
 
+/// A clonable handle to the [`SplitFiles`] for `--split-by-best-ref`, which are
+/// written by a single dedicated thread as with [`AlignmentWriterThreaded`].
 #[cfg(not(feature = "dev_no_rayon"))]
-impl AlignmentWriter for AlignmentWriterThreaded {
+pub struct SplitFilesThreaded {
+    sender:        std::sync::mpsc::Sender<(SplitKey, String)>,
+    writer_thread: Option<std::thread::JoinHandle<std::io::Result<()>>>,
+}
+
+#[cfg(not(feature = "dev_no_rayon"))]
+impl Clone for SplitFilesThreaded {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            sender:        self.sender.clone(),
+            writer_thread: None,
+        }
+    }
+}
+
+#[cfg(not(feature = "dev_no_rayon"))]
+impl SplitFilesThreaded {
+    /// Moves the `files` into a writer thread and creates a channel to it.
+    #[must_use]
+    pub fn new(mut files: SplitFiles) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel::<(SplitKey, String)>();
+        let writer_thread = std::thread::spawn(move || -> std::io::Result<()> {
+            while let Ok((key, line)) = receiver.recv() {
+                files.write_line(key, &line)?;
+            }
+            files.flush()
+        });
+
+        Self {
+            sender,
+            writer_thread: Some(writer_thread),
+        }
+    }
+
+    /// Sends a line to be written to the file for `key`, handling errors as in
+    /// [`AlignmentWriterThreaded::write`].
+    #[inline]
+    pub fn write_line(&mut self, key: SplitKey, line: String) -> Result<(), ThreadedWriteError> {
+        self.sender.send((key, line)).map_err(|_| {
+            if let Some(thread) = std::mem::take(&mut self.writer_thread)
+                && let Err(e) = thread.join().unwrap()
+            {
+                ThreadedWriteError::IoError(e)
+            } else {
+                ThreadedWriteError::ReceiverDeallocated
+            }
+        })
+    }
+
+    /// Finalizes the writing by closing the thread and propagating any errors,
+    /// as in [`AlignmentWriterThreaded::flush`].
+    #[inline]
+    pub fn flush(self) -> std::io::Result<()> {
+        if let Some(thread) = self.writer_thread {
+            drop(self.sender);
+            thread.join().unwrap()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The files written by a [`SplitWriter`], which depends on whether
+/// `dev_no_rayon` is set.
+#[cfg(not(feature = "dev_no_rayon"))]
+type SplitFilesWriter = SplitFilesThreaded;
+
+/// The files written by a [`SplitWriter`], which depends on whether
+/// `dev_no_rayon` is set.
+#[cfg(feature = "dev_no_rayon")]
+type SplitFilesWriter = SplitFiles;
+
+/// The writer for `--split-by-best-ref`, which writes each alignment to a SAM
+/// file named after its reference, or to `unmapped.sam` if the query is
+/// unmapped. With `--split-queries`, each query is also written whole to a
+/// FASTQ file of the same name.
+#[cfg_attr(not(feature = "dev_no_rayon"), derive(Clone))]
+pub struct SplitWriter {
+    files:   SplitFilesWriter,
+    queries: bool,
+}
+
+impl SplitWriter {
+    /// Wraps the `files` for writing, also writing the whole queries if
+    /// `queries` is true.
+    pub fn new(files: SplitFiles, queries: bool) -> Self {
+        #[cfg(not(feature = "dev_no_rayon"))]
+        let files = SplitFilesThreaded::new(files);
+        SplitWriter { files, queries }
+    }
+
+    /// Writes the alignment (see [`AlignmentWriter::write_alignment`]) to the
+    /// output of its reference, or of the unmapped queries, along with the
+    /// whole query if `--split-queries` was given.
+    fn write_alignment<'q, 'r>(
+        &mut self, alignment: AlignmentAndSeqs<'q, 'r>, read_group: Option<&str>, config: &AlignerConfig,
+    ) -> Result<(), WriterError> {
+        let mapped = alignment.mapping.as_ref().is_some_and(|mapping| mapping.inner.score > 0);
+        if !mapped && config.exclude_unmapped {
+            return Ok(());
+        }
+        let stem = if mapped {
+            split_file_stem(process_header(&alignment.reference.name))
+        } else {
+            "unmapped".to_string()
+        };
+
+        if self.queries {
+            let query = alignment.query;
+            let quality = query.quality.as_ref().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "`--split-queries` requires FASTQ queries, but the query {header} has no quality scores",
+                        header = process_header(&query.header)
+                    ),
+                )
+            })?;
+            let record = FastQView {
+                header:   &query.header,
+                sequence: query.sequence.as_slice().into(),
+                quality:  quality.as_view(),
+            };
+            self.routed(stem.clone(), SplitKind::Queries).write_fastq(record)?;
+        }
+
+        self.routed(stem, SplitKind::Sam)
+            .write_alignment(alignment, read_group, config)
+    }
+
+    /// An [`AlignmentWriter`] writing to the file with the given `stem` and
+    /// `kind`.
+    fn routed(&mut self, stem: String, kind: SplitKind) -> Routed<'_> {
+        Routed {
+            files: &mut self.files,
+            key:   SplitKey { stem, kind },
+        }
+    }
+}
+
+/// Converts a reference name to a file stem, replacing any characters other
+/// than ASCII alphanumerics, `-`, `_`, and `.` with `_`. A name that would be
+/// empty or hidden is prefixed with `_`.
+fn split_file_stem(name: &str) -> String {
+    let stem = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if stem.is_empty() || stem.starts_with('.') {
+        format!("_{stem}")
+    } else {
+        stem
+    }
+}
+
+/// An [`AlignmentWriter`] writing each record as a line of one of the
+/// [`SplitFiles`].
+struct Routed<'a> {
+    files: &'a mut SplitFilesWriter,
+    key:   SplitKey,
+}
+
+impl Routed<'_> {
+    /// Writes a line to the file.
+    #[inline]
+    fn write(&mut self, line: String) -> Result<(), WriterError> {
+        #[cfg(not(feature = "dev_no_rayon"))]
+        return self.files.write_line(self.key.clone(), line);
+
+        #[cfg(feature = "dev_no_rayon")]
+        return self.files.write_line(self.key.clone(), &line);
+    }
+}
+
+impl AlignmentWriter for Routed<'_> {
+    #[inline]
+    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>, tags: UnmappedTags) -> Result<(), WriterError> {
+        self.write(format!("{record}{tags}"))
+    }
+
     #[inline]
-    fn write_unmapped<'a>(&mut self, record: SamDataView<'a>) -> Result<(), ThreadedWriteError> {
-        self.write(format!("{record}"))
+    fn write_record<'a, T: AnyInt>(
+        &mut self, record: SamDataView<'a>, score: T, normalized: Option<f64>, tags: ExtraTags,
+    ) -> Result<(), WriterError> {
+        let mut line = format!("{record}\tAS:i:{score}");
+        if let Some(normalized) = normalized {
+            line.push_str(&format!("\tYN:f:{normalized:.4}"));
+        }
+        line.push_str(&tags.to_string());
+        self.write(line)
     }
 
     #[inline]
-    fn write_record<'a, T: AnyInt>(&mut self, record: SamDataView<'a>, score: T) -> Result<(), ThreadedWriteError> {
-        self.write(format!("{record}\tAS:i:{score}"))
+    fn write_fastq(&mut self, record: FastQView) -> Result<(), WriterError> {
+        self.write(format!(
+            "@{header}\n{sequence}\n+\n{quality}",
+            header = record.header,
+            sequence = record.sequence,
+            quality = record.quality
+        ))
     }
 }
 
@@ -296,15 +994,21 @@ fn process_header(header: &str) -> &str {
 
 /// Writes a SAM-style header to the writer, containing the `HD` and `SQ` lines.
 #[inline]
-pub fn write_header<W: Write>(writer: &mut W, references: &[FastaSeq]) -> std::io::Result<()> {
+pub fn write_header<W: Write>(
+    writer: &mut W, references: &[FastaSeq], reference_groups: &[Option<String>],
+) -> std::io::Result<()> {
     writeln!(writer, "@HD\tVN:1.4")?;
-    for reference in references {
-        writeln!(
+    for (reference, group) in references.iter().zip(reference_groups) {
+        write!(
             writer,
             "@SQ\tSN:{name}\tLN:{len}",
             name = process_header(&reference.name),
             len = reference.sequence.len()
         )?;
+        if let Some(group) = group {
+            write!(writer, "\tDS:group={group}")?;
+        }
+        writeln!(writer)?;
     }
     Ok(())
 }
@@ -0,0 +1,381 @@
+//! Tabulates index (barcode) combinations from FASTQ headers or index read
+//! files and estimates the rate of index hopping.
+
+use clap::Args;
+use foldhash::fast::SeedableRandomState;
+use irma_records::{
+    hashing::get_hasher,
+    io::{InputOptions, OutputOptions, ValidatePaths},
+    paired::{ZipPairedReadsExt, get_index_sequences},
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Args, Debug)]
+pub struct BarcodesArgs {
+    #[arg(required_unless_present = "index1")]
+    /// Path to a FASTQ or .fastq.gz file with the index sequences in the
+    /// Illumina header comments (e.g. `1:N:0:AACGCACGAG+GCCTCGGATA`)
+    fastq_input: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "fastq_input", alias = "i1")]
+    /// Path to an I1 index read FASTQ file, used instead of header parsing
+    index1: Option<PathBuf>,
+
+    #[arg(long, requires = "index1", alias = "i2")]
+    /// Path to an I2 index read FASTQ file for dual-indexed runs
+    index2: Option<PathBuf>,
+
+    #[arg(short = 'e', long)]
+    /// A tab-delimited file of expected combinations, with lines of the form
+    /// `sample<TAB>index1[<TAB>index2]`. If omitted, the expected combinations
+    /// are inferred as the index pairs which are each other's most frequent
+    /// partner
+    expected: Option<PathBuf>,
+
+    #[arg(short = 'o', long)]
+    /// Output path for the TSV table of combinations. Prints to STDOUT if not
+    /// provided
+    output: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Output path for an additional JSON summary
+    json: Option<PathBuf>,
+}
+
+impl ValidatePaths for BarcodesArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        self.fastq_input
+            .iter()
+            .chain(self.index1.iter())
+            .chain(self.index2.iter())
+            .chain(self.expected.iter())
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        self.output.iter().chain(self.json.iter())
+    }
+}
+
+/// A combination of a first index and an optional second index.
+type IndexCombination = (String, Option<String>);
+
+/// The classification of an observed index combination.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CombinationStatus {
+    /// The combination is expected (or was inferred as expected)
+    Expected,
+    /// The combination is unexpected, but both indices are part of expected
+    /// combinations, consistent with index hopping
+    Hopped,
+    /// The combination contains an index not part of any expected combination
+    Unknown,
+}
+
+impl std::fmt::Display for CombinationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CombinationStatus::Expected => write!(f, "expected"),
+            CombinationStatus::Hopped => write!(f, "hopped"),
+            CombinationStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// The tallied combinations and read counts.
+#[derive(Default)]
+struct BarcodeSummary {
+    total_reads:    usize,
+    unparsed_reads: usize,
+    expected_reads: usize,
+    hopped_reads:   usize,
+    unknown_reads:  usize,
+    combinations:   Vec<(IndexCombination, usize, CombinationStatus)>,
+}
+
+impl BarcodeSummary {
+    /// The estimated index hopping rate, as the fraction of reads with hopped
+    /// combinations among the reads with expected or hopped combinations.
+    /// `None` is returned if hopping cannot be assessed.
+    fn hopping_rate(&self) -> Option<f64> {
+        let assessed = self.expected_reads + self.hopped_reads;
+        (assessed > 0).then(|| self.hopped_reads as f64 / assessed as f64)
+    }
+}
+
+/// Sub-program for barcode and index hopping QC.
+pub fn barcodes_process(args: BarcodesArgs) -> std::io::Result<()> {
+    args.validate_paths()?;
+
+    let mut counts = HashMap::with_hasher(get_hasher());
+    let mut unparsed_reads = 0;
+
+    if let Some(index1) = &args.index1 {
+        count_index_reads(index1, args.index2.as_deref(), &mut counts)?;
+    } else if let Some(fastq_input) = &args.fastq_input {
+        let reader = InputOptions::new_from_path(fastq_input)
+            .use_file_or_zip()
+            .decode_in_thread()
+            .parse_fastq()
+            .open()?;
+
+        for record in reader {
+            let record = record?;
+            if let Some((index1, index2)) = get_index_sequences(&record.header) {
+                *counts
+                    .entry((index1.to_ascii_uppercase(), index2.map(str::to_ascii_uppercase)))
+                    .or_insert(0usize) += 1;
+            } else {
+                unparsed_reads += 1;
+            }
+        }
+    }
+
+    let expected = match &args.expected {
+        Some(path) => read_expected(path)?,
+        None => infer_expected(&counts),
+    };
+
+    let summary = classify(counts, &expected, unparsed_reads);
+
+    let mut writer = OutputOptions::new_from_opt_path(args.output.as_ref())
+        .use_file_zip_or_stdout()
+        .open()?;
+    write_tsv(&mut writer, &summary)?;
+    writer.flush()?;
+
+    if let Some(path) = &args.json {
+        let mut json_writer = OutputOptions::new_from_path(path).use_file().open()?;
+        write_json(&mut json_writer, &summary)?;
+        json_writer.flush()?;
+    }
+
+    match summary.hopping_rate() {
+        Some(rate) => eprintln!(
+            "IRMA-core barcodes: {hopped} of {total} reads have hopped index combinations (estimated hopping rate {percent:.4}%)",
+            hopped = summary.hopped_reads,
+            total = summary.total_reads,
+            percent = rate * 100.0
+        ),
+        None => eprintln!("IRMA-core barcodes: index hopping could not be assessed"),
+    }
+
+    Ok(())
+}
+
+/// Tallies the index combinations from I1 (and optionally I2) index read
+/// files. Paired index files are checked for matching headers.
+fn count_index_reads(
+    index1: &Path, index2: Option<&Path>, counts: &mut HashMap<IndexCombination, usize, SeedableRandomState>,
+) -> std::io::Result<()> {
+    let readers = InputOptions::new_from_paths(index1, index2)
+        .use_file_or_zip()
+        .decode_in_thread()
+        .parse_fastq()
+        .open()?;
+
+    if let Some((reader2, index2)) = readers.reader2.zip(index2) {
+        for pair in readers.reader1.zip_paired_reads(reader2) {
+            let [read1, read2] = pair.map_err(|e| e.add_path_context(index1, index2))?;
+            *counts
+                .entry((
+                    read1.sequence.to_string().to_ascii_uppercase(),
+                    Some(read2.sequence.to_string().to_ascii_uppercase()),
+                ))
+                .or_insert(0) += 1;
+        }
+    } else {
+        for read1 in readers.reader1 {
+            *counts
+                .entry((read1?.sequence.to_string().to_ascii_uppercase(), None))
+                .or_insert(0) += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the expected index combinations from a tab-delimited file with lines
+/// of the form `sample<TAB>index1[<TAB>index2]`. Empty lines and lines
+/// beginning with `#` are skipped.
+///
+/// ## Errors
+///
+/// IO errors are propagated, and lines lacking an index produce an error with
+/// the path as context.
+fn read_expected(path: &Path) -> std::io::Result<HashSet<IndexCombination, SeedableRandomState>> {
+    let reader = InputOptions::new_from_path(path).use_file().open()?;
+    let mut expected = HashSet::with_hasher(get_hasher());
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split('\t').skip(1);
+        let Some(index1) = fields.next().filter(|s| !s.is_empty()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Expected a line of the form 'sample<TAB>index1[<TAB>index2]' in file: {path}\nLine: {line}",
+                    path = path.display()
+                ),
+            ));
+        };
+        let index2 = fields.next().filter(|s| !s.is_empty());
+
+        expected.insert((index1.to_ascii_uppercase(), index2.map(str::to_ascii_uppercase)));
+    }
+
+    Ok(expected)
+}
+
+/// Infers the expected combinations as those where the second index is the
+/// most frequent partner of the first index, and vice versa. Single-indexed
+/// combinations are all considered expected.
+fn infer_expected(
+    counts: &HashMap<IndexCombination, usize, SeedableRandomState>,
+) -> HashSet<IndexCombination, SeedableRandomState> {
+    let mut best_for_index1: HashMap<&str, (&IndexCombination, usize), _> = HashMap::with_hasher(get_hasher());
+    let mut best_for_index2: HashMap<&str, (&IndexCombination, usize), _> = HashMap::with_hasher(get_hasher());
+
+    for (combination, &count) in counts {
+        let (index1, Some(index2)) = combination else {
+            continue;
+        };
+
+        for (best, index) in [(&mut best_for_index1, index1), (&mut best_for_index2, index2)] {
+            best.entry(index.as_str())
+                .and_modify(|e| {
+                    if count > e.1 {
+                        *e = (combination, count);
+                    }
+                })
+                .or_insert((combination, count));
+        }
+    }
+
+    counts
+        .keys()
+        .filter(|combination| match combination {
+            (index1, Some(index2)) => {
+                best_for_index1.get(index1.as_str()).map(|e| e.0) == Some(combination)
+                    && best_for_index2.get(index2.as_str()).map(|e| e.0) == Some(combination)
+            }
+            (_, None) => true,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Classifies each observed combination against the expected combinations,
+/// sorting them by descending count.
+fn classify(
+    counts: HashMap<IndexCombination, usize, SeedableRandomState>,
+    expected: &HashSet<IndexCombination, SeedableRandomState>, unparsed_reads: usize,
+) -> BarcodeSummary {
+    let expected_index1: HashSet<&str> = expected.iter().map(|(index1, _)| index1.as_str()).collect();
+    let expected_index2: HashSet<&str> = expected.iter().filter_map(|(_, index2)| index2.as_deref()).collect();
+
+    let mut summary = BarcodeSummary {
+        total_reads: unparsed_reads,
+        unparsed_reads,
+        ..Default::default()
+    };
+
+    for (combination, count) in counts {
+        let status = if expected.contains(&combination) {
+            summary.expected_reads += count;
+            CombinationStatus::Expected
+        } else if let (index1, Some(index2)) = &combination
+            && expected_index1.contains(index1.as_str())
+            && expected_index2.contains(index2.as_str())
+        {
+            summary.hopped_reads += count;
+            CombinationStatus::Hopped
+        } else {
+            summary.unknown_reads += count;
+            CombinationStatus::Unknown
+        };
+
+        summary.total_reads += count;
+        summary.combinations.push((combination, count, status));
+    }
+
+    summary
+        .combinations
+        .sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    summary
+}
+
+/// Writes the table of combinations as TSV.
+fn write_tsv(writer: &mut impl Write, summary: &BarcodeSummary) -> std::io::Result<()> {
+    let parsed_reads = summary.total_reads - summary.unparsed_reads;
+
+    writeln!(writer, "index1\tindex2\tcount\tfraction\tstatus")?;
+    for ((index1, index2), count, status) in &summary.combinations {
+        let fraction = *count as f64 / parsed_reads as f64;
+        writeln!(
+            writer,
+            "{index1}\t{index2}\t{count}\t{fraction:.6}\t{status}",
+            index2 = index2.as_deref().unwrap_or("*")
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The summary and combinations written with `--json`.
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    total_reads:    usize,
+    unparsed_reads: usize,
+    expected_reads: usize,
+    hopped_reads:   usize,
+    unknown_reads:  usize,
+    /// The estimated hopping rate, or `null` if it cannot be assessed
+    hopping_rate:   Option<f64>,
+    combinations:   Vec<JsonCombination<'a>>,
+}
+
+/// A combination written with `--json`, where a missing second index is
+/// `null`.
+#[derive(Serialize)]
+struct JsonCombination<'a> {
+    index1: &'a str,
+    index2: Option<&'a str>,
+    count:  usize,
+    status: CombinationStatus,
+}
+
+/// Writes the summary and combinations as JSON.
+fn write_json(writer: &mut impl Write, summary: &BarcodeSummary) -> std::io::Result<()> {
+    let json = JsonSummary {
+        total_reads:    summary.total_reads,
+        unparsed_reads: summary.unparsed_reads,
+        expected_reads: summary.expected_reads,
+        hopped_reads:   summary.hopped_reads,
+        unknown_reads:  summary.unknown_reads,
+        hopping_rate:   summary.hopping_rate(),
+        combinations:   summary
+            .combinations
+            .iter()
+            .map(|((index1, index2), count, status)| JsonCombination {
+                index1,
+                index2: index2.as_deref(),
+                count: *count,
+                status: *status,
+            })
+            .collect(),
+    };
+
+    serde_json::to_writer_pretty(&mut *writer, &json).map_err(std::io::Error::from)?;
+    writeln!(writer)
+}
@@ -0,0 +1,248 @@
+//! Compares two FASTQ or FASTA files at the record level, reporting the records
+//! found in only one file and the records whose sequence or quality scores
+//! differ.
+
+use clap::{Args, ValueEnum, builder::PossibleValue};
+use foldhash::fast::SeedableRandomState;
+use irma_records::{
+    hashing::get_hasher,
+    io::{FastX, InputOptions, OutputOptions, ValidatePaths, WriteRecord},
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::BuildHasher,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Args, Debug)]
+pub struct CompareArgs {
+    /// Path to the first FASTQ or FASTA file (A)
+    file_a: PathBuf,
+
+    /// Path to the second FASTQ or FASTA file (B)
+    file_b: PathBuf,
+
+    #[arg(long, default_value_t = CompareKey::Id)]
+    /// How records are matched between the files: by header (comparing the
+    /// sequence and quality scores of matched records), or by sequence alone
+    by: CompareKey,
+
+    #[arg(short = 'o', long)]
+    /// Output path for the summary counts. Prints to STDOUT if not provided
+    output: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Output path for the records of A which are not identically present in B
+    diff_a: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Output path for the records of B which are not identically present in A
+    diff_b: Option<PathBuf>,
+}
+
+impl ValidatePaths for CompareArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        [&self.file_a, &self.file_b]
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        self.output.iter().chain(self.diff_a.iter()).chain(self.diff_b.iter())
+    }
+}
+
+/// A clap enum for specifying how records are matched.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum CompareKey {
+    Id,
+    Sequence,
+}
+
+impl Display for CompareKey {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompareKey::Id => write!(f, "id"),
+            CompareKey::Sequence => write!(f, "sequence"),
+        }
+    }
+}
+
+impl ValueEnum for CompareKey {
+    #[inline]
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Id, Self::Sequence]
+    }
+
+    #[inline]
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Id => Some(PossibleValue::new("id").alias("header")),
+            Self::Sequence => Some(PossibleValue::new("sequence").alias("seq")),
+        }
+    }
+}
+
+/// A record of B held in the index, identified by its position in the file.
+struct IndexedRecord {
+    position:      usize,
+    sequence_hash: u64,
+    quality_hash:  u64,
+}
+
+/// The summary counts of a comparison.
+#[derive(Default, Debug)]
+struct CompareSummary {
+    records_a:          usize,
+    records_b:          usize,
+    identical:          usize,
+    only_in_a:          usize,
+    only_in_b:          usize,
+    differing_sequence: usize,
+    differing_quality:  usize,
+}
+
+/// Sub-program for comparing two FASTQ or FASTA files.
+pub fn compare_process(args: CompareArgs) -> std::io::Result<()> {
+    args.validate_paths()?;
+
+    let hasher = get_hasher();
+
+    // B is indexed by the full key of each record (its header or sequence),
+    // so the index grows with the total size of those keys. Only hashes of the
+    // sequences and quality scores are kept alongside, and A is streamed
+    // against the index
+    let mut summary = CompareSummary::default();
+    let mut index: HashMap<Vec<u8>, Vec<IndexedRecord>, SeedableRandomState> = HashMap::with_hasher(get_hasher());
+
+    for (position, record) in open_fastx(&args.file_b)?.enumerate() {
+        let record = record?;
+        let indexed = IndexedRecord {
+            position,
+            sequence_hash: hasher.hash_one(&record.sequence),
+            quality_hash: quality_hash(&record, &hasher),
+        };
+        let key = match args.by {
+            CompareKey::Id => record.header.into_bytes(),
+            CompareKey::Sequence => record.sequence,
+        };
+        index.entry(key).or_default().push(indexed);
+        summary.records_b += 1;
+    }
+
+    let mut identical_in_b = vec![false; summary.records_b];
+
+    let mut diff_a = match &args.diff_a {
+        Some(path) => Some(OutputOptions::new_from_opt_path(Some(path)).use_file_zip_or_stdout().open()?),
+        None => None,
+    };
+
+    for record in open_fastx(&args.file_a)? {
+        let record = record?;
+        summary.records_a += 1;
+
+        let sequence_hash = hasher.hash_one(&record.sequence);
+        let quality_hash = quality_hash(&record, &hasher);
+        let key = match args.by {
+            CompareKey::Id => record.header.as_bytes(),
+            CompareKey::Sequence => record.sequence.as_slice(),
+        };
+
+        // Records with a repeated key are matched in order, preferring an
+        // identical record where one exists
+        let matched = index.get_mut(key).and_then(|candidates| {
+            let i = candidates
+                .iter()
+                .position(|c| c.sequence_hash == sequence_hash && c.quality_hash == quality_hash)
+                .unwrap_or(0);
+            (!candidates.is_empty()).then(|| candidates.remove(i))
+        });
+
+        let is_identical = match (args.by, matched) {
+            (_, None) => {
+                summary.only_in_a += 1;
+                false
+            }
+            (CompareKey::Sequence, Some(matched)) => {
+                identical_in_b[matched.position] = true;
+                summary.identical += 1;
+                true
+            }
+            (CompareKey::Id, Some(matched)) => {
+                if matched.sequence_hash != sequence_hash {
+                    summary.differing_sequence += 1;
+                    false
+                } else if matched.quality_hash != quality_hash {
+                    summary.differing_quality += 1;
+                    false
+                } else {
+                    identical_in_b[matched.position] = true;
+                    summary.identical += 1;
+                    true
+                }
+            }
+        };
+
+        if !is_identical && let Some(writer) = &mut diff_a {
+            record.write_record(writer)?;
+        }
+    }
+
+    summary.only_in_b = index.values().map(Vec::len).sum();
+
+    if let Some(writer) = &mut diff_a {
+        writer.flush()?;
+    }
+
+    // B is streamed a second time to write its records, since the index only
+    // holds their keys and hashes
+    if let Some(path) = &args.diff_b {
+        let mut writer = OutputOptions::new_from_opt_path(Some(path)).use_file_zip_or_stdout().open()?;
+        for (record, identical) in open_fastx(&args.file_b)?.zip(&identical_in_b) {
+            let record = record?;
+            if !identical {
+                record.write_record(&mut writer)?;
+            }
+        }
+        writer.flush()?;
+    }
+
+    let mut writer = OutputOptions::new_from_opt_path(args.output.as_ref())
+        .use_file_zip_or_stdout()
+        .open()?;
+    write_summary(&mut writer, &summary, args.by)?;
+    writer.flush()
+}
+
+/// Opens a FASTQ or FASTA file (optionally `.gz` compressed) for reading.
+fn open_fastx(path: &Path) -> std::io::Result<impl Iterator<Item = std::io::Result<FastX>>> {
+    InputOptions::new_from_path(path)
+        .use_file_or_zip()
+        .decode_in_thread()
+        .parse_fastx()
+        .open()
+}
+
+/// Hashes the quality scores of a record, using 0 for FASTA records.
+fn quality_hash(record: &FastX, hasher: &impl BuildHasher) -> u64 {
+    record
+        .quality
+        .as_ref()
+        .map_or(0, |quality| hasher.hash_one(quality.as_bytes()))
+}
+
+/// Writes the summary counts as tab-delimited key-value pairs. The differing
+/// counts are only included when matching by ID.
+fn write_summary(writer: &mut impl Write, summary: &CompareSummary, by: CompareKey) -> std::io::Result<()> {
+    writeln!(writer, "RECORDS_A\t{}", summary.records_a)?;
+    writeln!(writer, "RECORDS_B\t{}", summary.records_b)?;
+    writeln!(writer, "IDENTICAL\t{}", summary.identical)?;
+    writeln!(writer, "ONLY_IN_A\t{}", summary.only_in_a)?;
+    writeln!(writer, "ONLY_IN_B\t{}", summary.only_in_b)?;
+    if by == CompareKey::Id {
+        writeln!(writer, "DIFFERING_SEQUENCE\t{}", summary.differing_sequence)?;
+        writeln!(writer, "DIFFERING_QUALITY\t{}", summary.differing_quality)?;
+    }
+    Ok(())
+}
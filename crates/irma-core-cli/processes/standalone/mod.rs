@@ -1,4 +1,8 @@
 pub mod aligner;
+pub mod barcodes;
+pub mod compare;
+pub mod rc;
 pub mod sampler;
 pub mod trimmer;
+pub mod xfl_stats;
 pub mod xleave;
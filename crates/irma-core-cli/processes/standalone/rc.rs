@@ -0,0 +1,223 @@
+//! Reverse complements the records of FastQ or FASTA files, optionally only
+//! those matching an ID list or a regular expression.
+
+use crate::shared::output_buffering::OutputBufferingArgs;
+use clap::Args;
+use foldhash::fast::SeedableRandomState;
+use irma_records::{
+    hashing::get_hasher,
+    io::{FastX, InputOptions, OutputOptions, RecordWriters, ValidatePaths, WriteRecords},
+    paired::{ZipPairedReadsExt, get_molecular_id_side},
+};
+use regex::Regex;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use zoe::data::{err::ResultWithErrorContext, nucleotides::make_reverse_complement};
+
+/// The annotation appended to the ID of each reverse complemented record.
+const RC_ANNOTATION: &str = "/rc";
+
+#[derive(Args, Debug)]
+pub struct RcArgs {
+    /// Path to FASTQ, FASTA, or .gz file to be reverse complemented
+    pub input_file1: PathBuf,
+
+    /// Path to optional second FASTQ, FASTA, or .gz file for paired reads
+    pub input_file2: Option<PathBuf>,
+
+    #[arg(short = '1', long, short_alias = 'o', aliases = ["output-file", "output-file1", "output1"])]
+    /// Output file path for the records. Prints to STDOUT if not provided
+    pub output: Option<PathBuf>,
+
+    #[arg(short = '2', long, requires = "output", requires = "input_file2", alias = "output-file2")]
+    /// Output path for the second file of paired reads. If this argument is
+    /// omitted, paired output is interleaved
+    pub output2: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", conflicts_with = "regex")]
+    /// Only reverse complements the records whose IDs are listed in FILE, one
+    /// per line. A record matches by the first word of its header, or by its
+    /// molecular ID without the read side
+    pub ids: Option<PathBuf>,
+
+    #[arg(long, value_name = "PATTERN")]
+    /// Only reverse complements the records whose full header matches the
+    /// regular expression PATTERN
+    pub regex: Option<Regex>,
+
+    #[arg(long)]
+    /// Leaves the headers of reverse complemented records as they are, rather
+    /// than appending `/rc` to their IDs
+    pub no_annotate: bool,
+
+    #[arg(long, requires = "input_file2")]
+    /// Writes the records of the second input first (or to the first output),
+    /// and those of the first input second
+    pub swap_sides: bool,
+
+    #[arg(short = 'v', long)]
+    /// Prints the number of records reverse complemented to stderr
+    pub verbose: bool,
+
+    #[command(flatten)]
+    pub buffering: OutputBufferingArgs,
+}
+
+impl ValidatePaths for RcArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        let input1 = std::iter::once(&self.input_file1);
+        let input2 = self.input_file2.iter();
+        let ids = self.ids.iter();
+
+        input1.chain(input2).chain(ids)
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        self.output.iter().chain(self.output2.iter())
+    }
+}
+
+/// The records which are reverse complemented.
+enum Selection {
+    /// Every record
+    All,
+    /// The records with an ID in the set, from `--ids`
+    Ids(HashSet<Box<str>, SeedableRandomState>),
+    /// The records whose header matches, from `--regex`
+    Regex(Regex),
+}
+
+impl Selection {
+    /// Reads the IDs for `--ids`, one per line, where blank lines and lines
+    /// starting with `#` are skipped. Only the first word of each line is
+    /// used.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors while reading the file are propagated.
+    fn from_ids_file(path: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+
+        let mut ids = HashSet::with_hasher(get_hasher());
+        ids.extend(
+            contents
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+                .filter_map(|line| line.split_ascii_whitespace().next())
+                .map(Box::from),
+        );
+        Ok(Selection::Ids(ids))
+    }
+
+    /// Whether the record with `header` is reverse complemented.
+    fn matches(&self, header: &str) -> bool {
+        match self {
+            Selection::All => true,
+            Selection::Ids(ids) => {
+                header.split_ascii_whitespace().next().is_some_and(|id| ids.contains(id))
+                    || get_molecular_id_side(header, '0').is_some_and(|(id, _)| ids.contains(id))
+            }
+            Selection::Regex(regex) => regex.is_match(header),
+        }
+    }
+}
+
+/// Reverse complements the selected records, reversing their quality scores
+/// and annotating their headers.
+struct ReverseComplementer {
+    selection: Selection,
+    annotate:  bool,
+    /// The number of records seen
+    total:     usize,
+    /// The number of records reverse complemented
+    reversed:  usize,
+}
+
+impl ReverseComplementer {
+    /// Reverse complements `record` if it is selected.
+    fn apply(&mut self, mut record: FastX) -> FastX {
+        self.total += 1;
+        if self.selection.matches(&record.header) {
+            make_reverse_complement(&mut record.sequence);
+            if let Some(quality) = &mut record.quality {
+                quality.make_reverse();
+            }
+            if self.annotate {
+                // The ID is annotated rather than the end of the header, so
+                // that any Illumina description is left intact
+                let id_end = record
+                    .header
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(record.header.len());
+                record.header.insert_str(id_end, RC_ANNOTATION);
+            }
+            self.reversed += 1;
+        }
+        record
+    }
+}
+
+/// Sub-program for reverse complementing FastQ or FASTA records.
+pub fn rc_process(args: RcArgs) -> std::io::Result<()> {
+    args.validate_paths()?;
+
+    let selection = match (&args.ids, args.regex) {
+        (Some(path), _) => Selection::from_ids_file(path).with_path_context("Failed to read the IDs file", path)?,
+        (None, Some(regex)) => Selection::Regex(regex),
+        (None, None) => Selection::All,
+    };
+    let mut rc = ReverseComplementer {
+        selection,
+        annotate: !args.no_annotate,
+        total: 0,
+        reversed: 0,
+    };
+
+    let readers = InputOptions::new_from_paths(&args.input_file1, args.input_file2.as_ref())
+        .use_file_or_zip()
+        .decode_in_thread()
+        .parse_fastx()
+        .open()?;
+
+    let writer = OutputOptions::new_from_opt_paths(args.output.as_ref(), args.output2.as_ref())
+        .with_buffering(args.buffering.buffering())
+        .use_file_zip_or_stdout()
+        .open()?;
+
+    match readers.reader2.zip(args.input_file2.as_ref()) {
+        Some((reader2, input_file2)) => readers
+            .reader1
+            .zip_paired_reads(reader2)
+            .map(|pair| {
+                pair.map_err(|e| e.add_path_context(&args.input_file1, input_file2))
+                    .map(|[read1, read2]| {
+                        let (read1, read2) = (rc.apply(read1), rc.apply(read2));
+                        if args.swap_sides { [read2, read1] } else { [read1, read2] }
+                    })
+            })
+            .write_records(writer)?,
+        None => {
+            // `--output2` requires a second input, so a single input only has
+            // a single output
+            let RecordWriters::SingleEnd(writer) = writer else {
+                unreachable!("A second output requires a second input");
+            };
+            readers
+                .reader1
+                .map(|record| record.map(|record| rc.apply(record)))
+                .write_records(writer)?;
+        }
+    }
+
+    if args.verbose {
+        eprintln!(
+            "IRMA-core rc reverse complemented {reversed} of {total} records",
+            reversed = rc.reversed,
+            total = rc.total
+        );
+    }
+
+    Ok(())
+}
@@ -1,27 +1,52 @@
 //! Randomly downsamples FastQ or FASTA files. Deinterleaving supported.
 
-use clap::Args;
+use crate::{
+    args::{abort_clap, quality_filter::ParsedQualityFilterArgs},
+    shared::{
+        compression::CompressionArgs,
+        output_buffering::OutputBufferingArgs,
+        xfl::{parse_cluster_size, replace_cluster_size},
+    },
+};
+use clap::{Args, error::ErrorKind};
+use foldhash::fast::SeedableRandomState;
+#[cfg(feature = "async-io")]
+use irma_records::io::{ReadFileZipAsync, WriteFileZipStdoutAsync, run_with_async_io};
 use irma_records::{
+    fastq::ReadTransforms,
+    hashing::get_hasher,
     io::{
-        DispatchFastX, FastXReader, InputOptions, IterWithContext, OutputOptions, ReadFileZipInThread, RecordReaders,
-        RecordWriters, SequenceWriter, ValidatePaths, WriteFileZipStdout, WriteRecord, WriteRecordCompatibleItem,
-        WriteRecords, is_gz,
+        CompressionOptions, DispatchFastX, FastXReader, GzipFormat, InputOptions, IterWithContext, OutputBuffering,
+        OutputOptions, ReadFileZipInThread, RecordReaders, RecordWriters, SequenceWriter, SkipRecords, ValidatePaths,
+        WriteFileZipStdout, WriteRecord, WriteRecordCompatibleItem, WriteRecords, WriterWithContext, is_gz,
     },
-    paired::{DeinterleavedPairedReadsExt, ZipPairedReadsExt},
+    paired::{DeinterleaveError, DeinterleavedPairedReadsExt, ZipPairedReadsError, ZipPairedReadsExt, check_paired_headers},
 };
-use rand::{SeedableRng, make_rng};
+use rand::{Rng, RngExt, SeedableRng, make_rng, seq::SliceRandom};
 use rand_xoshiro::Xoshiro256StarStar;
+use serde::Serialize;
 use std::{
+    cell::Cell,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, hash_map::Entry},
     fmt::Debug,
-    io::{BufRead, Read, Write},
+    fs::File,
+    io::{BufWriter, Read, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
 };
 use zoe::{
-    data::records::HeaderReadable,
+    data::{
+        err::{ResultWithErrorContext, WithErrorContext},
+        fasta::FastaSeq,
+        fastq::FastQ,
+        records::HeaderReadable,
+    },
     iter_utils::{
         ProcessResultsExt,
         sampling::{DownsampleBernoulli, SkipSampler, downsample_reservoir},
     },
+    prelude::Len,
 };
 
 #[derive(Args, Debug)]
@@ -46,14 +71,73 @@ pub struct SamplerArgs {
     #[command(flatten)]
     target: Target,
 
+    #[arg(long, value_name = "TSV", requires = "per_group_target")]
+    /// Tab-delimited file assigning read IDs (first column) to groups, such as
+    /// amplicons or references (second column), for `--per-group-target`
+    pub groups: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["input_file2", "output2", "targets", "per_group_target", "min_quality", "min_length"])]
+    /// Treats the input as deflated FASTA with `%<SIZE>` cluster headers, as
+    /// written by `preprocess` and `xflate`, and samples reads rather than
+    /// clusters, so each cluster is drawn in proportion to its size. The target
+    /// is a number or percent of reads
+    pub weight_by_cluster_size: bool,
+
+    #[arg(long, requires = "weight_by_cluster_size")]
+    /// Rewrites the `%<SIZE>` of each sampled cluster to the number of its reads
+    /// that were sampled, rather than keeping its original size
+    pub adjust_counts: bool,
+
     #[arg(short = 's', long)]
     /// For reproducibility, provide an optional seed for the random number
     /// generator
     pub rng_seed: Option<u64>,
 
+    #[arg(long)]
+    /// Writes the sampled records in a random order rather than their input
+    /// order, drawn from the same seed as the sample itself
+    pub shuffle: bool,
+
+    #[arg(long, value_name = "N", default_value = "100000", requires = "shuffle")]
+    /// The maximum number of records held in memory by `--shuffle` when the
+    /// sample is written as it is drawn. Samples of at most this size, or that
+    /// are already held in memory, are shuffled in full
+    pub shuffle_buffer: NonZeroUsize,
+
+    #[arg(long, value_name = "Q")]
+    /// Samples only from the reads whose quality (the geometric mean, or the
+    /// median with `--use-median`) is at least this. Requires FASTQ input
+    pub min_quality: Option<u8>,
+
+    #[arg(long, requires = "min_quality")]
+    /// Interprets `--min-quality` as the median, not the geometric mean
+    pub use_median: bool,
+
+    #[arg(long, value_name = "N")]
+    /// Samples only from the reads of at least this length
+    pub min_length: Option<NonZeroUsize>,
+
     #[arg(short = 'v', long)]
-    /// Prints the original number of records and subsampled amount to stderr
+    /// Prints the original number of records and subsampled amount to stderr,
+    /// along with the expected sample size when sampling a percent of a stream
     pub verbose: bool,
+
+    #[arg(long, value_name = "JSON")]
+    /// Writes the population size, and the achieved count and fraction of each
+    /// sample, as JSON
+    pub summary_json: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub buffering: OutputBufferingArgs,
+
+    #[command(flatten)]
+    pub compression: CompressionArgs,
+
+    #[cfg(feature = "async-io")]
+    #[arg(long)]
+    /// Read and write files with an async (tokio) runtime, which may help when
+    /// IO is slow, such as on network file systems
+    pub async_io: bool,
 }
 
 #[derive(Args, Debug)]
@@ -69,6 +153,17 @@ struct Target {
     /// [0, 100]. Either a `subsample_target` or `percent_target` must be
     /// specified
     pub percent_target: Option<usize>,
+
+    #[arg(long, value_delimiter = ',', requires = "output")]
+    /// Comma-separated target numbers of reads for nested subsamples, produced
+    /// in a single pass. Each is written to the output path(s) with `{target}`
+    /// replaced by the target, and smaller samples are subsets of larger ones
+    pub targets: Option<Vec<usize>>,
+
+    #[arg(long, requires = "groups")]
+    /// Maximum number of reads (or pairs) to sample from each group given by
+    /// `--groups`, balancing coverage across the groups
+    pub per_group_target: Option<usize>,
 }
 
 /// Parses a percent (as a `usize`) from the command line
@@ -89,32 +184,75 @@ impl ValidatePaths for SamplerArgs {
     fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
         let input1 = std::iter::once(&self.input_file);
         let input2 = self.input_file2.iter();
+        let groups = self.groups.iter();
 
-        input1.chain(input2)
+        input1.chain(input2).chain(groups)
     }
 
     fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
         let output1 = self.output.iter();
         let output2 = self.output2.iter();
+        let summary_json = self.summary_json.iter();
 
-        output1.chain(output2)
+        output1.chain(output2).chain(summary_json)
     }
 }
 
 /// main process getting called by irma-core main.rs
 pub fn sampler_process(args: SamplerArgs) -> Result<(), std::io::Error> {
     args.validate_paths()?;
+    #[cfg(feature = "async-io")]
+    args.buffering.check_async_io(args.async_io, "sampler");
 
-    let (io_args, rng, target, verbose) = parse_sampler_args(args)?;
+    let (paths, rngs, target, report, prefilter, weighting) = parse_sampler_args(args)?;
 
-    // Get the population sequence count from one of the files if possible
-    let mut seq_count = get_paired_seq_count(&io_args)?;
+    #[cfg(feature = "async-io")]
+    if paths.async_io {
+        return run_with_async_io(move || {
+            sample_files(open_sampler_io_async(paths)?, rngs, target, report, &prefilter, weighting)
+        });
+    }
 
-    let is_single = io_args.reader2.is_none() && matches!(io_args.writer, RecordWriters::SingleEnd(_));
+    sample_files(open_sampler_io(paths)?, rngs, target, report, &prefilter, weighting)
+}
+
+/// Samples the records of the opened inputs, writing them to the outputs.
+/// Only the records passing the `prefilter` are sampled. Deflated clusters are
+/// sampled by their size if `weighting` is given.
+fn sample_files<R, W>(
+    io_args: IOArgs<R, W>, rngs: SamplerRngs, target: SamplingTarget, report: SamplerReport, prefilter: &Prefilter,
+    weighting: Option<ClusterWeighting>,
+) -> std::io::Result<()>
+where
+    R: Read,
+    W: Write, {
+    if let Some(weighting) = weighting {
+        return sample_clusters(io_args, rngs, target, report, weighting);
+    }
+
+    if prefilter.checks_quality()
+        && let FastXReader::Fasta(_) = io_args.reader1.iter.inner_iter()
+    {
+        return Err(std::io::Error::other(format!(
+            "`--min-quality` requires FASTQ input, but FASTA was found in: {}",
+            io_args.reader1.path.display()
+        )));
+    }
+
+    // Get the population sequence count from one of the files if possible. The
+    // number of records passing the prefilter is not known in advance
+    let mut seq_count = if prefilter.is_active() {
+        None
+    } else {
+        get_paired_seq_count(&io_args)?
+    };
+
+    // Validity: at least one writer is always opened
+    let is_single = io_args.reader2.is_none() && matches!(io_args.writers[0], RecordWriters::SingleEnd(_));
 
     // For de-interleaving, must divide sequence count by 2 to get number of
     // pairs
-    if io_args.reader2.is_none() && matches!(io_args.writer, RecordWriters::PairedEnd(_)) {
+    if io_args.reader2.is_none() && matches!(io_args.writers[0], RecordWriters::PairedEnd(_)) {
         seq_count = seq_count.map(|seq_count| seq_count / 2)
     }
 
@@ -123,6 +261,17 @@ pub fn sampler_process(args: SamplerArgs) -> Result<(), std::io::Error> {
         (SamplingTarget::Count(count), _) => SamplingTarget::Count(count),
         (SamplingTarget::Percent(percent), Some(seq_count)) => SamplingTarget::Count(seq_count * percent / 100),
         (SamplingTarget::Percent(percent), None) => SamplingTarget::Percent(percent),
+        (target @ (SamplingTarget::Nested(_) | SamplingTarget::PerGroup { .. }), _) => target,
+    };
+    let nested_targets = match &target {
+        SamplingTarget::Nested(targets) => Some(targets.clone()),
+        _ => None,
+    };
+    // A percent is only left unconverted when the population size is unknown,
+    // in which case each record is sampled independently
+    let bernoulli_probability = match &target {
+        SamplingTarget::Percent(percent) => Some(*percent as f64 / 100.0),
+        _ => None,
     };
 
     let Reader {
@@ -130,7 +279,40 @@ pub fn sampler_process(args: SamplerArgs) -> Result<(), std::io::Error> {
         iter: reader1,
     } = io_args.reader1;
 
-    let (total_original, total_downsampled) = if let Some(reader2) = io_args.reader2 {
+    let (total_original, total_downsampled) = if let SamplingTarget::Count(target_count) = target
+        && let Some(seq_count) = seq_count
+        && target_count <= seq_count
+        && input_path1.is_file()
+        && io_args.reader2.as_ref().is_none_or(|reader2| reader2.path.is_file())
+    {
+        // The inputs can be reopened, so the records that are not sampled are
+        // skipped rather than parsed
+        let input_path2 = io_args.reader2.map(|reader2| reader2.path);
+        let input_path2 = input_path2.as_deref();
+        // Validity: exactly one writer is opened when the target is not nested
+        let writer = io_args.writers.into_iter().next().expect("A single writer should be opened");
+
+        match reader1.inner_iter() {
+            FastXReader::Fastq(_) => {
+                let open = |path: &Path| {
+                    InputOptions::new_from_path(path)
+                        .use_file_or_zip()
+                        .parse_fastq_skippable()
+                        .open()
+                };
+                sample_by_skipping(open, &input_path1, input_path2, writer, target_count, seq_count, rngs)?
+            }
+            FastXReader::Fasta(_) => {
+                let open = |path: &Path| {
+                    InputOptions::new_from_path(path)
+                        .use_file_or_zip()
+                        .parse_fasta_skippable()
+                        .open()
+                };
+                sample_by_skipping(open, &input_path1, input_path2, writer, target_count, seq_count, rngs)?
+            }
+        }
+    } else if let Some(reader2) = io_args.reader2 {
         let Reader {
             path: input_path2,
             iter: reader2,
@@ -140,10 +322,12 @@ pub fn sampler_process(args: SamplerArgs) -> Result<(), std::io::Error> {
 
         match (reader1.dispatch(), reader2.dispatch()) {
             (DispatchFastX::Fastq(reader1), DispatchFastX::Fastq(reader2)) => {
-                sample_paired_input(reader1, reader2, io_args.writer, target, seq_count, rng, input_paths)?
+                let iterator = zip_paired_inputs(reader1, reader2, &input_paths).prefilter(prefilter);
+                sample_paired_input(iterator, io_args.writers, target, seq_count, rngs)?
             }
             (DispatchFastX::Fasta(reader1), DispatchFastX::Fasta(reader2)) => {
-                sample_paired_input(reader1, reader2, io_args.writer, target, seq_count, rng, input_paths)?
+                let iterator = zip_paired_inputs(reader1, reader2, &input_paths).prefilter(prefilter);
+                sample_paired_input(iterator, io_args.writers, target, seq_count, rngs)?
             }
             (DispatchFastX::Fastq(_), DispatchFastX::Fasta(_)) => {
                 return Err(std::io::Error::other(
@@ -159,40 +343,275 @@ pub fn sampler_process(args: SamplerArgs) -> Result<(), std::io::Error> {
     } else {
         match reader1.dispatch() {
             DispatchFastX::Fastq(reader) => {
-                sample_single_input(reader, io_args.writer, target, seq_count, rng, &input_path1)?
+                sample_single_input(reader, io_args.writers, target, seq_count, rngs, &input_path1, prefilter)?
             }
             DispatchFastX::Fasta(reader) => {
-                sample_single_input(reader, io_args.writer, target, seq_count, rng, &input_path1)?
+                sample_single_input(reader, io_args.writers, target, seq_count, rngs, &input_path1, prefilter)?
             }
         }
     };
 
-    if verbose {
-        let single_paired = if is_single { "total records" } else { "pairs of records" };
-        let percent = 100.0 * total_downsampled as f32 / total_original as f32;
-        eprintln!("Downsampled {total_original} {single_paired} to {total_downsampled} ({percent:.02} %).");
+    let single_paired = if is_single { "total records" } else { "pairs of records" };
+    let excluded = prefilter.is_active().then(|| prefilter.excluded.get());
+
+    let samples = if let Some(targets) = nested_targets {
+        targets
+            .into_iter()
+            .map(|target| {
+                if target > total_original {
+                    eprintln!(
+                        "Sampler Warning: Target sample size ({target}) was greater than population size ({total_original}); no downsampling has occurred for this target.",
+                    );
+                }
+                SampleSummary::new(total_original, target.min(total_original), None)
+            })
+            .collect()
+    } else {
+        vec![SampleSummary::new(total_original, total_downsampled, bernoulli_probability)]
+    };
+
+    if report.verbose {
+        if let Some(excluded) = excluded {
+            eprintln!(
+                "Excluded {excluded} {unit} failing the quality or length filters before sampling.",
+                unit = if is_single { "records" } else { "pairs of records" }
+            );
+        }
+        for sample in &samples {
+            let percent = 100.0 * sample.fraction;
+            eprintln!(
+                "Downsampled {total_original} {single_paired} to {sampled} ({percent:.02} %).",
+                sampled = sample.sampled
+            );
+            if let Some(bernoulli) = &sample.bernoulli {
+                let z_score = match bernoulli.z_score {
+                    Some(z_score) => format!(" ({z_score:+.2} SD)"),
+                    None => String::new(),
+                };
+                eprintln!(
+                    "Expected {expected:.1} ± {std_dev:.1} for a {percent:.0} % Bernoulli sample, a deviation of {deviation:+.1}{z_score}.",
+                    expected = bernoulli.expected,
+                    std_dev = bernoulli.std_dev,
+                    percent = 100.0 * bernoulli.probability,
+                    deviation = bernoulli.deviation
+                );
+            }
+        }
+    }
+
+    if let Some(writer) = report.summary_json {
+        let summary = SamplerSummary {
+            unit: if is_single { "records" } else { "pairs" },
+            population: total_original,
+            excluded,
+            clusters: None,
+            samples,
+        };
+        summary.write(writer)?;
+    }
+
+    Ok(())
+}
+
+/// How the clusters of a deflated FASTA are sampled, for
+/// `--weight-by-cluster-size`.
+#[derive(Clone, Copy, Debug)]
+struct ClusterWeighting {
+    /// Whether the size in the header of each sampled cluster is replaced by
+    /// the number of its reads that were sampled, for `--adjust-counts`
+    adjust_counts: bool,
+}
+
+/// Samples reads from a deflated FASTA whose headers give the size of each
+/// cluster as `%<SIZE>`, writing each cluster with at least one sampled read.
+///
+/// The target refers to reads, and the reads are sampled uniformly without
+/// replacement, so each cluster is drawn in proportion to its size while only
+/// the patterns are read. The clusters are held in memory, since the number of
+/// reads is only known once every header is read. The sampled read positions
+/// are drawn with Method D as in [`sample_and_write_records`].
+fn sample_clusters<R, W>(
+    io_args: IOArgs<R, W>, rngs: SamplerRngs, target: SamplingTarget, report: SamplerReport, weighting: ClusterWeighting,
+) -> std::io::Result<()>
+where
+    R: Read,
+    W: Write, {
+    let Reader { path, iter } = io_args.reader1;
+    let DispatchFastX::Fasta(reader) = iter.dispatch() else {
+        return Err(std::io::Error::other(format!(
+            "`--weight-by-cluster-size` requires deflated FASTA input, but FASTQ was found in: {}",
+            path.display()
+        )));
+    };
+    // Validity: a single-end writer is opened, since `--output2` and nested
+    // targets conflict with `--weight-by-cluster-size`
+    let Some(RecordWriters::SingleEnd(writer)) = io_args.writers.into_iter().next() else {
+        unreachable!("A single-end writer should be opened")
+    };
+
+    let clusters = reader
+        .map(|record| {
+            let record = record?;
+            let size = parse_cluster_size(&record.name).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Invalid header in file: {path}\nHeader: {name}\n\nWeighting by cluster size requires a header of the format: <PREFIX><ID>%<SIZE>[REST], where <SIZE> is the number of reads in the cluster",
+                        path = path.display(),
+                        name = record.name
+                    ),
+                )
+            })?;
+            Ok((record, size))
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    // The cumulative number of reads at the end of each cluster
+    let ends = clusters
+        .iter()
+        .scan(0, |end, (_, size)| {
+            *end += size;
+            Some(*end)
+        })
+        .collect::<Vec<_>>();
+    let population = ends.last().copied().unwrap_or(0);
+
+    let target_count = match target {
+        SamplingTarget::Count(count) => {
+            if count > population {
+                eprintln!(
+                    "Sampler Warning: Target sample size ({count}) was greater than population size ({population}); no downsampling has occurred.",
+                );
+            }
+            count.min(population)
+        }
+        SamplingTarget::Percent(percent) => population * percent / 100,
+        SamplingTarget::Nested(_) | SamplingTarget::PerGroup { .. } => {
+            unreachable!("Nested and per-group targets conflict with `--weight-by-cluster-size`")
+        }
+    };
+
+    let SamplerRngs {
+        sample: mut rng,
+        mut shuffle,
+    } = rngs;
+    let mut sampled_counts = vec![0; clusters.len()];
+    for position in SkipSampler::new(0..population, target_count, population, &mut rng)? {
+        sampled_counts[ends.partition_point(|&end| end <= position)] += 1;
     }
+
+    let total_clusters = clusters.len();
+    let mut sampled_clusters = 0;
+    clusters
+        .into_iter()
+        .zip(sampled_counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|((mut record, _), count)| {
+            sampled_clusters += 1;
+            if weighting.adjust_counts {
+                record.name = replace_cluster_size(&record.name, count);
+            }
+            record
+        })
+        .shuffle_buffered(shuffle.as_mut())
+        .write_records(writer)?;
+
+    let sample = SampleSummary::new(population, target_count, None);
+    if report.verbose {
+        eprintln!(
+            "Downsampled {population} reads in {total_clusters} clusters to {target_count} reads in {sampled_clusters} clusters ({percent:.02} %).",
+            percent = 100.0 * sample.fraction
+        );
+    }
+    if let Some(writer) = report.summary_json {
+        let summary = SamplerSummary {
+            unit: "reads",
+            population,
+            excluded: None,
+            clusters: Some(ClusterSummary {
+                population: total_clusters,
+                sampled:    sampled_clusters,
+            }),
+            samples: vec![sample],
+        };
+        summary.write(writer)?;
+    }
+
     Ok(())
 }
 
 /// Performs sampling for a single input file.
 ///
 /// This may correspond to either single reads or interleaved paired reads,
-/// depending on the number of writers.
+/// depending on the number of writers. Only the records (or pairs) passing the
+/// `prefilter` are sampled.
 ///
 /// This returns a tuple containing the original counts and downsampled counts.
 /// For single end reads, the counts are the number of records. For paired end
 /// reads, each pair counts once.
 fn sample_single_input<R1, W, A>(
-    reader: R1, writer: RecordWriters<W>, target: SamplingTarget, seq_count: Option<usize>, rng: Xoshiro256StarStar,
-    input_path1: &Path,
+    reader: R1, writers: Vec<RecordWriters<W>>, target: SamplingTarget, seq_count: Option<usize>, mut rngs: SamplerRngs,
+    input_path1: &Path, prefilter: &Prefilter,
 ) -> std::io::Result<(usize, usize)>
 where
     R1: Iterator<Item = std::io::Result<A>>,
     W: Write,
-    A: HeaderReadable + WriteRecord<W> + Debug + Sync + Send + 'static,
+    A: HeaderReadable + WriteRecord<W> + Prefiltered + Clone + Debug + Sync + Send + 'static,
     std::io::Result<A>: WriteRecord<W>, {
-    // Don't perform sampling if target is higher than population sequence count
+    if let SamplingTarget::Nested(targets) = &target {
+        let (single_writers, paired_writers): (Vec<_>, Vec<_>) = writers
+            .into_iter()
+            .partition(|writer| matches!(writer, RecordWriters::SingleEnd(_)));
+
+        if paired_writers.is_empty() {
+            let writers = single_writers.into_iter().filter_map(|writer| match writer {
+                RecordWriters::SingleEnd(writer) => Some(writer),
+                RecordWriters::PairedEnd(_) => None,
+            });
+            return sample_nested_and_write_results(reader.prefilter(prefilter), writers.collect(), targets, rngs);
+        }
+
+        let writers = paired_writers.into_iter().filter_map(|writer| match writer {
+            RecordWriters::SingleEnd(_) => None,
+            RecordWriters::PairedEnd(writer) => Some(writer),
+        });
+        let iterator = reader
+            .deinterleave()
+            .map(|res| res.map_err(|e| e.add_path_context(input_path1)))
+            .prefilter(prefilter);
+        return sample_nested_and_write_results(iterator, writers.collect(), targets, rngs);
+    }
+
+    // Validity: exactly one writer is opened when the target is not nested
+    let writer = writers.into_iter().next().expect("A single writer should be opened");
+
+    if let SamplingTarget::PerGroup { target, groups } = &target {
+        return match writer {
+            RecordWriters::SingleEnd(writer) => sample_grouped_and_write_results(
+                reader.prefilter(prefilter),
+                writer,
+                *target,
+                |record: &A| groups.find(record.header()),
+                rngs,
+            ),
+            RecordWriters::PairedEnd(writer) => {
+                let iterator = reader
+                    .deinterleave()
+                    .map(|res| res.map_err(|e| e.add_path_context(input_path1)))
+                    .prefilter(prefilter);
+                sample_grouped_and_write_results(
+                    iterator,
+                    writer,
+                    *target,
+                    |[read1, _]: &[A; 2]| groups.find(read1.header()),
+                    rngs,
+                )
+            }
+        };
+    }
+
+    // Don't perform sampling if target is higher than population sequence count.
+    // The count is not known when prefiltering, so all records are written
     if let SamplingTarget::Count(target_count) = target
         && let Some(seq_count) = seq_count
         && target_count > seq_count
@@ -202,12 +621,13 @@ where
         );
         match writer {
             RecordWriters::SingleEnd(writer) => {
-                reader.write_records(writer)?;
+                reader.shuffle_buffered(rngs.shuffle.as_mut()).write_records(writer)?;
             }
             RecordWriters::PairedEnd(writer) => {
                 reader
                     .deinterleave()
                     .map(|res| res.map_err(|e| e.add_path_context(input_path1)))
+                    .shuffle_buffered(rngs.shuffle.as_mut())
                     .write_records(writer)?;
             }
         }
@@ -215,35 +635,57 @@ where
     }
 
     match writer {
-        RecordWriters::SingleEnd(writer) => sample_and_write_results(reader, writer, target, seq_count, rng),
+        RecordWriters::SingleEnd(writer) => {
+            sample_and_write_results(reader.prefilter(prefilter), writer, target, seq_count, rngs)
+        }
         RecordWriters::PairedEnd(writer) => {
             let iterator = reader
                 .deinterleave()
-                .map(|res| res.map_err(|e| e.add_path_context(input_path1)));
+                .map(|res| res.map_err(|e| e.add_path_context(input_path1)))
+                .prefilter(prefilter);
 
-            sample_and_write_results(iterator, writer, target, seq_count, rng)
+            sample_and_write_results(iterator, writer, target, seq_count, rngs)
         }
     }
 }
 
-/// Performs sampling for a pair of inputs.
+/// Zips the reads of a pair of inputs, adding context including the paths to
+/// any zipping errors.
+fn zip_paired_inputs<R1, R2, A>(
+    reader1: R1, reader2: R2, input_paths: &[PathBuf; 2],
+) -> impl Iterator<Item = std::io::Result<[A; 2]>>
+where
+    R1: Iterator<Item = std::io::Result<A>>,
+    R2: Iterator<Item = std::io::Result<A>>,
+    A: HeaderReadable, {
+    reader1
+        .zip_paired_reads(reader2)
+        .map(|res| res.map_err(|e| e.add_path_context(&input_paths[0], &input_paths[1])))
+}
+
+/// Performs sampling for a pair of inputs, whose reads have been zipped into
+/// pairs by [`zip_paired_inputs`].
 ///
 /// This returns a tuple containing the original counts and downsampled counts.
 /// Each pair of reads counts once.
-fn sample_paired_input<R1, R2, W, A>(
-    reader1: R1, reader2: R2, writer: RecordWriters<W>, target: SamplingTarget, seq_count: Option<usize>,
-    rng: Xoshiro256StarStar, input_paths: [PathBuf; 2],
+fn sample_paired_input<I, W, A>(
+    iterator: I, writers: Vec<RecordWriters<W>>, target: SamplingTarget, seq_count: Option<usize>, mut rngs: SamplerRngs,
 ) -> std::io::Result<(usize, usize)>
 where
-    R1: Iterator<Item = std::io::Result<A>>,
-    R2: Iterator<Item = std::io::Result<A>>,
+    I: Iterator<Item = std::io::Result<[A; 2]>>,
     W: Write,
-    A: HeaderReadable + WriteRecord<W> + Debug + Sync + Send + 'static, {
-    // Zip the paired reads, and add context including the paths to any zipping
-    // errors
-    let iterator = reader1
-        .zip_paired_reads(reader2)
-        .map(|res| res.map_err(|e| e.add_path_context(&input_paths[0], &input_paths[1])));
+    A: HeaderReadable + WriteRecord<W> + Clone + Debug + Sync + Send + 'static, {
+    if let SamplingTarget::Nested(targets) = &target {
+        return sample_nested_and_write_results(iterator, writers, targets, rngs);
+    }
+
+    // Validity: exactly one writer is opened when the target is not nested
+    let writer = writers.into_iter().next().expect("A single writer should be opened");
+
+    if let SamplingTarget::PerGroup { target, groups } = &target {
+        let group_of = |[read1, _]: &[A; 2]| groups.find(read1.header());
+        return sample_grouped_and_write_results(iterator, writer, *target, group_of, rngs);
+    }
 
     // Don't perform sampling if target is higher than population sequence count
     if let SamplingTarget::Count(target_count) = target
@@ -253,11 +695,124 @@ where
         eprintln!(
             "Sampler Warning: Target sample size ({target_count}) was greater than population size ({seq_count}); no downsampling has occurred.",
         );
-        iterator.write_records(writer)?;
+        iterator.shuffle_buffered(rngs.shuffle.as_mut()).write_records(writer)?;
         return Ok((seq_count, seq_count));
     }
 
-    sample_and_write_results(iterator, writer, target, seq_count, rng)
+    sample_and_write_results(iterator, writer, target, seq_count, rngs)
+}
+
+/// Samples an exact `target` from a population of `seq_count` records (or pairs
+/// of records) by skipping over the records that are not sampled, rather than
+/// parsing them.
+///
+/// The inputs are reopened with `open`, so they must be regular files. The
+/// sampled positions are drawn with Method D as in
+/// [`sample_and_write_records`], so the same records are sampled for a given
+/// seed. Only the sampled records are parsed and have their paired headers
+/// checked. The other records are only checked for their structure, and to
+/// ensure that paired inputs have the same number of records and that
+/// interleaved inputs have an even number.
+///
+/// This returns a tuple containing the original count and downsampled count.
+fn sample_by_skipping<S, A, W>(
+    open: impl Fn(&Path) -> std::io::Result<S>, input_path1: &Path, input_path2: Option<&Path>, writer: RecordWriters<W>,
+    target: usize, seq_count: usize, rngs: SamplerRngs,
+) -> std::io::Result<(usize, usize)>
+where
+    S: Iterator<Item = std::io::Result<A>> + SkipRecords,
+    W: Write,
+    A: HeaderReadable + WriteRecord<W> + Clone + Debug + Sync + Send + 'static, {
+    let SamplerRngs {
+        sample: mut rng,
+        mut shuffle,
+    } = rngs;
+    let positions = SkipSampler::new(0..seq_count, target, seq_count, &mut rng)?;
+    let mut reader1 = open(input_path1)?;
+    let ended_early = |path: &Path| {
+        std::io::Error::other("The input ended before the expected number of records")
+            .with_path_context("Failed to sample the reads in file", path)
+    };
+
+    let mut next_position = 0;
+    let mut total_downsampled = 0;
+
+    if let Some(input_path2) = input_path2 {
+        let mut reader2 = open(input_path2)?;
+        positions
+            .map(|position| {
+                let skip = position - next_position;
+                next_position = position + 1;
+                total_downsampled += 1;
+
+                let read1 = reader1.nth(skip).ok_or_else(|| ended_early(input_path1))??;
+                let read2 = reader2.nth(skip).ok_or_else(|| ended_early(input_path2))??;
+                if let Err(source) = check_paired_headers(&read1, &read2) {
+                    let records = [read1, read2];
+                    return Err(
+                        ZipPairedReadsError::BadHeaders { records, source }.add_path_context(input_path1, input_path2)
+                    );
+                }
+                Ok([read1, read2])
+            })
+            .shuffle_buffered(shuffle.as_mut())
+            .write_records(writer)?;
+
+        let remaining1 = reader1.skip_remaining()?;
+        let remaining2 = reader2.skip_remaining()?;
+        if remaining1 != remaining2 {
+            return Err(std::io::Error::other(format!(
+                "The paired inputs have different numbers of records: {total1} in {path1} and {total2} in {path2}",
+                total1 = next_position + remaining1,
+                total2 = next_position + remaining2,
+                path1 = input_path1.display(),
+                path2 = input_path2.display()
+            )));
+        }
+        return Ok((seq_count, total_downsampled));
+    }
+
+    match writer {
+        RecordWriters::SingleEnd(writer) => {
+            positions
+                .map(|position| {
+                    let skip = position - next_position;
+                    next_position = position + 1;
+                    total_downsampled += 1;
+                    reader1.nth(skip).ok_or_else(|| ended_early(input_path1))?
+                })
+                .process_results(|iter| iter.shuffle_buffered(shuffle.as_mut()).write_records(writer))??;
+            reader1.skip_remaining()?;
+        }
+        RecordWriters::PairedEnd(writer) => {
+            positions
+                .map(|position| {
+                    let skip = 2 * (position - next_position);
+                    next_position = position + 1;
+                    total_downsampled += 1;
+
+                    let read1 = reader1.nth(skip).ok_or_else(|| ended_early(input_path1))??;
+                    let read2 = reader1.next().ok_or_else(|| ended_early(input_path1))??;
+                    if let Err(source) = check_paired_headers(&read1, &read2) {
+                        let records = [read1, read2];
+                        return Err(DeinterleaveError::BadHeaders { records, source }.add_path_context(input_path1));
+                    }
+                    Ok([read1, read2])
+                })
+                .shuffle_buffered(shuffle.as_mut())
+                .write_records(writer)?;
+
+            if !reader1.skip_remaining()?.is_multiple_of(2) {
+                return Err(
+                    std::io::Error::other("An odd number of reads was found while de-interleaving")
+                        .with_path_context("Failed to deinterleave the reads in file", input_path1)
+                        .into(),
+                );
+            }
+        }
+    }
+
+    Ok((seq_count, total_downsampled))
 }
 
 /// Samples and writes all records from an iterator of results, propagating any
@@ -281,7 +836,7 @@ where
 /// [`FastQ`]: zoe::data::records::fastq::FastQ
 /// [`FastaSeq`]: zoe::data::records::fasta::FastaSeq
 fn sample_and_write_results<I, W, A, E>(
-    iterator: I, writer: W, target: SamplingTarget, seq_count: Option<usize>, rng: Xoshiro256StarStar,
+    iterator: I, writer: W, target: SamplingTarget, seq_count: Option<usize>, rngs: SamplerRngs,
 ) -> std::io::Result<(usize, usize)>
 where
     I: Iterator<Item = Result<A, E>>,
@@ -289,7 +844,7 @@ where
     A: WriteRecordCompatibleItem<W>,
     std::io::Error: From<E>, {
     iterator.process_results(|mut iter| {
-        let out = sample_and_write_records(&mut iter, writer, target, seq_count, rng);
+        let out = sample_and_write_records(&mut iter, writer, target, seq_count, rngs);
         // Fully exhaust the input iterator, so that any errors are indeed
         // surfaced
         iter.last();
@@ -323,11 +878,15 @@ where
 /// [`FastaSeq`]: zoe::data::records::fasta::FastaSeq
 #[inline]
 fn sample_and_write_records<I, W>(
-    iterator: &mut I, writer: W, target: SamplingTarget, seq_count: Option<usize>, mut rng: Xoshiro256StarStar,
+    iterator: &mut I, writer: W, target: SamplingTarget, seq_count: Option<usize>, rngs: SamplerRngs,
 ) -> std::io::Result<(usize, usize)>
 where
     I: Iterator<Item: WriteRecordCompatibleItem<W>>,
     W: SequenceWriter, {
+    let SamplerRngs {
+        sample: mut rng,
+        mut shuffle,
+    } = rngs;
     let mut total_original = 0;
     let mut total_downsampled = 0;
 
@@ -337,6 +896,7 @@ where
                 .inspect(|_| total_original += 1)
                 .downsample_bernoulli(percent as f32 / 100.0, &mut rng)
                 .inspect(|_| total_downsampled += 1)
+                .shuffle_buffered(shuffle.as_mut())
                 .write_records(writer)?;
         }
         SamplingTarget::Count(target) => {
@@ -344,27 +904,380 @@ where
                 total_original = total_items;
                 SkipSampler::new(iterator, target, total_items, &mut rng)?
                     .inspect(|_| total_downsampled += 1)
+                    .shuffle_buffered(shuffle.as_mut())
                     .write_records(writer)?;
             } else {
-                let samples = downsample_reservoir(iterator.inspect(|_| total_original += 1), &mut rng, target);
+                let mut samples = downsample_reservoir(iterator.inspect(|_| total_original += 1), &mut rng, target);
                 total_downsampled = samples.len();
+                if let Some(shuffle) = &mut shuffle {
+                    samples.shuffle(&mut shuffle.rng);
+                }
                 samples.into_iter().write_records(writer)?;
             }
         }
+        SamplingTarget::Nested(_) => unreachable!("Nested targets are sampled with `sample_nested_and_write_records`"),
+        SamplingTarget::PerGroup { .. } => {
+            unreachable!("Per-group targets are sampled with `sample_grouped_and_write_records`")
+        }
+    }
+
+    Ok((total_original, total_downsampled))
+}
+
+/// Samples and writes records for nested targets from an iterator of results,
+/// propagating any errors in the input.
+///
+/// See [`sample_nested_and_write_records`] for more details.
+fn sample_nested_and_write_results<I, W, A, E>(
+    iterator: I, writers: Vec<W>, targets: &[usize], rngs: SamplerRngs,
+) -> std::io::Result<(usize, usize)>
+where
+    I: Iterator<Item = Result<A, E>>,
+    W: SequenceWriter,
+    A: WriteRecordCompatibleItem<W> + Clone,
+    std::io::Error: From<E>, {
+    iterator.process_results(|iter| sample_nested_and_write_records(iter, writers, targets, rngs))?
+}
+
+/// A record kept during nested sampling, ordered by its random key (with ties
+/// broken by the input index).
+struct KeyedRecord<A> {
+    key:    u64,
+    index:  usize,
+    record: A,
+}
+
+impl<A> PartialEq for KeyedRecord<A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<A> Eq for KeyedRecord<A> {}
+
+impl<A> PartialOrd for KeyedRecord<A> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<A> Ord for KeyedRecord<A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.key, self.index).cmp(&(other.key, other.index))
+    }
+}
+
+/// Samples records for several nested targets in a single pass, writing the
+/// sample for `targets[i]` to `writers[i]`.
+///
+/// Each record is assigned a random key, and the sample for a target `t` is
+/// the `t` records with the smallest keys, which is a uniform random sample.
+/// Since the samples are all prefixes of the same ordering, each sample is a
+/// subset of every larger one. Only the records for the largest target are
+/// kept in memory, using a max-heap of the smallest keys seen so far. The
+/// records are written in their input order, unless `shuffle` is given, in
+/// which case every sample is written in the same random order.
+///
+/// This returns a tuple containing the original count and the size of the
+/// largest sample.
+fn sample_nested_and_write_records<I, W>(
+    iterator: I, writers: Vec<W>, targets: &[usize], rngs: SamplerRngs,
+) -> std::io::Result<(usize, usize)>
+where
+    I: Iterator<Item: WriteRecordCompatibleItem<W> + Clone>,
+    W: SequenceWriter, {
+    let SamplerRngs {
+        sample: mut rng,
+        shuffle,
+    } = rngs;
+    let capacity = targets.iter().copied().max().unwrap_or(0);
+    let mut heap = BinaryHeap::new();
+    let mut total_original = 0;
+
+    for (index, record) in iterator.enumerate() {
+        total_original += 1;
+        let keyed = KeyedRecord {
+            key: rng.next_u64(),
+            index,
+            record,
+        };
+
+        if heap.len() < capacity {
+            heap.push(keyed);
+        } else if let Some(mut largest) = heap.peek_mut()
+            && keyed < *largest
+        {
+            *largest = keyed;
+        }
+    }
+
+    // The rank of a record is its position when ordered by key, and the sample
+    // for a target contains the records of lower rank
+    let mut ranked = heap
+        .into_sorted_vec()
+        .into_iter()
+        .enumerate()
+        .map(|(rank, keyed)| (keyed.index, rank, keyed.record))
+        .collect::<Vec<_>>();
+    match shuffle {
+        Some(mut shuffle) => ranked.shuffle(&mut shuffle.rng),
+        None => ranked.sort_unstable_by_key(|(index, _, _)| *index),
+    }
+
+    for (&target, writer) in targets.iter().zip(writers) {
+        ranked
+            .iter()
+            .filter(|(_, rank, _)| *rank < target)
+            .map(|(_, _, record)| record.clone())
+            .write_records(writer)?;
+    }
+
+    Ok((total_original, ranked.len()))
+}
+
+/// The random number generators used by sampler.
+struct SamplerRngs {
+    /// The generator for drawing the sample
+    sample:  Xoshiro256StarStar,
+    /// The order of the written records for `--shuffle`, if given
+    shuffle: Option<Shuffle>,
+}
+
+/// The random order in which sampled records are written, for `--shuffle`.
+struct Shuffle {
+    /// The maximum number of records held by [`ShuffleBuffered`]
+    buffer: usize,
+    rng:    Xoshiro256StarStar,
+}
+
+/// An iterator which randomizes the order of the items of another, holding at
+/// most `capacity` items in memory.
+///
+/// Each item is drawn uniformly at random from those held, which are then
+/// refilled from the inner iterator. If the inner iterator has at most
+/// `capacity` items, they are shuffled in full. Otherwise, the shuffle is local,
+/// and no item is returned more than `capacity` positions before its position
+/// in the input. If there is no generator, the items are returned in order.
+struct ShuffleBuffered<'a, I: Iterator> {
+    iter:     std::iter::Fuse<I>,
+    held:     Vec<I::Item>,
+    capacity: usize,
+    rng:      Option<&'a mut Xoshiro256StarStar>,
+}
+
+impl<I: Iterator> Iterator for ShuffleBuffered<'_, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(rng) = self.rng.as_deref_mut() else {
+            return self.iter.next();
+        };
+
+        while self.held.len() < self.capacity
+            && let Some(item) = self.iter.next()
+        {
+            self.held.push(item);
+        }
+
+        if self.held.is_empty() {
+            return None;
+        }
+        let index = rng.random_range(0..self.held.len());
+        Some(self.held.swap_remove(index))
+    }
+}
+
+/// An extension trait for shuffling the records written by sampler.
+trait ShuffleBufferedExt: Iterator + Sized {
+    /// Randomizes the order of the items as in [`ShuffleBuffered`], or returns
+    /// them in order if `shuffle` is [`None`].
+    fn shuffle_buffered(self, shuffle: Option<&mut Shuffle>) -> ShuffleBuffered<'_, Self> {
+        let (capacity, rng) = match shuffle {
+            Some(shuffle) => (shuffle.buffer, Some(&mut shuffle.rng)),
+            None => (0, None),
+        };
+        ShuffleBuffered {
+            iter: self.fuse(),
+            held: Vec::new(),
+            capacity,
+            rng,
+        }
+    }
+}
+
+impl<I: Iterator> ShuffleBufferedExt for I {}
+
+/// Samples and writes records with at most `target` from each group from an
+/// iterator of results, propagating any errors in the input.
+///
+/// See [`sample_grouped_and_write_records`] for more details.
+fn sample_grouped_and_write_results<I, W, A, E, F>(
+    iterator: I, writer: W, target: usize, group_of: F, rngs: SamplerRngs,
+) -> std::io::Result<(usize, usize)>
+where
+    I: Iterator<Item = Result<A, E>>,
+    W: SequenceWriter,
+    A: WriteRecordCompatibleItem<W>,
+    F: Fn(&A) -> Option<usize>,
+    std::io::Error: From<E>, {
+    iterator.process_results(|iter| sample_grouped_and_write_records(iter, writer, target, group_of, rngs))?
+}
+
+/// Samples at most `target` records from each group, where `group_of` gives
+/// the index of the group of a record (if it has one), and writes them.
+///
+/// Each group is sampled independently in the same way as the largest target
+/// in [`sample_nested_and_write_records`], so that only the records kept so
+/// far for each group are held in memory. Records without a group are not
+/// sampled, and a warning is given if there are any. The records are written
+/// in their input order, unless `shuffle` is given.
+///
+/// This returns a tuple containing the original count (including records
+/// without a group) and the downsampled count.
+fn sample_grouped_and_write_records<I, W, F>(
+    iterator: I, writer: W, target: usize, group_of: F, rngs: SamplerRngs,
+) -> std::io::Result<(usize, usize)>
+where
+    I: Iterator<Item: WriteRecordCompatibleItem<W>>,
+    W: SequenceWriter,
+    F: Fn(&I::Item) -> Option<usize>, {
+    let SamplerRngs {
+        sample: mut rng,
+        shuffle,
+    } = rngs;
+    let mut heaps: Vec<BinaryHeap<KeyedRecord<I::Item>>> = Vec::new();
+    let mut total_original = 0;
+    let mut ungrouped = 0;
+
+    for (index, record) in iterator.enumerate() {
+        total_original += 1;
+        let Some(group) = group_of(&record) else {
+            ungrouped += 1;
+            continue;
+        };
+        if heaps.len() <= group {
+            heaps.resize_with(group + 1, BinaryHeap::new);
+        }
+
+        let heap = &mut heaps[group];
+        let keyed = KeyedRecord {
+            key: rng.next_u64(),
+            index,
+            record,
+        };
+
+        if heap.len() < target {
+            heap.push(keyed);
+        } else if let Some(mut largest) = heap.peek_mut()
+            && keyed < *largest
+        {
+            *largest = keyed;
+        }
     }
 
+    if ungrouped > 0 {
+        eprintln!(
+            "Sampler Warning: {ungrouped} of {total_original} records were not assigned to a group and were not sampled."
+        );
+    }
+
+    let mut sampled = heaps.into_iter().flat_map(BinaryHeap::into_vec).collect::<Vec<_>>();
+    match shuffle {
+        Some(mut shuffle) => sampled.shuffle(&mut shuffle.rng),
+        None => sampled.sort_unstable_by_key(|keyed| keyed.index),
+    }
+    let total_downsampled = sampled.len();
+    sampled.into_iter().map(|keyed| keyed.record).write_records(writer)?;
+
     Ok((total_original, total_downsampled))
 }
 
+/// The `--min-quality` and `--min-length` filters applied before sampling, so
+/// that the target refers to the reads passing them.
+struct Prefilter {
+    /// The filters, or `None` if neither option was given
+    filter:         Option<ParsedQualityFilterArgs>,
+    /// Whether `--min-quality` was given
+    checks_quality: bool,
+    /// The number of records (or pairs) excluded by the filters
+    excluded:       Cell<usize>,
+}
+
+impl Prefilter {
+    /// Whether any filter is applied.
+    fn is_active(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// Whether the read quality is filtered, which requires FASTQ input.
+    fn checks_quality(&self) -> bool {
+        self.checks_quality
+    }
+
+    /// Whether `record` passes the filters, counting it as excluded if not.
+    fn passes<A: Prefiltered>(&self, record: &A) -> bool {
+        let passes = self
+            .filter
+            .as_ref()
+            .is_none_or(|filter| record.passes(filter, self.checks_quality));
+        if !passes {
+            self.excluded.set(self.excluded.get() + 1);
+        }
+        passes
+    }
+}
+
+/// A record, or pair of records, which can be checked against the
+/// [`Prefilter`].
+trait Prefiltered {
+    /// Whether the record passes the length filter of `filter`, and its quality
+    /// filter if `check_quality` is true.
+    fn passes(&self, filter: &ParsedQualityFilterArgs, check_quality: bool) -> bool;
+}
+
+impl Prefiltered for FastQ {
+    fn passes(&self, filter: &ParsedQualityFilterArgs, check_quality: bool) -> bool {
+        filter.passes_raw_length(self.sequence.len())
+            && (!check_quality || filter.passes_quality(self.get_q_center(filter.use_median)))
+    }
+}
+
+impl Prefiltered for FastaSeq {
+    fn passes(&self, filter: &ParsedQualityFilterArgs, _check_quality: bool) -> bool {
+        // Validity: `--min-quality` is rejected for FASTA input
+        filter.passes_raw_length(self.sequence.len())
+    }
+}
+
+/// A pair passes only if both reads do.
+impl<A: Prefiltered> Prefiltered for [A; 2] {
+    fn passes(&self, filter: &ParsedQualityFilterArgs, check_quality: bool) -> bool {
+        self.iter().all(|read| read.passes(filter, check_quality))
+    }
+}
+
+/// An extension trait for applying the [`Prefilter`] to an iterator of results.
+trait PrefilterExt<A, E>: Iterator<Item = Result<A, E>> + Sized {
+    /// Removes the records that fail the `prefilter`, keeping any errors so
+    /// that they are propagated.
+    fn prefilter(self, prefilter: &Prefilter) -> impl Iterator<Item = Result<A, E>>
+    where
+        A: Prefiltered, {
+        self.filter(|res| res.as_ref().map_or(true, |record| prefilter.passes(record)))
+    }
+}
+
+impl<I: Iterator<Item = Result<A, E>>, A, E> PrefilterExt<A, E> for I {}
+
 /// Gets the number of input sequences, using whichever paired input exists, is
 /// a file, and is not zipped.
 ///
 /// If neither meets these conditions, `None` is returned.
-fn get_paired_seq_count(io_args: &IOArgs) -> std::io::Result<Option<usize>> {
+fn get_paired_seq_count<R: Read, W>(io_args: &IOArgs<R, W>) -> std::io::Result<Option<usize>> {
     let IOArgs {
         reader1,
         reader2,
-        writer: _,
+        writers: _,
     } = &io_args;
 
     if reader1.path.is_file() && !is_gz(&reader1.path) {
@@ -381,16 +1294,63 @@ fn get_paired_seq_count(io_args: &IOArgs) -> std::io::Result<Option<usize>> {
 
 /// The type sampler uses for input, along with the input path for error
 /// context.
-struct Reader {
+struct Reader<R: Read> {
     path: PathBuf,
-    iter: IterWithContext<FastXReader<ReadFileZipInThread>>,
+    iter: IterWithContext<FastXReader<R>>,
 }
 
-/// The IO arguments used by sampler, including up to two readers and writers.
-struct IOArgs {
-    reader1: Reader,
-    reader2: Option<Reader>,
-    writer:  RecordWriters<WriteFileZipStdout>,
+/// The IO arguments used by sampler, including up to two readers and the
+/// writers.
+///
+/// ## Validity
+///
+/// `writers` contains one entry per target for [`SamplingTarget::Nested`] (in
+/// the same order), and exactly one entry otherwise. All entries are the same
+/// variant of [`RecordWriters`].
+struct IOArgs<R: Read, W> {
+    reader1: Reader<R>,
+    reader2: Option<Reader<R>>,
+    writers: Vec<RecordWriters<W>>,
+}
+
+impl<R: Read, W> IOArgs<R, W> {
+    /// Assembles the [`IOArgs`] from the opened readers and writers, along with
+    /// the input paths.
+    fn new(
+        readers: RecordReaders<IterWithContext<FastXReader<R>>>, writers: Vec<RecordWriters<W>>, input1: PathBuf,
+        input2: Option<PathBuf>,
+    ) -> Self {
+        let RecordReaders { reader1, reader2 } = readers;
+
+        let reader1 = Reader {
+            path: input1,
+            iter: reader1,
+        };
+        let reader2 = input2.zip(reader2).map(|(path, iter)| Reader { path, iter });
+
+        IOArgs {
+            reader1,
+            reader2,
+            writers,
+        }
+    }
+}
+
+/// The paths used by sampler, before the files are opened.
+///
+/// ## Validity
+///
+/// `outputs` contains one entry per target for [`SamplingTarget::Nested`] (in
+/// the same order, with the placeholders filled), and exactly one entry
+/// otherwise.
+struct SamplerPaths {
+    input1:      PathBuf,
+    input2:      Option<PathBuf>,
+    outputs:     Vec<(Option<PathBuf>, Option<PathBuf>)>,
+    buffering:   OutputBuffering,
+    compression: CompressionOptions,
+    #[cfg(feature = "async-io")]
+    async_io:    bool,
 }
 
 /// The target number of sequences to sample
@@ -399,54 +1359,357 @@ enum SamplingTarget {
     Percent(usize),
     /// The target as an exact count
     Count(usize),
+    /// Several exact counts for nested samples, sorted and without duplicates
+    Nested(Vec<usize>),
+    /// An exact count for each group of reads
+    PerGroup { target: usize, groups: ReadGroups },
+}
+
+/// How the outcome of sampling is reported.
+struct SamplerReport {
+    /// Whether to print the sample sizes to stderr
+    verbose:      bool,
+    /// The writer for `--summary-json`, if given
+    summary_json: Option<BufWriter<WriterWithContext<File>>>,
+}
+
+/// The summary written by `--summary-json`.
+#[derive(Serialize, Debug)]
+struct SamplerSummary {
+    /// Whether the counts are of single records or of pairs
+    unit:       &'static str,
+    /// The number of records (or pairs) in the input, or of those passing the
+    /// prefilter if one was given
+    population: usize,
+    /// The number of records (or pairs) excluded by the prefilter, if one was
+    /// given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    excluded:   Option<usize>,
+    /// The number of clusters in the input and sampled, for
+    /// `--weight-by-cluster-size`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clusters:   Option<ClusterSummary>,
+    /// Each sample, in the order of the targets
+    samples:    Vec<SampleSummary>,
+}
+
+impl SamplerSummary {
+    /// Writes the summary as JSON.
+    fn write<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+/// The number of clusters of a deflated FASTA in the input and with at least
+/// one sampled read.
+#[derive(Serialize, Debug)]
+struct ClusterSummary {
+    population: usize,
+    sampled:    usize,
+}
+
+/// The achieved size of a sample.
+#[derive(Serialize, Debug)]
+struct SampleSummary {
+    /// The number of records (or pairs) sampled
+    sampled:   usize,
+    /// The fraction of the population sampled
+    fraction:  f64,
+    /// The expected and realized sample sizes, if each record was sampled
+    /// independently, in which case the sample size is not exact
+    bernoulli: Option<BernoulliSummary>,
+}
+
+impl SampleSummary {
+    /// Summarizes a sample of `sampled` out of `population` records, which
+    /// were sampled independently with `bernoulli_probability` if it is given.
+    fn new(population: usize, sampled: usize, bernoulli_probability: Option<f64>) -> Self {
+        SampleSummary {
+            sampled,
+            fraction: sampled as f64 / population as f64,
+            bernoulli: bernoulli_probability.map(|probability| BernoulliSummary::new(population, sampled, probability)),
+        }
+    }
+}
+
+/// The expected and realized size of a Bernoulli sample, for checking that a
+/// percent target was achieved within the expected variation.
+#[derive(Serialize, Debug)]
+struct BernoulliSummary {
+    /// The probability with which each record was sampled
+    probability: f64,
+    /// The expected sample size, `n * p`
+    expected:    f64,
+    /// The standard deviation of the sample size, `sqrt(n * p * (1 - p))`
+    std_dev:     f64,
+    /// The realized sample size minus the expected size
+    deviation:   f64,
+    /// The deviation in standard deviations, or `None` if the sample size
+    /// cannot vary
+    z_score:     Option<f64>,
+}
+
+impl BernoulliSummary {
+    fn new(population: usize, sampled: usize, probability: f64) -> Self {
+        let expected = population as f64 * probability;
+        let std_dev = (expected * (1.0 - probability)).sqrt();
+        let deviation = sampled as f64 - expected;
+        BernoulliSummary {
+            probability,
+            expected,
+            std_dev,
+            deviation,
+            z_score: (std_dev > 0.0).then(|| deviation / std_dev),
+        }
+    }
+}
+
+/// The groups, such as amplicons, to which reads are assigned by `--groups`.
+struct ReadGroups(HashMap<Box<str>, usize, SeedableRandomState>);
+
+impl ReadGroups {
+    /// Reads the groups from a tab-delimited file with the columns `read_id`
+    /// and `group`. Blank lines and lines starting with `#` are skipped. A read
+    /// ID may be repeated (such as for both reads of a pair) as long as the
+    /// group is the same.
+    ///
+    /// ## Errors
+    ///
+    /// Errors are returned if the file cannot be read, or contains a line with
+    /// fewer than two columns, an empty read ID, or a read ID assigned to more
+    /// than one group.
+    fn from_tsv(path: &Path) -> std::io::Result<Self> {
+        let invalid = |msg: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{msg} in the groups file: {path}", path = path.display()),
+            )
+        };
+
+        let contents = std::fs::read_to_string(path)?;
+
+        let mut reads = HashMap::with_hasher(get_hasher());
+        let mut group_indices = HashMap::<&str, usize, SeedableRandomState>::with_hasher(get_hasher());
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line_number = line_number + 1;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+            let [read_id, group, ..] = fields.as_slice() else {
+                return Err(invalid(format!("Expected two columns on line {line_number}")));
+            };
+            if read_id.is_empty() {
+                return Err(invalid(format!("Empty read ID on line {line_number}")));
+            }
+
+            let next_index = group_indices.len();
+            let group_index = *group_indices.entry(group).or_insert(next_index);
+
+            match reads.entry(Box::<str>::from(*read_id)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(group_index);
+                }
+                Entry::Occupied(entry) if *entry.get() != group_index => {
+                    return Err(invalid(format!(
+                        "The read ID '{read_id}' is assigned to more than one group on line {line_number}"
+                    )));
+                }
+                Entry::Occupied(_) => {}
+            }
+        }
+
+        Ok(ReadGroups(reads))
+    }
+
+    /// Finds the index of the group of a read from its header, whose first
+    /// word is the read ID.
+    fn find(&self, header: &str) -> Option<usize> {
+        let read_id = header.split_ascii_whitespace().next()?;
+        self.0.get(read_id).copied()
+    }
+}
+
+/// The placeholder in output paths which is replaced by the target for nested
+/// sampling.
+const TARGET_PLACEHOLDER: &str = "{target}";
+
+/// Fills the [`TARGET_PLACEHOLDER`] in an output path template.
+fn fill_output_template(template: &Path, target: usize) -> PathBuf {
+    PathBuf::from(template.to_string_lossy().replace(TARGET_PLACEHOLDER, &target.to_string()))
 }
 
-fn parse_sampler_args(args: SamplerArgs) -> Result<(IOArgs, Xoshiro256StarStar, SamplingTarget, bool), std::io::Error> {
+/// Parses the sampler arguments from the clap arguments, reading the groups
+/// file if `--per-group-target` was given and opening the `--summary-json`
+/// output.
+///
+/// ## Errors
+///
+/// Any errors while reading the groups file or opening the summary are
+/// propagated, with the path as context.
+fn parse_sampler_args(
+    args: SamplerArgs,
+) -> std::io::Result<(
+    SamplerPaths,
+    SamplerRngs,
+    SamplingTarget,
+    SamplerReport,
+    Prefilter,
+    Option<ClusterWeighting>,
+)> {
     let rng = if let Some(seed) = &args.rng_seed {
         Xoshiro256StarStar::seed_from_u64(*seed)
     } else {
         make_rng()
     };
 
-    let readers = InputOptions::new_from_paths(&args.input_file, args.input_file2.as_ref())
+    // The shuffle draws from a jump ahead of the sampling generator, so that
+    // the same records are sampled with or without `--shuffle`
+    let shuffle = args.shuffle.then(|| {
+        let mut rng = rng.clone();
+        rng.jump();
+        Shuffle {
+            buffer: args.shuffle_buffer.get(),
+            rng,
+        }
+    });
+    let rngs = SamplerRngs { sample: rng, shuffle };
+
+    let target = if let Some(count) = args.target.subsample_target {
+        SamplingTarget::Count(count)
+    } else if let Some(percent) = args.target.percent_target {
+        SamplingTarget::Percent(percent)
+    } else if let Some(mut targets) = args.target.targets {
+        targets.sort_unstable();
+        targets.dedup();
+        SamplingTarget::Nested(targets)
+    } else if let Some(target) = args.target.per_group_target
+        && let Some(path) = &args.groups
+    {
+        let groups = ReadGroups::from_tsv(path).with_path_context("Failed to read the groups file", path)?;
+        SamplingTarget::PerGroup { target, groups }
+    } else {
+        unreachable!("This can't be reached because clap requires a value for a target")
+    };
+
+    let outputs = if let SamplingTarget::Nested(targets) = &target {
+        let templates = args.output.iter().chain(args.output2.iter());
+        if let Some(template) = templates
+            .clone()
+            .find(|template| !template.to_string_lossy().contains(TARGET_PLACEHOLDER))
+        {
+            abort_clap(
+                ErrorKind::InvalidValue,
+                format!(
+                    "Output paths must contain `{TARGET_PLACEHOLDER}` when using `--targets`, but found: {}",
+                    template.display()
+                ),
+                Some("sampler"),
+            );
+        }
+
+        targets
+            .iter()
+            .map(|&target| {
+                let output1 = args.output.as_deref().map(|path| fill_output_template(path, target));
+                let output2 = args.output2.as_deref().map(|path| fill_output_template(path, target));
+                (output1, output2)
+            })
+            .collect()
+    } else {
+        vec![(args.output, args.output2)]
+    };
+
+    let paths = SamplerPaths {
+        input1: args.input_file,
+        input2: args.input_file2,
+        outputs,
+        buffering: args.buffering.buffering(),
+        compression: args.compression.options(GzipFormat::Gzip),
+        #[cfg(feature = "async-io")]
+        async_io: args.async_io,
+    };
+    let report = SamplerReport {
+        verbose:      args.verbose,
+        summary_json: match &args.summary_json {
+            Some(path) => Some(OutputOptions::new_from_path(path).use_file().open()?),
+            None => None,
+        },
+    };
+    let prefilter = Prefilter {
+        filter:         (args.min_quality.is_some() || args.min_length.is_some()).then(|| ParsedQualityFilterArgs {
+            min_read_quality:       args.min_quality.unwrap_or(0),
+            use_median:             args.use_median,
+            min_length:             args.min_length.map_or(1, NonZeroUsize::get),
+            enforce_clipped_length: false,
+            max_n_frac:             None,
+        }),
+        checks_quality: args.min_quality.is_some(),
+        excluded:       Cell::new(0),
+    };
+    let weighting = args.weight_by_cluster_size.then_some(ClusterWeighting {
+        adjust_counts: args.adjust_counts,
+    });
+    Ok((paths, rngs, target, report, prefilter, weighting))
+}
+
+/// Opens the inputs and outputs for sampler, decoding any gzip inputs on a
+/// separate thread.
+fn open_sampler_io(paths: SamplerPaths) -> std::io::Result<IOArgs<ReadFileZipInThread, WriteFileZipStdout>> {
+    let readers = InputOptions::new_from_paths(&paths.input1, paths.input2.as_ref())
         .use_file_or_zip()
         .decode_in_thread()
         .parse_fastx()
         .open()?;
 
-    let writer = OutputOptions::new_from_opt_paths(args.output.as_ref(), args.output2.as_ref())
-        .use_file_zip_or_stdout()
-        .open()?;
+    let writers = paths
+        .outputs
+        .iter()
+        .map(|(output1, output2)| {
+            OutputOptions::new_from_opt_paths(output1.as_ref(), output2.as_ref())
+                .with_buffering(paths.buffering)
+                .with_compression(paths.compression)
+                .use_file_zip_or_stdout()
+                .open()
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
 
-    let RecordReaders { reader1, reader2 } = readers;
+    Ok(IOArgs::new(readers, writers, paths.input1, paths.input2))
+}
 
-    let reader1 = Reader {
-        path: args.input_file,
-        iter: reader1,
-    };
-    let reader2 = args.input_file2.zip(reader2).map(|(path, iter)| Reader { path, iter });
+/// Opens the inputs and outputs for sampler, where files are read and written
+/// by async tasks. This must be called within [`run_with_async_io`].
+#[cfg(feature = "async-io")]
+fn open_sampler_io_async(paths: SamplerPaths) -> std::io::Result<IOArgs<ReadFileZipAsync, WriteFileZipStdoutAsync>> {
+    let readers = InputOptions::new_from_paths(&paths.input1, paths.input2.as_ref())
+        .use_file_or_zip_async()
+        .parse_fastx()
+        .open()?;
 
-    let io_args = IOArgs {
-        reader1,
-        reader2,
-        writer,
-    };
-    let target = if let Some(count) = args.target.subsample_target {
-        SamplingTarget::Count(count)
-    } else if let Some(percent) = args.target.percent_target {
-        SamplingTarget::Percent(percent)
-    } else {
-        unreachable!("This can't be reached because clap requires a value for either count or percent")
-    };
-    Ok((io_args, rng, target, args.verbose))
+    let writers = paths
+        .outputs
+        .iter()
+        .map(|(output1, output2)| {
+            OutputOptions::new_from_opt_paths(output1.as_ref(), output2.as_ref())
+                .with_compression(paths.compression)
+                .use_file_zip_or_stdout_async()
+                .open()
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    Ok(IOArgs::new(readers, writers, paths.input1, paths.input2))
 }
 
 /// Gets the count of the number of records in `input_file`.
 ///
-/// For FASTQ, this is achieved by counting the number of lines, and dividing it
-/// by 4. For FASTA, this is achieved by counting the number of header
-/// characters `>` in the file. The input file must exist, be a file, and not be
-/// zipped.
+/// The records are skipped rather than parsed (see [`SkipRecords`]). For FASTQ,
+/// this is achieved by counting the number of lines, and dividing it by 4. For
+/// FASTA, this is achieved by counting the number of header characters `>` in
+/// the file. The input file must exist, be a file, and not be zipped.
 ///
 /// ## Notes
 ///
@@ -454,17 +1717,9 @@ fn parse_sampler_args(args: SamplerArgs) -> Result<(IOArgs, Xoshiro256StarStar,
 /// without an actual record, the sequence estimate will be off by 1, but this
 /// error will then be handled when the file is read during sampling.
 fn get_seq_count<R: Read>(input_file: &Path, reader: &FastXReader<R>) -> std::io::Result<usize> {
-    let input = InputOptions::new_from_path(input_file).use_file().open()?;
+    let options = InputOptions::new_from_path(input_file).use_file();
     match reader {
-        FastXReader::Fasta(_) => {
-            // the first item in the `split` iterator will be empty if the first
-            // character in the file is a `>`, so we subtract 1
-            let header_count = input.split(b'>').count().saturating_sub(1);
-            Ok(header_count)
-        }
-        FastXReader::Fastq(_) => {
-            let line_count = input.lines().process_results(|iter| iter.count())?;
-            Ok(line_count / 4)
-        }
+        FastXReader::Fasta(_) => options.parse_fasta_skippable().open()?.skip_remaining(),
+        FastXReader::Fastq(_) => options.parse_fastq_skippable().open()?.skip_remaining(),
     }
 }
@@ -0,0 +1,334 @@
+//! Compares trimming configurations on a sample of reads for `trim-eval`,
+//! reporting the reads dropped, bases removed, adapter remnants, and quality of
+//! each so that parameters can be chosen objectively.
+
+use super::{PairFilter, ParsedTrimmerOptions, TrimmerArgs, parse_trimmer_quality_filter, trim_filter};
+use crate::{
+    args::clipping::{parse_clipping_args, validate_acgtn},
+    shared::{
+        trace::{ReadTracer, TraceArgs},
+        trimming::TrimmedCounts,
+    },
+};
+use clap::{Args, Command, FromArgMatches};
+use irma_records::{
+    io::{InputOptions, OutputOptions, ValidatePaths},
+    paired::ReadSide,
+};
+use std::{
+    ffi::OsString,
+    io::{ErrorKind, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+use zoe::prelude::*;
+
+/// The TruSeq and Nextera adapter prefixes searched for in the trimmed reads
+/// when `--remnant` is not given.
+const DEFAULT_REMNANTS: [&str; 2] = ["AGATCGGAAGAGC", "CTGTCTCTTATACACATCT"];
+
+#[derive(Args, Debug)]
+pub struct TrimEvalArgs {
+    /// Path to the FASTQ file of sample reads, which may be zipped. Reads are
+    /// trimmed as single-end reads
+    fastq_input: PathBuf,
+
+    #[arg(long, value_name = "JSON")]
+    /// A trimming configuration written by `trimmer --summary-json`. May be
+    /// repeated
+    config: Vec<PathBuf>,
+
+    #[arg(long, value_name = "FLAGS", allow_hyphen_values = true)]
+    /// A trimming configuration given as `trimmer` flags separated by
+    /// whitespace, such as "-G 12 -A CTGTCTCTTATACACATCT --a-fuzzy". An empty
+    /// string applies the defaults. May be repeated
+    flags: Vec<String>,
+
+    #[arg(short = 'n', long, default_value = "100000")]
+    /// The number of reads taken from the start of the sample
+    max_reads: NonZeroUsize,
+
+    #[arg(long, value_name = "SEQ", value_parser = validate_acgtn)]
+    /// An adapter sequence searched for in the trimmed reads, each read
+    /// containing one counting as an adapter remnant. Defaults to the TruSeq
+    /// and Nextera adapter prefixes. May be repeated
+    remnant: Vec<Nucleotides>,
+
+    #[arg(short = 'o', long)]
+    /// Output path for the comparison table. Prints to STDOUT if not provided
+    output: Option<PathBuf>,
+}
+
+impl ValidatePaths for TrimEvalArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        std::iter::once(&self.fastq_input).chain(self.config.iter())
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        self.output.iter()
+    }
+}
+
+/// A trimming configuration being evaluated.
+struct Configuration {
+    /// The name of the configuration in the table
+    label:   String,
+    options: ParsedTrimmerOptions,
+}
+
+/// The quality scores of a set of bases.
+#[derive(Default, Debug)]
+struct QualityProfile {
+    bases: usize,
+    sum:   u64,
+    /// The number of bases with a quality of at least 30
+    q30:   usize,
+}
+
+impl QualityProfile {
+    /// Adds the quality scores of a read, encoded with an offset of 33.
+    fn add(&mut self, quality: &[u8]) {
+        for score in quality.iter().map(|q| q.saturating_sub(33)) {
+            self.bases += 1;
+            self.sum += u64::from(score);
+            self.q30 += usize::from(score >= 30);
+        }
+    }
+
+    /// The mean quality score, or `None` if there are no bases.
+    fn mean(&self) -> Option<f64> {
+        (self.bases > 0).then(|| self.sum as f64 / self.bases as f64)
+    }
+
+    /// The fraction of bases with a quality of at least 30, or `None` if there
+    /// are no bases.
+    fn q30_fraction(&self) -> Option<f64> {
+        (self.bases > 0).then(|| self.q30 as f64 / self.bases as f64)
+    }
+}
+
+/// The metrics of one configuration applied to the sample.
+#[derive(Default, Debug)]
+struct EvalMetrics {
+    reads_in:      usize,
+    reads_kept:    usize,
+    bases_in:      usize,
+    bases_out:     usize,
+    /// The bases trimmed from kept reads (or masked, with `--mask`) and those
+    /// of dropped reads
+    bases_removed: usize,
+    /// The number of kept reads containing an adapter remnant
+    remnant_reads: usize,
+    quality_in:    QualityProfile,
+    quality_out:   QualityProfile,
+}
+
+/// Sub-program for comparing trimming configurations on a sample of reads.
+pub fn trim_eval_process(args: TrimEvalArgs) -> std::io::Result<()> {
+    args.validate_paths()?;
+
+    let TrimEvalArgs {
+        fastq_input,
+        config,
+        flags,
+        max_reads,
+        remnant,
+        output,
+    } = args;
+
+    if config.is_empty() && flags.is_empty() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            "At least one configuration must be given with `--config` or `--flags`",
+        ));
+    }
+
+    let mut configurations = Vec::with_capacity(config.len() + flags.len());
+    for path in &config {
+        let args = [OsString::from("--replay"), path.clone().into_os_string()];
+        configurations.push(parse_configuration(path.display().to_string(), &fastq_input, args)?);
+    }
+    for flags in &flags {
+        let label = if flags.trim().is_empty() {
+            "defaults".to_string()
+        } else {
+            flags.trim().to_string()
+        };
+        configurations.push(parse_configuration(
+            label,
+            &fastq_input,
+            flags.split_ascii_whitespace().map(OsString::from),
+        )?);
+    }
+
+    let remnants = if remnant.is_empty() {
+        DEFAULT_REMNANTS.iter().map(|seq| Nucleotides::from(seq.as_bytes())).collect()
+    } else {
+        remnant
+    };
+
+    let sample = InputOptions::new_from_path(&fastq_input)
+        .use_file_or_zip()
+        .decode_in_thread()
+        .parse_fastq()
+        .open()?
+        .take(max_reads.get())
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut writer = OutputOptions::new_from_opt_path(output.as_ref())
+        .use_file_zip_or_stdout()
+        .open()?;
+    writeln!(
+        writer,
+        "config\treads_in\treads_dropped\tpct_dropped\tbases_in\tbases_out\tpct_bases_removed\tmean_length_out\tadapter_remnants\tpct_adapter_remnants\tmean_quality_in\tmean_quality_out\tpct_q30_out"
+    )?;
+    for configuration in &configurations {
+        let metrics = evaluate(&sample, &configuration.options, &remnants)?;
+        write_metrics(&mut writer, &configuration.label, &metrics)?;
+    }
+    writer.flush()
+}
+
+/// Parses a configuration from `trimmer` flags, applying `--replay` if it was
+/// given. The sample path stands in for the input, and any inputs or outputs
+/// among the flags are ignored.
+///
+/// ## Errors
+///
+/// An error is returned if the flags are invalid, if a replayed summary cannot
+/// be read, if the primer file cannot be processed, or if the configuration
+/// requires a sequencing summary.
+fn parse_configuration(
+    label: String, sample: &Path, flags: impl IntoIterator<Item = OsString>,
+) -> std::io::Result<Configuration> {
+    // Only the first line of a clap error is kept, since the usage and tips
+    // refer to `trimmer` rather than `trim-eval`
+    let clap_error = |e: clap::Error| {
+        let rendered = e.render().to_string();
+        let first = rendered.lines().next().unwrap_or_default();
+        first.strip_prefix("error: ").unwrap_or(first).to_string()
+    };
+    let invalid = |msg: String| {
+        std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid trimming configuration `{label}`\n{msg}"),
+        )
+    };
+
+    let command = TrimmerArgs::augment_args(Command::new("trimmer").no_binary_name(true));
+    let matches = command
+        .try_get_matches_from(std::iter::once(sample.as_os_str().to_owned()).chain(flags))
+        .map_err(|e| invalid(clap_error(e)))?;
+    let mut args = TrimmerArgs::from_arg_matches(&matches).map_err(|e| invalid(clap_error(e)))?;
+
+    if let Some(path) = &args.replay {
+        super::TrimmerParameters::from_summary(path)?
+            .apply_to(&mut args)
+            .map_err(|e| invalid(e.to_string()))?;
+    }
+    if args.ont_summary.is_some() {
+        return Err(invalid(
+            "Filtering with a sequencing summary (`--ont-summary`) cannot be evaluated".to_string(),
+        ));
+    }
+
+    let options = ParsedTrimmerOptions {
+        mask:              args.mask,
+        quality_filter:    parse_trimmer_quality_filter(args.quality_filter),
+        fasta_out:         false,
+        fix_sides:         false,
+        verbose:           false,
+        clipping_args:     parse_clipping_args(args.clipping_args)?,
+        ont_summary:       None,
+        ont_keep_unlisted: false,
+        pair_filter:       PairFilter::Both,
+        tracer:            ReadTracer::new("trim-eval", TraceArgs { trace_read: Vec::new() }),
+    };
+
+    Ok(Configuration { label, options })
+}
+
+/// Trims a copy of each read in `sample` with `options`, tallying the metrics.
+///
+/// ## Errors
+///
+/// An error is returned for a read violating `--strict-bases fail`.
+fn evaluate(sample: &[FastQ], options: &ParsedTrimmerOptions, remnants: &[Nucleotides]) -> std::io::Result<EvalMetrics> {
+    let mut metrics = EvalMetrics::default();
+    let mut counts = TrimmedCounts::default();
+
+    for read in sample {
+        let mut read = read.clone();
+        let len_in = read.sequence.len();
+        let n_in = count_n(read.sequence.as_bytes());
+        metrics.reads_in += 1;
+        metrics.bases_in += len_in;
+        metrics.quality_in.add(read.quality.as_bytes());
+
+        let Some(trimmed) = trim_filter(&mut read, ReadSide::Unpaired, options, &mut counts)? else {
+            metrics.bases_removed += len_in;
+            continue;
+        };
+
+        let len_out = trimmed.sequence.len();
+        metrics.reads_kept += 1;
+        metrics.bases_out += len_out;
+        metrics.bases_removed += len_in - len_out;
+        if options.mask {
+            metrics.bases_removed += count_n(trimmed.sequence).saturating_sub(n_in);
+        }
+        if let Some(quality) = &trimmed.quality {
+            metrics.quality_out.add(quality.as_bytes());
+        }
+        if remnants
+            .iter()
+            .any(|remnant| contains_remnant(trimmed.sequence, remnant.as_bytes()))
+        {
+            metrics.remnant_reads += 1;
+        }
+    }
+
+    Ok(metrics)
+}
+
+/// The number of `N` or `n` bases in a sequence.
+fn count_n(sequence: &[u8]) -> usize {
+    sequence.iter().filter(|b| b.eq_ignore_ascii_case(&b'N')).count()
+}
+
+/// Whether `sequence` contains `remnant`, ignoring case.
+fn contains_remnant(sequence: &[u8], remnant: &[u8]) -> bool {
+    sequence
+        .windows(remnant.len())
+        .any(|window| window.eq_ignore_ascii_case(remnant))
+}
+
+/// Writes the row of the comparison table for one configuration. Percentages
+/// and means without any reads or bases are written as `*`.
+fn write_metrics<W: Write>(writer: &mut W, label: &str, metrics: &EvalMetrics) -> std::io::Result<()> {
+    let percent = |count: usize, total: usize| {
+        if total > 0 {
+            format!("{:.2}", count as f64 / total as f64 * 100.0)
+        } else {
+            "*".to_string()
+        }
+    };
+    let decimal = |value: Option<f64>| value.map_or_else(|| "*".to_string(), |value| format!("{value:.2}"));
+
+    let reads_dropped = metrics.reads_in - metrics.reads_kept;
+    writeln!(
+        writer,
+        "{label}\t{reads_in}\t{reads_dropped}\t{pct_dropped}\t{bases_in}\t{bases_out}\t{pct_removed}\t{mean_length}\t{remnants}\t{pct_remnants}\t{quality_in}\t{quality_out}\t{pct_q30}",
+        reads_in = metrics.reads_in,
+        pct_dropped = percent(reads_dropped, metrics.reads_in),
+        bases_in = metrics.bases_in,
+        bases_out = metrics.bases_out,
+        pct_removed = percent(metrics.bases_removed, metrics.bases_in),
+        mean_length = decimal((metrics.reads_kept > 0).then(|| metrics.bases_out as f64 / metrics.reads_kept as f64)),
+        remnants = metrics.remnant_reads,
+        pct_remnants = percent(metrics.remnant_reads, metrics.reads_kept),
+        quality_in = decimal(metrics.quality_in.mean()),
+        quality_out = decimal(metrics.quality_out.mean()),
+        pct_q30 = decimal(metrics.quality_out.q30_fraction().map(|fraction| fraction * 100.0)),
+    )
+}
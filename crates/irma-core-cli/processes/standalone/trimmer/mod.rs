@@ -0,0 +1,1629 @@
+//! Reads FastQ files and trims with various options.
+
+use crate::{
+    args::{
+        abort_clap,
+        clipping::{ClippingArgs, ParsedClippingArgs, StrictBases, TrimStep, parse_clipping_args},
+        quality_filter::{ParsedQualityFilterArgs, QualityFilterArgs, n_fraction, parse_quality_filter_args},
+    },
+    shared::{
+        compression::CompressionArgs,
+        cycles::BeforeAfterCycles,
+        histogram::BeforeAfterHistograms,
+        ont_summary::{OntStatus, OntSummary, OntSummaryCriteria, parse_channel_range},
+        paired_paths::infer_r2_path,
+        tee_input::TeeInputArgs,
+        trace::{ReadTracer, TraceArgs},
+        trimming::{NonCanonicalBases, SidedTrimmedCounts, TrimmedCounts, trim_read},
+    },
+};
+use clap::{Args, ValueEnum, error::ErrorKind};
+use core::fmt;
+use irma_records::{
+    fastq::{ReadTransforms, fix_header_sides},
+    io::{
+        CompressionOptions, FastXViewMut, GzipFormat, InputOptions, IterWithContext, OutputOptions, PairedWriters,
+        ReadFileZipInThread, RecordReaders, RecordWriters, TeeReader, ValidatePaths, WriteFileZipStdout, WriteRecord,
+        WriterWithContext,
+    },
+    paired::{DeinterleavedPairedReadsExt, ReadSide, ZipPairedReadsExt, ZipReadsError},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+use zoe::{data::err::ResultWithErrorContext, prelude::*};
+
+mod eval;
+mod replay;
+
+pub use eval::{TrimEvalArgs, trim_eval_process};
+use replay::TrimmerParameters;
+
+#[derive(Args, Debug)]
+pub struct TrimmerArgs {
+    #[arg(required = true, value_name = "FASTQ_INPUTS")]
+    /// Paths to .fastq or .fastq.gz files to be trimmed. Two inputs are
+    /// trimmed as paired reads (R1 then R2). More inputs, such as the lanes of
+    /// a sample, are trimmed one after another (see `--input-pairing`)
+    fastq_inputs: Vec<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t)]
+    /// How the inputs are paired
+    input_pairing: InputPairing,
+
+    #[arg(long, conflicts_with = "input_pairing")]
+    /// Infers the R2 input of each input from its file name (`_R1_`, `_R1.`,
+    /// or `_1.` becomes `_R2_`, `_R2.`, or `_2.`), checking that it exists and
+    /// that the first records of both files are paired
+    auto_r2: bool,
+
+    #[arg(short = '1', long, short_alias = 'o', aliases = ["output-file", "output-file1", "output1", "fastq-output", "fastq-output1"])]
+    /// Output filepath for trimmed reads. Trimmed reads print to STDOUT if not
+    /// provided. May also use '-o'.
+    output: Option<PathBuf>,
+
+    #[arg(short = '2', long, aliases = ["output-file2", "output2", "fastq-output2"])]
+    /// Output path for secondary trimmed file if using paired reads. If this
+    /// argument is omitted, output is interleaved.
+    output2: Option<PathBuf>,
+
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_output_template, conflicts_with_all = ["output", "output2"])]
+    /// Writes the reads of each input to its own output, named by replacing
+    /// `{name}` in this template with the input's file name without its
+    /// `.fastq`, `.fq`, or `.gz` extensions. The R2 reads of paired inputs are
+    /// written to the output named for the R2 input, unless `--interleaved-out`
+    /// is given
+    output_template: Option<String>,
+
+    #[arg(long, conflicts_with = "output2")]
+    /// Writes paired inputs to a single interleaved output (R1 then R2 for each
+    /// pair), such as STDOUT for piping to an aligner. Requires paired inputs.
+    /// Use with `--filter-widows` to keep only complete pairs
+    interleaved_out: bool,
+
+    #[arg(short = 'm', long)]
+    /// Perform masking with 'N' instead of clipping. Default behavior is
+    /// clipping if not provided
+    mask: bool,
+
+    #[command(flatten)]
+    quality_filter: QualityFilterArgs,
+
+    #[arg(short = 'f', long)]
+    /// Filter widowed reads
+    filter_widows: bool,
+
+    #[arg(long, value_enum, default_value_t, requires = "filter_widows")]
+    /// With `--filter-widows`, when a pair is dropped for the trimmed lengths
+    /// of its mates. A mate kept for its partner may be shorter than
+    /// `--min-length`, but must pass the other filters
+    pair_filter: PairFilter,
+
+    #[arg(long)]
+    /// Writes the trimmed reads as FASTA, dropping the quality scores
+    fasta_out: bool,
+
+    #[arg(long)]
+    /// Writes outputs ending in `.gz` as BGZF (blocked gzip), which htslib
+    /// tools can index. Outputs ending in `.bgz` are always written as BGZF
+    bgzf: bool,
+
+    #[command(flatten)]
+    compression: CompressionArgs,
+
+    #[arg(long)]
+    /// Makes the headers of paired reads agree with their actual read sides,
+    /// inserting the side into SRA IDs lacking one and rewriting the side of
+    /// Illumina comments (such as the `1` in `1:N:0:ACGT`)
+    fix_sides: bool,
+
+    #[command(flatten)]
+    clipping_args: ClippingArgs,
+
+    #[arg(long)]
+    /// Path to an Oxford Nanopore `sequencing_summary.txt`. Reads are joined
+    /// to it by read ID and filtered using the `--ont-*` criteria
+    ont_summary: Option<PathBuf>,
+
+    #[arg(long, requires = "ont_summary")]
+    /// Filters reads whose `mean_qscore_template` in the sequencing summary is
+    /// below this threshold
+    ont_min_qscore: Option<f32>,
+
+    #[arg(long, value_delimiter = ',', value_parser = parse_channel_range, requires = "ont_summary")]
+    /// Comma-separated channels or ranges of channels (such as `1-256,300`).
+    /// Reads sequenced on other channels are filtered
+    ont_channels: Option<Vec<RangeInclusive<u32>>>,
+
+    #[arg(long, requires = "ont_summary")]
+    /// Filters reads whose `passes_filtering` status in the sequencing summary
+    /// is false
+    ont_pass_only: bool,
+
+    #[arg(long, requires = "ont_summary")]
+    /// Keeps reads that are absent from the sequencing summary, which are
+    /// otherwise filtered
+    ont_keep_unlisted: bool,
+
+    #[arg(long, value_name = "TSV")]
+    /// Writes histograms of the read lengths before trimming and of the reads
+    /// written after trimming and filtering, as a tab-delimited table
+    length_hist: Option<PathBuf>,
+
+    #[arg(long, default_value = "10", requires = "length_hist")]
+    /// The width of the length bins in `--length-hist`
+    length_hist_bin: NonZeroUsize,
+
+    #[arg(long, value_name = "TSV")]
+    /// Writes the mean quality and base composition at each cycle (position)
+    /// of the reads before trimming and of the reads written after trimming
+    /// and filtering, as a tab-delimited table
+    cycle_report: Option<PathBuf>,
+
+    #[command(flatten)]
+    tee: TeeInputArgs,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+
+    #[arg(long, value_name = "JSON")]
+    /// Writes the trimming parameters of this run as JSON, which can be applied
+    /// to a later run with `--replay`
+    summary_json: Option<PathBuf>,
+
+    #[arg(long, value_name = "JSON", conflicts_with_all = [
+        "mask", "filter_widows", "pair_filter", "fasta_out", "QualityFilterArgs", "ClippingArgs",
+        "ont_min_qscore", "ont_channels", "ont_pass_only", "ont_keep_unlisted",
+    ])]
+    /// Applies the trimming parameters recorded by `--summary-json` in an
+    /// earlier run, for reproducing it exactly. Trimming and filtering flags
+    /// cannot also be given
+    replay: Option<PathBuf>,
+
+    #[arg(short = 'v', long)]
+    /// Prints the number of records trimmed for each method to stderr
+    verbose: bool,
+}
+
+/// How the inputs of `trimmer` are paired.
+#[derive(ValueEnum, Copy, Clone, Eq, PartialEq, Debug, Default)]
+enum InputPairing {
+    /// Two inputs are trimmed as a pair, and any other number separately
+    #[default]
+    Auto,
+    /// The inputs are consecutive R1 and R2 pairs
+    Pairs,
+    /// Each input is trimmed separately, as single-end or interleaved reads
+    Separate,
+}
+
+/// When a pair is dropped for the trimmed lengths of its mates, for
+/// `--pair-filter`.
+#[derive(ValueEnum, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+enum PairFilter {
+    /// Both mates must meet the minimum length
+    #[default]
+    Both,
+    /// Either mate meeting the minimum length keeps both
+    Any,
+    /// Only the length of R1 is considered
+    R1Only,
+}
+
+impl PairFilter {
+    /// Whether R1 and R2 are kept when below the minimum length, as long as
+    /// their mates are not.
+    fn keeps_short(self) -> [bool; 2] {
+        match self {
+            PairFilter::Both => [false, false],
+            PairFilter::Any => [true, true],
+            PairFilter::R1Only => [false, true],
+        }
+    }
+}
+
+impl fmt::Display for PairFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairFilter::Both => write!(f, "both"),
+            PairFilter::Any => write!(f, "any"),
+            PairFilter::R1Only => write!(f, "r1-only"),
+        }
+    }
+}
+
+impl ValidatePaths for TrimmerArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        let summary = self.ont_summary.iter();
+        let replay = self.replay.iter();
+
+        self.fastq_inputs.iter().chain(summary).chain(replay)
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        let output1 = self.output.iter();
+        let output2 = self.output2.iter();
+        let length_hist = self.length_hist.iter();
+        let cycle_report = self.cycle_report.iter();
+        let summary_json = self.summary_json.iter();
+
+        output1
+            .chain(output2)
+            .chain(length_hist)
+            .chain(cycle_report)
+            .chain(summary_json)
+            .chain(self.tee.outputs())
+    }
+}
+
+/// Sub-program for trimming FASTQ data.
+pub fn trimmer_process(args: TrimmerArgs) -> Result<(), std::io::Error> {
+    let ParsedTrimmerArgs {
+        inputs,
+        mut writers,
+        compression,
+        filter_widows,
+        tee,
+        trimming_args,
+        primer_file,
+        length_hist,
+        cycle_report,
+        summary_json,
+    } = parse_trimmer_args(args)?;
+
+    let new_counts = || {
+        let mut counts = SidedTrimmedCounts::default();
+        if let Some((_, bin_width)) = &length_hist {
+            counts.r1.lengths = Some(BeforeAfterHistograms::new(*bin_width));
+            counts.r2.lengths = Some(BeforeAfterHistograms::new(*bin_width));
+        }
+        if cycle_report.is_some() {
+            counts.r1.cycles = Some(BeforeAfterCycles::default());
+            counts.r2.cycles = Some(BeforeAfterCycles::default());
+        }
+        counts
+    };
+
+    let report_inputs = trimming_args.verbose && inputs.len() > 1;
+    // Teeing is only allowed for a single input (or pair)
+    let (tee_path1, tee_path2) = tee.paths();
+    let mut counts = new_counts();
+    let mut strategy = PairedIoStrategy::OneInOneOut;
+    for input in inputs {
+        let readers = InputOptions::new_from_paths(&input.path1, input.path2.as_ref())
+            .use_file_or_zip()
+            .decode_in_thread()
+            .tee_to(tee_path1, tee_path2)
+            .parse_fastq()
+            .open()?;
+
+        let mut input_counts = new_counts();
+        let label = input.to_string();
+        strategy = match &mut writers {
+            Some(writers) => trim_input(
+                input,
+                readers,
+                writers.as_mut(),
+                filter_widows,
+                &trimming_args,
+                &mut input_counts,
+            )?,
+            None => {
+                let (output1, output2) = input.outputs.as_ref().expect("outputs are named for every input");
+                let writers = OutputOptions::new_from_opt_paths(Some(output1), output2.as_ref())
+                    .with_compression(compression)
+                    .use_file_zip_or_stdout()
+                    .open()?;
+                trim_input(input, readers, writers, filter_widows, &trimming_args, &mut input_counts)?
+            }
+        };
+
+        if report_inputs {
+            input_counts.write_input_counts(&label, &trimming_args);
+        }
+        counts = counts + input_counts;
+    }
+
+    if let Some((writer, _)) = length_hist
+        && let Some(lengths) = counts.combined().lengths
+    {
+        lengths.write_tsv(writer)?;
+    }
+
+    if let Some(writer) = cycle_report
+        && let Some(cycles) = counts.combined().cycles
+    {
+        cycles.write_tsv(writer)?;
+    }
+
+    if let Some((writer, parameters)) = summary_json {
+        parameters.write_summary(writer)?;
+    }
+
+    if trimming_args.verbose {
+        counts
+            .combined()
+            .write_counts(&trimming_args.clipping_args, &strategy, &trimming_args, primer_file);
+        if strategy.is_paired() {
+            counts.write_side_counts(&strategy, &trimming_args);
+        }
+    }
+    Ok(())
+}
+
+/// Trims the reads of one input (or pair of inputs) and writes them to
+/// `writers`, returning how they were paired.
+fn trim_input<W: Write + Send>(
+    input: TrimmerInput, readers: RecordReaders<TrimmerReader>, writers: RecordWriters<W>, filter_widows: bool,
+    trimming_args: &ParsedTrimmerOptions, counts: &mut SidedTrimmedCounts,
+) -> std::io::Result<PairedIoStrategy> {
+    let (io_args, strategy) = PairedIoArgs::new(input, readers, writers, filter_widows);
+
+    match io_args {
+        PairedIoArgs::OneInOneOutFilter {
+            input_path1,
+            reader1,
+            mut writer,
+        } => {
+            reader1
+                .deinterleave()
+                .map(|res| res.map_err(|e| e.add_path_context(&input_path1)))
+                .try_for_each(|pair| trim_and_write_pair(pair?, trimming_args, &mut writer, counts))?;
+            writer.flush()?;
+        }
+        PairedIoArgs::TwoInOneOutFilter {
+            input_path1,
+            input_path2,
+            reader1,
+            reader2,
+            mut writer,
+        } => {
+            reader1
+                .zip_paired_reads(reader2)
+                .map(|res| res.map_err(|e| e.add_path_context(&input_path1, &input_path2)))
+                .try_for_each(|pair| trim_and_write_pair(pair?, trimming_args, &mut writer, counts))?;
+
+            writer.flush()?;
+        }
+        PairedIoArgs::OneInTwoOutFilter {
+            input_path1,
+            reader1,
+            mut writer,
+        } => {
+            reader1
+                .deinterleave()
+                .map(|res| res.map_err(|e| e.add_path_context(&input_path1)))
+                .try_for_each(|pair| trim_and_write_pair(pair?, trimming_args, &mut writer, counts))?;
+            writer.flush()?;
+        }
+        PairedIoArgs::TwoInTwoOutFilter {
+            input_path1,
+            input_path2,
+            reader1,
+            reader2,
+            mut writer,
+        } => {
+            reader1
+                .zip_paired_reads(reader2)
+                .map(|res| res.map_err(|e| e.add_path_context(&input_path1, &input_path2)))
+                .try_for_each(|pair| trim_and_write_pair(pair?, trimming_args, &mut writer, counts))?;
+            writer.flush()?;
+        }
+        PairedIoArgs::OneInOneOutNoFilter { mut reader1, mut writer } => {
+            let counts = counts.side_mut(ReadSide::Unpaired);
+            reader1
+                .try_for_each(|read| trim_and_write_seq(read?, ReadSide::Unpaired, trimming_args, &mut writer, counts))?;
+            writer.flush()?;
+        }
+        PairedIoArgs::TwoInOneOutNoFilter {
+            input_path1,
+            input_path2,
+            mut reader1,
+            mut reader2,
+            mut writer,
+        } => {
+            let result = reader1
+                .by_ref()
+                .zip_paired_reads_unchecked(reader2.by_ref())
+                .try_for_each(|pair| {
+                    let [read1, read2] = pair?;
+                    trim_and_write_seq(read1, ReadSide::R1, trimming_args, &mut writer, &mut counts.r1)
+                        .map_err(ZipReadsError::IoError)?;
+                    trim_and_write_seq(read2, ReadSide::R2, trimming_args, &mut writer, &mut counts.r2)
+                        .map_err(ZipReadsError::IoError)
+                });
+
+            match result {
+                Ok(()) => {}
+                Err(ZipReadsError::ExtraFirstRead(read1)) => {
+                    std::iter::once(Ok(read1)).chain(reader1).try_for_each(|read1| {
+                        trim_and_write_seq(read1?, ReadSide::R1, trimming_args, &mut writer, &mut counts.r1)
+                    })?;
+                }
+                Err(ZipReadsError::ExtraSecondRead(read2)) => {
+                    std::iter::once(Ok(read2)).chain(reader2).try_for_each(|read2| {
+                        trim_and_write_seq(read2?, ReadSide::R2, trimming_args, &mut writer, &mut counts.r2)
+                    })?;
+                }
+                Err(err) => return Err(err.add_path_context(&input_path1, &input_path2)),
+            }
+
+            writer.flush()?;
+        }
+        PairedIoArgs::OneInTwoOutNoFilter {
+            input_path1,
+            reader1,
+            mut writer,
+        } => {
+            reader1
+                .deinterleave()
+                .map(|res| res.map_err(|e| e.add_path_context(&input_path1)))
+                .try_for_each(|pair| {
+                    let [read1, read2] = pair?;
+                    trim_and_write_seq(read1, ReadSide::R1, trimming_args, &mut writer.writer1, &mut counts.r1)?;
+                    trim_and_write_seq(read2, ReadSide::R2, trimming_args, &mut writer.writer2, &mut counts.r2)
+                })?;
+            writer.flush()?;
+        }
+        PairedIoArgs::TwoInTwoOutNoFilter {
+            mut reader1,
+            mut reader2,
+            mut writer,
+        } => {
+            let SidedTrimmedCounts {
+                r1: counts1,
+                r2: counts2,
+                ..
+            } = counts;
+
+            std::thread::scope(|s| {
+                let handle = s.spawn(|| {
+                    reader2.try_for_each(|read2| {
+                        trim_and_write_seq(read2?, ReadSide::R2, trimming_args, &mut writer.writer2, counts2)
+                    })?;
+                    writer.writer2.flush()
+                });
+
+                reader1.try_for_each(|read1| {
+                    trim_and_write_seq(read1?, ReadSide::R1, trimming_args, &mut writer.writer1, counts1)
+                })?;
+                writer.writer1.flush()?;
+
+                handle.join().unwrap()
+            })?;
+        }
+    }
+
+    Ok(strategy)
+}
+
+/// Possible IO combinations for trimming inputs, outputs and widow filtering.
+/// Input can be paired or single, output can be paired or single, and filtering
+/// can be toggled, leaving 8 possible options.
+///
+/// This enum also holds the reader(s) and writer for each option.
+#[allow(clippy::enum_variant_names)]
+enum PairedIoArgs<I, W> {
+    OneInOneOutFilter {
+        input_path1: PathBuf,
+        reader1:     I,
+        writer:      W,
+    },
+    TwoInOneOutFilter {
+        input_path1: PathBuf,
+        input_path2: PathBuf,
+        reader1:     I,
+        reader2:     I,
+        writer:      W,
+    },
+    OneInTwoOutFilter {
+        input_path1: PathBuf,
+        reader1:     I,
+        writer:      PairedWriters<W>,
+    },
+    TwoInTwoOutFilter {
+        input_path1: PathBuf,
+        input_path2: PathBuf,
+        reader1:     I,
+        reader2:     I,
+        writer:      PairedWriters<W>,
+    },
+    OneInOneOutNoFilter {
+        reader1: I,
+        writer:  W,
+    },
+    TwoInOneOutNoFilter {
+        input_path1: PathBuf,
+        input_path2: PathBuf,
+        reader1:     I,
+        reader2:     I,
+        writer:      W,
+    },
+    OneInTwoOutNoFilter {
+        input_path1: PathBuf,
+        reader1:     I,
+        writer:      PairedWriters<W>,
+    },
+    TwoInTwoOutNoFilter {
+        reader1: I,
+        reader2: I,
+        writer:  PairedWriters<W>,
+    },
+}
+
+impl<I, W> PairedIoArgs<I, W> {
+    /// Chooses how the reads of an input (or pair of inputs) are paired, from
+    /// the number of inputs and outputs and whether widows are filtered.
+    fn new(
+        input: TrimmerInput, readers: RecordReaders<I>, writers: RecordWriters<W>, filter_widows: bool,
+    ) -> (Self, PairedIoStrategy) {
+        let reader1 = readers.reader1;
+        let input_path1 = input.path1;
+
+        if let Some((reader2, input_path2)) = readers.reader2.zip(input.path2) {
+            match (writers, filter_widows) {
+                // Case 2: In 1, In 2, Out 1 (interleaved Illumina), no widow filtering
+                (RecordWriters::SingleEnd(writer), false) => (
+                    PairedIoArgs::TwoInOneOutNoFilter {
+                        input_path1,
+                        input_path2,
+                        reader1,
+                        reader2,
+                        writer,
+                    },
+                    PairedIoStrategy::TwoInOneOut,
+                ),
+
+                // Case 3: In 1, In 2, Out 1, Filtering widows / orphan reads
+                (RecordWriters::SingleEnd(writer), true) => (
+                    PairedIoArgs::TwoInOneOutFilter {
+                        input_path1,
+                        input_path2,
+                        reader1,
+                        reader2,
+                        writer,
+                    },
+                    PairedIoStrategy::TwoInOneOutFilter,
+                ),
+
+                // Case 4: In 1, In 2, Out 1, Out 2 (separated output Illumina), no filtering
+                (RecordWriters::PairedEnd(writer), false) => (
+                    PairedIoArgs::TwoInTwoOutNoFilter {
+                        reader1,
+                        reader2,
+                        writer,
+                    },
+                    PairedIoStrategy::TwoInTwoOut,
+                ),
+
+                // Case 5: In 1, In 2, Out 1, Out 2, filter widows
+                (RecordWriters::PairedEnd(writer), true) => (
+                    PairedIoArgs::TwoInTwoOutFilter {
+                        input_path1,
+                        input_path2,
+                        reader1,
+                        reader2,
+                        writer,
+                    },
+                    PairedIoStrategy::TwoInTwoOutFilter,
+                ),
+            }
+        } else {
+            match (writers, filter_widows) {
+                (RecordWriters::SingleEnd(writer), false) => {
+                    // Case 1: In 1, Out 1 (ONT, single-end, PacBio)
+                    (
+                        PairedIoArgs::OneInOneOutNoFilter { reader1, writer },
+                        PairedIoStrategy::OneInOneOut,
+                    )
+                }
+
+                (RecordWriters::SingleEnd(writer), true) => (
+                    PairedIoArgs::OneInOneOutFilter {
+                        input_path1,
+                        reader1,
+                        writer,
+                    },
+                    PairedIoStrategy::OneInOneOutFilter,
+                ),
+
+                (RecordWriters::PairedEnd(writer), false) => (
+                    PairedIoArgs::OneInTwoOutNoFilter {
+                        input_path1,
+                        reader1,
+                        writer,
+                    },
+                    PairedIoStrategy::OneInTwoOut,
+                ),
+
+                (RecordWriters::PairedEnd(writer), true) => (
+                    PairedIoArgs::OneInTwoOutFilter {
+                        input_path1,
+                        reader1,
+                        writer,
+                    },
+                    PairedIoStrategy::OneInTwoOutFilter,
+                ),
+            }
+        }
+    }
+}
+
+/// Similar to [`PairedIoArgs`], but lacking state, used for display purposes.
+#[derive(Debug)]
+pub enum PairedIoStrategy {
+    OneInOneOutFilter,
+    TwoInOneOutFilter,
+    OneInTwoOutFilter,
+    TwoInTwoOutFilter,
+    OneInOneOut,
+    TwoInOneOut,
+    OneInTwoOut,
+    TwoInTwoOut,
+}
+
+impl PairedIoStrategy {
+    /// Whether the reads are processed as pairs, so that counts can be
+    /// reported for each side. Only a single input without widow filtering is
+    /// treated as single-end.
+    fn is_paired(&self) -> bool {
+        !matches!(self, PairedIoStrategy::OneInOneOut)
+    }
+
+    /// Whether widows are filtered, so that the mates of each pair are
+    /// written or dropped together.
+    fn filters_widows(&self) -> bool {
+        matches!(
+            self,
+            PairedIoStrategy::OneInOneOutFilter
+                | PairedIoStrategy::OneInTwoOutFilter
+                | PairedIoStrategy::TwoInOneOutFilter
+                | PairedIoStrategy::TwoInTwoOutFilter
+        )
+    }
+}
+
+impl fmt::Display for PairedIoStrategy {
+    /// Display output for the different possible IO combinations, to be used in
+    /// [`write_counts`](TrimmedCounts::write_counts).
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PairedIoStrategy::OneInOneOutFilter => write!(
+                f,
+                "one interleaved input and wrote to one interleaved output with filtering of widowed reads"
+            ),
+            PairedIoStrategy::TwoInOneOutFilter => write!(
+                f,
+                "two paired inputs and wrote to one interleaved output with filtering of widowed reads"
+            ),
+
+            PairedIoStrategy::OneInTwoOutFilter => write!(
+                f,
+                "one interleaved input and wrote to two paired outputs with filtering of widowed reads"
+            ),
+            PairedIoStrategy::TwoInTwoOutFilter => write!(
+                f,
+                "two paired inputs and wrote to two paired outputs with filtering of widowed reads"
+            ),
+            PairedIoStrategy::OneInOneOut => write!(f, "one input and wrote to one output"),
+            PairedIoStrategy::TwoInOneOut => write!(
+                f,
+                "two paired inputs and wrote to one interleaved output without filtering of widowed read"
+            ),
+            PairedIoStrategy::OneInTwoOut => write!(
+                f,
+                "one interleaved input and wrote to two paired outputs without filtering of widowed reads"
+            ),
+            PairedIoStrategy::TwoInTwoOut => write!(
+                f,
+                "two paired inputs and wrote to two paired outputs without filtering of widowed reads"
+            ),
+        }
+    }
+}
+
+/// The reader of each `trimmer` input.
+type TrimmerReader = IterWithContext<FastQReader<TeeReader<ReadFileZipInThread>>>;
+
+/// An input of `trimmer`, or a pair of inputs trimmed together.
+#[derive(Debug)]
+struct TrimmerInput {
+    path1:   PathBuf,
+    path2:   Option<PathBuf>,
+    /// The outputs named by `--output-template`, if given
+    outputs: Option<(PathBuf, Option<PathBuf>)>,
+}
+
+impl fmt::Display for TrimmerInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path2 {
+            Some(path2) => write!(f, "{} and {}", self.path1.display(), path2.display()),
+            None => write!(f, "{}", self.path1.display()),
+        }
+    }
+}
+
+/// Parsed arguments for the `trimmer` subprocess
+struct ParsedTrimmerArgs {
+    /// The inputs, trimmed one after another
+    inputs:        Vec<TrimmerInput>,
+    /// The writers shared by every input, or `None` with `--output-template`
+    writers:       Option<RecordWriters<WriteFileZipStdout>>,
+    /// How the compressed outputs are written, with `--bgzf`,
+    /// `--compress-level`, and `--compress-threads`
+    compression:   CompressionOptions,
+    filter_widows: bool,
+    tee:           TeeInputArgs,
+    trimming_args: ParsedTrimmerOptions,
+    primer_file:   Option<PathBuf>,
+    /// The writer and bin width for `--length-hist`
+    length_hist:   Option<(BufWriter<WriterWithContext<File>>, NonZeroUsize)>,
+    cycle_report:  Option<BufWriter<WriterWithContext<File>>>,
+    /// The writer and recorded parameters for `--summary-json`
+    summary_json:  Option<(BufWriter<WriterWithContext<File>>, TrimmerParameters)>,
+}
+
+/// Arguments related to clipping/masking reads, including length/widow
+/// filtering
+#[derive(Debug)]
+struct ParsedTrimmerOptions {
+    mask:              bool,
+    quality_filter:    ParsedQualityFilterArgs,
+    fasta_out:         bool,
+    fix_sides:         bool,
+    verbose:           bool,
+    clipping_args:     ParsedClippingArgs,
+    ont_summary:       Option<OntSummary>,
+    ont_keep_unlisted: bool,
+    pair_filter:       PairFilter,
+    tracer:            ReadTracer,
+}
+
+/// Parses the trimmer arguments from the clap arguments
+///
+/// ## Errors
+///
+/// An error could occur when inferring the R2 inputs, naming the outputs with
+/// `--output-template`, creating the writers, processing the primer file, or
+/// reading the summary for `--replay`. Any errors generated will have
+/// customized error messages including additional information.
+fn parse_trimmer_args(mut args: TrimmerArgs) -> std::io::Result<ParsedTrimmerArgs> {
+    if args.auto_r2 {
+        // Resolved to explicit pairs, so that the R2 inputs are validated too
+        args.fastq_inputs = std::mem::take(&mut args.fastq_inputs)
+            .into_iter()
+            .map(|path| infer_r2_path(&path).map(|path2| [path, path2]))
+            .collect::<std::io::Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        args.input_pairing = InputPairing::Pairs;
+    }
+    let mut inputs = pair_inputs(std::mem::take(&mut args.fastq_inputs), args.input_pairing);
+    let paired = inputs[0].path2.is_some();
+    if inputs.len() > 1 && args.tee.outputs().next().is_some() {
+        abort_clap(
+            ErrorKind::ArgumentConflict,
+            "`--tee-input` and `--tee-input2` require a single input or pair of inputs",
+            Some("trimmer"),
+        );
+    }
+    args.tee.check_paired(paired, "trimmer");
+    if args.interleaved_out && !paired {
+        abort_clap(
+            ErrorKind::MissingRequiredArgument,
+            "`--interleaved-out` requires a second input",
+            Some("trimmer"),
+        );
+    }
+    if let Some(template) = &args.output_template {
+        name_template_outputs(&mut inputs, template, args.interleaved_out)?;
+    }
+    // Restored for validation, since the inputs are given to clap as a list
+    args.fastq_inputs = inputs
+        .iter()
+        .flat_map(|input| std::iter::once(input.path1.clone()).chain(input.path2.clone()))
+        .collect();
+    if let Some(path) = &args.replay {
+        TrimmerParameters::from_summary(path)?.apply_to(&mut args)?;
+    }
+    args.validate_paths()?;
+
+    let summary_json = match &args.summary_json {
+        Some(path) => Some((
+            OutputOptions::new_from_path(path).use_file().open()?,
+            TrimmerParameters::from_args(&args)?,
+        )),
+        None => None,
+    };
+
+    let TrimmerArgs {
+        fastq_inputs: _,
+        input_pairing: _,
+        auto_r2: _,
+        output,
+        output2,
+        output_template,
+        interleaved_out: _,
+        mask,
+        filter_widows,
+        pair_filter,
+        fasta_out,
+        bgzf,
+        compression,
+        fix_sides,
+        quality_filter,
+        clipping_args,
+        ont_summary,
+        ont_min_qscore,
+        ont_channels,
+        ont_pass_only,
+        ont_keep_unlisted,
+        length_hist,
+        length_hist_bin,
+        cycle_report,
+        tee,
+        trace,
+        summary_json: _,
+        replay: _,
+        verbose,
+    } = args;
+
+    let ont_summary = match &ont_summary {
+        Some(path) => {
+            let criteria = OntSummaryCriteria {
+                min_qscore: ont_min_qscore,
+                channels:   ont_channels,
+                pass_only:  ont_pass_only,
+            };
+            Some(OntSummary::from_tsv(path, &criteria).with_path_context("Failed to read the sequencing summary", path)?)
+        }
+        None => None,
+    };
+
+    let compression = compression.options(if bgzf { GzipFormat::Bgzf } else { GzipFormat::Gzip });
+    let writers = match output_template {
+        Some(_) => None,
+        None => Some(
+            OutputOptions::new_from_opt_paths(output.as_ref(), output2.as_ref())
+                .with_compression(compression)
+                .use_file_zip_or_stdout()
+                .open()?,
+        ),
+    };
+
+    let length_hist = match &length_hist {
+        Some(path) => Some((OutputOptions::new_from_path(path).use_file().open()?, length_hist_bin)),
+        None => None,
+    };
+
+    let cycle_report = match &cycle_report {
+        Some(path) => Some(OutputOptions::new_from_path(path).use_file().open()?),
+        None => None,
+    };
+
+    let quality_filter = parse_trimmer_quality_filter(quality_filter);
+    let primer_file = clipping_args.primer_trim.clone();
+    let clipping_args = parse_clipping_args(clipping_args)?;
+
+    let parsed = ParsedTrimmerArgs {
+        inputs,
+        writers,
+        compression,
+        filter_widows,
+        tee,
+        trimming_args: ParsedTrimmerOptions {
+            mask,
+            quality_filter,
+            fasta_out,
+            fix_sides,
+            clipping_args,
+            verbose,
+            ont_summary,
+            ont_keep_unlisted,
+            pair_filter,
+            tracer: ReadTracer::new("trimmer", trace),
+        },
+        primer_file,
+        length_hist,
+        cycle_report,
+        summary_json,
+    };
+
+    Ok(parsed)
+}
+
+/// Groups the inputs into the inputs (or pairs of inputs) trimmed one after
+/// another. Exits with a clap error if the inputs cannot be paired.
+fn pair_inputs(paths: Vec<PathBuf>, pairing: InputPairing) -> Vec<TrimmerInput> {
+    let paired = match pairing {
+        InputPairing::Auto => paths.len() == 2,
+        InputPairing::Pairs => true,
+        InputPairing::Separate => false,
+    };
+    if !paired {
+        return paths
+            .into_iter()
+            .map(|path1| TrimmerInput {
+                path1,
+                path2: None,
+                outputs: None,
+            })
+            .collect();
+    }
+
+    if !paths.len().is_multiple_of(2) {
+        abort_clap(
+            ErrorKind::WrongNumberOfValues,
+            format!(
+                "`--input-pairing pairs` requires an even number of inputs, but {} were given",
+                paths.len()
+            ),
+            Some("trimmer"),
+        );
+    }
+    let mut paths = paths.into_iter();
+    std::iter::from_fn(|| {
+        Some(TrimmerInput {
+            path1:   paths.next()?,
+            path2:   paths.next(),
+            outputs: None,
+        })
+    })
+    .collect()
+}
+
+/// Validates the template of `--output-template`, which must contain `{name}`
+/// so that each input is written to its own output.
+fn parse_output_template(template: &str) -> Result<String, String> {
+    if template.contains("{name}") {
+        Ok(template.to_string())
+    } else {
+        Err("The template must contain `{name}`".to_string())
+    }
+}
+
+/// Names the outputs of each input from `--output-template`. The R2 reads of
+/// paired inputs are written to their own output unless `interleaved_out`.
+///
+/// ## Errors
+///
+/// An error is returned if two outputs would have the same name, or an output
+/// would have the same name as an input.
+fn name_template_outputs(inputs: &mut [TrimmerInput], template: &str, interleaved_out: bool) -> std::io::Result<()> {
+    let name_output = |input: &Path| {
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        let name = name.strip_suffix(".gz").unwrap_or(&name);
+        let name = [".fastq", ".fq"]
+            .into_iter()
+            .find_map(|extension| name.strip_suffix(extension))
+            .unwrap_or(name);
+        PathBuf::from(template.replace("{name}", name))
+    };
+
+    let mut seen = inputs
+        .iter()
+        .flat_map(|input| std::iter::once(&input.path1).chain(&input.path2))
+        .map(std::path::absolute)
+        .collect::<std::io::Result<HashSet<_>>>()?;
+    for input in inputs {
+        let output1 = name_output(&input.path1);
+        let output2 = input.path2.as_deref().filter(|_| !interleaved_out).map(name_output);
+        for output in std::iter::once(&output1).chain(&output2) {
+            if !seen.insert(std::path::absolute(output)?) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "`--output-template` names the output {} for more than one input, or for an input itself",
+                        output.display()
+                    ),
+                ));
+            }
+        }
+        input.outputs = Some((output1, output2));
+    }
+    Ok(())
+}
+
+/// Parses the quality filtering arguments of `trimmer`. The minimum length is
+/// always enforced after trimming, which matches `preprocess` with `-E`.
+fn parse_trimmer_quality_filter(quality_filter: QualityFilterArgs) -> ParsedQualityFilterArgs {
+    ParsedQualityFilterArgs {
+        enforce_clipped_length: true,
+        ..parse_quality_filter_args(quality_filter)
+    }
+}
+
+/// Trims a read (either with clipping or masking) and checks its length,
+/// quality center, and (with `--max-n-frac`) fraction of N bases. `Some` is
+/// returned if it passes all filters. With `--ont-summary`, reads not meeting the sequencing summary criteria are
+/// filtered before trimming.
+///
+/// The returned record is FASTA (lacking quality scores) if `--fasta-out` was
+/// specified, and FASTQ otherwise.
+///
+/// Each step is traced for reads selected with `--trace-read`.
+fn trim_filter<'a>(
+    read: &'a mut FastQ, side: ReadSide, args: &ParsedTrimmerOptions, trim_counts: &mut TrimmedCounts,
+) -> std::io::Result<Option<FastXViewMut<'a>>> {
+    match trim_filter_mate(read, side, args, trim_counts, false)? {
+        TrimmedMate::Kept(record) => Ok(Some(record)),
+        TrimmedMate::Short(_) | TrimmedMate::LengthFiltered | TrimmedMate::Filtered => Ok(None),
+    }
+}
+
+/// A mate trimmed by [`trim_filter_mate`].
+enum TrimmedMate<'a> {
+    /// The read passed every filter
+    Kept(FastXViewMut<'a>),
+    /// The read passed every filter but the minimum length. Whether it is
+    /// written depends on its mate and `--pair-filter`
+    Short(FastXViewMut<'a>),
+    /// The read was filtered for its length
+    LengthFiltered,
+    /// The read was filtered for any other reason
+    Filtered,
+}
+
+/// As [`trim_filter`], but with `keep_short`, a non-empty read below the
+/// minimum length is still checked against the other filters and returned as
+/// [`TrimmedMate::Short`] rather than being counted as length filtered, so
+/// that `--pair-filter` can decide whether it is written with its mate.
+/// Trimming does not stop early in that case, so the fully trimmed read is
+/// available.
+fn trim_filter_mate<'a>(
+    read: &'a mut FastQ, side: ReadSide, args: &ParsedTrimmerOptions, trim_counts: &mut TrimmedCounts, keep_short: bool,
+) -> std::io::Result<TrimmedMate<'a>> {
+    let trace = args.tracer.start(&read.header);
+    trace.step("input", || format!("length {len}", len = read.len()));
+
+    if args.fix_sides
+        && let Some(side) = side.to_char()
+    {
+        fix_header_sides(&mut read.header, side);
+    }
+
+    if let Some(summary) = &args.ont_summary {
+        let status = summary.status(&read.header);
+        trace.step("ont summary", || format!("{status:?}"));
+        match status {
+            OntStatus::Kept => {}
+            OntStatus::Filtered => {
+                trim_counts.ont_filtered += 1;
+                trace.step("dropped", || "fails the `--ont-*` criteria".to_string());
+                return Ok(TrimmedMate::Filtered);
+            }
+            OntStatus::Unlisted => {
+                trim_counts.ont_unlisted += 1;
+                if !args.ont_keep_unlisted {
+                    trace.step("dropped", || "absent from the sequencing summary".to_string());
+                    return Ok(TrimmedMate::Filtered);
+                }
+            }
+        }
+    }
+
+    let bases = NonCanonicalBases::new(read.sequence.as_bytes());
+    if !args.clipping_args.preserve_bases {
+        trim_counts.recoded_bases += bases.recoded;
+    }
+    trim_counts.non_iupac_bases += bases.non_iupac;
+    if bases.is_dropped(args.clipping_args.strict_bases, &read.header)? {
+        trim_counts.strict_filtered += 1;
+        trace.step("dropped", || {
+            format!(
+                "contains {count} character(s) that are not IUPAC nucleotide codes",
+                count = bases.non_iupac
+            )
+        });
+        return Ok(TrimmedMate::Filtered);
+    }
+
+    let min_length = args.quality_filter.min_length;
+    let length_filtered = |len: Option<usize>| {
+        trace.step("dropped", || match len {
+            Some(len) => format!("the trimmed length {len} is empty or below the minimum length {min_length}"),
+            None => format!("the read became shorter than the minimum length {min_length} while trimming"),
+        });
+    };
+    // A short read is only returned if it is not empty, since empty reads are
+    // always filtered
+    let is_short = |len: usize| keep_short && len > 0;
+    let trim_min_length = if keep_short { 0 } else { min_length };
+    let (trimmed, short) = if args.mask {
+        let fq_view = read.as_view_mut();
+        trim_read(
+            fq_view,
+            args.mask,
+            &args.clipping_args,
+            trim_counts,
+            args.verbose,
+            trim_min_length,
+            side,
+            &trace,
+        );
+        if args.quality_filter.passes_clipped_length(read.len()) {
+            (read.as_view_mut(), false)
+        } else if is_short(read.len()) {
+            (read.as_view_mut(), true)
+        } else {
+            trim_counts.length_filtered += 1;
+            length_filtered(Some(read.len()));
+            return Ok(TrimmedMate::LengthFiltered);
+        }
+    } else {
+        let fq_view = read.as_view_mut();
+        match trim_read(
+            fq_view,
+            args.mask,
+            &args.clipping_args,
+            trim_counts,
+            args.verbose,
+            trim_min_length,
+            side,
+            &trace,
+        ) {
+            Some(edited) if args.quality_filter.passes_clipped_length(edited.len()) => (edited, false),
+            Some(edited) if is_short(edited.len()) => (edited, true),
+            edited => {
+                trim_counts.length_filtered += 1;
+                length_filtered(edited.map(|edited| edited.len()));
+                return Ok(TrimmedMate::LengthFiltered);
+            }
+        }
+    };
+    let q_center = trimmed.get_q_center(args.quality_filter.use_median);
+    if !args.quality_filter.passes_quality(q_center) {
+        trim_counts.quality_filtered += 1;
+        trace.step("dropped", || {
+            format!(
+                "the quality center {q_center:?} is below the minimum quality {min_quality}",
+                min_quality = args.quality_filter.min_read_quality
+            )
+        });
+        return Ok(TrimmedMate::Filtered);
+    }
+    if !args.quality_filter.passes_n_fraction(trimmed.sequence.as_bytes()) {
+        trim_counts.n_filtered += 1;
+        trace.step("dropped", || {
+            format!(
+                "the N fraction {n_frac:.4} is above the maximum {max_n_frac}",
+                n_frac = n_fraction(trimmed.sequence.as_bytes()),
+                max_n_frac = args.quality_filter.max_n_frac.unwrap_or_default()
+            )
+        });
+        return Ok(TrimmedMate::Filtered);
+    }
+    if short {
+        trace.step("short", || {
+            format!(
+                "the trimmed length {len} is below the minimum length {min_length}, so it is kept only if `--pair-filter` allows",
+                len = trimmed.len()
+            )
+        });
+    } else {
+        trace.step("kept", || {
+            format!("length {len}, quality center {q_center:?}", len = trimmed.len())
+        });
+    }
+
+    let mut record = FastXViewMut::from(trimmed);
+    if args.fasta_out {
+        record.quality = None;
+    }
+    Ok(if short {
+        TrimmedMate::Short(record)
+    } else {
+        TrimmedMate::Kept(record)
+    })
+}
+
+/// Trims a read (either with clipping or masking) and writes it if it passes
+/// the length filter.
+fn trim_and_write_seq<W: Write>(
+    mut read: FastQ, side: ReadSide, args: &ParsedTrimmerOptions, writer: &mut W, counts: &mut TrimmedCounts,
+) -> std::io::Result<()> {
+    counts.total_processed += 1;
+    if let Some(lengths) = &mut counts.lengths {
+        lengths.before.add(read.len());
+    }
+    if let Some(cycles) = &mut counts.cycles {
+        cycles.before.add(read.sequence.as_bytes(), Some(read.quality.as_bytes()));
+    }
+    if let Some(trimmed) = trim_filter(&mut read, side, args, counts)? {
+        if let Some(lengths) = &mut counts.lengths {
+            lengths.after.add(trimmed.sequence.len());
+        }
+        if let Some(cycles) = &mut counts.cycles {
+            cycles
+                .after
+                .add(trimmed.sequence, trimmed.quality.as_ref().map(|q| q.as_bytes()));
+        }
+        trimmed.write_record(writer)
+    } else {
+        Ok(())
+    }
+}
+
+/// Trims a pair of reads (either with clipping or masking) and writes them if
+/// both pass the filters, with `--pair-filter` deciding whether a pair is
+/// written when a mate is below the minimum length. A widow is counted on the
+/// side of the read that passed (or was never trimmed).
+fn trim_and_write_pair<'a, W>(
+    pair: [FastQ; 2], args: &ParsedTrimmerOptions, writer: &mut W, counts: &mut SidedTrimmedCounts,
+) -> std::io::Result<()>
+where
+    for<'b> [FastXViewMut<'b>; 2]: WriteRecord<W>, {
+    counts.r1.total_processed += 1;
+    counts.r2.total_processed += 1;
+    let [mut read1, mut read2] = pair;
+    if let (Some(lengths1), Some(lengths2)) = (&mut counts.r1.lengths, &mut counts.r2.lengths) {
+        lengths1.before.add(read1.len());
+        lengths2.before.add(read2.len());
+    }
+    if let (Some(cycles1), Some(cycles2)) = (&mut counts.r1.cycles, &mut counts.r2.cycles) {
+        cycles1.before.add(read1.sequence.as_bytes(), Some(read1.quality.as_bytes()));
+        cycles2.before.add(read2.sequence.as_bytes(), Some(read2.quality.as_bytes()));
+    }
+    let [keep_short1, keep_short2] = args.pair_filter.keeps_short();
+    let r1_trimmed = match trim_filter_mate(&mut read1, ReadSide::R1, args, &mut counts.r1, keep_short1)? {
+        mate @ (TrimmedMate::LengthFiltered | TrimmedMate::Filtered) => {
+            // Filtered first read, which we've counted as filtered, so need to
+            // count second read as being widow filtered
+            if matches!(mate, TrimmedMate::LengthFiltered) {
+                counts.pairs_length_dropped += 1;
+            }
+            counts.r2.widow_filtered += 1;
+            trace_widow(&args.tracer, &read2.header);
+            return Ok(());
+        }
+        mate => mate,
+    };
+    let r2_trimmed = match trim_filter_mate(&mut read2, ReadSide::R2, args, &mut counts.r2, keep_short2)? {
+        mate @ (TrimmedMate::LengthFiltered | TrimmedMate::Filtered) => {
+            if matches!(mate, TrimmedMate::LengthFiltered) {
+                counts.pairs_length_dropped += 1;
+            }
+            if let TrimmedMate::Short(r1_trimmed) | TrimmedMate::Kept(r1_trimmed) = &r1_trimmed {
+                counts.r1.widow_filtered += 1;
+                trace_widow(&args.tracer, r1_trimmed.header);
+            }
+            return Ok(());
+        }
+        mate => mate,
+    };
+
+    let (r1_trimmed, r2_trimmed) = match (r1_trimmed, r2_trimmed) {
+        (TrimmedMate::Kept(r1_trimmed), TrimmedMate::Kept(r2_trimmed)) => (r1_trimmed, r2_trimmed),
+        (TrimmedMate::Short(r1_trimmed), TrimmedMate::Short(r2_trimmed)) => {
+            // Neither mate is long enough to keep the other
+            counts.pairs_length_dropped += 1;
+            for (mate_counts, header) in [(&mut counts.r1, r1_trimmed.header), (&mut counts.r2, r2_trimmed.header)] {
+                mate_counts.length_filtered += 1;
+                trace_short_pair(&args.tracer, header);
+            }
+            return Ok(());
+        }
+        (
+            TrimmedMate::Kept(r1_trimmed) | TrimmedMate::Short(r1_trimmed),
+            TrimmedMate::Kept(r2_trimmed) | TrimmedMate::Short(r2_trimmed),
+        ) => {
+            counts.pairs_short_kept += 1;
+            (r1_trimmed, r2_trimmed)
+        }
+        _ => unreachable!("filtered mates have already returned"),
+    };
+    if let (Some(lengths1), Some(lengths2)) = (&mut counts.r1.lengths, &mut counts.r2.lengths) {
+        lengths1.after.add(r1_trimmed.sequence.len());
+        lengths2.after.add(r2_trimmed.sequence.len());
+    }
+    if let (Some(cycles1), Some(cycles2)) = (&mut counts.r1.cycles, &mut counts.r2.cycles) {
+        cycles1
+            .after
+            .add(r1_trimmed.sequence, r1_trimmed.quality.as_ref().map(|q| q.as_bytes()));
+        cycles2
+            .after
+            .add(r2_trimmed.sequence, r2_trimmed.quality.as_ref().map(|q| q.as_bytes()));
+    }
+    [r1_trimmed, r2_trimmed].write_record(writer)
+}
+
+/// Traces a read selected with `--trace-read` being dropped since both it and
+/// its mate are below the minimum length.
+fn trace_short_pair(tracer: &ReadTracer, header: &str) {
+    tracer
+        .start(header)
+        .step("dropped", || "below the minimum length, as is its mate".to_string());
+}
+
+/// Traces a read selected with `--trace-read` being dropped as a widow.
+fn trace_widow(tracer: &ReadTracer, header: &str) {
+    tracer
+        .start(header)
+        .step("dropped", || "widowed, since its mate was filtered".to_string());
+}
+
+impl TrimmedCounts {
+    /// Writes the trimmed and filtered counts for verbose output
+    fn write_counts(
+        self, args: &ParsedClippingArgs, strategy: &PairedIoStrategy, options: &ParsedTrimmerOptions,
+        primer_file: Option<PathBuf>,
+    ) {
+        let ParsedClippingArgs {
+            preserve_bases: _,
+            strict_bases,
+            barcodes,
+            barcode_pairs,
+            b_annotate_sample: _,
+            b_restrict_left: _,
+            b_restrict_right: _,
+            b_hdist,
+            b_quality_aware,
+            adapters,
+            a_max_mismatch,
+            primer_kmers,
+            p_restrict_left: _,
+            p_restrict_right: _,
+            polyg_left,
+            polyg_right,
+            hard_left,
+            hard_right,
+            hard_left_r2,
+            hard_right_r2,
+            trim_order: _,
+        } = args;
+        let ParsedTrimmerOptions {
+            mask,
+            quality_filter,
+            fasta_out: _,
+            fix_sides: _,
+            clipping_args: _,
+            verbose: _,
+            ont_summary,
+            ont_keep_unlisted,
+            pair_filter: _,
+            tracer: _,
+        } = options;
+
+        let trim_mask = match mask {
+            true => "masked",
+            false => "trimmed",
+        };
+
+        eprintln!("IRMA-core trimmer processed reads from {strategy}");
+
+        eprintln!("{:<20} {:>10} reads", "Input:", self.total_processed);
+
+        if polyg_left.is_some() || polyg_right.is_some() {
+            let polyg_left = polyg_left.unwrap_or(0);
+            let polyg_right = polyg_right.unwrap_or(0);
+            let percent = self.poly_g as f64 / self.total_processed as f64 * 100.0;
+            let thresholds = if polyg_left == polyg_right {
+                format!("with a PolyG threshold of {polyg_left} bases")
+            } else {
+                format!(
+                    "with a left PolyG threshold of {polyg_left} bases and a right PolyG threshold of {polyg_right} bases"
+                )
+            };
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) {thresholds}",
+                format!("PolyG {trim_mask}:"),
+                self.poly_g
+            );
+        }
+        if barcodes.is_some() {
+            let percent = self.barcode as f64 / self.total_processed as f64 * 100.0;
+            let forgiven = b_quality_aware
+                .map(|q| format!(" (forgiving mismatches below quality {q})"))
+                .unwrap_or_default();
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) with an allowable hamming distance of {b_hdist}{forgiven}",
+                format!("Barcode {trim_mask}:"),
+                self.barcode
+            );
+        }
+        if barcode_pairs.is_some() {
+            let percent = self.barcode as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) with an allowable hamming distance of {b_hdist}",
+                format!("Barcode {trim_mask}:"),
+                self.barcode
+            );
+            let percent = self.unassigned as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) without a unique barcode pair",
+                "Unassigned:", self.unassigned
+            );
+        }
+        if adapters.is_some() {
+            let percent = self.adapter as f64 / self.total_processed as f64 * 100.0;
+            let fuzziness = match a_max_mismatch {
+                0 => "exact matching".to_string(),
+                _ => format!("an allowable hamming distance of {a_max_mismatch}"),
+            };
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) with {fuzziness}",
+                format!("Adapter {trim_mask}:"),
+                self.adapter
+            );
+        }
+        if primer_kmers.is_some() {
+            let context = if let Some(path) = primer_file {
+                format!("using primer set {}", path.display())
+            } else {
+                "".to_string()
+            };
+            let percent = self.primer as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) {context}",
+                format!("Primer {trim_mask}:"),
+                self.primer
+            );
+        }
+        if args.is_configured(TrimStep::Hard) {
+            let percent = self.hard as f64 / self.total_processed as f64 * 100.0;
+            let amounts = |left: &usize, right: &usize| {
+                if left == right {
+                    format!("{left} bases")
+                } else {
+                    format!("{left} bases on the left and {right} bases on the right")
+                }
+            };
+            let mut thresholds = format!("with an amount of {}", amounts(hard_left, hard_right));
+            if (hard_left, hard_right) != (hard_left_r2, hard_right_r2) {
+                thresholds = format!("{thresholds} ({} for R2)", amounts(hard_left_r2, hard_right_r2));
+            }
+            eprintln!(
+                "{:<20} {:>10} reads ({percent:.2}%) {thresholds}",
+                format!("Hard {trim_mask}:"),
+                self.hard
+            );
+        }
+
+        let percent_trimmed = self.total_trimmed as f64 / self.total_processed as f64 * 100.0;
+        eprintln!(
+            "{:<20} {:>10} reads ({percent_trimmed:.2}%)",
+            format!("Total {trim_mask}:"),
+            self.total_trimmed
+        );
+
+        let min_length = quality_filter.min_length;
+        let percent_filtered = self.length_filtered as f64 / self.total_processed as f64 * 100.0;
+        eprintln!(
+            "{:<20} {:>10} reads ({percent_filtered:.2}%) for being shorter than the minimum post-trimming length of {min_length}",
+            "Length filtered:", self.length_filtered,
+        );
+
+        if quality_filter.min_read_quality > 0 {
+            let center = if quality_filter.use_median {
+                "median"
+            } else {
+                "geometric mean"
+            };
+            let percent_quality = self.quality_filtered as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent_quality:.2}%) for having a {center} quality below {min_read_quality}",
+                "Quality filtered:",
+                self.quality_filtered,
+                min_read_quality = quality_filter.min_read_quality,
+            );
+        }
+
+        if let Some(max_n_frac) = quality_filter.max_n_frac {
+            let percent_n = self.n_filtered as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent_n:.2}%) for having a post-trimming N fraction above {max_n_frac}",
+                "N filtered:", self.n_filtered,
+            );
+        }
+
+        if self.recoded_bases > 0 || self.non_iupac_bases > 0 || strict_bases.is_some() {
+            eprintln!(
+                "{:<20} {:>10} bases recoded to canonical uppercase ACGTN",
+                "Recoded bases:", self.recoded_bases
+            );
+            eprintln!(
+                "{:<20} {:>10} bases that are not IUPAC nucleotide codes",
+                "Non-IUPAC bases:", self.non_iupac_bases
+            );
+        }
+
+        if *strict_bases == Some(StrictBases::Drop) {
+            let percent_strict = self.strict_filtered as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent_strict:.2}%) for containing characters that are not IUPAC nucleotide codes",
+                "Strict filtered:", self.strict_filtered
+            );
+        }
+
+        if ont_summary.is_some() {
+            let percent_ont = self.ont_filtered as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent_ont:.2}%) for not meeting the sequencing summary criteria",
+                "ONT filtered:", self.ont_filtered
+            );
+            let action = if *ont_keep_unlisted { "kept" } else { "filtered" };
+            let percent_unlisted = self.ont_unlisted as f64 / self.total_processed as f64 * 100.0;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent_unlisted:.2}%) {action} for being absent from the sequencing summary",
+                "ONT unlisted:", self.ont_unlisted
+            );
+        }
+
+        if strategy.filters_widows() {
+            let percent_widowed = self.widow_filtered as f64 / self.total_processed as f64 * 100.;
+            eprintln!(
+                "{:<20} {:>10} reads ({percent_widowed:.2}%) for their paired read being shorter than the minimum post-trimming length of {min_length}",
+                "Widow filtered:", self.widow_filtered
+            )
+        }
+    }
+}
+
+impl SidedTrimmedCounts {
+    /// Writes the reads processed, trimmed, and dropped from one of several
+    /// inputs for verbose output, before the counts of all inputs are written
+    /// by [`write_counts`](TrimmedCounts::write_counts).
+    fn write_input_counts(&self, input: &str, options: &ParsedTrimmerOptions) {
+        let trim_mask = if options.mask { "masked" } else { "trimmed" };
+        let counts = self.combined();
+        let total = counts.total_processed as f64;
+        let percent_trimmed = counts.total_trimmed as f64 / total * 100.0;
+        let dropped = counts.dropped(options.ont_keep_unlisted);
+        let percent_dropped = dropped as f64 / total * 100.0;
+        eprintln!(
+            "IRMA-core trimmer processed {} reads from {input} ({} {trim_mask} ({percent_trimmed:.2}%), {dropped} dropped ({percent_dropped:.2}%))",
+            counts.total_processed, counts.total_trimmed
+        );
+    }
+
+    /// Writes the adapter hit rate, mean bases trimmed, and drop rate of each
+    /// read side for verbose output, following
+    /// [`write_counts`](TrimmedCounts::write_counts). With widow filtering, the
+    /// pairs affected by `--pair-filter` are also written.
+    fn write_side_counts(&self, strategy: &PairedIoStrategy, options: &ParsedTrimmerOptions) {
+        let trim_mask = if options.mask { "masked" } else { "trimmed" };
+        let has_adapters = options.clipping_args.adapters.is_some();
+
+        eprintln!("IRMA-core trimmer counts by read side");
+        for (label, counts) in [("R1:", &self.r1), ("R2:", &self.r2)] {
+            let total = counts.total_processed as f64;
+            let mut stats = Vec::new();
+            if has_adapters {
+                let percent_adapter = counts.adapter as f64 / total * 100.0;
+                stats.push(format!("{percent_adapter:.2}% adapter {trim_mask}"));
+            }
+            if !options.mask {
+                // Reads filtered before trimming (by the sequencing summary or
+                // as widows of a filtered R1) count as having nothing trimmed
+                let mean_bases = counts.bases_trimmed as f64 / total;
+                stats.push(format!("{mean_bases:.2} mean bases trimmed per read"));
+            }
+            let percent_dropped = counts.dropped(options.ont_keep_unlisted) as f64 / total * 100.0;
+            stats.push(format!("{percent_dropped:.2}% dropped"));
+
+            eprintln!(
+                "{label:<20} {:>10} reads ({stats})",
+                counts.total_processed,
+                stats = stats.join(", ")
+            );
+        }
+
+        if strategy.filters_widows() {
+            let total = self.r1.total_processed as f64;
+            let percent_dropped = self.pairs_length_dropped as f64 / total * 100.0;
+            let percent_kept = self.pairs_short_kept as f64 / total * 100.0;
+            eprintln!(
+                "{:<20} {:>10} pairs ({percent_dropped:.2}%) dropped and {} pairs ({percent_kept:.2}%) kept with a mate below the minimum length, under `--pair-filter {}`",
+                "Pair filtered:", self.pairs_length_dropped, self.pairs_short_kept, options.pair_filter
+            );
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Run summaries for `--summary-json`, which record the trimming parameters of
+//! a run so that `--replay` can apply exactly the same parameters to a later
+//! run.
+
+use crate::{
+    args::clipping::{MAX_KMER_LENGTH, StrictBases, TrimEnd, TrimStep, validate_acgtn},
+    trimmer::{PairFilter, TrimmerArgs},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+use zoe::prelude::Nucleotides;
+
+/// The subcommand recorded in the summary, checked when it is replayed.
+const SUBCOMMAND: &str = "trimmer";
+
+/// The summary written by `--summary-json` and read by `--replay`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TrimmerSummary {
+    /// The version of `irma-core` that wrote the summary
+    irma_core_version: String,
+    /// The subcommand that wrote the summary, which must be `trimmer`
+    subcommand:        String,
+    /// The trimming parameters of the run
+    parameters:        TrimmerParameters,
+}
+
+/// The canonicalized trimming parameters of a run. Every option affecting which
+/// reads are written and how they are trimmed is present, with defaults filled
+/// in, `--a-fuzzy` folded into `a_max_mismatch`, and file paths made absolute.
+/// Inputs and outputs are not included, so the same parameters can be applied
+/// to other reads.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TrimmerParameters {
+    mask:              bool,
+    filter_widows:     bool,
+    #[serde(default)]
+    pair_filter:       PairFilter,
+    fasta_out:         bool,
+    min_read_quality:  u8,
+    use_median:        bool,
+    min_length:        NonZeroUsize,
+    max_n_frac:        Option<f64>,
+    preserve_bases:    bool,
+    #[serde(default)]
+    strict_bases:      Option<StrictBases>,
+    polyg_trim:        Option<NonZeroUsize>,
+    g_polyg_end:       TrimEnd,
+    g_polyg_left:      Option<NonZeroUsize>,
+    g_polyg_right:     Option<NonZeroUsize>,
+    barcode_trim:      Option<String>,
+    barcode_pairs:     Option<PathBuf>,
+    #[serde(default)]
+    b_annotate_sample: bool,
+    b_end:             TrimEnd,
+    b_restrict:        Option<NonZeroUsize>,
+    b_restrict_left:   Option<NonZeroUsize>,
+    b_restrict_right:  Option<NonZeroUsize>,
+    b_hdist:           usize,
+    b_quality_aware:   Option<u8>,
+    adapter_trim:      Option<String>,
+    a_max_mismatch:    usize,
+    primer_trim:       Option<PathBuf>,
+    p_fuzzy:           bool,
+    p_kmer_length:     Option<usize>,
+    #[serde(default)]
+    p_kmer_auto:       bool,
+    p_end:             TrimEnd,
+    p_restrict:        NonZeroUsize,
+    p_restrict_left:   Option<NonZeroUsize>,
+    p_restrict_right:  Option<NonZeroUsize>,
+    hard_trim:         Option<usize>,
+    h_left:            Option<usize>,
+    h_right:           Option<usize>,
+    #[serde(default)]
+    hard_trim_r2:      Option<usize>,
+    #[serde(default)]
+    h_left_r2:         Option<usize>,
+    #[serde(default)]
+    h_right_r2:        Option<usize>,
+    trim_order:        Option<Vec<TrimStep>>,
+    /// Whether reads were filtered using `--ont-summary`. The path itself is
+    /// an input, and must be given again when replaying
+    ont_summary:       bool,
+    ont_min_qscore:    Option<f32>,
+    ont_channels:      Option<Vec<RangeInclusive<u32>>>,
+    ont_pass_only:     bool,
+    ont_keep_unlisted: bool,
+}
+
+impl TrimmerParameters {
+    /// Records the trimming parameters given on the command line.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the primer or barcode pairs path cannot be made
+    /// absolute.
+    pub fn from_args(args: &TrimmerArgs) -> std::io::Result<Self> {
+        let sequence =
+            |seq: &Option<Nucleotides>| seq.as_ref().map(|seq| String::from_utf8_lossy(seq.as_bytes()).into_owned());
+        let clipping = &args.clipping_args;
+        let quality_filter = &args.quality_filter;
+
+        Ok(TrimmerParameters {
+            mask:              args.mask,
+            filter_widows:     args.filter_widows,
+            pair_filter:       args.pair_filter,
+            fasta_out:         args.fasta_out,
+            min_read_quality:  quality_filter.min_read_quality,
+            use_median:        quality_filter.use_median,
+            min_length:        quality_filter.min_length,
+            max_n_frac:        quality_filter.max_n_frac,
+            preserve_bases:    clipping.preserve_bases,
+            strict_bases:      clipping.strict_bases,
+            polyg_trim:        clipping.polyg_trim,
+            g_polyg_end:       clipping.g_polyg_end,
+            g_polyg_left:      clipping.g_polyg_left,
+            g_polyg_right:     clipping.g_polyg_right,
+            barcode_trim:      sequence(&clipping.barcode_trim),
+            barcode_pairs:     clipping.barcode_pairs.as_deref().map(std::path::absolute).transpose()?,
+            b_annotate_sample: clipping.b_annotate_sample,
+            b_end:             clipping.b_end,
+            b_restrict:        clipping.b_restrict,
+            b_restrict_left:   clipping.b_restrict_left,
+            b_restrict_right:  clipping.b_restrict_right,
+            b_hdist:           clipping.b_hdist,
+            b_quality_aware:   clipping.b_quality_aware,
+            adapter_trim:      sequence(&clipping.adapter_trim),
+            a_max_mismatch:    clipping.a_max_mismatch.unwrap_or(usize::from(clipping.a_fuzzy)),
+            primer_trim:       clipping.primer_trim.as_deref().map(std::path::absolute).transpose()?,
+            p_fuzzy:           clipping.p_fuzzy,
+            p_kmer_length:     clipping.p_kmer_length,
+            p_kmer_auto:       clipping.p_kmer_auto,
+            p_end:             clipping.p_end,
+            p_restrict:        clipping.p_restrict,
+            p_restrict_left:   clipping.p_restrict_left,
+            p_restrict_right:  clipping.p_restrict_right,
+            hard_trim:         clipping.hard_trim,
+            h_left:            clipping.h_left,
+            h_right:           clipping.h_right,
+            hard_trim_r2:      clipping.hard_trim_r2,
+            h_left_r2:         clipping.h_left_r2,
+            h_right_r2:        clipping.h_right_r2,
+            trim_order:        clipping.trim_order.clone(),
+            ont_summary:       args.ont_summary.is_some(),
+            ont_min_qscore:    args.ont_min_qscore,
+            ont_channels:      args.ont_channels.clone(),
+            ont_pass_only:     args.ont_pass_only,
+            ont_keep_unlisted: args.ont_keep_unlisted,
+        })
+    }
+
+    /// Reads the parameters from a summary written by `--summary-json`. A
+    /// warning is printed if it was written by a different version.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the summary cannot be read or parsed, if it was
+    /// written by another subcommand, or if its parameters are invalid. Each
+    /// contains the summary path as context.
+    pub fn from_summary(path: &Path) -> std::io::Result<Self> {
+        let invalid = |msg: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid summary for `--replay`: {path}\n{msg}", path = path.display()),
+            )
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Failed to read the summary: {}\n{e}", path.display())))?;
+        let summary: TrimmerSummary = serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+
+        if summary.subcommand != SUBCOMMAND {
+            return Err(invalid(format!(
+                "The summary was written by `{}` rather than `{SUBCOMMAND}`",
+                summary.subcommand
+            )));
+        }
+        if summary.irma_core_version != env!("CARGO_PKG_VERSION") {
+            eprintln!(
+                "IRMA-core trimmer WARNING! The replayed summary was written by version {}, so trimming may differ.",
+                summary.irma_core_version
+            );
+        }
+
+        summary.parameters.validate().map_err(invalid)?;
+        Ok(summary.parameters)
+    }
+
+    /// Checks the values that clap would otherwise have validated, since the
+    /// summary may have been edited.
+    fn validate(&self) -> Result<(), String> {
+        for sequence in [&self.barcode_trim, &self.adapter_trim].into_iter().flatten() {
+            validate_acgtn(sequence)?;
+        }
+        if self.barcode_trim.is_some() && self.barcode_pairs.is_some() {
+            return Err("Only one of `barcode_trim` and `barcode_pairs` may be given".to_string());
+        }
+        if (self.barcode_trim.is_some() || self.barcode_pairs.is_some()) && self.adapter_trim.is_some() {
+            return Err("Barcode and adapter trimming cannot both be given".to_string());
+        }
+        if self.b_quality_aware.is_some() && self.barcode_trim.is_none() {
+            return Err("`b_quality_aware` requires `barcode_trim`".to_string());
+        }
+        if let Some(max_n_frac) = self.max_n_frac
+            && !(0.0..=1.0).contains(&max_n_frac)
+        {
+            return Err(format!("`max_n_frac` must be between 0.0 and 1.0, but is {max_n_frac}"));
+        }
+        if self.b_hdist > 3 || self.a_max_mismatch > 3 {
+            return Err("The hamming distances must be between 0 and 3".to_string());
+        }
+        if self.primer_trim.is_some() {
+            match self.p_kmer_length {
+                Some(k) if (2..=MAX_KMER_LENGTH).contains(&k) => {}
+                Some(k) => return Err(format!("`p_kmer_length` must be between 2 and {MAX_KMER_LENGTH}, but is {k}")),
+                None => return Err("`p_kmer_length` is required for primer trimming".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the parameters to `args`, replacing its trimming options. Clap
+    /// ensures that none of them were also given on the command line.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if the replayed run filtered reads with a
+    /// sequencing summary but `--ont-summary` was not given, or vice versa.
+    pub fn apply_to(self, args: &mut TrimmerArgs) -> std::io::Result<()> {
+        if self.ont_summary != args.ont_summary.is_some() {
+            let msg = if self.ont_summary {
+                "The replayed run filtered reads with a sequencing summary, so `--ont-summary` must be given"
+            } else {
+                "The replayed run did not filter reads with a sequencing summary, so `--ont-summary` cannot be given"
+            };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
+
+        let clipping = &mut args.clipping_args;
+        let quality_filter = &mut args.quality_filter;
+        let sequence = |seq: Option<String>| seq.map(|seq| Nucleotides::from(seq.into_bytes()));
+
+        args.mask = self.mask;
+        args.filter_widows = self.filter_widows;
+        args.pair_filter = self.pair_filter;
+        args.fasta_out = self.fasta_out;
+        quality_filter.min_read_quality = self.min_read_quality;
+        quality_filter.use_median = self.use_median;
+        quality_filter.min_length = self.min_length;
+        quality_filter.max_n_frac = self.max_n_frac;
+        clipping.preserve_bases = self.preserve_bases;
+        clipping.strict_bases = self.strict_bases;
+        clipping.polyg_trim = self.polyg_trim;
+        clipping.g_polyg_end = self.g_polyg_end;
+        clipping.g_polyg_left = self.g_polyg_left;
+        clipping.g_polyg_right = self.g_polyg_right;
+        clipping.barcode_trim = sequence(self.barcode_trim);
+        clipping.barcode_pairs = self.barcode_pairs;
+        clipping.b_annotate_sample = self.b_annotate_sample;
+        clipping.b_end = self.b_end;
+        clipping.b_restrict = self.b_restrict;
+        clipping.b_restrict_left = self.b_restrict_left;
+        clipping.b_restrict_right = self.b_restrict_right;
+        clipping.b_hdist = self.b_hdist;
+        clipping.b_quality_aware = self.b_quality_aware;
+        clipping.adapter_trim = sequence(self.adapter_trim);
+        clipping.a_fuzzy = false;
+        clipping.a_max_mismatch = Some(self.a_max_mismatch);
+        clipping.primer_trim = self.primer_trim;
+        clipping.p_fuzzy = self.p_fuzzy;
+        clipping.p_kmer_length = self.p_kmer_length;
+        clipping.p_kmer_auto = self.p_kmer_auto;
+        clipping.p_end = self.p_end;
+        clipping.p_restrict = self.p_restrict;
+        clipping.p_restrict_left = self.p_restrict_left;
+        clipping.p_restrict_right = self.p_restrict_right;
+        clipping.hard_trim = self.hard_trim;
+        clipping.h_left = self.h_left;
+        clipping.h_right = self.h_right;
+        clipping.hard_trim_r2 = self.hard_trim_r2;
+        clipping.h_left_r2 = self.h_left_r2;
+        clipping.h_right_r2 = self.h_right_r2;
+        clipping.trim_order = self.trim_order;
+        args.ont_min_qscore = self.ont_min_qscore;
+        args.ont_channels = self.ont_channels;
+        args.ont_pass_only = self.ont_pass_only;
+        args.ont_keep_unlisted = self.ont_keep_unlisted;
+
+        Ok(())
+    }
+
+    /// Writes the parameters as a JSON summary for `--replay`, along with the
+    /// version and subcommand.
+    pub fn write_summary<W: Write>(self, mut writer: W) -> std::io::Result<()> {
+        let summary = TrimmerSummary {
+            irma_core_version: env!("CARGO_PKG_VERSION").to_string(),
+            subcommand:        SUBCOMMAND.to_string(),
+            parameters:        self,
+        };
+        serde_json::to_writer_pretty(&mut writer, &summary).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
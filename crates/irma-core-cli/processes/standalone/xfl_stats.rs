@@ -0,0 +1,444 @@
+//! Inspects and summarizes XFL tables, such as those written by `preprocess`
+//! and `xflate`, reporting the distribution of cluster sizes and the runs from
+//! which the reads of the largest clusters came.
+
+use crate::shared::xfl::{XFL_VERSION, XflHeader, XflLayout, parse_cluster_id, parse_cluster_size};
+use clap::{Args, ValueEnum};
+use foldhash::fast::SeedableRandomState;
+use irma_records::{
+    hashing::get_hasher,
+    io::{InputOptions, OutputOptions, ValidatePaths},
+    paired::{RunMetadata, get_run_metadata},
+};
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+use zoe::{data::fasta::FastaSeq, prelude::Nucleotides};
+
+#[derive(Args, Debug)]
+pub struct XflStatsArgs {
+    /// Path to the XFL table, which may be `.gz` compressed
+    table_file: PathBuf,
+
+    /// Paths to the deflated FASTA file(s) written alongside the table. When
+    /// provided, their checksum is verified against the table's header, and
+    /// the sequence length of each of the largest clusters is reported
+    fasta_files: Vec<PathBuf>,
+
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 10)]
+    /// The number of largest clusters to report
+    top: usize,
+
+    #[arg(long, value_enum, default_value_t)]
+    /// The format of the report
+    format: StatsFormat,
+
+    #[arg(short = 'o', long)]
+    /// Output path for the report. Prints to STDOUT if not provided
+    output: Option<PathBuf>,
+}
+
+impl ValidatePaths for XflStatsArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        std::iter::once(&self.table_file).chain(&self.fasta_files)
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        self.output.iter()
+    }
+}
+
+/// The format of the `xfl-stats` report.
+#[derive(ValueEnum, Copy, Clone, Eq, PartialEq, Debug, Default)]
+enum StatsFormat {
+    /// Tab-delimited key-value pairs, followed by a table of the size
+    /// distribution and a table of the largest clusters, each beginning with a
+    /// `#` header line
+    #[default]
+    Tsv,
+    /// A single JSON object
+    Json,
+}
+
+/// The summary of an XFL table.
+#[derive(Serialize, Debug)]
+struct XflStats {
+    /// The version of the XFL header, or `legacy` for tables without one
+    version:           String,
+    /// The number of header lines, which is more than one for tables
+    /// concatenated from sharded runs
+    sections:          usize,
+    /// The layout of the reads, or `mixed` if the sections differ
+    layout:            String,
+    /// Whether the reads stored per cluster were capped in any section
+    capped:            bool,
+    clusters:          usize,
+    /// The number of reads (or pairs) in the clusters, including any that were
+    /// not stored because the table was capped
+    reads:             usize,
+    /// The number of reads (or pairs) whose headers are stored in the table
+    stored_reads:      usize,
+    /// The number of clusters with a single read
+    singletons:        usize,
+    min_size:          Option<usize>,
+    median_size:       Option<f64>,
+    mean_size:         Option<f64>,
+    max_size:          Option<usize>,
+    /// Whether the checksum of the FASTA files matches every section of the
+    /// table, or `None` if no FASTA was provided or the table has no header
+    checksum_match:    Option<bool>,
+    size_distribution: Vec<SizeBin>,
+    largest_clusters:  Vec<ClusterSummary>,
+}
+
+/// The clusters whose sizes fall within a range. The ranges are powers of two,
+/// so that the bins are `1`, `2`, `3-4`, `5-8`, and so on.
+#[derive(Serialize, Debug)]
+struct SizeBin {
+    sizes:    String,
+    clusters: usize,
+    reads:    usize,
+}
+
+/// One of the largest clusters, along with the runs from which its stored
+/// reads came.
+#[derive(Serialize, Debug)]
+struct ClusterSummary {
+    name:   String,
+    size:   usize,
+    /// The length of the cluster sequence, if the FASTA files were provided
+    /// and contain the cluster
+    length: Option<usize>,
+    runs:   Vec<RunCount>,
+    /// The order of the cluster in the table, for breaking ties in size
+    #[serde(skip)]
+    order:  usize,
+}
+
+/// The number of stored reads of a cluster from a given run (and lane, for
+/// Illumina reads).
+#[derive(Serialize, Debug)]
+struct RunCount {
+    run:   String,
+    reads: usize,
+}
+
+/// The cluster sequences of the FASTA files, keyed by cluster prefix and
+/// number.
+type ClusterSequences = HashMap<(String, usize), Nucleotides, SeedableRandomState>;
+
+/// Sub-program for summarizing XFL tables.
+pub fn xfl_stats_process(args: XflStatsArgs) -> std::io::Result<()> {
+    args.validate_paths()?;
+
+    let sequences = if args.fasta_files.is_empty() {
+        None
+    } else {
+        Some(read_cluster_sequences(&args.fasta_files)?)
+    };
+
+    let stats = summarize_table(&args.table_file, args.top, sequences.as_ref())?;
+
+    let mut writer = OutputOptions::new_from_opt_path(args.output.as_ref())
+        .use_file_zip_or_stdout()
+        .open()?;
+    match args.format {
+        StatsFormat::Tsv => write_tsv(&mut writer, &stats)?,
+        StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &stats).map_err(std::io::Error::from)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Reads the cluster sequences of the deflated FASTA files. Sequences of
+/// clusters named with `{c}` are reverse complemented, as when reinflating, so
+/// that the checksum matches the table.
+fn read_cluster_sequences(fasta_files: &[PathBuf]) -> std::io::Result<ClusterSequences> {
+    let mut sequences = HashMap::with_hasher(get_hasher());
+
+    for file in fasta_files {
+        let reader = InputOptions::new_from_path(file).use_file_or_zip().parse_fasta().open()?;
+
+        for record in reader {
+            let FastaSeq { name, sequence } = record?;
+            let (prefix, num) = parse_cluster_name(&name, file)?;
+
+            let mut sequence = Nucleotides::from_vec_unchecked(sequence);
+            if name.ends_with("{c}") {
+                sequence.make_reverse_complement();
+            }
+            sequences.insert((prefix.to_string(), num), sequence);
+        }
+    }
+
+    Ok(sequences)
+}
+
+/// Reads the XFL table and summarizes its clusters, keeping the `top` largest.
+/// If the cluster `sequences` are provided, they are checked against the
+/// checksum of each section, and the lengths of the largest clusters are
+/// included.
+///
+/// The size of each cluster is taken from its name (`<PREFIX><ID>%<SIZE>`), or
+/// is the number of reads stored for it if its name has no size.
+fn summarize_table(table_file: &Path, top: usize, sequences: Option<&ClusterSequences>) -> std::io::Result<XflStats> {
+    let reader = InputOptions::new_from_path(table_file).use_file_or_zip().open()?;
+
+    let mut headers: Vec<XflHeader> = Vec::new();
+    let mut sizes = Vec::new();
+    let mut stored_reads = 0;
+    let mut largest = Vec::new();
+    // The smallest size of the retained largest clusters, once more than `top`
+    // have been seen
+    let mut min_largest = 0;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = XflHeader::parse(&line, table_file)? {
+            headers.push(header);
+            continue;
+        }
+
+        let layout = headers.last().map_or(XflLayout::Reads, |header| header.layout);
+        let mut fields = line.split('\t');
+        let name = fields.next().unwrap_or_default();
+        let (prefix, num) = parse_cluster_name(name, table_file)?;
+
+        let stored = fields.clone().count() / layout.fields_per_read();
+        let size = parse_cluster_size(name).unwrap_or(stored);
+        stored_reads += stored;
+        sizes.push(size);
+
+        if top > 0 && size >= min_largest {
+            largest.push(ClusterSummary {
+                name: name.to_string(),
+                size,
+                length: sequences
+                    .and_then(|sequences| sequences.get(&(prefix.to_string(), num)))
+                    .map(|sequence| sequence.as_bytes().len()),
+                // The first header of each read (or pair) identifies its run
+                runs: count_runs(fields.step_by(layout.fields_per_read())),
+                order: sizes.len(),
+            });
+
+            // Trimmed in batches, so that the retained clusters are only
+            // sorted occasionally
+            if largest.len() >= 2 * top {
+                sort_and_truncate(&mut largest, top);
+                min_largest = largest[top - 1].size;
+            }
+        }
+    }
+    sort_and_truncate(&mut largest, top);
+
+    let checksum_match = match sequences {
+        Some(sequences) if !headers.is_empty() => Some(checksums_match(&headers, sequences)),
+        _ => None,
+    };
+
+    Ok(summarize_sizes(&headers, sizes, stored_reads, checksum_match, largest))
+}
+
+/// Sorts the clusters from largest to smallest, breaking ties by their order
+/// in the table, and keeps the first `top`.
+fn sort_and_truncate(clusters: &mut Vec<ClusterSummary>, top: usize) {
+    clusters.sort_unstable_by_key(|cluster| (Reverse(cluster.size), cluster.order));
+    clusters.truncate(top);
+}
+
+/// Tallies the runs of the given read headers, from the most to the least
+/// reads. Headers that are neither Illumina nor SRA-style are tallied as
+/// `unknown`.
+fn count_runs<'a>(headers: impl Iterator<Item = &'a str>) -> Vec<RunCount> {
+    let mut counts: HashMap<String, usize, SeedableRandomState> = HashMap::with_hasher(get_hasher());
+    for header in headers {
+        let run = match get_run_metadata(header) {
+            Some(RunMetadata::Illumina {
+                instrument,
+                run_id,
+                flowcell,
+                lane,
+            }) => format!("{instrument}:{run_id}:{flowcell}:{lane}"),
+            Some(RunMetadata::Sra { accession }) => accession,
+            None => "unknown".to_string(),
+        };
+        *counts.entry(run).or_insert(0) += 1;
+    }
+
+    let mut runs = counts
+        .into_iter()
+        .map(|(run, reads)| RunCount { run, reads })
+        .collect::<Vec<_>>();
+    runs.sort_unstable_by(|a, b| b.reads.cmp(&a.reads).then_with(|| a.run.cmp(&b.run)));
+    runs
+}
+
+/// Whether the checksum of the cluster sequences matches every section of the
+/// table. A section whose clusters are not all present does not match.
+fn checksums_match(headers: &[XflHeader], sequences: &ClusterSequences) -> bool {
+    headers.iter().all(|header| {
+        let prefix = &header.naming.prefix;
+        let observed = (0..header.clusters)
+            .map(|i| {
+                sequences
+                    .get(&(prefix.clone(), header.naming.start + i))
+                    .map(|sequence| (sequence, 0))
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|clusters| XflHeader::from_clusters(clusters, header.layout, header.naming.clone()));
+        observed.is_some_and(|observed| observed.crc32 == header.crc32)
+    })
+}
+
+/// Computes the summary statistics of the cluster sizes (in table order).
+fn summarize_sizes(
+    headers: &[XflHeader], mut sizes: Vec<usize>, stored_reads: usize, checksum_match: Option<bool>,
+    largest_clusters: Vec<ClusterSummary>,
+) -> XflStats {
+    let layout_name = |layout: XflLayout| match layout {
+        XflLayout::Reads => "reads",
+        XflLayout::Pairs => "pairs",
+    };
+    let layout = match headers.split_first() {
+        None => layout_name(XflLayout::Reads),
+        Some((first, rest)) if rest.iter().all(|header| header.layout == first.layout) => layout_name(first.layout),
+        Some(_) => "mixed",
+    };
+
+    let mut size_distribution: Vec<SizeBin> = Vec::new();
+    for &size in &sizes {
+        // The bin of sizes in (2^(k-1), 2^k], with 1 in the first bin
+        let bin = size.max(1).next_power_of_two().trailing_zeros() as usize;
+        if size_distribution.len() <= bin {
+            size_distribution.resize_with(bin + 1, || SizeBin {
+                sizes:    String::new(),
+                clusters: 0,
+                reads:    0,
+            });
+        }
+        size_distribution[bin].clusters += 1;
+        size_distribution[bin].reads += size;
+    }
+    for (bin, size_bin) in size_distribution.iter_mut().enumerate() {
+        let (low, high) = if bin == 0 { (1, 1) } else { ((1 << (bin - 1)) + 1, 1 << bin) };
+        size_bin.sizes = if low == high {
+            low.to_string()
+        } else {
+            format!("{low}-{high}")
+        };
+    }
+    size_distribution.retain(|size_bin| size_bin.clusters > 0);
+
+    let clusters = sizes.len();
+    let reads = sizes.iter().sum::<usize>();
+    sizes.sort_unstable();
+    let median_size = match clusters {
+        0 => None,
+        _ if clusters % 2 == 1 => Some(sizes[clusters / 2] as f64),
+        _ => Some((sizes[clusters / 2 - 1] + sizes[clusters / 2]) as f64 / 2.0),
+    };
+
+    XflStats {
+        version: if headers.is_empty() { "legacy" } else { XFL_VERSION }.to_string(),
+        sections: headers.len(),
+        layout: layout.to_string(),
+        capped: headers.iter().any(|header| header.max_members.is_some()),
+        clusters,
+        reads,
+        stored_reads,
+        singletons: sizes.iter().take_while(|&&size| size == 1).count(),
+        min_size: sizes.first().copied(),
+        median_size,
+        mean_size: (clusters > 0).then(|| reads as f64 / clusters as f64),
+        max_size: sizes.last().copied(),
+        checksum_match,
+        size_distribution,
+        largest_clusters,
+    }
+}
+
+/// Writes the summary as tab-delimited key-value pairs, followed by the size
+/// distribution and the largest clusters. Missing values are written as `*`.
+fn write_tsv(writer: &mut impl Write, stats: &XflStats) -> std::io::Result<()> {
+    let or_star = |value: Option<String>| value.unwrap_or_else(|| "*".to_string());
+
+    writeln!(writer, "VERSION\t{}", stats.version)?;
+    writeln!(writer, "SECTIONS\t{}", stats.sections)?;
+    writeln!(writer, "LAYOUT\t{}", stats.layout)?;
+    writeln!(writer, "CAPPED\t{}", stats.capped)?;
+    writeln!(writer, "CLUSTERS\t{}", stats.clusters)?;
+    writeln!(writer, "READS\t{}", stats.reads)?;
+    writeln!(writer, "STORED_READS\t{}", stats.stored_reads)?;
+    writeln!(writer, "SINGLETONS\t{}", stats.singletons)?;
+    writeln!(writer, "MIN_SIZE\t{}", or_star(stats.min_size.map(|size| size.to_string())))?;
+    writeln!(
+        writer,
+        "MEDIAN_SIZE\t{}",
+        or_star(stats.median_size.map(|size| format!("{size:.1}")))
+    )?;
+    writeln!(
+        writer,
+        "MEAN_SIZE\t{}",
+        or_star(stats.mean_size.map(|size| format!("{size:.2}")))
+    )?;
+    writeln!(writer, "MAX_SIZE\t{}", or_star(stats.max_size.map(|size| size.to_string())))?;
+    writeln!(
+        writer,
+        "CHECKSUM_MATCH\t{}",
+        or_star(stats.checksum_match.map(|matched| matched.to_string()))
+    )?;
+
+    writeln!(writer, "#sizes\tclusters\treads")?;
+    for SizeBin { sizes, clusters, reads } in &stats.size_distribution {
+        writeln!(writer, "{sizes}\t{clusters}\t{reads}")?;
+    }
+
+    writeln!(writer, "#cluster\tsize\tlength\truns")?;
+    for cluster in &stats.largest_clusters {
+        let runs = cluster
+            .runs
+            .iter()
+            .map(|RunCount { run, reads }| format!("{run}={reads}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        writeln!(
+            writer,
+            "{name}\t{size}\t{length}\t{runs}",
+            name = cluster.name,
+            size = cluster.size,
+            length = or_star(cluster.length.map(|length| length.to_string())),
+            runs = if runs.is_empty() { "*" } else { &runs }
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Parses the cluster prefix and number from a name of the form
+/// `<PREFIX><ID>%[REST]`.
+///
+/// ## Errors
+///
+/// If `name` does not have this form, an error is returned including `name`
+/// and `path` as context.
+fn parse_cluster_name<'a>(name: &'a str, path: &Path) -> std::io::Result<(&'a str, usize)> {
+    parse_cluster_id(name).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Invalid cluster name in file: {path}\nName: {name}\n\nClusters must be named <PREFIX><ID>%[REST], as written by `preprocess` and `xflate`",
+                path = path.display()
+            ),
+        )
+    })
+}
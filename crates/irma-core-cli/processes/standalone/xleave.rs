@@ -1,107 +0,0 @@
-//! Interleaves or de-interleaves paired FastQ or FASTA files.
-
-use clap::Args;
-use irma_records::{
-    io::{DispatchFastX, InputOptions, OutputOptions, RecordWriters, ValidatePaths, WriteRecords},
-    paired::{DeinterleavedPairedReadsExt, ZipPairedReadsExt},
-};
-use std::path::PathBuf;
-
-#[derive(Args, Debug)]
-pub struct XleaveArgs {
-    /// Path to FASTQ, FASTA, or .gz file to be interleaved/deinterleaved
-    pub input_file1: PathBuf,
-
-    /// Path to optional second FASTQ, FASTA, or .gz file to be deinterleaved
-    pub input_file2: Option<PathBuf>,
-
-    #[arg(short = '1', long, short_alias = 'o', aliases = ["output-file", "output-file1", "output1"])]
-    /// Output file path for interleaved/deinterleaved reads
-    pub output: Option<PathBuf>,
-
-    #[arg(short = '2', long, requires = "output", alias = "output-file2")]
-    /// Output path for a second sampled file if deinterleaving paired-end
-    /// reads. If this argument is omitted, output is interleaved
-    pub output2: Option<PathBuf>,
-}
-
-impl ValidatePaths for XleaveArgs {
-    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
-        let input1 = std::iter::once(&self.input_file1);
-        let input2 = self.input_file2.iter();
-
-        input1.chain(input2)
-    }
-
-    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
-        let output1 = self.output.iter();
-        let output2 = self.output2.iter();
-
-        output1.chain(output2)
-    }
-}
-
-pub fn xleave_process(args: XleaveArgs) -> Result<(), std::io::Error> {
-    args.validate_paths()?;
-
-    let readers = InputOptions::new_from_paths(&args.input_file1, args.input_file2.as_ref())
-        .use_file_or_zip()
-        .decode_in_thread()
-        .parse_fastx()
-        .open()?;
-
-    let writer = OutputOptions::new_from_opt_paths(args.output.as_ref(), args.output2.as_ref())
-        .use_file_zip_or_stdout()
-        .open()?;
-
-    let reader1 = readers.reader1;
-    let input_path1 = args.input_file1;
-
-    if let Some((reader2, input_path2)) = readers.reader2.zip(args.input_file2) {
-        let RecordWriters::SingleEnd(writer) = writer else {
-            return Err(std::io::Error::other(
-                "Two inputs and two outputs were provided. No interleaving or de-interleaving can occur.",
-            ));
-        };
-
-        match (reader1.dispatch(), reader2.dispatch()) {
-            (DispatchFastX::Fastq(reader1), DispatchFastX::Fastq(reader2)) => reader1
-                .zip_paired_reads(reader2)
-                .map(|res| res.map_err(|e| e.add_path_context(&input_path1, &input_path2)))
-                .write_records(writer)?,
-            (DispatchFastX::Fasta(reader1), DispatchFastX::Fasta(reader2)) => reader1
-                .zip_paired_reads(reader2)
-                .map(|res| res.map_err(|e| e.add_path_context(&input_path1, &input_path2)))
-                .write_records(writer)?,
-            (DispatchFastX::Fastq(_), DispatchFastX::Fasta(_)) => {
-                return Err(std::io::Error::other(
-                    "Paired read inputs must be both FASTQ or both FASTA. Found FASTQ for first input and FASTA for second input.",
-                ));
-            }
-            (DispatchFastX::Fasta(_), DispatchFastX::Fastq(_)) => {
-                return Err(std::io::Error::other(
-                    "Paired read inputs must be both FASTQ or both FASTA. Found FASTA for first input and FASTQ for second input.",
-                ));
-            }
-        }
-    } else {
-        let RecordWriters::PairedEnd(writer) = writer else {
-            return Err(std::io::Error::other(
-                "One input and one output were provided. No interleaving or de-interleaving can occur.",
-            ));
-        };
-
-        match reader1.dispatch() {
-            DispatchFastX::Fastq(reader) => reader
-                .deinterleave()
-                .map(|res| res.map_err(|e| e.add_path_context(&input_path1)))
-                .write_records(writer)?,
-            DispatchFastX::Fasta(reader) => reader
-                .deinterleave()
-                .map(|res| res.map_err(|e| e.add_path_context(&input_path1)))
-                .write_records(writer)?,
-        }
-    }
-
-    Ok(())
-}
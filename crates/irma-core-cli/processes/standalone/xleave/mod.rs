@@ -0,0 +1,409 @@
+//! Interleaves or de-interleaves paired FastQ or FASTA files.
+
+mod raw;
+
+use crate::shared::{output_buffering::OutputBufferingArgs, paired_paths::infer_r2_path, tee_input::TeeInputArgs};
+use clap::Args;
+#[cfg(feature = "async-io")]
+use irma_records::io::run_with_async_io;
+use irma_records::{
+    fastq::fix_header_sides,
+    io::{
+        DispatchFastX, FastXReader, InputOptions, IterWithContext, OutputOptions, RecordReaders, RecordWriters,
+        ValidatePaths, WriteRecords,
+    },
+    paired::{DeinterleavedPairedReadsExt, ZipPairedReadsExt, get_molecular_id_side},
+};
+use raw::{RawChecks, deinterleave_raw, interleave_raw};
+use std::{
+    io::{BufRead, Read, Write},
+    path::{Path, PathBuf},
+};
+use zoe::{
+    data::{fasta::FastaSeq, records::HeaderReadable},
+    prelude::FastQ,
+};
+
+#[derive(Args, Debug)]
+pub struct XleaveArgs {
+    /// Path to FASTQ, FASTA, or .gz file to be interleaved/deinterleaved
+    pub input_file1: PathBuf,
+
+    /// Path to optional second FASTQ, FASTA, or .gz file to be deinterleaved
+    pub input_file2: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "input_file2")]
+    /// Infers the second input from the first input's file name (`_R1_`,
+    /// `_R1.`, or `_1.` becomes `_R2_`, `_R2.`, or `_2.`), checking that it
+    /// exists and that the first records of both files are paired
+    pub auto_r2: bool,
+
+    #[arg(short = '1', long, short_alias = 'o', aliases = ["output-file", "output-file1", "output1"], conflicts_with = "count_only")]
+    /// Output file path for interleaved/deinterleaved reads
+    pub output: Option<PathBuf>,
+
+    #[arg(
+        short = '2',
+        long,
+        requires = "output",
+        alias = "output-file2",
+        conflicts_with = "count_only"
+    )]
+    /// Output path for a second sampled file if deinterleaving paired-end
+    /// reads. If this argument is omitted, output is interleaved
+    pub output2: Option<PathBuf>,
+
+    #[arg(long, value_name = "N")]
+    /// Fails if the number of records written (or counted, with
+    /// `--count-only`) is not N. Each read of a pair counts as one record
+    pub expect_records: Option<usize>,
+
+    #[arg(long, conflicts_with = "count_only")]
+    /// Fails unless the headers of every pair have the explicit read sides 1
+    /// and 2, in that order, in addition to matching molecular IDs
+    pub paired_strict: bool,
+
+    #[arg(long, conflicts_with_all = ["count_only", "raw"])]
+    /// Makes the headers of each pair agree with their read sides, inserting
+    /// the side into SRA IDs lacking one and rewriting the side of Illumina
+    /// comments (such as the `1` in `1:N:0:ACGT`)
+    pub fix_sides: bool,
+
+    #[arg(long)]
+    /// Prints the number of records in each input to stdout, without
+    /// interleaving or de-interleaving
+    pub count_only: bool,
+
+    #[arg(long, conflicts_with = "count_only")]
+    /// Copies the bytes of each record directly from the inputs to the outputs,
+    /// only locating the record boundaries, which is faster for large files.
+    /// FASTQ records must be four lines. The IDs of each pair are not checked
+    /// unless `--check-ids` is also given
+    pub raw: bool,
+
+    #[arg(long, requires = "raw")]
+    /// With `--raw`, checks that the IDs of each pair match, as is always done
+    /// otherwise
+    pub check_ids: bool,
+
+    #[command(flatten)]
+    pub buffering: OutputBufferingArgs,
+
+    #[command(flatten)]
+    pub tee: TeeInputArgs,
+
+    #[cfg(feature = "async-io")]
+    #[arg(long, conflicts_with = "raw")]
+    /// Read and write files with an async (tokio) runtime, which may help when
+    /// IO is slow, such as on network file systems
+    pub async_io: bool,
+}
+
+impl ValidatePaths for XleaveArgs {
+    fn inputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        let input1 = std::iter::once(&self.input_file1);
+        let input2 = self.input_file2.iter();
+
+        input1.chain(input2)
+    }
+
+    fn outputs(&self) -> impl IntoIterator<Item = &PathBuf> {
+        let output1 = self.output.iter();
+        let output2 = self.output2.iter();
+
+        output1.chain(output2).chain(self.tee.outputs())
+    }
+}
+
+pub fn xleave_process(mut args: XleaveArgs) -> Result<(), std::io::Error> {
+    if args.auto_r2 {
+        args.input_file2 = Some(infer_r2_path(&args.input_file1)?);
+    }
+    args.tee.check_paired(args.input_file2.is_some(), "xleave");
+    args.validate_paths()?;
+
+    let checks = XleaveChecks {
+        expect_records: args.expect_records,
+        paired_strict:  args.paired_strict,
+    };
+
+    let (tee_path1, tee_path2) = args.tee.paths();
+
+    if args.count_only {
+        let readers = InputOptions::new_from_paths(&args.input_file1, args.input_file2.as_ref())
+            .use_file_or_zip()
+            .decode_in_thread()
+            .tee_to(tee_path1, tee_path2)
+            .parse_fastx()
+            .open()?;
+
+        return count_records(readers, &args.input_file1, args.input_file2.as_deref(), checks);
+    }
+
+    #[cfg(feature = "async-io")]
+    args.buffering.check_async_io(args.async_io, "xleave");
+
+    #[cfg(feature = "async-io")]
+    if args.async_io {
+        return run_with_async_io(move || {
+            let (tee_path1, tee_path2) = args.tee.paths();
+            let readers = InputOptions::new_from_paths(&args.input_file1, args.input_file2.as_ref())
+                .use_file_or_zip_async()
+                .tee_to(tee_path1, tee_path2)
+                .parse_fastx()
+                .open()?;
+
+            let writer = OutputOptions::new_from_opt_paths(args.output.as_ref(), args.output2.as_ref())
+                .use_file_zip_or_stdout_async()
+                .open()?;
+
+            xleave_records(readers, writer, args.input_file1, args.input_file2, checks, args.fix_sides)
+        });
+    }
+
+    let writer = OutputOptions::new_from_opt_paths(args.output.as_ref(), args.output2.as_ref())
+        .with_buffering(args.buffering.buffering())
+        .use_file_zip_or_stdout()
+        .open()?;
+
+    let readers = InputOptions::new_from_paths(&args.input_file1, args.input_file2.as_ref())
+        .use_file_or_zip()
+        .decode_in_thread()
+        .tee_to(tee_path1, tee_path2);
+
+    if args.raw {
+        let checks = RawChecks {
+            checks,
+            check_ids: args.check_ids,
+        };
+        return xleave_raw(
+            readers.open()?,
+            writer,
+            &args.input_file1,
+            args.input_file2.as_deref(),
+            checks,
+        );
+    }
+
+    xleave_records(
+        readers.parse_fastx().open()?,
+        writer,
+        args.input_file1,
+        args.input_file2,
+        checks,
+        args.fix_sides,
+    )
+}
+
+/// The assertions made by `xleave`, which cause it to fail if they do not hold.
+#[derive(Copy, Clone, Debug)]
+struct XleaveChecks {
+    /// The expected number of records, if `--expect-records` was provided
+    expect_records: Option<usize>,
+    /// Whether `--paired-strict` was provided
+    paired_strict:  bool,
+}
+
+impl XleaveChecks {
+    /// Checks the number of records written or counted against
+    /// `--expect-records`, where `action` describes what happened to them.
+    fn check_count(self, count: usize, action: &str) -> std::io::Result<()> {
+        match self.expect_records {
+            Some(expected) if expected != count => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Expected {expected} records, but {count} records were {action}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Prints the number of records in each input for `--count-only`, as a
+/// tab-delimited path and count per line, and checks the total against
+/// `--expect-records`.
+fn count_records<R: Read>(
+    readers: RecordReaders<IterWithContext<FastXReader<R>>>, input_path1: &Path, input_path2: Option<&Path>,
+    checks: XleaveChecks,
+) -> std::io::Result<()> {
+    let mut stdout = OutputOptions::new_stdout().open()?;
+    let mut total = 0;
+
+    let inputs = std::iter::once((input_path1, readers.reader1)).chain(input_path2.zip(readers.reader2));
+    for (path, mut reader) in inputs {
+        let count = reader.try_fold(0, |count, record| record.map(|_| count + 1))?;
+        writeln!(stdout, "{path}\t{count}", path = path.display())?;
+        total += count;
+    }
+
+    stdout.flush()?;
+    checks.check_count(total, "counted")
+}
+
+/// Applies `--paired-strict` to each pair and counts the records that pass, so
+/// the count is available once `pairs` has been written. With `fix_sides`, the
+/// headers of each pair are then rewritten for `--fix-sides`.
+fn check_pairs<'a, A: HeaderReadable + HeaderMut>(
+    pairs: impl Iterator<Item = std::io::Result<[A; 2]>> + 'a, checks: XleaveChecks, fix_sides: bool, count: &'a mut usize,
+) -> impl Iterator<Item = std::io::Result<[A; 2]>> + 'a {
+    pairs.map(move |pair| {
+        let mut pair = pair?;
+        if checks.paired_strict {
+            check_strict_sides(&pair)?;
+        }
+        if fix_sides {
+            for (read, side) in pair.iter_mut().zip(['1', '2']) {
+                fix_header_sides(read.header_mut(), side);
+            }
+        }
+        *count += 2;
+        Ok(pair)
+    })
+}
+
+/// Records whose headers can be rewritten, for `--fix-sides`.
+trait HeaderMut {
+    fn header_mut(&mut self) -> &mut String;
+}
+
+impl HeaderMut for FastQ {
+    #[inline]
+    fn header_mut(&mut self) -> &mut String {
+        &mut self.header
+    }
+}
+
+impl HeaderMut for FastaSeq {
+    #[inline]
+    fn header_mut(&mut self) -> &mut String {
+        &mut self.name
+    }
+}
+
+/// Checks that the headers of a pair have the explicit read sides `1` and `2`,
+/// in that order.
+fn check_strict_sides<A: HeaderReadable>([read1, read2]: &[A; 2]) -> std::io::Result<()> {
+    let side = |read: &A| get_molecular_id_side(read.header(), '0').map(|(_, side)| side);
+
+    if side(read1) == Some('1') && side(read2) == Some('2') {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "With --paired-strict, each pair must have the read sides 1 and 2 in order\nHeader 1: {header1}\nHeader 2: {header2}",
+                header1 = read1.header(),
+                header2 = read2.header()
+            ),
+        ))
+    }
+}
+
+/// Interleaves the records of two readers, or de-interleaves the records of a
+/// single reader, depending on the number of readers and writers. The `checks`
+/// are applied as the records are written, and the headers are rewritten for
+/// `--fix-sides` if `fix_sides` is set.
+fn xleave_records<R, W>(
+    readers: RecordReaders<IterWithContext<FastXReader<R>>>, writer: RecordWriters<W>, input_path1: PathBuf,
+    input_path2: Option<PathBuf>, checks: XleaveChecks, fix_sides: bool,
+) -> std::io::Result<()>
+where
+    R: Read,
+    W: Write, {
+    let reader1 = readers.reader1;
+    let mut count = 0;
+
+    if let Some((reader2, input_path2)) = readers.reader2.zip(input_path2) {
+        let RecordWriters::SingleEnd(writer) = writer else {
+            return Err(std::io::Error::other(
+                "Two inputs and two outputs were provided. No interleaving or de-interleaving can occur.",
+            ));
+        };
+
+        match (reader1.dispatch(), reader2.dispatch()) {
+            (DispatchFastX::Fastq(reader1), DispatchFastX::Fastq(reader2)) => check_pairs(
+                reader1
+                    .zip_paired_reads(reader2)
+                    .map(|res| res.map_err(|e| e.add_path_context(&input_path1, &input_path2))),
+                checks,
+                fix_sides,
+                &mut count,
+            )
+            .write_records(writer)?,
+            (DispatchFastX::Fasta(reader1), DispatchFastX::Fasta(reader2)) => check_pairs(
+                reader1
+                    .zip_paired_reads(reader2)
+                    .map(|res| res.map_err(|e| e.add_path_context(&input_path1, &input_path2))),
+                checks,
+                fix_sides,
+                &mut count,
+            )
+            .write_records(writer)?,
+            (DispatchFastX::Fastq(_), DispatchFastX::Fasta(_)) => return Err(mixed_formats_error("FASTQ", "FASTA")),
+            (DispatchFastX::Fasta(_), DispatchFastX::Fastq(_)) => return Err(mixed_formats_error("FASTA", "FASTQ")),
+        }
+    } else {
+        let RecordWriters::PairedEnd(writer) = writer else {
+            return Err(std::io::Error::other(
+                "One input and one output were provided. No interleaving or de-interleaving can occur.",
+            ));
+        };
+
+        match reader1.dispatch() {
+            DispatchFastX::Fastq(reader) => check_pairs(
+                reader
+                    .deinterleave()
+                    .map(|res| res.map_err(|e| e.add_path_context(&input_path1))),
+                checks,
+                fix_sides,
+                &mut count,
+            )
+            .write_records(writer)?,
+            DispatchFastX::Fasta(reader) => check_pairs(
+                reader
+                    .deinterleave()
+                    .map(|res| res.map_err(|e| e.add_path_context(&input_path1))),
+                checks,
+                fix_sides,
+                &mut count,
+            )
+            .write_records(writer)?,
+        }
+    }
+
+    checks.check_count(count, "written")
+}
+
+/// Interleaves or de-interleaves the raw records of the readers for `--raw`, in
+/// the same way as [`xleave_records`].
+fn xleave_raw<R, W>(
+    readers: RecordReaders<R>, writer: RecordWriters<W>, input_path1: &Path, input_path2: Option<&Path>, checks: RawChecks,
+) -> std::io::Result<()>
+where
+    R: BufRead,
+    W: Write, {
+    let count = match (readers.reader2.zip(input_path2), writer) {
+        (Some((reader2, input_path2)), RecordWriters::SingleEnd(writer)) => {
+            interleave_raw(readers.reader1, reader2, writer, input_path1, input_path2, checks)?
+        }
+        (None, RecordWriters::PairedEnd(writers)) => deinterleave_raw(readers.reader1, writers, input_path1, checks)?,
+        (Some(_), RecordWriters::PairedEnd(_)) => {
+            return Err(std::io::Error::other(
+                "Two inputs and two outputs were provided. No interleaving or de-interleaving can occur.",
+            ));
+        }
+        (None, RecordWriters::SingleEnd(_)) => {
+            return Err(std::io::Error::other(
+                "One input and one output were provided. No interleaving or de-interleaving can occur.",
+            ));
+        }
+    };
+
+    checks.checks.check_count(count, "written")
+}
+
+/// Creates an error for paired inputs of different formats.
+fn mixed_formats_error(format1: &str, format2: &str) -> std::io::Error {
+    std::io::Error::other(format!(
+        "Paired read inputs must be both FASTQ or both FASTA. Found {format1} for first input and {format2} for second input."
+    ))
+}
@@ -0,0 +1,306 @@
+//! The `--raw` pass-through for `xleave`, which copies the bytes of each record
+//! from the inputs to the outputs without parsing them into owned records.
+
+use super::{XleaveChecks, check_strict_sides, mixed_formats_error};
+use irma_records::{
+    io::PairedWriters,
+    paired::{DeinterleaveError, PairedHeaderError, ZipPairedReadsError, check_paired_headers},
+};
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+};
+use zoe::data::{
+    err::{ResultWithErrorContext, WithErrorContext},
+    records::HeaderReadable,
+};
+
+/// The format of a raw input, determined by the first byte of its first
+/// record.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum RawFormat {
+    Fastq,
+    Fasta,
+}
+
+impl RawFormat {
+    /// The name of the format, for error messages.
+    fn name(self) -> &'static str {
+        match self {
+            RawFormat::Fastq => "FASTQ",
+            RawFormat::Fasta => "FASTA",
+        }
+    }
+}
+
+/// The bytes of a single record, including its newlines, along with where its
+/// header line ends.
+#[derive(Default, Debug)]
+struct RawRecord {
+    bytes:      Vec<u8>,
+    /// The length of the header line, including the `@` or `>` but excluding
+    /// the line ending
+    header_len: usize,
+}
+
+impl RawRecord {
+    /// The header of the record, without the `@` or `>`.
+    ///
+    /// ## Errors
+    ///
+    /// The header must be valid UTF-8.
+    fn header(&self) -> std::io::Result<RawHeader<&str>> {
+        std::str::from_utf8(&self.bytes[1..self.header_len])
+            .map(RawHeader)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "The header is not valid UTF-8"))
+    }
+}
+
+/// The header of a [`RawRecord`], so that the paired header checks and their
+/// errors can be reused. It is borrowed while checking and owned in errors.
+#[derive(Debug)]
+struct RawHeader<S = String>(S);
+
+impl<S: AsRef<str>> HeaderReadable for RawHeader<S> {
+    #[inline]
+    fn header(&self) -> &str {
+        self.0.as_ref()
+    }
+}
+
+impl RawHeader<&str> {
+    /// Copies the header, so that it can be returned in an error.
+    fn to_owned(&self) -> RawHeader {
+        RawHeader(self.0.to_string())
+    }
+}
+
+impl RawRecord {
+    /// The header of the record for an error, which is empty if it is not
+    /// valid UTF-8.
+    fn error_header(&self) -> RawHeader {
+        self.header()
+            .map_or_else(|_| RawHeader(String::new()), |header| header.to_owned())
+    }
+}
+
+/// Reads whole records as bytes, only locating the boundaries between them:
+/// four lines for FASTQ, or a `>` line and the lines up to the next for FASTA.
+/// Blank lines between records are skipped, and a missing final newline is
+/// added.
+struct RawRecordReader<'a, R> {
+    reader: R,
+    format: Option<RawFormat>,
+    /// The path of the input, for error context
+    path:   &'a Path,
+}
+
+impl<'a, R: BufRead> RawRecordReader<'a, R> {
+    fn new(reader: R, path: &'a Path) -> Self {
+        RawRecordReader {
+            reader,
+            format: None,
+            path,
+        }
+    }
+
+    /// Reads the next record into `record`, returning `false` once the input is
+    /// exhausted.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors are propagated. An error is returned if a record does not
+    /// start with `@` or `>` (or switches between them), or if a FASTQ record
+    /// is truncated or its third line does not start with `+`.
+    fn read_record(&mut self, record: &mut RawRecord) -> std::io::Result<bool> {
+        record.bytes.clear();
+
+        let marker = loop {
+            match self.reader.fill_buf()?.first() {
+                None => return Ok(false),
+                Some(b'\n' | b'\r') => self.reader.consume(1),
+                Some(&marker) => break marker,
+            }
+        };
+
+        let format = match (self.format, marker) {
+            (None | Some(RawFormat::Fastq), b'@') => RawFormat::Fastq,
+            (None | Some(RawFormat::Fasta), b'>') => RawFormat::Fasta,
+            _ => {
+                return Err(self.invalid(format!(
+                    "A record starts with '{}' rather than '@' or '>' as expected",
+                    marker.escape_ascii()
+                )));
+            }
+        };
+        self.format = Some(format);
+
+        self.read_line(&mut record.bytes)?;
+        record.header_len = record.bytes.trim_ascii_end().len();
+
+        match format {
+            RawFormat::Fastq => {
+                for line in 1..4 {
+                    let start = record.bytes.len();
+                    if self.read_line(&mut record.bytes)? == 0 {
+                        return Err(self.invalid("A FASTQ record is truncated".to_string()));
+                    }
+                    if line == 2 && record.bytes[start] != b'+' {
+                        return Err(self.invalid(
+                            "The third line of a FASTQ record does not start with '+'. Only four-line FASTQ is supported"
+                                .to_string(),
+                        ));
+                    }
+                }
+            }
+            RawFormat::Fasta => {
+                while self.reader.fill_buf()?.first().is_some_and(|&b| b != b'>') {
+                    self.read_line(&mut record.bytes)?;
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Appends a line to `bytes`, adding a newline if it is missing. The number
+    /// of bytes read is returned.
+    fn read_line(&mut self, bytes: &mut Vec<u8>) -> std::io::Result<usize> {
+        let n = self.reader.read_until(b'\n', bytes)?;
+        if n > 0 && !bytes.ends_with(b"\n") {
+            bytes.push(b'\n');
+        }
+        Ok(n)
+    }
+
+    /// Creates an error for an invalid record, with the path as context.
+    fn invalid(&self, msg: String) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+            .with_path_context("Invalid record in file", self.path)
+            .into()
+    }
+}
+
+/// Whether the headers of each pair are checked, for `--check-ids` or
+/// `--paired-strict`.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct RawChecks {
+    pub(super) checks:    XleaveChecks,
+    pub(super) check_ids: bool,
+}
+
+impl RawChecks {
+    /// Checks the headers of a pair read from `paths`, if requested. A
+    /// mismatch is reported with `mismatch`, which adds the path context.
+    fn check_pair(
+        self, [record1, record2]: [&RawRecord; 2], [path1, path2]: [&Path; 2],
+        mismatch: impl FnOnce([RawHeader; 2], PairedHeaderError) -> std::io::Error,
+    ) -> std::io::Result<()> {
+        if !self.check_ids && !self.checks.paired_strict {
+            return Ok(());
+        }
+
+        fn header<'a>(record: &'a RawRecord, path: &Path) -> std::io::Result<RawHeader<&'a str>> {
+            Ok(record.header().with_path_context("Invalid header in file", path)?)
+        }
+        let headers = [header(record1, path1)?, header(record2, path2)?];
+
+        if self.check_ids
+            && let Err(source) = check_paired_headers(&headers[0], &headers[1])
+        {
+            return Err(mismatch(headers.each_ref().map(RawHeader::to_owned), source));
+        }
+        if self.checks.paired_strict {
+            check_strict_sides(&headers)?;
+        }
+        Ok(())
+    }
+}
+
+/// Interleaves two inputs by copying their records alternately to `writer`,
+/// returning the number of records written.
+///
+/// ## Errors
+///
+/// IO errors are propagated. An error is returned if the inputs have different
+/// formats or numbers of records, or if the headers of a pair fail the
+/// `checks`.
+pub(super) fn interleave_raw<R: BufRead, W: Write>(
+    reader1: R, reader2: R, mut writer: W, path1: &Path, path2: &Path, checks: RawChecks,
+) -> std::io::Result<usize> {
+    let mut reader1 = RawRecordReader::new(reader1, path1);
+    let mut reader2 = RawRecordReader::new(reader2, path2);
+    let (mut record1, mut record2) = (RawRecord::default(), RawRecord::default());
+    let mut count = 0;
+
+    loop {
+        match (reader1.read_record(&mut record1)?, reader2.read_record(&mut record2)?) {
+            (true, true) => {}
+            (true, false) => {
+                let header = record1.error_header();
+                return Err(ZipPairedReadsError::ExtraFirstRead(header).add_path_context(path1, path2));
+            }
+            (false, true) => {
+                let header = record2.error_header();
+                return Err(ZipPairedReadsError::ExtraSecondRead(header).add_path_context(path1, path2));
+            }
+            (false, false) => break,
+        }
+
+        if count == 0
+            && let (Some(format1), Some(format2)) = (reader1.format, reader2.format)
+            && format1 != format2
+        {
+            return Err(mixed_formats_error(format1.name(), format2.name()));
+        }
+
+        checks.check_pair([&record1, &record2], [path1, path2], |records, source| {
+            ZipPairedReadsError::BadHeaders { records, source }.add_path_context(path1, path2)
+        })?;
+
+        writer.write_all(&record1.bytes)?;
+        writer.write_all(&record2.bytes)?;
+        count += 2;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+/// De-interleaves an input by copying its records alternately to the two
+/// `writers`, returning the number of records written.
+///
+/// ## Errors
+///
+/// IO errors are propagated. An error is returned if the input has an odd
+/// number of records, or if the headers of a pair fail the `checks`.
+pub(super) fn deinterleave_raw<R: BufRead, W: Write>(
+    reader: R, writers: PairedWriters<W>, path: &Path, checks: RawChecks,
+) -> std::io::Result<usize> {
+    let PairedWriters {
+        mut writer1,
+        mut writer2,
+    } = writers;
+    let mut reader = RawRecordReader::new(reader, path);
+    let (mut record1, mut record2) = (RawRecord::default(), RawRecord::default());
+    let mut count = 0;
+
+    while reader.read_record(&mut record1)? {
+        if !reader.read_record(&mut record2)? {
+            let header = record1.error_header();
+            return Err(DeinterleaveError::OddNumberOfReads(header).add_path_context(path));
+        }
+
+        checks.check_pair([&record1, &record2], [path, path], |records, source| {
+            DeinterleaveError::BadHeaders { records, source }.add_path_context(path)
+        })?;
+
+        writer1.write_all(&record1.bytes)?;
+        writer2.write_all(&record2.bytes)?;
+        count += 2;
+    }
+
+    writer1.flush()?;
+    writer2.flush()?;
+    Ok(count)
+}
@@ -1,7 +1,7 @@
 use crate::fastq::ReadTransforms;
 use zoe::{
     data::{fastq::FastQ, types::phred::QualityScores},
-    prelude::Nucleotides,
+    prelude::{Len, Nucleotides},
 };
 
 static QNAMES: [&str; 26] = [
@@ -84,3 +84,57 @@ fn test_fix_sra_names() {
         assert_eq!(fq.header, o);
     }
 }
+
+#[test]
+fn test_process_adapter_max_mismatch() {
+    // The adapter is searched for as the 3' (reverse) adapter
+    let adapter = b"CTGTCTCTTATACACATCT";
+    let forward = Nucleotides::from(adapter.as_slice()).to_reverse_complement();
+    // The adapter with two mismatches, followed by three bases
+    let read = b"ACGTACGTACGTACGTACGTCTGACTCTTATACTCATCTAAA";
+
+    for (max_mismatch, expected) in [(0, 42), (1, 42), (2, 20), (3, 20)] {
+        let mut fq = FastQ {
+            header:   "read".to_string(),
+            sequence: Nucleotides::from(read.as_slice()),
+            quality:  QualityScores::try_from(vec![b'I'; read.len()]).unwrap(),
+        };
+        fq.process_adapter(adapter, forward.as_bytes(), max_mismatch, false);
+        assert_eq!(fq.sequence.len(), expected, "max_mismatch = {max_mismatch}");
+        assert_eq!(fq.quality.len(), expected);
+    }
+}
+
+#[test]
+fn test_process_barcode_quality_aware() {
+    let barcode = b"ACGTTGCA";
+    let reverse = Nucleotides::from(barcode.as_slice()).to_reverse_complement();
+    // The barcode with two mismatches (at positions 1 and 4), followed by
+    // eight bases
+    let read = b"AGGTAGCACCCCCCCC";
+
+    // Only the first mismatch is at a low-quality base
+    let mut quality = vec![b'I'; read.len()];
+    quality[1] = b'#';
+
+    for (hdist, forgive_below, expected) in [
+        (1, None, 16),
+        (1, Some(2), 16),
+        (1, Some(20), 8),
+        (0, Some(20), 16),
+        (2, None, 8),
+    ] {
+        let mut fq = FastQ {
+            header:   "read".to_string(),
+            sequence: Nucleotides::from(read.as_slice()),
+            quality:  QualityScores::try_from(quality.clone()).unwrap(),
+        };
+        fq.process_barcode(barcode, reverse.as_bytes(), hdist, forgive_below, false, Some(10), Some(0));
+        assert_eq!(
+            fq.sequence.len(),
+            expected,
+            "hdist = {hdist}, forgive_below = {forgive_below:?}"
+        );
+        assert_eq!(fq.quality.len(), expected);
+    }
+}
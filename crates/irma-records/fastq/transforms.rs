@@ -1,6 +1,8 @@
 //! Code supporting the [`ReadTransforms`] trait for editing FASTQ reads.
 
+use crate::paired::set_comment_side;
 use foldhash::fast::SeedableRandomState;
+use std::ops::Range;
 use zoe::{
     data::fastq::FastQ,
     kmer::encoders::three_bit::ThreeBitKmerSet,
@@ -31,6 +33,55 @@ pub(crate) fn fix_sra_format(header: &mut String, read_side: char) {
     }
 }
 
+/// Makes the read side of a header consistent with `read_side`. SRA IDs
+/// lacking a read side have it inserted, as with
+/// [`ReadTransforms::fix_header`], and the side of an Illumina comment is
+/// rewritten with [`set_comment_side`].
+pub fn fix_header_sides(header: &mut String, read_side: char) {
+    fix_sra_format(header, read_side);
+    set_comment_side(header, read_side);
+}
+
+/// The window searched for a barcode on the left end of a read of length
+/// `len`, which is the first `restrict` bases if provided.
+#[inline]
+fn left_window(len: usize, restrict: Option<usize>) -> Range<usize> {
+    0..restrict.map_or(len, |restrict| restrict.min(len))
+}
+
+/// The window searched for a barcode on the right end of a read of length
+/// `len`, which is the last `restrict` bases if provided.
+#[inline]
+fn right_window(len: usize, restrict: Option<usize>) -> Range<usize> {
+    len - restrict.map_or(len, |restrict| restrict.min(len))..len
+}
+
+/// Finds the first occurrence of `needle` within the `window` of `sequence`,
+/// allowing up to `hdist` mismatches at bases whose quality score is at least
+/// `forgive_below`. Mismatches at bases with lower quality scores are
+/// forgiven. The returned range is relative to the start of `sequence`.
+///
+/// The quality scores are expected to be Phred+33 encoded, with the same
+/// length as `sequence`.
+fn find_quality_aware(
+    sequence: &[u8], quality: &[u8], needle: &[u8], hdist: usize, forgive_below: u8, window: Range<usize>,
+) -> Option<Range<usize>> {
+    if needle.is_empty() || window.len() < needle.len() {
+        return None;
+    }
+
+    (window.start..=window.end - needle.len()).find_map(|start| {
+        let end = start + needle.len();
+        let mismatches = sequence[start..end]
+            .iter()
+            .zip(&quality[start..end])
+            .zip(needle)
+            .filter(|&((base, &q), expected)| base != expected && q.saturating_sub(33) >= forgive_below)
+            .count();
+        (mismatches <= hdist).then_some(start..end)
+    })
+}
+
 /// An extension trait for FASTQ data supporting editing of the reads. This
 /// includes hard clipping, masking, adapter or barcode removal, primer
 /// handling, poly-G cleanup, canonical base recoding, and read-quality
@@ -131,15 +182,20 @@ pub trait ReadTransforms {
     /// number of mismatches (a given hamming distance). This must be 0, 1, 2,
     /// or 3.
     ///
+    /// If `forgive_below` is provided, mismatches at bases with a quality score
+    /// below it are forgiven, and only the remaining mismatches count towards
+    /// `hdist`.
+    ///
     /// A future version of this function may change the search order, if
     /// reverse string search algorithms get implemented.
     ///
     /// ## Panics
     ///
     /// `hdist` must be between 0 and 3.
+    #[allow(clippy::too_many_arguments)]
     fn process_barcode(
-        &mut self, barcode: &[u8], reverse: &[u8], hdist: usize, masking: bool, b_restrict_left: Option<usize>,
-        b_restrict_right: Option<usize>,
+        &mut self, barcode: &[u8], reverse: &[u8], hdist: usize, forgive_below: Option<u8>, masking: bool,
+        b_restrict_left: Option<usize>, b_restrict_right: Option<usize>,
     ) -> &mut Self;
 
     /// Trims tails of consecutive `G` that are at the exact beginning or end of
@@ -187,13 +243,17 @@ pub trait ReadTransforms {
     /// Both the forward and reverse adapters are given (corresponding to
     /// whether the read is on the forward or reverse strand). First, `reverse`
     /// is searched for from left to right and then the same with `forward`. If
-    /// nothing is found and `is_fuzzy` is true, the same is repeated but
-    /// allowing up to one mismatch.
+    /// nothing is found and `max_mismatch` is nonzero, the same is repeated but
+    /// allowing up to `max_mismatch` mismatches.
     ///
     /// The found region is masked if `masking` is true. Otherwise, if `reverse`
     /// is located, 3' trimming occurs, and if `forward` is located, 5' trimming
     /// occurs.
-    fn process_adapter(&mut self, reverse: &[u8], forward: &[u8], allow_fuzzy: bool, masking: bool) -> &mut Self;
+    ///
+    /// ## Panics
+    ///
+    /// `max_mismatch` must be between 0 and 3.
+    fn process_adapter(&mut self, reverse: &[u8], forward: &[u8], max_mismatch: usize, masking: bool) -> &mut Self;
 
     /// Computes the geometric mean or median of the quality scores.
     ///
@@ -282,9 +342,46 @@ impl ReadTransforms for FastQ {
 
     #[inline]
     fn process_barcode(
-        &mut self, barcode: &[u8], reverse: &[u8], hdist: usize, masking: bool, b_restrict_left: Option<usize>,
-        b_restrict_right: Option<usize>,
+        &mut self, barcode: &[u8], reverse: &[u8], hdist: usize, forgive_below: Option<u8>, masking: bool,
+        b_restrict_left: Option<usize>, b_restrict_right: Option<usize>,
     ) -> &mut Self {
+        if let Some(forgive_below) = forgive_below {
+            let left_barcode_pos = find_quality_aware(
+                self.sequence.as_bytes(),
+                self.quality.as_bytes(),
+                barcode,
+                hdist,
+                forgive_below,
+                left_window(self.sequence.len(), b_restrict_left),
+            );
+            if let Some(left_range) = left_barcode_pos {
+                if masking {
+                    self.sequence.mask_if_exists(left_range);
+                } else {
+                    self.sequence.cut_to_start(left_range.end);
+                    self.quality.cut_to_start(left_range.end);
+                }
+            }
+
+            let right_barcode_pos = find_quality_aware(
+                self.sequence.as_bytes(),
+                self.quality.as_bytes(),
+                reverse,
+                hdist,
+                forgive_below,
+                right_window(self.sequence.len(), b_restrict_right),
+            );
+            if let Some(right_range) = right_barcode_pos {
+                if masking {
+                    self.sequence.mask_if_exists(right_range);
+                } else {
+                    self.sequence.shorten_to(right_range.start);
+                    self.quality.shorten_to(right_range.start);
+                }
+            }
+            return self;
+        }
+
         let restricted_substring_fn = match hdist {
             0 => |needle: &[u8], seq: &RangeSearch<'_, Nucleotides>| seq.find_substring(needle),
             1 => |needle: &[u8], seq: &RangeSearch<'_, Nucleotides>| seq.find_fuzzy_substring::<1>(needle),
@@ -367,18 +464,22 @@ impl ReadTransforms for FastQ {
     }
 
     #[inline]
-    fn process_adapter(&mut self, reverse: &[u8], forward: &[u8], allow_fuzzy: bool, masking: bool) -> &mut Self {
+    fn process_adapter(&mut self, reverse: &[u8], forward: &[u8], max_mismatch: usize, masking: bool) -> &mut Self {
+        let fuzzy_substring_fn = match max_mismatch {
+            0 => |_: &[u8], _: &Nucleotides| -> Option<Range<usize>> { None },
+            1 => |needle: &[u8], seq: &Nucleotides| seq.find_fuzzy_substring::<1>(needle),
+            2 => |needle: &[u8], seq: &Nucleotides| seq.find_fuzzy_substring::<2>(needle),
+            3 => |needle: &[u8], seq: &Nucleotides| seq.find_fuzzy_substring::<3>(needle),
+            _ => panic!("The value of max_mismatch must be between 0 and 3. Found: {max_mismatch}"),
+        };
+
         if masking {
-            let mut range = self
+            let range = self
                 .sequence
                 .find_substring(reverse)
-                .or_else(|| self.sequence.find_substring(forward));
-
-            if allow_fuzzy {
-                range = range
-                    .or_else(|| self.sequence.find_fuzzy_substring::<1>(reverse))
-                    .or_else(|| self.sequence.find_fuzzy_substring::<1>(forward));
-            }
+                .or_else(|| self.sequence.find_substring(forward))
+                .or_else(|| fuzzy_substring_fn(reverse, &self.sequence))
+                .or_else(|| fuzzy_substring_fn(forward, &self.sequence));
 
             if let Some(r) = range {
                 self.sequence.mask_if_exists(r);
@@ -392,16 +493,14 @@ impl ReadTransforms for FastQ {
                 // Remove the 5' and clone back in
                 self.sequence.cut_to_start(r.end);
                 self.quality.cut_to_start(r.end);
-            } else if allow_fuzzy {
-                if let Some(r) = self.sequence.find_fuzzy_substring::<1>(reverse) {
-                    // Chop 3' end of sequence data
-                    self.sequence.shorten_to(r.start);
-                    self.quality.shorten_to(r.start);
-                } else if let Some(r) = self.sequence.find_fuzzy_substring::<1>(forward) {
-                    // Remove the 5' and clone back in
-                    self.sequence.cut_to_start(r.end);
-                    self.quality.cut_to_start(r.end);
-                }
+            } else if let Some(r) = fuzzy_substring_fn(reverse, &self.sequence) {
+                // Chop 3' end of sequence data
+                self.sequence.shorten_to(r.start);
+                self.quality.shorten_to(r.start);
+            } else if let Some(r) = fuzzy_substring_fn(forward, &self.sequence) {
+                // Remove the 5' and clone back in
+                self.sequence.cut_to_start(r.end);
+                self.quality.cut_to_start(r.end);
             }
         }
 
@@ -503,9 +602,42 @@ impl ReadTransforms for FastQViewMut<'_> {
 
     #[inline]
     fn process_barcode(
-        &mut self, barcode: &[u8], reverse: &[u8], hdist: usize, masking: bool, b_restrict_left: Option<usize>,
-        b_restrict_right: Option<usize>,
+        &mut self, barcode: &[u8], reverse: &[u8], hdist: usize, forgive_below: Option<u8>, masking: bool,
+        b_restrict_left: Option<usize>, b_restrict_right: Option<usize>,
     ) -> &mut Self {
+        if let Some(forgive_below) = forgive_below {
+            let left_barcode_pos = find_quality_aware(
+                self.sequence.as_bytes(),
+                self.quality.as_bytes(),
+                barcode,
+                hdist,
+                forgive_below,
+                left_window(self.sequence.len(), b_restrict_left),
+            );
+            if let Some(left_range) = left_barcode_pos {
+                if masking {
+                    self.sequence.mask_if_exists(left_range.clone());
+                }
+                self.restrict(left_range.end..);
+            }
+
+            let right_barcode_pos = find_quality_aware(
+                self.sequence.as_bytes(),
+                self.quality.as_bytes(),
+                reverse,
+                hdist,
+                forgive_below,
+                right_window(self.sequence.len(), b_restrict_right),
+            );
+            if let Some(right_range) = right_barcode_pos {
+                if masking {
+                    self.sequence.mask_if_exists(right_range.clone());
+                }
+                self.restrict(..right_range.start);
+            }
+            return self;
+        }
+
         let substring_fn = match hdist {
             0 => |needle: &[u8], seq: &NucleotidesViewMut<'_>| seq.find_substring(needle),
             1 => |needle: &[u8], seq: &NucleotidesViewMut<'_>| seq.find_fuzzy_substring::<1>(needle),
@@ -577,18 +709,22 @@ impl ReadTransforms for FastQViewMut<'_> {
     }
 
     #[inline]
-    fn process_adapter(&mut self, reverse: &[u8], forward: &[u8], allow_fuzzy: bool, masking: bool) -> &mut Self {
+    fn process_adapter(&mut self, reverse: &[u8], forward: &[u8], max_mismatch: usize, masking: bool) -> &mut Self {
+        let fuzzy_substring_fn = match max_mismatch {
+            0 => |_: &[u8], _: &NucleotidesViewMut<'_>| -> Option<Range<usize>> { None },
+            1 => |needle: &[u8], seq: &NucleotidesViewMut<'_>| seq.find_fuzzy_substring::<1>(needle),
+            2 => |needle: &[u8], seq: &NucleotidesViewMut<'_>| seq.find_fuzzy_substring::<2>(needle),
+            3 => |needle: &[u8], seq: &NucleotidesViewMut<'_>| seq.find_fuzzy_substring::<3>(needle),
+            _ => panic!("The value of max_mismatch must be between 0 and 3. Found: {max_mismatch}"),
+        };
+
         if masking {
-            let mut range = self
+            let range = self
                 .sequence
                 .find_substring(reverse)
-                .or_else(|| self.sequence.find_substring(forward));
-
-            if allow_fuzzy {
-                range = range
-                    .or_else(|| self.sequence.find_fuzzy_substring::<1>(reverse))
-                    .or_else(|| self.sequence.find_fuzzy_substring::<1>(forward));
-            }
+                .or_else(|| self.sequence.find_substring(forward))
+                .or_else(|| fuzzy_substring_fn(reverse, &self.sequence))
+                .or_else(|| fuzzy_substring_fn(forward, &self.sequence));
 
             if let Some(r) = range {
                 self.sequence.mask_if_exists(r);
@@ -600,14 +736,12 @@ impl ReadTransforms for FastQViewMut<'_> {
             } else if let Some(r) = self.sequence.find_substring(forward) {
                 // Remove the 5' end
                 self.restrict(r.end..);
-            } else if allow_fuzzy {
-                if let Some(r) = self.sequence.find_fuzzy_substring::<1>(reverse) {
-                    // Chop 3' end of sequence data
-                    self.restrict(..r.start);
-                } else if let Some(r) = self.sequence.find_fuzzy_substring::<1>(forward) {
-                    // Remove the 5' end
-                    self.restrict(r.end..);
-                }
+            } else if let Some(r) = fuzzy_substring_fn(reverse, &self.sequence) {
+                // Chop 3' end of sequence data
+                self.restrict(..r.start);
+            } else if let Some(r) = fuzzy_substring_fn(forward, &self.sequence) {
+                // Remove the 5' end
+                self.restrict(r.end..);
             }
         }
 
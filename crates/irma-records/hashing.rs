@@ -1,10 +1,38 @@
-use foldhash::{SharedSeed, fast::SeedableRandomState};
-use std::env;
+use foldhash::{SharedSeed, fast::SeedableRandomState, quality::FixedState};
+use std::{
+    env,
+    hash::BuildHasher,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 const SEED_ENV_VAR: &str = "IRMA_SEED";
 
-fn get_seed() -> Option<u64> {
-    env::var(SEED_ENV_VAR).ok().map(|s| s.bytes().fold(0, |a, b| a ^ b) as u64)
+/// Whether the seed is derived from `IRMA_SEED` by XOR-folding its bytes.
+static LEGACY_SEED_DERIVATION: AtomicBool = AtomicBool::new(false);
+
+/// Derives the seed from `IRMA_SEED` by XOR-folding its bytes for the rest of
+/// the process, as in versions before 0.10.1. This only has 256 possible seeds,
+/// and similar seed strings often collide, so it should only be used to
+/// reproduce earlier outputs.
+pub fn use_legacy_seed_derivation() {
+    LEGACY_SEED_DERIVATION.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether [`use_legacy_seed_derivation`] has been called.
+pub fn is_legacy_seed_derivation() -> bool {
+    LEGACY_SEED_DERIVATION.load(Ordering::Relaxed)
+}
+
+/// Returns the seed derived from the `IRMA_SEED` environment variable, or
+/// `None` if it is not set. The string is hashed with a fixed seed, unless
+/// [`use_legacy_seed_derivation`] was called.
+pub fn get_seed() -> Option<u64> {
+    let seed = env::var(SEED_ENV_VAR).ok()?;
+    if is_legacy_seed_derivation() {
+        Some(seed.bytes().fold(0, |a, b| a ^ b) as u64)
+    } else {
+        Some(FixedState::with_seed(0).hash_one(seed.as_bytes()))
+    }
 }
 
 pub fn get_hasher() -> SeedableRandomState {
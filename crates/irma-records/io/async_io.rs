@@ -0,0 +1,386 @@
+//! An optional IO backend driven by a [tokio](https://tokio.rs) runtime,
+//! enabled with the `async-io` feature.
+//!
+//! Files are read and written by asynchronous tasks, which exchange chunks of
+//! bytes with the synchronous [`AsyncFileReader`] and [`AsyncFileWriter`] over
+//! bounded channels. When a channel is full, the faster side waits for the
+//! slower one, so memory use stays bounded. Parsing and processing of records
+//! remain synchronous, running on tokio's blocking thread pool via
+//! [`run_with_async_io`].
+
+use crate::io::{
+    BgzfWriter, CompressionOptions, GzipFormat, GzipReaderInThread, WriterWithContext, WriterWithErrorContext, is_bgz, is_gz,
+};
+use flate2::write::GzEncoder;
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Stdout, Write, stdout},
+    path::Path,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Builder,
+    sync::{mpsc, oneshot},
+};
+use zoe::{data::err::ResultWithErrorContext, define_whichever};
+
+/// The number of bytes in each chunk sent between the tasks and the readers or
+/// writers.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of chunks that may be in flight for each file before the sending
+/// side waits.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// The number of worker threads driving the IO tasks. The processing itself
+/// runs on the blocking thread pool, so few are needed.
+const WORKER_THREADS: usize = 2;
+
+/// Runs `process` on the blocking thread pool of a new tokio runtime, so that
+/// the async readers and writers in this module can be opened and used within
+/// it.
+///
+/// ## Errors
+///
+/// Errors are returned if the runtime cannot be started, and any error from
+/// `process` is propagated. A panic in `process` is resumed on the calling
+/// thread.
+pub fn run_with_async_io<F, T>(process: F) -> std::io::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static, {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(WORKER_THREADS)
+        .enable_all()
+        .build()
+        .with_context("Failed to start the async IO runtime")?;
+
+    match runtime.block_on(runtime.spawn_blocking(process)) {
+        Ok(out) => out,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(std::io::Error::other(e)),
+    }
+}
+
+/// A reader for a file whose contents are read ahead by an asynchronous task.
+///
+/// The task stops once the file is read in full or an error occurs, or once
+/// this reader is dropped.
+///
+/// ## Panics
+///
+/// This must be created within the context of a tokio runtime, such as within
+/// [`run_with_async_io`], and must not be read from an asynchronous task.
+pub struct AsyncFileReader {
+    receiver: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk:    Vec<u8>,
+    position: usize,
+}
+
+impl AsyncFileReader {
+    /// Creates a new [`AsyncFileReader`] from an open file, spawning the task
+    /// that reads it.
+    pub fn new(file: File) -> Self {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut file = tokio::fs::File::from_std(file);
+
+        tokio::spawn(async move {
+            loop {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let message = match file.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(bytes_read) => {
+                        chunk.truncate(bytes_read);
+                        Ok(chunk)
+                    }
+                    Err(e) => Err(e),
+                };
+
+                let is_err = message.is_err();
+                // A closed channel means the reader was dropped
+                if sender.send(message).await.is_err() || is_err {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for AsyncFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.position == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(0),
+            }
+        }
+
+        let bytes_read = buf.len().min(self.chunk.len() - self.position);
+        buf[..bytes_read].copy_from_slice(&self.chunk[self.position..self.position + bytes_read]);
+        self.position += bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+/// A message sent from an [`AsyncFileWriter`] to its task.
+enum WriteMessage {
+    /// A chunk of bytes to write
+    Data(Vec<u8>),
+    /// A request to flush the file, acknowledged once all prior chunks are
+    /// written
+    Flush(oneshot::Sender<()>),
+}
+
+/// A writer for a file whose contents are written by an asynchronous task.
+///
+/// Writes are collected into chunks, so this does not need to be wrapped in a
+/// [`BufWriter`]. If the task fails, the error is returned by the next write or
+/// flush.
+///
+/// Dropping this waits for all pending chunks to be written, ignoring any
+/// errors, so [`flush`] should be called to observe them.
+///
+/// ## Panics
+///
+/// This must be created within the context of a tokio runtime, such as within
+/// [`run_with_async_io`], and must not be written to or dropped from an
+/// asynchronous task.
+///
+/// [`flush`]: Write::flush
+pub struct AsyncFileWriter {
+    sender: Option<mpsc::Sender<WriteMessage>>,
+    done:   Option<oneshot::Receiver<std::io::Result<()>>>,
+    chunk:  Vec<u8>,
+}
+
+impl AsyncFileWriter {
+    /// Creates a new [`AsyncFileWriter`] from an open file, spawning the task
+    /// that writes to it.
+    pub fn new(file: File) -> Self {
+        let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (done_sender, done) = oneshot::channel();
+        let mut file = tokio::fs::File::from_std(file);
+
+        tokio::spawn(async move {
+            let result = async {
+                while let Some(message) = receiver.recv().await {
+                    match message {
+                        WriteMessage::Data(chunk) => file.write_all(&chunk).await?,
+                        WriteMessage::Flush(ack) => {
+                            file.flush().await?;
+                            let _ = ack.send(());
+                        }
+                    }
+                }
+                file.flush().await
+            }
+            .await;
+
+            // On an error, the receiver is dropped here, so the writer sees a
+            // closed channel and retrieves the error from `done`
+            let _ = done_sender.send(result);
+        });
+
+        Self {
+            sender: Some(sender),
+            done:   Some(done),
+            chunk:  Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Sends a message to the task, retrieving the task's error if it has
+    /// stopped.
+    fn send(&mut self, message: WriteMessage) -> std::io::Result<()> {
+        match &self.sender {
+            Some(sender) if sender.blocking_send(message).is_ok() => Ok(()),
+            _ => Err(self.task_error()),
+        }
+    }
+
+    /// Sends the collected bytes to the task, if there are any.
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        self.send(WriteMessage::Data(chunk))
+    }
+
+    /// Waits for the stopped task and retrieves its error. Further writes
+    /// return a generic error.
+    fn task_error(&mut self) -> std::io::Error {
+        self.sender = None;
+        match self.done.take().map(oneshot::Receiver::blocking_recv) {
+            Some(Ok(Err(e))) => e,
+            _ => std::io::Error::other("The async file writer is no longer running"),
+        }
+    }
+}
+
+impl Write for AsyncFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_chunk()?;
+
+        let (ack, acknowledged) = oneshot::channel();
+        self.send(WriteMessage::Flush(ack))?;
+        acknowledged.blocking_recv().map_err(|_| self.task_error())
+    }
+}
+
+impl Drop for AsyncFileWriter {
+    fn drop(&mut self) {
+        let _ = self.send_chunk();
+        self.sender = None;
+        if let Some(done) = self.done.take() {
+            let _ = done.blocking_recv();
+        }
+    }
+}
+
+define_whichever! {
+    /// An enum for the different input types [`File`] and a gzip compressed
+    /// file, where the file is read by an asynchronous task.
+    ///
+    /// For the [`Zipped`] variant, decoding is performed eagerly on a separate
+    /// thread, as with [`ReadFileZipInThread`].
+    ///
+    /// [`Zipped`]: ReadFileZipAsync::Zipped
+    /// [`ReadFileZipInThread`]: crate::io::ReadFileZipInThread
+    pub enum ReadFileZipAsync {
+        /// A regular uncompressed file.
+        File(AsyncFileReader),
+        /// A gzip compressed file, using eager decoding on a separate thread.
+        Zipped(GzipReaderInThread),
+    }
+
+    impl Read for ReadFileZipAsync {}
+}
+
+impl ReadFileZipAsync {
+    /// Opens a [`ReadFileZipAsync`] from a path.
+    ///
+    /// The file is determined to be zipped if it ends in `.gz`.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors when opening the file or forming the pipe are propagated.
+    ///
+    /// ## Panics
+    ///
+    /// This must be called within the context of a tokio runtime. See
+    /// [`AsyncFileReader`].
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let reader = AsyncFileReader::new(File::open(&path)?);
+
+        if is_gz(&path) {
+            Ok(ReadFileZipAsync::Zipped(GzipReaderInThread::from_readable(reader)?))
+        } else {
+            Ok(ReadFileZipAsync::File(reader))
+        }
+    }
+}
+
+define_whichever! {
+    /// An enum for the different acceptable output types, where files are
+    /// written by an asynchronous task. All variants are wrapped in
+    /// [`WriterWithContext`] to add context to write errors.
+    ///
+    /// Stdout is written synchronously, as in [`WriteFileZipStdout`].
+    ///
+    /// [`WriteFileZipStdout`]: crate::io::WriteFileZipStdout
+    pub enum WriteFileZipStdoutAsync {
+        /// A writer for a regular uncompressed file.
+        File(WriterWithContext<AsyncFileWriter>),
+        /// A writer for a gzip compressed file, where compression is performed
+        /// by the caller.
+        Zipped(WriterWithContext<GzEncoder<AsyncFileWriter>>),
+        /// A writer for a BGZF (blocked gzip) compressed file, where compression
+        /// is performed by the caller.
+        Bgzf(WriterWithContext<BgzfWriter<AsyncFileWriter>>),
+        /// A writer for uncompressed data to stdout.
+        Stdout(WriterWithContext<BufWriter<Stdout>>),
+    }
+
+    impl Write for WriteFileZipStdoutAsync {}
+}
+
+impl WriteFileZipStdoutAsync {
+    /// Creates a new [`WriteFileZipStdoutAsync`] from an optional filename. If
+    /// a path is not provided, [`WriteFileZipStdoutAsync::Stdout`] is used.
+    ///
+    /// ## Errors
+    ///
+    /// If a path is provided, any IO errors when creating the file are
+    /// propagated. If no path is provided, this method is infallible. Any
+    /// failed writes will have context added including the path if available.
+    ///
+    /// ## Panics
+    ///
+    /// This must be called within the context of a tokio runtime. See
+    /// [`AsyncFileWriter`].
+    pub fn create(path: Option<impl AsRef<Path>>) -> std::io::Result<Self> {
+        Self::with_compression(path, CompressionOptions::default())
+    }
+
+    /// Similar to [`WriteFileZipStdoutAsync::create`], but writes a compressed
+    /// output in the format and level specified by `compression`. A path ending
+    /// in `.bgz` is written as BGZF regardless of its format. The number of
+    /// threads is not used.
+    ///
+    /// ## Errors
+    ///
+    /// If a path is provided, any IO errors when creating the file are
+    /// propagated. If no path is provided, this method is infallible. Any
+    /// failed writes will have context added including the path if available.
+    ///
+    /// ## Panics
+    ///
+    /// This must be called within the context of a tokio runtime. See
+    /// [`AsyncFileWriter`].
+    pub fn with_compression(path: Option<impl AsRef<Path>>, compression: CompressionOptions) -> std::io::Result<Self> {
+        let CompressionOptions { format, level, .. } = compression;
+
+        match path {
+            Some(path) => {
+                let writer = AsyncFileWriter::new(File::create(&path)?);
+
+                let writer = if is_bgz(&path) || (format == GzipFormat::Bgzf && is_gz(&path)) {
+                    Self::Bgzf(BgzfWriter::new(writer, level).writer_with_path_context("Failed to write to BGZF file", path))
+                } else if is_gz(&path) {
+                    Self::Zipped(
+                        GzEncoder::new(writer, level).writer_with_path_context("Failed to write to zipped file", path),
+                    )
+                } else {
+                    Self::File(writer.writer_with_path_context("Failed to write to file", path))
+                };
+
+                Ok(writer)
+            }
+            None => Ok(WriteFileZipStdoutAsync::Stdout(
+                BufWriter::new(stdout()).writer_with_context("Failed to write to stdout"),
+            )),
+        }
+    }
+}
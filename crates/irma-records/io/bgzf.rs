@@ -0,0 +1,171 @@
+use flate2::{Compression, Crc, write::DeflateEncoder};
+use std::io::Write;
+
+/// The largest amount of uncompressed data stored in a single BGZF block. This
+/// matches htslib, and leaves room for the compressed data (along with the
+/// header and footer) to fit within the 64 KiB limit even when the data is
+/// incompressible.
+pub const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// The largest size of a BGZF block, including its header and footer.
+const BGZF_MAX_BLOCK_SIZE: usize = 0x10000;
+
+/// The header of a BGZF block up to (but not including) `BSIZE`: a gzip member
+/// header with the `FEXTRA` flag set, holding a single `BC` subfield.
+const BGZF_HEADER: [u8; 16] = [
+    0x1f, 0x8b, // ID1, ID2
+    0x08, // CM (deflate)
+    0x04, // FLG (FEXTRA)
+    0x00, 0x00, 0x00, 0x00, // MTIME
+    0x00, // XFL
+    0xff, // OS (unknown)
+    0x06, 0x00, // XLEN
+    b'B', b'C', // SI1, SI2
+    0x02, 0x00, // SLEN
+];
+
+/// The length of a BGZF block's header, including `BSIZE`, and footer (the
+/// CRC-32 and `ISIZE`).
+const BGZF_OVERHEAD: usize = BGZF_HEADER.len() + 2 + 8;
+
+/// The empty block ending a BGZF file, which lets readers distinguish a
+/// complete file from a truncated one.
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// A writer for the [BGZF](https://samtools.github.io/hts-specs/SAMv1.pdf)
+/// (blocked gzip) format used by htslib. The data is split into independently
+/// compressed gzip members of at most [`BGZF_BLOCK_SIZE`] bytes, each recording
+/// its compressed size, so that the output can be indexed and seeked by tools
+/// such as `samtools` and `tabix`. Since each block is a gzip member, the output
+/// is also a valid multi-member gzip file.
+///
+/// The output is ended with the [`BGZF_EOF`] block by [`BgzfWriter::finish`],
+/// or when the writer is dropped (in which case any errors are ignored).
+#[derive(Debug)]
+pub struct BgzfWriter<W: Write> {
+    /// The underlying writer, which is `None` once finished.
+    inner:      Option<W>,
+    /// The uncompressed data of the current block.
+    buffer:     Vec<u8>,
+    /// The compressed data of the current block, kept to reuse its allocation.
+    compressed: Vec<u8>,
+    /// The compression level of each block.
+    level:      Compression,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// Creates a new [`BgzfWriter`] compressing each block with `level`.
+    pub fn new(inner: W, level: Compression) -> Self {
+        Self {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            compressed: Vec::with_capacity(BGZF_MAX_BLOCK_SIZE),
+            level,
+        }
+    }
+
+    /// Compresses and writes any buffered data as a block, followed by the
+    /// [`BGZF_EOF`] block, and returns the underlying writer after flushing it.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors when compressing or writing are propagated.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.write_eof()?;
+        // Validity: `inner` is only taken here, which consumes the writer
+        Ok(self.inner.take().expect("The writer should not be finished"))
+    }
+
+    /// Writes any remaining data and the [`BGZF_EOF`] block, then flushes the
+    /// underlying writer. Afterward, the writer is considered finished.
+    fn write_eof(&mut self) -> std::io::Result<()> {
+        self.write_block()?;
+        if let Some(inner) = &mut self.inner {
+            inner.write_all(&BGZF_EOF)?;
+            inner.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the buffered data as a single block and writes it, unless
+    /// there is no buffered data.
+    fn write_block(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let Some(inner) = &mut self.inner else {
+            return Err(std::io::Error::other("Cannot write to a finished BGZF writer"));
+        };
+
+        write_bgzf_block(inner, &self.buffer, self.level, &mut self.compressed)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+/// Compresses `data`, which must be at most [`BGZF_BLOCK_SIZE`] bytes, as a
+/// single BGZF block and writes it to `inner`. `compressed` holds the
+/// compressed data, so that its allocation can be reused between blocks.
+///
+/// ## Errors
+///
+/// Any IO errors when compressing or writing are propagated.
+pub(crate) fn write_bgzf_block(
+    inner: &mut impl Write, data: &[u8], level: Compression, compressed: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    compressed.clear();
+    let mut encoder = DeflateEncoder::new(&mut *compressed, level);
+    encoder.write_all(data)?;
+    encoder.finish()?;
+
+    let block_size = compressed.len() + BGZF_OVERHEAD;
+    if block_size > BGZF_MAX_BLOCK_SIZE {
+        return Err(std::io::Error::other(format!(
+            "A BGZF block of {block_size} bytes exceeds the maximum of {BGZF_MAX_BLOCK_SIZE}"
+        )));
+    }
+
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    // Validity: `block_size` is at most 0x10000, so `BSIZE` fits in a u16, and
+    // the data is at most `BGZF_BLOCK_SIZE` bytes
+    inner.write_all(&BGZF_HEADER)?;
+    inner.write_all(&((block_size - 1) as u16).to_le_bytes())?;
+    inner.write_all(compressed)?;
+    inner.write_all(&crc.sum().to_le_bytes())?;
+    inner.write_all(&(data.len() as u32).to_le_bytes())?;
+    Ok(())
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        let n = buf.len().min(BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    /// Writes any buffered data as a (possibly short) block and flushes the
+    /// underlying writer.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_block()?;
+        match &mut self.inner {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.write_eof();
+        }
+    }
+}
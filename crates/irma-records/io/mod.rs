@@ -1,4 +1,5 @@
 use std::{
+    error::Error,
     fmt::Display,
     io::{BufRead, Read, Write},
     path::{Path, PathBuf},
@@ -8,25 +9,47 @@ use zoe::{
     prelude::{FastQReader, FastaReader},
 };
 
+#[cfg(feature = "async-io")]
+mod async_io;
+mod bgzf;
 mod fastx;
 mod open_options;
+mod parallel_gz;
+mod progress;
 mod readers;
+mod skip;
+mod temp;
 mod write_records;
 mod writers;
 
+#[cfg(feature = "async-io")]
+pub use async_io::*;
+pub use bgzf::*;
 pub use fastx::*;
 pub use open_options::*;
+pub use parallel_gz::*;
+pub use progress::{RecordId, records_read, set_record_hook};
 pub use readers::*;
+pub use skip::*;
+pub use temp::*;
 pub use write_records::*;
 pub use writers::*;
 
+#[cfg(test)]
+mod test;
+
 /// A wrapper around a fallible iterator, which adds context to any errors in
 /// the items.
+///
+/// The number of the item at which an error occurred is also recorded, as a
+/// [`RecordNumberError`] beneath the context.
 pub struct IterWithContext<I> {
     /// The inner, fallible iterator.
     iter:        I,
     /// The context to add for any errors.
     description: String,
+    /// The number of items yielded so far.
+    records:     usize,
 }
 
 impl<I> IterWithContext<I> {
@@ -36,15 +59,42 @@ impl<I> IterWithContext<I> {
     }
 }
 
+/// Adds the context `description` and the 1-based `record` number to any error
+/// in an item.
+#[inline]
+fn add_item_context<V, E>(val: Result<V, E>, description: &str, record: usize) -> std::io::Result<V>
+where
+    E: Error + Send + Sync + 'static, {
+    val.map_err(|e| RecordNumberError::new(record, e).with_context(description).into())
+}
+
+/// Adds context to an item as in [`add_item_context`], and passes the ID of a
+/// successfully read record to the hook set with [`set_record_hook`].
+#[inline]
+fn add_record_context<V, E>(val: Result<V, E>, description: &str, record: usize) -> std::io::Result<V>
+where
+    V: RecordId,
+    E: Error + Send + Sync + 'static, {
+    let val = add_item_context(val, description, record);
+    if let Ok(val) = &val {
+        progress::note_record_read(val);
+    }
+    val
+}
+
 impl<I, V, E> Iterator for IterWithContext<I>
 where
     I: Iterator<Item = Result<V, E>>,
-    E: WithErrorContext,
+    V: RecordId,
+    E: Error + Send + Sync + 'static,
 {
     type Item = std::io::Result<V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|val| Ok(val.with_context(&self.description)?))
+        let val = self.iter.next()?;
+        self.records += 1;
+        progress::count_records_read(1);
+        Some(add_record_context(val, &self.description, self.records))
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -54,17 +104,22 @@ where
     fn count(self) -> usize
     where
         Self: Sized, {
-        self.iter.count()
+        let count = self.iter.count();
+        progress::count_records_read(count);
+        count
     }
 
     fn last(self) -> Option<Self::Item>
     where
         Self: Sized, {
-        self.iter.last().map(|val| Ok(val.with_context(&self.description)?))
+        // Folding keeps track of the record number of the last item
+        self.fold(None, |_, val| Some(val))
     }
 
     fn nth(&mut self, n: usize) -> Option<Self::Item> {
-        self.iter.nth(n).map(|val| Ok(val.with_context(&self.description)?))
+        let val = self.iter.nth(n)?;
+        self.records += n + 1;
+        Some(add_record_context(val, &self.description, self.records))
     }
 
     fn try_fold<B, F, R>(&mut self, init: B, mut f: F) -> R
@@ -72,8 +127,11 @@ where
         Self: Sized,
         F: FnMut(B, Self::Item) -> R,
         R: std::ops::Try<Output = B>, {
+        let description = &self.description;
+        let records = &mut self.records;
         self.iter.try_fold(init, |accum, val| {
-            f(accum, val.with_context(&self.description).map_err(Into::into))
+            *records += 1;
+            f(accum, add_record_context(val, description, *records))
         })
     }
 
@@ -81,12 +139,56 @@ where
     where
         Self: Sized,
         F: FnMut(B, Self::Item) -> B, {
-        self.iter.fold(init, |accum, val| {
-            f(accum, val.with_context(&self.description).map_err(Into::into))
+        let IterWithContext {
+            iter,
+            description,
+            mut records,
+        } = self;
+        iter.fold(init, |accum, val| {
+            records += 1;
+            f(accum, add_record_context(val, &description, records))
         })
     }
 }
 
+/// An error recording the 1-based number of the record (or other item) at which
+/// an [`IterWithContext`] yielded an error. The original error is its
+/// [`Error::source`].
+#[derive(Debug)]
+pub struct RecordNumberError {
+    record: usize,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl RecordNumberError {
+    /// Wraps `source`, which occurred at the given 1-based `record` number.
+    pub fn new(record: usize, source: impl Error + Send + Sync + 'static) -> Self {
+        Self {
+            record,
+            source: Box::new(source),
+        }
+    }
+
+    /// Returns the 1-based number of the record at which the error occurred.
+    #[inline]
+    #[must_use]
+    pub fn record(&self) -> usize {
+        self.record
+    }
+}
+
+impl Display for RecordNumberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "At record {}", self.record)
+    }
+}
+
+impl Error for RecordNumberError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
 /// An extension trait for fallible iterators allowing additional context to be
 /// added to each [`Err`] variant that gets yielded (via a
 /// [`ErrorWithContext`]).
@@ -112,12 +214,13 @@ pub trait IterWithErrorContext: Sized {
 impl<I, V, E> IterWithErrorContext for I
 where
     I: Iterator<Item = Result<V, E>>,
-    E: WithErrorContext,
+    E: Error + Send + Sync + 'static,
 {
     fn iter_with_context(self, description: impl Into<String>) -> IterWithContext<Self> {
         IterWithContext {
             iter:        self,
             description: description.into(),
+            records:     0,
         }
     }
 
@@ -143,7 +246,7 @@ impl<R: Read> IterWithContext<FastXReader<R>> {
     /// Moves the context inside each variant so that the reader type can be
     /// matched on.
     pub fn dispatch(self) -> DispatchFastX<R> {
-        let IterWithContext { iter, description } = self;
+        let IterWithContext { iter, description, .. } = self;
 
         match iter {
             FastXReader::Fastq(iter) => DispatchFastX::Fastq(iter.iter_with_context(description)),
@@ -239,10 +342,18 @@ pub trait ValidatePaths {
 /// Checks whether a file is a [gzip
 /// file](https://www.rfc-editor.org/rfc/rfc1952#page-5).
 ///
-/// This is currently done naively by seeing if it ends with a `gz` extension.
+/// This is currently done naively by seeing if it ends with a `gz` or `bgz`
+/// extension, since BGZF files are also gzip files (see [`is_bgz`]).
 #[inline]
 pub fn is_gz<P: AsRef<Path>>(path: P) -> bool {
-    path.as_ref().extension().is_some_and(|ext| ext == "gz")
+    path.as_ref().extension().is_some_and(|ext| ext == "gz" || ext == "bgz")
+}
+
+/// Checks whether a file is a [BGZF](https://samtools.github.io/hts-specs/SAMv1.pdf)
+/// (blocked gzip) file, by seeing if it ends with a `bgz` extension.
+#[inline]
+pub fn is_bgz<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext == "bgz")
 }
 
 /// A wrapper around a writer of type `W` such that error context is added to
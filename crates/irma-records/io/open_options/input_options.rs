@@ -1,7 +1,9 @@
+#[cfg(feature = "async-io")]
+use crate::io::ReadFileZipAsync;
 use crate::io::{
-    FastXReader, GzipReaderInThread, InputContext, IterWithContext, IterWithErrorContext, OptionalPaths, PairedErrors,
-    ReadFileStdin, ReadFileZip, ReadFileZipInThread, ReaderType, ReaderWithContext, RecordReaders,
-    open_options::PairedStruct,
+    FastQSkipReader, FastXReader, FastaSkipReader, GzipReaderInThread, InputContext, IterWithContext, IterWithErrorContext,
+    OptionalPaths, PairedErrors, ReadFileStdin, ReadFileZip, ReadFileZipInThread, ReaderType, ReaderWithContext,
+    RecordReaders, TeeReader, TolerantFastQReader, open_options::PairedStruct,
 };
 use std::{
     fs::File,
@@ -53,13 +55,21 @@ use zoe::{
 ///    happen eagerly on a separate thread with `decode_in_thread`. This is
 ///    useful when the contents are processed in a streamed manner (i.e., not
 ///    collected).
-/// 4. Optionally parse the input as a particular record format:
+/// 4. Optionally write an exact copy of the bytes read to a file with
+///    `tee_to` ([`TeeReader`]). If `decode_in_thread` or `use_file_or_zip` was
+///    used, the copy holds the decompressed bytes.
+/// 5. Optionally parse the input as a particular record format:
 ///    - `parse_fastq`: Parses the input as a FASTQ file ([`FastQReader`])
 ///    - `parse_fasta`: Parses the input as a FASTA file ([`FastaReader`])
 ///    - `parse_fastx`: Parses the input as either a FASTQ or FASTA file
 ///      ([`FastXReader`])
 ///    - `parse_sam`: Parses the input as a SAM file ([`SAMReader`])
-/// 5. Call the `open` method to retrieve the inputs, with context automatically
+///    - `parse_fastq_skippable` or `parse_fasta_skippable`: Parses the input as
+///      a FASTQ or FASTA file which can skip over records without parsing them
+///      ([`FastQSkipReader`] or [`FastaSkipReader`])
+///    - `parse_fastq_tolerant`: Parses the input as a FASTQ file which can
+///      skip structurally invalid records ([`TolerantFastQReader`])
+/// 6. Call the `open` method to retrieve the inputs, with context automatically
 ///    added to any errors.
 ///
 /// See the uses of this in IRMA-core for examples.
@@ -216,6 +226,26 @@ impl<'a> InputOptions<'a, RecordReaders<&'a Path>> {
             input:   self.input.and_then(|readers| readers.try_map(ReadFileZip::open)),
         }
     }
+
+    /// Interprets the path(s) using [`ReadFileZipAsync`], where each file is
+    /// read by an asynchronous task, and gzip files are decoded on a separate
+    /// thread.
+    ///
+    /// Each file is determined to be zipped if the path end in `.gz`.
+    ///
+    /// ## Panics
+    ///
+    /// This must be called within the context of a tokio runtime, such as
+    /// within [`run_with_async_io`].
+    ///
+    /// [`run_with_async_io`]: crate::io::run_with_async_io
+    #[cfg(feature = "async-io")]
+    pub fn use_file_or_zip_async(self) -> InputOptions<'a, RecordReaders<ReadFileZipAsync>> {
+        InputOptions {
+            context: self.context,
+            input:   self.input.and_then(|readers| readers.try_map(ReadFileZipAsync::open)),
+        }
+    }
 }
 
 impl<'a> InputOptions<'a, OptionalPaths<'a>> {
@@ -303,6 +333,46 @@ impl<'a> InputOptions<'a, RecordReaders<ReadFileZip>> {
     }
 }
 
+impl<'a, R> InputOptions<'a, R>
+where
+    R: Read,
+{
+    /// Writes an exact copy of the bytes read from the input to `path`, via
+    /// [`TeeReader`]. If `path` is `None`, the input is read unchanged.
+    pub fn tee_to(self, path: Option<&Path>) -> InputOptions<'a, TeeReader<R>> {
+        InputOptions {
+            context: self.context,
+            input:   self
+                .input
+                .and_then(|src| TeeReader::new(src, path).map_err(PairedErrors::Err1)),
+        }
+    }
+}
+
+impl<'a, R> InputOptions<'a, RecordReaders<R>>
+where
+    R: Read,
+{
+    /// Writes an exact copy of the bytes read from each input to `path1` and
+    /// `path2` respectively, via [`TeeReader`]. An input whose path is `None`
+    /// is read unchanged, as is `path2` if the inputs are unpaired.
+    pub fn tee_to(self, path1: Option<&Path>, path2: Option<&Path>) -> InputOptions<'a, RecordReaders<TeeReader<R>>> {
+        let input = self.input.and_then(|readers| {
+            let reader1 = TeeReader::new(readers.reader1, path1).map_err(PairedErrors::Err1)?;
+            let reader2 = readers
+                .reader2
+                .map(|reader2| TeeReader::new(reader2, path2).map_err(PairedErrors::Err2))
+                .transpose()?;
+            Ok(RecordReaders { reader1, reader2 })
+        });
+
+        InputOptions {
+            context: self.context,
+            input,
+        }
+    }
+}
+
 impl<'a, R> InputOptions<'a, R>
 where
     R: Read,
@@ -373,6 +443,34 @@ where
         }
     }
 
+    /// Parses the input as a FASTQ file, via the iterator [`FastQSkipReader`].
+    pub fn parse_fastq_skippable(self) -> InputOptions<'a, FastQSkipReader<BufReader<R>>> {
+        InputOptions {
+            context: self.context.with_reader1(ReaderType::FastQ),
+            input:   self.input.map(|src| FastQSkipReader::new(BufReader::new(src))),
+        }
+    }
+
+    /// Parses the input as a FASTQ file, via the iterator
+    /// [`TolerantFastQReader`]. Structurally invalid records are skipped if
+    /// `skip_bad_records` is true.
+    pub fn parse_fastq_tolerant(self, skip_bad_records: bool) -> InputOptions<'a, TolerantFastQReader<BufReader<R>>> {
+        InputOptions {
+            context: self.context.with_reader1(ReaderType::FastQ),
+            input:   self
+                .input
+                .map(|src| TolerantFastQReader::new(BufReader::new(src), skip_bad_records)),
+        }
+    }
+
+    /// Parses the input as a FASTA file, via the iterator [`FastaSkipReader`].
+    pub fn parse_fasta_skippable(self) -> InputOptions<'a, FastaSkipReader<BufReader<R>>> {
+        InputOptions {
+            context: self.context.with_reader1(ReaderType::Fasta),
+            input:   self.input.map(|src| FastaSkipReader::new(BufReader::new(src))),
+        }
+    }
+
     /// Parses the input as a SAM file, via the iterator [`SAMReader`].
     pub fn parse_sam(self) -> InputOptions<'a, SAMReader<R, true>> {
         let src = match self.input {
@@ -416,6 +514,20 @@ where
         }
     }
 
+    /// Parses the input(s) as FASTQ files, via the iterator
+    /// [`TolerantFastQReader`]. Structurally invalid records are skipped if
+    /// `skip_bad_records` is true.
+    pub fn parse_fastq_tolerant(
+        self, skip_bad_records: bool,
+    ) -> InputOptions<'a, RecordReaders<TolerantFastQReader<BufReader<R>>>> {
+        InputOptions {
+            context: self.context.with_reader1(ReaderType::FastQ).with_reader2(ReaderType::FastQ),
+            input:   self
+                .input
+                .map(|srcs| srcs.map(|src| TolerantFastQReader::new(BufReader::new(src), skip_bad_records))),
+        }
+    }
+
     /// Parses the input(s) as FASTA file(s), via the iterator [`FastaReader`].
     #[allow(dead_code)]
     pub fn parse_fasta(self) -> InputOptions<'a, RecordReaders<FastaReader<R>>> {
@@ -647,6 +759,24 @@ impl InputOptions<'_, ReadFileStdin> {
     }
 }
 
+impl<R> InputOptions<'_, TeeReader<R>>
+where
+    R: Read,
+{
+    /// Opens the [`TeeReader`], wrapping it in a [`BufReader`].
+    ///
+    /// ## Errors
+    ///
+    /// IO errors when opening the input or creating the file for the copy are
+    /// propagated. Context is added that includes the path. Any failed reads
+    /// from the reader (or writes to the copy) will also have similar context
+    /// due to the [`ReaderWithContext`] wrapper.
+    #[allow(dead_code)]
+    pub fn open(self) -> std::io::Result<ReaderWithContext<BufReader<TeeReader<R>>>> {
+        self.open_readable()
+    }
+}
+
 impl<R> InputOptions<'_, FastQReader<R>>
 where
     R: Read,
@@ -706,6 +836,56 @@ where
     }
 }
 
+impl<R> InputOptions<'_, FastQSkipReader<BufReader<R>>>
+where
+    R: Read,
+{
+    /// Opens the [`FastQSkipReader`].
+    ///
+    /// ## Errors
+    ///
+    /// Any IO error is propagated with context that includes the path if
+    /// available. Any items that are errors in the iterator (or errors while
+    /// skipping) will have the record type FASTQ as context due to the
+    /// [`IterWithContext`] wrapper.
+    pub fn open(self) -> std::io::Result<IterWithContext<FastQSkipReader<BufReader<R>>>> {
+        self.open_iter()
+    }
+}
+
+impl<R> InputOptions<'_, TolerantFastQReader<BufReader<R>>>
+where
+    R: Read,
+{
+    /// Opens the [`TolerantFastQReader`].
+    ///
+    /// ## Errors
+    ///
+    /// Any IO error is propagated with context that includes the path if
+    /// available. Any items that are errors in the iterator will have the
+    /// record type FASTQ as context due to the [`IterWithContext`] wrapper.
+    pub fn open(self) -> std::io::Result<IterWithContext<TolerantFastQReader<BufReader<R>>>> {
+        self.open_iter()
+    }
+}
+
+impl<R> InputOptions<'_, FastaSkipReader<BufReader<R>>>
+where
+    R: Read,
+{
+    /// Opens the [`FastaSkipReader`].
+    ///
+    /// ## Errors
+    ///
+    /// Any IO error is propagated with context that includes the path if
+    /// available. Any items that are errors in the iterator (or errors while
+    /// skipping) will have the record type FASTA as context due to the
+    /// [`IterWithContext`] wrapper.
+    pub fn open(self) -> std::io::Result<IterWithContext<FastaSkipReader<BufReader<R>>>> {
+        self.open_iter()
+    }
+}
+
 impl<R> InputOptions<'_, SAMReader<R, true>>
 where
     R: Read,
@@ -786,6 +966,25 @@ impl InputOptions<'_, RecordReaders<ReadFileStdin>> {
     }
 }
 
+impl<R> InputOptions<'_, RecordReaders<TeeReader<R>>>
+where
+    R: Read,
+{
+    /// Opens the potentially paired [`TeeReader`] inputs, wrapping each in a
+    /// [`BufReader`].
+    ///
+    /// ## Errors
+    ///
+    /// IO errors when opening the inputs or creating the files for the copies
+    /// are propagated. Context is added that includes the path. Any failed
+    /// reads from the readers (or writes to the copies) will also have similar
+    /// context due to the [`ReaderWithContext`] wrapper.
+    #[allow(dead_code)]
+    pub fn open(self) -> std::io::Result<RecordReaders<BufReader<ReaderWithContext<TeeReader<R>>>>> {
+        self.open_readable().map(|readers| readers.map(BufReader::new))
+    }
+}
+
 impl<R> InputOptions<'_, RecordReaders<FastQReader<R>>>
 where
     R: Read,
@@ -803,6 +1002,22 @@ where
     }
 }
 
+impl<R> InputOptions<'_, RecordReaders<TolerantFastQReader<BufReader<R>>>>
+where
+    R: Read,
+{
+    /// Opens the potentially paired [`TolerantFastQReader`] inputs.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO error is propagated with context that includes the path if
+    /// available. Any items that are errors in the iterators will have the
+    /// record type FASTQ as context due to the [`IterWithContext`] wrapper.
+    pub fn open(self) -> std::io::Result<RecordReaders<IterWithContext<TolerantFastQReader<BufReader<R>>>>> {
+        self.open_iter()
+    }
+}
+
 impl<R> InputOptions<'_, RecordReaders<FastaReader<R>>>
 where
     R: Read,
@@ -1,6 +1,8 @@
+#[cfg(feature = "async-io")]
+use crate::io::WriteFileZipStdoutAsync;
 use crate::io::{
-    OptionalPaths, OutputContext, PairedErrors, RecordWriters, WriteFileZipStdout, WriterWithContext,
-    open_options::PairedStruct,
+    CompressionOptions, GzipFormat, OptionalPaths, OutputBuffering, OutputContext, PairedErrors, RecordWriters,
+    WriteFileZipStdout, WriterWithContext, open_options::PairedStruct,
 };
 use std::{
     fs::File,
@@ -17,7 +19,8 @@ use std::{
 ///   [`WriteFileZipStdout`]
 /// - Automatically adding context including the path and record type (if
 ///   applicable) to any errors while creating the file or writing to the file
-/// - Altering the capacity of the [`BufWriter`]
+/// - Altering the capacity of the [`BufWriter`], or how [`WriteFileZipStdout`]
+///   outputs are buffered or compressed
 ///
 /// To use this, use the following steps:
 ///
@@ -33,7 +36,9 @@ use std::{
 ///      paired output files are being created, and the first path is optional.
 ///      This is compatible with [`WriteFileZipStdout`].
 /// 2. Optionally set the capacity for the [`BufWriter`] to create using
-///    `with_capacity`.
+///    `with_capacity`, or how [`WriteFileZipStdout`] outputs are buffered or
+///    compressed using `with_buffering`, `with_gzip_format`, and
+///    `with_compression`.
 /// 3. Call a method to interpret the path as something readable. The options
 ///    may differ depending on the constructor used.
 ///    - `use_file`: Interpret the path as a regular file ([`File`])
@@ -63,6 +68,12 @@ pub struct OutputOptions<'a, W> {
 
     /// The capacity for the [`BufWriter`] to create.
     capacity: Option<usize>,
+
+    /// How uncompressed [`WriteFileZipStdout`] outputs are buffered.
+    buffering: OutputBuffering,
+
+    /// How compressed [`WriteFileZipStdout`] outputs are written.
+    compression: CompressionOptions,
 }
 
 impl<'a> OutputOptions<'a, &'a Path> {
@@ -75,9 +86,11 @@ impl<'a> OutputOptions<'a, &'a Path> {
         P: AsRef<Path> + ?Sized, {
         let path = path.as_ref();
         Self {
-            context:  OutputContext::new(Some(path), None),
-            output:   Ok(path),
-            capacity: None,
+            context:     OutputContext::new(Some(path), None),
+            output:      Ok(path),
+            capacity:    None,
+            buffering:   OutputBuffering::Block,
+            compression: CompressionOptions::default(),
         }
     }
 
@@ -95,6 +108,8 @@ impl<'a> OutputOptions<'a, &'a Path> {
             context: self.context,
             output,
             capacity: self.capacity,
+            buffering: self.buffering,
+            compression: self.compression,
         }
     }
 }
@@ -103,9 +118,11 @@ impl<'a> OutputOptions<'a, Stdout> {
     /// Creates a new [`OutputOptions`] for writing to [`Stdout`].
     pub fn new_stdout() -> Self {
         Self {
-            context:  OutputContext::default(),
-            output:   Ok(stdout()),
-            capacity: None,
+            context:     OutputContext::default(),
+            output:      Ok(stdout()),
+            capacity:    None,
+            buffering:   OutputBuffering::Block,
+            compression: CompressionOptions::default(),
         }
     }
 
@@ -126,9 +143,11 @@ impl<'a> OutputOptions<'a, Option<&'a Path>> {
         P: AsRef<Path> + ?Sized, {
         let path = path.map(AsRef::as_ref);
         Self {
-            context:  OutputContext::new(path, None),
-            output:   Ok(path),
-            capacity: None,
+            context:     OutputContext::new(path, None),
+            output:      Ok(path),
+            capacity:    None,
+            buffering:   OutputBuffering::Block,
+            compression: CompressionOptions::default(),
         }
     }
 
@@ -139,26 +158,47 @@ impl<'a> OutputOptions<'a, Option<&'a Path>> {
         self
     }
 
+    /// Sets how the output is buffered if it is uncompressed (see
+    /// [`OutputBuffering`]).
+    pub fn with_buffering(mut self, buffering: OutputBuffering) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Sets how the output is written if it is compressed (see
+    /// [`GzipFormat`]).
+    pub fn with_gzip_format(mut self, gzip_format: GzipFormat) -> Self {
+        self.compression.format = gzip_format;
+        self
+    }
+
+    /// Sets the format, level, and number of threads used if the output is
+    /// compressed (see [`CompressionOptions`]). This replaces any format set by
+    /// `with_gzip_format`.
+    pub fn with_compression(mut self, compression: CompressionOptions) -> Self {
+        self.compression = compression;
+        self
+    }
+
     /// Interprets the optional path using [`WriteFileZipStdout`], which
     /// supports regular files, [gzip
     /// files](https://www.rfc-editor.org/rfc/rfc1952#page-5), and stdout (in
     /// the case that no path was provided).
     ///
-    /// The output is zipped if the path ends in `.gz`.
+    /// The output is zipped if the path ends in `.gz` or `.bgz`, and written as
+    /// BGZF if it ends in `.bgz` or [`GzipFormat::Bgzf`] was selected.
     pub fn use_file_zip_or_stdout(self) -> OutputOptions<'a, WriteFileZipStdout> {
         let output = self.output.and_then(|path| {
-            if let Some(capacity) = self.capacity {
-                WriteFileZipStdout::with_capacity(capacity, path)
-            } else {
-                WriteFileZipStdout::create(path)
-            }
-            .map_err(PairedErrors::Err1)
+            WriteFileZipStdout::with_compression(path, self.capacity, self.buffering, self.compression)
+                .map_err(PairedErrors::Err1)
         });
 
         OutputOptions {
             context: self.context,
             output,
             capacity: self.capacity,
+            buffering: self.buffering,
+            compression: self.compression,
         }
     }
 }
@@ -178,9 +218,11 @@ impl<'a> OutputOptions<'a, RecordWriters<&'a Path>> {
         let path1 = path1.as_ref();
         let path2 = path2.map(AsRef::as_ref);
         Self {
-            context:  OutputContext::new(Some(path1), path2),
-            output:   Ok(RecordWriters::new(path1, path2)),
-            capacity: None,
+            context:     OutputContext::new(Some(path1), path2),
+            output:      Ok(RecordWriters::new(path1, path2)),
+            capacity:    None,
+            buffering:   OutputBuffering::Block,
+            compression: CompressionOptions::default(),
         }
     }
 
@@ -195,9 +237,11 @@ impl<'a> OutputOptions<'a, RecordWriters<&'a Path>> {
     #[allow(dead_code)]
     pub fn use_file(self) -> OutputOptions<'a, RecordWriters<File>> {
         OutputOptions {
-            context:  self.context,
-            output:   self.output.and_then(|writers| writers.try_map(File::create)),
-            capacity: self.capacity,
+            context:     self.context,
+            output:      self.output.and_then(|writers| writers.try_map(File::create)),
+            capacity:    self.capacity,
+            buffering:   self.buffering,
+            compression: self.compression,
         }
     }
 }
@@ -219,9 +263,11 @@ impl<'a> OutputOptions<'a, OptionalPaths<'a>> {
         let path1 = path1.map(AsRef::as_ref);
         let path2 = path2.map(AsRef::as_ref);
         Self {
-            context:  OutputContext::new(path1, path2),
-            output:   Ok(OptionalPaths { path1, path2 }),
-            capacity: None,
+            context:     OutputContext::new(path1, path2),
+            output:      Ok(OptionalPaths { path1, path2 }),
+            capacity:    None,
+            buffering:   OutputBuffering::Block,
+            compression: CompressionOptions::default(),
         }
     }
 
@@ -232,23 +278,71 @@ impl<'a> OutputOptions<'a, OptionalPaths<'a>> {
         self
     }
 
+    /// Sets how the output is buffered if it is uncompressed (see
+    /// [`OutputBuffering`]).
+    pub fn with_buffering(mut self, buffering: OutputBuffering) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Sets how the output is written if it is compressed (see
+    /// [`GzipFormat`]).
+    pub fn with_gzip_format(mut self, gzip_format: GzipFormat) -> Self {
+        self.compression.format = gzip_format;
+        self
+    }
+
+    /// Sets the format, level, and number of threads used if the output is
+    /// compressed (see [`CompressionOptions`]). This replaces any format set by
+    /// `with_gzip_format`.
+    pub fn with_compression(mut self, compression: CompressionOptions) -> Self {
+        self.compression = compression;
+        self
+    }
+
     /// Interprets the optional path(s) using [`WriteFileZipStdout`].
     ///
     /// Only `path1` has the potential of being [`WriteFileZipStdout::Stdout`],
     /// since if `path2` is `None`, this corresponds to unpaired output.
     pub fn use_file_zip_or_stdout(self) -> OutputOptions<'a, RecordWriters<WriteFileZipStdout>> {
         OutputOptions {
-            context:  self.context,
-            output:   self.output.and_then(|paths| {
+            context:     self.context,
+            output:      self.output.and_then(|paths| {
                 paths.try_map_writers(|path| {
-                    if let Some(capacity) = self.capacity {
-                        WriteFileZipStdout::with_capacity(capacity, path)
-                    } else {
-                        WriteFileZipStdout::create(path)
-                    }
+                    WriteFileZipStdout::with_compression(path, self.capacity, self.buffering, self.compression)
                 })
             }),
-            capacity: self.capacity,
+            capacity:    self.capacity,
+            buffering:   self.buffering,
+            compression: self.compression,
+        }
+    }
+
+    /// Interprets the optional path(s) using [`WriteFileZipStdoutAsync`], where
+    /// files are written by asynchronous tasks. The capacity, buffering, and
+    /// number of compression threads are not used, since the compression
+    /// already occurs apart from the processing.
+    ///
+    /// Only `path1` has the potential of being
+    /// [`WriteFileZipStdoutAsync::Stdout`], since if `path2` is `None`, this
+    /// corresponds to unpaired output.
+    ///
+    /// ## Panics
+    ///
+    /// This must be called within the context of a tokio runtime, such as
+    /// within [`run_with_async_io`].
+    ///
+    /// [`run_with_async_io`]: crate::io::run_with_async_io
+    #[cfg(feature = "async-io")]
+    pub fn use_file_zip_or_stdout_async(self) -> OutputOptions<'a, RecordWriters<WriteFileZipStdoutAsync>> {
+        OutputOptions {
+            context:     self.context,
+            output:      self.output.and_then(|paths| {
+                paths.try_map_writers(|path| WriteFileZipStdoutAsync::with_compression(path, self.compression))
+            }),
+            capacity:    self.capacity,
+            buffering:   self.buffering,
+            compression: self.compression,
         }
     }
 }
@@ -359,3 +453,21 @@ impl<'a> OutputOptions<'a, RecordWriters<WriteFileZipStdout>> {
         }
     }
 }
+
+#[cfg(feature = "async-io")]
+impl<'a> OutputOptions<'a, RecordWriters<WriteFileZipStdoutAsync>> {
+    /// Opens the potentially paired [`WriteFileZipStdoutAsync`] outputs.
+    ///
+    /// ## Errors
+    ///
+    /// If a path was provided for the first input, IO errors when creating the
+    /// file are propagated. Context is added that includes the path. Any failed
+    /// writes will also have similar context added (by definition of
+    /// [`WriteFileZipStdoutAsync`]).
+    pub fn open(self) -> std::io::Result<RecordWriters<WriteFileZipStdoutAsync>> {
+        match self.output {
+            Ok(writer) => Ok(writer),
+            Err(e) => Err(self.context.add_context(e).into()),
+        }
+    }
+}
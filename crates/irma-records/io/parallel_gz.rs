@@ -0,0 +1,271 @@
+use crate::io::{BGZF_BLOCK_SIZE, BGZF_EOF, GzipFormat, bgzf::write_bgzf_block};
+use flate2::{Compression, write::GzEncoder};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    num::NonZeroUsize,
+    sync::{
+        Arc, Mutex, PoisonError,
+        mpsc::{Receiver, Sender, SyncSender, TryRecvError, channel, sync_channel},
+    },
+    thread::{self, JoinHandle},
+};
+
+/// The amount of uncompressed data given to a compression thread at a time.
+/// For BGZF, this is split into blocks of at most [`BGZF_BLOCK_SIZE`] bytes.
+const CHUNK_SIZE: usize = 16 * BGZF_BLOCK_SIZE;
+
+/// The number of chunks that may be compressing or awaiting their turn to be
+/// written for each thread, which bounds the memory used.
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// A chunk of uncompressed data, numbered by its position in the output.
+type Job = (u64, Vec<u8>);
+
+/// A compressed chunk, numbered by its position in the output.
+type CompressedJob = (u64, std::io::Result<Vec<u8>>);
+
+/// A gzip or BGZF writer that compresses chunks of the data on several threads
+/// at once, writing them in order.
+///
+/// With [`GzipFormat::Gzip`], each chunk is written as its own gzip member,
+/// which is a valid multi-member gzip file that any gzip reader decodes as a
+/// whole. With [`GzipFormat::Bgzf`], each chunk is written as BGZF blocks,
+/// followed by the [`BGZF_EOF`] block at the end of the output, identical to
+/// the output of [`BgzfWriter`].
+///
+/// The output is finished by [`ParallelGzWriter::finish`], or when the writer
+/// is dropped (in which case any errors are ignored).
+///
+/// [`BgzfWriter`]: crate::io::BgzfWriter
+#[derive(Debug)]
+pub struct ParallelGzWriter<W: Write> {
+    /// The underlying writer, which is `None` once finished.
+    inner:     Option<W>,
+    /// The uncompressed data of the current chunk.
+    buffer:    Vec<u8>,
+    /// Whether to write BGZF blocks, which also writes the EOF block.
+    format:    GzipFormat,
+    /// The sender of chunks to the compression threads, which is dropped once
+    /// finished so that the threads exit.
+    jobs:      Option<SyncSender<Job>>,
+    /// The receiver of compressed chunks, which may arrive out of order.
+    results:   Receiver<CompressedJob>,
+    /// The compression threads.
+    workers:   Vec<JoinHandle<()>>,
+    /// The compressed chunks received ahead of their turn to be written.
+    pending:   BTreeMap<u64, Vec<u8>>,
+    /// The number of the next chunk to send.
+    next_job:  u64,
+    /// The number of the next chunk to write.
+    next_out:  u64,
+    /// The largest number of chunks that are sent but not yet written.
+    in_flight: u64,
+}
+
+impl<W: Write> ParallelGzWriter<W> {
+    /// Creates a new [`ParallelGzWriter`] in the given `format`, compressing
+    /// with `level` on `threads` threads.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors when spawning the threads are propagated.
+    pub fn new(inner: W, format: GzipFormat, level: Compression, threads: NonZeroUsize) -> std::io::Result<Self> {
+        let (job_sender, job_receiver) = sync_channel::<Job>(threads.get());
+        let (result_sender, results) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..threads.get())
+            .map(|i| {
+                let job_receiver = Arc::clone(&job_receiver);
+                let result_sender = result_sender.clone();
+                thread::Builder::new()
+                    .name(format!("gzip-{i}"))
+                    .spawn(move || compress_jobs(&job_receiver, &result_sender, format, level))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            format,
+            jobs: Some(job_sender),
+            results,
+            workers,
+            pending: BTreeMap::new(),
+            next_job: 0,
+            next_out: 0,
+            in_flight: (threads.get() * CHUNKS_PER_THREAD) as u64,
+        })
+    }
+
+    /// Compresses and writes any buffered data, waiting for every thread, and
+    /// then writes the [`BGZF_EOF`] block if the format is BGZF. The threads
+    /// are stopped, and the underlying writer is returned after flushing it.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors when compressing or writing are propagated.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.write_eof()?;
+        // Validity: `inner` is only taken here, which consumes the writer
+        Ok(self.inner.take().expect("The writer should not be finished"))
+    }
+
+    /// Writes any remaining data and, for BGZF, the [`BGZF_EOF`] block, then
+    /// flushes the underlying writer and stops the threads. Afterward, the
+    /// writer is considered finished.
+    fn write_eof(&mut self) -> std::io::Result<()> {
+        self.send_chunk()?;
+        self.write_compressed(true)?;
+        if let Some(inner) = &mut self.inner {
+            if self.format == GzipFormat::Bgzf {
+                inner.write_all(&BGZF_EOF)?;
+            }
+            inner.flush()?;
+        }
+        self.stop_workers();
+        Ok(())
+    }
+
+    /// Sends the buffered data to the compression threads as a chunk, unless
+    /// there is no buffered data. If too many chunks are already in flight,
+    /// this first waits for the oldest to be written.
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        while self.next_job - self.next_out >= self.in_flight {
+            self.receive(true)?;
+            self.write_compressed(false)?;
+        }
+
+        let Some(jobs) = &self.jobs else {
+            return Err(std::io::Error::other("Cannot write to a finished gzip writer"));
+        };
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        jobs.send((self.next_job, chunk)).map_err(|_| stopped_error())?;
+        self.next_job += 1;
+        Ok(())
+    }
+
+    /// Writes the compressed chunks that are next in order. With `wait`, this
+    /// waits until every chunk sent has been written, and otherwise writes only
+    /// those already received.
+    fn write_compressed(&mut self, wait: bool) -> std::io::Result<()> {
+        loop {
+            while let Some(compressed) = self.pending.remove(&self.next_out) {
+                let Some(inner) = &mut self.inner else {
+                    return Err(std::io::Error::other("Cannot write to a finished gzip writer"));
+                };
+                inner.write_all(&compressed)?;
+                self.next_out += 1;
+            }
+
+            if self.next_out == self.next_job || !self.receive(wait)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Receives a compressed chunk into `pending`, returning whether one was
+    /// received. With `wait`, this blocks until a chunk arrives.
+    fn receive(&mut self, wait: bool) -> std::io::Result<bool> {
+        let (number, compressed) = if wait {
+            self.results.recv().map_err(|_| stopped_error())?
+        } else {
+            match self.results.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => return Err(stopped_error()),
+            }
+        };
+        self.pending.insert(number, compressed?);
+        Ok(true)
+    }
+
+    /// Stops the compression threads once they have finished their chunks.
+    fn stop_workers(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<W: Write> Write for ParallelGzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE {
+            self.send_chunk()?;
+            self.write_compressed(false)?;
+        }
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    /// Compresses any buffered data as a (possibly short) chunk, waits for
+    /// every chunk to be written, and flushes the underlying writer.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_chunk()?;
+        self.write_compressed(true)?;
+        match &mut self.inner {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for ParallelGzWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.write_eof();
+        }
+        self.stop_workers();
+    }
+}
+
+/// Compresses the chunks received from `jobs` until the sender is dropped,
+/// sending each to `results` with its number.
+fn compress_jobs(jobs: &Mutex<Receiver<Job>>, results: &Sender<CompressedJob>, format: GzipFormat, level: Compression) {
+    let mut scratch = Vec::new();
+    loop {
+        // The lock is released before compressing, so that other threads can
+        // receive chunks in the meantime
+        let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        let Ok((number, chunk)) = job else {
+            return;
+        };
+
+        let compressed = match format {
+            GzipFormat::Gzip => compress_gzip(&chunk, level),
+            GzipFormat::Bgzf => compress_bgzf(&chunk, level, &mut scratch),
+        };
+        if results.send((number, compressed)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Compresses `chunk` as a single gzip member.
+fn compress_gzip(chunk: &[u8], level: Compression) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(chunk.len() / 2), level);
+    encoder.write_all(chunk)?;
+    encoder.finish()
+}
+
+/// Compresses `chunk` as consecutive BGZF blocks, using `scratch` for the
+/// compressed data of each block.
+fn compress_bgzf(chunk: &[u8], level: Compression, scratch: &mut Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(chunk.len() / 2);
+    for block in chunk.chunks(BGZF_BLOCK_SIZE) {
+        write_bgzf_block(&mut compressed, block, level, scratch)?;
+    }
+    Ok(compressed)
+}
+
+/// The error when the compression threads have stopped unexpectedly, such as
+/// from a panic.
+fn stopped_error() -> std::io::Error {
+    std::io::Error::other("The gzip compression threads unexpectedly stopped")
+}
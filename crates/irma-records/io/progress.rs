@@ -0,0 +1,107 @@
+//! Process-wide progress counters, updated as records are read so that a
+//! long-running process can report how far it has got (such as to a metrics
+//! endpoint) without each subcommand tracking this itself. The ID of each
+//! record read can also be passed to a hook (see [`set_record_hook`]).
+
+use crate::io::FastX;
+use std::sync::{
+    OnceLock,
+    atomic::{AtomicU64, Ordering},
+};
+use zoe::data::{fasta::FastaSeq, fastq::FastQ, records::HeaderReadable, sam::SamRow};
+
+/// The number of records yielded by every [`IterWithContext`] in this process.
+///
+/// [`IterWithContext`]: crate::io::IterWithContext
+static RECORDS_READ: AtomicU64 = AtomicU64::new(0);
+
+/// Adds `n` records to the count of records read, called by
+/// [`IterWithContext`] as items are yielded.
+///
+/// [`IterWithContext`]: crate::io::IterWithContext
+#[inline]
+pub(crate) fn count_records_read(n: usize) {
+    RECORDS_READ.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Returns the number of records read so far by this process, across all
+/// inputs opened with [`InputOptions`]. Records that are skipped without being
+/// parsed (such as with [`SkipRecords`]) are not counted.
+///
+/// [`InputOptions`]: crate::io::InputOptions
+/// [`SkipRecords`]: crate::io::SkipRecords
+#[inline]
+#[must_use]
+pub fn records_read() -> u64 {
+    RECORDS_READ.load(Ordering::Relaxed)
+}
+
+/// The hook called with the ID of each record read, set with
+/// [`set_record_hook`].
+static RECORD_HOOK: OnceLock<fn(&str)> = OnceLock::new();
+
+/// Calls `hook` with the ID of each record yielded by an [`IterWithContext`]
+/// for the rest of the process, on the thread reading the record, such as for
+/// noting the records read before a panic. Only the first call has an effect.
+///
+/// [`IterWithContext`]: crate::io::IterWithContext
+pub fn set_record_hook(hook: fn(&str)) {
+    let _ = RECORD_HOOK.set(hook);
+}
+
+/// Passes the ID of a record to the hook set with [`set_record_hook`], if any.
+#[inline]
+pub(crate) fn note_record_read(record: &impl RecordId) {
+    if let Some(hook) = RECORD_HOOK.get()
+        && let Some(id) = record.record_id()
+    {
+        hook(id);
+    }
+}
+
+/// A record read by an [`IterWithContext`], with an ID that can be passed to
+/// the hook set with [`set_record_hook`].
+///
+/// [`IterWithContext`]: crate::io::IterWithContext
+pub trait RecordId {
+    /// The ID of the record, which is its header up to the first whitespace,
+    /// or `None` for items without one (such as SAM header lines).
+    fn record_id(&self) -> Option<&str>;
+}
+
+/// The ID within a FastQ or FASTA header.
+#[inline]
+fn header_id(header: &str) -> &str {
+    header.split_ascii_whitespace().next().unwrap_or_default()
+}
+
+impl RecordId for FastQ {
+    #[inline]
+    fn record_id(&self) -> Option<&str> {
+        Some(header_id(self.header()))
+    }
+}
+
+impl RecordId for FastaSeq {
+    #[inline]
+    fn record_id(&self) -> Option<&str> {
+        Some(header_id(self.header()))
+    }
+}
+
+impl RecordId for FastX {
+    #[inline]
+    fn record_id(&self) -> Option<&str> {
+        Some(header_id(self.header()))
+    }
+}
+
+impl RecordId for SamRow {
+    #[inline]
+    fn record_id(&self) -> Option<&str> {
+        match self {
+            SamRow::Header(_) => None,
+            SamRow::Data(data) => Some(&data.qname),
+        }
+    }
+}
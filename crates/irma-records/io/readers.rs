@@ -1,12 +1,77 @@
 use crate::io::is_gz;
-use flate2::read::MultiGzDecoder;
+use flate2::bufread::GzDecoder;
 use std::{
     fs::File,
-    io::{PipeReader, Read, Stdin, stdin},
-    path::Path,
+    io::{BufRead, BufReader, BufWriter, PipeReader, Read, Stdin, Write, stdin},
+    path::{Path, PathBuf},
     thread::{self, JoinHandle},
 };
-use zoe::{data::err::ResultWithErrorContext, define_whichever};
+use zoe::{
+    data::err::{ResultWithErrorContext, WithErrorContext},
+    define_whichever,
+};
+
+/// A decoder for a [gzip file](https://www.rfc-editor.org/rfc/rfc1952#page-5)
+/// that may have multiple members, such as those written by instruments that
+/// compress each chunk of reads separately and concatenate the results.
+///
+/// This behaves like [`MultiGzDecoder`], but it tracks which member is being
+/// decoded, so that errors contain `in gzip member #k` as context.
+///
+/// [`MultiGzDecoder`]: flate2::read::MultiGzDecoder
+pub struct MultiGzMemberDecoder<R> {
+    /// The decoder for the current member, or `None` once all members have
+    /// been decoded
+    decoder: Option<GzDecoder<BufReader<R>>>,
+    /// The 1-based index of the current member
+    member:  usize,
+}
+
+impl<R: Read> MultiGzMemberDecoder<R> {
+    /// Creates a new [`MultiGzMemberDecoder`], which decodes the gzip members
+    /// in `reader` in order.
+    pub fn new(reader: R) -> Self {
+        Self {
+            decoder: Some(GzDecoder::new(BufReader::new(reader))),
+            member:  1,
+        }
+    }
+
+    /// The 1-based index of the member currently being decoded. Once all data
+    /// has been read, this is the number of members.
+    #[inline]
+    pub fn member(&self) -> usize {
+        self.member
+    }
+}
+
+impl<R: Read> Read for MultiGzMemberDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while let Some(decoder) = &mut self.decoder {
+            let member = self.member;
+            let bytes_read = decoder
+                .read(buf)
+                .map_err(|e| e.with_context(format!("Failed to decode the data in gzip member #{member}")))?;
+            if bytes_read > 0 {
+                return Ok(bytes_read);
+            }
+
+            // The member is complete, so another is started if any data
+            // remains after it
+            let mut reader = self.decoder.take().expect("The decoder was just used").into_inner();
+            if !reader.fill_buf()?.is_empty() {
+                self.decoder = Some(GzDecoder::new(reader));
+                self.member += 1;
+            }
+        }
+
+        Ok(0)
+    }
+}
 
 /// A reader for a [gzip file](https://www.rfc-editor.org/rfc/rfc1952#page-5)
 /// that may have multiple members, spawning a separate thread for the unzipping
@@ -28,14 +93,14 @@ pub struct GzipReaderInThread {
 
 impl GzipReaderInThread {
     /// Creates a new [`GzipReaderInThread`] from an existing
-    /// [`MultiGzDecoder`].
+    /// [`MultiGzMemberDecoder`].
     ///
     /// ## Errors
     ///
     /// Any IO errors occurring when forming the pipe are propagated with
     /// context. Errors occurring during decoding appear when reading from the
     /// [`GzipReaderInThread`].
-    pub fn from_decoder<R>(mut decoder: MultiGzDecoder<R>) -> std::io::Result<Self>
+    pub fn from_decoder<R>(mut decoder: MultiGzMemberDecoder<R>) -> std::io::Result<Self>
     where
         R: Read + Send + 'static, {
         let (reader, mut writer) =
@@ -68,7 +133,7 @@ impl GzipReaderInThread {
     pub fn from_readable<R>(readable: R) -> std::io::Result<Self>
     where
         R: Read + Send + 'static, {
-        Self::from_decoder(MultiGzDecoder::new(readable))
+        Self::from_decoder(MultiGzMemberDecoder::new(readable))
     }
 
     /// Creates a new [`GzipReaderInThread`] from a path.
@@ -151,7 +216,7 @@ define_whichever! {
         /// A regular uncompressed file.
         File(File),
         /// A gzip compressed file, using lazy decoding.
-        Zipped(MultiGzDecoder<File>),
+        Zipped(MultiGzMemberDecoder<File>),
     }
 
     impl Read for ReadFileZip {}
@@ -169,7 +234,7 @@ impl ReadFileZip {
         let file = File::open(&path)?;
 
         if is_gz(path) {
-            Ok(Self::Zipped(MultiGzDecoder::new(file)))
+            Ok(Self::Zipped(MultiGzMemberDecoder::new(file)))
         } else {
             Ok(Self::File(file))
         }
@@ -220,6 +285,57 @@ impl ReadFileZipInThread {
     }
 }
 
+/// A reader that writes an exact copy of every byte it reads to a file, such
+/// as for archiving the (decompressed) bytes of an input stream that cannot be
+/// read again.
+///
+/// If no copy was requested, this passes reads through unchanged. The copy
+/// holds only the bytes that were read, so it is complete only if the input is
+/// read in its entirety. It is flushed when the end of the input is reached.
+pub struct TeeReader<R> {
+    reader: R,
+    copy:   Option<(BufWriter<File>, PathBuf)>,
+}
+
+impl<R: Read> TeeReader<R> {
+    /// Creates a new [`TeeReader`], writing the copy to `path` if provided. Any
+    /// existing file at `path` is truncated.
+    ///
+    /// ## Errors
+    ///
+    /// Any IO errors when creating the file for the copy are propagated with
+    /// context.
+    pub fn new(reader: R, path: Option<&Path>) -> std::io::Result<Self> {
+        let copy = match path {
+            Some(path) => {
+                let file =
+                    File::create(path).with_path_context("Failed to create the file for the copy of the input", path)?;
+                Some((BufWriter::new(file), path.to_path_buf()))
+            }
+            None => None,
+        };
+
+        Ok(Self { reader, copy })
+    }
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+
+        if let Some((writer, path)) = &mut self.copy {
+            let written = if bytes_read == 0 && !buf.is_empty() {
+                writer.flush()
+            } else {
+                writer.write_all(&buf[..bytes_read])
+            };
+            written.with_path_context("Failed to write the copy of the input", path)?;
+        }
+
+        Ok(bytes_read)
+    }
+}
+
 /// Readers for a set of possibly-paired records.
 ///
 /// This stores a single reader, and an optional second reader for paired reads.
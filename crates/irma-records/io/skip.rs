@@ -0,0 +1,440 @@
+use crate::io::{IterWithContext, add_item_context};
+use std::io::{BufRead, BufReader, ErrorKind};
+use zoe::{
+    data::fasta::FastaSeq,
+    prelude::{FastQ, FastQReader, FastaReader, Nucleotides, QualityScores},
+};
+
+/// A trait for readers that can advance over records without parsing them.
+pub trait SkipRecords {
+    /// Advances over up to `n` records without parsing them or allocating
+    /// buffers for them, returning the number of records skipped. Fewer than
+    /// `n` are skipped only if the end of the input is reached.
+    ///
+    /// Skipped records are only checked for their overall structure (such as
+    /// the number of lines in a FASTQ record), so malformed headers, sequences,
+    /// or quality scores are not detected. Passing [`usize::MAX`] counts the
+    /// remaining records.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors are propagated. If the input ends partway through a record,
+    /// the complete records before it are skipped, and the error is returned by
+    /// the next call (or immediately if there are none).
+    fn skip_records(&mut self, n: usize) -> std::io::Result<usize>;
+
+    /// Skips all remaining records, returning the number skipped.
+    ///
+    /// ## Errors
+    ///
+    /// Unlike [`skip_records`], an error for a partial record at the end of the
+    /// input is returned.
+    ///
+    /// [`skip_records`]: SkipRecords::skip_records
+    fn skip_remaining(&mut self) -> std::io::Result<usize> {
+        let skipped = self.skip_records(usize::MAX)?;
+        // At the end of the input, this only surfaces a pending error
+        self.skip_records(1)?;
+        Ok(skipped)
+    }
+}
+
+/// Consumes bytes from `reader` until `n` occurrences of `byte` have been
+/// consumed or the end of the input is reached, returning the number of
+/// occurrences consumed and whether any bytes followed the last of them at the
+/// end of the input.
+///
+/// The occurrences in each buffer are counted before being located, which the
+/// compiler vectorizes, so that only the buffer containing the last occurrence
+/// is scanned byte by byte.
+fn consume_occurrences<R: BufRead>(reader: &mut R, byte: u8, n: usize) -> std::io::Result<(usize, bool)> {
+    let mut found = 0;
+    let mut trailing = false;
+
+    while found < n {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok((found, trailing));
+        }
+
+        let remaining = n - found;
+        let in_buffer = buffer.iter().filter(|&&b| b == byte).count();
+        if in_buffer < remaining {
+            found += in_buffer;
+            trailing = buffer.last() != Some(&byte);
+            let len = buffer.len();
+            reader.consume(len);
+        } else {
+            // Validity: the buffer contains at least `remaining` occurrences
+            let end = buffer
+                .iter()
+                .enumerate()
+                .filter(|&(_, &b)| b == byte)
+                .nth(remaining - 1)
+                .map_or(buffer.len() - 1, |(i, _)| i);
+            reader.consume(end + 1);
+            found = n;
+        }
+    }
+
+    Ok((found, false))
+}
+
+/// Appends bytes from `reader` to `buf` up to, but not including, the next
+/// occurrence of `byte` (or the end of the input).
+fn read_until_before<R: BufRead>(reader: &mut R, byte: u8, buf: &mut Vec<u8>) -> std::io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(i) = buffer.iter().position(|&b| b == byte) {
+            buf.extend_from_slice(&buffer[..i]);
+            reader.consume(i);
+            return Ok(());
+        }
+
+        buf.extend_from_slice(buffer);
+        let len = buffer.len();
+        reader.consume(len);
+    }
+}
+
+/// Consumes bytes from `reader` up to, but not including, the next occurrence
+/// of `byte` (or the end of the input).
+fn consume_until_before<R: BufRead>(reader: &mut R, byte: u8) -> std::io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(i) = buffer.iter().position(|&b| b == byte) {
+            reader.consume(i);
+            return Ok(());
+        }
+
+        let len = buffer.len();
+        reader.consume(len);
+    }
+}
+
+/// A FASTQ reader which can skip records with [`SkipRecords`], rather than
+/// parsing them, making [`Iterator::nth`] cheap.
+///
+/// The records that are yielded are parsed by [`FastQReader`], so they are
+/// validated in the same way. Like [`FastQReader`], multiline FASTQ is not
+/// supported.
+pub struct FastQSkipReader<R: BufRead> {
+    reader:    R,
+    record:    Vec<u8>,
+    /// Whether a skip reached a partial record at the end of the input
+    truncated: bool,
+}
+
+impl<R: BufRead> FastQSkipReader<R> {
+    /// Creates a [`FastQSkipReader`] from a buffered reader positioned at the
+    /// start of a record.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            record: Vec::new(),
+            truncated: false,
+        }
+    }
+
+    /// The error for a partial record at the end of the input.
+    fn truncated_error() -> std::io::Error {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            "The FASTQ data ended partway through a record! Ensure that the FASTQ file is not multi-line.",
+        )
+    }
+}
+
+impl<R: BufRead> SkipRecords for FastQSkipReader<R> {
+    fn skip_records(&mut self, n: usize) -> std::io::Result<usize> {
+        if self.truncated {
+            return Err(Self::truncated_error());
+        }
+
+        let (lines, trailing) = consume_occurrences(&mut self.reader, b'\n', n.saturating_mul(4))?;
+        let lines = lines + usize::from(trailing);
+        let skipped = lines / 4;
+
+        if !lines.is_multiple_of(4) {
+            if skipped == 0 {
+                return Err(Self::truncated_error());
+            }
+            self.truncated = true;
+        }
+
+        Ok(skipped)
+    }
+}
+
+impl<R: BufRead> Iterator for FastQSkipReader<R> {
+    type Item = std::io::Result<FastQ>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.truncated {
+            return Some(Err(Self::truncated_error()));
+        }
+
+        self.record.clear();
+        for _ in 0..4 {
+            if let Err(e) = self.reader.read_until(b'\n', &mut self.record) {
+                return Some(Err(e));
+            }
+        }
+
+        if self.record.is_empty() {
+            return None;
+        }
+
+        let reader = BufReader::with_capacity(self.record.len(), self.record.as_slice());
+        match FastQReader::from_bufreader(reader) {
+            Ok(mut reader) => reader.next(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self.skip_records(n) {
+            Ok(skipped) if skipped == n => self.next(),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// A FASTQ reader which reads the four lines of each record before parsing
+/// them, so that a structurally invalid record (such as one whose sequence and
+/// quality scores differ in length) never leaves the reader partway through
+/// it. With `skip_bad_records`, such records are skipped and counted rather
+/// than yielded as errors.
+///
+/// Records are validated in the same way as [`FastQReader`], and multiline
+/// FASTQ is not supported. IO errors, and an input ending partway through a
+/// record, are always yielded as errors.
+pub struct TolerantFastQReader<R: BufRead> {
+    reader:           R,
+    skip_bad_records: bool,
+    /// The lines of the current record, without their line breaks
+    lines:            [Vec<u8>; 4],
+    /// The number of records read, including those skipped
+    records:          usize,
+    /// The number of invalid records skipped
+    skipped:          usize,
+    /// The 1-based number and error of the first record skipped
+    first_skipped:    Option<(usize, std::io::Error)>,
+}
+
+impl<R: BufRead> TolerantFastQReader<R> {
+    /// Creates a [`TolerantFastQReader`] from a buffered reader positioned at
+    /// the start of a record.
+    pub fn new(reader: R, skip_bad_records: bool) -> Self {
+        Self {
+            reader,
+            skip_bad_records,
+            lines: Default::default(),
+            records: 0,
+            skipped: 0,
+            first_skipped: None,
+        }
+    }
+
+    /// The number of invalid records skipped so far.
+    #[inline]
+    #[must_use]
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// The 1-based record number and error of the first invalid record
+    /// skipped, if any.
+    #[inline]
+    #[must_use]
+    pub fn first_skipped(&self) -> Option<(usize, &std::io::Error)> {
+        self.first_skipped.as_ref().map(|(record, e)| (*record, e))
+    }
+
+    /// Reads the four lines of the next record, returning `false` at the end
+    /// of the input.
+    ///
+    /// ## Errors
+    ///
+    /// IO errors are propagated, and an error is returned if the input ends
+    /// partway through a record.
+    fn read_lines(&mut self) -> std::io::Result<bool> {
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            line.clear();
+            if self.reader.read_until(b'\n', line)? == 0 {
+                return if i == 0 {
+                    Ok(false)
+                } else {
+                    Err(FastQSkipReader::<R>::truncated_error())
+                };
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Parses the lines of the current record, with the same checks as
+    /// [`FastQReader`].
+    fn parse_record(&self) -> std::io::Result<FastQ> {
+        let invalid = |msg: String| std::io::Error::new(ErrorKind::InvalidData, msg);
+        let [header, sequence, plus, quality] = &self.lines;
+
+        let Some(header) = header.strip_prefix(b"@") else {
+            return Err(invalid(
+                "Missing '@' symbol at header line beginning! Ensure that the FASTQ file is not multi-line.".to_string(),
+            ));
+        };
+        if header.is_empty() {
+            return Err(invalid("Missing FASTQ header!".to_string()));
+        }
+        let header = String::from_utf8(header.to_vec()).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+
+        if sequence.is_empty() {
+            return Err(invalid(format!("Missing FASTQ sequence! See header: {header}")));
+        }
+        if !plus.starts_with(b"+") {
+            return Err(invalid(format!(
+                "Missing '+' line! Ensure that the FASTQ file is not multi-line. See header: {header}"
+            )));
+        }
+        if quality.is_empty() {
+            return Err(invalid(format!("Missing FASTQ quality scores! See header: {header}")));
+        }
+        if quality.len() != sequence.len() {
+            return Err(invalid(format!(
+                "Sequence and quality score length mismatch ({s} ≠ {q})! See: {header}",
+                s = sequence.len(),
+                q = quality.len(),
+            )));
+        }
+        let quality =
+            QualityScores::try_from(quality.as_slice()).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(FastQ {
+            header,
+            sequence: Nucleotides::from(sequence.clone()),
+            quality,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for TolerantFastQReader<R> {
+    type Item = std::io::Result<FastQ>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.read_lines() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+            self.records += 1;
+
+            match self.parse_record() {
+                Err(e) if self.skip_bad_records => {
+                    self.skipped += 1;
+                    self.first_skipped.get_or_insert((self.records, e));
+                }
+                result => return Some(result),
+            }
+        }
+    }
+}
+
+/// A FASTA reader which can skip records with [`SkipRecords`], rather than
+/// parsing them, making [`Iterator::nth`] cheap. Records are delimited by `>`.
+///
+/// The records that are yielded are parsed by [`FastaReader`], so they are
+/// validated in the same way.
+pub struct FastaSkipReader<R: BufRead> {
+    reader: R,
+    record: Vec<u8>,
+}
+
+impl<R: BufRead> FastaSkipReader<R> {
+    /// Creates a [`FastaSkipReader`] from a buffered reader positioned at the
+    /// start of a record (or at whitespace preceding the first record).
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            record: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> SkipRecords for FastaSkipReader<R> {
+    fn skip_records(&mut self, n: usize) -> std::io::Result<usize> {
+        if n == 0 {
+            return Ok(0);
+        }
+
+        // Passing the `>` of a record means it is skipped, once the rest of it
+        // is consumed
+        let (skipped, _) = consume_occurrences(&mut self.reader, b'>', n)?;
+        consume_until_before(&mut self.reader, b'>')?;
+        Ok(skipped)
+    }
+}
+
+impl<R: BufRead> Iterator for FastaSkipReader<R> {
+    type Item = std::io::Result<FastaSeq>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record.clear();
+
+        // Any whitespace before the first record is kept, since FastaReader
+        // skips it
+        match self.reader.read_until(b'>', &mut self.record) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        if let Err(e) = read_until_before(&mut self.reader, b'>', &mut self.record) {
+            return Some(Err(e));
+        }
+
+        if !self.record.ends_with(b"\n") && self.reader.fill_buf().is_ok_and(|buffer| !buffer.is_empty()) {
+            return Some(Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "FASTA records must start with the '>' symbol on a newline, and no other '>' symbols can occur in a header or sequence!",
+            )));
+        }
+
+        let reader = BufReader::with_capacity(self.record.len(), self.record.as_slice());
+        match FastaReader::from_bufreader(reader) {
+            Ok(mut reader) => reader.next(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self.skip_records(n) {
+            Ok(skipped) if skipped == n => self.next(),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<I: SkipRecords> SkipRecords for IterWithContext<I> {
+    fn skip_records(&mut self, n: usize) -> std::io::Result<usize> {
+        // An error occurs partway through the record after those yielded so far
+        let skipped = add_item_context(self.iter.skip_records(n), &self.description, self.records + 1)?;
+        self.records += skipped;
+        Ok(skipped)
+    }
+}
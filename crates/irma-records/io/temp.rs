@@ -0,0 +1,295 @@
+//! Temporary files and directories for scratch space, such as spills from an
+//! external sort, checkpoints, or outputs written atomically.
+//!
+//! Temporary paths are created by a [`TempManager`], either beside the output
+//! they belong to or in the directory given with [`set_temp_dir`]. Each path is
+//! registered until it is dropped or persisted, so that [`cleanup_temp_files`]
+//! can remove any left behind when the process panics or exits early.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, Once, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use zoe::data::err::ResultWithErrorContext;
+
+/// The directory for temporary paths, overriding the directory of the output.
+static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The temporary paths which have not yet been removed or persisted.
+static REGISTERED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// The number of temporary paths created by this process, for unique names.
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Ensures the panic hook is only installed once.
+static INSTALL_HOOK: Once = Once::new();
+
+/// Places all temporary paths in `dir` for the rest of the process, rather
+/// than beside their outputs, such as for `--tmpdir`. Only the first call has
+/// an effect.
+///
+/// ## Errors
+///
+/// `dir` must be an existing directory.
+pub fn set_temp_dir(dir: impl Into<PathBuf>) -> std::io::Result<()> {
+    let dir = dir.into();
+    if !dir.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("The temporary directory does not exist: '{}'", dir.display()),
+        ));
+    }
+    let _ = TEMP_DIR.set(dir);
+    Ok(())
+}
+
+/// Removes any temporary paths which have not been dropped or persisted. This
+/// is called by the panic hook installed with [`install_cleanup_hook`], and
+/// should be called before exiting with [`std::process::exit`], which does not
+/// run destructors. Failures are ignored, since cleanup is best effort.
+pub fn cleanup_temp_files() {
+    let paths = match REGISTERED.lock() {
+        Ok(mut registered) => std::mem::take(&mut *registered),
+        // A poisoned lock only means another thread panicked mid-push, which
+        // leaves the list intact
+        Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+    };
+
+    for path in paths {
+        if path.is_dir() {
+            let _ = std::fs::remove_dir_all(&path);
+        } else {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+/// Installs a panic hook removing any temporary paths (with
+/// [`cleanup_temp_files`]) before running the previous hook. Installing the
+/// hook more than once has no effect.
+pub fn install_cleanup_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            cleanup_temp_files();
+            previous(info);
+        }));
+    });
+}
+
+/// Registers a temporary path for cleanup.
+fn register(path: &Path) {
+    let mut registered = REGISTERED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    registered.push(path.to_path_buf());
+}
+
+/// Removes a temporary path from those cleaned up, once it has been removed or
+/// persisted.
+fn deregister(path: &Path) {
+    let mut registered = REGISTERED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(i) = registered.iter().position(|registered| registered == path) {
+        registered.swap_remove(i);
+    }
+}
+
+/// Creates namespaced temporary files and directories for a subsystem, such as
+/// `sort` or `checkpoint`.
+///
+/// Each path is named `.{output}.{namespace}.{pid}.{n}.tmp` after the output it
+/// belongs to, and is placed beside it unless [`set_temp_dir`] was called.
+#[derive(Clone, Debug)]
+pub struct TempManager {
+    namespace: String,
+}
+
+impl TempManager {
+    /// Creates a manager naming its temporary paths with `namespace`.
+    pub fn new(namespace: impl Into<String>) -> Self {
+        TempManager {
+            namespace: namespace.into(),
+        }
+    }
+
+    /// Returns the directory in which the temporary paths for `output` are
+    /// placed.
+    pub fn dir_for(&self, output: &Path) -> PathBuf {
+        match TEMP_DIR.get() {
+            Some(dir) => dir.clone(),
+            None => match output.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+        }
+    }
+
+    /// Returns a new, unique temporary path for `output`.
+    fn next_path(&self, output: &Path) -> PathBuf {
+        let name = output
+            .file_name()
+            .map_or_else(|| "output".into(), |name| name.to_string_lossy());
+        let n = CREATED.fetch_add(1, Ordering::Relaxed);
+        self.dir_for(output).join(format!(
+            ".{name}.{namespace}.{pid}.{n}.tmp",
+            namespace = self.namespace,
+            pid = std::process::id()
+        ))
+    }
+
+    /// Creates a temporary file for `output`, which is removed when dropped
+    /// unless it is persisted with [`TempFile::persist`].
+    ///
+    /// ## Errors
+    ///
+    /// Errors opening the file are returned with its path as context.
+    pub fn create_file(&self, output: &Path) -> std::io::Result<TempFile> {
+        let path = self.next_path(output);
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_path_context("Failed to create temporary file", &path)?;
+        register(&path);
+        Ok(TempFile { path, file: Some(file) })
+    }
+
+    /// Creates a temporary directory for `output`, which is removed along with
+    /// its contents when dropped.
+    ///
+    /// ## Errors
+    ///
+    /// Errors creating the directory are returned with its path as context.
+    pub fn create_dir(&self, output: &Path) -> std::io::Result<TempDir> {
+        let path = self.next_path(output);
+        std::fs::create_dir(&path).with_path_context("Failed to create temporary directory", &path)?;
+        register(&path);
+        Ok(TempDir { path })
+    }
+
+    /// Checks that at least `bytes` are available in the directory of the
+    /// temporary paths for `output`, before a large spill.
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if fewer bytes are available. The check always
+    /// passes if the available space cannot be determined, such as on
+    /// platforms other than Unix.
+    pub fn check_space(&self, output: &Path, bytes: u64) -> std::io::Result<()> {
+        let dir = self.dir_for(output);
+        match available_space(&dir) {
+            Some(available) if available < bytes => Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!(
+                    "Only {available} bytes are available for {bytes} bytes of temporary files in: '{}'",
+                    dir.display()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A temporary file created by a [`TempManager`], which is removed when dropped
+/// unless persisted.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    /// The open file, which is only `None` once persisted
+    file: Option<File>,
+}
+
+impl TempFile {
+    /// Returns the path of the temporary file.
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the open temporary file.
+    #[inline]
+    pub fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("The file is only taken when persisted")
+    }
+
+    /// Closes the temporary file and moves it to `output`, replacing any
+    /// existing file. When the temporary file is beside `output`, this is
+    /// atomic.
+    ///
+    /// ## Errors
+    ///
+    /// Errors syncing or renaming the file are returned with the path of the
+    /// output as context, in which case the temporary file is removed.
+    pub fn persist(mut self, output: &Path) -> std::io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()
+                .with_path_context("Failed to sync temporary file", &self.path)?;
+        }
+        std::fs::rename(&self.path, output).with_path_context("Failed to move temporary file to", output)?;
+        deregister(&self.path);
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Once persisted, the file no longer exists at this path
+        let _ = std::fs::remove_file(&self.path);
+        deregister(&self.path);
+    }
+}
+
+/// A temporary directory created by a [`TempManager`], which is removed along
+/// with its contents when dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Returns the path of the temporary directory.
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+        deregister(&self.path);
+    }
+}
+
+/// The number of bytes available to unprivileged users on the file system
+/// containing `dir`, or `None` if it cannot be determined.
+fn available_space(dir: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+        let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: `path` is a valid C string, and `stats` is only read once
+        // `statvfs` succeeds and has initialized it
+        let stats = unsafe {
+            if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+                return None;
+            }
+            stats.assume_init()
+        };
+        #[allow(clippy::unnecessary_cast)]
+        Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        None
+    }
+}
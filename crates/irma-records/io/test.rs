@@ -0,0 +1,310 @@
+use crate::io::{
+    BGZF_BLOCK_SIZE, BGZF_EOF, BgzfWriter, FastQSkipReader, FastaSkipReader, GzipFormat, IterWithErrorContext,
+    MultiGzMemberDecoder, ParallelGzWriter, SkipRecords, TeeReader, TempManager, TolerantFastQReader, records_read,
+    set_record_hook,
+};
+use flate2::{Compression, write::GzEncoder};
+use std::{
+    cell::RefCell,
+    io::{BufReader, Read, Write},
+    num::NonZeroUsize,
+};
+use zoe::prelude::{FastQReader, FastaReader};
+
+static FASTQ: &[u8] = b"@r1\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIIII\n@r3\nTTAA\n+\nIIII\n@r4\nCATG\n+\nIIII";
+
+static FASTA: &[u8] = b"\n>s1 first\nACGT\nAC\n>s2\nGG\n>s3\nTTAA\nTT\nT\n>s4\nCATG\n";
+
+/// Buffer capacities for the readers, where the small ones split records (and
+/// line breaks) across buffers.
+static CAPACITIES: [usize; 4] = [1, 3, 7, 8192];
+
+#[test]
+fn test_fastq_skip_matches_parsing() {
+    let expected = FastQReader::new(FASTQ).collect::<std::io::Result<Vec<_>>>().unwrap();
+
+    for capacity in CAPACITIES {
+        for n in 0..expected.len() {
+            let mut reader = FastQSkipReader::new(BufReader::with_capacity(capacity, FASTQ));
+            assert_eq!(reader.nth(n).unwrap().unwrap(), expected[n], "capacity {capacity}, n {n}");
+            assert_eq!(reader.skip_records(usize::MAX).unwrap(), expected.len() - n - 1);
+        }
+
+        let mut reader = FastQSkipReader::new(BufReader::with_capacity(capacity, FASTQ));
+        assert_eq!(reader.skip_records(1).unwrap(), 1);
+        assert_eq!(reader.next().unwrap().unwrap(), expected[1]);
+        assert_eq!(reader.nth(1).unwrap().unwrap(), expected[3]);
+        assert!(reader.next().is_none());
+        assert!(reader.nth(1).is_none());
+    }
+}
+
+#[test]
+fn test_fastq_skip_truncated() {
+    let truncated = &FASTQ[..FASTQ.len() - 6];
+    let mut reader = FastQSkipReader::new(BufReader::with_capacity(5, truncated));
+    assert_eq!(reader.skip_records(usize::MAX).unwrap(), 3);
+    assert!(reader.skip_records(usize::MAX).is_err());
+
+    let mut reader = FastQSkipReader::new(BufReader::with_capacity(5, truncated));
+    assert!(reader.skip_remaining().is_err());
+
+    let mut reader = FastQSkipReader::new(BufReader::with_capacity(5, &truncated[48..]));
+    assert!(reader.skip_records(1).is_err());
+
+    let mut reader = FastQSkipReader::new(BufReader::with_capacity(5, truncated));
+    assert_eq!(reader.skip_records(3).unwrap(), 3);
+    assert!(reader.next().unwrap().is_err());
+}
+
+#[test]
+fn test_tolerant_fastq_matches_parsing() {
+    let expected = FastQReader::new(FASTQ).collect::<std::io::Result<Vec<_>>>().unwrap();
+
+    for skip_bad_records in [false, true] {
+        for capacity in CAPACITIES {
+            let reader = TolerantFastQReader::new(BufReader::with_capacity(capacity, FASTQ), skip_bad_records);
+            assert_eq!(reader.collect::<std::io::Result<Vec<_>>>().unwrap(), expected);
+        }
+    }
+
+    let crlf = b"@r1\r\nACGT\r\n+\r\nIIII\r\n";
+    let mut reader = TolerantFastQReader::new(&crlf[..], false);
+    assert_eq!(reader.next().unwrap().unwrap(), expected[0]);
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn test_tolerant_fastq_bad_records() {
+    let bad = b"@r1\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIII\n@r3\nTTAA\n+\nIIII\nr4\nCATG\n+\nIIII\n@r5\nCATG\n+\nIIII";
+
+    let mut reader = TolerantFastQReader::new(&bad[..], false);
+    assert_eq!(reader.next().unwrap().unwrap().header, "r1");
+    assert!(reader.next().unwrap().is_err());
+    assert_eq!(reader.next().unwrap().unwrap().header, "r3");
+    assert_eq!(reader.skipped(), 0);
+
+    let mut reader = TolerantFastQReader::new(&bad[..], true);
+    let headers = reader
+        .by_ref()
+        .map(|record| record.map(|r| r.header))
+        .collect::<std::io::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(headers, ["r1", "r3", "r5"]);
+    assert_eq!(reader.skipped(), 2);
+    assert_eq!(reader.first_skipped().unwrap().0, 2);
+}
+
+#[test]
+fn test_tolerant_fastq_truncated() {
+    let truncated = &FASTQ[..FASTQ.len() - 6];
+    let reader = TolerantFastQReader::new(truncated, true);
+    let records = reader.collect::<Vec<_>>();
+    assert_eq!(records.len(), 4);
+    assert!(records[3].is_err());
+}
+
+#[test]
+fn test_fasta_skip_matches_parsing() {
+    let expected = FastaReader::new(FASTA).collect::<std::io::Result<Vec<_>>>().unwrap();
+
+    for capacity in CAPACITIES {
+        for n in 0..expected.len() {
+            let mut reader = FastaSkipReader::new(BufReader::with_capacity(capacity, FASTA));
+            assert_eq!(reader.nth(n).unwrap().unwrap(), expected[n], "capacity {capacity}, n {n}");
+            assert_eq!(reader.skip_records(usize::MAX).unwrap(), expected.len() - n - 1);
+        }
+
+        let mut reader = FastaSkipReader::new(BufReader::with_capacity(capacity, FASTA));
+        assert_eq!(reader.skip_records(usize::MAX).unwrap(), expected.len());
+        assert!(reader.next().is_none());
+    }
+}
+
+#[test]
+fn test_fasta_misplaced_symbol() {
+    let mut reader = FastaSkipReader::new(b">s1\nAC>GT\n>s2\nGG\n".as_slice());
+    assert!(reader.next().unwrap().is_err());
+}
+
+#[test]
+fn test_tee_reader_copies_records() {
+    let path = std::env::temp_dir().join(format!("irma-records-tee-{}.fq", std::process::id()));
+
+    let tee = TeeReader::new(FASTQ, Some(&path)).unwrap();
+    let records = FastQReader::from_readable(tee)
+        .unwrap()
+        .collect::<std::io::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(records, FastQReader::new(FASTQ).collect::<std::io::Result<Vec<_>>>().unwrap());
+    assert_eq!(std::fs::read(&path).unwrap(), FASTQ);
+    std::fs::remove_file(&path).unwrap();
+
+    let mut copied = Vec::new();
+    TeeReader::new(FASTQ, None).unwrap().read_to_end(&mut copied).unwrap();
+    assert_eq!(copied, FASTQ);
+}
+
+/// Compresses each chunk as its own gzip member, concatenating the members.
+fn gzip_members(chunks: &[&[u8]]) -> Vec<u8> {
+    let mut members = Vec::new();
+    for chunk in chunks {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(chunk).unwrap();
+        members.extend(encoder.finish().unwrap());
+    }
+    members
+}
+
+#[test]
+fn test_multi_gz_members_decoded() {
+    let (first, rest) = FASTQ.split_at(16);
+    let (second, third) = rest.split_at(16);
+    let members = gzip_members(&[first, second, third]);
+
+    let mut decoder = MultiGzMemberDecoder::new(members.as_slice());
+    let mut decoded = Vec::new();
+    decoder.read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, FASTQ);
+    assert_eq!(decoder.member(), 3);
+}
+
+#[test]
+fn test_multi_gz_member_in_error() {
+    let mut members = gzip_members(&[&FASTQ[..16], &FASTQ[16..]]);
+    let second_start = gzip_members(&[&FASTQ[..16]]).len();
+    members.truncate(second_start + 15);
+
+    let mut decoder = MultiGzMemberDecoder::new(members.as_slice());
+    let error = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+    assert!(error.to_string().contains("in gzip member #2"), "{error}");
+}
+
+#[test]
+fn test_bgzf_blocks_decoded() {
+    let data = FASTQ.repeat(BGZF_BLOCK_SIZE / FASTQ.len() * 2 + 1);
+    let mut writer = BgzfWriter::new(Vec::new(), Compression::default());
+    writer.write_all(&data).unwrap();
+    let bgzf = writer.finish().unwrap();
+    assert!(bgzf.ends_with(&BGZF_EOF));
+
+    // Each block records its size, so the blocks can be walked
+    let mut offset = 0;
+    let mut blocks = 0;
+    while offset < bgzf.len() {
+        assert_eq!(&bgzf[offset + 12..offset + 14], b"BC");
+        offset += usize::from(u16::from_le_bytes([bgzf[offset + 16], bgzf[offset + 17]])) + 1;
+        blocks += 1;
+    }
+    assert_eq!(offset, bgzf.len());
+    assert_eq!(blocks, data.len().div_ceil(BGZF_BLOCK_SIZE) + 1);
+
+    let mut decoder = MultiGzMemberDecoder::new(bgzf.as_slice());
+    let mut decoded = Vec::new();
+    decoder.read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, data);
+}
+
+#[test]
+fn test_bgzf_eof_written_on_drop() {
+    let mut bgzf = Vec::new();
+    drop(BgzfWriter::new(&mut bgzf, Compression::default()));
+    assert_eq!(bgzf, BGZF_EOF);
+}
+
+#[test]
+fn test_parallel_gz_decoded() {
+    // Enough data for several chunks, so that they are compressed out of order
+    let data = FASTQ.repeat(BGZF_BLOCK_SIZE / FASTQ.len() * 40 + 1);
+    let threads = NonZeroUsize::new(3).unwrap();
+
+    for format in [GzipFormat::Gzip, GzipFormat::Bgzf] {
+        let mut writer = ParallelGzWriter::new(Vec::new(), format, Compression::fast(), threads).unwrap();
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        assert_eq!(compressed.ends_with(&BGZF_EOF), format == GzipFormat::Bgzf);
+
+        let mut decoder = MultiGzMemberDecoder::new(compressed.as_slice());
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
+
+#[test]
+fn test_parallel_bgzf_matches_bgzf_writer() {
+    let data = FASTQ.repeat(BGZF_BLOCK_SIZE / FASTQ.len() * 20 + 1);
+
+    let mut writer = BgzfWriter::new(Vec::new(), Compression::default());
+    writer.write_all(&data).unwrap();
+    let expected = writer.finish().unwrap();
+
+    let threads = NonZeroUsize::new(2).unwrap();
+    let mut writer = ParallelGzWriter::new(Vec::new(), GzipFormat::Bgzf, Compression::default(), threads).unwrap();
+    writer.write_all(&data).unwrap();
+    assert_eq!(writer.finish().unwrap(), expected);
+}
+
+#[test]
+fn test_temp_file_removed_unless_persisted() {
+    let dir = std::env::temp_dir();
+    let output = dir.join(format!("irma-records-temp-{}.fq", std::process::id()));
+    let manager = TempManager::new("test");
+
+    let mut temp = manager.create_file(&output).unwrap();
+    let temp_path = temp.path().to_path_buf();
+    assert_eq!(temp_path.parent(), Some(dir.as_path()));
+    temp.file().write_all(FASTQ).unwrap();
+    drop(temp);
+    assert!(!temp_path.exists());
+
+    let mut temp = manager.create_file(&output).unwrap();
+    let temp_path = temp.path().to_path_buf();
+    temp.file().write_all(FASTQ).unwrap();
+    temp.persist(&output).unwrap();
+    assert!(!temp_path.exists());
+    assert_eq!(std::fs::read(&output).unwrap(), FASTQ);
+    std::fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_temp_dir_cleaned_up() {
+    let output = std::env::temp_dir().join(format!("irma-records-temp-dir-{}.fq", std::process::id()));
+    let manager = TempManager::new("test");
+
+    let temp = manager.create_dir(&output).unwrap();
+    let temp_path = temp.path().to_path_buf();
+    std::fs::write(temp_path.join("spill"), FASTQ).unwrap();
+    drop(temp);
+    assert!(!temp_path.exists());
+
+    assert!(manager.check_space(&output, 0).is_ok());
+    assert!(manager.check_space(&output, u64::MAX).is_err());
+}
+
+#[test]
+fn test_records_read_counted() {
+    let before = records_read();
+    let n = FastQReader::new(FASTQ).iter_with_context("test").count();
+    // Other tests may read records concurrently
+    assert!(records_read() >= before + n as u64);
+}
+
+thread_local! {
+    static NOTED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+#[test]
+fn test_record_hook() {
+    set_record_hook(|id| NOTED.with_borrow_mut(|noted| noted.push(id.to_string())));
+
+    // Records are noted whether they are yielded one at a time or folded
+    let mut reader = FastaReader::new(FASTA).iter_with_context("test");
+    assert!(reader.next().is_some());
+    reader.for_each(drop);
+
+    // Only records read on this thread are noted
+    NOTED.with_borrow(|noted| assert_eq!(noted, &["s1", "s2", "s3", "s4"]));
+}
@@ -1,24 +1,92 @@
-use crate::io::{WriterWithContext, WriterWithErrorContext, is_gz};
+use crate::io::{BgzfWriter, ParallelGzWriter, WriterWithContext, WriterWithErrorContext, is_bgz, is_gz};
 use flate2::{Compression, write::GzEncoder};
 use std::{
     fs::File,
-    io::{BufWriter, Stdout, Write, stdout},
+    io::{BufWriter, LineWriter, Stdout, Write, stdout},
+    num::NonZeroUsize,
     path::Path,
 };
 use zoe::define_whichever;
 
+/// The largest capacity of the [`BufWriter`] used for an output that is a
+/// named pipe (FIFO), including process substitutions and stdout piped to
+/// another process, with [`OutputBuffering::Block`]. This is the size up to
+/// which writes to a pipe are atomic on Linux (`PIPE_BUF`), so that the
+/// consumer receives records promptly rather than in large bursts.
+pub const FIFO_CAPACITY: usize = 4096;
+
+/// How the uncompressed outputs of a [`WriteFileZipStdout`] are buffered.
+/// Compressed outputs are always block buffered, since partial gzip members
+/// cannot be consumed line by line.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum OutputBuffering {
+    /// Writes are collected in a [`BufWriter`] and written once it fills. If
+    /// the output is a named pipe, its capacity is limited to
+    /// [`FIFO_CAPACITY`].
+    #[default]
+    Block,
+    /// The output is flushed at the end of each line.
+    Line,
+    /// Each write is passed directly to the output. Stdout remains line
+    /// buffered by the standard library.
+    Unbuffered,
+}
+
+/// How the compressed outputs of a [`WriteFileZipStdout`] are written. Paths
+/// ending in `.bgz` are always written as BGZF.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum GzipFormat {
+    /// A single gzip member.
+    #[default]
+    Gzip,
+    /// Blocked gzip (see [`BgzfWriter`]), which can be indexed by htslib tools.
+    Bgzf,
+}
+
+/// How the compressed outputs of a [`WriteFileZipStdout`] are encoded.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CompressionOptions {
+    /// The format of the compressed output.
+    pub format:  GzipFormat,
+    /// The compression level, from 0 (no compression) to 9 (smallest output).
+    pub level:   Compression,
+    /// The number of threads used to compress the output. With more than one,
+    /// a [`ParallelGzWriter`] is used.
+    pub threads: NonZeroUsize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            format:  GzipFormat::default(),
+            level:   Compression::default(),
+            threads: NonZeroUsize::MIN,
+        }
+    }
+}
+
 define_whichever! {
-    /// An enum for the different acceptable output types. A [`BufWriter`] is
-    /// used for all variants, and all variants are wrapped in
-    /// [`WriterWithContext`] to add context to write errors.
+    /// An enum for the different acceptable output types. All variants are
+    /// buffered (see [`OutputBuffering`]) and wrapped in [`WriterWithContext`]
+    /// to add context to write errors.
     #[derive(Debug)]
     pub enum WriteFileZipStdout {
         /// A writer for a regular uncompressed file.
         File(WriterWithContext<BufWriter<File>>),
+        /// A writer for an uncompressed file which is flushed after each line.
+        FileLines(WriterWithContext<LineWriter<File>>),
         /// A writer for a gzip compressed file.
         Zipped(WriterWithContext<GzEncoder<BufWriter<File>>>),
+        /// A writer for a BGZF (blocked gzip) compressed file.
+        Bgzf(WriterWithContext<BgzfWriter<BufWriter<File>>>),
+        /// A writer for a gzip or BGZF compressed file which is compressed on
+        /// several threads.
+        ZippedParallel(WriterWithContext<ParallelGzWriter<BufWriter<File>>>),
         /// A writer for uncompressed data to stdout.
         Stdout(WriterWithContext<BufWriter<Stdout>>),
+        /// A writer for uncompressed data to stdout which is flushed after each
+        /// line.
+        StdoutLines(WriterWithContext<LineWriter<Stdout>>),
     }
 
     impl Write for WriteFileZipStdout {}
@@ -34,54 +102,159 @@ impl WriteFileZipStdout {
     /// propagated. If no path is provided, this method is infallible. Any
     /// failed writes will have context added including the path if available.
     pub fn create(path: Option<impl AsRef<Path>>) -> std::io::Result<Self> {
-        match path {
-            Some(path) => {
-                let file = File::create(&path)?;
-                let bufwriter = BufWriter::new(file);
-
-                let writer = if is_gz(&path) {
-                    Self::Zipped(
-                        GzEncoder::new(bufwriter, Compression::default())
-                            .writer_with_path_context("Failed to write to zipped file", path),
-                    )
-                } else {
-                    Self::File(bufwriter.writer_with_path_context("Failed to write to file", path))
-                };
-
-                Ok(writer)
-            }
-            None => Ok(WriteFileZipStdout::Stdout(
-                BufWriter::new(stdout()).writer_with_context("Failed to write to stdout"),
-            )),
-        }
+        Self::with_buffering(path, None, OutputBuffering::Block)
     }
 
     /// Similar to [`WriteFileZipStdout::create`], but uses a specified
     /// `capacity` for the underlying [`BufWriter`].
     pub fn with_capacity(capacity: usize, path: Option<impl AsRef<Path>>) -> std::io::Result<Self> {
+        Self::with_buffering(path, Some(capacity), OutputBuffering::Block)
+    }
+
+    /// Similar to [`WriteFileZipStdout::create`], but buffers the output as
+    /// specified by `buffering`. With [`OutputBuffering::Block`], the
+    /// underlying [`BufWriter`] has the specified `capacity` if provided,
+    /// limited to [`FIFO_CAPACITY`] if the output is a named pipe. Otherwise,
+    /// `capacity` is only used for compressed outputs.
+    ///
+    /// ## Errors
+    ///
+    /// If a path is provided, any IO errors when creating the file are
+    /// propagated. If no path is provided, this method is infallible. Any
+    /// failed writes will have context added including the path if available.
+    pub fn with_buffering(
+        path: Option<impl AsRef<Path>>, capacity: Option<usize>, buffering: OutputBuffering,
+    ) -> std::io::Result<Self> {
+        Self::with_compression(path, capacity, buffering, CompressionOptions::default())
+    }
+
+    /// Similar to [`WriteFileZipStdout::with_buffering`], but writes a
+    /// compressed output as specified by `compression`. A path ending in
+    /// `.bgz` is written as BGZF regardless of its format.
+    ///
+    /// ## Errors
+    ///
+    /// If a path is provided, any IO errors when creating the file (or
+    /// spawning the compression threads) are propagated. If no path is
+    /// provided, this method is infallible. Any failed writes will have context
+    /// added including the path if available.
+    pub fn with_compression(
+        path: Option<impl AsRef<Path>>, capacity: Option<usize>, buffering: OutputBuffering, compression: CompressionOptions,
+    ) -> std::io::Result<Self> {
+        let CompressionOptions { format, level, threads } = compression;
+        let capacity = capacity.unwrap_or(DEFAULT_CAPACITY);
+
         match path {
             Some(path) => {
+                let fifo = is_fifo(path.as_ref());
                 let file = File::create(&path)?;
-                let bufwriter = BufWriter::with_capacity(capacity, file);
 
-                let writer = if is_gz(&path) {
+                let format = if is_bgz(&path) { GzipFormat::Bgzf } else { format };
+
+                let writer = if is_gz(&path) && threads.get() > 1 {
+                    Self::ZippedParallel(
+                        ParallelGzWriter::new(BufWriter::with_capacity(capacity, file), format, level, threads)?
+                            .writer_with_path_context("Failed to write to zipped file", path),
+                    )
+                } else if is_gz(&path) && format == GzipFormat::Bgzf {
+                    Self::Bgzf(
+                        BgzfWriter::new(BufWriter::with_capacity(capacity, file), level)
+                            .writer_with_path_context("Failed to write to BGZF file", path),
+                    )
+                } else if is_gz(&path) {
                     Self::Zipped(
-                        GzEncoder::new(bufwriter, Compression::default())
+                        GzEncoder::new(BufWriter::with_capacity(capacity, file), level)
                             .writer_with_path_context("Failed to write to zipped file", path),
                     )
                 } else {
-                    Self::File(bufwriter.writer_with_path_context("Failed to write to file", path))
+                    match buffering {
+                        OutputBuffering::Block => {
+                            let capacity = if fifo { capacity.min(FIFO_CAPACITY) } else { capacity };
+                            Self::File(
+                                BufWriter::with_capacity(capacity, file)
+                                    .writer_with_path_context("Failed to write to file", path),
+                            )
+                        }
+                        OutputBuffering::Line => {
+                            Self::FileLines(LineWriter::new(file).writer_with_path_context("Failed to write to file", path))
+                        }
+                        OutputBuffering::Unbuffered => Self::File(
+                            BufWriter::with_capacity(0, file).writer_with_path_context("Failed to write to file", path),
+                        ),
+                    }
+                };
+
+                Ok(writer)
+            }
+            None => {
+                let writer = match buffering {
+                    OutputBuffering::Block => {
+                        let capacity = if stdout_is_fifo() {
+                            capacity.min(FIFO_CAPACITY)
+                        } else {
+                            capacity
+                        };
+                        Self::Stdout(
+                            BufWriter::with_capacity(capacity, stdout()).writer_with_context("Failed to write to stdout"),
+                        )
+                    }
+                    OutputBuffering::Line => {
+                        Self::StdoutLines(LineWriter::new(stdout()).writer_with_context("Failed to write to stdout"))
+                    }
+                    OutputBuffering::Unbuffered => {
+                        Self::Stdout(BufWriter::with_capacity(0, stdout()).writer_with_context("Failed to write to stdout"))
+                    }
                 };
 
                 Ok(writer)
             }
-            None => Ok(WriteFileZipStdout::Stdout(
-                BufWriter::with_capacity(capacity, stdout()).writer_with_context("Failed to write to stdout"),
-            )),
         }
     }
 }
 
+/// The default capacity of a [`BufWriter`], which is not exposed by the
+/// standard library.
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// Whether `path` is a named pipe (FIFO). This is always false on platforms
+/// other than Unix.
+#[inline]
+fn is_fifo(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Whether stdout is a pipe, such as when it is piped to another process. This
+/// is always false on platforms other than Unix.
+#[inline]
+fn stdout_is_fifo() -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::{fd::AsFd, unix::fs::FileTypeExt};
+        // The duplicated file descriptor is closed when the file is dropped
+        stdout()
+            .as_fd()
+            .try_clone_to_owned()
+            .map(File::from)
+            .and_then(|file| file.metadata())
+            .is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
 /// A struct containing two writers for paired reads: one for left reads and one
 /// for the right.
 ///
@@ -142,4 +315,16 @@ impl<W> RecordWriters<W> {
             None => Self::SingleEnd(writer1),
         }
     }
+
+    /// Borrows the writer(s) mutably, such as for writing the records of
+    /// several inputs to the same outputs.
+    #[inline]
+    pub fn as_mut(&mut self) -> RecordWriters<&mut W> {
+        match self {
+            Self::SingleEnd(writer) => RecordWriters::SingleEnd(writer),
+            Self::PairedEnd(writers) => {
+                RecordWriters::PairedEnd(PairedWriters::new(&mut writers.writer1, &mut writers.writer2))
+            }
+        }
+    }
 }
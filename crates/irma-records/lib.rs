@@ -1,5 +1,5 @@
 #![doc = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/README.md"))]
-#![feature(portable_simd, try_trait_v2, int_format_into)]
+#![feature(portable_simd, try_trait_v2)]
 
 pub mod fastq;
 pub mod hashing;
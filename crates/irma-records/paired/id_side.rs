@@ -3,7 +3,7 @@ use std::simd::Simd;
 use zoe::data::records::HeaderReadable;
 
 /// An enum representing the read side for paired or unpaired reads.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum ReadSide {
     R1,
     R2,
@@ -22,6 +22,18 @@ impl ReadSide {
         }
     }
 
+    /// The side of a read from its SAM flags: [`ReadSide::R1`] for the first
+    /// segment of a template (64), [`ReadSide::R2`] for the last segment (128),
+    /// and `Unpaired` if neither or both are set.
+    #[inline]
+    pub fn from_sam_flag(flag: u16) -> Self {
+        match (flag & 0x40 != 0, flag & 0x80 != 0) {
+            (true, false) => ReadSide::R1,
+            (false, true) => ReadSide::R2,
+            _ => ReadSide::Unpaired,
+        }
+    }
+
     /// Convert to a SIMD vector for generating count metadata. [`ReadSide::R1`]
     /// and `Unpaired` are both `[1, 0]` and [`ReadSide::R2`] is `[0, 1]`.
     #[inline]
@@ -98,6 +110,121 @@ pub fn get_molecular_id_side(s: &str, default_side: char) -> Option<(&str, char)
     }
 }
 
+/// Takes a FASTQ header and returns the index (barcode) sequences from the
+/// Illumina comment, such as `AACGCACGAG+GCCTCGGATA` in
+/// `M02989:9:000000000-L4PJL:1:2112:9890:15606 1:N:0:AACGCACGAG+GCCTCGGATA`.
+///
+/// The first index is returned along with the second index, if the reads are
+/// dual-indexed. Both space and underscore delimited comments are supported.
+/// `None` is returned if no index sequence composed of `ACGTN` is present. The
+/// bases may be in either case, and are returned as written.
+pub fn get_index_sequences(s: &str) -> Option<(&str, Option<&str>)> {
+    let comment = s.rsplit([' ', '_']).next()?;
+
+    let mut fields = comment.split(':');
+    let (_side, filtered, _control, index) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+
+    if fields.next().is_some() || !matches!(filtered, "Y" | "N") {
+        return None;
+    }
+
+    let (index1, index2) = match index.split_once('+') {
+        Some((index1, index2)) => (index1, Some(index2)),
+        None => (index, None),
+    };
+
+    let is_index = |index: &str| {
+        !index.is_empty()
+            && index
+                .bytes()
+                .all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+    };
+
+    if is_index(index1) && index2.is_none_or(is_index) {
+        Some((index1, index2))
+    } else {
+        None
+    }
+}
+
+/// Rewrites the read side of the Illumina comment in a FASTQ header to
+/// `read_side`, such as the `1` in
+/// `M02989:9:000000000-L4PJL:1:2112:9890:15606 1:N:0:AACGCACGAG`, preserving
+/// the rest of the comment. This keeps the comment consistent with the actual
+/// read side after the reads have been renamed or re-paired.
+///
+/// Both space and underscore delimited comments are supported. Returns whether
+/// the header had a comment to rewrite, leaving it unchanged otherwise.
+pub fn set_comment_side(header: &mut String, read_side: char) -> bool {
+    let Some(start) = header.rfind([' ', '_']).map(|i| i + 1) else {
+        return false;
+    };
+
+    let mut fields = header[start..].split(':');
+    let is_comment = matches!(
+        (fields.next(), fields.next(), fields.next(), fields.next(), fields.next()),
+        (Some(side), Some("Y" | "N"), Some(_), Some(_), None) if side.len() == 1 && side.as_bytes()[0].is_ascii_digit()
+    );
+
+    if is_comment {
+        header.replace_range(start..=start, read_side.encode_utf8(&mut [0; 4]));
+    }
+    is_comment
+}
+
+/// Run-level metadata parsed from a FASTQ header, recording where the reads
+/// came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunMetadata {
+    /// The fields shared by every read of an Illumina run, from a header such
+    /// as `M02989:9:000000000-L4PJL:1:2112:9890:15606 1:N:0:AACGCACGAG`
+    Illumina {
+        instrument: String,
+        run_id:     String,
+        flowcell:   String,
+        lane:       String,
+    },
+    /// The run accession of reads downloaded from the SRA (or ENA or DDBJ),
+    /// such as `SRR26182418` in `SRR26182418.1.1`
+    Sra { accession: String },
+}
+
+/// Takes a FASTQ header and returns the run-level metadata it contains, if it
+/// is an Illumina or SRA-style header. SRA-style headers take precedence, so
+/// that the accession is returned even if the original Illumina header follows
+/// it. Both space and underscore delimited comments are supported.
+pub fn get_run_metadata(s: &str) -> Option<RunMetadata> {
+    let id = s.split([' ', '\t']).next().unwrap_or_default();
+
+    if id.starts_with("SRR") || id.starts_with("DRR") || id.starts_with("ERR") {
+        let accession = id.split(['.', '_']).next().unwrap_or_default();
+        if accession.len() > 3 && accession[3..].bytes().all(|b| b.is_ascii_digit()) {
+            return Some(RunMetadata::Sra {
+                accession: accession.to_string(),
+            });
+        }
+    }
+
+    let mut fields = id.split(':');
+    let (instrument, run_id, flowcell, lane) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    // The tile and the x and y coordinates
+    if fields.take(3).count() < 3 {
+        return None;
+    }
+
+    let is_number = |field: &str| !field.is_empty() && field.bytes().all(|b| b.is_ascii_digit());
+    if instrument.is_empty() || flowcell.is_empty() || !is_number(run_id) || !is_number(lane) {
+        return None;
+    }
+
+    Some(RunMetadata::Illumina {
+        instrument: instrument.to_string(),
+        run_id:     run_id.to_string(),
+        flowcell:   flowcell.to_string(),
+        lane:       lane.to_string(),
+    })
+}
+
 /// Returns whether two reads have matching molecular IDs. Errors if the read
 /// ID's don't match or can't be parsed.
 pub fn check_paired_headers<A: HeaderReadable, B: HeaderReadable>(read1: &A, read2: &B) -> Result<(), PairedHeaderError> {
@@ -6,7 +6,10 @@ mod id_side;
 
 pub use deinterleave::{DeinterleaveError, DeinterleavedPairedReads, DeinterleavedPairedReadsExt};
 pub use header_error::PairedHeaderError;
-pub use id_side::{ReadSide, check_paired_headers, get_molecular_id_side};
+pub use id_side::{
+    ReadSide, RunMetadata, check_paired_headers, get_index_sequences, get_molecular_id_side, get_run_metadata,
+    set_comment_side,
+};
 pub use zip_reads::{ZipPairedReadsError, ZipPairedReadsExt, ZipReadsError};
 
 #[cfg(test)]
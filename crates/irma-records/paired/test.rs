@@ -1,4 +1,7 @@
-use crate::paired::get_molecular_id_side;
+use crate::{
+    fastq::fix_header_sides,
+    paired::{ReadSide, RunMetadata, get_index_sequences, get_molecular_id_side, get_run_metadata, set_comment_side},
+};
 
 static QNAMES: [&str; 26] = [
     "SRR26182418.1 M07901:28:000000000-KP3NB:1:1101:10138:2117 length=147",
@@ -64,3 +67,173 @@ fn test_get_mol_id() {
         assert_eq!(get_molecular_id_side(o, '0'), Some(extracted[i]), "'{o}'");
     }
 }
+
+#[test]
+fn test_get_index_sequences() {
+    let extracted = [
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(("AACGCACGAG", Some("GCCTCGGATA"))),
+        Some(("AACGCACGAG", Some("GCCTCGGATA"))),
+        Some(("TTCTCGTGCA", Some("CTCTGTGTAT"))),
+        Some(("TTCTCGTGCA", Some("CTCTGTGTAT"))),
+        Some(("TAGGCATG", Some("ATAGCCTT"))),
+        Some(("TAGGCATG", Some("ATAGCCTT"))),
+        Some(("CTCTGCAGCG", Some("GATGGATGTA"))),
+        Some(("CTCTGCAGCG", Some("GATGGATGTA"))),
+        Some(("CTCTGCAGCG", Some("GATGGATGTA"))),
+        Some(("CTCTGCAGCG", Some("GATGGATGTA"))),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    ];
+
+    for (i, o) in QNAMES.iter().enumerate() {
+        assert_eq!(get_index_sequences(o), extracted[i], "'{o}'");
+    }
+
+    assert_eq!(
+        get_index_sequences("A00350:691:HCKYLDSX3:2:2119:23863:2456 1:N:0:ACGTAC"),
+        Some(("ACGTAC", None))
+    );
+
+    // Lowercase indices are recognized and returned as written
+    assert_eq!(
+        get_index_sequences("M02989:9:000000000-L4PJL:1:2112:9890:15606 1:N:0:aacgcacgag+GCCTCGGATA"),
+        Some(("aacgcacgag", Some("GCCTCGGATA")))
+    );
+}
+
+#[test]
+fn test_set_comment_side() {
+    let has_comment = [
+        false, false, false, false, false, false, true, true, true, true, true, true, true, true, true, true, false, false,
+        false, false, true, true, false, false, false, false,
+    ];
+
+    for (i, &o) in QNAMES.iter().enumerate() {
+        for side in ['1', '2'] {
+            let mut header = o.to_string();
+            assert_eq!(set_comment_side(&mut header, side), has_comment[i], "'{o}'");
+
+            if has_comment[i] {
+                let start = o.rfind([' ', '_']).unwrap() + 1;
+                assert_eq!(header, format!("{}{side}{}", &o[..start], &o[start + 1..]), "'{o}'");
+                assert_eq!(get_index_sequences(&header), get_index_sequences(o), "'{o}'");
+            } else {
+                assert_eq!(header, o);
+            }
+        }
+    }
+
+    let mut header = "M02989:9:000000000-L4PJL:1:2114:17393:19614_1:N:0:CTCTGCAGCG+GATGGATGTA".to_string();
+    set_comment_side(&mut header, '2');
+    assert_eq!(
+        get_molecular_id_side(&header, '0'),
+        Some(("M02989:9:000000000-L4PJL:1:2114:17393:19614", '2'))
+    );
+}
+
+#[test]
+fn test_sam_flag_side_round_trip() {
+    // Paired SAM records share a QNAME, so the side from the flags is appended
+    // as a legacy Illumina suffix when converting back to FASTQ
+    let qname = "A00350:691:HCKYLDSX3:2:2119:23863:2456";
+    let with_side = |flag: u16| match ReadSide::from_sam_flag(flag).to_char() {
+        Some(side) => format!("{qname}/{side}"),
+        None => qname.to_string(),
+    };
+
+    // R1 forward with R2 reverse, and R1 reverse with R2 forward
+    for (flag1, flag2) in [(99, 147), (83, 163)] {
+        let (header1, header2) = (with_side(flag1), with_side(flag2));
+        assert_ne!(header1, header2);
+        assert_eq!(get_molecular_id_side(&header1, '0'), Some((qname, '1')));
+        assert_eq!(get_molecular_id_side(&header2, '0'), Some((qname, '2')));
+    }
+
+    // Unpaired, or with neither or both segment flags
+    for flag in [0, 16, 0xC1] {
+        assert_eq!(with_side(flag), qname);
+    }
+}
+
+#[test]
+fn test_fix_header_sides() {
+    let mut header = "SRR26182418.1 1:N:18:NULL".to_string();
+    fix_header_sides(&mut header, '2');
+    assert_eq!(header, "SRR26182418.1.2 2:N:18:NULL");
+
+    let mut header = "SRR26182418.1.1 1:N:18:NULL".to_string();
+    fix_header_sides(&mut header, '1');
+    assert_eq!(header, "SRR26182418.1.1 1:N:18:NULL");
+
+    let mut header = "A00350:691:HCKYLDSX3:2:2119:23863:2456/1".to_string();
+    fix_header_sides(&mut header, '2');
+    assert_eq!(header, "A00350:691:HCKYLDSX3:2:2119:23863:2456/1");
+}
+
+#[test]
+fn test_get_run_metadata() {
+    let illumina = |instrument: &str, run_id: &str, flowcell: &str, lane: &str| {
+        Some(RunMetadata::Illumina {
+            instrument: instrument.to_string(),
+            run_id:     run_id.to_string(),
+            flowcell:   flowcell.to_string(),
+            lane:       lane.to_string(),
+        })
+    };
+    let sra = |accession: &str| {
+        Some(RunMetadata::Sra {
+            accession: accession.to_string(),
+        })
+    };
+
+    let extracted = [
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        illumina("A00350", "691", "HCKYLDSX3", "2"),
+        illumina("A00350", "691", "HCKYLDSX3", "2"),
+        illumina("M02989", "9", "000000000-L4PJL", "1"),
+        illumina("M02989", "9", "000000000-L4PJL", "1"),
+        illumina("NS500500", "69", "HKJFLAFX5", "1"),
+        illumina("NS500500", "69", "HKJFLAFX5", "1"),
+        illumina("A01000", "249", "HJFFWDRX2", "1"),
+        illumina("A01000", "249", "HJFFWDRX2", "1"),
+        illumina("M02989", "9", "000000000-L4PJL", "1"),
+        illumina("M02989", "9", "000000000-L4PJL", "1"),
+        illumina("M02989_1", "9", "000000000-L4PJL", "1"),
+        illumina("M02989_1", "9", "000000000-L4PJL", "1"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("SRR26182418"),
+        sra("ERR26182418"),
+        sra("DRR26182418"),
+        sra("ERR26182418"),
+        sra("DRR26182418"),
+    ];
+
+    for (i, o) in QNAMES.iter().enumerate() {
+        assert_eq!(get_run_metadata(o), extracted[i], "'{o}'");
+    }
+
+    assert_eq!(get_run_metadata("HWUSI-EAS100R:6:73:941:1973#0/1"), None);
+    assert_eq!(get_run_metadata("read_1"), None);
+    assert_eq!(get_run_metadata("A00350:691:HCKYLDSX3:X:2119:23863:2456"), None);
+}
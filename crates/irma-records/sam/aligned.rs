@@ -2,12 +2,18 @@ use crate::sam::merge_pairs::SamExpandableAlignment;
 use std::ops::Range;
 use zoe::data::sam::SamData;
 
+/// The placeholder in [`SamAligned::aligned`] for reference positions skipped
+/// by an `N` operation, such as introns in spliced alignments. It is not a valid
+/// `SEQ` character, and these positions are treated as not covered by the
+/// query.
+pub(crate) const SKIPPED: u8 = b'~';
+
 /// Struct holding the alignment information of a SAM query sequence to some
 /// reference.
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub(crate) struct SamAligned {
     /// Query bases aligned to the reference (insertions removed, deletions
-    /// added).
+    /// added, and skipped positions marked with [`SKIPPED`]).
     pub(crate) aligned:           Vec<u8>,
     /// Query quality scores aligned to the reference (insertions removed,
     /// deletions added).
@@ -46,14 +52,15 @@ impl SamAligned {
 
     /// At the given reference index, provides the reference-aligned query's
     /// nucleotide and encoded (ASCII) quality score as an optional tuple of
-    /// `(base, qs)`, otherwise, `None` is returned.
+    /// `(base, qs)`, otherwise, `None` is returned. Skipped positions are
+    /// also `None`, since the query does not cover them.
     #[must_use]
     pub(crate) fn get_base_and_quality(&self, at_index: usize) -> Option<(u8, u8)> {
         // Safety: reference range is the same length as `aligned` and
         // `qaligned`; thus, subtracting the start of the range will render
         // appropriate 0-based index. If the reference index it outside the
         // range, then `None` is returned.
-        if self.ref_range().contains(&at_index) {
+        if self.ref_range().contains(&at_index) && self.aligned[at_index - self.ref_start] != SKIPPED {
             Some((
                 self.aligned[at_index - self.ref_start],
                 self.qaligned[at_index - self.ref_start],
@@ -64,14 +71,15 @@ impl SamAligned {
     }
 
     /// At the given reference index, provides the reference-aligned query's
-    /// nucleotide as an `Option`, otherwise, `None` is returned.
+    /// nucleotide as an `Option`, otherwise, `None` is returned. Skipped
+    /// positions are also `None`, since the query does not cover them.
     #[must_use]
     pub(crate) fn get_base(&self, at_index: usize) -> Option<u8> {
         // Safety: reference range is the same length as `aligned` and
         // `qaligned`; thus, subtracting the start of the range will render
         // appropriate 0-based index. If the reference index it outside the
         // range, then `None` is returned.
-        if self.ref_range().contains(&at_index) {
+        if self.ref_range().contains(&at_index) && self.aligned[at_index - self.ref_start] != SKIPPED {
             Some(self.aligned[at_index - self.ref_start])
         } else {
             None
@@ -107,8 +115,11 @@ impl From<&SamData> for SamAligned {
     ///
     /// ## Panics
     ///
-    /// Currently panics on invalid cigar states. To be removed in the future
-    /// for either a `Result` or type-state validated CIGAR strings.
+    /// Panics if a CIGAR string is not syntactically valid, or covers more query
+    /// bases than `SEQ`, which can be checked beforehand with
+    /// [`TypedCigar::validate`].
+    ///
+    /// [`TypedCigar::validate`]: crate::sam::TypedCigar::validate
     ///
     /// This also has a chance of panicking in debug mode if an insertion
     /// appears at the start of the alignment.
@@ -1,106 +1,453 @@
-use core::fmt::NumBuffer;
-use zoe::data::{cigar::Ciglet, types::cigar::Cigar};
+//! CIGAR utilities shared by the SAM consumers and producers: a typed CIGAR
+//! representation, a builder for constructing one an operation at a time,
+//! validators for length and clipping consistency, and helpers for mapping
+//! between reference and query coordinates.
 
-/// A CIGAR string as an "expanded" opcode-only byte string, e.g. `3M` → `MMM`.
-#[derive(Clone, PartialEq)]
-pub(crate) struct ExpandedCigar(Vec<u8>);
+use std::{
+    error::Error,
+    fmt::{Display, Write},
+};
+use zoe::{
+    alignment::AlignmentStates,
+    data::{
+        cigar::{CigarError, Ciglet},
+        err::GetCode,
+        types::cigar::Cigar,
+    },
+};
 
-impl ExpandedCigar {
-    /// Condenses the [`ExpandedCigar`] back to its standard form,  e.g. `MMM` →
-    /// `3M`.
+/// A single CIGAR operation.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum CigarOp {
+    /// `M`: an alignment match, which may be a sequence match or mismatch
+    Match,
+    /// `I`: an insertion to the reference
+    Insertion,
+    /// `D`: a deletion from the reference
+    Deletion,
+    /// `N`: a skipped region of the reference
+    Skip,
+    /// `S`: a soft clip, where the clipped bases are present in `SEQ`
+    SoftClip,
+    /// `H`: a hard clip, where the clipped bases are absent from `SEQ`
+    HardClip,
+    /// `P`: padding, a silent deletion from the padded reference
+    Padding,
+    /// `=`: a sequence match
+    SeqMatch,
+    /// `X`: a sequence mismatch
+    SeqMismatch,
+}
+
+impl CigarOp {
+    /// Parses an operation from its byte, returning `None` if it is not one of
+    /// `MIDNSHP=X`.
     #[inline]
     #[must_use]
-    pub(crate) fn condense_to_cigar(self) -> Cigar {
-        let mut condensed: Vec<u8> = Vec::new();
-        let mut buff = NumBuffer::new();
+    pub const fn from_byte(op: u8) -> Option<Self> {
+        match op {
+            b'M' => Some(CigarOp::Match),
+            b'I' => Some(CigarOp::Insertion),
+            b'D' => Some(CigarOp::Deletion),
+            b'N' => Some(CigarOp::Skip),
+            b'S' => Some(CigarOp::SoftClip),
+            b'H' => Some(CigarOp::HardClip),
+            b'P' => Some(CigarOp::Padding),
+            b'=' => Some(CigarOp::SeqMatch),
+            b'X' => Some(CigarOp::SeqMismatch),
+            _ => None,
+        }
+    }
 
-        let mut cigars = self.0.iter().copied().filter(|op| is_valid_op(*op));
+    /// The byte representing the operation in a CIGAR string.
+    #[inline]
+    #[must_use]
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            CigarOp::Match => b'M',
+            CigarOp::Insertion => b'I',
+            CigarOp::Deletion => b'D',
+            CigarOp::Skip => b'N',
+            CigarOp::SoftClip => b'S',
+            CigarOp::HardClip => b'H',
+            CigarOp::Padding => b'P',
+            CigarOp::SeqMatch => b'=',
+            CigarOp::SeqMismatch => b'X',
+        }
+    }
+
+    /// Whether the operation consumes bases of the query (`SEQ`), which is the
+    /// case for `MIS=X`.
+    #[inline]
+    #[must_use]
+    pub const fn consumes_query(self) -> bool {
+        matches!(
+            self,
+            CigarOp::Match | CigarOp::Insertion | CigarOp::SoftClip | CigarOp::SeqMatch | CigarOp::SeqMismatch
+        )
+    }
+
+    /// Whether the operation consumes bases of the reference, which is the case
+    /// for `MDN=X`.
+    #[inline]
+    #[must_use]
+    pub const fn consumes_reference(self) -> bool {
+        matches!(
+            self,
+            CigarOp::Match | CigarOp::Deletion | CigarOp::Skip | CigarOp::SeqMatch | CigarOp::SeqMismatch
+        )
+    }
+
+    /// Whether the operation aligns a query base to a reference base, which is
+    /// the case for `M=X`.
+    #[inline]
+    #[must_use]
+    pub const fn is_aligned(self) -> bool {
+        self.consumes_query() && self.consumes_reference()
+    }
+
+    /// Whether the operation is a soft or hard clip.
+    #[inline]
+    #[must_use]
+    pub const fn is_clipping(self) -> bool {
+        matches!(self, CigarOp::SoftClip | CigarOp::HardClip)
+    }
+}
+
+/// A run of a single [`CigarOp`], such as `10M`.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct CigarElement {
+    /// The number of times the operation is repeated, which is non-zero
+    pub len: usize,
+    pub op:  CigarOp,
+}
+
+impl Display for CigarElement {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.len)?;
+        f.write_char(char::from(self.op.to_byte()))
+    }
+}
+
+/// A CIGAR string parsed into typed [`CigarElement`]s, each with a non-zero
+/// length. An empty [`TypedCigar`] represents the unavailable CIGAR `*`.
+///
+/// Parsing only checks the syntax of the CIGAR string, so that clipping and
+/// lengths can be checked separately with [`TypedCigar::validate`].
+#[derive(Clone, Default, Eq, PartialEq, Hash, Debug)]
+pub struct TypedCigar(Vec<CigarElement>);
+
+impl TypedCigar {
+    /// The elements of the CIGAR string, in order.
+    #[inline]
+    #[must_use]
+    pub fn elements(&self) -> &[CigarElement] {
+        &self.0
+    }
+
+    /// Whether the CIGAR string is empty (or `*`).
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The operations of the CIGAR string, each repeated by its length, e.g.
+    /// `3M` → `MMM`.
+    #[inline]
+    pub fn expanded_ops(&self) -> impl Iterator<Item = CigarOp> + '_ {
+        self.0.iter().flat_map(|element| std::iter::repeat_n(element.op, element.len))
+    }
+
+    /// The number of bases of the query (`SEQ`) in the alignment, including
+    /// soft clips but excluding hard clips.
+    #[inline]
+    #[must_use]
+    pub fn query_len(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|element| element.op.consumes_query())
+            .map(|element| element.len)
+            .sum()
+    }
+
+    /// The number of bases of the reference spanned by the alignment.
+    #[inline]
+    #[must_use]
+    pub fn ref_len(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|element| element.op.consumes_reference())
+            .map(|element| element.len)
+            .sum()
+    }
+
+    /// The number of bases hard or soft clipped from the start of the query.
+    #[inline]
+    #[must_use]
+    pub fn clipped_start(&self) -> usize {
+        self.0
+            .iter()
+            .take_while(|element| element.op.is_clipping())
+            .map(|element| element.len)
+            .sum()
+    }
 
-        let Some(mut previous) = cigars.next() else {
-            return Cigar::from_vec_unchecked(condensed);
-        };
+    /// The number of bases hard or soft clipped from the end of the query.
+    #[inline]
+    #[must_use]
+    pub fn clipped_end(&self) -> usize {
+        self.0
+            .iter()
+            .rev()
+            .take_while(|element| element.op.is_clipping())
+            .map(|element| element.len)
+            .sum()
+    }
 
-        let mut count: usize = 1;
+    /// Checks that the CIGAR string is consistent with the SAM specification,
+    /// and with the length of `SEQ` if it is provided (it should be `None`
+    /// when `SEQ` is `*`).
+    ///
+    /// ## Errors
+    ///
+    /// An error is returned if a hard clip is not at either end, if a soft clip
+    /// is not at either end or between a hard clip and the end, or if the
+    /// length of the query differs from `seq_len`.
+    pub fn validate(&self, seq_len: Option<usize>) -> Result<(), CigarValidationError> {
+        let last = self.0.len().saturating_sub(1);
+        for (i, element) in self.0.iter().enumerate() {
+            match element.op {
+                CigarOp::HardClip if i != 0 && i != last => return Err(CigarValidationError::HardClipNotAtEnd),
+                CigarOp::SoftClip => {
+                    let at_start = self.0[..i].iter().all(|element| element.op == CigarOp::HardClip);
+                    let at_end = self.0[i + 1..].iter().all(|element| element.op == CigarOp::HardClip);
+                    if !at_start && !at_end {
+                        return Err(CigarValidationError::SoftClipNotAtEnd);
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        for op in cigars {
-            if previous == op {
-                count += 1;
-            } else {
-                condensed.extend_from_slice(count.format_into(&mut buff).as_bytes());
-                condensed.push(previous);
-                previous = op;
-                count = 1;
+        if let Some(seq_len) = seq_len {
+            let query_len = self.query_len();
+            if query_len != seq_len {
+                return Err(CigarValidationError::QueryLenMismatch { query_len, seq_len });
             }
         }
 
-        condensed.extend_from_slice(count.format_into(&mut buff).as_bytes());
-        condensed.push(previous);
+        Ok(())
+    }
 
-        Cigar::from_vec_unchecked(condensed)
+    /// Returns the index in the query (`SEQ`) of the base aligned to the
+    /// 0-based `ref_index`, given that the alignment starts at the 0-based
+    /// `ref_start`. `None` is returned if the reference position is deleted,
+    /// skipped, or outside of the alignment.
+    #[must_use]
+    pub fn query_index_at(&self, ref_start: usize, ref_index: usize) -> Option<usize> {
+        let (mut ref_pos, mut query_pos) = (ref_start, 0);
+        for &CigarElement { len, op } in &self.0 {
+            if op.consumes_reference() && ref_index < ref_pos + len {
+                return if op.consumes_query() && ref_index >= ref_pos {
+                    Some(query_pos + (ref_index - ref_pos))
+                } else {
+                    None
+                };
+            }
+            if op.consumes_reference() {
+                ref_pos += len;
+            }
+            if op.consumes_query() {
+                query_pos += len;
+            }
+        }
+        None
     }
-}
 
-impl std::fmt::Display for ExpandedCigar {
+    /// Returns the 0-based reference index of the base aligned to the query
+    /// (`SEQ`) index `query_index`, given that the alignment starts at the
+    /// 0-based `ref_start`. `None` is returned if the query base is inserted,
+    /// soft clipped, or outside of the query.
+    #[must_use]
+    pub fn ref_index_at(&self, ref_start: usize, query_index: usize) -> Option<usize> {
+        let (mut ref_pos, mut query_pos) = (ref_start, 0);
+        for &CigarElement { len, op } in &self.0 {
+            if op.consumes_query() && query_index < query_pos + len {
+                return op.consumes_reference().then(|| ref_pos + (query_index - query_pos));
+            }
+            if op.consumes_reference() {
+                ref_pos += len;
+            }
+            if op.consumes_query() {
+                query_pos += len;
+            }
+        }
+        None
+    }
+
+    /// Converts the elements back to a [`Cigar`] string.
     #[inline]
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_str(&String::from_utf8_lossy(&self.0))
+    #[must_use]
+    pub fn to_cigar(&self) -> Cigar {
+        Cigar::from_ciglets_unchecked(self.0.iter().map(|element| Ciglet {
+            inc: element.len,
+            op:  element.op.to_byte(),
+        }))
     }
 }
 
-impl std::fmt::Debug for ExpandedCigar {
-    #[inline]
+impl Display for TypedCigar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&String::from_utf8_lossy(&self.0))
+        if self.0.is_empty() {
+            return f.write_char('*');
+        }
+        for element in &self.0 {
+            write!(f, "{element}")?;
+        }
+        Ok(())
     }
 }
 
-impl From<Cigar> for ExpandedCigar {
-    #[inline]
-    fn from(c: Cigar) -> ExpandedCigar {
-        let mut expanded = Vec::new();
+impl TryFrom<&Cigar> for TypedCigar {
+    type Error = CigarValidationError;
 
-        for Ciglet { inc, op } in &c {
-            expanded.extend(std::iter::repeat_n(op, inc));
+    /// Parses a [`Cigar`] string, where `*` and the empty string give an empty
+    /// [`TypedCigar`].
+    fn try_from(cigar: &Cigar) -> Result<Self, Self::Error> {
+        if !cigar.is_valid() {
+            // Revalidated for the specific error, which is only reached for
+            // invalid records
+            return Err(CigarValidationError::Syntax(
+                Cigar::try_from(cigar.as_bytes())
+                    .err()
+                    .unwrap_or(CigarError::InvalidOperation),
+            ));
+        }
+        if cigar.as_bytes() == b"*" {
+            return Ok(TypedCigar::default());
         }
 
-        ExpandedCigar(expanded)
+        let mut builder = CigarBuilder::new();
+        for Ciglet { inc, op } in cigar {
+            let op = CigarOp::from_byte(op).ok_or(CigarValidationError::Syntax(CigarError::InvalidOperation))?;
+            builder.push_n(op, inc);
+        }
+        Ok(builder.build())
     }
 }
 
-impl From<Vec<u8>> for ExpandedCigar {
-    #[inline]
-    fn from(vec: Vec<u8>) -> Self {
-        ExpandedCigar(vec)
+impl TryFrom<&AlignmentStates> for TypedCigar {
+    type Error = CigarValidationError;
+
+    /// Builds a [`TypedCigar`] from the states of an alignment.
+    fn try_from(states: &AlignmentStates) -> Result<Self, Self::Error> {
+        let mut builder = CigarBuilder::new();
+        for &Ciglet { inc, op } in states.iter() {
+            let op = CigarOp::from_byte(op).ok_or(CigarValidationError::Syntax(CigarError::InvalidOperation))?;
+            builder.push_n(op, inc);
+        }
+        Ok(builder.build())
     }
 }
 
-impl From<&str> for ExpandedCigar {
+/// Builds a [`TypedCigar`] one or more operations at a time, merging adjacent
+/// runs of the same operation.
+#[derive(Clone, Default, Debug)]
+pub struct CigarBuilder {
+    elements: Vec<CigarElement>,
+}
+
+impl CigarBuilder {
+    /// Creates an empty builder.
     #[inline]
-    fn from(s: &str) -> Self {
-        ExpandedCigar(s.as_bytes().to_owned())
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty builder with space for `capacity` elements.
+    #[inline]
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        CigarBuilder {
+            elements: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Appends a single operation.
+    #[inline]
+    pub fn push(&mut self, op: CigarOp) {
+        self.push_n(op, 1);
+    }
+
+    /// Appends a run of `len` operations, which has no effect if `len` is
+    /// zero.
+    #[inline]
+    pub fn push_n(&mut self, op: CigarOp, len: usize) {
+        if len == 0 {
+            return;
+        }
+        match self.elements.last_mut() {
+            Some(last) if last.op == op => last.len += len,
+            _ => self.elements.push(CigarElement { len, op }),
+        }
     }
-}
 
-impl From<&[u8]> for ExpandedCigar {
+    /// Finishes building the CIGAR string.
     #[inline]
-    fn from(v: &[u8]) -> Self {
-        ExpandedCigar(v.to_vec())
+    #[must_use]
+    pub fn build(self) -> TypedCigar {
+        TypedCigar(self.elements)
     }
 }
 
-impl<const N: usize> From<&[u8; N]> for ExpandedCigar {
+impl FromIterator<CigarOp> for CigarBuilder {
     #[inline]
-    fn from(v: &[u8; N]) -> Self {
-        ExpandedCigar(v.to_vec())
+    fn from_iter<T: IntoIterator<Item = CigarOp>>(iter: T) -> Self {
+        let mut builder = CigarBuilder::new();
+        for op in iter {
+            builder.push(op);
+        }
+        builder
     }
 }
 
-/// Returns whether a byte represents a valid CIGAR operation.
-///
-/// The following operations are valid: `MIDNSHPX=`.
-//
-// Copied/redundant with Zoe.
-#[inline]
-pub(crate) const fn is_valid_op(op: u8) -> bool {
-    matches!(op, b'M' | b'I' | b'D' | b'N' | b'S' | b'H' | b'P' | b'X' | b'=')
+/// An error from parsing or validating a [`TypedCigar`].
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum CigarValidationError {
+    /// The CIGAR string is not syntactically valid.
+    Syntax(CigarError),
+    /// A hard clip is not the first or last operation.
+    HardClipNotAtEnd,
+    /// A soft clip is separated from the end by an operation other than a hard
+    /// clip.
+    SoftClipNotAtEnd,
+    /// The length of the query in the CIGAR string differs from `SEQ`.
+    QueryLenMismatch { query_len: usize, seq_len: usize },
+}
+
+impl Display for CigarValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CigarValidationError::Syntax(e) => write!(f, "{e}"),
+            CigarValidationError::HardClipNotAtEnd => {
+                write!(f, "A hard clip must be the first or last CIGAR operation")
+            }
+            CigarValidationError::SoftClipNotAtEnd => {
+                write!(
+                    f,
+                    "Only hard clips may be between a soft clip and the end of the CIGAR string"
+                )
+            }
+            CigarValidationError::QueryLenMismatch { query_len, seq_len } => write!(
+                f,
+                "The CIGAR string covers {query_len} query bases, but the sequence has {seq_len}"
+            ),
+        }
+    }
 }
+
+impl Error for CigarValidationError {}
+impl GetCode for CigarValidationError {}
@@ -1,11 +1,5 @@
-use crate::sam::{ExpandedCigar, PairedMergeStats, SamAligned, SamInsertion};
-use std::iter::repeat_n;
-use zoe::{
-    alignment::NextCiglet,
-    data::{cigar::Ciglet, sam::SamData},
-    prelude::QualityScores,
-    search::ByteSubstring,
-};
+use crate::sam::{CigarBuilder, CigarElement, CigarOp, PairedMergeStats, SKIPPED, SamAligned, SamInsertion, TypedCigar};
+use zoe::{data::sam::SamData, prelude::QualityScores, search::ByteSubstring};
 
 /// Makes a merged qname for paired-end reads based on a provided qname plus
 /// adding or replacing `3` as the read side.
@@ -89,14 +83,24 @@ pub trait SamMergeablePairs {
     /// detect and correct errors. Based on the work by **Shepard et al. 2016**
     /// for IRMA.
     ///
+    /// The returned [`PairedMergeStats`] describe the decisions made for this
+    /// pair alone, with `self` counted as the first read and `other` as the
+    /// second.
+    ///
     /// ## Notes
     ///
-    /// This algorithm is designed for local alignment.
+    /// This algorithm is designed for local alignment. Reference positions
+    /// skipped by an `N` operation (such as introns in spliced alignments) are
+    /// treated as not covered by that read, so the other read's bases are used
+    /// there, and the merged read skips any position neither read covers.
+    /// Padding (`P`) does not affect the unpadded reference coordinates, so it
+    /// is dropped, and insertions separated only by padding are joined.
     ///
     /// ## Panics
     ///
-    /// Currently panics on invalid cigar states. To be removed in the future
-    /// for either a `Result` or type-state validated CIGAR strings.
+    /// Panics if a CIGAR string is not syntactically valid, or covers more query
+    /// bases than `SEQ`, which can be checked beforehand with
+    /// [`TypedCigar::validate`].
     ///
     /// This also has a chance of panicking in debug mode if an insertion
     /// appears at the start of the alignment.
@@ -169,11 +173,11 @@ impl SamMergeablePairs for SamData {
         let m_mapq = mapq1.midpoint(mapq2);
         let m_pos = paired_range.start + 1;
 
-        let mut merged_cigars = Vec::with_capacity(paired_range.len());
+        let mut merged_cigars = CigarBuilder::new();
         let mut merged_seq = Vec::with_capacity(paired_range.len());
         let mut merged_quals = Vec::with_capacity(paired_range.len());
 
-        merged_cigars.extend(std::iter::repeat_n(b'H', m_num_clipped_start));
+        merged_cigars.push_n(CigarOp::HardClip, m_num_clipped_start);
 
         // 0-based index relative to reference
         for ref_index in paired_range {
@@ -187,12 +191,12 @@ impl SamMergeablePairs for SamData {
                     if x == y {
                         if x == b'-' {
                             stats.true_variations += 1;
-                            merged_cigars.push(b'D');
+                            merged_cigars.push(CigarOp::Deletion);
                         } else {
                             if x != r {
                                 stats.true_variations += 1;
                             }
-                            merged_cigars.push(b'M');
+                            merged_cigars.push(CigarOp::Match);
 
                             merged_seq.push(x);
                             merged_quals.push(std::cmp::max(qx, qy));
@@ -200,13 +204,14 @@ impl SamMergeablePairs for SamData {
                     } else {
                         // x ≠ y
                         stats.variant_errors += 1;
-                        merged_cigars.push(b'M');
+                        merged_cigars.push(CigarOp::Match);
 
                         if x == r {
                             if y == b'-' {
                                 stats.deletion_errors += 1;
                             }
 
+                            stats.r1_wins += 1;
                             merged_seq.push(x);
                             merged_quals.push(qx);
                         } else if y == r {
@@ -214,25 +219,30 @@ impl SamMergeablePairs for SamData {
                                 stats.deletion_errors += 1;
                             }
 
+                            stats.r2_wins += 1;
                             merged_seq.push(y);
                             merged_quals.push(qy);
                         // x ≠ y
                         } else if y == b'-' {
                             stats.deletion_errors += 1;
 
+                            stats.r1_wins += 1;
                             merged_seq.push(x);
                             merged_quals.push(qx);
                         } else if x == b'-' {
                             stats.deletion_errors += 1;
 
+                            stats.r2_wins += 1;
                             merged_seq.push(y);
                             merged_quals.push(qy);
 
                         // x, y ≠ r, x ≠ y
                         } else if x.is_base_acgt() && y.is_not_base_acgt() {
+                            stats.r1_wins += 1;
                             merged_seq.push(x);
                             merged_quals.push(qx);
                         } else if y.is_base_acgt() && x.is_not_base_acgt() {
+                            stats.r2_wins += 1;
                             merged_seq.push(y);
                             merged_quals.push(qy);
 
@@ -241,9 +251,11 @@ impl SamMergeablePairs for SamData {
                         //
                         // NB: x, y must be bases
                         } else if qx > qy.saturating_add(4) {
+                            stats.r1_wins += 1;
                             merged_seq.push(x);
                             merged_quals.push(qx);
                         } else if qy > qx.saturating_add(4) {
+                            stats.r2_wins += 1;
                             merged_seq.push(y);
                             merged_quals.push(qy);
                         } else {
@@ -254,14 +266,14 @@ impl SamMergeablePairs for SamData {
                 }
                 (Some((base, quality)), None) | (None, Some((base, quality))) => {
                     if base == b'-' {
-                        merged_cigars.push(b'D');
+                        merged_cigars.push(CigarOp::Deletion);
                     } else {
-                        merged_cigars.push(b'M');
+                        merged_cigars.push(CigarOp::Match);
                         merged_seq.push(base);
                         merged_quals.push(quality); // ensure this is an encoded value during testing
                     }
                 }
-                (None, None) => merged_cigars.push(b'N'),
+                (None, None) => merged_cigars.push(CigarOp::Skip),
             }
 
             let range1 = a1.get_insert_after(ref_index);
@@ -279,19 +291,21 @@ impl SamMergeablePairs for SamData {
                     if insert1 == insert2 {
                         merged_seq.extend_from_slice(insert1.to_ascii_lowercase().as_slice());
                         merged_quals.extend(quals1.iter().zip(quals2).map(|(q1, q2)| std::cmp::max(*q1, *q2)));
-                        merged_cigars.extend(repeat_n(b'I', insert1.len()));
+                        merged_cigars.push_n(CigarOp::Insertion, insert1.len());
                     } else if insert2.contains_substring(insert1) {
                         merged_seq.extend_from_slice(insert1.to_ascii_lowercase().as_slice());
                         merged_quals.extend_from_slice(quals1);
-                        merged_cigars.extend(repeat_n(b'I', insert1.len()));
+                        merged_cigars.push_n(CigarOp::Insertion, insert1.len());
 
                         stats.insert_errors += 1;
+                        stats.r1_wins += 1;
                     } else if insert1.contains_substring(insert2) {
                         merged_seq.extend_from_slice(insert2.to_ascii_lowercase().as_slice());
                         merged_quals.extend_from_slice(quals2);
-                        merged_cigars.extend(repeat_n(b'I', insert2.len()));
+                        merged_cigars.push_n(CigarOp::Insertion, insert2.len());
 
                         stats.insert_errors += 1;
+                        stats.r2_wins += 1;
                     } else {
                         stats.insert_errors += 1;
                     }
@@ -304,6 +318,7 @@ impl SamMergeablePairs for SamData {
                     if p2.is_some() && pp2.is_some() {
                         stats.insert_obs += 1;
                         stats.insert_errors += 1;
+                        stats.r2_wins += 1;
                     // If unmated at this locus, then just add the insert
                     } else {
                         let insert = &self.seq[r1.query_range()];
@@ -311,7 +326,7 @@ impl SamMergeablePairs for SamData {
 
                         merged_seq.extend_from_slice(insert.to_ascii_lowercase().as_slice());
                         merged_quals.extend_from_slice(quals);
-                        merged_cigars.extend(repeat_n(b'I', insert.len()));
+                        merged_cigars.push_n(CigarOp::Insertion, insert.len());
                     }
                 }
                 (None, Some(r2)) => {
@@ -322,6 +337,7 @@ impl SamMergeablePairs for SamData {
                     if p1.is_some() && pp1.is_some() {
                         stats.insert_obs += 1;
                         stats.insert_errors += 1;
+                        stats.r1_wins += 1;
                     } else {
                         let insert = &other.seq[r2.query_range()].to_ascii_lowercase();
                         let quals = &other.qual[r2.query_range()];
@@ -329,16 +345,16 @@ impl SamMergeablePairs for SamData {
                         // Remove lower-casing if it does nothing downstream.
                         merged_seq.extend_from_slice(insert.to_ascii_lowercase().as_slice());
                         merged_quals.extend_from_slice(quals);
-                        merged_cigars.extend(repeat_n(b'I', insert.len()));
+                        merged_cigars.push_n(CigarOp::Insertion, insert.len());
                     }
                 }
                 _ => {}
             }
         }
 
-        merged_cigars.extend(std::iter::repeat_n(b'H', m_num_clipped_end));
+        merged_cigars.push_n(CigarOp::HardClip, m_num_clipped_end);
 
-        let merged_cigars = ExpandedCigar::from(merged_cigars).condense_to_cigar();
+        let merged_cigars = merged_cigars.build().to_cigar();
 
         (
             SamData::new(
@@ -367,8 +383,9 @@ pub(crate) trait SamExpandableAlignment {
     ///
     /// ## Panics
     ///
-    /// Currently panics on invalid cigar states. To be removed in the future
-    /// for either a `Result` or type-state validated CIGAR strings.
+    /// Panics if a CIGAR string is not syntactically valid, or covers more query
+    /// bases than `SEQ`, which can be checked beforehand with
+    /// [`TypedCigar::validate`].
     ///
     /// This also has a chance of panicking in debug mode if an insertion
     /// appears at the start of the alignment.
@@ -388,14 +405,13 @@ impl SamExpandableAlignment for SamData {
         let mut q_aln: Vec<u8> = Vec::new();
         let mut insertions = Vec::new();
 
-        let mut ciglets = self.cigar.iter();
-
-        let num_clipped_start = ciglets.remove_clipping_front();
-        let num_clipped_end = ciglets.remove_clipping_back();
+        let cigar = TypedCigar::try_from(&self.cigar).unwrap_or_else(|e| panic!("Invalid CIGAR {}: {e}", self.cigar));
+        let num_clipped_start = cigar.clipped_start();
+        let num_clipped_end = cigar.clipped_end();
 
-        for Ciglet { inc, op } in &self.cigar {
+        for &CigarElement { len: inc, op } in cigar.elements() {
             match op {
-                b'M' | b'=' | b'X' => {
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch => {
                     for _ in 0..inc {
                         q_aln.push(self.qual[query_index]);
                         aln.push(self.seq[query_index]);
@@ -403,7 +419,7 @@ impl SamExpandableAlignment for SamData {
                         ref_index += 1;
                     }
                 }
-                b'D' => {
+                CigarOp::Deletion => {
                     for _ in 0..inc {
                         // We use the minimum value for deletions so that it can
                         // still be parsed by other programs.
@@ -412,32 +428,43 @@ impl SamExpandableAlignment for SamData {
                     }
                     ref_index += inc;
                 }
-                b'I' => {
+                CigarOp::Insertion => {
                     debug_assert!(
                         ref_index > 0,
                         "Local alignment invariant violated: leading insertion at reference start"
                     );
-                    let ins = SamInsertion {
-                        // Insertion reference index uses 5' site (0-based)
-                        // by convention.
-                        ref_index:   ref_index - 1,
-                        query_start: query_index,
-                        query_end:   query_index + inc,
-                    };
-                    insertions.push(ins);
+                    // Insertion reference index uses 5' site (0-based) by
+                    // convention.
+                    let insert_ref_index = ref_index - 1;
+                    match insertions.last_mut() {
+                        // An insertion split by padding, such as `2I1P2I`, is
+                        // a single insertion in the unpadded reference
+                        Some(SamInsertion {
+                            ref_index: last_ref_index,
+                            query_end,
+                            ..
+                        }) if *last_ref_index == insert_ref_index && *query_end == query_index => {
+                            *query_end += inc;
+                        }
+                        _ => insertions.push(SamInsertion {
+                            ref_index:   insert_ref_index,
+                            query_start: query_index,
+                            query_end:   query_index + inc,
+                        }),
+                    }
                     query_index += inc;
                 }
-                b'S' => query_index += inc,
-                b'N' => {
+                CigarOp::SoftClip => query_index += inc,
+                CigarOp::Skip => {
                     for _ in 0..inc {
                         q_aln.push(b'!');
-                        aln.push(b'N');
+                        aln.push(SKIPPED);
                     }
                     ref_index += inc;
                 }
-                b'H' => {}
-                // TODO: Replace with a continue if type-state is adopted.
-                _ => panic!("Extended CIGAR {op} not yet supported.\n"),
+                // Padding is a silent deletion from the padded reference, so
+                // neither the query nor the reference advances
+                CigarOp::HardClip | CigarOp::Padding => {}
             }
         }
 
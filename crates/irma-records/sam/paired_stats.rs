@@ -16,6 +16,11 @@ pub struct PairedMergeStats {
     /// Total number of mismatching paired insertions, including disagreement in
     /// insertion presence
     pub insert_errors:   u64,
+    /// Disagreements resolved in favor of the first read of the pair, whether
+    /// a base, a deletion, or the presence or content of an insertion
+    pub r1_wins:         u64,
+    /// Disagreements resolved in favor of the second read of the pair
+    pub r2_wins:         u64,
 }
 
 impl Add for PairedMergeStats {
@@ -30,6 +35,8 @@ impl Add for PairedMergeStats {
             deletion_errors: self.deletion_errors + other.deletion_errors,
             insert_obs:      self.insert_obs + other.insert_obs,
             insert_errors:   self.insert_errors + other.insert_errors,
+            r1_wins:         self.r1_wins + other.r1_wins,
+            r2_wins:         self.r2_wins + other.r2_wins,
         }
     }
 }
@@ -44,6 +51,8 @@ impl AddAssign for PairedMergeStats {
             deletion_errors: self.deletion_errors + other.deletion_errors,
             insert_obs:      self.insert_obs + other.insert_obs,
             insert_errors:   self.insert_errors + other.insert_errors,
+            r1_wins:         self.r1_wins + other.r1_wins,
+            r2_wins:         self.r2_wins + other.r2_wins,
         }
     }
 }
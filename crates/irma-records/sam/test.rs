@@ -1,8 +1,11 @@
 use crate::sam::{
-    ExpandedCigar,
+    CigarBuilder, CigarOp, CigarValidationError, TypedCigar,
     merge_pairs::{SamMergeablePairs, make_merged_qname},
 };
-use zoe::data::{cigar::Cigar, sam::SamData};
+use zoe::{
+    alignment::AlignmentStates,
+    data::{cigar::Cigar, sam::SamData},
+};
 
 #[test]
 fn merge_no_mismatch() {
@@ -74,10 +77,16 @@ fn merge_with_mismatch() {
     //s2 .........GCGGCTTT...
     //m  ....AAAAAGGCGCTTT...
 
-    let (m, _) = s1.merge_pair_using_reference(&s2, reference, false);
+    let (m, stats) = s1.merge_pair_using_reference(&s2, reference, false);
     assert_eq!(m.seq, b"AAAAAGGCGCTTT".into());
     assert_eq!(m.qual, b"FFFFFFFFFFFFA".try_into().unwrap());
     assert_eq!(Cigar::try_from(b"13M").unwrap(), m.cigar);
+    assert_eq!(stats.observations, 3);
+    assert_eq!(stats.variant_errors, 2);
+    assert_eq!((stats.r1_wins, stats.r2_wins), (2, 0));
+
+    let (_, stats) = s2.merge_pair_using_reference(&s1, reference, false);
+    assert_eq!((stats.r1_wins, stats.r2_wins), (0, 2));
 }
 
 #[test]
@@ -232,6 +241,123 @@ fn merge_with_clipping_past_left() {
     assert_eq!(Cigar::try_from(b"8H10M8H").unwrap(), m.cigar);
 }
 
+#[test]
+fn merge_with_shared_skip() {
+    let s1 = SamData::new(
+        "s1".to_string(),
+        0,
+        "ref".to_string(),
+        5,
+        30,
+        "3M4N3M".try_into().unwrap(),
+        b"AAACGG".into(),
+        b"FFFFFF".try_into().unwrap(),
+    );
+
+    let s2 = SamData::new(
+        "s2".to_string(),
+        0,
+        "ref".to_string(),
+        6,
+        30,
+        "2M4N5M".try_into().unwrap(),
+        b"AACGGTT".into(),
+        b"FFFFFFF".try_into().unwrap(),
+    );
+
+    let reference = b"TTTTAAAAAGGCGGTTTTAGGA";
+
+    //   1234567890123456789012
+    //r  TTTTAAAAAGGCGGTTTTAGGA
+    //s1 ....AAAnnnnCGG........
+    //s2 .....AAnnnnCGGTT......
+    //m  ....AAAnnnnCGGTT......
+
+    let (m, stats) = s1.merge_pair_using_reference(&s2, reference, false);
+    assert_eq!(m.seq, b"AAACGGTT".into());
+    assert_eq!(m.qual, b"FFFFFFFF".try_into().unwrap());
+    assert_eq!(Cigar::try_from(b"3M4N5M").unwrap(), m.cigar);
+    assert_eq!(stats.observations, 5);
+    assert_eq!(stats.variant_errors, 0);
+}
+
+#[test]
+fn merge_with_skip_covered_by_mate() {
+    let s1 = SamData::new(
+        "s1".to_string(),
+        0,
+        "ref".to_string(),
+        5,
+        30,
+        "3M4N3M".try_into().unwrap(),
+        b"AAACGG".into(),
+        b"FFFFFF".try_into().unwrap(),
+    );
+
+    let s2 = SamData::new(
+        "s2".to_string(),
+        0,
+        "ref".to_string(),
+        8,
+        30,
+        "5M".try_into().unwrap(),
+        b"AAGGC".into(),
+        b"EEEEE".try_into().unwrap(),
+    );
+
+    let reference = b"TTTTAAAAAGGCGGTTTTAGGA";
+
+    //   1234567890123456789012
+    //r  TTTTAAAAAGGCGGTTTTAGGA
+    //s1 ....AAAnnnnCGG........
+    //s2 .......AAGGC..........
+    //m  ....AAAAAGGCGG........
+
+    let (m, stats) = s1.merge_pair_using_reference(&s2, reference, false);
+    assert_eq!(m.seq, b"AAAAAGGCGG".into());
+    assert_eq!(m.qual, b"FFFEEEEFFF".try_into().unwrap());
+    assert_eq!(Cigar::try_from(b"10M").unwrap(), m.cigar);
+    assert_eq!(stats.observations, 1);
+    assert_eq!(stats.variant_errors, 0);
+}
+
+#[test]
+fn merge_with_padded_insertion() {
+    let s1 = SamData::new(
+        "s1".to_string(),
+        0,
+        "ref".to_string(),
+        5,
+        30,
+        "3M2I1P2I3M".try_into().unwrap(),
+        b"AAACCTTAAG".into(),
+        b"FFFFFFFFFF".try_into().unwrap(),
+    );
+
+    let s2 = SamData::new(
+        "s2".to_string(),
+        0,
+        "ref".to_string(),
+        10,
+        30,
+        "5M".try_into().unwrap(),
+        b"GGCGG".into(),
+        b"FFFFF".try_into().unwrap(),
+    );
+
+    let reference = b"TTTTAAAAAGGCGGTTTTAGGA";
+
+    //   1234567    890123456789012
+    //r  TTTTAAA----AAGGCGGTTTTAGGA
+    //s1 ....AAAcctt AAG...........
+    //s2 ......     ..GGCGG........
+    //m  ....AAAccttAAGGCGG........
+
+    let (m, _) = s1.merge_pair_using_reference(&s2, reference, false);
+    assert_eq!(m.seq, b"AAAccttAAGGCGG".into());
+    assert_eq!(Cigar::try_from(b"3M4I7M").unwrap(), m.cigar);
+}
+
 static QNAMES: [&str; 26] = [
     "SRR26182418.1 M07901:28:000000000-KP3NB:1:1101:10138:2117 length=147",
     "SRR26182418.1 M07901:28:000000000-KP3NB:1:1101:10138:2117 length=301",
@@ -300,8 +426,12 @@ fn test_make_merged_qname() {
 #[test]
 fn test_expand() {
     let cigar = Cigar::from_slice_unchecked("4S10M2I2D3M4H4P");
-    let expanded: ExpandedCigar = "SSSSMMMMMMMMMMIIDDMMMHHHHPPPP".into();
-    assert_eq!(ExpandedCigar::from(cigar), expanded);
+    let expanded = TypedCigar::try_from(&cigar)
+        .unwrap()
+        .expanded_ops()
+        .map(CigarOp::to_byte)
+        .collect::<Vec<_>>();
+    assert_eq!(expanded, b"SSSSMMMMMMMMMMIIDDMMMHHHHPPPP");
 }
 
 #[test]
@@ -310,6 +440,98 @@ fn test_condense_cigar() {
 
     for c in cigars {
         let cigar = Cigar::from_slice_unchecked(c);
-        assert_eq!(ExpandedCigar::from(cigar.clone()).condense_to_cigar(), cigar);
+        let expanded = TypedCigar::try_from(&cigar).unwrap().expanded_ops().collect::<Vec<_>>();
+        assert_eq!(expanded.into_iter().collect::<CigarBuilder>().build().to_cigar(), cigar);
+    }
+}
+
+#[test]
+fn test_typed_cigar_parsing() {
+    let star = TypedCigar::try_from(&Cigar::from_slice_unchecked("*")).unwrap();
+    assert!(star.is_empty());
+    assert_eq!(star.to_string(), "*");
+
+    for invalid in ["3Q", "M", "3M4", "0M"] {
+        let result = TypedCigar::try_from(&Cigar::from_slice_unchecked(invalid));
+        assert!(
+            matches!(result, Err(CigarValidationError::Syntax(_))),
+            "'{invalid}' gave {result:?}"
+        );
     }
+
+    // Adjacent runs of the same operation are merged
+    let cigar = TypedCigar::try_from(&Cigar::from_slice_unchecked("2M3M1I")).unwrap();
+    assert_eq!(cigar.to_string(), "5M1I");
+
+    let mut states = AlignmentStates::new();
+    states.add_inc_op(2, b'S');
+    states.add_inc_op(5, b'M');
+    states.add_inc_op(1, b'D');
+    states.add_inc_op(3, b'M');
+    let cigar = TypedCigar::try_from(&states).unwrap();
+    assert_eq!(cigar.to_cigar(), states.to_cigar_unchecked());
+}
+
+#[test]
+fn test_typed_cigar_lengths() {
+    let cigar = TypedCigar::try_from(&Cigar::from_slice_unchecked("2H3S10M2I2D1N3=4X5S")).unwrap();
+    assert_eq!(cigar.query_len(), 27);
+    assert_eq!(cigar.ref_len(), 20);
+    assert_eq!(cigar.clipped_start(), 5);
+    assert_eq!(cigar.clipped_end(), 5);
+}
+
+#[test]
+fn test_typed_cigar_validate() {
+    let valid: [(&str, Option<usize>); 5] = [
+        ("10M", Some(10)),
+        ("2H3S5M4S1H", Some(12)),
+        ("5H5M5S", Some(10)),
+        ("4M2I4M", None),
+        ("*", None),
+    ];
+    for (c, seq_len) in valid {
+        let cigar = TypedCigar::try_from(&Cigar::from_slice_unchecked(c)).unwrap();
+        assert_eq!(cigar.validate(seq_len), Ok(()), "'{c}'");
+    }
+
+    let invalid: [(&str, Option<usize>, CigarValidationError); 4] = [
+        ("5M2H5M", None, CigarValidationError::HardClipNotAtEnd),
+        ("5M2S5M", None, CigarValidationError::SoftClipNotAtEnd),
+        ("3S2H5M", None, CigarValidationError::HardClipNotAtEnd),
+        (
+            "5M5S",
+            Some(9),
+            CigarValidationError::QueryLenMismatch {
+                query_len: 10,
+                seq_len:   9,
+            },
+        ),
+    ];
+    for (c, seq_len, expected) in invalid {
+        let cigar = TypedCigar::try_from(&Cigar::from_slice_unchecked(c)).unwrap();
+        assert_eq!(cigar.validate(seq_len), Err(expected), "'{c}'");
+    }
+}
+
+#[test]
+fn test_typed_cigar_coordinates() {
+    //      0123456789
+    // ref  ...AAC--GTT
+    // seq    sAA-CCGTT
+    // The alignment starts at reference index 3 with `1S2M1D2I3M`
+    let cigar = TypedCigar::try_from(&Cigar::from_slice_unchecked("1S2M1D2I3M")).unwrap();
+
+    let query_indices = (0..9).map(|r| cigar.query_index_at(3, r)).collect::<Vec<_>>();
+    assert_eq!(
+        query_indices,
+        [None, None, None, Some(1), Some(2), None, Some(5), Some(6), Some(7)]
+    );
+    assert_eq!(cigar.query_index_at(3, 9), None);
+
+    let ref_indices = (0..9).map(|q| cigar.ref_index_at(3, q)).collect::<Vec<_>>();
+    assert_eq!(
+        ref_indices,
+        [None, Some(3), Some(4), None, None, Some(6), Some(7), Some(8), None]
+    );
 }